@@ -0,0 +1,301 @@
+//!
+//! Annotation of the values pointing into the memory of target, the registers at a stop by
+//! [`TraceContext::annotated`] and the stack slots by [`Annotator::stack_scan`].
+//!
+//! A value is described by the first found of: the symbol, as `ntdll!LdrpHandle+10`; the string
+//! pointed to, quoted; the module and offset, as `ntdll+1a40`; the stack of thread, as
+//! `stack of tid 1234`; the heap block, as `heap block 0x5a0e80+10 size 0x40`; the region
+//! containing it, by its info and the offset, such as `Heap #0+1a40`, or `TEB+30` for the TEB and
+//! PEB. The values out of the mapped memory are not annotated.
+//!
+//! The stacks of threads are resolved by their TEBs on Windows, elsewhere by the regions of their
+//! stack pointers, which are only readable for the stopped threads, and the `[stack]` of the main
+//! thread. The heap blocks are walked in the memory on Linux; on Windows the toolhelp walk runs a
+//! thread in target which can't be done at a stop, so they're only resolved if given by
+//! [`Annotator::with_heap`].
+//!
+
+use crate::{
+    heap::{HeapBlock, HeapSnapshot},
+    prelude::*,
+    range::RangeValue,
+    register::general_regs,
+};
+
+use core::cell::OnceCell;
+use core::ops::Range;
+use std::collections::BTreeMap;
+
+/// What a value points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ValueKind {
+    /// Executable code with symbol
+    Code,
+    /// Code address follows a call instruction, only detected in stack scanning
+    ReturnAddress,
+    /// Inside a module, a data symbol or no symbol
+    Module,
+    Stack,
+    Heap,
+    Teb,
+    Peb,
+    String,
+    /// Other valid memory
+    Memory,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Annotation {
+    pub kind: ValueKind,
+    /// Human-readable description, such as "ntdll!LdrpHandle+10", "Heap #0+1a40"
+    pub desc: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RegAnnotation {
+    pub name: &'static str,
+    pub value: usize,
+    pub annotation: Option<Annotation>,
+}
+
+/// A pointer-sized slot in stack memory
+#[derive(Debug, Clone, Serialize)]
+pub struct StackSlot {
+    pub address: usize,
+    pub value: usize,
+    pub annotation: Option<Annotation>,
+}
+
+impl StackSlot {
+    #[inline]
+    pub fn is_return_address(&self) -> bool {
+        self.annotation.as_ref().map(|a| a.kind) == Some(ValueKind::ReturnAddress)
+    }
+}
+
+/// Annotate values by a memory layout snapshot of target
+pub struct Annotator<'a, T: UDbgTarget + ?Sized = dyn UDbgTarget> {
+    pub target: &'a T,
+    pub pages: Vec<MemoryPage>,
+    /// The busy heap blocks by address, walked on first use
+    heap: OnceCell<BTreeMap<usize, HeapBlock>>,
+    /// The stack ranges of threads, resolved on first use
+    stacks: OnceCell<Vec<(Range<usize>, tid_t)>>,
+}
+
+impl<'a, T: UDbgTarget + ?Sized> Annotator<'a, T> {
+    /// Max length of string to detect
+    pub const MAX_STRING: usize = 0x40;
+
+    pub fn new(target: &'a T) -> Self {
+        let mut pages = target.collect_memory_info();
+        pages.sort_by_key(|p| p.base);
+        Self {
+            target,
+            pages,
+            heap: OnceCell::new(),
+            stacks: OnceCell::new(),
+        }
+    }
+
+    /// Resolve the heap blocks by `blocks`, such as the live blocks of
+    /// [`crate::heap::AllocTracker`] or a [`HeapSnapshot`] captured before the stop
+    pub fn with_heap(self, blocks: impl IntoIterator<Item = HeapBlock>) -> Self {
+        let heap = OnceCell::new();
+        heap.set(blocks.into_iter().map(|b| (b.address, b)).collect())
+            .ok();
+        Self { heap, ..self }
+    }
+
+    #[inline]
+    pub fn find_page(&self, address: usize) -> Option<&MemoryPage> {
+        RangeValue::binary_search(&self.pages, address)
+    }
+
+    /// The busy heap block containing the address
+    pub fn find_heap_block(&self, address: usize) -> Option<HeapBlock> {
+        let blocks = self.heap.get_or_init(|| {
+            if cfg!(windows) {
+                return Default::default();
+            }
+            HeapSnapshot::capture(self.target)
+                .map(|s| s.blocks)
+                .unwrap_or_default()
+        });
+        let (_, block) = blocks.range(..=address).next_back()?;
+        Some(*block).filter(|b| address < b.address + b.size)
+    }
+
+    /// The thread whose stack contains the address
+    pub fn stack_owner(&self, address: usize) -> Option<tid_t> {
+        let stacks = self.stacks.get_or_init(|| {
+            self.target.enum_thread(false).map_or(vec![], |threads| {
+                threads
+                    .filter_map(|t| Some((self.stack_range(t.as_ref())?, t.tid)))
+                    .collect()
+            })
+        });
+        stacks
+            .iter()
+            .find(|(range, _)| range.contains(&address))
+            .map(|s| s.1)
+    }
+
+    #[cfg(windows)]
+    fn stack_range(&self, thread: &dyn UDbgThread) -> Option<Range<usize>> {
+        let limits = crate::peb::Teb::of(self.target, thread)
+            .ok()?
+            .stack_limits()
+            .ok()?;
+        let bottom = match limits.deallocation {
+            0 => limits.limit,
+            bottom => bottom,
+        };
+        Some(bottom..limits.base)
+    }
+
+    #[cfg(not(windows))]
+    fn stack_range(&self, thread: &dyn UDbgThread) -> Option<Range<usize>> {
+        let sp = thread.reg_value("_sp").ok()?;
+        self.find_page(sp).map(|p| p.base..p.base + p.size)
+    }
+
+    /// Describe the value as the module document, None if it's out of the mapped memory
+    pub fn annotate(&self, value: usize) -> Option<Annotation> {
+        let page = self.find_page(value)?;
+        let sym = self.target.get_symbol_(value, None);
+        if let Some(sym) = sym.as_ref().filter(|s| !s.symbol.is_empty()) {
+            return Some(Annotation {
+                kind: if page.is_executable() {
+                    ValueKind::Code
+                } else {
+                    ValueKind::Module
+                },
+                desc: sym.to_string(value),
+            });
+        }
+
+        if !page.is_executable() {
+            if let Some(desc) = self.string_at(value) {
+                return Some(Annotation {
+                    kind: ValueKind::String,
+                    desc,
+                });
+            }
+        }
+
+        if let Some(sym) = sym {
+            return Some(Annotation {
+                kind: ValueKind::Module,
+                desc: sym.to_string(value),
+            });
+        }
+
+        let info = page.info.as_deref().unwrap_or_default();
+        let (kind, desc) = if let Some(tid) = self.stack_owner(value) {
+            (ValueKind::Stack, format!("stack of tid {tid}"))
+        } else if page.flags.contains(MemoryFlags::STACK) {
+            // the main thread's, whose tid is the pid
+            if cfg!(not(windows)) && info == "[stack]" {
+                let tid = self.target.base().pid.get();
+                (ValueKind::Stack, format!("stack of tid {tid}"))
+            } else {
+                (ValueKind::Stack, info.to_string())
+            }
+        } else if page.flags.contains(MemoryFlags::HEAP) {
+            let desc = match self.find_heap_block(value) {
+                Some(b) if b.address == value => {
+                    format!("heap block {:#x} size {:#x}", b.address, b.size)
+                }
+                Some(b) => format!(
+                    "heap block {:#x}+{:x} size {:#x}",
+                    b.address,
+                    value - b.address,
+                    b.size
+                ),
+                None => format!("{}+{:x}", info, value - page.base),
+            };
+            (ValueKind::Heap, desc)
+        } else if page.flags.contains(MemoryFlags::TEB) {
+            (ValueKind::Teb, format!("TEB+{:x}", value - page.base))
+        } else if page.flags.contains(MemoryFlags::PEB) {
+            (ValueKind::Peb, format!("PEB+{:x}", value - page.base))
+        } else if info.is_empty() {
+            (
+                ValueKind::Memory,
+                format!("{} {}", page.type_(), page.protect()),
+            )
+        } else {
+            (
+                ValueKind::Memory,
+                format!("{}+{:x}", info, value - page.base),
+            )
+        };
+        Some(Annotation { kind, desc })
+    }
+
+    /// Detect a printable string, return its quoted form
+    pub fn string_at(&self, address: usize) -> Option<String> {
+        let (wide, s) = self.target.detect_string(address, Self::MAX_STRING)?;
+        if s.chars().count() < 4 || s.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+            return None;
+        }
+        Some(if wide {
+            format!("L{:?}", s)
+        } else {
+            format!("{:?}", s)
+        })
+    }
+
+    /// Annotate the general registers
+    pub fn annotate_regs(&self, regs: &dyn UDbgRegs, arch: u32) -> Vec<RegAnnotation> {
+        general_regs(arch)
+            .iter()
+            .filter_map(|&(name, id)| {
+                let value = regs.get_reg(id)?.as_int();
+                Some(RegAnnotation {
+                    name,
+                    value,
+                    annotation: self.annotate(value),
+                })
+            })
+            .collect()
+    }
+
+    /// Walk the raw stack memory from `sp`, classify each pointer-sized slot,
+    /// stop at the end of stack region or after `max_slots` slots
+    pub fn stack_scan(&self, sp: usize, max_slots: usize) -> Vec<StackSlot> {
+        let layout = self.target.base().data_layout();
+        let psize = layout.pointer_size;
+        let end = self
+            .find_page(sp)
+            .map(|p| p.base + p.size)
+            .unwrap_or(sp + max_slots * psize);
+        let count = ((end - sp) / psize).min(max_slots);
+        let buf = self.target.read_bytes(sp, count * psize);
+
+        buf.chunks_exact(psize)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let value = if psize == 4 {
+                    u32::from_bytes(chunk, layout.endian) as usize
+                } else {
+                    u64::from_bytes(chunk, layout.endian) as usize
+                };
+                let mut annotation = self.annotate(value);
+                if let Some(a) = annotation.as_mut() {
+                    if matches!(a.kind, ValueKind::Code | ValueKind::Module)
+                        && self.target.is_return_address(value)
+                    {
+                        a.kind = ValueKind::ReturnAddress;
+                    }
+                }
+                StackSlot {
+                    address: sp + i * psize,
+                    value,
+                    annotation,
+                }
+            })
+            .collect()
+    }
+}
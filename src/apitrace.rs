@@ -0,0 +1,496 @@
+//!
+//! API call tracer: log the calls of the functions specified by `module!function`, with the
+//! arguments and return values decoded by their prototypes, like a lightweight API monitor.
+//!
+//! The prototypes of the common functions in kernel32, ntdll and libc are built in, see
+//! [`find_proto`]. The functions without prototype are traced with [`ApiTracer::raw_args`] raw
+//! arguments. Each call is reported at its return as [`ApiCall`], wrapped as [`UEvent::Custom`]
+//! of kind [`API_CALL_EVENT`], the functions returning nothing are reported at their entries.
+//!
+
+use crate::{
+    ipc::{return_reg, ApiHit, ApiHooks},
+    prelude::*,
+    target::call_args,
+};
+
+use std::sync::Arc;
+
+/// Kind of the custom event which carries [`ApiCall`]
+pub const API_CALL_EVENT: &str = "api_call";
+
+/// Type of argument or return value, decides how the value is decoded
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum ArgType {
+    /// 32-bit signed integer
+    Int,
+    /// 32-bit unsigned integer, such as `DWORD`, flags
+    UInt,
+    /// Pointer-sized integer, such as `size_t`
+    Size,
+    Ptr,
+    Handle,
+    /// 32-bit `BOOL`
+    Bool,
+    /// Pointer to a nul-terminated string
+    Str,
+    /// Pointer to a nul-terminated UTF-16 string
+    WStr,
+    /// `NTSTATUS`
+    Status,
+    /// No return value
+    Void,
+}
+
+/// Prototype of a function
+#[derive(Debug, Clone, Serialize)]
+pub struct Proto {
+    pub name: &'static str,
+    pub args: &'static [(&'static str, ArgType)],
+    pub ret: ArgType,
+}
+
+macro_rules! protos {
+    ($($name:ident($($arg:ident: $ty:ident),*) -> $ret:ident;)*) => {
+        &[$(Proto {
+            name: stringify!($name),
+            args: &[$((stringify!($arg), ArgType::$ty)),*],
+            ret: ArgType::$ret,
+        }),*]
+    };
+}
+
+/// The built-in prototypes
+pub const PROTOTYPES: &[Proto] = protos! {
+    // kernel32
+    CreateFileA(
+        lpFileName: Str, dwDesiredAccess: UInt, dwShareMode: UInt, lpSecurityAttributes: Ptr,
+        dwCreationDisposition: UInt, dwFlagsAndAttributes: UInt, hTemplateFile: Handle
+    ) -> Handle;
+    CreateFileW(
+        lpFileName: WStr, dwDesiredAccess: UInt, dwShareMode: UInt, lpSecurityAttributes: Ptr,
+        dwCreationDisposition: UInt, dwFlagsAndAttributes: UInt, hTemplateFile: Handle
+    ) -> Handle;
+    ReadFile(
+        hFile: Handle, lpBuffer: Ptr, nNumberOfBytesToRead: UInt, lpNumberOfBytesRead: Ptr,
+        lpOverlapped: Ptr
+    ) -> Bool;
+    WriteFile(
+        hFile: Handle, lpBuffer: Ptr, nNumberOfBytesToWrite: UInt, lpNumberOfBytesWritten: Ptr,
+        lpOverlapped: Ptr
+    ) -> Bool;
+    DeleteFileA(lpFileName: Str) -> Bool;
+    DeleteFileW(lpFileName: WStr) -> Bool;
+    CloseHandle(hObject: Handle) -> Bool;
+    LoadLibraryA(lpLibFileName: Str) -> Ptr;
+    LoadLibraryW(lpLibFileName: WStr) -> Ptr;
+    LoadLibraryExA(lpLibFileName: Str, hFile: Handle, dwFlags: UInt) -> Ptr;
+    LoadLibraryExW(lpLibFileName: WStr, hFile: Handle, dwFlags: UInt) -> Ptr;
+    GetModuleHandleA(lpModuleName: Str) -> Ptr;
+    GetModuleHandleW(lpModuleName: WStr) -> Ptr;
+    GetProcAddress(hModule: Ptr, lpProcName: Str) -> Ptr;
+    FreeLibrary(hLibModule: Ptr) -> Bool;
+    VirtualAlloc(lpAddress: Ptr, dwSize: Size, flAllocationType: UInt, flProtect: UInt) -> Ptr;
+    VirtualAllocEx(
+        hProcess: Handle, lpAddress: Ptr, dwSize: Size, flAllocationType: UInt, flProtect: UInt
+    ) -> Ptr;
+    VirtualFree(lpAddress: Ptr, dwSize: Size, dwFreeType: UInt) -> Bool;
+    VirtualProtect(lpAddress: Ptr, dwSize: Size, flNewProtect: UInt, lpflOldProtect: Ptr) -> Bool;
+    VirtualProtectEx(
+        hProcess: Handle, lpAddress: Ptr, dwSize: Size, flNewProtect: UInt, lpflOldProtect: Ptr
+    ) -> Bool;
+    OpenProcess(dwDesiredAccess: UInt, bInheritHandle: Bool, dwProcessId: UInt) -> Handle;
+    ReadProcessMemory(
+        hProcess: Handle, lpBaseAddress: Ptr, lpBuffer: Ptr, nSize: Size, lpNumberOfBytesRead: Ptr
+    ) -> Bool;
+    WriteProcessMemory(
+        hProcess: Handle, lpBaseAddress: Ptr, lpBuffer: Ptr, nSize: Size,
+        lpNumberOfBytesWritten: Ptr
+    ) -> Bool;
+    CreateProcessA(
+        lpApplicationName: Str, lpCommandLine: Str, lpProcessAttributes: Ptr,
+        lpThreadAttributes: Ptr, bInheritHandles: Bool, dwCreationFlags: UInt, lpEnvironment: Ptr,
+        lpCurrentDirectory: Str, lpStartupInfo: Ptr, lpProcessInformation: Ptr
+    ) -> Bool;
+    CreateProcessW(
+        lpApplicationName: WStr, lpCommandLine: WStr, lpProcessAttributes: Ptr,
+        lpThreadAttributes: Ptr, bInheritHandles: Bool, dwCreationFlags: UInt, lpEnvironment: Ptr,
+        lpCurrentDirectory: WStr, lpStartupInfo: Ptr, lpProcessInformation: Ptr
+    ) -> Bool;
+    CreateThread(
+        lpThreadAttributes: Ptr, dwStackSize: Size, lpStartAddress: Ptr, lpParameter: Ptr,
+        dwCreationFlags: UInt, lpThreadId: Ptr
+    ) -> Handle;
+    CreateRemoteThread(
+        hProcess: Handle, lpThreadAttributes: Ptr, dwStackSize: Size, lpStartAddress: Ptr,
+        lpParameter: Ptr, dwCreationFlags: UInt, lpThreadId: Ptr
+    ) -> Handle;
+    ExitProcess(uExitCode: UInt) -> Void;
+    Sleep(dwMilliseconds: UInt) -> Void;
+    WaitForSingleObject(hHandle: Handle, dwMilliseconds: UInt) -> UInt;
+    CreateMutexW(lpMutexAttributes: Ptr, bInitialOwner: Bool, lpName: WStr) -> Handle;
+    CreateEventW(
+        lpEventAttributes: Ptr, bManualReset: Bool, bInitialState: Bool, lpName: WStr
+    ) -> Handle;
+    RegOpenKeyExW(
+        hKey: Handle, lpSubKey: WStr, ulOptions: UInt, samDesired: UInt, phkResult: Ptr
+    ) -> Int;
+    RegQueryValueExW(
+        hKey: Handle, lpValueName: WStr, lpReserved: Ptr, lpType: Ptr, lpData: Ptr, lpcbData: Ptr
+    ) -> Int;
+    RegSetValueExW(
+        hKey: Handle, lpValueName: WStr, Reserved: UInt, dwType: UInt, lpData: Ptr, cbData: UInt
+    ) -> Int;
+    OutputDebugStringA(lpOutputString: Str) -> Void;
+    OutputDebugStringW(lpOutputString: WStr) -> Void;
+    // ntdll
+    NtCreateFile(
+        FileHandle: Ptr, DesiredAccess: UInt, ObjectAttributes: Ptr, IoStatusBlock: Ptr,
+        AllocationSize: Ptr, FileAttributes: UInt, ShareAccess: UInt, CreateDisposition: UInt,
+        CreateOptions: UInt, EaBuffer: Ptr, EaLength: UInt
+    ) -> Status;
+    NtOpenFile(
+        FileHandle: Ptr, DesiredAccess: UInt, ObjectAttributes: Ptr, IoStatusBlock: Ptr,
+        ShareAccess: UInt, OpenOptions: UInt
+    ) -> Status;
+    NtReadFile(
+        FileHandle: Handle, Event: Handle, ApcRoutine: Ptr, ApcContext: Ptr, IoStatusBlock: Ptr,
+        Buffer: Ptr, Length: UInt, ByteOffset: Ptr, Key: Ptr
+    ) -> Status;
+    NtWriteFile(
+        FileHandle: Handle, Event: Handle, ApcRoutine: Ptr, ApcContext: Ptr, IoStatusBlock: Ptr,
+        Buffer: Ptr, Length: UInt, ByteOffset: Ptr, Key: Ptr
+    ) -> Status;
+    NtClose(Handle: Handle) -> Status;
+    NtOpenProcess(
+        ProcessHandle: Ptr, DesiredAccess: UInt, ObjectAttributes: Ptr, ClientId: Ptr
+    ) -> Status;
+    NtAllocateVirtualMemory(
+        ProcessHandle: Handle, BaseAddress: Ptr, ZeroBits: Size, RegionSize: Ptr,
+        AllocationType: UInt, Protect: UInt
+    ) -> Status;
+    NtFreeVirtualMemory(
+        ProcessHandle: Handle, BaseAddress: Ptr, RegionSize: Ptr, FreeType: UInt
+    ) -> Status;
+    NtProtectVirtualMemory(
+        ProcessHandle: Handle, BaseAddress: Ptr, RegionSize: Ptr, NewProtect: UInt, OldProtect: Ptr
+    ) -> Status;
+    NtReadVirtualMemory(
+        ProcessHandle: Handle, BaseAddress: Ptr, Buffer: Ptr, BufferSize: Size,
+        NumberOfBytesRead: Ptr
+    ) -> Status;
+    NtWriteVirtualMemory(
+        ProcessHandle: Handle, BaseAddress: Ptr, Buffer: Ptr, BufferSize: Size,
+        NumberOfBytesWritten: Ptr
+    ) -> Status;
+    NtQueryInformationProcess(
+        ProcessHandle: Handle, ProcessInformationClass: UInt, ProcessInformation: Ptr,
+        ProcessInformationLength: UInt, ReturnLength: Ptr
+    ) -> Status;
+    NtSetInformationThread(
+        ThreadHandle: Handle, ThreadInformationClass: UInt, ThreadInformation: Ptr,
+        ThreadInformationLength: UInt
+    ) -> Status;
+    NtCreateThreadEx(
+        ThreadHandle: Ptr, DesiredAccess: UInt, ObjectAttributes: Ptr, ProcessHandle: Handle,
+        StartRoutine: Ptr, Argument: Ptr, CreateFlags: UInt, ZeroBits: Size, StackSize: Size,
+        MaximumStackSize: Size, AttributeList: Ptr
+    ) -> Status;
+    NtDelayExecution(Alertable: Bool, DelayInterval: Ptr) -> Status;
+    NtTerminateProcess(ProcessHandle: Handle, ExitStatus: Status) -> Status;
+    LdrLoadDll(DllPath: WStr, DllCharacteristics: Ptr, DllName: Ptr, DllHandle: Ptr) -> Status;
+    LdrGetProcedureAddress(
+        DllHandle: Ptr, ProcedureName: Ptr, ProcedureNumber: UInt, ProcedureAddress: Ptr
+    ) -> Status;
+    RtlAllocateHeap(HeapHandle: Handle, Flags: UInt, Size: Size) -> Ptr;
+    RtlReAllocateHeap(HeapHandle: Handle, Flags: UInt, BaseAddress: Ptr, Size: Size) -> Ptr;
+    RtlFreeHeap(HeapHandle: Handle, Flags: UInt, BaseAddress: Ptr) -> Bool;
+    // libc
+    open(pathname: Str, flags: Int, mode: UInt) -> Int;
+    openat(dirfd: Int, pathname: Str, flags: Int, mode: UInt) -> Int;
+    read(fd: Int, buf: Ptr, count: Size) -> Size;
+    write(fd: Int, buf: Ptr, count: Size) -> Size;
+    close(fd: Int) -> Int;
+    unlink(pathname: Str) -> Int;
+    stat(pathname: Str, statbuf: Ptr) -> Int;
+    access(pathname: Str, mode: Int) -> Int;
+    fopen(pathname: Str, mode: Str) -> Ptr;
+    fclose(stream: Ptr) -> Int;
+    fread(ptr: Ptr, size: Size, nmemb: Size, stream: Ptr) -> Size;
+    fwrite(ptr: Ptr, size: Size, nmemb: Size, stream: Ptr) -> Size;
+    malloc(size: Size) -> Ptr;
+    calloc(nmemb: Size, size: Size) -> Ptr;
+    realloc(ptr: Ptr, size: Size) -> Ptr;
+    free(ptr: Ptr) -> Void;
+    mmap(addr: Ptr, length: Size, prot: Int, flags: Int, fd: Int, offset: Size) -> Ptr;
+    munmap(addr: Ptr, length: Size) -> Int;
+    mprotect(addr: Ptr, len: Size, prot: Int) -> Int;
+    memcpy(dest: Ptr, src: Ptr, n: Size) -> Ptr;
+    memset(s: Ptr, c: Int, n: Size) -> Ptr;
+    strlen(s: Str) -> Size;
+    strcmp(s1: Str, s2: Str) -> Int;
+    strncmp(s1: Str, s2: Str, n: Size) -> Int;
+    strcpy(dest: Ptr, src: Str) -> Ptr;
+    getenv(name: Str) -> Str;
+    setenv(name: Str, value: Str, overwrite: Int) -> Int;
+    system(command: Str) -> Int;
+    execve(pathname: Str, argv: Ptr, envp: Ptr) -> Int;
+    fork() -> Int;
+    exit(status: Int) -> Void;
+    dlopen(filename: Str, flags: Int) -> Ptr;
+    dlsym(handle: Ptr, symbol: Str) -> Ptr;
+    dlclose(handle: Ptr) -> Int;
+    socket(domain: Int, type_: Int, protocol: Int) -> Int;
+    connect(sockfd: Int, addr: Ptr, addrlen: UInt) -> Int;
+    bind(sockfd: Int, addr: Ptr, addrlen: UInt) -> Int;
+    listen(sockfd: Int, backlog: Int) -> Int;
+    accept(sockfd: Int, addr: Ptr, addrlen: Ptr) -> Int;
+    send(sockfd: Int, buf: Ptr, len: Size, flags: Int) -> Size;
+    recv(sockfd: Int, buf: Ptr, len: Size, flags: Int) -> Size;
+    pthread_create(thread: Ptr, attr: Ptr, start_routine: Ptr, arg: Ptr) -> Int;
+    ptrace(request: Int, pid: Int, addr: Ptr, data: Ptr) -> Size;
+};
+
+/// Find the built-in prototype by the function name, `module!` prefix is ignored
+pub fn find_proto(name: &str) -> Option<&'static Proto> {
+    let name = name.rsplit('!').next().unwrap_or(name);
+    PROTOTYPES.iter().find(|p| p.name == name)
+}
+
+/// An argument of traced call
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiArg {
+    /// The name in prototype, or `argN` without prototype
+    pub name: &'static str,
+    pub raw: usize,
+    /// Decoded value, such as the string pointed to, `None` if it's an integer or unreadable
+    pub decoded: Option<String>,
+}
+
+/// A call of the traced function
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiCall {
+    pub tid: tid_t,
+    pub function: Arc<str>,
+    pub address: usize,
+    /// Arguments read at the entry
+    pub args: Vec<ApiArg>,
+    /// Return value, `None` for the functions returning nothing
+    pub ret: Option<usize>,
+    pub ret_decoded: Option<String>,
+}
+
+impl ApiCall {
+    #[inline]
+    pub fn into_event(self) -> UEvent {
+        UEvent::custom(API_CALL_EVENT, self)
+    }
+}
+
+impl core::fmt::Display for ApiCall {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "~{} {}(", self.tid, self.function)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            match &arg.decoded {
+                Some(d) => write!(f, "{}={d}", arg.name)?,
+                None => write!(f, "{}={:#x}", arg.name, arg.raw)?,
+            }
+        }
+        f.write_str(")")?;
+        match (&self.ret_decoded, self.ret) {
+            (Some(d), _) => write!(f, " = {d}"),
+            (None, Some(r)) => write!(f, " = {r:#x}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+const RAW_ARG_NAMES: &[&str] = &[
+    "arg1", "arg2", "arg3", "arg4", "arg5", "arg6", "arg7", "arg8",
+];
+
+struct Api {
+    name: Arc<str>,
+    address: usize,
+    proto: Option<&'static Proto>,
+}
+
+/// Call waiting for return
+struct Pending {
+    api: usize,
+    args: Vec<ApiArg>,
+}
+
+/// Tracer of the API calls in a target
+pub struct ApiTracer {
+    hooks: ApiHooks<usize, Pending>,
+    apis: Vec<Api>,
+    /// Count of the arguments read for the functions without prototype, at most 8
+    pub raw_args: usize,
+    /// Max bytes of the strings decoded
+    pub max_string: usize,
+}
+
+impl ApiTracer {
+    pub fn new(target: Arc<dyn UDbgTarget>) -> Self {
+        Self {
+            hooks: ApiHooks::new(target),
+            apis: vec![],
+            raw_args: 4,
+            max_string: 260,
+        }
+    }
+
+    #[inline]
+    fn target(&self) -> &dyn UDbgTarget {
+        self.hooks.target().as_ref()
+    }
+
+    /// Hook the functions specified by `module!function`, returns the count of hooked ones,
+    /// could be called again after the modules loaded
+    pub fn trace<'a>(&mut self, functions: impl IntoIterator<Item = &'a str>) -> usize {
+        let mut count = 0;
+        for name in functions {
+            let address = match self.target().get_address_by_symbol(name) {
+                Some(a) => a,
+                None => {
+                    warn!("api trace: {name} not found");
+                    continue;
+                }
+            };
+            if self.apis.iter().any(|a| a.address == address) {
+                count += 1;
+                continue;
+            }
+            if self.hooks.hook(address, self.apis.len(), name) {
+                self.apis.push(Api {
+                    name: name.into(),
+                    address,
+                    proto: find_proto(name),
+                });
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Remove all the breakpoints set by this tracer
+    pub fn uninstall(&mut self) {
+        self.hooks.unhook_all();
+        self.apis.clear();
+    }
+
+    /// If the breakpoint at `address` is set by this tracer, the event should be continued
+    /// silently then
+    #[inline]
+    pub fn owns(&self, address: usize) -> bool {
+        self.hooks.owns(address)
+    }
+
+    /// Handle a breakpoint event, returns the call if a traced one is complete
+    pub fn on_breakpoint(
+        &mut self,
+        bp: &dyn UDbgBreakpoint,
+        ctx: &mut dyn TraceContext,
+    ) -> Option<ApiCall> {
+        let tid = self.target().base().event_tid.get();
+        match self.hooks.hit(bp.address(), tid)? {
+            ApiHit::Entry(api) => self.on_entry(api, tid, ctx),
+            ApiHit::Return(pending) => self.on_return(pending, tid, ctx),
+        }
+    }
+
+    /// Handle an event, returns the [`API_CALL_EVENT`] if a call traced
+    pub fn on_event(&mut self, event: &UEvent, ctx: &mut dyn TraceContext) -> Option<UEvent> {
+        match event {
+            UEvent::Breakpoint(bp) => self
+                .on_breakpoint(bp.as_ref(), ctx)
+                .map(ApiCall::into_event),
+            _ => None,
+        }
+    }
+
+    fn on_entry(&mut self, api: usize, tid: tid_t, ctx: &mut dyn TraceContext) -> Option<ApiCall> {
+        let arch = ctx.arch();
+        let regs: &dyn UDbgRegs = ctx.register()?;
+        let target = self.hooks.target().clone();
+        let arg = call_args(target.as_ref(), regs, arch);
+
+        let proto = self.apis[api].proto;
+        let args = match proto {
+            Some(p) => p
+                .args
+                .iter()
+                .enumerate()
+                .map(|(i, &(name, ty))| {
+                    let raw = arg(i + 1).unwrap_or_default();
+                    ApiArg {
+                        name,
+                        raw,
+                        decoded: self.decode(ty, raw),
+                    }
+                })
+                .collect(),
+            None => RAW_ARG_NAMES
+                .iter()
+                .take(self.raw_args)
+                .enumerate()
+                .map(|(i, &name)| ApiArg {
+                    name,
+                    raw: arg(i + 1).unwrap_or_default(),
+                    decoded: None,
+                })
+                .collect(),
+        };
+
+        if proto.map_or(false, |p| p.ret == ArgType::Void) {
+            return Some(self.call(api, tid, args, None));
+        }
+        self.hooks
+            .wait_return(tid, regs, arch, Pending { api, args });
+        None
+    }
+
+    fn on_return(
+        &mut self,
+        pending: Pending,
+        tid: tid_t,
+        ctx: &mut dyn TraceContext,
+    ) -> Option<ApiCall> {
+        let arch = ctx.arch();
+        let ret = ctx.register()?.get_reg(return_reg(arch))?.as_int();
+        Some(self.call(pending.api, tid, pending.args, Some(ret)))
+    }
+
+    fn call(&self, api: usize, tid: tid_t, args: Vec<ApiArg>, ret: Option<usize>) -> ApiCall {
+        let api = &self.apis[api];
+        ApiCall {
+            tid,
+            function: api.name.clone(),
+            address: api.address,
+            args,
+            ret,
+            ret_decoded: ret.and_then(|r| self.decode(api.proto?.ret, r)),
+        }
+    }
+
+    fn decode(&self, ty: ArgType, raw: usize) -> Option<String> {
+        Some(match ty {
+            ArgType::Int => (raw as u32 as i32).to_string(),
+            ArgType::UInt => format!("{:#x}", raw as u32),
+            ArgType::Bool => (raw as u32 != 0).to_string(),
+            ArgType::Status => format!("{:#010x}", raw as u32),
+            ArgType::Str if raw != 0 => {
+                let s = self.target().read_cstring(raw, self.max_string)?;
+                format!("{:?}", String::from_utf8_lossy(&s))
+            }
+            ArgType::WStr if raw != 0 => {
+                format!("{:?}", self.target().read_wstring(raw, self.max_string)?)
+            }
+            _ => return None,
+        })
+    }
+}
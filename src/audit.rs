@@ -0,0 +1,264 @@
+//!
+//! Audit log of the mutating operations performed through target, such as memory writes, register sets, injections, terminations.
+//!
+//! Each target has its own [`AuditLog`] in [`TargetBase::audit`], which is disabled by default,
+//! enable it and optionally attach a sink to persist the entries as they're recorded. The log keeps
+//! the last [`AuditLog::MAX_ENTRIES`] entries in memory, the sink receives all of them.
+//!
+
+use crate::{
+    prelude::*,
+    register::{general_regs, CpuReg},
+};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize)]
+pub enum AuditAction {
+    WriteMemory {
+        address: usize,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    /// Write to executable memory, includes software breakpoints and patches
+    WriteCode {
+        address: usize,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    /// A general register changed, recorded when the registers are written back to the thread
+    SetRegister {
+        tid: tid_t,
+        name: &'static str,
+        before: usize,
+        after: usize,
+    },
+    /// Memory allocated in target by [`crate::inject`]
+    VirtualAlloc {
+        address: usize,
+        size: usize,
+    },
+    VirtualFree {
+        address: usize,
+    },
+    /// Thread, code or library injected into target, `kind` is one of "thread", "code", "library"
+    Inject {
+        kind: String,
+        address: usize,
+        detail: String,
+    },
+    Terminate,
+    Detach,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub time: SystemTime,
+    pub pid: pid_t,
+    pub action: AuditAction,
+}
+
+impl core::fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        write!(f, "{time:.6}\t{}\t", self.pid)?;
+        match &self.action {
+            AuditAction::WriteMemory {
+                address,
+                before,
+                after,
+            } => write!(
+                f,
+                "write {address:x} {} -> {}",
+                hex::encode(before),
+                hex::encode(after)
+            ),
+            AuditAction::WriteCode {
+                address,
+                before,
+                after,
+            } => write!(
+                f,
+                "code {address:x} {} -> {}",
+                hex::encode(before),
+                hex::encode(after)
+            ),
+            AuditAction::SetRegister {
+                tid,
+                name,
+                before,
+                after,
+            } => write!(f, "reg ~{tid} {name} {before:x} -> {after:x}"),
+            AuditAction::VirtualAlloc { address, size } => write!(f, "alloc {address:x} {size:x}"),
+            AuditAction::VirtualFree { address } => write!(f, "free {address:x}"),
+            AuditAction::Inject {
+                kind,
+                address,
+                detail,
+            } => write!(f, "inject {kind} {address:x} {detail}"),
+            AuditAction::Terminate => write!(f, "terminate"),
+            AuditAction::Detach => write!(f, "detach"),
+        }
+    }
+}
+
+/// Append-only log of mutating operations
+#[derive(Default)]
+pub struct AuditLog {
+    enabled: AtomicBool,
+    entries: Mutex<VecDeque<AuditEntry>>,
+    sink: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl AuditLog {
+    /// Max entries kept in memory
+    pub const MAX_ENTRIES: usize = 0x1000;
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_enabled(&self, enable: bool) {
+        self.enabled.store(enable, Ordering::Relaxed);
+    }
+
+    /// Write each entry as a line to `sink` when it's recorded
+    pub fn set_sink(&self, sink: Option<Box<dyn Write + Send>>) {
+        *self.sink.lock() = sink;
+    }
+
+    pub fn record(&self, pid: pid_t, action: AuditAction) {
+        if !self.is_enabled() {
+            return;
+        }
+        let entry = AuditEntry {
+            time: SystemTime::now(),
+            pid,
+            action,
+        };
+        if let Some(sink) = self.sink.lock().as_mut() {
+            writeln!(sink, "{entry}")
+                .and_then(|_| sink.flush())
+                .log_error("audit sink");
+        }
+        let mut entries = self.entries.lock();
+        if entries.len() >= Self::MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Record the changed general registers between two contexts
+    pub fn record_regs(
+        &self,
+        pid: pid_t,
+        tid: tid_t,
+        arch: u32,
+        before: &dyn UDbgRegs,
+        after: &dyn UDbgRegs,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        for &(name, id) in general_regs(arch) {
+            if let (Some(CpuReg::Int(b)), Some(CpuReg::Int(a))) =
+                (before.get_reg(id), after.get_reg(id))
+            {
+                if a != b {
+                    self.record(
+                        pid,
+                        AuditAction::SetRegister {
+                            tid,
+                            name,
+                            before: b,
+                            after: a,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Snapshot of the last entries recorded
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.entries.lock().iter().cloned().collect()
+    }
+
+    /// Export the entries kept as text lines
+    pub fn export<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        for entry in self.entries.lock().iter() {
+            writeln!(w, "{entry}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Write memory and record the bytes before and after writing
+pub(crate) fn audit_write<T: ReadMemory + ?Sized>(
+    target: &T,
+    base: &TargetBase,
+    address: usize,
+    len: usize,
+    code: bool,
+    write: impl FnOnce() -> Option<usize>,
+) -> Option<usize> {
+    if !base.audit.is_enabled() {
+        return write();
+    }
+
+    let before = target.read_bytes(address, len);
+    let result = write();
+    if result.is_some() {
+        let after = target.read_bytes(address, len);
+        base.audit.record(
+            base.pid.get(),
+            if code {
+                AuditAction::WriteCode {
+                    address,
+                    before,
+                    after,
+                }
+            } else {
+                AuditAction::WriteMemory {
+                    address,
+                    before,
+                    after,
+                }
+            },
+        );
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_entries() {
+        let log = AuditLog::default();
+        log.record(1, AuditAction::Detach);
+        assert!(log.entries().is_empty());
+
+        log.set_enabled(true);
+        for address in 0..AuditLog::MAX_ENTRIES + 2 {
+            log.record(1, AuditAction::VirtualFree { address });
+        }
+        let entries = log.entries();
+        assert_eq!(entries.len(), AuditLog::MAX_ENTRIES);
+        assert!(matches!(
+            entries[0].action,
+            AuditAction::VirtualFree { address: 2 }
+        ));
+    }
+}
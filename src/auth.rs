@@ -0,0 +1,285 @@
+//!
+//! Authorization of the remote clients, required before exposing the process control on a socket.
+//!
+//! Each client presents a token of an [`ApiKey`], which is scoped to [`Scope::ReadOnly`] or
+//! [`Scope::Full`]. The operations may be rate limited per client by [`OpLimit`], and every
+//! operation requested is recorded in the audit trail, allowed or not. The trail keeps the last
+//! [`Authorizer::MAX_TRAIL`] entries in memory, persist all of them by a sink.
+//!
+
+use crate::prelude::*;
+
+use core::time::Duration;
+use spin::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    /// Inspect the target without changing it, such as reading memory and stack
+    ReadOnly,
+    /// Control the target, such as setting breakpoints, stepping, killing
+    Full,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Name of the client recorded in the audit trail
+    pub name: String,
+    pub token: String,
+    pub scope: Scope,
+}
+
+/// Max calls of an operation by a client in a window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpLimit {
+    pub max_calls: usize,
+    pub window: Duration,
+}
+
+/// The client authenticated by a token
+#[derive(Debug, Clone, Serialize)]
+pub struct Principal {
+    pub name: String,
+    pub scope: Scope,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteAuditEntry {
+    pub time: SystemTime,
+    /// Name of the client, empty if it's not authenticated
+    pub client: String,
+    pub operation: String,
+    /// The reason if it's denied
+    pub denied: Option<String>,
+}
+
+impl core::fmt::Display for RemoteAuditEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let client = if self.client.is_empty() {
+            "-"
+        } else {
+            &self.client
+        };
+        write!(f, "{time:.6}\t{client}\t{}", self.operation)?;
+        match &self.denied {
+            Some(reason) => write!(f, "\tdenied: {reason}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Compare the tokens in constant time
+fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |r, (a, b)| r | (a ^ b)) == 0
+}
+
+/// The keys, limits and audit trail of a remote server
+#[derive(Default)]
+pub struct Authorizer {
+    keys: Vec<ApiKey>,
+    limits: HashMap<String, OpLimit>,
+    default_limit: Option<OpLimit>,
+    /// The start and calls of current window, by client and the operation limited, the
+    /// operations without their own limit are counted together by the empty key
+    windows: Mutex<HashMap<(String, String), (Instant, usize)>>,
+    trail: Mutex<VecDeque<RemoteAuditEntry>>,
+    sink: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl Authorizer {
+    /// Max entries of the audit trail kept in memory
+    pub const MAX_TRAIL: usize = 0x1000;
+
+    pub fn new(keys: Vec<ApiKey>) -> Self {
+        Self {
+            keys,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_key(&mut self, key: ApiKey) {
+        self.keys.push(key);
+    }
+
+    /// Limit an operation of each client, None to remove the limit
+    pub fn set_limit(&mut self, operation: &str, limit: Option<OpLimit>) {
+        match limit {
+            Some(limit) => self.limits.insert(operation.into(), limit),
+            None => self.limits.remove(operation),
+        };
+    }
+
+    /// Limit the operations without their own limit, their calls are counted together
+    pub fn set_default_limit(&mut self, limit: Option<OpLimit>) {
+        self.default_limit = limit;
+    }
+
+    /// Write each entry of audit trail as a line to `sink` when it's recorded
+    pub fn set_sink(&self, sink: Option<Box<dyn Write + Send>>) {
+        *self.sink.lock() = sink;
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<Principal> {
+        self.keys
+            .iter()
+            .find(|k| token_eq(&k.token, token))
+            .map(|k| Principal {
+                name: k.name.clone(),
+                scope: k.scope,
+            })
+    }
+
+    /// Check and record an operation requested by the client, `mutating` operations require
+    /// [`Scope::Full`]
+    pub fn authorize(
+        &self,
+        client: Option<&Principal>,
+        operation: &str,
+        mutating: bool,
+    ) -> UDbgResult<()> {
+        let result = self.check(client, operation, mutating);
+        self.record(RemoteAuditEntry {
+            time: SystemTime::now(),
+            client: client.map(|c| c.name.clone()).unwrap_or_default(),
+            operation: operation.into(),
+            denied: result.as_ref().err().cloned(),
+        });
+        result.map_err(UDbgError::AccessDenied)
+    }
+
+    fn check(
+        &self,
+        client: Option<&Principal>,
+        operation: &str,
+        mutating: bool,
+    ) -> Result<(), String> {
+        let client = client.ok_or("not authenticated")?;
+        if mutating && client.scope != Scope::Full {
+            return Err("read-only token".into());
+        }
+        // the operations are named by the client, only the ones limited are counted apart
+        let (key, limit) = match self.limits.get_key_value(operation) {
+            Some((key, limit)) => (key.as_str(), limit),
+            None => match self.default_limit.as_ref() {
+                Some(limit) => ("", limit),
+                None => return Ok(()),
+            },
+        };
+        let mut windows = self.windows.lock();
+        let (start, calls) = windows
+            .entry((client.name.clone(), key.into()))
+            .or_insert_with(|| (Instant::now(), 0));
+        if start.elapsed() >= limit.window {
+            *start = Instant::now();
+            *calls = 0;
+        }
+        if *calls >= limit.max_calls {
+            return Err(format!(
+                "rate limit {} per {:?}",
+                limit.max_calls, limit.window
+            ));
+        }
+        *calls += 1;
+        Ok(())
+    }
+
+    fn record(&self, entry: RemoteAuditEntry) {
+        if let Some(sink) = self.sink.lock().as_mut() {
+            writeln!(sink, "{entry}")
+                .and_then(|_| sink.flush())
+                .log_error("remote audit sink");
+        }
+        let mut trail = self.trail.lock();
+        if trail.len() >= Self::MAX_TRAIL {
+            trail.pop_front();
+        }
+        trail.push_back(entry);
+    }
+
+    /// Snapshot of the last entries of audit trail
+    pub fn trail(&self) -> Vec<RemoteAuditEntry> {
+        self.trail.lock().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str, token: &str, scope: Scope) -> ApiKey {
+        ApiKey {
+            name: name.into(),
+            token: token.into(),
+            scope,
+        }
+    }
+
+    #[test]
+    fn scope() {
+        let auth = Authorizer::new(vec![
+            key("viewer", "ro-token", Scope::ReadOnly),
+            key("admin", "rw-token", Scope::Full),
+        ]);
+        assert!(auth.authenticate("ro-tokeN").is_none());
+        assert!(auth.authenticate("").is_none());
+        let viewer = auth.authenticate("ro-token").unwrap();
+        let admin = auth.authenticate("rw-token").unwrap();
+        assert_eq!(admin.name, "admin");
+
+        assert!(auth.authorize(None, "readMemory", false).is_err());
+        assert!(auth.authorize(Some(&viewer), "readMemory", false).is_ok());
+        assert!(auth.authorize(Some(&viewer), "writeMemory", true).is_err());
+        assert!(auth.authorize(Some(&admin), "writeMemory", true).is_ok());
+
+        let trail = auth.trail();
+        assert_eq!(trail.len(), 4);
+        assert_eq!(trail[0].client, "");
+        assert!(trail[2].denied.is_some());
+        assert!(trail[3].denied.is_none());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut auth = Authorizer::new(vec![
+            key("a", "token-a", Scope::Full),
+            key("b", "token-b", Scope::Full),
+        ]);
+        let limit = |max_calls| OpLimit {
+            max_calls,
+            window: Duration::from_secs(60),
+        };
+        auth.set_limit("pause", Some(limit(1)));
+        auth.set_default_limit(Some(limit(2)));
+        let a = auth.authenticate("token-a").unwrap();
+        let b = auth.authenticate("token-b").unwrap();
+
+        assert!(auth.authorize(Some(&a), "pause", true).is_ok());
+        assert!(auth.authorize(Some(&a), "pause", true).is_err());
+        // counted per client
+        assert!(auth.authorize(Some(&b), "pause", true).is_ok());
+
+        // the operations without their own limit are counted together
+        assert!(auth.authorize(Some(&a), "stackTrace", false).is_ok());
+        assert!(auth.authorize(Some(&a), "variables", false).is_ok());
+        assert!(auth.authorize(Some(&a), "stackTrace", false).is_err());
+
+        auth.set_default_limit(None);
+        assert!(auth.authorize(Some(&a), "stackTrace", false).is_ok());
+    }
+
+    #[test]
+    fn max_trail() {
+        let auth = Authorizer::default();
+        for _ in 0..Authorizer::MAX_TRAIL + 1 {
+            auth.authorize(None, "next", true).ok();
+        }
+        assert_eq!(auth.trail().len(), Authorizer::MAX_TRAIL);
+    }
+}
@@ -0,0 +1,171 @@
+//!
+//! Named bookmarks on addresses and the navigation history over the visited addresses, shared by frontends.
+//!
+//! Each target has its own [`Bookmarks`] in [`TargetBase::bookmarks`],
+//! the module-relative bookmarks are persisted with the [`crate::project::Project`] of the module.
+//!
+
+use crate::prelude::*;
+
+use spin::{Mutex, MutexGuard};
+
+/// Max addresses kept in navigation history
+pub const MAX_HISTORY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BookmarkLocation {
+    Address(usize),
+    /// Relative to module, survives the relocation
+    Module {
+        module: String,
+        rva: usize,
+    },
+}
+
+impl BookmarkLocation {
+    /// Module-relative location if the address is in a module
+    pub fn of_address<T: UDbgTarget + ?Sized>(target: &T, address: usize) -> Self {
+        match target.find_module(address) {
+            Some(m) => Self::Module {
+                module: m.data().name.to_string(),
+                rva: address - m.data().base,
+            },
+            None => Self::Address(address),
+        }
+    }
+
+    pub fn resolve<T: UDbgTarget + ?Sized>(&self, target: &T) -> Option<usize> {
+        match self {
+            Self::Address(a) => Some(*a),
+            Self::Module { module, rva } => target.get_module(module).map(|m| m.data().base + rva),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub name: String,
+    pub location: BookmarkLocation,
+    #[serde(default)]
+    pub note: String,
+}
+
+/// Back/forward history over the addresses visited by consumer
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Navigation {
+    back: Vec<usize>,
+    forward: Vec<usize>,
+    current: Option<usize>,
+}
+
+impl Navigation {
+    #[inline]
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    /// Visit a new address, the forward history is dropped
+    pub fn visit(&mut self, address: usize) {
+        if self.current == Some(address) {
+            return;
+        }
+        if let Some(c) = self.current.replace(address) {
+            self.back.push(c);
+            if self.back.len() > MAX_HISTORY {
+                self.back.remove(0);
+            }
+        }
+        self.forward.clear();
+    }
+
+    pub fn back(&mut self) -> Option<usize> {
+        let address = self.back.pop()?;
+        self.forward.extend(self.current.replace(address));
+        Some(address)
+    }
+
+    pub fn forward(&mut self) -> Option<usize> {
+        let address = self.forward.pop()?;
+        self.back.extend(self.current.replace(address));
+        Some(address)
+    }
+
+    #[inline]
+    pub fn can_back(&self) -> bool {
+        !self.back.is_empty()
+    }
+
+    #[inline]
+    pub fn can_forward(&self) -> bool {
+        !self.forward.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.back.clear();
+        self.forward.clear();
+        self.current = None;
+    }
+}
+
+/// Bookmarks and navigation history of a target
+#[derive(Debug, Default)]
+pub struct Bookmarks {
+    items: Mutex<Vec<Bookmark>>,
+    navigation: Mutex<Navigation>,
+}
+
+impl Bookmarks {
+    /// Add a bookmark, the one with the same name is replaced
+    pub fn add(&self, bookmark: Bookmark) {
+        let mut items = self.items.lock();
+        items.retain(|b| b.name != bookmark.name);
+        items.push(bookmark);
+    }
+
+    pub fn remove(&self, name: &str) -> Option<Bookmark> {
+        let mut items = self.items.lock();
+        let i = items.iter().position(|b| b.name == name)?;
+        Some(items.remove(i))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Bookmark> {
+        self.items.lock().iter().find(|b| b.name == name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Bookmark> {
+        self.items.lock().clone()
+    }
+
+    /// Bookmarks in a module, used to persist them with project
+    pub fn in_module(&self, module: &str) -> Vec<Bookmark> {
+        self.items
+            .lock()
+            .iter()
+            .filter(|b| match &b.location {
+                BookmarkLocation::Module { module: m, .. } => m.eq_ignore_ascii_case(module),
+                _ => false,
+            })
+            .cloned()
+            .collect()
+    }
+
+    #[inline]
+    pub fn navigation(&self) -> MutexGuard<'_, Navigation> {
+        self.navigation.lock()
+    }
+
+    #[inline]
+    pub fn visit(&self, address: usize) {
+        self.navigation.lock().visit(address)
+    }
+
+    #[inline]
+    pub fn back(&self) -> Option<usize> {
+        self.navigation.lock().back()
+    }
+
+    #[inline]
+    pub fn forward(&self) -> Option<usize> {
+        self.navigation.lock().forward()
+    }
+}
@@ -0,0 +1,176 @@
+//!
+//! Interop with the Breakpad/Crashpad crash-reporting pipelines.
+//!
+//! [`write_sym`] writes the symbols of a loaded module as a Breakpad `.sym` file, and [`save_sym`]
+//! puts it in the layout of symbol store, `<debug file>/<debug id>/<name>.sym`.
+//! The Breakpad and Crashpad streams of minidumps are read by
+//! [`crate::minidump::MiniDumpTarget::crash_info`].
+//!
+
+use crate::{elf::ElfHelper, prelude::*};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Identifiers of module used by Breakpad to match the symbol files
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleId {
+    /// PDB GUID and age on Windows, the first 16 bytes of build id and 0 on others
+    pub debug_id: String,
+    /// The PDB name on Windows, or the module name
+    pub debug_file: String,
+    /// The full build id of ELF
+    pub code_id: Option<String>,
+}
+
+pub fn module_id(module: &dyn UDbgModule) -> ModuleId {
+    let data = module.data();
+    if let Some(syms) = module.symbols_data().filter(|s| !s.pdb_sig.is_empty()) {
+        let pdb = syms.pdb_name.rsplit(['\\', '/']).next().unwrap_or_default();
+        return ModuleId {
+            debug_id: syms.pdb_sig.to_ascii_uppercase(),
+            debug_file: if pdb.is_empty() {
+                data.name.to_string()
+            } else {
+                pdb.into()
+            },
+            code_id: None,
+        };
+    }
+
+    let build_id = std::fs::read(data.path.as_ref()).ok().and_then(|file| {
+        let elf = ElfHelper::parse(&file)?;
+        elf.build_id(&file).map(<[u8]>::to_vec)
+    });
+    let debug_id = match build_id.as_ref() {
+        Some(id) => {
+            let mut guid = [0u8; 16];
+            let len = id.len().min(16);
+            guid[..len].copy_from_slice(&id[..len]);
+            // the first 3 fields of GUID are little endian
+            guid[..4].reverse();
+            guid[4..6].reverse();
+            guid[6..8].reverse();
+            format!("{}0", hex::encode_upper(guid))
+        }
+        None => "0".repeat(33),
+    };
+    ModuleId {
+        debug_id,
+        debug_file: data.name.to_string(),
+        code_id: build_id.map(hex::encode),
+    }
+}
+
+fn os_name(module: &dyn UDbgModule) -> &'static str {
+    if module
+        .symbols_data()
+        .map_or(false, |s| !s.pdb_sig.is_empty())
+    {
+        return "windows";
+    }
+    if cfg!(windows) {
+        "windows"
+    } else if cfg!(target_os = "macos") {
+        "mac"
+    } else {
+        "Linux"
+    }
+}
+
+fn arch_name(arch: &str) -> &str {
+    match arch {
+        "aarch64" => "arm64",
+        arch => arch,
+    }
+}
+
+/// Write the symbols of module as a Breakpad symbol file, with the source lines if available
+pub fn write_sym<W: Write>(module: &dyn UDbgModule, mut w: W) -> UDbgResult<()> {
+    let data = module.data();
+    let id = module_id(module);
+    writeln!(
+        w,
+        "MODULE {} {} {} {}",
+        os_name(module),
+        arch_name(data.arch),
+        id.debug_id,
+        id.debug_file
+    )?;
+    if let Some(code_id) = id.code_id.as_ref() {
+        writeln!(w, "INFO CODE_ID {code_id} {}", data.name)?;
+    }
+
+    let mut symbols = module
+        .enum_symbol(None)
+        .map(|i| i.collect::<Vec<_>>())
+        .unwrap_or_default();
+    symbols.sort_by_key(|s| s.offset);
+    symbols.dedup_by_key(|s| s.offset);
+
+    // the FILE records must precede the line records, so the functions are buffered
+    let source = module.symbol_file();
+    let mut files = HashMap::<Arc<str>, usize>::new();
+    let mut records = vec![];
+    for s in symbols.iter() {
+        let flags = SymbolFlags::from_bits_truncate(s.flags);
+        if !flags.contains(SymbolFlags::FUNCTION) || s.len == 0 {
+            records.push(format!("PUBLIC {:x} 0 {}", s.offset, s.name));
+            continue;
+        }
+        records.push(format!("FUNC {:x} {:x} 0 {}", s.offset, s.len, s.name));
+        let source = match source.as_ref() {
+            Some(f) => f,
+            None => continue,
+        };
+        let end = s.offset + s.len;
+        let lines = (s.offset..end)
+            .filter_map(|offset| Some((offset, source.find_line(offset)?)))
+            .collect::<Vec<_>>();
+        for (i, (offset, line)) in lines.iter().enumerate() {
+            let next = lines.get(i + 1).map_or(end, |l| l.0);
+            let count = files.len();
+            let file = *files.entry(line.file.clone()).or_insert(count);
+            records.push(format!(
+                "{offset:x} {:x} {} {file}",
+                next - offset,
+                line.line
+            ));
+        }
+    }
+
+    let mut files = files.into_iter().collect::<Vec<_>>();
+    files.sort_by_key(|f| f.1);
+    for (path, i) in files {
+        writeln!(w, "FILE {i} {path}")?;
+    }
+    for r in records {
+        writeln!(w, "{r}")?;
+    }
+    Ok(())
+}
+
+/// Write the symbol file of module into a Breakpad symbol store, returns the path of it
+pub fn save_sym(module: &dyn UDbgModule, store: impl AsRef<Path>) -> UDbgResult<PathBuf> {
+    let id = module_id(module);
+    let stem = Path::new(&id.debug_file)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| id.debug_file.clone());
+    let dir = store.as_ref().join(&id.debug_file).join(&id.debug_id);
+    std::fs::create_dir_all(&dir)?;
+    // the name of symbol file keeps the extension except .pdb
+    let name = if id.debug_file.to_ascii_lowercase().ends_with(".pdb") {
+        format!("{stem}.sym")
+    } else {
+        format!("{}.sym", id.debug_file)
+    };
+    let path = dir.join(name);
+    let mut w = BufWriter::new(File::create(&path)?);
+    write_sym(module, &mut w)?;
+    w.flush()?;
+    Ok(path)
+}
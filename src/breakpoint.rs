@@ -4,10 +4,17 @@
 
 use std::{
     cell::Cell,
+    collections::HashMap,
     sync::{Arc, Weak},
 };
 
-use crate::{error::*, os::tid_t, register::*, target::UDbgTarget};
+use crate::{
+    consts::*,
+    error::*,
+    os::tid_t,
+    register::*,
+    target::{TargetUtil, UDbgTarget},
+};
 use cfg_if::*;
 
 pub type BpID = isize;
@@ -119,6 +126,17 @@ impl HwbpLen {
         }
     }
 
+    /// Length of a breakpoint watching `size` bytes, 1, 2, 4 or 8
+    pub fn from_size(size: usize) -> Option<Self> {
+        Some(match size {
+            1 => Self::L1,
+            2 => Self::L2,
+            4 => Self::L4,
+            8 => Self::L8,
+            _ => return None,
+        })
+    }
+
     pub fn encode(self) -> u8 {
         if crate::consts::IS_X86 {
             return match self {
@@ -146,6 +164,8 @@ pub struct BpOpt {
     pub temp: bool,
     pub enable: bool,
     pub tid: Option<tid_t>,
+    /// Hit only if the stack pointer is not below it, such as at the return of a frame
+    pub sp: Option<usize>,
 }
 
 impl From<usize> for BpOpt {
@@ -167,6 +187,7 @@ impl BpOpt {
             temp: false,
             enable: true,
             tid: None,
+            sp: None,
             rw: None,
             len: None,
             table: false,
@@ -179,6 +200,7 @@ impl BpOpt {
             temp: false,
             enable: true,
             tid: None,
+            sp: None,
             rw: ty.into(),
             len,
             table: false,
@@ -200,6 +222,11 @@ impl BpOpt {
         self
     }
 
+    pub fn stack(mut self, sp: usize) -> Self {
+        self.sp = Some(sp);
+        self
+    }
+
     pub fn len(mut self, len: HwbpLen) -> Self {
         self.len = len.into();
         self
@@ -280,12 +307,19 @@ pub struct Breakpoint {
     pub bp_type: InnerBpType,
     pub hit_count: Cell<usize>,
     pub hit_tid: Option<tid_t>,
+    pub hit_sp: Option<usize>,
 
     pub target: Weak<dyn UDbgTarget>,
     pub common: *const crate::os::TargetCommon,
 }
 
 impl Breakpoint {
+    /// Whether the hit in thread `tid` at stack pointer `sp` is reported to user
+    #[inline]
+    pub fn reports_hit(&self, tid: tid_t, sp: usize) -> bool {
+        self.hit_tid.map_or(true, |t| t == tid) && self.hit_sp.map_or(true, |s| sp >= s)
+    }
+
     pub fn get_hwbp_len(&self) -> Option<usize> {
         if let InnerBpType::Hard(info) = self.bp_type {
             Some(match info.len as _ {
@@ -384,6 +418,34 @@ impl UDbgBreakpoint for Breakpoint {
     }
 }
 
+/// A hardware breakpoint, see [`crate::target::TargetUtil::add_hw_bp`]
+#[derive(Clone)]
+pub struct HwBreakpoint {
+    pub bp: Arc<dyn UDbgBreakpoint>,
+    pub kind: HwbpType,
+    /// Bytes watched from the address
+    pub size: usize,
+    /// Index of the debug register, e.g. DR0-DR3 on x86
+    pub slot: usize,
+    /// The only thread it's set for, None for all threads
+    pub tid: Option<tid_t>,
+}
+
+impl core::ops::Deref for HwBreakpoint {
+    type Target = dyn UDbgBreakpoint;
+
+    fn deref(&self) -> &Self::Target {
+        self.bp.as_ref()
+    }
+}
+
+impl HwBreakpoint {
+    /// If the event is a hit of this breakpoint
+    pub fn is_hit(&self, event: &crate::event::UEvent) -> bool {
+        matches!(event, crate::event::UEvent::Breakpoint(bp) if bp.get_id() == self.bp.get_id())
+    }
+}
+
 pub trait BreakpointManager {
     fn add_breakpoint(&self, opt: BpOpt) -> UDbgResult<Arc<dyn UDbgBreakpoint>> {
         Err(UDbgError::NotSupport)
@@ -399,3 +461,95 @@ pub trait BreakpointManager {
         vec![]
     }
 }
+
+/// Return address of the call at the entry of function, in the link register on ARM, or at the
+/// top of stack
+pub fn return_address<T: UDbgTarget + ?Sized>(
+    target: &T,
+    regs: &dyn UDbgRegs,
+    arch: u32,
+) -> Option<usize> {
+    match arch {
+        ARCH_ARM => regs.get_reg(regid::ARM_REG_LR).map(|r| r.as_int()),
+        ARCH_ARM64 => regs.get_reg(regid::ARM64_REG_LR).map(|r| r.as_int()),
+        _ => target.read_ptr(regs.get_reg(regid::COMM_REG_SP)?.as_int()),
+    }
+}
+
+/// Breakpoints at the return addresses and the calls waiting for them, shared by the hooks and
+/// the tracers. A breakpoint is set for the first waiter of its address, and removed with the last
+pub struct ReturnWaiters<W> {
+    returns: HashMap<usize, (Arc<dyn UDbgBreakpoint>, Vec<(tid_t, W)>)>,
+}
+
+impl<W> Default for ReturnWaiters<W> {
+    fn default() -> Self {
+        Self {
+            returns: HashMap::new(),
+        }
+    }
+}
+
+impl<W> ReturnWaiters<W> {
+    /// If the address is a return address waited
+    #[inline]
+    pub fn contains(&self, address: usize) -> bool {
+        self.returns.contains_key(&address)
+    }
+
+    /// Wait for the thread returning to `ret`, fails if the breakpoint can't be set
+    pub fn wait<T: UDbgTarget + ?Sized>(
+        &mut self,
+        target: &T,
+        ret: usize,
+        tid: tid_t,
+        waiter: W,
+    ) -> UDbgResult<()> {
+        if !self.returns.contains_key(&ret) {
+            let bp = target.add_breakpoint(ret.into())?;
+            self.returns.insert(ret, (bp, vec![]));
+        }
+        if let Some((_, waiters)) = self.returns.get_mut(&ret) {
+            waiters.push((tid, waiter));
+        }
+        Ok(())
+    }
+
+    /// Take the waiter of the thread returned to `address`, the innermost call of the recursive
+    /// ones returns first
+    pub fn take(&mut self, address: usize, tid: tid_t) -> Option<W> {
+        let (_, waiters) = self.returns.get_mut(&address)?;
+        let i = waiters.iter().rposition(|w| w.0 == tid)?;
+        let waiter = waiters.remove(i).1;
+        if waiters.is_empty() {
+            self.remove(address);
+        }
+        Some(waiter)
+    }
+
+    /// Keep the waiters specified by the predicate only
+    pub fn retain(&mut self, mut f: impl FnMut(tid_t, &W) -> bool) {
+        for (_, waiters) in self.returns.values_mut() {
+            waiters.retain(|(tid, w)| f(*tid, w));
+        }
+        self.returns.retain(|_, (bp, waiters)| {
+            if waiters.is_empty() {
+                bp.remove().log_error("remove return bp");
+            }
+            !waiters.is_empty()
+        });
+    }
+
+    /// Remove all the breakpoints and the waiters
+    pub fn clear(&mut self) {
+        for (_, (bp, _)) in self.returns.drain() {
+            bp.remove().log_error("remove return bp");
+        }
+    }
+
+    fn remove(&mut self, address: usize) {
+        if let Some((bp, _)) = self.returns.remove(&address) {
+            bp.remove().log_error("remove return bp");
+        }
+    }
+}
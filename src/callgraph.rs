@@ -0,0 +1,201 @@
+//!
+//! Dynamic call graph built from trace data.
+//!
+//! [`CallGraph`] takes the calls as caller, callee and call site triples, from the
+//! [`crate::functrace`] records or any other tracer, and counts the edges between functions.
+//! It can be queried per function, and exported to DOT or JSON.
+//!
+
+use crate::{functrace::FuncCall, prelude::*};
+
+use core::fmt::Write;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Max example call sites kept for each edge
+const MAX_SITES: usize = 8;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallEdge {
+    /// The caller function, 0 for the calls from the untraced code
+    pub caller: usize,
+    pub callee: usize,
+    pub count: usize,
+    /// Some of the call sites, as the return addresses
+    pub sites: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallNode {
+    pub address: usize,
+    pub name: String,
+    /// Times this function was called
+    pub calls: usize,
+}
+
+#[derive(Serialize)]
+struct GraphData<'a> {
+    nodes: Vec<CallNode>,
+    edges: Vec<&'a CallEdge>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallGraph {
+    names: HashMap<usize, Arc<str>>,
+    edges: BTreeMap<(usize, usize), CallEdge>,
+}
+
+impl CallGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build from the calls recorded by [`crate::functrace::FuncTracer`]
+    pub fn from_calls<'a>(calls: impl IntoIterator<Item = &'a FuncCall>) -> Self {
+        let mut result = Self::new();
+        result.add_calls(calls);
+        result
+    }
+
+    pub fn add_calls<'a>(&mut self, calls: impl IntoIterator<Item = &'a FuncCall>) {
+        for c in calls {
+            if !c.function.is_empty() {
+                self.set_name(c.address, c.function.clone());
+            }
+            self.add_call(c.caller, c.address, c.call_site);
+        }
+    }
+
+    /// Record a call, `caller` is None if it's from the untraced code
+    pub fn add_call(&mut self, caller: Option<usize>, callee: usize, site: usize) {
+        let caller = caller.unwrap_or(0);
+        let edge = self
+            .edges
+            .entry((caller, callee))
+            .or_insert_with(|| CallEdge {
+                caller,
+                callee,
+                count: 0,
+                sites: vec![],
+            });
+        edge.count += 1;
+        if edge.sites.len() < MAX_SITES && !edge.sites.contains(&site) {
+            edge.sites.push(site);
+        }
+    }
+
+    pub fn set_name(&mut self, address: usize, name: impl Into<Arc<str>>) {
+        self.names.insert(address, name.into());
+    }
+
+    /// Name the functions without name by the symbols of target
+    pub fn resolve_names<T: UDbgTarget + ?Sized>(&mut self, target: &T) {
+        for f in self.functions() {
+            if f != 0 && !self.names.contains_key(&f) {
+                if let Some(name) = target.get_symbol_string(f) {
+                    self.names.insert(f, name.into());
+                }
+            }
+        }
+    }
+
+    /// Name of function, or its address if unknown
+    pub fn name(&self, address: usize) -> String {
+        match self.names.get(&address) {
+            Some(name) => name.to_string(),
+            None if address == 0 => "<untraced>".into(),
+            None => format!("sub_{address:x}"),
+        }
+    }
+
+    /// All the functions in graph, sorted by address
+    pub fn functions(&self) -> Vec<usize> {
+        let mut result = self
+            .edges
+            .keys()
+            .flat_map(|&(caller, callee)| [caller, callee])
+            .collect::<Vec<_>>();
+        result.sort();
+        result.dedup();
+        result
+    }
+
+    pub fn edges(&self) -> impl Iterator<Item = &CallEdge> {
+        self.edges.values()
+    }
+
+    /// The edges from the callers of function, from the most frequent
+    pub fn callers(&self, function: usize) -> Vec<&CallEdge> {
+        let mut result = self
+            .edges
+            .values()
+            .filter(|e| e.callee == function)
+            .collect::<Vec<_>>();
+        result.sort_by_key(|e| core::cmp::Reverse(e.count));
+        result
+    }
+
+    /// The edges to the callees of function, from the most frequent
+    pub fn callees(&self, function: usize) -> Vec<&CallEdge> {
+        let mut result = self
+            .edges
+            .range((function, 0)..=(function, usize::MAX))
+            .map(|(_, e)| e)
+            .collect::<Vec<_>>();
+        result.sort_by_key(|e| core::cmp::Reverse(e.count));
+        result
+    }
+
+    /// Find the function by name
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .find(|(_, n)| n.as_ref() == name)
+            .map(|(a, _)| *a)
+    }
+
+    fn nodes(&self) -> Vec<CallNode> {
+        self.functions()
+            .into_iter()
+            .map(|address| CallNode {
+                address,
+                name: self.name(address),
+                calls: self.callers(address).iter().map(|e| e.count).sum(),
+            })
+            .collect()
+    }
+
+    /// Graphviz DOT, the edges are labeled with the call counts
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph callgraph {\n    node [shape=box];\n");
+        for n in self.nodes() {
+            writeln!(
+                out,
+                "    \"{:x}\" [label=\"{}\\n{} calls\"];",
+                n.address,
+                n.name.replace('"', "\\\""),
+                n.calls
+            )
+            .ok();
+        }
+        for e in self.edges() {
+            writeln!(
+                out,
+                "    \"{:x}\" -> \"{:x}\" [label=\"{}\"];",
+                e.caller, e.callee, e.count
+            )
+            .ok();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// JSON with the `nodes` and `edges`
+    pub fn to_json(&self) -> UDbgResult<String> {
+        let data = GraphData {
+            nodes: self.nodes(),
+            edges: self.edges().collect(),
+        };
+        serde_json::to_string_pretty(&data).map_err(|err| err.to_string().into())
+    }
+}
@@ -223,7 +223,7 @@ impl dyn UDbgTarget {
         }
     }
 
-    pub fn disasm<'a>(
+    pub fn cs_disasm<'a>(
         &self,
         cs: &'a Capstone,
         address: usize,
@@ -245,4 +245,13 @@ impl dyn UDbgTarget {
             Err(vec![])
         }
     }
+
+    #[deprecated(note = "renamed to `cs_disasm`")]
+    pub fn disasm<'a>(
+        &self,
+        cs: &'a Capstone,
+        address: usize,
+    ) -> Result<Instructions<'a>, Vec<u8>> {
+        self.cs_disasm(cs, address)
+    }
 }
@@ -0,0 +1,139 @@
+//!
+//! Comments attached to addresses, shown inline in the disassembly output.
+//!
+//! Each target has its own [`Comments`] in [`TargetBase::comments`]. The user comments are merged with
+//! the ones imported from map files and the source lines in the symbol file of the module,
+//! see [`comments_at`]. The module-relative user comments are persisted with [`crate::project::Project`].
+//!
+
+use crate::prelude::*;
+
+use core::ops::Range;
+use spin::RwLock;
+use std::collections::BTreeMap;
+
+/// Where a comment comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CommentSource {
+    User,
+    /// Source file and line from the symbol file, generated on demand
+    SourceLine,
+    /// Imported from map files or the exported databases of other tools
+    MapFile,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Comment {
+    pub text: String,
+    pub source: CommentSource,
+}
+
+/// Comments of a target, at most one comment per source for an address
+#[derive(Debug, Default)]
+pub struct Comments {
+    items: RwLock<BTreeMap<usize, Vec<Comment>>>,
+}
+
+impl Comments {
+    /// Set the user comment of an address, it's removed if the text is empty
+    pub fn set(&self, address: usize, text: &str) {
+        if text.is_empty() {
+            self.remove(address, Some(CommentSource::User));
+        } else {
+            self.add(address, text, CommentSource::User);
+        }
+    }
+
+    /// Add a comment, the existing one from the same source is replaced
+    pub fn add(&self, address: usize, text: &str, source: CommentSource) {
+        let mut items = self.items.write();
+        let list = items.entry(address).or_default();
+        list.retain(|c| c.source != source);
+        list.push(Comment {
+            text: text.into(),
+            source,
+        });
+    }
+
+    /// Remove the comments from `source` of an address, or all if None
+    pub fn remove(&self, address: usize, source: Option<CommentSource>) {
+        let mut items = self.items.write();
+        if let Some(list) = items.get_mut(&address) {
+            list.retain(|c| source.map_or(false, |s| c.source != s));
+            if list.is_empty() {
+                items.remove(&address);
+            }
+        }
+    }
+
+    pub fn get(&self, address: usize) -> Vec<Comment> {
+        self.items.read().get(&address).cloned().unwrap_or_default()
+    }
+
+    /// Comments in the address range, in the order of address
+    pub fn in_range(&self, range: Range<usize>) -> Vec<(usize, Comment)> {
+        self.items
+            .read()
+            .range(range)
+            .flat_map(|(&a, list)| list.iter().map(move |c| (a, c.clone())))
+            .collect()
+    }
+
+    /// Drop all the comments from `source`, e.g. before importing a map file again
+    pub fn clear_source(&self, source: CommentSource) {
+        let mut items = self.items.write();
+        items.retain(|_, list| {
+            list.retain(|c| c.source != source);
+            !list.is_empty()
+        });
+    }
+}
+
+/// The stored comments of an address, and the source line which starts there if the symbol file has line info
+pub fn comments_at<T: UDbgTarget + ?Sized>(target: &T, address: usize) -> Vec<Comment> {
+    let mut result = target.base().comments.get(address);
+    let line = target.find_module(address).and_then(|m| {
+        let offset = address.checked_sub(m.data().base)?;
+        m.symbol_file()?.find_line(offset as u32)
+    });
+    if let Some(line) = line {
+        result.push(Comment {
+            text: format!("{}:{}", line.file, line.line),
+            source: CommentSource::SourceLine,
+        });
+    }
+    result
+}
+
+/// An instruction in the disassembly output
+#[derive(Debug, Clone, Serialize)]
+pub struct DisasmLine {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    /// Formatted instruction
+    pub text: String,
+    /// Symbol of the address, if it's the beginning of a symbol
+    pub symbol: Option<String>,
+    pub comments: Vec<Comment>,
+}
+
+impl DisasmLine {
+    /// Build a line with the symbol and comments of the address from target
+    pub fn new<T: UDbgTarget + ?Sized>(
+        target: &T,
+        address: usize,
+        bytes: Vec<u8>,
+        text: String,
+    ) -> Self {
+        Self {
+            address,
+            bytes,
+            text,
+            symbol: target
+                .get_symbol_(address, Some(0))
+                .filter(|s| !s.symbol.is_empty())
+                .map(|s| format!("{}!{}", s.module, s.symbol)),
+            comments: comments_at(target, address),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//!
+//! Compare the same module between two targets, e.g. two instances of a process,
+//! or a process and the [`crate::pe::PETarget`] of its file, to spot the tampering in one instance.
+//!
+//! The differences caused by loading are normalized: the pointers fixed by base relocations are
+//! compared relative to the module base, and the IAT slots are compared by the imported names.
+//! The writable sections are skipped by default, as they change during execution.
+//!
+
+use crate::{decompile::pe_relocations, iat::directory_iat, prelude::*, unpack::read_sections};
+
+use core::ops::Range;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone)]
+pub struct CompareOptions {
+    /// Compare the writable sections too
+    pub writable: bool,
+    /// Differences separated by less than this count of bytes are merged
+    pub merge_gap: usize,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        Self {
+            writable: false,
+            merge_gap: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DiffKind {
+    Bytes,
+    /// A relocated pointer points to a different offset
+    Relocation,
+    /// An IAT slot imports a different function, or points to a non-export in the first target
+    Import,
+}
+
+/// A different range, the offsets are relative to module base
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffRange {
+    pub offset: usize,
+    pub kind: DiffKind,
+    pub section: String,
+    pub a: Vec<u8>,
+    pub b: Vec<u8>,
+    /// Imported names of the IAT slot, for [`DiffKind::Import`]
+    pub imports: Option<(Option<String>, Option<String>)>,
+}
+
+impl DiffRange {
+    #[inline]
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.a.len()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModuleDiff {
+    pub module: String,
+    pub base_a: usize,
+    pub base_b: usize,
+    /// Bytes compared
+    pub compared: usize,
+    /// Relocated pointers equal after normalization
+    pub relocations: usize,
+    /// IAT slots equal after normalization
+    pub imports: usize,
+    pub diffs: Vec<DiffRange>,
+}
+
+/// Name of the function an IAT slot imports, the value is the RVA of `IMAGE_IMPORT_BY_NAME`
+/// in file, or the address of export at runtime
+fn import_name<T: UDbgTarget + ?Sized>(
+    target: &T,
+    base: usize,
+    size: usize,
+    value: usize,
+) -> Option<String> {
+    if value == 0 {
+        return None;
+    }
+    if value < size {
+        return target.read_utf8(base + value + 2, 256);
+    }
+    target
+        .get_symbol_(value, Some(0))
+        .filter(|s| !s.symbol.is_empty())
+        .map(|s| s.symbol.to_string())
+}
+
+fn read_ptr(data: &[u8], ps: usize) -> usize {
+    match ps {
+        4 => u32::from_le_bytes(data[..4].try_into().unwrap()) as usize,
+        _ => u64::from_le_bytes(data[..8].try_into().unwrap()) as usize,
+    }
+}
+
+/// Compare a module loaded in two targets
+pub fn compare_module<A: UDbgTarget + ?Sized, B: UDbgTarget + ?Sized>(
+    a: &A,
+    module_a: &dyn UDbgModule,
+    b: &B,
+    module_b: &dyn UDbgModule,
+    options: &CompareOptions,
+) -> UDbgResult<ModuleDiff> {
+    let (da, db) = (module_a.data(), module_b.data());
+    let ps = a.base().pointer_size();
+    let sections = read_sections(a, da.base).ok_or(UDbgError::NotSupport)?;
+    let image = 0..da.size;
+    let relocs = pe_relocations(a, da.base, &(da.base..da.base + da.size))
+        .into_iter()
+        .map(|a| a - da.base)
+        .collect::<HashSet<_>>();
+    let iat = directory_iat(a, da.base)
+        .map(|r| r.start - da.base..r.end - da.base)
+        .unwrap_or_default();
+
+    let mut result = ModuleDiff {
+        module: da.name.to_string(),
+        base_a: da.base,
+        base_b: db.base,
+        ..Default::default()
+    };
+    for s in sections
+        .iter()
+        .filter(|s| options.writable || !s.is_writable())
+    {
+        let start = s.address - da.base;
+        let len = s.virtual_size.min(image.end.saturating_sub(start));
+        let bytes_a = a.read_bytes(da.base + start, len);
+        let bytes_b = b.read_bytes(db.base + start, len);
+        let len = bytes_a.len().min(bytes_b.len());
+        result.compared += len;
+
+        let mut diffs = Vec::<DiffRange>::new();
+        let mut push = |offset: usize, kind, a: &[u8], b: &[u8], imports| match diffs.last_mut() {
+            Some(d)
+                if kind == DiffKind::Bytes
+                    && d.kind == kind
+                    && d.range().end + options.merge_gap >= offset =>
+            {
+                let gap = d.range().end..offset;
+                d.a.extend_from_slice(&bytes_a[gap.start - start..gap.end - start]);
+                d.b.extend_from_slice(&bytes_b[gap.start - start..gap.end - start]);
+                d.a.extend_from_slice(a);
+                d.b.extend_from_slice(b);
+            }
+            _ => diffs.push(DiffRange {
+                offset,
+                kind,
+                section: s.name.clone(),
+                a: a.into(),
+                b: b.into(),
+                imports,
+            }),
+        };
+
+        let mut i = 0;
+        while i < len {
+            let offset = start + i;
+            let slot = i + ps <= len && (relocs.contains(&offset) || iat.contains(&offset));
+            if !slot {
+                if bytes_a[i] != bytes_b[i] {
+                    push(
+                        offset,
+                        DiffKind::Bytes,
+                        &bytes_a[i..i + 1],
+                        &bytes_b[i..i + 1],
+                        None,
+                    );
+                }
+                i += 1;
+                continue;
+            }
+
+            let (sa, sb) = (&bytes_a[i..i + ps], &bytes_b[i..i + ps]);
+            let (va, vb) = (read_ptr(sa, ps), read_ptr(sb, ps));
+            if iat.contains(&offset) {
+                let na = import_name(a, da.base, da.size, va);
+                let nb = import_name(b, db.base, db.size, vb);
+                let same = match (&na, &nb) {
+                    (Some(x), Some(y)) => x == y,
+                    // hooked to somewhere not exported
+                    (None, _) => va == 0,
+                    (Some(_), None) => true,
+                };
+                if same {
+                    result.imports += 1;
+                } else {
+                    push(offset, DiffKind::Import, sa, sb, Some((na, nb)));
+                }
+            } else if va.wrapping_sub(da.base) == vb.wrapping_sub(db.base) {
+                result.relocations += 1;
+            } else {
+                push(offset, DiffKind::Relocation, sa, sb, None);
+            }
+            i += ps;
+        }
+        result.diffs.extend(diffs);
+    }
+    Ok(result)
+}
+
+/// Compare a module by name between two targets
+pub fn compare_module_by_name<A: UDbgTarget + ?Sized, B: UDbgTarget + ?Sized>(
+    a: &A,
+    b: &B,
+    name: &str,
+    options: &CompareOptions,
+) -> UDbgResult<ModuleDiff> {
+    let ma = a.get_module(name).ok_or(UDbgError::NotFound)?;
+    let mb = b.get_module(name).ok_or(UDbgError::NotFound)?;
+    if ma.data().size != mb.data().size {
+        return Err(format!("size of {name} differs").into());
+    }
+    compare_module(a, ma.as_ref(), b, mb.as_ref(), options)
+}
@@ -0,0 +1,415 @@
+//!
+//! Basic-block coverage of modules, saved in the drcov format of DynamoRIO, which is loaded by the
+//! coverage plugins such as Lighthouse.
+//!
+//! [`Coverage::add_module`] sets a breakpoint on the start of every basic block in a module, found
+//! by a recursive disassembly from the function entries, or [`Coverage::add_blocks`] on the blocks
+//! supplied, such as a list exported from IDA and read by [`parse_block_list`]. Each breakpoint is
+//! removed at its first hit, so the overhead drops as the coverage grows.
+//!
+
+use crate::{
+    disasm::{disasm, FlowKind},
+    functrace::module_functions,
+    prelude::*,
+};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Instructions disassembled at once in the discovery
+const DISASM_BATCH: usize = 0x10;
+/// Max instructions of a block supplied without size
+const MAX_BLOCK_INSNS: usize = 0x100;
+
+/// A basic block, by the offset in module
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BasicBlock {
+    pub offset: usize,
+    /// Size in bytes, 0 if unknown
+    pub size: usize,
+}
+
+/// Parse a block list, a block per line as the hex offset and optionally the size, `#` starts a
+/// comment
+pub fn parse_block_list(text: &str) -> UDbgResult<Vec<BasicBlock>> {
+    let hex = |s: &str| {
+        let s = s.trim_start_matches("0x").trim_start_matches("0X");
+        usize::from_str_radix(s, 16).map_err(|_| UDbgError::from(format!("invalid number: {s}")))
+    };
+    let mut result = vec![];
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut parts = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|s| !s.is_empty());
+        let offset = match parts.next() {
+            Some(s) => hex(s)?,
+            None => continue,
+        };
+        let size = parts.next().map(hex).transpose()?.unwrap_or(0);
+        result.push(BasicBlock { offset, size });
+    }
+    Ok(result)
+}
+
+/// Find the basic blocks of a module by the recursive disassembly from the function entries, the
+/// blocks only reached by the indirect branches are missed
+pub fn discover_blocks<T: UDbgTarget + ?Sized>(
+    target: &T,
+    module: &dyn UDbgModule,
+) -> UDbgResult<Vec<BasicBlock>> {
+    let data = module.data();
+    let range = data.base..data.base + data.size;
+    let mut work = module_functions(target, module, None)?
+        .into_iter()
+        .map(|f| f.0)
+        .collect::<Vec<_>>();
+    if data.entry != 0 {
+        work.push(data.entry_point());
+    }
+
+    // the decoded instructions as the next address, and whether it ends a block
+    let mut insns = BTreeMap::new();
+    let mut leaders = BTreeSet::new();
+    while let Some(mut address) = work.pop() {
+        if !range.contains(&address) || !leaders.insert(address) {
+            continue;
+        }
+        'run: while range.contains(&address) && !insns.contains_key(&address) {
+            let batch = disasm(target, address, DISASM_BATCH)?;
+            if batch.is_empty() {
+                break;
+            }
+            for insn in batch {
+                // joined the instructions decoded from another leader
+                if insns.contains_key(&insn.address) {
+                    break 'run;
+                }
+                let ends = insn.flow != FlowKind::Next;
+                insns.insert(insn.address, (insn.next(), ends));
+                if !ends {
+                    address = insn.next();
+                    continue;
+                }
+                work.extend(insn.branch_target());
+                if matches!(
+                    insn.flow,
+                    FlowKind::ConditionalJump
+                        | FlowKind::Call
+                        | FlowKind::IndirectCall
+                        | FlowKind::Interrupt
+                ) {
+                    work.push(insn.next());
+                }
+                break 'run;
+            }
+        }
+    }
+
+    let mut result = vec![];
+    for &start in leaders.iter() {
+        let mut address = start;
+        while let Some(&(next, ends)) = insns.get(&address) {
+            address = next;
+            if ends || leaders.contains(&next) {
+                break;
+            }
+        }
+        if address > start {
+            result.push(BasicBlock {
+                offset: start - data.base,
+                size: address - start,
+            });
+        }
+    }
+    Ok(result)
+}
+
+/// Size of the block at `address`, up to the first instruction passing the control
+fn block_size<T: UDbgTarget + ?Sized>(target: &T, address: usize) -> usize {
+    let insns = disasm(target, address, MAX_BLOCK_INSNS).unwrap_or_default();
+    insns
+        .iter()
+        .find(|i| i.flow != FlowKind::Next)
+        .or_else(|| insns.last())
+        .map_or(0, |i| i.next() - address)
+}
+
+struct CoveredModule {
+    name: Arc<str>,
+    path: Arc<str>,
+    base: usize,
+    size: usize,
+    entry: usize,
+}
+
+struct Block {
+    module: usize,
+    size: usize,
+    /// Removed at the first hit
+    bp: Option<Arc<dyn UDbgBreakpoint>>,
+    hit: bool,
+}
+
+/// Coverage of the blocks, feed it the events by [`Self::handle`]
+#[derive(Default)]
+pub struct Coverage {
+    modules: Vec<CoveredModule>,
+    blocks: BTreeMap<usize, Block>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cover the blocks of a module found by [`discover_blocks`], returns the count of blocks
+    pub fn add_module<T: UDbgTarget + ?Sized>(
+        &mut self,
+        target: &T,
+        module: &str,
+    ) -> UDbgResult<usize> {
+        let m = target.get_module(module).ok_or(UDbgError::NotFound)?;
+        let blocks = discover_blocks(target, m.as_ref())?;
+        self.add_blocks(target, module, &blocks)
+    }
+
+    /// Cover the blocks supplied of a module, the blocks without size are measured by the
+    /// disassembly. Returns the count of blocks added
+    pub fn add_blocks<T: UDbgTarget + ?Sized>(
+        &mut self,
+        target: &T,
+        module: &str,
+        blocks: &[BasicBlock],
+    ) -> UDbgResult<usize> {
+        let m = target.get_module(module).ok_or(UDbgError::NotFound)?;
+        let data = m.data();
+        let index = match self.modules.iter().position(|m| m.base == data.base) {
+            Some(i) => i,
+            None => {
+                self.modules.push(CoveredModule {
+                    name: data.name.clone(),
+                    path: data.path.clone(),
+                    base: data.base,
+                    size: data.size,
+                    entry: data.entry_point(),
+                });
+                self.modules.len() - 1
+            }
+        };
+
+        let mut count = 0;
+        for b in blocks.iter().filter(|b| b.offset < data.size) {
+            let address = data.base + b.offset;
+            if self.blocks.contains_key(&address) {
+                continue;
+            }
+            let bp = match target.add_breakpoint(address.into()) {
+                Ok(bp) => Some(bp),
+                // the user's breakpoint is kept, and counts as the block's
+                Err(UDbgError::BpExists) => None,
+                Err(err) => {
+                    warn!("cover {address:x}: {err:?}");
+                    continue;
+                }
+            };
+            let size = match b.size {
+                0 => block_size(target, address),
+                size => size,
+            };
+            self.blocks.insert(
+                address,
+                Block {
+                    module: index,
+                    size,
+                    bp,
+                    hit: false,
+                },
+            );
+            count += 1;
+        }
+        info!("covering {count} blocks of {}", data.name);
+        Ok(count)
+    }
+
+    /// Handle the event from the debug loop, returns the reply if it's a coverage breakpoint
+    pub fn handle(&mut self, event: &UEvent) -> Option<UserReply> {
+        let address = match event {
+            UEvent::Breakpoint(bp) => bp.address(),
+            UEvent::ModuleUnload(m) => {
+                self.on_unload(m.data().base);
+                return None;
+            }
+            _ => return None,
+        };
+        let block = self.blocks.get_mut(&address)?;
+        block.hit = true;
+        let bp = block.bp.take()?;
+        bp.remove().log_error("remove coverage bp");
+        Some(UserReply::Run(false))
+    }
+
+    fn on_unload(&mut self, base: usize) {
+        let module = match self.modules.iter().position(|m| m.base == base) {
+            Some(i) => i,
+            None => return,
+        };
+        for b in self.blocks.values_mut().filter(|b| b.module == module) {
+            // the code is gone, the failure of restoring it is expected
+            if let Some(bp) = b.bp.take() {
+                bp.remove().ok();
+            }
+        }
+    }
+
+    /// Count of the blocks covered and of all the blocks
+    pub fn covered(&self) -> (usize, usize) {
+        let hit = self.blocks.values().filter(|b| b.hit).count();
+        (hit, self.blocks.len())
+    }
+
+    /// Count of the blocks covered and of all the blocks, by module name
+    pub fn covered_by_module(&self) -> Vec<(Arc<str>, usize, usize)> {
+        let mut counts = vec![(0, 0); self.modules.len()];
+        for b in self.blocks.values() {
+            counts[b.module].0 += b.hit as usize;
+            counts[b.module].1 += 1;
+        }
+        self.modules
+            .iter()
+            .zip(counts)
+            .map(|(m, (hit, all))| (m.name.clone(), hit, all))
+            .collect()
+    }
+
+    /// Addresses of the blocks covered
+    pub fn hits(&self) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .filter(|b| b.1.hit)
+            .map(|b| *b.0)
+            .collect()
+    }
+
+    /// Write the covered blocks in drcov version 2
+    pub fn write_drcov<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "DRCOV VERSION: 2")?;
+        writeln!(w, "DRCOV FLAVOR: udbg")?;
+        writeln!(w, "Module Table: version 2, count {}", self.modules.len())?;
+        writeln!(
+            w,
+            "Columns: id, base, end, entry, checksum, timestamp, path"
+        )?;
+        for (i, m) in self.modules.iter().enumerate() {
+            writeln!(
+                w,
+                "{i:3}, {:#018x}, {:#018x}, {:#018x}, 0x00000000, 0x00000000, {}",
+                m.base,
+                m.base + m.size,
+                m.entry,
+                m.path
+            )?;
+        }
+        let hits = self.blocks.iter().filter(|b| b.1.hit).collect::<Vec<_>>();
+        writeln!(w, "BB Table: {} bbs", hits.len())?;
+        for (&address, b) in hits {
+            let start = (address - self.modules[b.module].base) as u32;
+            w.write_all(&start.to_le_bytes())?;
+            w.write_all(&(b.size.min(u16::MAX as usize) as u16).to_le_bytes())?;
+            w.write_all(&(b.module as u16).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Save the covered blocks to a drcov file
+    pub fn save_drcov(&self, path: impl AsRef<Path>) -> UDbgResult<()> {
+        let mut w = BufWriter::new(std::fs::File::create(path)?);
+        self.write_drcov(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Remove all the breakpoints, the coverage is kept
+    pub fn stop(&mut self) {
+        for b in self.blocks.values_mut() {
+            if let Some(bp) = b.bp.take() {
+                bp.remove().log_error("remove coverage bp");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_list() {
+        let text = "# offset size\n0x1000 10\n2000, 0x20 # comment\n\n  3000\n";
+        assert_eq!(
+            parse_block_list(text).unwrap(),
+            [
+                BasicBlock {
+                    offset: 0x1000,
+                    size: 0x10
+                },
+                BasicBlock {
+                    offset: 0x2000,
+                    size: 0x20
+                },
+                BasicBlock {
+                    offset: 0x3000,
+                    size: 0
+                },
+            ]
+        );
+        assert!(parse_block_list("1000 xyz").is_err());
+    }
+
+    #[test]
+    fn drcov() {
+        let mut cov = Coverage::new();
+        cov.modules.push(CoveredModule {
+            name: "a.exe".into(),
+            path: "C:\\a.exe".into(),
+            base: 0x400000,
+            size: 0x1000,
+            entry: 0x400100,
+        });
+        for (address, size, hit) in [
+            (0x400100, 0x10, true),
+            (0x400200, 0x8, false),
+            (0x400300, 0x20000, true),
+        ] {
+            cov.blocks.insert(
+                address,
+                Block {
+                    module: 0,
+                    size,
+                    bp: None,
+                    hit,
+                },
+            );
+        }
+        let mut out = vec![];
+        cov.write_drcov(&mut out).unwrap();
+
+        let header = concat!(
+            "DRCOV VERSION: 2\n",
+            "DRCOV FLAVOR: udbg\n",
+            "Module Table: version 2, count 1\n",
+            "Columns: id, base, end, entry, checksum, timestamp, path\n",
+            "  0, 0x0000000000400000, 0x0000000000401000, 0x0000000000400100, 0x00000000, 0x00000000, C:\\a.exe\n",
+            "BB Table: 2 bbs\n",
+        );
+        assert!(out.starts_with(header.as_bytes()));
+        // start, size clamped to u16, module id; the missed block is not written
+        assert_eq!(
+            &out[header.len()..],
+            [0x00, 0x01, 0, 0, 0x10, 0, 0, 0, 0x00, 0x03, 0, 0, 0xff, 0xff, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,662 @@
+//!
+//! Automatic capture of crash artifacts at second-chance exceptions.
+//!
+//! When a [`CapturePolicy`] is set to [`TargetBase::crash`], the engine captures the memory around
+//! the registers pointing to readable memory, the faulting page, the top of stack and the recent
+//! events into a [`CrashReport`], before the user callback receives the exception.
+//! The history of events is kept all the time while the policy is set.
+//! [`CrashReport::bundle`] saves the report with everything else about the crash into a zip.
+//!
+
+use crate::{
+    exception::stop_reason,
+    prelude::*,
+    register::{general_regs, regid},
+};
+
+use sha2::{Digest, Sha256};
+use spin::Mutex;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+const PAGE_SIZE: usize = 0x1000;
+const STATUS_ACCESS_VIOLATION: u32 = 0xC0000005;
+const STATUS_IN_PAGE_ERROR: u32 = 0xC0000006;
+
+/// Artifacts to capture at second-chance exceptions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturePolicy {
+    /// Bytes before and after each register pointing to readable memory, 0 to skip
+    pub bytes_around_regs: usize,
+    /// Capture the page of the faulting address, or of the pc if unknown
+    pub fault_page: bool,
+    /// Bytes from the stack pointer, 0 to skip
+    pub stack_bytes: usize,
+    /// Recent events kept for the report, 0 to skip
+    pub history_len: usize,
+}
+
+impl Default for CapturePolicy {
+    fn default() -> Self {
+        Self {
+            bytes_around_regs: 0x40,
+            fault_page: true,
+            stack_bytes: 0x200,
+            history_len: 32,
+        }
+    }
+}
+
+/// A captured memory block
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryCapture {
+    /// Register name, `stack` or `fault page`
+    pub source: String,
+    pub address: usize,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRecord {
+    pub time: SystemTime,
+    pub tid: tid_t,
+    pub event: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashReport {
+    pub time: SystemTime,
+    pub pid: pid_t,
+    pub tid: tid_t,
+    pub code: u32,
+    pub reason: String,
+    pub pc: usize,
+    /// The inaccessible address of access violations
+    pub fault_address: Option<usize>,
+    pub registers: Vec<(&'static str, usize)>,
+    pub memory: Vec<MemoryCapture>,
+    /// Recent events before the exception, from the oldest
+    pub history: Vec<EventRecord>,
+}
+
+impl CrashReport {
+    /// The captured block containing `address`
+    pub fn find_memory(&self, address: usize) -> Option<&MemoryCapture> {
+        self.memory
+            .iter()
+            .find(|m| (m.address..m.address + m.data.len()).contains(&address))
+    }
+}
+
+/// Module entry of the crash bundle
+#[derive(Debug, Clone, Serialize)]
+pub struct BundleModule {
+    pub name: String,
+    pub path: String,
+    pub base: usize,
+    pub size: usize,
+    /// SHA-256 of the module file, None if it can't be read
+    pub sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BundleMetadata<'a> {
+    pid: pid_t,
+    image_path: Option<String>,
+    arch: &'static str,
+    target: &'a TargetBase,
+    /// The minidump/core in bundle, None if it can't be written on this platform
+    dump: Option<&'static str>,
+}
+
+fn to_json<S: Serialize + ?Sized>(value: &S) -> UDbgResult<Vec<u8>> {
+    serde_json::to_vec_pretty(value).map_err(|err| err.to_string().into())
+}
+
+fn bundle_error(err: zip::result::ZipError) -> UDbgError {
+    UDbgError::Text(format!("zip: {err}"))
+}
+
+impl CrashReport {
+    /// Save the report into a zip at `path`, with the minidump (on Windows) or core (on Linux), metadata of target,
+    /// module list with hashes, event history and the audit log
+    pub fn bundle<T: UDbgTarget + ?Sized>(
+        &self,
+        target: &T,
+        path: impl AsRef<Path>,
+    ) -> UDbgResult<()> {
+        let path = path.as_ref();
+        let mut zip = ZipWriter::new(File::create(path)?);
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        let mut add = |name: &str, data: &[u8]| -> UDbgResult<()> {
+            zip.start_file(name, options).map_err(bundle_error)?;
+            zip.write_all(data)?;
+            Ok(())
+        };
+
+        let dump = match sys::write_dump(target, self, &path.with_extension("dmp.tmp")) {
+            Ok(Some((name, data))) => {
+                add(name, &data)?;
+                Some(name)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                warn!("write dump: {err:?}");
+                None
+            }
+        };
+        let metadata = BundleMetadata {
+            pid: target.base().pid.get(),
+            image_path: target.image_path().ok(),
+            arch: target.base().arch,
+            target: target.base(),
+            dump,
+        };
+        add("metadata.json", &to_json(&metadata)?)?;
+        add("report.json", &to_json(self)?)?;
+        add("history.json", &to_json(&self.history)?)?;
+        add("modules.json", &to_json(&bundle_modules(target)?)?)?;
+        let mut audit = vec![];
+        target.base().audit.export(&mut audit)?;
+        add("audit.log", &audit)?;
+
+        zip.finish().map_err(bundle_error)?;
+        Ok(())
+    }
+}
+
+fn bundle_modules<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<Vec<BundleModule>> {
+    Ok(target
+        .enum_module()?
+        .map(|m| {
+            let data = m.data();
+            BundleModule {
+                name: data.name.to_string(),
+                path: data.path.to_string(),
+                base: data.base,
+                size: data.size,
+                sha256: std::fs::read(data.path.as_ref())
+                    .ok()
+                    .map(|bytes| hex::encode(Sha256::digest(&bytes))),
+            }
+        })
+        .collect())
+}
+
+/// Capture state of a target, disabled until a policy is set
+#[derive(Default)]
+pub struct CrashCapture {
+    policy: Mutex<Option<CapturePolicy>>,
+    history: Mutex<VecDeque<EventRecord>>,
+    reports: Mutex<Vec<CrashReport>>,
+}
+
+impl CrashCapture {
+    pub fn policy(&self) -> Option<CapturePolicy> {
+        self.policy.lock().clone()
+    }
+
+    /// Set the policy, None to disable the capture and clear the history
+    pub fn set_policy(&self, policy: Option<CapturePolicy>) {
+        if policy.is_none() {
+            self.history.lock().clear();
+        }
+        *self.policy.lock() = policy;
+    }
+
+    /// The recent events, from the oldest
+    pub fn history(&self) -> Vec<EventRecord> {
+        self.history.lock().iter().cloned().collect()
+    }
+
+    pub fn last_report(&self) -> Option<CrashReport> {
+        self.reports.lock().last().cloned()
+    }
+
+    /// Take the reports captured since last call
+    pub fn take_reports(&self) -> Vec<CrashReport> {
+        core::mem::take(&mut *self.reports.lock())
+    }
+
+    /// Called by the engine before the user callback
+    pub(crate) fn on_event(&self, ctx: &mut dyn TraceContext, event: &UEvent) {
+        let policy = match self.policy() {
+            Some(p) => p,
+            None => return,
+        };
+        if let UEvent::Exception { first: false, code } = *event {
+            let report = self.capture(ctx, &policy, code, event);
+            self.reports.lock().push(report);
+        }
+        if policy.history_len > 0 {
+            let mut history = self.history.lock();
+            while history.len() >= policy.history_len {
+                history.pop_front();
+            }
+            history.push_back(EventRecord {
+                time: SystemTime::now(),
+                tid: ctx.target().base().event_tid.get(),
+                event: stop_reason(event),
+            });
+        }
+    }
+
+    fn capture(
+        &self,
+        ctx: &mut dyn TraceContext,
+        policy: &CapturePolicy,
+        code: u32,
+        event: &UEvent,
+    ) -> CrashReport {
+        let target = ctx.target();
+        let arch = ctx.arch();
+        let fault_address = match code {
+            STATUS_ACCESS_VIOLATION | STATUS_IN_PAGE_ERROR => ctx.exception_param(1),
+            _ => None,
+        };
+        let readable = |a: usize| target.virtual_query(a).map_or(false, |p| p.is_readable());
+
+        let mut registers = vec![];
+        let (mut pc, mut sp) = (0, 0);
+        if let Some(regs) = ctx.register() {
+            let regs = &*regs;
+            registers = general_regs(arch)
+                .iter()
+                .filter_map(|&(name, id)| Some((name, regs.get_reg(id)?.as_int())))
+                .collect();
+            pc = regs.get_reg(regid::COMM_REG_PC).map_or(0, |r| r.as_int());
+            sp = regs.get_reg(regid::COMM_REG_SP).map_or(0, |r| r.as_int());
+        }
+
+        let mut memory = vec![];
+        if policy.bytes_around_regs > 0 {
+            let n = policy.bytes_around_regs;
+            for &(name, value) in registers.iter().filter(|r| readable(r.1)) {
+                // not before the page of value, the previous one may be inaccessible
+                let address = value.saturating_sub(n).max(value & !(PAGE_SIZE - 1));
+                let data = target.read_bytes(address, n * 2);
+                if !data.is_empty() {
+                    memory.push(MemoryCapture {
+                        source: name.into(),
+                        address,
+                        data,
+                    });
+                }
+            }
+        }
+        if policy.stack_bytes > 0 && sp != 0 {
+            memory.push(MemoryCapture {
+                source: "stack".into(),
+                address: sp,
+                data: target.read_bytes(sp, policy.stack_bytes),
+            });
+        }
+        if policy.fault_page {
+            let address = fault_address.unwrap_or(pc) & !(PAGE_SIZE - 1);
+            let data = target.read_bytes(address, PAGE_SIZE);
+            if !data.is_empty() {
+                memory.push(MemoryCapture {
+                    source: "fault page".into(),
+                    address,
+                    data,
+                });
+            }
+        }
+
+        CrashReport {
+            time: SystemTime::now(),
+            pid: target.base().pid.get(),
+            tid: target.base().event_tid.get(),
+            code,
+            reason: stop_reason(event),
+            pc,
+            fault_address,
+            registers,
+            memory,
+            history: self.history(),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::*;
+    use crate::os::windows::{
+        ntdll::{
+            MiniDumpWithDataSegs, MiniDumpWithFullMemoryInfo, MiniDumpWithHandleData,
+            MiniDumpWithThreadInfo, MiniDumpWithUnloadedModules, MiniDumpWriteDump,
+        },
+        ProcessAccess,
+    };
+
+    use core::ptr::{null, null_mut};
+    use std::os::windows::io::AsRawHandle;
+
+    /// Write the minidump to `temp`, then take it back
+    pub fn write_dump<T: UDbgTarget + ?Sized>(
+        target: &T,
+        report: &CrashReport,
+        temp: &Path,
+    ) -> UDbgResult<Option<(&'static str, Vec<u8>)>> {
+        let process = match target.process() {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        // the handle data needs the handles duplicated
+        let handle = process.handle_with(
+            ProcessAccess::QUERY_INFO | ProcessAccess::READ | ProcessAccess::DUP_HANDLE,
+        )?;
+        let file = File::create(temp)?;
+        let dump_type = MiniDumpWithDataSegs
+            | MiniDumpWithHandleData
+            | MiniDumpWithUnloadedModules
+            | MiniDumpWithThreadInfo
+            | MiniDumpWithFullMemoryInfo;
+        let written = unsafe {
+            MiniDumpWriteDump(
+                handle,
+                report.pid as u32,
+                file.as_raw_handle() as _,
+                dump_type,
+                None,
+                null_mut(),
+                null(),
+            ) != 0
+        };
+        let result = if written {
+            drop(file);
+            std::fs::read(temp)
+                .map(|data| Some(("minidump.dmp", data)))
+                .map_err(Into::into)
+        } else {
+            Err(UDbgError::system())
+        };
+        std::fs::remove_file(temp).ok();
+        result
+    }
+}
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    target_pointer_width = "64"
+))]
+mod sys {
+    use super::*;
+
+    const ET_CORE: u16 = 4;
+    const PT_LOAD: u32 = 1;
+    const PT_NOTE: u32 = 4;
+    const NT_PRSTATUS: u32 = 1;
+    const NT_AUXV: u32 = 6;
+    const EHDR_SIZE: usize = 64;
+    const PHDR_SIZE: usize = 56;
+    /// Offset of `pr_reg` in `elf_prstatus`
+    const PRSTATUS_REG: usize = 112;
+
+    #[cfg(target_arch = "x86_64")]
+    const EM_MACHINE: u16 = 62;
+    #[cfg(target_arch = "aarch64")]
+    const EM_MACHINE: u16 = 183;
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const EM_MACHINE: u16 = 0;
+
+    /// A readable region of target in the core
+    pub struct CoreRegion {
+        pub base: usize,
+        pub size: usize,
+        /// `PF_*` flags of the segment
+        pub flags: u32,
+        /// Shorter than `size` if the region can't be read completely
+        pub data: Vec<u8>,
+    }
+
+    /// Write an ELF core of the readable memory and the registers of the threads
+    pub fn write_dump<T: UDbgTarget + ?Sized>(
+        target: &T,
+        report: &CrashReport,
+        _temp: &Path,
+    ) -> UDbgResult<Option<(&'static str, Vec<u8>)>> {
+        if target.process().is_none() {
+            return Ok(None);
+        }
+
+        let mut regions = vec![];
+        for page in target.enum_memory()? {
+            if !page.is_readable() {
+                continue;
+            }
+            let flags = 4 | (page.is_writable() as u32) << 1 | page.is_executable() as u32;
+            regions.push(CoreRegion {
+                base: page.base,
+                size: page.size,
+                flags,
+                data: target.read_bytes(page.base, page.size),
+            });
+        }
+
+        let mut threads = vec![];
+        for t in target.enum_thread(false)? {
+            match get_regset(t.tid) {
+                Ok(regs) => threads.push((t.tid, regs)),
+                Err(err) => warn!("core ~{}: {err:?}", t.tid),
+            }
+        }
+        if threads.is_empty() {
+            return Err("core: no registers of any thread".into());
+        }
+        // the first thread is the current one of the core
+        threads.sort_by_key(|t| t.0 != report.tid);
+
+        let auxv = std::fs::read(format!("/proc/{}/auxv", report.pid)).unwrap_or_default();
+        Ok(Some((
+            "core",
+            write_core(report, &regions, &threads, &auxv),
+        )))
+    }
+
+    /// The general registers of a stopped thread, in the layout of `elf_gregset_t`
+    fn get_regset(tid: tid_t) -> UDbgResult<Vec<u8>> {
+        let mut buf = [0u8; 0x400];
+        let mut io = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        let r = unsafe {
+            libc::ptrace(
+                libc::PTRACE_GETREGSET,
+                tid,
+                NT_PRSTATUS as usize,
+                &mut io as *mut libc::iovec,
+            )
+        };
+        if r < 0 {
+            return Err(UDbgError::system());
+        }
+        Ok(buf[..io.iov_len].to_vec())
+    }
+
+    fn push_note(out: &mut Vec<u8>, ty: u32, desc: &[u8]) {
+        const NAME: &[u8] = b"CORE\0\0\0\0";
+        out.extend_from_slice(&5u32.to_ne_bytes());
+        out.extend_from_slice(&(desc.len() as u32).to_ne_bytes());
+        out.extend_from_slice(&ty.to_ne_bytes());
+        out.extend_from_slice(NAME);
+        out.extend_from_slice(desc);
+        out.resize((out.len() + 3) & !3, 0);
+    }
+
+    fn prstatus(tid: tid_t, signal: u16, regs: &[u8]) -> Vec<u8> {
+        let mut desc = vec![0u8; PRSTATUS_REG];
+        desc[0..4].copy_from_slice(&(signal as i32).to_ne_bytes());
+        desc[12..14].copy_from_slice(&signal.to_ne_bytes());
+        desc[32..36].copy_from_slice(&tid.to_ne_bytes());
+        desc.extend_from_slice(regs);
+        // pr_fpvalid and the padding
+        desc.resize(desc.len() + 8, 0);
+        desc
+    }
+
+    /// Build the ELF64 core in the byte order of host, which is the one of target
+    pub fn write_core(
+        report: &CrashReport,
+        regions: &[CoreRegion],
+        threads: &[(tid_t, Vec<u8>)],
+        auxv: &[u8],
+    ) -> Vec<u8> {
+        let mut notes = vec![];
+        for (tid, regs) in threads {
+            let signal = if *tid == report.tid {
+                report.code as u16
+            } else {
+                0
+            };
+            push_note(&mut notes, NT_PRSTATUS, &prstatus(*tid, signal, regs));
+        }
+        if !auxv.is_empty() {
+            push_note(&mut notes, NT_AUXV, auxv);
+        }
+
+        let phnum = regions.len() + 1;
+        let notes_offset = EHDR_SIZE + PHDR_SIZE * phnum;
+        let mut out = Vec::with_capacity(
+            notes_offset + notes.len() + regions.iter().map(|r| r.data.len()).sum::<usize>(),
+        );
+
+        out.extend_from_slice(b"\x7fELF");
+        out.push(2);
+        out.push(if cfg!(target_endian = "little") { 1 } else { 2 });
+        out.push(1);
+        out.resize(16, 0);
+        out.extend_from_slice(&ET_CORE.to_ne_bytes());
+        out.extend_from_slice(&EM_MACHINE.to_ne_bytes());
+        out.extend_from_slice(&1u32.to_ne_bytes());
+        out.extend_from_slice(&0u64.to_ne_bytes());
+        out.extend_from_slice(&(EHDR_SIZE as u64).to_ne_bytes());
+        out.extend_from_slice(&0u64.to_ne_bytes());
+        out.extend_from_slice(&0u32.to_ne_bytes());
+        for v in [EHDR_SIZE, PHDR_SIZE, phnum, 0, 0, 0] {
+            out.extend_from_slice(&(v as u16).to_ne_bytes());
+        }
+
+        let mut push_phdr = |ty: u32, flags: u32, offset: usize, vaddr: usize, filesz, memsz| {
+            out.extend_from_slice(&ty.to_ne_bytes());
+            out.extend_from_slice(&flags.to_ne_bytes());
+            for v in [
+                offset,
+                vaddr,
+                vaddr,
+                filesz,
+                memsz,
+                if ty == PT_LOAD { 0x1000 } else { 4 },
+            ] {
+                out.extend_from_slice(&(v as u64).to_ne_bytes());
+            }
+        };
+        push_phdr(PT_NOTE, 0, notes_offset, 0, notes.len(), 0);
+        let mut offset = notes_offset + notes.len();
+        for r in regions {
+            push_phdr(PT_LOAD, r.flags, offset, r.base, r.data.len(), r.size);
+            offset += r.data.len();
+        }
+
+        out.extend_from_slice(&notes);
+        for r in regions {
+            out.extend_from_slice(&r.data);
+        }
+        out
+    }
+}
+
+#[cfg(not(any(
+    windows,
+    all(
+        any(target_os = "linux", target_os = "android"),
+        target_pointer_width = "64"
+    )
+)))]
+mod sys {
+    use super::*;
+
+    pub fn write_dump<T: UDbgTarget + ?Sized>(
+        _target: &T,
+        _report: &CrashReport,
+        _temp: &Path,
+    ) -> UDbgResult<Option<(&'static str, Vec<u8>)>> {
+        Err("no dump writer on this platform".into())
+    }
+}
+
+#[cfg(all(
+    test,
+    any(target_os = "linux", target_os = "android"),
+    target_pointer_width = "64"
+))]
+mod tests {
+    use super::sys::*;
+    use super::*;
+    use goblin::elf::{program_header::*, Elf};
+
+    #[test]
+    fn core() {
+        let report = CrashReport {
+            time: SystemTime::now(),
+            pid: 100,
+            tid: 101,
+            code: 11,
+            reason: String::new(),
+            pc: 0,
+            fault_address: None,
+            registers: vec![],
+            memory: vec![],
+            history: vec![],
+        };
+        let regions = [
+            CoreRegion {
+                base: 0x10000,
+                size: 0x1000,
+                flags: PF_R | PF_X,
+                data: vec![0xCC; 0x1000],
+            },
+            CoreRegion {
+                base: 0x20000,
+                size: 0x2000,
+                flags: PF_R | PF_W,
+                data: vec![0x55; 0x10],
+            },
+        ];
+        let threads = [(101, vec![1u8; 0xD8]), (102, vec![2u8; 0xD8])];
+        let data = write_core(&report, &regions, &threads, &[3u8; 0x20]);
+
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.header.e_type, goblin::elf::header::ET_CORE);
+        let loads = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .collect::<Vec<_>>();
+        assert_eq!(loads.len(), 2);
+        assert_eq!(loads[1].p_vaddr, 0x20000);
+        assert_eq!(loads[1].p_filesz, 0x10);
+        assert_eq!(loads[1].p_memsz, 0x2000);
+        assert_eq!(loads[1].p_flags, PF_R | PF_W);
+        assert_eq!(&data[loads[1].file_range()], &[0x55; 0x10]);
+
+        let notes = elf
+            .iter_note_headers(&data)
+            .unwrap()
+            .map(|n| n.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(notes.len(), 3);
+        assert!(notes.iter().all(|n| n.name == "CORE"));
+        assert_eq!(notes[0].n_type, 1);
+        assert_eq!(notes[0].desc.len(), 112 + 0xD8 + 8);
+        assert_eq!(&notes[0].desc[12..14], &11u16.to_ne_bytes());
+        assert_eq!(&notes[1].desc[32..36], &102i32.to_ne_bytes());
+        assert_eq!(notes[2].n_type, 6);
+        assert_eq!(notes[2].desc, &[3u8; 0x20]);
+    }
+}
@@ -0,0 +1,374 @@
+//!
+//! Identify crypto routines by scanning the well-known constants, such as AES S-boxes, SHA IVs, CRC tables, ChaCha sigma.
+//!
+//! The constant tables found in data are followed to the code referencing them,
+//! the immediate constants are only counted in code, and the results are grouped by function and algorithm.
+//!
+
+use crate::{
+    prelude::*,
+    scan::{readable_regions, scan_memory, CancelToken, Matcher, ScanEvent, ScanOptions},
+};
+
+use core::ops::Range;
+use std::collections::{BTreeMap, HashSet};
+
+enum ConstData {
+    Bytes(&'static [u8]),
+    /// Table of 32-bit words, matched in both byte orders
+    Words(&'static [u32]),
+    /// Table of 64-bit words, matched in both byte orders
+    Words64(&'static [u64]),
+    /// Immediate operand of instruction, only matched in code
+    Imm(u32),
+}
+
+/// A well-known crypto constant
+pub struct CryptoConst {
+    pub name: &'static str,
+    pub algorithm: &'static str,
+    data: ConstData,
+}
+
+macro_rules! constant {
+    ($alg:literal, $name:literal, $kind:ident($data:expr)) => {
+        CryptoConst {
+            name: $name,
+            algorithm: $alg,
+            data: ConstData::$kind($data),
+        }
+    };
+}
+
+#[rustfmt::skip]
+pub static CONSTANTS: &[CryptoConst] = &[
+    constant!("AES", "AES S-box", Bytes(&[
+        0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+        0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    ])),
+    constant!("AES", "AES inverse S-box", Bytes(&[
+        0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+        0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    ])),
+    constant!("AES", "AES T-table", Words(&[
+        0xc66363a5, 0xf87c7c84, 0xee777799, 0xf67b7b8d, 0xfff2f20d, 0xd66b6bbd, 0xde6f6fb1, 0x91c5c554,
+    ])),
+    constant!("MD5", "MD5 T", Words(&[0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee])),
+    constant!("MD5", "MD5 T[0]", Imm(0xd76aa478)),
+    constant!("MD5", "MD5 T[1]", Imm(0xe8c7b756)),
+    constant!("SHA-1", "SHA-1 IV", Words(&[0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0])),
+    constant!("SHA-1", "SHA-1 IV[4]", Imm(0xc3d2e1f0)),
+    constant!("SHA-1", "SHA-1 K0", Imm(0x5a827999)),
+    constant!("SHA-1", "SHA-1 K1", Imm(0x6ed9eba1)),
+    constant!("SHA-1", "SHA-1 K2", Imm(0x8f1bbcdc)),
+    constant!("SHA-1", "SHA-1 K3", Imm(0xca62c1d6)),
+    constant!("SHA-256", "SHA-256 IV", Words(&[
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ])),
+    constant!("SHA-256", "SHA-256 IV[0]", Imm(0x6a09e667)),
+    constant!("SHA-256", "SHA-256 IV[1]", Imm(0xbb67ae85)),
+    constant!("SHA-256", "SHA-256 K", Words(&[
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    ])),
+    constant!("SHA-512", "SHA-512 IV", Words64(&[
+        0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
+    ])),
+    constant!("SHA-512", "SHA-512 K", Words64(&[
+        0x428a2f98d728ae22, 0x7137449123ef65cd, 0xb5c0fbcfec4d3b2f, 0xe9b5dba58189dbbc,
+    ])),
+    constant!("CRC32", "CRC32 table", Words(&[
+        0x00000000, 0x77073096, 0xee0e612c, 0x990951ba, 0x076dc419, 0x706af48f, 0xe963a535, 0x9e6495a3,
+    ])),
+    constant!("CRC32", "CRC32 polynomial", Imm(0xedb88320)),
+    constant!("CRC32C", "CRC32C table", Words(&[
+        0x00000000, 0xf26b8303, 0xe13b70f7, 0x1350f3f4, 0xc79a971f, 0x35f1141c, 0x26a1e7e8, 0xd4ca64eb,
+    ])),
+    constant!("CRC32C", "CRC32C polynomial", Imm(0x82f63b78)),
+    constant!("ChaCha/Salsa", "sigma", Bytes(b"expand 32-byte k")),
+    constant!("ChaCha/Salsa", "tau", Bytes(b"expand 16-byte k")),
+    constant!("ChaCha/Salsa", "sigma[0]", Imm(0x61707865)),
+    constant!("ChaCha/Salsa", "sigma[1]", Imm(0x3320646e)),
+    constant!("ChaCha/Salsa", "sigma[2]", Imm(0x79622d32)),
+    constant!("ChaCha/Salsa", "sigma[3]", Imm(0x6b206574)),
+    constant!("Blowfish", "Blowfish P-array", Words(&[
+        0x243f6a88, 0x85a308d3, 0x13198a2e, 0x03707344, 0xa4093822, 0x299f31d0,
+    ])),
+    constant!("TEA", "TEA delta", Imm(0x9e3779b9)),
+    constant!("TEA", "TEA sum", Imm(0xc6ef3720)),
+];
+
+impl CryptoConst {
+    #[inline]
+    pub fn is_immediate(&self) -> bool {
+        matches!(self.data, ConstData::Imm(_))
+    }
+
+    /// Byte patterns of this constant, the big-endian one is the second if exists
+    fn patterns(&self) -> Vec<Vec<u8>> {
+        match self.data {
+            ConstData::Bytes(b) => vec![b.to_vec()],
+            ConstData::Imm(v) => vec![v.to_le_bytes().to_vec()],
+            ConstData::Words(w) => vec![
+                w.iter().flat_map(|v| v.to_le_bytes()).collect(),
+                w.iter().flat_map(|v| v.to_be_bytes()).collect(),
+            ],
+            ConstData::Words64(w) => vec![
+                w.iter().flat_map(|v| v.to_le_bytes()).collect(),
+                w.iter().flat_map(|v| v.to_be_bytes()).collect(),
+            ],
+        }
+    }
+
+    /// Weight in the confidence of a candidate, tables are much more specific than immediates
+    fn weight(&self) -> f32 {
+        match self.data {
+            ConstData::Imm(_) => 0.25,
+            _ => 0.7,
+        }
+    }
+}
+
+/// Matcher of all the patterns, identifies which one by [`Patterns::identify`]
+struct Patterns(Vec<(usize, Vec<u8>)>);
+
+impl Patterns {
+    fn new() -> Self {
+        Self(
+            CONSTANTS
+                .iter()
+                .enumerate()
+                .flat_map(|(i, c)| c.patterns().into_iter().map(move |p| (i, p)))
+                .collect(),
+        )
+    }
+
+    fn identify(&self, data: &[u8]) -> Vec<usize> {
+        self.0
+            .iter()
+            .filter(|(_, p)| data.starts_with(p))
+            .map(|(i, _)| *i)
+            .collect()
+    }
+}
+
+impl Matcher for Patterns {
+    fn max_len(&self) -> usize {
+        self.0.iter().map(|(_, p)| p.len()).max().unwrap_or(0)
+    }
+
+    fn find(&self, data: &[u8], found: &mut dyn FnMut(usize)) {
+        for i in 0..data.len() {
+            if self.0.iter().any(|(_, p)| data[i..].starts_with(p)) {
+                found(i);
+            }
+        }
+    }
+}
+
+/// A crypto constant found in target
+#[derive(Debug, Clone, Serialize)]
+pub struct CryptoMatch {
+    pub name: &'static str,
+    pub address: usize,
+    /// Address of the code which references the constant table, or the immediate itself
+    pub reference: usize,
+}
+
+/// A function which is likely to implement crypto algorithm
+#[derive(Debug, Clone, Serialize)]
+pub struct CryptoCandidate {
+    pub algorithm: &'static str,
+    /// Start of the function, or the referencing address if the function boundary is unknown
+    pub function: usize,
+    pub symbol: Option<String>,
+    pub matches: Vec<CryptoMatch>,
+    /// 0.0 ~ 1.0, by the count and kind of distinct constants found
+    pub confidence: f32,
+}
+
+/// Find the code referencing `range`, by absolute pointer, or RIP-relative displacement on x86_64
+fn find_code_refs<T: UDbgTarget + ?Sized>(
+    target: &T,
+    code: &[(usize, Vec<u8>)],
+    range: &Range<usize>,
+) -> Vec<usize> {
+    let psize = target.base().pointer_size();
+    let rip_relative = target.base().arch == "x86_64" && psize == 8;
+    let mut result = vec![];
+    for (base, data) in code {
+        for i in 0..data.len().saturating_sub(psize) {
+            let address = base + i;
+            let pointer = if psize == 8 {
+                u64::from_le_bytes(data[i..i + 8].try_into().unwrap()) as usize
+            } else {
+                u32::from_le_bytes(data[i..i + 4].try_into().unwrap()) as usize
+            };
+            if range.contains(&pointer) {
+                result.push(address);
+                continue;
+            }
+            if rip_relative {
+                let disp = i32::from_le_bytes(data[i..i + 4].try_into().unwrap()) as isize;
+                // the instruction may end with an immediate after the displacement
+                if [0, 1, 2, 4].iter().any(|tail| {
+                    range.contains(&((address + 4 + tail) as isize).wrapping_add(disp) as usize)
+                }) {
+                    result.push(address);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Scan the module (or all the memory if `module` is None) for crypto constants,
+/// returns the candidate functions sorted by confidence
+pub fn scan_crypto<T: UDbgTarget + ?Sized>(
+    target: &T,
+    module: Option<&str>,
+) -> UDbgResult<Vec<CryptoCandidate>> {
+    let range = match module {
+        Some(name) => {
+            let m = target.get_module(name).ok_or(UDbgError::NotFound)?;
+            let data = m.data();
+            data.base..data.base + data.size
+        }
+        None => 0..usize::MAX,
+    };
+    let pages = target
+        .enum_memory()?
+        .filter(|p| p.is_commit() && p.is_readable() && range.contains(&p.base))
+        .collect::<Vec<_>>();
+    let code = pages
+        .iter()
+        .filter(|p| p.is_executable())
+        .map(|p| p.base..p.base + p.size)
+        .collect::<Vec<_>>();
+    let regions = if module.is_some() {
+        pages.iter().map(|p| p.base..p.base + p.size).collect()
+    } else {
+        readable_regions(target)?
+    };
+
+    let patterns = Patterns::new();
+    let mut found = vec![];
+    scan_memory(
+        target,
+        &regions,
+        &patterns,
+        &ScanOptions::default(),
+        &CancelToken::default(),
+        |e| {
+            if let ScanEvent::Found(a) = e {
+                found.push(a);
+            }
+            true
+        },
+    );
+
+    let in_code = |a: usize| code.iter().any(|r| r.contains(&a));
+    let mut code_data = None;
+    // (function, algorithm) -> matches
+    let mut groups = BTreeMap::<(usize, &'static str), Vec<CryptoMatch>>::new();
+    for address in found {
+        let data = target.read_bytes(address, patterns.max_len());
+        for i in patterns.identify(&data) {
+            let c = &CONSTANTS[i];
+            let references = if in_code(address) {
+                vec![address]
+            } else if c.is_immediate() {
+                continue;
+            } else {
+                let len = c.patterns()[0].len();
+                let code_data = code_data.get_or_insert_with(|| {
+                    code.iter()
+                        .map(|r| (r.start, target.read_bytes(r.start, r.end - r.start)))
+                        .collect::<Vec<_>>()
+                });
+                find_code_refs(target, code_data, &(address..address + len))
+            };
+            for reference in references {
+                let function = target.function_start(reference).unwrap_or(reference);
+                groups
+                    .entry((function, c.algorithm))
+                    .or_default()
+                    .push(CryptoMatch {
+                        name: c.name,
+                        address,
+                        reference,
+                    });
+            }
+        }
+    }
+
+    let mut result = groups
+        .into_iter()
+        .map(|((function, algorithm), matches)| {
+            let distinct = matches.iter().map(|m| m.name).collect::<HashSet<_>>();
+            let confidence = CONSTANTS
+                .iter()
+                .filter(|c| distinct.contains(c.name))
+                .map(CryptoConst::weight)
+                .sum::<f32>()
+                .min(1.0);
+            CryptoCandidate {
+                algorithm,
+                function,
+                symbol: target.get_symbol_string(function),
+                matches,
+                confidence,
+            }
+        })
+        .collect::<Vec<_>>();
+    result.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find_all(patterns: &Patterns, data: &[u8]) -> Vec<usize> {
+        let mut result = vec![];
+        patterns.find(data, &mut |i| result.push(i));
+        result
+    }
+
+    #[test]
+    fn find_patterns() {
+        let patterns = Patterns::new();
+        let sha1_be = [
+            0x67452301u32,
+            0xefcdab89,
+            0x98badcfe,
+            0x10325476,
+            0xc3d2e1f0,
+        ]
+        .iter()
+        .flat_map(|v| v.to_be_bytes())
+        .collect::<Vec<_>>();
+        let mut data = vec![0x11u8; 0x20];
+        data.extend_from_slice(b"expand 32-byte k");
+        data.extend_from_slice(&sha1_be);
+        // a table cut at the end is not matched
+        data.extend_from_slice(&sha1_be[..sha1_be.len() - 1]);
+
+        let found = find_all(&patterns, &data);
+        assert!(found.contains(&0x20));
+        assert!(found.contains(&0x30));
+        assert!(!found.contains(&0x44));
+        assert!(found.iter().all(|&i| i >= 0x20));
+        assert!(find_all(&patterns, &[0x11; 0x100]).is_empty());
+
+        let names = |at: usize| {
+            patterns
+                .identify(&data[at..])
+                .into_iter()
+                .map(|i| CONSTANTS[i].name)
+                .collect::<Vec<_>>()
+        };
+        // the first word of sigma is also an immediate
+        assert_eq!(names(0x20), ["sigma", "sigma[0]"]);
+        assert_eq!(names(0x30), ["SHA-1 IV"]);
+    }
+}
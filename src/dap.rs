@@ -0,0 +1,1032 @@
+//!
+//! Debug Adapter Protocol frontend, serves VSCode and other DAP clients on stdio, TCP, unix
+//! socket, vsock or Windows named pipe.
+//!
+//! The requests are read by a separate thread and handled in the debug event loop of
+//! [`UDbgEngine`], so the requests about the stopped target, such as `stackTrace`, `scopes`
+//! and `variables`, are served while the target is interrupted. `pause` and `disconnect`
+//! are handled by the reader thread, since the event loop doesn't return while target is running.
+//!
+//! The source line breakpoints are not supported, use the function breakpoints by symbol or the
+//! instruction breakpoints by address instead. Don't log to stdout when serving on stdio.
+//!
+//! A client may request the memory encodings of [`crate::transfer`] by the `udbgMemoryEncoding`
+//! argument of `initialize`, the encodings accepted are returned in the same field, then the
+//! data of `readMemory` is encoded by them.
+//!
+//! The servers on sockets may require the clients to authenticate by an [`Authorizer`]: the
+//! token is passed by the `udbgToken` argument of `initialize`, the requests before it are
+//! denied. The read-only tokens may only inspect the threads, stacks, variables and memory of
+//! the target, not attach, pause, continue or detach it. The TCP server requires an authorizer
+//! unless it listens on loopback, the vsock server always requires one, and the unix socket is
+//! only accessible to the owner.
+//!
+
+use crate::{
+    annotate::Annotator,
+    auth::{Authorizer, Principal},
+    prelude::*,
+    regfmt::ContextView,
+    register::regid,
+    transfer::{Encoding, MemoryEncoder},
+};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use serde_json::{json, Value};
+use spin::Mutex;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+
+/// Stack slots to scan for the return addresses
+const STACK_SLOTS: usize = 0x400;
+const MAX_FRAMES: usize = 64;
+/// Stack slots shown in the `Stack` scope
+const STACK_VARIABLES: usize = 32;
+/// Max size of a message body, a larger `Content-Length` is rejected without reading it
+const MAX_MESSAGE: usize = 16 << 20;
+/// Max bytes read by a `readMemory` request, the client reads the rest by more requests
+const MAX_READ_MEMORY: usize = 1 << 20;
+
+/// Read a message with the `Content-Length` header, None if the stream is closed
+fn read_message(reader: &mut impl BufRead) -> UDbgResult<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            if key.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or("missing Content-Length")?;
+    if length > MAX_MESSAGE {
+        return Err(format!("Content-Length {length} exceeds {MAX_MESSAGE}").into());
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| err.to_string().into())
+}
+
+/// If the request may change the target or its execution, which is denied for the read-only
+/// clients. Only the inspection requests are allowed for them
+fn is_mutating(command: &str) -> bool {
+    !matches!(
+        command,
+        "initialize" | "threads" | "stackTrace" | "scopes" | "variables" | "readMemory"
+    )
+}
+
+fn parse_address(s: &str) -> Option<usize> {
+    let s = s.trim();
+    usize::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok()
+}
+
+struct Client {
+    writer: Mutex<Box<dyn Write + Send>>,
+    seq: AtomicI64,
+}
+
+impl Client {
+    fn send(&self, mut msg: Value) {
+        msg["seq"] = self.seq.fetch_add(1, Ordering::Relaxed).into();
+        let body = msg.to_string();
+        let mut writer = self.writer.lock();
+        write!(writer, "Content-Length: {}\r\n\r\n{body}", body.len())
+            .and_then(|_| writer.flush())
+            .log_error("dap write");
+    }
+
+    fn respond(&self, request: &Value, result: UDbgResult<Value>) {
+        let mut msg = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+        });
+        match result {
+            Ok(body) => {
+                msg["success"] = true.into();
+                msg["body"] = body;
+            }
+            Err(err) => {
+                msg["success"] = false.into();
+                msg["message"] = format!("{err:?}").into();
+            }
+        }
+        self.send(msg)
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.send(json!({"type": "event", "event": event, "body": body}))
+    }
+}
+
+/// State shared with the reader thread
+struct Shared {
+    target: Mutex<Option<Arc<dyn UDbgTarget>>>,
+    pause: AtomicBool,
+    /// Target is created by `launch`, so it's killed on disconnect by default
+    launched: AtomicBool,
+    auth: Option<Arc<Authorizer>>,
+    /// The client authenticated by `initialize`
+    principal: Mutex<Option<Principal>>,
+}
+
+impl Shared {
+    /// Authenticate the `initialize` request, and check the others by the token of client
+    fn authorize(&self, request: &Value) -> UDbgResult<()> {
+        let auth = match self.auth.as_ref() {
+            Some(auth) => auth,
+            None => return Ok(()),
+        };
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        if command == "initialize" {
+            *self.principal.lock() = auth.authenticate(args["udbgToken"].as_str().unwrap_or(""));
+        }
+        let principal = self.principal.lock().clone();
+        auth.authorize(principal.as_ref(), command, is_mutating(command))
+    }
+
+    fn target(&self) -> UDbgResult<Arc<dyn UDbgTarget>> {
+        self.target.lock().clone().ok_or(UDbgError::NoTarget)
+    }
+
+    fn disconnect(&self, args: &Value) -> UDbgResult<Value> {
+        let target = self.target()?;
+        let terminate = args["terminateDebuggee"]
+            .as_bool()
+            .unwrap_or_else(|| self.launched.load(Ordering::Relaxed));
+        if terminate {
+            target.kill()?;
+        } else {
+            target.detach()?;
+        }
+        Ok(Value::Null)
+    }
+}
+
+fn spawn_reader(
+    reader: impl Read + Send + 'static,
+    requests: Sender<Value>,
+    client: Arc<Client>,
+    shared: Arc<Shared>,
+) {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        while let Some(request) = read_message(&mut reader).log_error("dap read").flatten() {
+            if let Err(err) = shared.authorize(&request) {
+                client.respond(&request, Err(err));
+                continue;
+            }
+            let command = request["command"].as_str().unwrap_or_default();
+            match command {
+                "pause" => {
+                    shared.pause.store(true, Ordering::Relaxed);
+                    let result = shared.target().and_then(|t| t.breakk());
+                    client.respond(&request, result.map(|_| Value::Null));
+                    continue;
+                }
+                "disconnect" | "terminate" => {
+                    let args = match command {
+                        "terminate" => json!({"terminateDebuggee": true}),
+                        _ => request["arguments"].clone(),
+                    };
+                    let result = match shared.target() {
+                        Ok(_) => shared.disconnect(&args),
+                        Err(_) => Ok(Value::Null),
+                    };
+                    client.respond(&request, result);
+                }
+                _ => {}
+            }
+            // the event loop stops waiting for the requests on disconnect
+            if requests.send(request).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+struct Frame {
+    tid: tid_t,
+    address: usize,
+    sp: usize,
+    /// The innermost frame of thread
+    top: bool,
+}
+
+struct Session {
+    client: Arc<Client>,
+    shared: Arc<Shared>,
+    requests: Receiver<Value>,
+    stop_on_entry: bool,
+    first_chance: bool,
+    second_chance: bool,
+    next_bp_id: usize,
+    /// Function breakpoints by name, None if the symbol isn't loaded yet
+    function_bps: HashMap<String, (usize, Option<Arc<dyn UDbgBreakpoint>>)>,
+    instruction_bps: HashMap<usize, Arc<dyn UDbgBreakpoint>>,
+    /// Frames of current stop, indexed by the frame id
+    frames: Vec<Frame>,
+    /// The stop is an exception, which is passed to target on continue
+    exception: bool,
+    memory: MemoryEncoder,
+}
+
+impl Session {
+    fn bp_json(id: usize, bp: Option<&Arc<dyn UDbgBreakpoint>>) -> Value {
+        match bp {
+            Some(bp) => json!({
+                "id": id,
+                "verified": true,
+                "instructionReference": format!("{:#x}", bp.address()),
+            }),
+            None => json!({
+                "id": id,
+                "verified": false,
+                "message": "pending until the symbol is loaded",
+            }),
+        }
+    }
+
+    fn add_function_bp(target: &dyn UDbgTarget, name: &str) -> Option<Arc<dyn UDbgBreakpoint>> {
+        let address = target.get_address_by_symbol(name)?;
+        target
+            .add_breakpoint(address.into())
+            .log_error_with(|err| format!("bp at {name}: {err:?}"))
+    }
+
+    fn set_function_bps(&mut self, args: &Value) -> UDbgResult<Value> {
+        let target = self.shared.target()?;
+        for (_, (_, bp)) in self.function_bps.drain() {
+            if let Some(bp) = bp {
+                bp.remove().log_error("remove bp");
+            }
+        }
+        let mut result = vec![];
+        for name in args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|b| b["name"].as_str())
+        {
+            self.next_bp_id += 1;
+            let bp = Self::add_function_bp(target.as_ref(), name);
+            result.push(Self::bp_json(self.next_bp_id, bp.as_ref()));
+            self.function_bps
+                .insert(name.to_string(), (self.next_bp_id, bp));
+        }
+        Ok(json!({ "breakpoints": result }))
+    }
+
+    /// Set the pending function breakpoints after a module is loaded
+    fn resolve_pending(&mut self, target: &dyn UDbgTarget) {
+        for (name, (id, bp)) in self.function_bps.iter_mut() {
+            if bp.is_none() {
+                *bp = Self::add_function_bp(target, name);
+                if bp.is_some() {
+                    self.client.event(
+                        "breakpoint",
+                        json!({"reason": "changed", "breakpoint": Self::bp_json(*id, bp.as_ref())}),
+                    );
+                }
+            }
+        }
+    }
+
+    fn set_instruction_bps(&mut self, args: &Value) -> UDbgResult<Value> {
+        let target = self.shared.target()?;
+        for (_, bp) in self.instruction_bps.drain() {
+            bp.remove().log_error("remove bp");
+        }
+        let mut result = vec![];
+        for b in args["breakpoints"].as_array().into_iter().flatten() {
+            self.next_bp_id += 1;
+            let address = b["instructionReference"]
+                .as_str()
+                .and_then(parse_address)
+                .map(|a| a.wrapping_add(b["offset"].as_i64().unwrap_or(0) as usize));
+            let bp = address.and_then(|a| {
+                target
+                    .add_breakpoint(a.into())
+                    .log_error_with(|err| format!("bp at {a:x}: {err:?}"))
+            });
+            result.push(match bp {
+                Some(bp) => {
+                    let json = Self::bp_json(self.next_bp_id, Some(&bp));
+                    self.instruction_bps.insert(bp.address(), bp);
+                    json
+                }
+                None => json!({
+                    "id": self.next_bp_id,
+                    "verified": false,
+                    "message": "invalid address",
+                }),
+            });
+        }
+        Ok(json!({ "breakpoints": result }))
+    }
+
+    fn threads(&self) -> UDbgResult<Value> {
+        let target = self.shared.target()?;
+        let threads = target
+            .enum_thread(false)?
+            .map(|t| {
+                let name = t.name();
+                json!({
+                    "id": t.tid,
+                    "name": match name.as_ref() {
+                        "" => format!("~{}", t.tid),
+                        name => format!("~{} {name}", t.tid),
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(json!({ "threads": threads }))
+    }
+
+    fn source_line(target: &dyn UDbgTarget, address: usize) -> Option<SourceLine> {
+        let module = target.find_module(address)?;
+        let offset = address - module.data().base;
+        module.symbol_file()?.line_at(offset as u32)
+    }
+
+    fn stack_trace(&mut self, ctx: &mut dyn TraceContext, args: &Value) -> UDbgResult<Value> {
+        let target = ctx.target();
+        let tid = args["threadId"].as_u64().ok_or("missing threadId")? as tid_t;
+        let (pc, sp) = if tid == target.base().event_tid.get() {
+            let regs = ctx.register().ok_or(UDbgError::NotSupport)?;
+            let reg = |id| regs.get_reg(id).map(|r| r.as_int());
+            (
+                reg(regid::COMM_REG_PC).ok_or(UDbgError::NotSupport)?,
+                reg(regid::COMM_REG_SP).ok_or(UDbgError::NotSupport)?,
+            )
+        } else {
+            let thread = target.open_thread(tid)?;
+            (thread.reg_value("_pc")?, thread.reg_value("_sp")?)
+        };
+
+        let mut addresses = vec![pc];
+        addresses.extend(
+            Annotator::new(target.as_ref())
+                .stack_scan(sp, STACK_SLOTS)
+                .into_iter()
+                .filter(|s| s.is_return_address())
+                .map(|s| s.value)
+                .take(MAX_FRAMES),
+        );
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = args["levels"]
+            .as_u64()
+            .filter(|&l| l > 0)
+            .map_or(addresses.len(), |l| l as usize);
+
+        let mut frames = vec![];
+        for (i, &address) in addresses.iter().enumerate().skip(start).take(levels) {
+            let id = self.frames.len();
+            self.frames.push(Frame {
+                tid,
+                address,
+                sp,
+                top: i == 0,
+            });
+            let mut frame = json!({
+                "id": id,
+                "name": target
+                    .get_symbol_string(address)
+                    .unwrap_or_else(|| format!("{address:x}")),
+                "instructionPointerReference": format!("{address:#x}"),
+                "line": 0,
+                "column": 0,
+            });
+            if let Some(line) = Self::source_line(target.as_ref(), address) {
+                frame["source"] = json!({"path": line.file.as_ref()});
+                frame["line"] = line.line.into();
+            }
+            frames.push(frame);
+        }
+        Ok(json!({"stackFrames": frames, "totalFrames": addresses.len()}))
+    }
+
+    fn scopes(&self, args: &Value) -> UDbgResult<Value> {
+        let id = args["frameId"].as_u64().ok_or("missing frameId")? as usize;
+        if id >= self.frames.len() {
+            return Err(UDbgError::NotFound);
+        }
+        Ok(json!({"scopes": [
+            {"name": "Registers", "variablesReference": id * 2 + 1, "expensive": false},
+            {"name": "Stack", "variablesReference": id * 2 + 2, "expensive": false},
+        ]}))
+    }
+
+    fn variables(&self, ctx: &mut dyn TraceContext, args: &Value) -> UDbgResult<Value> {
+        let reference = args["variablesReference"]
+            .as_u64()
+            .filter(|&r| r > 0)
+            .ok_or("missing variablesReference")? as usize;
+        let frame = self
+            .frames
+            .get((reference - 1) / 2)
+            .ok_or(UDbgError::NotFound)?;
+        let target = ctx.target();
+        let vars = if reference % 2 == 1 {
+            let arch = ctx.arch();
+            match ctx.register() {
+                Some(regs) if frame.top && frame.tid == target.base().event_tid.get() => {
+                    let view = ContextView::new(regs, arch, false);
+                    view.general
+                        .iter()
+                        .chain(view.flags.iter())
+                        .chain(view.segments.iter())
+                        .map(|r| (r.name.to_string(), format!("{:#x}", r.value.as_int())))
+                        .collect()
+                }
+                _ => vec![
+                    ("pc".into(), format!("{:#x}", frame.address)),
+                    ("sp".into(), format!("{:#x}", frame.sp)),
+                ],
+            }
+        } else {
+            Annotator::new(target.as_ref())
+                .stack_scan(frame.sp, STACK_VARIABLES)
+                .into_iter()
+                .map(|s| {
+                    let value = match s.annotation {
+                        Some(a) => format!("{:#x} {}", s.value, a.desc),
+                        None => format!("{:#x}", s.value),
+                    };
+                    (format!("[{:#x}]", s.address), value)
+                })
+                .collect::<Vec<_>>()
+        };
+        let vars = vars
+            .into_iter()
+            .map(|(name, value)| json!({"name": name, "value": value, "variablesReference": 0}))
+            .collect::<Vec<_>>();
+        Ok(json!({ "variables": vars }))
+    }
+
+    /// Read at most [`MAX_READ_MEMORY`] bytes, the bytes not returned are not counted as unreadable
+    fn read_memory(&mut self, args: &Value) -> UDbgResult<Value> {
+        let target = self.shared.target()?;
+        let address = args["memoryReference"]
+            .as_str()
+            .and_then(parse_address)
+            .ok_or("invalid memoryReference")?;
+        let address = address.wrapping_add(args["offset"].as_i64().unwrap_or(0) as usize);
+        let count = args["count"]
+            .as_u64()
+            .unwrap_or(0)
+            .min(MAX_READ_MEMORY as u64) as usize;
+        let data = target.read_bytes(address, count);
+        let mut body = json!({
+            "address": format!("{address:#x}"),
+            "unreadableBytes": count - data.len(),
+        });
+        if !data.is_empty() {
+            body["data"] = base64::encode(self.memory.encode(address, &data)?).into();
+        }
+        if !self.memory.encoding.is_plain() {
+            body["udbgEncoding"] = self.memory.encoding.names().into();
+        }
+        Ok(body)
+    }
+
+    fn continue_reply(&self) -> UserReply {
+        UserReply::Run(!self.exception)
+    }
+
+    /// Handle a request, returns the reply to resume target if it's a resuming request
+    fn handle(&mut self, request: &Value, ctx: Option<&mut dyn TraceContext>) -> Option<UserReply> {
+        let args = &request["arguments"];
+        let mut reply = None;
+        let result = match (request["command"].as_str().unwrap_or_default(), ctx) {
+            ("initialize", _) => {
+                let names = args["udbgMemoryEncoding"].as_array();
+                let encoding =
+                    Encoding::negotiate(names.into_iter().flatten().filter_map(Value::as_str));
+                self.memory = MemoryEncoder::new(encoding);
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsTerminateRequest": true,
+                    "supportsReadMemoryRequest": true,
+                    "udbgMemoryEncoding": encoding.names(),
+                    "exceptionBreakpointFilters": [
+                        {"filter": "first", "label": "First-chance exceptions", "default": false},
+                        {"filter": "second", "label": "Unhandled exceptions", "default": true},
+                    ],
+                }))
+            }
+            ("setBreakpoints", _) => {
+                let count = args["breakpoints"].as_array().map_or(0, |b| b.len());
+                let bp = json!({
+                    "verified": false,
+                    "message": "source line breakpoints are not supported",
+                });
+                Ok(json!({ "breakpoints": vec![bp; count] }))
+            }
+            ("setFunctionBreakpoints", _) => self.set_function_bps(args),
+            ("setInstructionBreakpoints", _) => self.set_instruction_bps(args),
+            ("setExceptionBreakpoints", _) => {
+                let filters = args["filters"].as_array().cloned().unwrap_or_default();
+                self.first_chance = filters.iter().any(|f| f == "first");
+                self.second_chance = filters.iter().any(|f| f == "second");
+                Ok(Value::Null)
+            }
+            ("threads", _) => self.threads(),
+            ("readMemory", _) => self.read_memory(args),
+            ("stackTrace", Some(ctx)) => self.stack_trace(ctx, args),
+            ("scopes", _) => self.scopes(args),
+            ("variables", Some(ctx)) => self.variables(ctx, args),
+            ("continue", Some(_)) => {
+                reply = Some(self.continue_reply());
+                Ok(json!({"allThreadsContinued": true}))
+            }
+            ("next", Some(_)) => {
+                reply = Some(UserReply::StepOver);
+                Ok(Value::Null)
+            }
+            ("stepIn", Some(_)) => {
+                reply = Some(UserReply::StepIn);
+                Ok(Value::Null)
+            }
+            ("stepOut", Some(_)) => {
+                reply = Some(UserReply::StepOut);
+                Ok(Value::Null)
+            }
+            // responded by the reader thread
+            ("disconnect" | "terminate", _) => return Some(UserReply::Run(false)),
+            ("stackTrace" | "variables" | "continue" | "next" | "stepIn" | "stepOut", None) => {
+                Err("target is running".into())
+            }
+            _ => Err(UDbgError::NotSupport),
+        };
+        self.client.respond(request, result);
+        reply
+    }
+
+    fn stop_reason(&mut self, target: &dyn UDbgTarget, event: &UEvent) -> Option<&'static str> {
+        let tid = target.base().event_tid.get();
+        let reason = match event {
+            UEvent::InitBp => self.stop_on_entry.then(|| "entry"),
+            UEvent::Breakpoint(bp) => Some(if self.instruction_bps.contains_key(&bp.address()) {
+                "instruction breakpoint"
+            } else if self
+                .function_bps
+                .values()
+                .any(|(_, b)| b.as_ref().map(|b| b.get_id()) == Some(bp.get_id()))
+            {
+                "function breakpoint"
+            } else {
+                "breakpoint"
+            }),
+            UEvent::Step => Some("step"),
+            UEvent::Exception { first, .. } => (if *first {
+                self.first_chance
+            } else {
+                self.second_chance
+            })
+            .then(|| "exception"),
+            UEvent::ThreadCreate(new_tid) => {
+                self.client
+                    .event("thread", json!({"reason": "started", "threadId": new_tid}));
+                None
+            }
+            UEvent::ThreadExit(_) => {
+                self.client
+                    .event("thread", json!({"reason": "exited", "threadId": tid}));
+                None
+            }
+            UEvent::ModuleLoad(m) | UEvent::ModuleUnload(m) => {
+                let data = m.data();
+                let reason = match event {
+                    UEvent::ModuleLoad(_) => "new",
+                    _ => "removed",
+                };
+                self.client.event(
+                    "module",
+                    json!({"reason": reason, "module": {
+                        "id": format!("{:x}", data.base),
+                        "name": data.name.as_ref(),
+                        "path": data.path.as_ref(),
+                        "addressRange": format!("{:#x}-{:#x}", data.base, data.base + data.size),
+                    }}),
+                );
+                if matches!(event, UEvent::ModuleLoad(_)) {
+                    self.resolve_pending(target);
+                }
+                None
+            }
+            UEvent::ProcessExit(code) => {
+                self.client.event("exited", json!({ "exitCode": code }));
+                return None;
+            }
+            _ => None,
+        };
+        if self.shared.pause.swap(false, Ordering::Relaxed) {
+            return Some(reason.unwrap_or("pause"));
+        }
+        reason
+    }
+
+    fn on_event(&mut self, ctx: &mut dyn TraceContext, event: UEvent) -> UserReply {
+        let target = ctx.target();
+        self.exception = matches!(event, UEvent::Exception { .. });
+        let reason = match self.stop_reason(target.as_ref(), &event) {
+            Some(r) => r,
+            None => return self.continue_reply(),
+        };
+
+        self.frames.clear();
+        self.client.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "description": event.to_string(),
+                "threadId": target.base().event_tid.get(),
+                "allThreadsStopped": true,
+            }),
+        );
+        while let Ok(request) = self.requests.recv() {
+            if let Some(reply) = self.handle(&request, Some(&mut *ctx)) {
+                return reply;
+            }
+        }
+        // the client is gone
+        UserReply::Run(false)
+    }
+}
+
+/// Serve a DAP client through the streams, until the client disconnects or the target exits
+pub fn serve(
+    engine: &mut dyn UDbgEngine,
+    reader: impl Read + Send + 'static,
+    writer: impl Write + Send + 'static,
+) -> UDbgResult<()> {
+    serve_with_auth(engine, reader, writer, None)
+}
+
+/// Serve a DAP client through the streams, the client is authorized by `auth` if it's set
+pub fn serve_with_auth(
+    engine: &mut dyn UDbgEngine,
+    reader: impl Read + Send + 'static,
+    writer: impl Write + Send + 'static,
+    auth: Option<Arc<Authorizer>>,
+) -> UDbgResult<()> {
+    let client = Arc::new(Client {
+        writer: Mutex::new(Box::new(writer)),
+        seq: AtomicI64::new(1),
+    });
+    let shared = Arc::new(Shared {
+        target: Mutex::new(None),
+        pause: AtomicBool::new(false),
+        launched: AtomicBool::new(false),
+        auth,
+        principal: Mutex::new(None),
+    });
+    let (sender, requests) = unbounded();
+    spawn_reader(reader, sender, client.clone(), shared.clone());
+
+    let mut session = Session {
+        client: client.clone(),
+        shared: shared.clone(),
+        requests,
+        stop_on_entry: false,
+        first_chance: false,
+        second_chance: true,
+        next_bp_id: 0,
+        function_bps: HashMap::new(),
+        instruction_bps: HashMap::new(),
+        frames: vec![],
+        exception: false,
+        memory: MemoryEncoder::default(),
+    };
+
+    // the requests before configurationDone
+    loop {
+        let request = match session.requests.recv() {
+            Ok(r) => r,
+            Err(_) => return Ok(()),
+        };
+        let args = &request["arguments"];
+        let target = match request["command"].as_str().unwrap_or_default() {
+            "launch" => args["program"]
+                .as_str()
+                .ok_or_else(|| UDbgError::from("missing program"))
+                .and_then(|program| {
+                    let argv = args["args"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(Value::as_str)
+                        .collect::<Vec<_>>();
+                    session.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                    shared.launched.store(true, Ordering::Relaxed);
+                    engine.create(program, args["cwd"].as_str(), &argv)
+                }),
+            "attach" => args["pid"]
+                .as_u64()
+                .ok_or_else(|| UDbgError::from("missing pid"))
+                .and_then(|pid| engine.attach(pid as pid_t)),
+            "configurationDone" => {
+                client.respond(&request, Ok(Value::Null));
+                break;
+            }
+            "disconnect" | "terminate" => return Ok(()),
+            _ => {
+                session.handle(&request, None);
+                continue;
+            }
+        };
+        match target {
+            Ok(target) => {
+                *shared.target.lock() = Some(target.clone());
+                client.respond(&request, Ok(json!({ "pid": target.pid() })));
+                client.event("initialized", json!({}));
+            }
+            Err(err) => client.respond(&request, Err(err)),
+        }
+    }
+    shared.target()?;
+
+    let result = engine.event_loop(&mut |ctx, event| session.on_event(ctx, event));
+    client.event("terminated", json!({}));
+    result
+}
+
+/// Serve a DAP client on stdin and stdout
+pub fn serve_stdio(engine: &mut dyn UDbgEngine) -> UDbgResult<()> {
+    serve(engine, std::io::stdin(), std::io::stdout())
+}
+
+/// Listen on the address, and serve the first DAP client connected. The `auth` is required
+/// unless the address is loopback
+pub fn serve_tcp(
+    engine: &mut dyn UDbgEngine,
+    address: impl ToSocketAddrs,
+    auth: Option<Arc<Authorizer>>,
+) -> UDbgResult<()> {
+    let listener = TcpListener::bind(address)?;
+    let local = listener.local_addr()?;
+    if auth.is_none() && !local.ip().is_loopback() {
+        return Err(format!("authorizer required to listen on {local}").into());
+    }
+    let (stream, peer) = listener.accept()?;
+    info!("dap client connected: {peer}");
+    serve_with_auth(engine, stream.try_clone()?, stream, auth)
+}
+
+/// Listen on the unix socket at path, and serve the first DAP client connected.
+/// The socket file is only accessible to the owner, and removed when the client is connected.
+#[cfg(unix)]
+pub fn serve_unix(
+    engine: &mut dyn UDbgEngine,
+    path: impl AsRef<std::path::Path>,
+    auth: Option<Arc<Authorizer>>,
+) -> UDbgResult<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = path.as_ref();
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    if let Err(err) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)) {
+        std::fs::remove_file(path).log_error("remove socket");
+        return Err(err.into());
+    }
+    let (stream, _) = listener.accept()?;
+    std::fs::remove_file(path).log_error("remove socket");
+    info!("dap client connected: {path:?}");
+    serve_with_auth(engine, stream.try_clone()?, stream, auth)
+}
+
+/// Listen on the vsock port of any CID, and serve the first DAP client connected,
+/// to debug a VM guest from the host without network. Any guest or host on the hypervisor can
+/// connect, so the `auth` is required
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn serve_vsock(
+    engine: &mut dyn UDbgEngine,
+    port: u32,
+    auth: Arc<Authorizer>,
+) -> UDbgResult<()> {
+    use core::mem::{size_of, zeroed};
+    use libc::*;
+    use std::{fs::File, os::unix::io::FromRawFd};
+
+    let stream = unsafe {
+        let fd = socket(AF_VSOCK, SOCK_STREAM | SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(UDbgError::system());
+        }
+        // closed on return
+        let _listener = File::from_raw_fd(fd);
+        let mut addr: sockaddr_vm = zeroed();
+        addr.svm_family = AF_VSOCK as _;
+        addr.svm_cid = VMADDR_CID_ANY;
+        addr.svm_port = port;
+        let len = size_of::<sockaddr_vm>() as socklen_t;
+        if bind(fd, &addr as *const _ as *const sockaddr, len) < 0 || listen(fd, 1) < 0 {
+            return Err(UDbgError::system());
+        }
+        let mut len = len;
+        let conn = accept4(
+            fd,
+            &mut addr as *mut _ as *mut sockaddr,
+            &mut len,
+            SOCK_CLOEXEC,
+        );
+        if conn < 0 {
+            return Err(UDbgError::system());
+        }
+        info!(
+            "dap client connected: vsock {}:{}",
+            addr.svm_cid, addr.svm_port
+        );
+        File::from_raw_fd(conn)
+    };
+    serve_with_auth(engine, stream.try_clone()?, stream, Some(auth))
+}
+
+/// Create the named pipe, `\\.\pipe\` is prepended if `name` is not a full path, and serve the
+/// first DAP client connected. The remote clients are rejected.
+#[cfg(windows)]
+pub fn serve_pipe(
+    engine: &mut dyn UDbgEngine,
+    name: &str,
+    auth: Option<Arc<Authorizer>>,
+) -> UDbgResult<()> {
+    let pipe = pipe::PipeStream::accept(name)?;
+    serve_with_auth(engine, pipe.try_clone()?, pipe, auth)
+}
+
+/// Named pipe opened for overlapped I/O, otherwise the blocking read of the reader thread
+/// blocks the writes to the same pipe
+#[cfg(windows)]
+mod pipe {
+    use super::*;
+    use crate::os::windows::Handle;
+
+    use core::mem::zeroed;
+    use core::ptr::null_mut;
+    use std::io::{Error as IoError, ErrorKind};
+    use winapi::shared::winerror::{ERROR_BROKEN_PIPE, ERROR_IO_PENDING, ERROR_PIPE_CONNECTED};
+    use winapi::um::{
+        errhandlingapi::GetLastError,
+        fileapi::*,
+        ioapiset::GetOverlappedResult,
+        minwinbase::*,
+        namedpipeapi::ConnectNamedPipe,
+        synchapi::{CreateEventW, SetEvent},
+        winbase::*,
+        winnt::HANDLE,
+    };
+
+    const BUFFER_SIZE: u32 = 0x10000;
+
+    pub struct PipeStream(Handle);
+
+    impl PipeStream {
+        pub fn accept(name: &str) -> UDbgResult<Self> {
+            let path = if name.starts_with(r"\\") {
+                name.to_string()
+            } else {
+                format!(r"\\.\pipe\{name}")
+            };
+            let pipe = unsafe {
+                Handle::from_raw_handle(CreateNamedPipeW(
+                    path.to_wide().as_ptr(),
+                    PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED | FILE_FLAG_FIRST_PIPE_INSTANCE,
+                    PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                    1,
+                    BUFFER_SIZE,
+                    BUFFER_SIZE,
+                    0,
+                    null_mut(),
+                ))
+            };
+            if !pipe.is_valid() {
+                return Err(UDbgError::system());
+            }
+            let pipe = Self(pipe);
+            pipe.overlapped(|h, ov| unsafe {
+                let ok = ConnectNamedPipe(h, ov);
+                if ok == 0 && GetLastError() == ERROR_PIPE_CONNECTED {
+                    // connected before the call, the event is not signaled
+                    SetEvent((*ov).hEvent)
+                } else {
+                    ok
+                }
+            })?;
+            info!("dap client connected: {path}");
+            Ok(pipe)
+        }
+
+        pub fn try_clone(&self) -> std::io::Result<Self> {
+            self.0.clone().map(Self)
+        }
+
+        fn overlapped(
+            &self,
+            f: impl FnOnce(HANDLE, *mut OVERLAPPED) -> i32,
+        ) -> std::io::Result<usize> {
+            unsafe {
+                let event = Handle::from_raw_handle(CreateEventW(null_mut(), 1, 0, null_mut()));
+                if event.is_null() {
+                    return Err(IoError::last_os_error());
+                }
+                let mut ov: OVERLAPPED = zeroed();
+                ov.hEvent = *event;
+                let mut len = 0;
+                if f(*self.0, &mut ov) == 0 && GetLastError() != ERROR_IO_PENDING {
+                    return Err(IoError::last_os_error());
+                }
+                if GetOverlappedResult(*self.0, &mut ov, &mut len, 1) == 0 {
+                    return Err(IoError::last_os_error());
+                }
+                Ok(len as usize)
+            }
+        }
+    }
+
+    impl Read for PipeStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            let result = self.overlapped(|h, ov| unsafe {
+                ReadFile(h, buf.as_mut_ptr().cast(), len, null_mut(), ov)
+            });
+            match result {
+                // the client disconnected
+                Err(err) if err.raw_os_error() == Some(ERROR_BROKEN_PIPE as _) => Ok(0),
+                r => r,
+            }
+        }
+    }
+
+    impl Write for PipeStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(u32::MAX as usize) as u32;
+            self.overlapped(|h, ov| unsafe {
+                WriteFile(h, buf.as_ptr().cast(), len, null_mut(), ov)
+            })
+            .map_err(|err| match err.raw_os_error() {
+                Some(code) if code == ERROR_BROKEN_PIPE as i32 => ErrorKind::BrokenPipe.into(),
+                _ => err,
+            })
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mutating_commands() {
+        for command in [
+            "initialize",
+            "threads",
+            "stackTrace",
+            "scopes",
+            "variables",
+            "readMemory",
+        ] {
+            assert!(!is_mutating(command), "{command}");
+        }
+        for command in [
+            "continue",
+            "next",
+            "stepIn",
+            "pause",
+            "setBreakpoints",
+            "writeMemory",
+            "evaluate",
+            "disconnect",
+        ] {
+            assert!(is_mutating(command), "{command}");
+        }
+    }
+
+    #[test]
+    fn address() {
+        assert_eq!(parse_address("0x401000"), Some(0x401000));
+        assert_eq!(parse_address(" 7ff6a000 "), Some(0x7ff6a000));
+        assert_eq!(parse_address("main"), None);
+        assert_eq!(parse_address(""), None);
+    }
+}
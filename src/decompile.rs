@@ -0,0 +1,323 @@
+//!
+//! Hook points for external decompilers, such as Ghidra headless, Binary Ninja, r2dec.
+//!
+//! The crate collects the bytes, relocations and symbols of a function from target as [`FunctionBytes`],
+//! a [`Decompiler`] backend turns it into pseudo-code, and [`DecompileCache`] caches the results
+//! by the address and content of the function, so the patched or unpacked code is decompiled again.
+//! The client of r2dec is enabled by the feature `r2dec`.
+//!
+
+use crate::prelude::*;
+
+use core::ops::Range;
+use spin::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// Bytes to read from the function start, if the function boundary is unknown
+pub const DEFAULT_FUNCTION_SIZE: usize = 0x1000;
+
+const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+/// An absolute pointer in the function, which is fixed by loader
+#[derive(Debug, Clone, Serialize)]
+pub struct Relocation {
+    pub address: usize,
+    /// The pointer value
+    pub target: usize,
+}
+
+/// Everything about a function a decompiler needs
+#[derive(Debug, Clone, Serialize)]
+pub struct FunctionBytes {
+    pub address: usize,
+    /// Architecture name, same as [`TargetBase::arch`]
+    pub arch: &'static str,
+    pub pointer_size: usize,
+    pub name: Option<String>,
+    pub bytes: Vec<u8>,
+    /// The function boundary is known, otherwise the bytes are read with [`DEFAULT_FUNCTION_SIZE`]
+    pub bounded: bool,
+    pub relocations: Vec<Relocation>,
+    /// Names of the addresses referenced by the function
+    pub symbols: Vec<(usize, String)>,
+}
+
+impl FunctionBytes {
+    /// Collect the function which contains `address`
+    pub fn collect<T: UDbgTarget + ?Sized>(target: &T, address: usize) -> UDbgResult<Self> {
+        let (range, bounded) = match target.function_range(address) {
+            Some(r) => (r, true),
+            None => {
+                let start = target.function_start(address).unwrap_or(address);
+                let end = target
+                    .virtual_query(start)
+                    .map(|p| p.base + p.size)
+                    .unwrap_or(start + DEFAULT_FUNCTION_SIZE);
+                (start..end.min(start + DEFAULT_FUNCTION_SIZE), false)
+            }
+        };
+        let bytes = target.read_bytes(range.start, range.end - range.start);
+        if bytes.is_empty() {
+            return Err(UDbgError::InvalidAddress);
+        }
+        let range = range.start..range.start + bytes.len();
+        let base = target.base();
+        let pointer_size = base.pointer_size();
+
+        let relocations = target
+            .find_module(range.start)
+            .map(|m| pe_relocations(target, m.data().base, &range))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|address| {
+                Some(Relocation {
+                    address,
+                    target: target.read_ptr(address)?,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut referenced = relocations.iter().map(|r| r.target).collect::<Vec<_>>();
+        if matches!(base.arch, "x86" | "x86_64") {
+            referenced.extend(direct_calls(&bytes, range.start));
+        }
+        referenced.sort_unstable();
+        referenced.dedup();
+        let symbols = referenced
+            .into_iter()
+            .filter_map(|a| {
+                target
+                    .get_symbol_(a, Some(0))
+                    .filter(|s| !s.symbol.is_empty())
+                    .map(|s| (a, format!("{}!{}", s.module, s.symbol)))
+            })
+            .collect();
+
+        Ok(Self {
+            address: range.start,
+            arch: base.arch,
+            pointer_size,
+            name: target
+                .get_symbol_(range.start, Some(0))
+                .filter(|s| !s.symbol.is_empty())
+                .map(|s| s.symbol.to_string()),
+            bytes,
+            bounded,
+            relocations,
+            symbols,
+        })
+    }
+
+    #[inline]
+    pub fn range(&self) -> Range<usize> {
+        self.address..self.address + self.bytes.len()
+    }
+
+    fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.bytes.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Targets of the `call rel32`/`jmp rel32` in x86 code, false positives are possible
+fn direct_calls(bytes: &[u8], address: usize) -> Vec<usize> {
+    let mut result = vec![];
+    for i in 0..bytes.len().saturating_sub(4) {
+        if bytes[i] == 0xE8 || bytes[i] == 0xE9 {
+            let disp = i32::from_le_bytes(bytes[i + 1..i + 5].try_into().unwrap()) as isize;
+            result.push(((address + i + 5) as isize).wrapping_add(disp) as usize);
+        }
+    }
+    result
+}
+
+/// Addresses of the base relocations of PE image at `base` in `range`
+pub(crate) fn pe_relocations<T: ReadMemory + ?Sized>(
+    target: &T,
+    base: usize,
+    range: &Range<usize>,
+) -> Vec<usize> {
+    let read_dir = || -> Option<(usize, usize)> {
+        if target.read_value::<u16>(base)? != 0x5A4D {
+            return None;
+        }
+        let nt = base + target.read_value::<u32>(base + 0x3C)? as usize;
+        let optional = nt + 24;
+        let dirs = match target.read_value::<u16>(optional)? {
+            // PE32+
+            0x20B => optional + 112,
+            _ => optional + 96,
+        };
+        let dir = dirs + IMAGE_DIRECTORY_ENTRY_BASERELOC * 8;
+        let rva = target.read_value::<u32>(dir)? as usize;
+        let size = target.read_value::<u32>(dir + 4)? as usize;
+        (rva > 0 && size > 0).then(|| (base + rva, size))
+    };
+    let (dir, size) = match read_dir() {
+        Some(d) => d,
+        None => return vec![],
+    };
+
+    let data = target.read_bytes(dir, size);
+    let mut result = vec![];
+    let mut block = data.as_slice();
+    while block.len() >= 8 {
+        let page = base + u32::from_le_bytes(block[..4].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(block[4..8].try_into().unwrap()) as usize;
+        if len < 8 || len > block.len() {
+            break;
+        }
+        if page < range.end && page + 0x1000 > range.start {
+            for e in block[8..len].chunks_exact(2) {
+                let e = u16::from_le_bytes([e[0], e[1]]);
+                let address = page + (e & 0xFFF) as usize;
+                if matches!(e >> 12, IMAGE_REL_BASED_HIGHLOW | IMAGE_REL_BASED_DIR64)
+                    && range.contains(&address)
+                {
+                    result.push(address);
+                }
+            }
+        }
+        block = &block[len..];
+    }
+    result
+}
+
+/// Backend which turns a function into pseudo-code
+pub trait Decompiler: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn decompile(&self, func: &FunctionBytes) -> UDbgResult<String>;
+}
+
+/// Decompile with a backend, and cache the results by function address and content
+pub struct DecompileCache {
+    decompiler: Box<dyn Decompiler>,
+    cache: Mutex<HashMap<(usize, u64), Arc<str>>>,
+}
+
+impl DecompileCache {
+    pub fn new(decompiler: Box<dyn Decompiler>) -> Self {
+        Self {
+            decompiler,
+            cache: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn decompiler(&self) -> &dyn Decompiler {
+        self.decompiler.as_ref()
+    }
+
+    /// Pseudo-code of the function which contains `address`
+    pub fn pseudo_code<T: UDbgTarget + ?Sized>(
+        &self,
+        target: &T,
+        address: usize,
+    ) -> UDbgResult<Arc<str>> {
+        let func = FunctionBytes::collect(target, address)?;
+        let key = (func.address, func.content_hash());
+        if let Some(code) = self.cache.lock().get(&key) {
+            return Ok(code.clone());
+        }
+        let code: Arc<str> = self.decompiler.decompile(&func)?.into();
+        self.cache.lock().insert(key, code.clone());
+        Ok(code)
+    }
+
+    /// Drop the cached results of the functions overlapped with `range`, or all if None
+    pub fn invalidate(&self, range: Option<Range<usize>>) {
+        let mut cache = self.cache.lock();
+        match range {
+            Some(r) => cache.retain(|(a, _), _| !r.contains(a)),
+            None => cache.clear(),
+        }
+    }
+}
+
+#[cfg(feature = "r2dec")]
+pub use self::r2dec::R2Dec;
+
+#[cfg(feature = "r2dec")]
+mod r2dec {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    /// Decompile by [r2dec](https://github.com/wargio/r2dec-js) plugin of radare2, through the `r2` command line
+    pub struct R2Dec {
+        /// Path of `r2` executable
+        pub r2: PathBuf,
+    }
+
+    impl Default for R2Dec {
+        fn default() -> Self {
+            Self { r2: "r2".into() }
+        }
+    }
+
+    /// Flag name acceptable by radare2
+    fn flag_name(name: &str) -> String {
+        name.chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' => c,
+                _ => '_',
+            })
+            .collect()
+    }
+
+    impl Decompiler for R2Dec {
+        fn name(&self) -> &str {
+            "r2dec"
+        }
+
+        fn decompile(&self, func: &FunctionBytes) -> UDbgResult<String> {
+            let (arch, bits) = match func.arch {
+                "x86" | "x86_64" => ("x86", func.pointer_size * 8),
+                "arm" | "aarch64" => ("arm", func.pointer_size * 8),
+                _ => return Err(UDbgError::NotSupport),
+            };
+            let path = std::env::temp_dir().join(format!(
+                "udbg-r2dec-{}-{:x}.bin",
+                std::process::id(),
+                func.address
+            ));
+            std::fs::write(&path, &func.bytes)?;
+
+            let mut commands = func
+                .symbols
+                .iter()
+                .map(|(a, n)| format!("f sym.{} = 0x{a:x}", flag_name(n)))
+                .collect::<Vec<_>>();
+            if let Some(name) = func.name.as_ref() {
+                commands.push(format!("f sym.{} = 0x{:x}", flag_name(name), func.address));
+            }
+            commands.push(format!("s 0x{:x}", func.address));
+            commands.push("af".into());
+            commands.push("pdd".into());
+
+            let output = Command::new(&self.r2)
+                .arg("-q")
+                .arg("-2")
+                .args(["-a", arch])
+                .args(["-b", &bits.to_string()])
+                .args(["-m", &format!("0x{:x}", func.address)])
+                .args(["-c", &commands.join(";")])
+                .arg(&path)
+                .output();
+            std::fs::remove_file(&path).ok();
+            let output = output?;
+            if !output.status.success() {
+                return Err(String::from_utf8_lossy(&output.stderr).to_string().into());
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).into())
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//!
+//! Disassembly of the target code into structured instructions, by iced on x86/x64 and by capstone
+//! on ARM, which requires the `capstone` feature.
+//!
+//! The original bytes under the software breakpoints are disassembled, and the operands referring
+//! to addresses are symbolized by the target: the branch targets, the immediates and the absolute
+//! or RIP-relative memory operands, whose pointed values are symbolized too.
+//!
+
+use crate::prelude::*;
+
+/// How an instruction passes the control
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowKind {
+    Next,
+    Jump,
+    ConditionalJump,
+    IndirectJump,
+    Call,
+    IndirectCall,
+    Return,
+    Interrupt,
+    /// An instruction always raising an exception, such as `ud2`
+    Exception,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Operand {
+    Reg(String),
+    Imm {
+        value: u64,
+        symbol: Option<String>,
+    },
+    /// Target of a relative branch
+    Branch {
+        address: usize,
+        symbol: Option<String>,
+    },
+    Mem {
+        base: Option<String>,
+        index: Option<String>,
+        scale: u32,
+        displacement: i64,
+        /// Size of the accessed memory, 0 if unknown
+        size: usize,
+        /// The address if it's absolute or RIP-relative
+        address: Option<usize>,
+        symbol: Option<String>,
+        /// Symbol of the pointer at `address`, such as an IAT slot
+        pointee: Option<String>,
+    },
+}
+
+/// A disassembled instruction
+#[derive(Debug, Clone, Serialize)]
+pub struct Insn {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    pub mnemonic: String,
+    /// Formatted instruction
+    pub text: String,
+    pub operands: Vec<Operand>,
+    pub flow: FlowKind,
+    /// Has a `rep` prefix
+    pub repeated: bool,
+}
+
+impl Insn {
+    /// Address of the next instruction
+    #[inline]
+    pub fn next(&self) -> usize {
+        self.address + self.bytes.len()
+    }
+
+    /// Target of the relative branch
+    pub fn branch_target(&self) -> Option<usize> {
+        self.operands.iter().find_map(|o| match o {
+            Operand::Branch { address, .. } => Some(*address),
+            _ => None,
+        })
+    }
+
+    /// A call, syscall or `rep` instruction, which the stepping over runs through
+    pub fn is_step_over(&self) -> bool {
+        matches!(self.flow, FlowKind::Call | FlowKind::IndirectCall)
+            || self.repeated
+            || matches!(self.mnemonic.as_str(), "syscall" | "sysenter" | "svc")
+    }
+}
+
+/// Read the code, with the original bytes under the software breakpoints
+pub fn read_code<T: UDbgTarget + ?Sized>(target: &T, address: usize, size: usize) -> Vec<u8> {
+    let mut buffer = target.read_bytes(address, size);
+    let range = address..address + buffer.len();
+    for bp in target.get_breakpoints() {
+        let a = bp.address();
+        if let Some(origin) = bp.origin_bytes().filter(|_| range.contains(&a)) {
+            let i = a - address;
+            let len = origin.len().min(buffer.len() - i);
+            buffer[i..i + len].copy_from_slice(&origin[..len]);
+        }
+    }
+    buffer
+}
+
+fn symbol_of<T: UDbgTarget + ?Sized>(target: &T, address: usize) -> Option<String> {
+    // the small values are not addresses
+    if address < 0x10000 {
+        return None;
+    }
+    target.get_symbol_string(address)
+}
+
+/// Disassemble `count` instructions at `address` in the context architecture of target, stops at
+/// the first invalid instruction
+pub fn disasm<T: UDbgTarget + ?Sized>(
+    target: &T,
+    address: usize,
+    count: usize,
+) -> UDbgResult<Vec<Insn>> {
+    match target.base().context_arch.get() {
+        ARCH_X86 => Ok(x86::disasm(target, address, count, 32)),
+        ARCH_X64 => Ok(x86::disasm(target, address, count, 64)),
+        #[cfg(feature = "capstone")]
+        arch @ (ARCH_ARM | ARCH_ARM64) => arm::disasm(target, address, count, arch),
+        _ => Err(UDbgError::NotSupport),
+    }
+}
+
+mod x86 {
+    use super::*;
+    use iced_x86::{
+        Decoder, DecoderOptions, FlowControl, Formatter, Instruction, IntelFormatter, OpKind,
+        Register,
+    };
+
+    const MAX_X86_INSN: usize = 15;
+
+    fn reg_name(reg: Register) -> Option<String> {
+        (reg != Register::None).then(|| format!("{reg:?}").to_lowercase())
+    }
+
+    fn operand<T: UDbgTarget + ?Sized>(target: &T, insn: &Instruction, i: u32) -> Option<Operand> {
+        Some(match insn.op_kind(i) {
+            OpKind::Register => Operand::Reg(reg_name(insn.op_register(i))?),
+            OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64 => {
+                let address = insn.near_branch_target() as usize;
+                Operand::Branch {
+                    address,
+                    symbol: symbol_of(target, address),
+                }
+            }
+            OpKind::Memory => {
+                let address = if insn.is_ip_rel_memory_operand() {
+                    Some(insn.ip_rel_memory_address() as usize)
+                } else if insn.memory_base() == Register::None
+                    && insn.memory_index() == Register::None
+                {
+                    Some(insn.memory_displacement64() as usize)
+                } else {
+                    None
+                };
+                let layout = target.base().data_layout();
+                Operand::Mem {
+                    base: reg_name(insn.memory_base()),
+                    index: reg_name(insn.memory_index()),
+                    scale: insn.memory_index_scale(),
+                    displacement: insn.memory_displacement64() as i64,
+                    size: insn.memory_size().size(),
+                    address,
+                    symbol: address.and_then(|a| symbol_of(target, a)),
+                    pointee: address
+                        .and_then(|a| target.read_pointer(a, layout))
+                        .and_then(|p| symbol_of(target, p)),
+                }
+            }
+            OpKind::Immediate8
+            | OpKind::Immediate8_2nd
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate64
+            | OpKind::Immediate8to16
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate32to64 => {
+                let value = insn.immediate(i);
+                Operand::Imm {
+                    value,
+                    symbol: symbol_of(target, value as usize),
+                }
+            }
+            // the far branches and the implicit string operands
+            _ => return None,
+        })
+    }
+
+    fn flow(insn: &Instruction) -> FlowKind {
+        match insn.flow_control() {
+            FlowControl::UnconditionalBranch => FlowKind::Jump,
+            FlowControl::ConditionalBranch => FlowKind::ConditionalJump,
+            FlowControl::IndirectBranch => FlowKind::IndirectJump,
+            FlowControl::Call => FlowKind::Call,
+            FlowControl::IndirectCall => FlowKind::IndirectCall,
+            FlowControl::Return => FlowKind::Return,
+            FlowControl::Interrupt => FlowKind::Interrupt,
+            FlowControl::Exception => FlowKind::Exception,
+            _ => FlowKind::Next,
+        }
+    }
+
+    pub fn disasm<T: UDbgTarget + ?Sized>(
+        target: &T,
+        address: usize,
+        count: usize,
+        bitness: u32,
+    ) -> Vec<Insn> {
+        let buffer = read_code(target, address, count * MAX_X86_INSN);
+        let mut decoder = Decoder::with_ip(bitness, &buffer, address as u64, DecoderOptions::NONE);
+        let mut formatter = IntelFormatter::new();
+        let mut insn = Instruction::default();
+        let mut result = Vec::with_capacity(count);
+        while result.len() < count && decoder.can_decode() {
+            let offset = decoder.position();
+            decoder.decode_out(&mut insn);
+            if insn.is_invalid() {
+                break;
+            }
+            let mut text = String::new();
+            formatter.format(&insn, &mut text);
+            result.push(Insn {
+                address: insn.ip() as usize,
+                bytes: buffer[offset..offset + insn.len()].to_vec(),
+                mnemonic: format!("{:?}", insn.mnemonic()).to_lowercase(),
+                text,
+                operands: (0..insn.op_count())
+                    .filter_map(|i| operand(target, &insn, i))
+                    .collect(),
+                flow: flow(&insn),
+                repeated: insn.has_rep_prefix() || insn.has_repne_prefix(),
+            });
+        }
+        result
+    }
+}
+
+#[cfg(feature = "capstone")]
+mod arm {
+    use super::*;
+    use crate::capstone::{simplify_operand, SimpleOperand, CS};
+    use capstone::{prelude::*, InsnGroupType};
+
+    const ARM_INSN_SIZE: usize = 4;
+
+    fn flow(mnemonic: &str, groups: &[InsnGroupId], has_branch: bool) -> FlowKind {
+        let is = |g: u32| groups.iter().any(|i| i.0 as u32 == g);
+        // the condition codes of ARM64 and the compare-and-branch instructions
+        let conditional =
+            mnemonic.contains('.') || mnemonic.starts_with("cb") || mnemonic.starts_with("tb");
+        if is(InsnGroupType::CS_GRP_RET) {
+            FlowKind::Return
+        } else if is(InsnGroupType::CS_GRP_CALL) {
+            if has_branch {
+                FlowKind::Call
+            } else {
+                FlowKind::IndirectCall
+            }
+        } else if is(InsnGroupType::CS_GRP_INT) {
+            FlowKind::Interrupt
+        } else if is(InsnGroupType::CS_GRP_JUMP) {
+            match (has_branch, conditional) {
+                (true, true) => FlowKind::ConditionalJump,
+                (true, false) => FlowKind::Jump,
+                _ => FlowKind::IndirectJump,
+            }
+        } else {
+            FlowKind::Next
+        }
+    }
+
+    pub fn disasm<T: UDbgTarget + ?Sized>(
+        target: &T,
+        address: usize,
+        count: usize,
+        arch: u32,
+    ) -> UDbgResult<Vec<Insn>> {
+        let cs = if arch == ARCH_ARM { &CS.arm } else { &CS.arm64 };
+        let buffer = read_code(target, address, count * ARM_INSN_SIZE);
+        let insns = cs
+            .disasm_count(&buffer, address as u64, count)
+            .map_err(|err| UDbgError::from(err.to_string()))?;
+
+        let mut result = Vec::with_capacity(count);
+        for i in insns.iter() {
+            let detail = cs
+                .insn_detail(i)
+                .map_err(|err| UDbgError::from(err.to_string()))?;
+            let mnemonic = i.mnemonic().unwrap_or_default().to_string();
+            let jumps = detail.groups().iter().any(|g| {
+                g.0 as u32 == InsnGroupType::CS_GRP_JUMP || g.0 as u32 == InsnGroupType::CS_GRP_CALL
+            });
+            let operands = detail
+                .arch_detail()
+                .operands()
+                .iter()
+                .filter_map(|o| {
+                    Some(match simplify_operand(i, o) {
+                        SimpleOperand::Reg(r) => Operand::Reg(cs.reg_name(r)?),
+                        SimpleOperand::Imm(value) if jumps => Operand::Branch {
+                            address: value,
+                            symbol: symbol_of(target, value),
+                        },
+                        SimpleOperand::Imm(value) => Operand::Imm {
+                            value: value as u64,
+                            symbol: symbol_of(target, value),
+                        },
+                        _ => return None,
+                    })
+                })
+                .collect::<Vec<_>>();
+            let has_branch = operands.iter().any(|o| matches!(o, Operand::Branch { .. }));
+            result.push(Insn {
+                address: i.address() as usize,
+                bytes: i.bytes().to_vec(),
+                flow: flow(&mnemonic, detail.groups(), has_branch),
+                text: format!("{} {}", mnemonic, i.op_str().unwrap_or_default())
+                    .trim_end()
+                    .to_string(),
+                mnemonic,
+                operands,
+                repeated: false,
+            });
+        }
+        Ok(result)
+    }
+}
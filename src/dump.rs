@@ -0,0 +1,308 @@
+//!
+//! Dump memory regions to files, each with a JSON sidecar describing where the bytes come from.
+//!
+//! The sidecar `<file>.json` holds the base, protection, owner module, hashes and the time of dump,
+//! so the dumped files can be fed to the analysis pipelines without the live target.
+//!
+//! [`dump_module`] dumps a PE module as a file loadable by the static analysis tools,
+//! with the import directory rebuilt from the runtime IAT by [`ImportTable`].
+//!
+
+use crate::{entropy::shannon_entropy, iat::ImportTable, prelude::*};
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CHUNK_SIZE: usize = 0x1000;
+
+/// Module which the dumped region belongs to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DumpModule {
+    pub name: String,
+    pub path: String,
+    pub base: usize,
+    /// Offset of the region in module
+    pub offset: usize,
+}
+
+/// Content of the sidecar file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionDump {
+    /// Path of the raw bytes
+    pub file: PathBuf,
+    pub pid: pid_t,
+    pub base: usize,
+    pub size: usize,
+    pub alloc_base: usize,
+    pub protect: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub usage: Option<String>,
+    pub module: Option<DumpModule>,
+    pub md5: String,
+    pub sha1: String,
+    pub sha256: String,
+    pub entropy: f32,
+    /// Bytes failed to read, filled with zero in the dumped file
+    pub unreadable: usize,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// Read the region, the unreadable pages are filled with zero, returns the count of them in bytes
+fn read_region<T: ReadMemory + ?Sized>(target: &T, base: usize, size: usize) -> (Vec<u8>, usize) {
+    let mut data = vec![0u8; size];
+    let mut unreadable = 0;
+    for (i, chunk) in data.chunks_mut(CHUNK_SIZE).enumerate() {
+        let len = chunk.len();
+        let read = target
+            .read_memory(base + i * CHUNK_SIZE, chunk)
+            .map_or(0, |r| r.len());
+        unreadable += len - read;
+    }
+    (data, unreadable)
+}
+
+impl MemoryPage {
+    /// Dump the bytes of this region to `path`, and the metadata to `<path>.json`
+    pub fn dump<T: UDbgTarget + ?Sized, P: AsRef<Path>>(
+        &self,
+        target: &T,
+        path: P,
+    ) -> UDbgResult<RegionDump> {
+        let path = path.as_ref();
+        let (data, unreadable) = read_region(target, self.base, self.size);
+        if unreadable == data.len() {
+            return Err(UDbgError::InvalidAddress);
+        }
+        std::fs::write(path, &data)?;
+
+        let result = RegionDump {
+            file: path.into(),
+            pid: target.base().pid.get(),
+            base: self.base,
+            size: self.size,
+            alloc_base: self.alloc_base,
+            protect: self.protect().into(),
+            type_: self.type_().into(),
+            usage: self.info.as_ref().map(ToString::to_string),
+            module: target.find_module(self.base).map(|m| {
+                let data = m.data();
+                DumpModule {
+                    name: data.name.to_string(),
+                    path: data.path.to_string(),
+                    base: data.base,
+                    offset: self.base - data.base,
+                }
+            }),
+            md5: hex::encode(Md5::digest(&data)),
+            sha1: hex::encode(Sha1::digest(&data)),
+            sha256: hex::encode(Sha256::digest(&data)),
+            entropy: shannon_entropy(&data),
+            unreadable,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        };
+        let sidecar = serde_json::to_vec_pretty(&result).map_err(std::io::Error::from)?;
+        std::fs::write(sidecar_path(path), sidecar)?;
+        Ok(result)
+    }
+
+    /// Private RWX region, usually allocated for the injected or unpacked code
+    pub fn is_private_rwx(&self) -> bool {
+        self.is_commit() && self.is_private() && self.is_executable() && self.is_writable()
+    }
+}
+
+/// Path of the sidecar file of a dumped file
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut result = path.as_os_str().to_owned();
+    result.push(".json");
+    result.into()
+}
+
+/// Dump all the committed regions matching `filter` into `dir`, named as `<pid>_<base>.bin`,
+/// the regions failed to dump are logged and skipped
+pub fn dump_regions<T: UDbgTarget + ?Sized, P: AsRef<Path>>(
+    target: &T,
+    dir: P,
+    filter: impl Fn(&MemoryPage) -> bool,
+) -> UDbgResult<Vec<RegionDump>> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+    let pid = target.base().pid.get();
+    Ok(target
+        .enum_memory()?
+        .filter(|p| p.is_commit() && filter(p))
+        .filter_map(|p| {
+            p.dump(target, dir.join(format!("{pid}_{:x}.bin", p.base)))
+                .log_error_with(|err| format!("dump {:x}: {err:?}", p.base))
+        })
+        .collect())
+}
+
+/// Options of [`dump_module`]
+#[derive(Debug, Clone, Default)]
+pub struct ModuleDumpOptions {
+    /// New entry point, e.g. the OEP found by [`crate::unpack::OepTracker`]
+    pub entry: Option<usize>,
+    /// Rebuild the import directory from the runtime IAT
+    pub rebuild_imports: bool,
+    /// IAT range, located automatically if None
+    pub iat: Option<core::ops::Range<usize>>,
+}
+
+/// Result of [`dump_module`]
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleDump {
+    pub file: PathBuf,
+    pub base: usize,
+    pub size: usize,
+    pub entry: usize,
+    pub imports: Option<ImportTable>,
+}
+
+const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+const IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT: usize = 11;
+const IMAGE_DIRECTORY_ENTRY_IAT: usize = 12;
+const IMPORT_SECTION: &[u8; 8] = b".udbgimp";
+/// IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE
+const IMPORT_SECTION_FLAGS: u32 = 0xC0000040;
+
+fn get_u32(image: &[u8], offset: usize) -> UDbgResult<u32> {
+    image
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or(UDbgError::InvalidAddress)
+}
+
+fn put_u32(image: &mut [u8], offset: usize, value: u32) -> UDbgResult<()> {
+    image
+        .get_mut(offset..offset + 4)
+        .ok_or(UDbgError::InvalidAddress)?
+        .copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+#[inline]
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+/// Dump a PE module from memory to a file which can be loaded by the static analysis tools.
+///
+/// The sections are laid out as in memory, the image base is set to the loaded address so
+/// the relocations are not needed, and the import directory is optionally rebuilt from the IAT
+/// in a new section.
+pub fn dump_module<T: UDbgTarget + ?Sized, P: AsRef<Path>>(
+    target: &T,
+    module: &dyn UDbgModule,
+    path: P,
+    options: &ModuleDumpOptions,
+) -> UDbgResult<ModuleDump> {
+    let data = module.data();
+    let (mut image, _) = read_region(target, data.base, data.size);
+    if image.get(..2) != Some(b"MZ") {
+        return Err(UDbgError::NotSupport);
+    }
+    let nt = get_u32(&image, 0x3C)? as usize;
+    if get_u32(&image, nt)? != 0x4550 {
+        return Err(UDbgError::NotSupport);
+    }
+    let count = u16::from_le_bytes([image[nt + 6], image[nt + 7]]) as usize;
+    let optional_size = u16::from_le_bytes([image[nt + 20], image[nt + 21]]) as usize;
+    let optional = nt + 24;
+    let pe64 = u16::from_le_bytes([image[optional], image[optional + 1]]) == 0x20B;
+    let (pointer_size, dirs) = if pe64 {
+        (8, optional + 112)
+    } else {
+        (4, optional + 96)
+    };
+    let section_align = get_u32(&image, optional + 32)? as usize;
+    let headers_size = get_u32(&image, optional + 60)? as usize;
+    let table = optional + optional_size;
+
+    // raw layout = memory layout
+    for i in 0..count {
+        let s = table + i * 40;
+        let virtual_size = get_u32(&image, s + 8)?;
+        let address = get_u32(&image, s + 12)?;
+        let raw_size = get_u32(&image, s + 16)?;
+        put_u32(&mut image, s + 16, virtual_size.max(raw_size))?;
+        put_u32(&mut image, s + 20, address)?;
+    }
+    put_u32(&mut image, optional + 36, section_align as u32)?;
+    if pe64 {
+        image[optional + 24..optional + 32].copy_from_slice(&(data.base as u64).to_le_bytes());
+    } else {
+        put_u32(&mut image, optional + 28, data.base as u32)?;
+    }
+    let entry = options.entry.unwrap_or_else(|| data.entry_point());
+    put_u32(&mut image, optional + 16, (entry - data.base) as u32)?;
+    // checksum
+    put_u32(&mut image, optional + 64, 0)?;
+
+    let imports = if options.rebuild_imports {
+        let imports = ImportTable::scan(target, module, options.iat.clone())?;
+        let header = table + count * 40;
+        if header + 40 > headers_size {
+            return Err("no room for the import section header".into());
+        }
+        let rva = align_up(image.len(), section_align);
+        let section = imports.build(data.base, rva, pointer_size);
+        let size = align_up(section.data.len(), section_align);
+        image.resize(rva, 0);
+        image.extend_from_slice(&section.data);
+        image.resize(rva + size, 0);
+        for &(slot, value) in section.thunks.iter() {
+            image[slot..slot + pointer_size].copy_from_slice(&value.to_le_bytes()[..pointer_size]);
+        }
+
+        image[header..header + 8].copy_from_slice(IMPORT_SECTION);
+        put_u32(&mut image, header + 8, size as u32)?;
+        put_u32(&mut image, header + 12, rva as u32)?;
+        put_u32(&mut image, header + 16, size as u32)?;
+        put_u32(&mut image, header + 20, rva as u32)?;
+        put_u32(&mut image, header + 36, IMPORT_SECTION_FLAGS)?;
+        image[nt + 6..nt + 8].copy_from_slice(&(count as u16 + 1).to_le_bytes());
+        put_u32(&mut image, optional + 56, image.len() as u32)?;
+
+        let (dir_rva, dir_size) = section.directory;
+        let dir = dirs + IMAGE_DIRECTORY_ENTRY_IMPORT * 8;
+        put_u32(&mut image, dir, dir_rva)?;
+        put_u32(&mut image, dir + 4, dir_size)?;
+        let dir = dirs + IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT * 8;
+        put_u32(&mut image, dir, 0)?;
+        put_u32(&mut image, dir + 4, 0)?;
+        let dir = dirs + IMAGE_DIRECTORY_ENTRY_IAT * 8;
+        put_u32(&mut image, dir, (imports.iat.start - data.base) as u32)?;
+        put_u32(
+            &mut image,
+            dir + 4,
+            (imports.iat.end - imports.iat.start) as u32,
+        )?;
+
+        let unresolved = imports.unresolved();
+        if unresolved > 0 {
+            warn!("{unresolved} IAT slots of {} are not resolved", data.name);
+        }
+        Some(imports)
+    } else {
+        None
+    };
+
+    let path = path.as_ref();
+    std::fs::write(path, &image)?;
+    Ok(ModuleDump {
+        file: path.into(),
+        base: data.base,
+        size: image.len(),
+        entry,
+        imports,
+    })
+}
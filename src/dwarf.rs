@@ -0,0 +1,323 @@
+//!
+//! DWARF debug information of ELF modules, as a [`SymbolFile`] like the PDB on Windows.
+//!
+//! The functions, source lines and inlined functions are parsed by gimli on first use.
+//! If the module itself is stripped, the separate debug file is searched by the build id
+//! and the path in `/usr/lib/debug`. The addresses are converted to the offsets in module by
+//! the lowest loadable segment.
+//!
+
+use crate::{elf::ElfHelper, prelude::*};
+
+use anyhow::Context;
+use gimli::{
+    AttributeValue, DebuggingInformationEntry, Dwarf, EndianSlice, FileEntry, LineProgramHeader,
+    RunTimeEndian, SectionId, Unit,
+};
+use goblin::elf::{
+    program_header::PT_LOAD,
+    section_header::{SHF_COMPRESSED, SHT_NOBITS},
+    Elf,
+};
+use spin::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+type Reader<'a> = EndianSlice<'a, RunTimeEndian>;
+
+/// Max depth to follow the abstract origins for the name
+const MAX_ORIGIN_DEPTH: usize = 8;
+
+struct InlineRange {
+    start: u32,
+    end: u32,
+    depth: usize,
+    frame: InlineFrame,
+}
+
+#[derive(Default)]
+struct DebugInfo {
+    symbols: Arc<SymbolMap>,
+    lines: BTreeMap<u32, SourceLine>,
+    /// Sorted by start
+    inlines: Vec<InlineRange>,
+}
+
+pub struct DwarfData {
+    pub path: Arc<str>,
+    info: Mutex<Option<Arc<DebugInfo>>>,
+}
+
+/// If the ELF has the DWARF debug information
+fn has_debug_info(elf: &Elf) -> bool {
+    elf.section_headers.iter().any(|s| {
+        s.sh_type != SHT_NOBITS && elf.shdr_strtab.get_at(s.sh_name) == Some(".debug_info")
+    })
+}
+
+/// The separate debug file of a stripped module
+fn find_debug_file(path: &str, build_id: Option<&[u8]>) -> Option<PathBuf> {
+    let mut paths = vec![];
+    if let Some(id) = build_id.filter(|id| id.len() > 1) {
+        let id = hex::encode(id);
+        paths.push(
+            Path::new("/usr/lib/debug/.build-id")
+                .join(&id[..2])
+                .join(format!("{}.debug", &id[2..])),
+        );
+    }
+    paths.push(PathBuf::from(format!("/usr/lib/debug{path}.debug")));
+    paths.into_iter().find(|p| p.is_file())
+}
+
+impl DwarfData {
+    /// Load the DWARF of module at `path`, or of its separate debug file
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let map = Utils::mapfile(path).context("map")?;
+        let elf = ElfHelper::parse(&map).context("parse")?;
+        let path = if has_debug_info(&elf) {
+            path.into()
+        } else {
+            let debug = find_debug_file(path, elf.build_id(&map)).context("no debug info")?;
+            debug.to_string_lossy().as_ref().into()
+        };
+        Ok(Self {
+            path,
+            info: None.into(),
+        })
+    }
+
+    fn info(&self) -> Arc<DebugInfo> {
+        if let Some(info) = self.info.lock().clone() {
+            return info;
+        }
+        let info = Arc::new(
+            Utils::mapfile(&self.path)
+                .and_then(|map| parse(&map))
+                .log_error_with(|err| format!("load dwarf of {}: {err:?}", self.path))
+                .unwrap_or_default(),
+        );
+        *self.info.lock() = info.clone().into();
+        info
+    }
+}
+
+impl SymbolFile for DwarfData {
+    fn path(&self) -> &str {
+        self.path.as_ref()
+    }
+
+    fn global(&self) -> anyhow::Result<Arc<SymbolMap>> {
+        Ok(self.info().symbols.clone())
+    }
+
+    fn find_line(&self, offset: u32) -> Option<SourceLine> {
+        self.info().lines.get(&offset).cloned()
+    }
+
+    fn line_at(&self, offset: u32) -> Option<SourceLine> {
+        self.info()
+            .lines
+            .range(..=offset)
+            .next_back()
+            .map(|(_, line)| line.clone())
+    }
+
+    fn inline_frames(&self, offset: u32) -> Vec<InlineFrame> {
+        let info = self.info();
+        let end = info.inlines.partition_point(|r| r.start <= offset);
+        let mut result = info.inlines[..end]
+            .iter()
+            .filter(|r| offset < r.end)
+            .collect::<Vec<_>>();
+        result.sort_by_key(|r| r.depth);
+        result.into_iter().map(|r| r.frame.clone()).collect()
+    }
+}
+
+fn parse(data: &[u8]) -> anyhow::Result<DebugInfo> {
+    let elf = Elf::parse(data).context("parse")?;
+    let endian = if elf.little_endian {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let bias = elf
+        .program_headers
+        .iter()
+        .filter(|p| p.p_type == PT_LOAD)
+        .map(|p| p.p_vaddr & !0xFFF)
+        .min()
+        .unwrap_or(0);
+    let load = |id: SectionId| -> Result<Reader, gimli::Error> {
+        let section = elf
+            .section_headers
+            .iter()
+            .filter(|s| s.sh_type != SHT_NOBITS && s.sh_flags & SHF_COMPRESSED as u64 == 0)
+            .find(|s| elf.shdr_strtab.get_at(s.sh_name) == Some(id.name()))
+            .and_then(|s| data.get(s.sh_offset as usize..(s.sh_offset + s.sh_size) as usize));
+        Ok(EndianSlice::new(section.unwrap_or_default(), endian))
+    };
+    let dwarf = Dwarf::load(load)?;
+    let offset = |address: u64| address.wrapping_sub(bias) as u32;
+
+    let mut symbols = SymbolMap::default();
+    let mut lines = BTreeMap::new();
+    let mut inlines = vec![];
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let header = unit.line_program.as_ref().map(|p| p.header().clone());
+        let mut files = HashMap::<u64, Arc<str>>::new();
+        let mut file_name = |index: u64| -> Option<Arc<str>> {
+            let header = header.as_ref()?;
+            if let Some(name) = files.get(&index) {
+                return Some(name.clone());
+            }
+            let name: Arc<str> = file_path(&dwarf, &unit, header, header.file(index)?)?.into();
+            files.insert(index, name.clone());
+            Some(name)
+        };
+
+        if let Some(program) = unit.line_program.clone() {
+            let mut rows = program.rows();
+            while let Some((_, row)) = rows.next_row()? {
+                if row.end_sequence() || !row.is_stmt() {
+                    continue;
+                }
+                let line = match row.line() {
+                    Some(line) => line.get() as u32,
+                    None => continue,
+                };
+                if let Some(file) = file_name(row.file_index()) {
+                    lines.insert(offset(row.address()), SourceLine { file, line });
+                }
+            }
+        }
+
+        let mut entries = unit.entries();
+        let mut depth = 0isize;
+        while let Some((delta, entry)) = entries.next_dfs()? {
+            depth += delta;
+            let tag = entry.tag();
+            if tag != gimli::DW_TAG_subprogram && tag != gimli::DW_TAG_inlined_subroutine {
+                continue;
+            }
+            let name: Arc<str> = match die_name(&dwarf, &unit, entry, 0) {
+                Some(name) => name.into(),
+                None => continue,
+            };
+            let mut ranges = dwarf.die_ranges(&unit, entry)?;
+            while let Some(range) = ranges.next()? {
+                if range.begin == 0 || range.end <= range.begin {
+                    continue;
+                }
+                let start = offset(range.begin);
+                let len = (range.end - range.begin) as u32;
+                if tag == gimli::DW_TAG_subprogram {
+                    symbols.entry(start as usize).or_insert_with(|| Symbol {
+                        offset: start,
+                        len,
+                        type_id: 0,
+                        flags: SymbolFlags::FUNCTION.bits(),
+                        name: name.clone(),
+                    });
+                    continue;
+                }
+                let call_file = match entry.attr_value(gimli::DW_AT_call_file) {
+                    Ok(Some(AttributeValue::FileIndex(i))) => Some(i),
+                    Ok(Some(AttributeValue::Udata(i))) => Some(i),
+                    _ => None,
+                };
+                let call_line = match entry.attr_value(gimli::DW_AT_call_line) {
+                    Ok(Some(value)) => value.udata_value(),
+                    _ => None,
+                };
+                let call_site = match (call_file.and_then(&mut file_name), call_line) {
+                    (Some(file), Some(line)) => Some(SourceLine {
+                        file,
+                        line: line as u32,
+                    }),
+                    _ => None,
+                };
+                inlines.push(InlineRange {
+                    start,
+                    end: start + len,
+                    depth: depth.max(0) as usize,
+                    frame: InlineFrame {
+                        name: name.clone(),
+                        call_site,
+                    },
+                });
+            }
+        }
+    }
+    inlines.sort_by_key(|r| r.start);
+
+    Ok(DebugInfo {
+        symbols: Arc::new(symbols),
+        lines,
+        inlines,
+    })
+}
+
+/// The linkage name of function, or the plain name, following the abstract origin
+fn die_name(
+    dwarf: &Dwarf<Reader>,
+    unit: &Unit<Reader>,
+    entry: &DebuggingInformationEntry<Reader>,
+    depth: usize,
+) -> Option<String> {
+    for at in [
+        gimli::DW_AT_linkage_name,
+        gimli::DW_AT_MIPS_linkage_name,
+        gimli::DW_AT_name,
+    ] {
+        if let Ok(Some(attr)) = entry.attr_value(at) {
+            if let Ok(name) = dwarf.attr_string(unit, attr) {
+                return Some(name.to_string_lossy().into_owned());
+            }
+        }
+    }
+    if depth >= MAX_ORIGIN_DEPTH {
+        return None;
+    }
+    for at in [gimli::DW_AT_abstract_origin, gimli::DW_AT_specification] {
+        if let Ok(Some(AttributeValue::UnitRef(origin))) = entry.attr_value(at) {
+            let origin = unit.entry(origin).ok()?;
+            return die_name(dwarf, unit, &origin, depth + 1);
+        }
+    }
+    None
+}
+
+fn file_path(
+    dwarf: &Dwarf<Reader>,
+    unit: &Unit<Reader>,
+    header: &LineProgramHeader<Reader>,
+    file: &FileEntry<Reader>,
+) -> Option<String> {
+    let mut path = PathBuf::new();
+    if let Some(dir) = unit.comp_dir {
+        path.push(dir.to_string_lossy().as_ref());
+    }
+    if let Some(dir) = file.directory(header) {
+        // an absolute directory replaces the compilation directory
+        path.push(
+            dwarf
+                .attr_string(unit, dir)
+                .ok()?
+                .to_string_lossy()
+                .as_ref(),
+        );
+    }
+    path.push(
+        dwarf
+            .attr_string(unit, file.path_name())
+            .ok()?
+            .to_string_lossy()
+            .as_ref(),
+    );
+    Some(path.to_string_lossy().into_owned())
+}
@@ -1,6 +1,8 @@
 //! ELF file helper
 
-use goblin::elf::{header::*, sym::Sym, Elf};
+use crate::symbol::{Symbol, SymbolFlags, SymbolMap};
+
+use goblin::elf::{header::*, note::NT_GNU_BUILD_ID, sym::Sym, Elf};
 use goblin::strtab::Strtab;
 
 #[derive(Deref, Clone)]
@@ -15,6 +17,21 @@ impl ElfSym<'_> {
     pub fn offset(&self) -> usize {
         self.sym.st_value as usize
     }
+
+    pub fn to_symbol(&self, bias: usize) -> Symbol {
+        let flags = if self.is_function() {
+            SymbolFlags::FUNCTION
+        } else {
+            SymbolFlags::NONE
+        };
+        Symbol {
+            offset: self.offset().wrapping_sub(bias) as u32,
+            name: self.name.into(),
+            flags: flags.bits(),
+            len: self.st_size as u32,
+            type_id: 0,
+        }
+    }
 }
 
 pub fn get_symbol<'a>(e: &'a Strtab, s: &Sym) -> Option<ElfSym<'a>> {
@@ -42,6 +59,20 @@ impl<'a> ElfHelper<'a> {
             .filter_map(move |s| get_symbol(&self.0.strtab, &s))
     }
 
+    /// The symbols from symtab and dynsym, keyed by the offset to `bias`, the mapping symbols are skipped
+    pub fn symbols(&'a self, bias: usize) -> SymbolMap {
+        let mut result = SymbolMap::default();
+        for s in self.enum_symbol().chain(self.enum_export()) {
+            if s.name.starts_with("$x.") {
+                continue;
+            }
+            result
+                .entry(s.offset().wrapping_sub(bias))
+                .or_insert_with(|| s.to_symbol(bias));
+        }
+        result
+    }
+
     pub fn get_export(&'a self, name: &str) -> Option<ElfSym<'a>> {
         for s in self.enum_export() {
             if s.name == name {
@@ -61,6 +92,15 @@ impl<'a> ElfHelper<'a> {
         self.0.entry
     }
 
+    /// The GNU build id from the notes, `data` is the file which is parsed from
+    pub fn build_id(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        self.0
+            .iter_note_headers(data)?
+            .filter_map(Result::ok)
+            .find(|n| n.n_type == NT_GNU_BUILD_ID)
+            .map(|n| n.desc)
+    }
+
     pub fn parse(data: &'a [u8]) -> Option<Self> {
         Elf::parse(data).ok().map(|elf| Self(elf))
     }
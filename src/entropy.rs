@@ -0,0 +1,183 @@
+//!
+//! Entropy map of target memory, to locate the packed/encrypted blobs and keys.
+//!
+//! The regions are split into chunks and the Shannon entropy (bits per byte, 0.0 ~ 8.0) of each chunk is computed,
+//! the pages containing high entropy chunks could be marked with [`MemoryFlags::HIGH_ENTROPY`] for rendering in memory map.
+//!
+
+use crate::{prelude::*, range::RangeValue, scan::readable_regions};
+
+use core::ops::Range;
+
+/// Default threshold of high entropy, compressed or encrypted data is usually above it
+pub const HIGH_ENTROPY: f32 = 7.2;
+
+/// Shannon entropy of data in bits per byte
+pub fn shannon_entropy(data: &[u8]) -> f32 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &b in data {
+        counts[b as usize] += 1;
+    }
+    let len = data.len() as f32;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f32 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EntropyChunk {
+    pub address: usize,
+    pub size: usize,
+    pub entropy: f32,
+}
+
+impl RangeValue for EntropyChunk {
+    #[inline]
+    fn as_range(&self) -> Range<usize> {
+        self.address..self.address + self.size
+    }
+}
+
+/// Entropy of the chunks, sorted by address
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EntropyMap {
+    pub chunk_size: usize,
+    pub chunks: Vec<EntropyChunk>,
+}
+
+impl EntropyMap {
+    /// Max bytes to read at once
+    const READ_SIZE: usize = 0x100000;
+
+    /// Compute the entropy of `regions` per `chunk_size`, the unreadable chunks are skipped
+    pub fn build<T: ReadMemory + ?Sized>(
+        target: &T,
+        regions: &[Range<usize>],
+        chunk_size: usize,
+    ) -> Self {
+        let chunk_size = chunk_size.max(0x10);
+        let block_size = Self::READ_SIZE / chunk_size * chunk_size;
+        let block_size = block_size.max(chunk_size);
+        let mut buf = vec![0u8; block_size];
+        let mut chunks = vec![];
+        for r in regions {
+            let mut address = r.start;
+            while address < r.end {
+                let size = block_size.min(r.end - address);
+                if let Some(data) = target.read_memory(address, &mut buf[..size]) {
+                    for (i, chunk) in data.chunks(chunk_size).enumerate() {
+                        chunks.push(EntropyChunk {
+                            address: address + i * chunk_size,
+                            size: chunk.len(),
+                            entropy: shannon_entropy(chunk),
+                        });
+                    }
+                }
+                address += size;
+            }
+        }
+        chunks.sort_by_key(|c| c.address);
+        Self { chunk_size, chunks }
+    }
+
+    /// Entropy map of all the readable memory of target
+    pub fn of_target<T: UDbgTarget + ?Sized>(target: &T, chunk_size: usize) -> UDbgResult<Self> {
+        Ok(Self::build(target, &readable_regions(target)?, chunk_size))
+    }
+
+    /// Entropy of the chunk contains `address`
+    #[inline]
+    pub fn entropy_at(&self, address: usize) -> Option<f32> {
+        RangeValue::binary_search(&self.chunks, address).map(|c| c.entropy)
+    }
+
+    /// Chunks overlapped with `range`
+    pub fn chunks_in(&self, range: Range<usize>) -> &[EntropyChunk] {
+        let begin = self
+            .chunks
+            .partition_point(|c| c.address + c.size <= range.start);
+        let end = self.chunks.partition_point(|c| c.address < range.end);
+        &self.chunks[begin..end.max(begin)]
+    }
+
+    /// Max entropy of the chunks in `range`
+    pub fn max_entropy(&self, range: Range<usize>) -> Option<f32> {
+        self.chunks_in(range)
+            .iter()
+            .map(|c| c.entropy)
+            .reduce(f32::max)
+    }
+
+    /// Ranges of the contiguous chunks whose entropy is above `threshold`
+    pub fn high_entropy(&self, threshold: f32) -> Vec<Range<usize>> {
+        let mut result: Vec<Range<usize>> = vec![];
+        for c in self.chunks.iter().filter(|c| c.entropy >= threshold) {
+            match result.last_mut() {
+                Some(last) if last.end == c.address => last.end = c.address + c.size,
+                _ => result.push(c.address..c.address + c.size),
+            }
+        }
+        result
+    }
+
+    /// Mark the pages which contain high entropy chunks with [`MemoryFlags::HIGH_ENTROPY`]
+    pub fn annotate_pages(&self, pages: &mut [MemoryPage], threshold: f32) {
+        for page in pages.iter_mut() {
+            if self
+                .max_entropy(page.base..page.base + page.size)
+                .map_or(false, |e| e >= threshold)
+            {
+                page.flags |= MemoryFlags::HIGH_ENTROPY;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entropy() {
+        assert_eq!(shannon_entropy(&[]), 0.0);
+        assert_eq!(shannon_entropy(&[0x41; 0x100]), 0.0);
+        assert!((shannon_entropy(b"abababab") - 1.0).abs() < 1e-6);
+        let all = (0..=255u8).collect::<Vec<_>>();
+        assert!((shannon_entropy(&all) - 8.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn entropy_map() {
+        let chunk = |address, entropy| EntropyChunk {
+            address,
+            size: 0x100,
+            entropy,
+        };
+        let map = EntropyMap {
+            chunk_size: 0x100,
+            chunks: vec![
+                chunk(0x1000, 7.9),
+                chunk(0x1100, 7.5),
+                chunk(0x1200, 3.0),
+                chunk(0x1400, 7.8),
+            ],
+        };
+        assert_eq!(
+            map.high_entropy(HIGH_ENTROPY),
+            [0x1000..0x1200, 0x1400..0x1500]
+        );
+        assert_eq!(map.entropy_at(0x1250), Some(3.0));
+        assert_eq!(map.entropy_at(0x1300), None);
+        assert_eq!(map.chunks_in(0x1180..0x1300).len(), 2);
+        assert_eq!(map.max_entropy(0x1180..0x1300), Some(7.5));
+        assert_eq!(map.max_entropy(0x1300..0x1400), None);
+    }
+}
@@ -18,6 +18,12 @@ pub enum UDbgError {
     BindFailed,
     SpawnFailed,
     TargetIsBusy,
+    /// Attach or create the debugger process itself
+    SelfDebug,
+    /// Enter the event loop or a blocking operation from event handler
+    Reentrant,
+    /// The access rights which are missing
+    AccessDenied(String),
     GetContext(u32),
     SetContext(u32),
     Text(String),
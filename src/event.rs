@@ -3,12 +3,14 @@
 //!
 
 use crate::{
-    breakpoint::UDbgBreakpoint,
+    breakpoint::{BpID, UDbgBreakpoint},
     os::tid_t,
     shell::*,
     symbol::UDbgModule,
+    syscall::SyscallInfo,
     target::{TraceContext, UDbgTarget},
 };
+use core::any::Any;
 use core::pin::Pin;
 use core::{fmt, marker::Unpin};
 use core::{
@@ -17,6 +19,7 @@ use core::{
 };
 use futures::task::{waker_ref, ArcWake};
 use spin::mutex::Mutex;
+use std::collections::HashMap;
 use std::{cell::Cell, rc::Rc};
 use std::{sync::Arc, time::Instant};
 
@@ -24,7 +27,15 @@ use std::{sync::Arc, time::Instant};
 pub enum UserReply {
     Run(bool), // handled: bool, for exception
     StepIn,
+    /// Step, running through the call, syscall or `rep` instruction
+    StepOver,
+    /// Run until the current function returns, to the return address unwound by
+    /// [`crate::unwind::stack_trace`]. It stepped over the call before, which is
+    /// [`Self::StepOver`] now
     StepOut,
+    /// Run until the address is reached
+    RunTo(usize),
+    #[deprecated(note = "renamed to `RunTo`")]
     Goto(usize),
     Native(usize),
     Lua,
@@ -87,6 +98,59 @@ impl UEventState {
             });
         }
     }
+
+    /// Like [`Self::loop_util`], but the breakpoints with callback are handled by `callbacks`
+    pub async fn loop_with<F: FnMut(&Arc<dyn UDbgTarget>, &UEvent) -> bool>(
+        &self,
+        callbacks: &mut BpCallbacks,
+        mut exit: F,
+    ) -> Arc<dyn UDbgTarget> {
+        loop {
+            let event = self.cont().await;
+            let target = self.context().target();
+            if let Some(reply) = callbacks.dispatch(&target, &event) {
+                self.reply(reply);
+                continue;
+            }
+            if exit(&target, &event) {
+                return target;
+            }
+            self.reply(match event {
+                UEvent::Exception { .. } => UserReply::Run(false),
+                _ => UserReply::Run(true),
+            });
+        }
+    }
+}
+
+pub type BpCallback = Box<dyn FnMut(&Arc<dyn UDbgTarget>, &Arc<dyn UDbgBreakpoint>) -> UserReply>;
+
+/// Callbacks of breakpoints, called by the event loop when they're hit,
+/// see [`UEventState::loop_with`]
+#[derive(Default)]
+pub struct BpCallbacks(HashMap<BpID, BpCallback>);
+
+impl BpCallbacks {
+    /// Set the callback of breakpoint, the reply it returns is used to continue
+    pub fn on_hit(
+        &mut self,
+        bp: &dyn UDbgBreakpoint,
+        callback: impl FnMut(&Arc<dyn UDbgTarget>, &Arc<dyn UDbgBreakpoint>) -> UserReply + 'static,
+    ) {
+        self.0.insert(bp.get_id(), Box::new(callback));
+    }
+
+    pub fn remove(&mut self, id: BpID) {
+        self.0.remove(&id);
+    }
+
+    /// Call the callback of the hit breakpoint, None if the event isn't handled
+    pub fn dispatch(&mut self, target: &Arc<dyn UDbgTarget>, event: &UEvent) -> Option<UserReply> {
+        match event {
+            UEvent::Breakpoint(bp) => Some(self.0.get_mut(&bp.get_id())?(target, bp)),
+            _ => None,
+        }
+    }
 }
 
 pub struct AsyncEvent(Rc<EventData>);
@@ -128,6 +192,61 @@ pub enum UEvent {
     ProcessExit(u32),
     #[display(fmt = "Exception {{ first: {first}, code: 0x{code:x} }}")]
     Exception { first: bool, code: u32 },
+    /// Code written by unpacker is executed, see [`crate::unpack::OepTracker`]
+    #[display(fmt = "PossibleOEP({_0:x})")]
+    PossibleOEP(usize),
+    #[display(fmt = "Custom({})", "_0.kind()")]
+    Custom(Arc<CustomEvent>),
+    /// A syscall is entered, see [`crate::syscall`]
+    #[display(fmt = "SyscallEnter({})", "_0.nr")]
+    SyscallEnter(Arc<SyscallInfo>),
+    #[display(fmt = "SyscallExit({}, {:x?})", "_0.nr", "_0.ret")]
+    SyscallExit(Arc<SyscallInfo>),
+}
+
+/// Strongly typed event introduced by engines/plugins, identified by a stable string kind
+pub struct CustomEvent {
+    kind: Arc<str>,
+    payload: Box<dyn Any + Send + Sync>,
+}
+
+impl CustomEvent {
+    pub fn new<T: Any + Send + Sync>(kind: impl Into<Arc<str>>, payload: T) -> Self {
+        Self {
+            kind: kind.into(),
+            payload: Box::new(payload),
+        }
+    }
+
+    #[inline]
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    #[inline]
+    pub fn is<T: Any>(&self) -> bool {
+        self.payload.is::<T>()
+    }
+
+    #[inline]
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.payload.downcast_ref()
+    }
+}
+
+impl UEvent {
+    /// Wrap a typed payload as custom event
+    pub fn custom<T: Any + Send + Sync>(kind: impl Into<Arc<str>>, payload: T) -> Self {
+        Self::Custom(Arc::new(CustomEvent::new(kind, payload)))
+    }
+
+    /// Get the payload if it is a custom event of the specific kind and type
+    pub fn as_custom<T: Any>(&self, kind: &str) -> Option<&T> {
+        match self {
+            Self::Custom(e) if e.kind() == kind => e.downcast_ref(),
+            _ => None,
+        }
+    }
 }
 
 impl Unpin for UEvent {}
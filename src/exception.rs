@@ -0,0 +1,361 @@
+//!
+//! Human-readable names and descriptions of the exception codes, NTSTATUS, Win32 errors and signals.
+//!
+//! The tables are available on all platforms except the signals which follow the numbering of host,
+//! so the frontends can decode the stop reasons of remote targets and dumps as well.
+//! [`ExceptionDetail`] decodes the parameters of exception records, such as the faulting address of
+//! access violations and the I/O status of in-page errors.
+//!
+
+use crate::prelude::*;
+
+use core::fmt;
+
+/// Name and description of a code
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CodeInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+#[rustfmt::skip]
+const NTSTATUS: &[(u32, &str, &str)] = &[
+    (0x00000000, "STATUS_SUCCESS", "The operation completed successfully"),
+    (0x00000102, "STATUS_TIMEOUT", "The wait timed out"),
+    (0x00000103, "STATUS_PENDING", "The operation is in progress"),
+    (0x4000001E, "STATUS_WX86_SINGLE_STEP", "Single step of a WOW64 process"),
+    (0x4000001F, "STATUS_WX86_BREAKPOINT", "Breakpoint of a WOW64 process"),
+    (0x40010005, "DBG_CONTROL_C", "Ctrl+C is pressed in the console"),
+    (0x40010008, "DBG_CONTROL_BREAK", "Ctrl+Break is pressed in the console"),
+    (0x406D1388, "MS_VC_EXCEPTION", "Thread name is set by the Visual C++ runtime"),
+    (0x80000001, "STATUS_GUARD_PAGE_VIOLATION", "A guard page is accessed"),
+    (0x80000002, "STATUS_DATATYPE_MISALIGNMENT", "Misaligned data is accessed"),
+    (0x80000003, "STATUS_BREAKPOINT", "A breakpoint is hit"),
+    (0x80000004, "STATUS_SINGLE_STEP", "A single step or hardware breakpoint is completed"),
+    (0x80000005, "STATUS_BUFFER_OVERFLOW", "The data is too large for the buffer"),
+    (0x80000026, "STATUS_LONGJUMP", "A long jump is executed"),
+    (0x80000029, "STATUS_UNWIND_CONSOLIDATE", "A frame consolidation is executed"),
+    (0xC0000001, "STATUS_UNSUCCESSFUL", "The operation was unsuccessful"),
+    (0xC0000002, "STATUS_NOT_IMPLEMENTED", "The function is not implemented"),
+    (0xC0000004, "STATUS_INFO_LENGTH_MISMATCH", "The length of information is mismatched"),
+    (0xC0000005, "STATUS_ACCESS_VIOLATION", "Access violation"),
+    (0xC0000006, "STATUS_IN_PAGE_ERROR", "The page could not be read in"),
+    (0xC0000008, "STATUS_INVALID_HANDLE", "An invalid handle is used"),
+    (0xC000000D, "STATUS_INVALID_PARAMETER", "An invalid parameter is passed"),
+    (0xC0000017, "STATUS_NO_MEMORY", "Not enough virtual memory or paging file quota"),
+    (0xC000001D, "STATUS_ILLEGAL_INSTRUCTION", "An illegal instruction is executed"),
+    (0xC0000022, "STATUS_ACCESS_DENIED", "Access is denied"),
+    (0xC0000023, "STATUS_BUFFER_TOO_SMALL", "The buffer is too small"),
+    (0xC0000024, "STATUS_OBJECT_TYPE_MISMATCH", "The type of object is mismatched"),
+    (0xC0000025, "STATUS_NONCONTINUABLE_EXCEPTION", "Continue from a noncontinuable exception"),
+    (0xC0000026, "STATUS_INVALID_DISPOSITION", "An exception handler returns an invalid disposition"),
+    (0xC0000034, "STATUS_OBJECT_NAME_NOT_FOUND", "The object name is not found"),
+    (0xC000003A, "STATUS_OBJECT_PATH_NOT_FOUND", "The object path is not found"),
+    (0xC0000043, "STATUS_SHARING_VIOLATION", "The file is used by another process"),
+    (0xC000008C, "STATUS_ARRAY_BOUNDS_EXCEEDED", "Array bounds are exceeded"),
+    (0xC000008D, "STATUS_FLOAT_DENORMAL_OPERAND", "A floating point operand is denormal"),
+    (0xC000008E, "STATUS_FLOAT_DIVIDE_BY_ZERO", "Floating point division by zero"),
+    (0xC000008F, "STATUS_FLOAT_INEXACT_RESULT", "The floating point result is inexact"),
+    (0xC0000090, "STATUS_FLOAT_INVALID_OPERATION", "Invalid floating point operation"),
+    (0xC0000091, "STATUS_FLOAT_OVERFLOW", "Floating point overflow"),
+    (0xC0000092, "STATUS_FLOAT_STACK_CHECK", "The floating point stack overflowed or underflowed"),
+    (0xC0000093, "STATUS_FLOAT_UNDERFLOW", "Floating point underflow"),
+    (0xC0000094, "STATUS_INTEGER_DIVIDE_BY_ZERO", "Integer division by zero"),
+    (0xC0000095, "STATUS_INTEGER_OVERFLOW", "Integer overflow"),
+    (0xC0000096, "STATUS_PRIVILEGED_INSTRUCTION", "A privileged instruction is executed"),
+    (0xC000009A, "STATUS_INSUFFICIENT_RESOURCES", "Insufficient system resources"),
+    (0xC00000BB, "STATUS_NOT_SUPPORTED", "The request is not supported"),
+    (0xC00000FD, "STATUS_STACK_OVERFLOW", "Stack overflow"),
+    (0xC000010A, "STATUS_PROCESS_IS_TERMINATING", "The process is terminating"),
+    (0xC0000120, "STATUS_CANCELLED", "The I/O request is cancelled"),
+    (0xC0000135, "STATUS_DLL_NOT_FOUND", "A dependent DLL is not found"),
+    (0xC0000139, "STATUS_ENTRYPOINT_NOT_FOUND", "An imported procedure is not found"),
+    (0xC000013A, "STATUS_CONTROL_C_EXIT", "The application is terminated by Ctrl+C"),
+    (0xC0000142, "STATUS_DLL_INIT_FAILED", "The initialization of a DLL failed"),
+    (0xC0000225, "STATUS_NOT_FOUND", "The object is not found"),
+    (0xC00002B4, "STATUS_FLOAT_MULTIPLE_FAULTS", "Multiple floating point faults"),
+    (0xC00002B5, "STATUS_FLOAT_MULTIPLE_TRAPS", "Multiple floating point traps"),
+    (0xC0000374, "STATUS_HEAP_CORRUPTION", "The heap is corrupted"),
+    (0xC0000409, "STATUS_STACK_BUFFER_OVERRUN", "Stack buffer overrun or fail fast"),
+    (0xC0000417, "STATUS_INVALID_CRUNTIME_PARAMETER", "An invalid parameter is passed to the C runtime"),
+    (0xC0000420, "STATUS_ASSERTION_FAILURE", "An assertion failed"),
+    (0xC0000602, "STATUS_FAIL_FAST_EXCEPTION", "Fail fast exception"),
+    (0xE0434352, "CLR_EXCEPTION", "A .NET exception is thrown"),
+    (0xE06D7363, "MSVC_CPP_EXCEPTION", "A C++ exception is thrown"),
+];
+
+#[rustfmt::skip]
+const WIN32_ERRORS: &[(u32, &str, &str)] = &[
+    (0, "ERROR_SUCCESS", "The operation completed successfully"),
+    (1, "ERROR_INVALID_FUNCTION", "Incorrect function"),
+    (2, "ERROR_FILE_NOT_FOUND", "The system cannot find the file specified"),
+    (3, "ERROR_PATH_NOT_FOUND", "The system cannot find the path specified"),
+    (4, "ERROR_TOO_MANY_OPEN_FILES", "The system cannot open the file"),
+    (5, "ERROR_ACCESS_DENIED", "Access is denied"),
+    (6, "ERROR_INVALID_HANDLE", "The handle is invalid"),
+    (8, "ERROR_NOT_ENOUGH_MEMORY", "Not enough memory resources are available"),
+    (13, "ERROR_INVALID_DATA", "The data is invalid"),
+    (14, "ERROR_OUTOFMEMORY", "Not enough storage is available"),
+    (18, "ERROR_NO_MORE_FILES", "There are no more files"),
+    (32, "ERROR_SHARING_VIOLATION", "The file is being used by another process"),
+    (50, "ERROR_NOT_SUPPORTED", "The request is not supported"),
+    (80, "ERROR_FILE_EXISTS", "The file exists"),
+    (87, "ERROR_INVALID_PARAMETER", "The parameter is incorrect"),
+    (109, "ERROR_BROKEN_PIPE", "The pipe has been ended"),
+    (111, "ERROR_BUFFER_OVERFLOW", "The file name is too long"),
+    (122, "ERROR_INSUFFICIENT_BUFFER", "The data area passed to a system call is too small"),
+    (126, "ERROR_MOD_NOT_FOUND", "The specified module could not be found"),
+    (127, "ERROR_PROC_NOT_FOUND", "The specified procedure could not be found"),
+    (183, "ERROR_ALREADY_EXISTS", "Cannot create a file when that file already exists"),
+    (193, "ERROR_BAD_EXE_FORMAT", "Not a valid application"),
+    (203, "ERROR_ENVVAR_NOT_FOUND", "The environment option is not found"),
+    (231, "ERROR_PIPE_BUSY", "All pipe instances are busy"),
+    (232, "ERROR_NO_DATA", "The pipe is being closed"),
+    (233, "ERROR_PIPE_NOT_CONNECTED", "No process is on the other end of the pipe"),
+    (234, "ERROR_MORE_DATA", "More data is available"),
+    (258, "WAIT_TIMEOUT", "The wait operation timed out"),
+    (259, "ERROR_NO_MORE_ITEMS", "No more data is available"),
+    (299, "ERROR_PARTIAL_COPY", "Only part of a memory request was completed"),
+    (487, "ERROR_INVALID_ADDRESS", "Attempt to access invalid address"),
+    (535, "ERROR_PIPE_CONNECTED", "There is a process on other end of the pipe"),
+    (536, "ERROR_PIPE_LISTENING", "Waiting for a process to open the other end of the pipe"),
+    (740, "ERROR_ELEVATION_REQUIRED", "The requested operation requires elevation"),
+    (995, "ERROR_OPERATION_ABORTED", "The I/O operation has been aborted"),
+    (997, "ERROR_IO_PENDING", "Overlapped I/O operation is in progress"),
+    (998, "ERROR_NOACCESS", "Invalid access to memory location"),
+    (1114, "ERROR_DLL_INIT_FAILED", "A dynamic link library initialization routine failed"),
+    (1168, "ERROR_NOT_FOUND", "Element not found"),
+    (1223, "ERROR_CANCELLED", "The operation was canceled by the user"),
+    (1314, "ERROR_PRIVILEGE_NOT_HELD", "A required privilege is not held by the client"),
+    (1400, "ERROR_INVALID_WINDOW_HANDLE", "Invalid window handle"),
+    (1450, "ERROR_NO_SYSTEM_RESOURCES", "Insufficient system resources exist"),
+    (1460, "ERROR_TIMEOUT", "This operation returned because the timeout period expired"),
+];
+
+fn lookup(table: &[(u32, &'static str, &'static str)], code: u32) -> Option<CodeInfo> {
+    table
+        .iter()
+        .find(|(c, _, _)| *c == code)
+        .map(|&(_, name, description)| CodeInfo { name, description })
+}
+
+/// Name of NTSTATUS or exception code
+pub fn ntstatus_info(code: u32) -> Option<CodeInfo> {
+    lookup(NTSTATUS, code)
+}
+
+/// Name of Win32 error, the HRESULT wrapping a Win32 error (0x8007xxxx) is also accepted
+pub fn win32_error_info(code: u32) -> Option<CodeInfo> {
+    let code = if code & 0xFFFF0000 == 0x80070000 {
+        code & 0xFFFF
+    } else {
+        code
+    };
+    lookup(WIN32_ERRORS, code)
+}
+
+#[cfg(unix)]
+#[rustfmt::skip]
+const SIGNALS: &[(i32, &str, &str)] = &[
+    (libc::SIGHUP, "SIGHUP", "Hangup"),
+    (libc::SIGINT, "SIGINT", "Interrupt"),
+    (libc::SIGQUIT, "SIGQUIT", "Quit"),
+    (libc::SIGILL, "SIGILL", "Illegal instruction"),
+    (libc::SIGTRAP, "SIGTRAP", "Trace/breakpoint trap"),
+    (libc::SIGABRT, "SIGABRT", "Aborted"),
+    (libc::SIGBUS, "SIGBUS", "Bus error"),
+    (libc::SIGFPE, "SIGFPE", "Floating point exception"),
+    (libc::SIGKILL, "SIGKILL", "Killed"),
+    (libc::SIGUSR1, "SIGUSR1", "User defined signal 1"),
+    (libc::SIGSEGV, "SIGSEGV", "Segmentation fault"),
+    (libc::SIGUSR2, "SIGUSR2", "User defined signal 2"),
+    (libc::SIGPIPE, "SIGPIPE", "Broken pipe"),
+    (libc::SIGALRM, "SIGALRM", "Alarm clock"),
+    (libc::SIGTERM, "SIGTERM", "Terminated"),
+    (libc::SIGCHLD, "SIGCHLD", "Child exited"),
+    (libc::SIGCONT, "SIGCONT", "Continued"),
+    (libc::SIGSTOP, "SIGSTOP", "Stopped (signal)"),
+    (libc::SIGTSTP, "SIGTSTP", "Stopped"),
+    (libc::SIGTTIN, "SIGTTIN", "Stopped (tty input)"),
+    (libc::SIGTTOU, "SIGTTOU", "Stopped (tty output)"),
+    (libc::SIGURG, "SIGURG", "Urgent I/O condition"),
+    (libc::SIGXCPU, "SIGXCPU", "CPU time limit exceeded"),
+    (libc::SIGXFSZ, "SIGXFSZ", "File size limit exceeded"),
+    (libc::SIGVTALRM, "SIGVTALRM", "Virtual timer expired"),
+    (libc::SIGPROF, "SIGPROF", "Profiling timer expired"),
+    (libc::SIGWINCH, "SIGWINCH", "Window changed"),
+    (libc::SIGIO, "SIGIO", "I/O possible"),
+    (libc::SIGSYS, "SIGSYS", "Bad system call"),
+];
+
+/// Name of signal number
+#[cfg(unix)]
+pub fn signal_info(signo: i32) -> Option<CodeInfo> {
+    SIGNALS
+        .iter()
+        .find(|(s, _, _)| *s == signo)
+        .map(|&(_, name, description)| CodeInfo { name, description })
+}
+
+/// Description of the `si_code` of a fault signal
+#[cfg(unix)]
+pub fn signal_code_description(signo: i32, code: i32) -> Option<&'static str> {
+    Some(match (signo, code) {
+        (libc::SIGSEGV, 1) => "address not mapped to object",
+        (libc::SIGSEGV, 2) => "invalid permissions for mapped object",
+        (libc::SIGBUS, 1) => "invalid address alignment",
+        (libc::SIGBUS, 2) => "nonexistent physical address",
+        (libc::SIGBUS, 3) => "object-specific hardware error",
+        (libc::SIGILL, 1) => "illegal opcode",
+        (libc::SIGILL, 2) => "illegal operand",
+        (libc::SIGILL, 3) => "illegal addressing mode",
+        (libc::SIGILL, 4) => "illegal trap",
+        (libc::SIGILL, 5) => "privileged opcode",
+        (libc::SIGILL, 6) => "privileged register",
+        (libc::SIGILL, 7) => "coprocessor error",
+        (libc::SIGILL, 8) => "internal stack error",
+        (libc::SIGFPE, 1) => "integer divide by zero",
+        (libc::SIGFPE, 2) => "integer overflow",
+        (libc::SIGFPE, 3) => "floating point divide by zero",
+        (libc::SIGFPE, 4) => "floating point overflow",
+        (libc::SIGFPE, 5) => "floating point underflow",
+        (libc::SIGFPE, 6) => "floating point inexact result",
+        (libc::SIGFPE, 7) => "floating point invalid operation",
+        (libc::SIGFPE, 8) => "subscript out of range",
+        (libc::SIGTRAP, 1) => "process breakpoint",
+        (libc::SIGTRAP, 2) => "process trace trap",
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AccessKind {
+    Read,
+    Write,
+    /// Execute the non-executable memory, aka DEP violation
+    Execute,
+}
+
+/// The faulting access of access violations and in-page errors
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessFault {
+    pub kind: AccessKind,
+    pub address: usize,
+    /// NTSTATUS of the underlying I/O, for in-page errors
+    pub io_status: Option<u32>,
+}
+
+/// Decoded exception record
+#[derive(Debug, Clone, Serialize)]
+pub struct ExceptionDetail {
+    pub code: u32,
+    pub info: Option<CodeInfo>,
+    pub address: usize,
+    pub noncontinuable: bool,
+    pub access: Option<AccessFault>,
+    /// The fast fail code of `__fastfail`
+    pub fast_fail: Option<u32>,
+    pub params: Vec<u64>,
+}
+
+const EXCEPTION_NONCONTINUABLE: u32 = 1;
+const STATUS_ACCESS_VIOLATION: u32 = 0xC0000005;
+const STATUS_IN_PAGE_ERROR: u32 = 0xC0000006;
+const STATUS_STACK_BUFFER_OVERRUN: u32 = 0xC0000409;
+const STATUS_FAIL_FAST_EXCEPTION: u32 = 0xC0000602;
+
+impl ExceptionDetail {
+    pub fn new(code: u32, flags: u32, address: usize, params: &[u64]) -> Self {
+        let access = match code {
+            STATUS_ACCESS_VIOLATION | STATUS_IN_PAGE_ERROR if params.len() >= 2 => {
+                Some(AccessFault {
+                    kind: match params[0] {
+                        0 => AccessKind::Read,
+                        8 => AccessKind::Execute,
+                        _ => AccessKind::Write,
+                    },
+                    address: params[1] as usize,
+                    io_status: (code == STATUS_IN_PAGE_ERROR)
+                        .then(|| params.get(2).map(|&s| s as u32))
+                        .flatten(),
+                })
+            }
+            _ => None,
+        };
+        Self {
+            code,
+            info: ntstatus_info(code),
+            address,
+            noncontinuable: flags & EXCEPTION_NONCONTINUABLE > 0,
+            access,
+            fast_fail: match code {
+                STATUS_STACK_BUFFER_OVERRUN | STATUS_FAIL_FAST_EXCEPTION => {
+                    params.first().map(|&c| c as u32)
+                }
+                _ => None,
+            },
+            params: params.into(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<&crate::os::ExceptionRecord> for ExceptionDetail {
+    fn from(r: &crate::os::ExceptionRecord) -> Self {
+        let count = (r.param_num as usize).min(r.params.len());
+        Self::new(r.code, r.flags, r.address as usize, &r.params[..count])
+    }
+}
+
+impl fmt::Display for ExceptionDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.info {
+            Some(info) => write!(f, "{} ({:08X})", info.description, self.code)?,
+            None => write!(f, "Exception {:08X}", self.code)?,
+        }
+        write!(f, " at {:x}", self.address)?;
+        if let Some(access) = self.access.as_ref() {
+            let kind = match access.kind {
+                AccessKind::Read => "reading",
+                AccessKind::Write => "writing",
+                AccessKind::Execute => "executing",
+            };
+            write!(f, ", {kind} {:x}", access.address)?;
+            if let Some(status) = access.io_status {
+                match ntstatus_info(status) {
+                    Some(info) => write!(f, ", I/O status {}", info.name)?,
+                    None => write!(f, ", I/O status {status:08X}")?,
+                }
+            }
+        }
+        if let Some(code) = self.fast_fail {
+            write!(f, ", fast fail code {code}")?;
+        }
+        if self.noncontinuable {
+            write!(f, " (noncontinuable)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Human-readable stop reason of event, the exception code is NTSTATUS on Windows and signal number on others
+pub fn stop_reason(event: &UEvent) -> String {
+    match event {
+        UEvent::Exception { first, code } => {
+            #[cfg(windows)]
+            let info = ntstatus_info(*code);
+            #[cfg(unix)]
+            let info = signal_info(*code as i32);
+            let chance = if *first {
+                "first chance"
+            } else {
+                "second chance"
+            };
+            match info {
+                Some(info) => format!("{} ({}), {chance}", info.description, info.name),
+                None => format!("Exception 0x{code:x}, {chance}"),
+            }
+        }
+        _ => event.to_string(),
+    }
+}
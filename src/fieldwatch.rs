@@ -0,0 +1,218 @@
+//!
+//! Data breakpoints on a member of C++ class across all its instances, which are found by the
+//! vtable, see [`crate::vtable`].
+//!
+//! The hardware slots are given to the instances at the start of heap blocks first, the most
+//! likely real objects, the others fall back to the page watches of [`crate::memwatch`], as do the
+//! fields which don't fit a hardware breakpoint by size or alignment. On x86 a hardware breakpoint
+//! traps after the write, so the `pc` of such a hit is the instruction next to the writer.
+//!
+
+use crate::{
+    breakpoint::HwBreakpoint,
+    heap::HeapSnapshot,
+    memwatch::{MemWatchHit, WatchAccess, MEM_WATCH_EVENT},
+    prelude::*,
+    register::regid,
+    vtable::{find_instances, Instance},
+};
+
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldWatchOptions {
+    /// Offset of the member in object
+    pub offset: usize,
+    pub size: usize,
+    /// Max hardware slots to use, all the free ones if None
+    pub hardware: Option<usize>,
+    /// Watch the instances out of hardware slots by pages, or leave them unwatched
+    pub page_fallback: bool,
+    /// Only the instances at the start of heap blocks, requires a [`HeapSnapshot`]
+    pub block_start_only: bool,
+}
+
+impl FieldWatchOptions {
+    pub fn new(offset: usize, size: usize) -> Self {
+        Self {
+            offset,
+            size,
+            hardware: None,
+            page_fallback: true,
+            block_start_only: false,
+        }
+    }
+}
+
+enum Watcher {
+    Hardware(HwBreakpoint),
+    /// Id of the memory watch
+    Page(usize),
+}
+
+/// The field of an instance being watched
+pub struct WatchedField {
+    pub instance: Instance,
+    /// Address of the field
+    pub address: usize,
+    pub hits: usize,
+    watcher: Watcher,
+}
+
+impl WatchedField {
+    #[inline]
+    pub fn is_hardware(&self) -> bool {
+        matches!(self.watcher, Watcher::Hardware(_))
+    }
+}
+
+/// A write to the watched field
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldWrite {
+    /// Address of the instance
+    pub instance: usize,
+    pub address: usize,
+    pub tid: tid_t,
+    pub pc: usize,
+    /// Symbol of `pc`, such as `module!function+offset`
+    pub symbol: Option<String>,
+    /// Disassembly of the writer, on x86 from the page watches only
+    pub instruction: Option<String>,
+    /// Bytes of the field after the write
+    pub value: Vec<u8>,
+    /// Reported by a hardware breakpoint
+    pub hardware: bool,
+}
+
+/// Watch the writes to a member of the instances of a class
+pub struct FieldWatch {
+    target: Arc<dyn UDbgTarget>,
+    pub options: FieldWatchOptions,
+    fields: Vec<WatchedField>,
+}
+
+impl FieldWatch {
+    /// Find the instances of class by `vtable`, and watch the field of each, should be called when
+    /// the target is stopped
+    pub fn start(
+        target: Arc<dyn UDbgTarget>,
+        vtable: usize,
+        heap: Option<&HeapSnapshot>,
+        options: FieldWatchOptions,
+    ) -> UDbgResult<Self> {
+        if options.size == 0 {
+            return Err("the size of field is 0".into());
+        }
+        let mut instances = find_instances(target.as_ref(), vtable, heap)?;
+        if options.block_start_only {
+            instances.retain(Instance::is_block_start);
+        }
+        if instances.is_empty() {
+            return Err(UDbgError::NotFound);
+        }
+        // the block starts first, stable for the others
+        instances.sort_by_key(|i| !i.is_block_start());
+
+        let mut this = Self {
+            target,
+            options,
+            fields: vec![],
+        };
+        let free = this.target.free_hw_slots();
+        let mut slots = this.options.hardware.map_or(free, |n| n.min(free));
+        for instance in instances {
+            let address = instance.address + this.options.offset;
+            let mut watcher = None;
+            if slots > 0 && this.fits_hardware(address) {
+                match this
+                    .target
+                    .add_hw_bp(address, this.options.size, HwbpType::Write)
+                {
+                    Ok(bp) => {
+                        slots -= 1;
+                        watcher = Some(Watcher::Hardware(bp));
+                    }
+                    Err(err) => {
+                        warn!("hardware breakpoint at {address:x}: {err:?}");
+                        slots = 0;
+                    }
+                }
+            }
+            if watcher.is_none() && this.options.page_fallback {
+                watcher = this
+                    .target
+                    .add_mem_watch(address..address + this.options.size, WatchAccess::Write)
+                    .log_error_with(|err| format!("watch {address:x}: {err:?}"))
+                    .map(Watcher::Page);
+            }
+            if let Some(watcher) = watcher {
+                this.fields.push(WatchedField {
+                    instance,
+                    address,
+                    hits: 0,
+                    watcher,
+                });
+            }
+        }
+        Ok(this)
+    }
+
+    fn fits_hardware(&self, address: usize) -> bool {
+        let size = self.options.size;
+        HwbpLen::from_size(size).is_some()
+            && size <= self.target.base().pointer_size()
+            && address % size == 0
+    }
+
+    #[inline]
+    pub fn fields(&self) -> &[WatchedField] {
+        &self.fields
+    }
+
+    /// Remove the breakpoints and the page watches, should be called when the target is stopped
+    pub fn stop(&mut self) {
+        for field in self.fields.drain(..) {
+            match field.watcher {
+                Watcher::Hardware(bp) => bp.remove().log_error("remove breakpoint"),
+                Watcher::Page(id) => self.target.remove_mem_watch(id).log_error("remove watch"),
+            };
+        }
+    }
+
+    /// Handle an event, returns the write if it's a hit of this watch: a hardware breakpoint, or a
+    /// [`MemWatchHit`] reported by the engine
+    pub fn on_event(&mut self, event: &UEvent, ctx: &mut dyn TraceContext) -> Option<FieldWrite> {
+        let page_hit = event.as_custom::<MemWatchHit>(MEM_WATCH_EVENT);
+        let field = self
+            .fields
+            .iter_mut()
+            .find(|f| match (&f.watcher, page_hit) {
+                (Watcher::Hardware(bp), _) => bp.is_hit(event),
+                (Watcher::Page(id), Some(hit)) => hit.id == *id,
+                _ => false,
+            })?;
+        field.hits += 1;
+
+        let target = self.target.as_ref();
+        let (pc, instruction) = match page_hit {
+            Some(hit) => (hit.pc, hit.instruction.clone()),
+            None => {
+                let pc = ctx
+                    .register()
+                    .and_then(|r| r.get_reg(regid::COMM_REG_PC))
+                    .map_or(0, |r| r.as_int());
+                (pc, None)
+            }
+        };
+        Some(FieldWrite {
+            instance: field.instance.address,
+            address: field.address,
+            tid: target.base().event_tid.get(),
+            pc,
+            symbol: target.get_symbol_string(pc),
+            instruction,
+            value: target.read_bytes(field.address, self.options.size),
+            hardware: page_hit.is_none(),
+        })
+    }
+}
@@ -0,0 +1,297 @@
+//!
+//! FLIRT-like signature matching, to name the statically linked library functions in stripped modules.
+//!
+//! The signature packs are in the text format of IDA FLAIR (`.pat`), each line describes a function:
+//!
+//! ```text
+//! 5589E583EC18C7042401000000E8........C9C3........................ 0C 3C8A 0025 :0000 _func :0010@ _local ^0014 _ref 8B45..
+//! ```
+//!
+//! - the leading 32 bytes in hex, `..` is a wildcard byte (relocations)
+//! - length and CRC16 of the bytes after the leading 32 bytes
+//! - total length of the function
+//! - public names with their offsets, `@` marks a local name
+//! - referenced names, which are ignored in matching
+//! - the tail bytes after the CRC range, with wildcards
+//!
+//! The matched names are added to the module as user symbols, the existing symbols are never overridden.
+//! The binary `.sig` files could be converted to `.pat` by the FLAIR tools.
+//!
+
+use crate::prelude::*;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Bytes of the leading pattern
+const LEADING: usize = 32;
+
+/// CRC16 used by FLAIR, over the bytes following the leading pattern
+pub fn crc16(data: &[u8]) -> u16 {
+    if data.is_empty() {
+        return 0;
+    }
+    let mut crc = 0xFFFFu16;
+    for &b in data {
+        let mut b = b;
+        for _ in 0..8 {
+            crc = if (crc ^ b as u16) & 1 > 0 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            };
+            b >>= 1;
+        }
+    }
+    (!crc).swap_bytes()
+}
+
+/// Parse hex bytes with `..` as wildcard
+fn parse_pattern(s: &str) -> Option<Vec<Option<u8>>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| match &s[i..i + 2] {
+            ".." => Some(None),
+            h => u8::from_str_radix(h, 16).ok().map(Some),
+        })
+        .collect()
+}
+
+/// A public name in a function signature
+#[derive(Debug, Clone, Serialize)]
+pub struct SigName {
+    pub offset: usize,
+    pub name: Box<str>,
+    pub local: bool,
+}
+
+/// Signature of a library function
+#[derive(Debug, Clone, Serialize)]
+pub struct FuncSignature {
+    /// Leading bytes, None for wildcard
+    pub pattern: Vec<Option<u8>>,
+    pub crc_len: usize,
+    pub crc: u16,
+    pub size: usize,
+    pub names: Vec<SigName>,
+    /// Bytes after the CRC range
+    pub tail: Vec<Option<u8>>,
+}
+
+impl FuncSignature {
+    /// Parse a line of `.pat` file
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut items = line.split_whitespace();
+        let pattern = parse_pattern(items.next()?)?;
+        let crc_len = usize::from_str_radix(items.next()?, 16).ok()?;
+        let crc = u16::from_str_radix(items.next()?, 16).ok()?;
+        let size = usize::from_str_radix(items.next()?, 16).ok()?;
+
+        let mut names = vec![];
+        let mut tail = vec![];
+        while let Some(item) = items.next() {
+            if let Some(offset) = item.strip_prefix(':') {
+                let local = offset.ends_with('@');
+                let offset = usize::from_str_radix(offset.trim_end_matches('@'), 16).ok()?;
+                let name = items.next()?;
+                names.push(SigName {
+                    offset,
+                    name: name.into(),
+                    local,
+                });
+            } else if item.starts_with('^') {
+                items.next()?;
+            } else {
+                tail = parse_pattern(item)?;
+            }
+        }
+        if pattern.len() > LEADING || names.is_empty() {
+            return None;
+        }
+        Some(Self {
+            pattern,
+            crc_len,
+            crc,
+            size,
+            names,
+            tail,
+        })
+    }
+
+    /// Bytes verified by this signature, the longer one wins when several signatures match
+    fn specificity(&self) -> usize {
+        let fixed = |p: &[Option<u8>]| p.iter().filter(|b| b.is_some()).count();
+        fixed(&self.pattern) + self.crc_len + fixed(&self.tail)
+    }
+
+    /// Check if the function at the beginning of `data` matches
+    pub fn is_match(&self, data: &[u8]) -> bool {
+        let tail_end = LEADING + self.crc_len + self.tail.len();
+        if data.len() < self.size.max(self.pattern.len()).max(tail_end) {
+            return false;
+        }
+        let matches =
+            |p: &[Option<u8>], d: &[u8]| p.iter().zip(d).all(|(p, b)| p.map_or(true, |p| p == *b));
+        if !matches(&self.pattern, data) {
+            return false;
+        }
+        if self.crc_len > 0 && crc16(&data[LEADING..LEADING + self.crc_len]) != self.crc {
+            return false;
+        }
+        matches(&self.tail, &data[LEADING + self.crc_len..])
+    }
+}
+
+/// A collection of function signatures, usually for a library
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SignaturePack {
+    pub name: String,
+    pub signatures: Vec<FuncSignature>,
+}
+
+impl SignaturePack {
+    /// Parse the content of `.pat` file, the invalid lines are skipped
+    pub fn from_pat(name: &str, text: &str) -> Self {
+        let signatures = text
+            .lines()
+            .map(str::trim)
+            .take_while(|l| *l != "---")
+            .filter(|l| !l.is_empty() && !l.starts_with(';'))
+            .filter_map(|l| {
+                let sig = FuncSignature::parse(l);
+                if sig.is_none() {
+                    warn!("invalid signature in {name}: {l}");
+                }
+                sig
+            })
+            .collect();
+        Self {
+            name: name.into(),
+            signatures,
+        }
+    }
+
+    /// Load a `.pat` file, or all the `.pat` files in a directory
+    pub fn load<P: AsRef<Path>>(path: P) -> UDbgResult<Self> {
+        let path = path.as_ref();
+        let name = path
+            .file_stem()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if !path.is_dir() {
+            return Ok(Self::from_pat(&name, &std::fs::read_to_string(path)?));
+        }
+
+        let mut result = Self {
+            name,
+            signatures: vec![],
+        };
+        for entry in std::fs::read_dir(path)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .map_or(false, |e| e.eq_ignore_ascii_case("pat"))
+            {
+                result.extend(Self::load(&path)?);
+            }
+        }
+        Ok(result)
+    }
+
+    #[inline]
+    pub fn extend(&mut self, other: Self) {
+        self.signatures.extend(other.signatures);
+    }
+
+    /// Match the signatures against the executable memory of module
+    pub fn match_module<T: UDbgTarget + ?Sized>(
+        &self,
+        target: &T,
+        module: &dyn UDbgModule,
+    ) -> UDbgResult<Vec<SigMatch>> {
+        let base = module.data().base;
+        let range = base..base + module.data().size;
+        let regions = target
+            .enum_memory()?
+            .filter(|p| p.is_commit() && p.is_executable() && range.contains(&p.base))
+            .map(|p| p.base..(p.base + p.size).min(range.end))
+            .collect::<Vec<_>>();
+
+        // index by the first byte, the patterns start with wildcard are checked everywhere
+        let mut index = HashMap::<u8, Vec<&FuncSignature>>::new();
+        let mut wild = vec![];
+        for sig in &self.signatures {
+            match sig.pattern.first().copied().flatten() {
+                Some(b) => index.entry(b).or_default().push(sig),
+                None => wild.push(sig),
+            }
+        }
+
+        let mut result = vec![];
+        for r in regions {
+            let data = target.read_bytes(r.start, r.end - r.start);
+            let mut i = 0;
+            while i < data.len() {
+                let candidates = index.get(&data[i]).into_iter().flatten().chain(&wild);
+                let mut best: Option<&FuncSignature> = None;
+                let mut ambiguous = false;
+                for &sig in candidates.filter(|s| s.is_match(&data[i..])) {
+                    match best {
+                        Some(b) if b.specificity() > sig.specificity() => {}
+                        Some(b) if b.specificity() == sig.specificity() => {
+                            ambiguous |= b.names[0].name != sig.names[0].name;
+                        }
+                        _ => {
+                            best = Some(sig);
+                            ambiguous = false;
+                        }
+                    }
+                }
+                i += match best {
+                    Some(sig) if !ambiguous => {
+                        result.push(SigMatch {
+                            address: r.start + i,
+                            size: sig.size,
+                            names: sig.names.clone(),
+                        });
+                        sig.size.max(1)
+                    }
+                    _ => 1,
+                };
+            }
+        }
+        Ok(result)
+    }
+
+    /// Match the signatures and add the names as synthetic symbols of module, returns the count of added symbols
+    pub fn apply<T: UDbgTarget + ?Sized>(
+        &self,
+        target: &T,
+        module: &dyn UDbgModule,
+    ) -> UDbgResult<usize> {
+        let base = module.data().base;
+        let mut count = 0;
+        for m in self.match_module(target, module)? {
+            for n in &m.names {
+                let offset = m.address + n.offset - base;
+                if module.find_symbol(offset, 0).is_some() {
+                    continue;
+                }
+                module.add_symbol(offset, &n.name)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// A function identified by signature
+#[derive(Debug, Clone, Serialize)]
+pub struct SigMatch {
+    pub address: usize,
+    pub size: usize,
+    pub names: Vec<SigName>,
+}
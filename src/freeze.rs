@@ -0,0 +1,161 @@
+//!
+//! Thread freezing with the nesting counts tracked by the crate, to scan or patch the memory safely
+//! while the target is running outside of a debug event.
+//!
+//! A thread is suspended by the OS at its first level and resumed at its last, so the nested
+//! freezes, such as a scan inside a [`FreezeGuard`], don't resume the threads under each other.
+//! [`crate::target::TargetUtil::with_suspended`], and so the safe patching and compare-and-swap,
+//! suspend the threads through it too. The OS is called under the lock of counts, so a count is
+//! never seen before the thread is suspended, nor a thread resumed before its suspending lands.
+//!
+
+use crate::prelude::*;
+
+use spin::Mutex;
+use std::collections::HashMap;
+
+/// Suspend counts of the threads by the crate
+#[derive(Default)]
+pub struct ThreadFreezer {
+    counts: Mutex<HashMap<tid_t, usize>>,
+}
+
+impl ThreadFreezer {
+    /// Suspend count of thread by the crate
+    pub fn count(&self, tid: tid_t) -> usize {
+        self.counts.lock().get(&tid).copied().unwrap_or_default()
+    }
+
+    /// The threads suspended by the crate
+    pub fn frozen(&self) -> Vec<tid_t> {
+        self.counts.lock().keys().copied().collect()
+    }
+
+    /// Suspend a thread a level, returns its count. The count is taken only if suspended
+    pub fn suspend_thread(&self, thread: &dyn UDbgThread) -> UDbgResult<usize> {
+        let mut counts = self.counts.lock();
+        let count = counts.get(&thread.tid).copied().unwrap_or_default() + 1;
+        if count == 1 {
+            thread.suspend()?;
+        }
+        counts.insert(thread.tid, count);
+        Ok(count)
+    }
+
+    /// Take a level of thread and call `resume` at its last level, returns the count left
+    fn release(&self, tid: tid_t, resume: impl FnOnce() -> UDbgResult<()>) -> UDbgResult<usize> {
+        let mut counts = self.counts.lock();
+        let count = counts.get_mut(&tid).ok_or(UDbgError::NotFound)?;
+        *count -= 1;
+        if *count > 0 {
+            return Ok(*count);
+        }
+        // forget it even if it's failed, the thread may be exited
+        counts.remove(&tid);
+        resume()?;
+        Ok(0)
+    }
+
+    /// Suspend a thread a level, returns its count
+    pub fn suspend<T: UDbgTarget + ?Sized>(&self, target: &T, tid: tid_t) -> UDbgResult<usize> {
+        self.suspend_thread(target.open_thread(tid)?.as_ref())
+    }
+
+    /// Resume a thread suspended by the crate a level, returns its count
+    pub fn resume<T: UDbgTarget + ?Sized>(&self, target: &T, tid: tid_t) -> UDbgResult<usize> {
+        self.release(tid, || {
+            target.open_thread(tid)?.resume()?;
+            Ok(())
+        })
+    }
+
+    /// Resume a thread suspended by the crate a level by the opened one, returns its count
+    pub fn resume_thread(&self, thread: &dyn UDbgThread) -> UDbgResult<usize> {
+        self.release(thread.tid, || {
+            thread.resume()?;
+            Ok(())
+        })
+    }
+
+    /// Suspend all threads of target a level, returns the threads suspended, the ones failed are
+    /// logged and skipped. Fails if none of the threads is suspended, such as unsupported
+    pub fn suspend_all<T: UDbgTarget + ?Sized>(&self, target: &T) -> UDbgResult<Vec<tid_t>> {
+        let mut error = None;
+        let suspended = target
+            .enum_thread(false)?
+            .filter(|t| match self.suspend_thread(t.as_ref()) {
+                Ok(_) => true,
+                Err(err) => {
+                    error!("suspend ~{}: {err:?}", t.tid);
+                    error = Some(err);
+                    false
+                }
+            })
+            .map(|t| t.tid)
+            .collect::<Vec<_>>();
+        match error {
+            Some(err) if suspended.is_empty() => Err(err),
+            _ => Ok(suspended),
+        }
+    }
+
+    /// Resume every thread suspended by the crate a level, returns the count of them
+    pub fn resume_all<T: UDbgTarget + ?Sized>(&self, target: &T) -> usize {
+        self.frozen()
+            .into_iter()
+            .filter(|&tid| {
+                self.resume(target, tid)
+                    .log_error_with(|err| format!("resume ~{tid}: {err:?}"))
+                    .is_some()
+            })
+            .count()
+    }
+
+    /// Resume every thread suspended by the crate at all levels and forget them, to cleanup the
+    /// target before detaching, returns the count of them
+    pub fn thaw_all<T: UDbgTarget + ?Sized>(&self, target: &T) -> usize {
+        let mut counts = self.counts.lock();
+        core::mem::take(&mut *counts)
+            .into_keys()
+            .filter(|&tid| {
+                target
+                    .open_thread(tid)
+                    .and_then(|t| t.resume())
+                    .log_error_with(|err| format!("resume ~{tid}: {err:?}"))
+                    .is_some()
+            })
+            .count()
+    }
+}
+
+/// The threads suspended by [`crate::target::TargetUtil::freeze`], resumed a level when dropped
+pub struct FreezeGuard<'a, T: UDbgTarget + ?Sized> {
+    target: &'a T,
+    threads: Vec<tid_t>,
+}
+
+impl<'a, T: UDbgTarget + ?Sized> FreezeGuard<'a, T> {
+    pub fn new(target: &'a T) -> UDbgResult<Self> {
+        Ok(Self {
+            threads: target.base().freeze.suspend_all(target)?,
+            target,
+        })
+    }
+
+    /// The threads suspended by this guard
+    #[inline]
+    pub fn threads(&self) -> &[tid_t] {
+        &self.threads
+    }
+}
+
+impl<T: UDbgTarget + ?Sized> Drop for FreezeGuard<'_, T> {
+    fn drop(&mut self) {
+        let freeze = &self.target.base().freeze;
+        for &tid in self.threads.iter() {
+            freeze
+                .resume(self.target, tid)
+                .log_error_with(|err| format!("resume ~{tid}: {err:?}"));
+        }
+    }
+}
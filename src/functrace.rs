@@ -0,0 +1,309 @@
+//!
+//! Function-entry tracing of a module, like ftrace for one module.
+//!
+//! [`FuncTracer::trace_module`] sets breakpoints on the entry of every function in a module,
+//! found by the function symbols, exports and the unwind table on Windows. On entry a breakpoint
+//! is set at the return address, so each call is logged with the thread and duration.
+//! When the breakpoints hit too often, the hottest functions are disabled to keep target usable.
+//!
+
+use crate::breakpoint::{return_address, ReturnWaiters};
+use crate::prelude::*;
+use crate::register::regid;
+
+use core::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct FuncTraceOptions {
+    /// Max entries of all the functions in a window, above which the hottest one is disabled
+    pub budget: usize,
+    pub window: Duration,
+    /// Max calls kept, the oldest are dropped
+    pub max_calls: usize,
+}
+
+impl Default for FuncTraceOptions {
+    fn default() -> Self {
+        Self {
+            budget: 5000,
+            window: Duration::from_secs(1),
+            max_calls: 10000,
+        }
+    }
+}
+
+/// A returned call of traced function
+#[derive(Debug, Clone, Serialize)]
+pub struct FuncCall {
+    pub tid: tid_t,
+    pub function: Arc<str>,
+    pub address: usize,
+    /// The innermost traced function on the stack when called
+    pub caller: Option<usize>,
+    /// The return address of the call, right after the call instruction
+    pub call_site: usize,
+    /// Depth of the traced calls in this thread when called
+    pub depth: usize,
+    pub duration: Duration,
+}
+
+struct Function {
+    name: Arc<str>,
+    bp: Arc<dyn UDbgBreakpoint>,
+    /// Entries in current window
+    hits: usize,
+    total: usize,
+}
+
+struct Frame {
+    function: usize,
+    ret: usize,
+    /// The stack pointer after returned
+    sp: usize,
+    start: Instant,
+}
+
+/// Entry addresses and names of the functions in module, sorted by address
+pub fn module_functions<T: UDbgTarget + ?Sized>(
+    target: &T,
+    module: &dyn UDbgModule,
+    pattern: Option<&str>,
+) -> UDbgResult<Vec<(usize, Arc<str>)>> {
+    let base = module.data().base;
+    let executable = |a: usize| target.virtual_query(a).map_or(false, |p| p.is_executable());
+    let mut result = module
+        .enum_symbol(pattern)?
+        .filter(|s| {
+            let flags = SymbolFlags::from_bits_truncate(s.flags);
+            flags.contains(SymbolFlags::FUNCTION)
+                || flags.contains(SymbolFlags::EXPORT) && executable(base + s.offset as usize)
+        })
+        .map(|s| (base + s.offset as usize, s.name))
+        .collect::<Vec<_>>();
+    #[cfg(windows)]
+    if pattern.is_none() {
+        // the functions without symbol
+        for f in module.runtime_function().unwrap_or_default() {
+            let address = base + f.BeginAddress as usize;
+            result.push((address, format!("sub_{address:x}").into()));
+        }
+    }
+    result.sort_by_key(|f| f.0);
+    result.dedup_by_key(|f| f.0);
+    Ok(result)
+}
+
+/// Trace the calls of the functions, feed it the events by [`Self::handle`]
+pub struct FuncTracer {
+    opts: FuncTraceOptions,
+    functions: HashMap<usize, Function>,
+    /// Breakpoints at return addresses, with the threads of frames returning to them
+    returns: ReturnWaiters<()>,
+    stacks: HashMap<tid_t, Vec<Frame>>,
+    window_start: Instant,
+    window_hits: usize,
+    calls: VecDeque<FuncCall>,
+}
+
+impl FuncTracer {
+    /// Trace the functions of module, optionally the ones whose name matches the wildcard
+    pub fn trace_module<T: UDbgTarget + ?Sized>(
+        target: &T,
+        module: &str,
+        pattern: Option<&str>,
+        opts: FuncTraceOptions,
+    ) -> UDbgResult<Self> {
+        let module = target.get_module(module).ok_or(UDbgError::NotFound)?;
+        let mut functions = HashMap::new();
+        for (address, name) in module_functions(target, module.as_ref(), pattern)? {
+            match target.add_breakpoint(address.into()) {
+                Ok(bp) => {
+                    functions.insert(
+                        address,
+                        Function {
+                            name,
+                            bp,
+                            hits: 0,
+                            total: 0,
+                        },
+                    );
+                }
+                // the user's breakpoint is kept
+                Err(UDbgError::BpExists) => {}
+                Err(err) => warn!("trace {name}: {err:?}"),
+            }
+        }
+        info!(
+            "tracing {} functions of {}",
+            functions.len(),
+            module.data().name
+        );
+        Ok(Self {
+            opts,
+            functions,
+            returns: ReturnWaiters::default(),
+            stacks: HashMap::new(),
+            window_start: Instant::now(),
+            window_hits: 0,
+            calls: VecDeque::new(),
+        })
+    }
+
+    /// Handle the event from the debug loop, returns the reply if it's a traced breakpoint
+    pub fn handle(&mut self, ctx: &mut dyn TraceContext, event: &UEvent) -> Option<UserReply> {
+        let address = match event {
+            UEvent::Breakpoint(bp) => bp.address(),
+            _ => return None,
+        };
+        let is_return = self.returns.contains(address);
+        let is_entry = self.functions.contains_key(&address);
+        if !is_return && !is_entry {
+            return None;
+        }
+
+        let target = ctx.target();
+        let tid = target.base().event_tid.get();
+        let arch = ctx.arch();
+        let ptr_size = ctx.pointer_size();
+        let regs = ctx.register()?;
+        let sp = regs.get_reg(regid::COMM_REG_SP)?.as_int();
+        // a function may be called at the return address of another one
+        if is_return {
+            self.on_return(tid, address, sp);
+        }
+        if is_entry {
+            let ret = return_address(target.as_ref(), regs, arch);
+            let sp = match arch {
+                ARCH_X86 | ARCH_X64 => sp + ptr_size,
+                _ => sp,
+            };
+            if let Some(ret) = ret {
+                self.on_entry(target.as_ref(), tid, address, ret, sp);
+            }
+        }
+        Some(UserReply::Run(false))
+    }
+
+    fn on_entry(
+        &mut self,
+        target: &dyn UDbgTarget,
+        tid: tid_t,
+        function: usize,
+        ret: usize,
+        sp: usize,
+    ) {
+        if let Some(f) = self.functions.get_mut(&function) {
+            f.hits += 1;
+            f.total += 1;
+        }
+        self.check_budget();
+
+        if self.returns.wait(target, ret, tid, ()).is_ok() {
+            self.stacks.entry(tid).or_default().push(Frame {
+                function,
+                ret,
+                sp,
+                start: Instant::now(),
+            });
+        }
+    }
+
+    fn on_return(&mut self, tid: tid_t, ret: usize, sp: usize) {
+        let stack = match self.stacks.get_mut(&tid) {
+            Some(s) => s,
+            None => return,
+        };
+        // the frames skipped by exceptions or longjmp are dropped too
+        while let Some(frame) = stack.last().filter(|f| f.sp <= sp) {
+            if frame.ret == ret {
+                let call = FuncCall {
+                    tid,
+                    function: self
+                        .functions
+                        .get(&frame.function)
+                        .map(|f| f.name.clone())
+                        .unwrap_or_default(),
+                    address: frame.function,
+                    caller: stack.iter().rev().nth(1).map(|f| f.function),
+                    call_site: frame.ret,
+                    depth: stack.len() - 1,
+                    duration: frame.start.elapsed(),
+                };
+                info!(
+                    "~{tid} {}{} {:?}",
+                    " ".repeat(call.depth),
+                    call.function,
+                    call.duration
+                );
+                if self.calls.len() >= self.opts.max_calls {
+                    self.calls.pop_front();
+                }
+                self.calls.push_back(call);
+            }
+            let ret = frame.ret;
+            stack.pop();
+            self.returns.take(ret, tid);
+        }
+    }
+
+    /// Disable the hottest function if the entries in current window exceed the budget
+    fn check_budget(&mut self) {
+        if self.window_start.elapsed() >= self.opts.window {
+            self.window_start = Instant::now();
+            self.window_hits = 0;
+            self.functions.values_mut().for_each(|f| f.hits = 0);
+        }
+        self.window_hits += 1;
+        if self.window_hits <= self.opts.budget {
+            return;
+        }
+        if let Some(f) = self
+            .functions
+            .values_mut()
+            .filter(|f| f.bp.enabled())
+            .max_by_key(|f| f.hits)
+        {
+            warn!("{} is too hot ({} hits), disabled", f.name, f.hits);
+            f.bp.enable(false).log_error("disable bp");
+            self.window_hits -= f.hits.min(self.window_hits);
+            f.hits = 0;
+        }
+    }
+
+    /// Names of the functions disabled by the budget
+    pub fn disabled(&self) -> Vec<Arc<str>> {
+        self.functions
+            .values()
+            .filter(|f| !f.bp.enabled())
+            .map(|f| f.name.clone())
+            .collect()
+    }
+
+    /// Total entries of each function, from the hottest
+    pub fn hits(&self) -> Vec<(Arc<str>, usize)> {
+        let mut result = self
+            .functions
+            .values()
+            .map(|f| (f.name.clone(), f.total))
+            .collect::<Vec<_>>();
+        result.sort_by_key(|f| core::cmp::Reverse(f.1));
+        result
+    }
+
+    /// Take the calls returned since last call
+    pub fn take_calls(&mut self) -> Vec<FuncCall> {
+        self.calls.drain(..).collect()
+    }
+
+    /// Remove all the breakpoints
+    pub fn stop(&mut self) {
+        for f in self.functions.drain().map(|(_, f)| f) {
+            f.bp.remove().log_error("remove bp");
+        }
+        self.returns.clear();
+        self.stacks.clear();
+    }
+}
@@ -0,0 +1,203 @@
+//!
+//! Graphics awareness: identify the GPU driver modules and threads, and break at the frame boundary.
+//!
+//! The present functions of the graphics APIs are resolved by symbols, the ones of DXGI and D3D9
+//! are not exported, so they need the public PDB of the system modules.
+//! Breaking at the frame boundary is the usual way to start debugging a game in a known state.
+//!
+
+use crate::prelude::*;
+
+use glob::{MatchOptions, Pattern};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum GpuVendor {
+    Nvidia,
+    Amd,
+    Intel,
+    /// The software renderer such as WARP and llvmpipe
+    Software,
+}
+
+/// User-mode drivers of the GPU vendors, matched case-insensitively
+#[rustfmt::skip]
+const DRIVERS: &[(&str, GpuVendor)] = &[
+    ("nvwgf2um*.dll", GpuVendor::Nvidia),
+    ("nvd3dum*.dll", GpuVendor::Nvidia),
+    ("nvoglv*.dll", GpuVendor::Nvidia),
+    ("nvcuda*.dll", GpuVendor::Nvidia),
+    ("nvldumd*.dll", GpuVendor::Nvidia),
+    ("libnvidia-*.so*", GpuVendor::Nvidia),
+    ("libGLX_nvidia.so*", GpuVendor::Nvidia),
+    ("libcuda.so*", GpuVendor::Nvidia),
+    ("amdxc*.dll", GpuVendor::Amd),
+    ("atidxx*.dll", GpuVendor::Amd),
+    ("atiumd*.dll", GpuVendor::Amd),
+    ("atio6axx.dll", GpuVendor::Amd),
+    ("amdvlk*.dll", GpuVendor::Amd),
+    ("aticfx*.dll", GpuVendor::Amd),
+    ("libvulkan_radeon.so*", GpuVendor::Amd),
+    ("radeonsi_dri.so", GpuVendor::Amd),
+    ("igd10iumd*.dll", GpuVendor::Intel),
+    ("igd12umd*.dll", GpuVendor::Intel),
+    ("igdumdim*.dll", GpuVendor::Intel),
+    ("igc*.dll", GpuVendor::Intel),
+    ("igvk*.dll", GpuVendor::Intel),
+    ("ig*icd*.dll", GpuVendor::Intel),
+    ("libvulkan_intel.so*", GpuVendor::Intel),
+    ("iris_dri.so", GpuVendor::Intel),
+    ("d3d10warp.dll", GpuVendor::Software),
+    ("swrast_dri.so", GpuVendor::Software),
+    ("libvulkan_lvp.so*", GpuVendor::Software),
+];
+
+/// Modules of the graphics APIs
+#[rustfmt::skip]
+const APIS: &[(&str, &str)] = &[
+    ("dxgi.dll", "DXGI"),
+    ("d3d9.dll", "D3D9"),
+    ("d3d11.dll", "D3D11"),
+    ("d3d12.dll", "D3D12"),
+    ("d3d12core.dll", "D3D12"),
+    ("vulkan-1.dll", "Vulkan"),
+    ("libvulkan.so*", "Vulkan"),
+    ("opengl32.dll", "OpenGL"),
+    ("libGL.so*", "OpenGL"),
+    ("libGLX.so*", "OpenGL"),
+    ("libEGL.so*", "OpenGL"),
+];
+
+/// Functions which present a frame, by API
+#[rustfmt::skip]
+pub const PRESENT_SYMBOLS: &[(&str, &str)] = &[
+    ("DXGI", "dxgi!CDXGISwapChain::Present"),
+    ("DXGI", "dxgi!CDXGISwapChain::Present1"),
+    ("D3D9", "d3d9!CSwapChain::Present"),
+    ("D3D9", "d3d9!CBaseDevice::Present"),
+    ("D3D9", "d3d9!CBaseDevice::PresentEx"),
+    ("Vulkan", "vulkan-1!vkQueuePresentKHR"),
+    ("Vulkan", "libvulkan.so.1!vkQueuePresentKHR"),
+    ("OpenGL", "gdi32!SwapBuffers"),
+    ("OpenGL", "opengl32!wglSwapBuffers"),
+    ("OpenGL", "libGLX.so.0!glXSwapBuffers"),
+    ("OpenGL", "libGL.so.1!glXSwapBuffers"),
+    ("OpenGL", "libEGL.so.1!eglSwapBuffers"),
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub enum GfxModuleKind {
+    /// Module of a graphics API, with the API name
+    Api(&'static str),
+    Driver(GpuVendor),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GfxModule {
+    pub name: Arc<str>,
+    pub base: usize,
+    pub size: usize,
+    pub kind: GfxModuleKind,
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    let options = MatchOptions {
+        case_sensitive: false,
+        ..Default::default()
+    };
+    Pattern::new(pattern).map_or(false, |p| p.matches_with(name, options))
+}
+
+/// Classify a module by name
+pub fn classify_module(name: &str) -> Option<GfxModuleKind> {
+    DRIVERS
+        .iter()
+        .find(|(p, _)| matches(p, name))
+        .map(|(_, v)| GfxModuleKind::Driver(*v))
+        .or_else(|| {
+            APIS.iter()
+                .find(|(p, _)| matches(p, name))
+                .map(|(_, api)| GfxModuleKind::Api(api))
+        })
+}
+
+/// The loaded graphics API and GPU driver modules
+pub fn gfx_modules<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<Vec<GfxModule>> {
+    Ok(target
+        .enum_module()?
+        .filter_map(|m| {
+            let data = m.data();
+            Some(GfxModule {
+                name: data.name.clone(),
+                base: data.base,
+                size: data.size,
+                kind: classify_module(&data.name)?,
+            })
+        })
+        .collect())
+}
+
+/// Threads started in the GPU driver modules, with the vendor
+#[cfg(windows)]
+pub fn gpu_threads<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<Vec<(tid_t, GpuVendor)>> {
+    let drivers = gfx_modules(target)?
+        .into_iter()
+        .filter_map(|m| match m.kind {
+            GfxModuleKind::Driver(v) => Some((m.base..m.base + m.size, v)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    Ok(target
+        .enum_thread(true)?
+        .filter_map(|t| {
+            let entry = t.entry();
+            let (_, vendor) = drivers.iter().find(|(r, _)| r.contains(&entry))?;
+            Some((t.tid, *vendor))
+        })
+        .collect())
+}
+
+/// A resolved present function
+#[derive(Debug, Clone, Serialize)]
+pub struct PresentPoint {
+    pub api: &'static str,
+    pub symbol: &'static str,
+    pub address: usize,
+}
+
+/// Resolve the present functions of the loaded graphics APIs
+pub fn present_points<T: UDbgTarget + ?Sized>(target: &T) -> Vec<PresentPoint> {
+    PRESENT_SYMBOLS
+        .iter()
+        .filter_map(|&(api, symbol)| {
+            let (module, _) = symbol.split_once('!')?;
+            target.get_module(module)?;
+            Some(PresentPoint {
+                api,
+                symbol,
+                address: target.get_address_by_symbol(symbol)?,
+            })
+        })
+        .collect()
+}
+
+/// Set breakpoints at the present functions, the temporary ones break at the next frame only
+#[cfg(not(feature = "passive"))]
+pub fn break_on_present<T: UDbgTarget + ?Sized>(
+    target: &T,
+    temp: bool,
+) -> UDbgResult<Vec<Arc<dyn UDbgBreakpoint>>> {
+    let points = present_points(target);
+    if points.is_empty() {
+        return Err(UDbgError::NotFound);
+    }
+    let mut result = vec![];
+    for p in points {
+        match target.add_breakpoint(BpOpt::int3(p.address).temp(temp)) {
+            Ok(bp) => result.push(bp),
+            Err(UDbgError::BpExists) => {}
+            Err(err) => warn!("breakpoint at {}: {err:?}", p.symbol),
+        }
+    }
+    Ok(result)
+}
@@ -0,0 +1,167 @@
+//!
+//! Crash-safe cleanup of debuggee, restores the breakpoint bytes, the inline hooks and the pages
+//! armed by the memory watches and the OEP heuristic, resumes the threads suspended by the crate
+//! and detaches the targets when the host panics or exits without detaching.
+//!
+//! A panic is only handled when it's not caught: when the guard is dropped by the unwinding, or in
+//! the panic hook if the host is built with `panic = "abort"`. The remaining targets are restored
+//! at the exit of process.
+//!
+//! All the work is best-effort: [`restore_all`] does it in a watchdog thread with timeout to avoid
+//! deadlock with the panicking thread, the exit does it in place since no thread can be spawned
+//! then, and detaching directly only works when it's called from the debugger thread.
+//! Nothing can be done if the host is killed forcibly, on Windows [`CleanupGuard::new`] makes the debuggee survive in that case,
+//! but the planted breakpoints are left.
+//!
+
+use crate::prelude::*;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+#[cfg(panic = "abort")]
+use std::sync::Once;
+use std::sync::{Arc, Weak};
+
+static SESSIONS: Mutex<Vec<(usize, Weak<dyn UDbgTarget>)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+#[cfg(panic = "abort")]
+static HOOK: Once = Once::new();
+
+/// Max time to wait for the cleanup when panic or exit
+pub const CLEANUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Restore the target when dropped while panicking, or when the host aborts by panic or exits with
+/// the guard alive
+pub struct CleanupGuard {
+    id: usize,
+    target: Weak<dyn UDbgTarget>,
+}
+
+impl CleanupGuard {
+    /// Register a target to cleanup, should be called in the debugger thread
+    pub fn new(target: &Arc<dyn UDbgTarget>) -> Self {
+        // no unwinding will drop the guards, every panic is uncaught
+        #[cfg(panic = "abort")]
+        HOOK.call_once(|| {
+            let prev = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                restore_all(CLEANUP_TIMEOUT);
+                prev(info)
+            }));
+        });
+
+        #[cfg(windows)]
+        unsafe {
+            // keep the debuggee alive if debugger exits without detaching
+            winapi::um::winbase::DebugSetProcessKillOnExit(0);
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let target = Arc::downgrade(target);
+        SESSIONS.lock().push((id, target.clone()));
+        Self { id, target }
+    }
+
+    /// Restore the target now
+    pub fn cleanup(&self) {
+        if let Some(t) = self.target.upgrade() {
+            restore(t.as_ref());
+        }
+    }
+}
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        SESSIONS.lock().retain(|(id, _)| *id != self.id);
+        if std::thread::panicking() {
+            self.cleanup();
+        }
+    }
+}
+
+/// Restore the original bytes of breakpoints and inline hooks, the protection of the watched and
+/// OEP-armed pages, resume the threads and detach the target
+pub fn restore(target: &dyn UDbgTarget) {
+    restore_memory(target);
+    force_detach(target);
+}
+
+fn restore_memory(target: &dyn UDbgTarget) {
+    let base = target.base();
+    #[cfg(not(feature = "passive"))]
+    base.hooks.remove_all(target);
+    base.mem_watch.remove_all(target);
+    #[cfg(windows)]
+    base.oep.lock().disarm_all(target);
+    for bp in target.get_breakpoints() {
+        if bp.enabled() {
+            bp.enable(false)
+                .log_error_with(|err| format!("restore bp {:x}: {err:?}", bp.address()));
+        }
+    }
+    base.freeze.thaw_all(target);
+    target.resume().ok();
+    target.detach().ok();
+}
+
+fn take_sessions() -> Vec<Arc<dyn UDbgTarget>> {
+    SESSIONS
+        .lock()
+        .drain(..)
+        .filter_map(|(_, t)| t.upgrade())
+        .collect()
+}
+
+/// Restore all the registered targets in a watchdog thread, wait for it at most `timeout`
+pub fn restore_all(timeout: Duration) {
+    let targets = take_sessions();
+    if targets.is_empty() {
+        return;
+    }
+
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    let watchdog = targets.clone();
+    std::thread::spawn(move || {
+        for t in watchdog {
+            restore_memory(t.as_ref());
+        }
+        tx.send(()).ok();
+    });
+    if rx.recv_timeout(timeout).is_err() {
+        error!("cleanup targets timeout");
+    }
+    // current thread may be the debugger thread
+    for t in targets {
+        force_detach(t.as_ref());
+    }
+}
+
+#[ctor::dtor]
+fn restore_at_exit() {
+    // the other threads are gone or being terminated, nothing to deadlock with
+    for t in take_sessions() {
+        restore(t.as_ref());
+    }
+}
+
+/// Detach without the debug event loop, it's only valid in the debugger thread
+fn force_detach(target: &dyn UDbgTarget) {
+    cfg_if! {
+        if #[cfg(windows)] {
+            unsafe {
+                winapi::um::debugapi::DebugActiveProcessStop(target.base().pid.get());
+            }
+        } else if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            use nix::{sys::ptrace, unistd::Pid};
+
+            if let Ok(threads) = target.enum_thread(false) {
+                for t in threads {
+                    ptrace::detach(Pid::from_raw(t.tid as _), None).ok();
+                }
+            }
+        } else {
+            let _ = target;
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//!
+//! Hang analysis: classify why a target stops responding, and name the blocking thread and resource.
+//!
+//! [`analyze_hang`] measures the CPU time of each thread over a short window, reads the wait chains
+//! by [`crate::waitchain`] and checks whether the windows of target still pump messages.
+//!
+
+use crate::{
+    prelude::*,
+    waitchain::{wait_chain, WaitChain},
+};
+
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct HangOptions {
+    /// Window to measure the CPU time of threads
+    pub window: Duration,
+    /// CPU usage of a thread in the window, above which it's considered spinning
+    pub busy_ratio: f64,
+}
+
+impl Default for HangOptions {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_millis(500),
+            busy_ratio: 0.9,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum HangKind {
+    /// No stuck thread found
+    Responsive,
+    /// A wait chain contains a cycle
+    Deadlock,
+    /// A window of target doesn't handle messages
+    MessageLoopBlocked,
+    /// A thread burns the CPU, e.g. an infinite loop
+    BusyLoop,
+    /// A thread waits for a resource owned by other thread or process, or for IO
+    Blocked,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadState {
+    pub tid: tid_t,
+    pub status: Arc<str>,
+    /// CPU time used in the window
+    pub cpu: Duration,
+    /// The wait chain starting from this thread
+    pub wait_chain: WaitChain,
+    /// Handles of the hung windows owned by this thread
+    pub hung_windows: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HangVerdict {
+    pub kind: HangKind,
+    /// The thread causing the hang
+    pub blocking_thread: Option<tid_t>,
+    /// The resource the blocking thread waits for
+    pub resource: Option<String>,
+    pub summary: String,
+    pub threads: Vec<ThreadState>,
+}
+
+/// Inspect the threads of target and classify the hang
+pub fn analyze_hang<T: UDbgTarget + ?Sized>(
+    target: &T,
+    opts: &HangOptions,
+) -> UDbgResult<HangVerdict> {
+    let pid = target.pid();
+    let threads = target.enum_thread(true)?.collect::<Vec<_>>();
+    let before = threads
+        .iter()
+        .filter_map(|t| Some((t.tid, sys::thread_cpu_time(pid, t.tid)?)))
+        .collect::<HashMap<_, _>>();
+    std::thread::sleep(opts.window);
+
+    let hung_windows = sys::hung_windows(pid);
+    let mut states = threads
+        .iter()
+        .map(|t| {
+            let cpu = before
+                .get(&t.tid)
+                .zip(sys::thread_cpu_time(pid, t.tid))
+                .map(|(b, a)| a.saturating_sub(*b))
+                .unwrap_or_default();
+            let wait_chain = wait_chain(target, t.tid).unwrap_or_default();
+            ThreadState {
+                tid: t.tid,
+                status: t.status(),
+                cpu,
+                wait_chain,
+                hung_windows: hung_windows
+                    .iter()
+                    .filter(|(_, tid)| *tid == t.tid)
+                    .map(|(hwnd, _)| *hwnd)
+                    .collect(),
+            }
+        })
+        .collect::<Vec<_>>();
+    states.sort_by_key(|s| core::cmp::Reverse(s.cpu));
+
+    let resource = |s: &ThreadState| {
+        s.wait_chain.resource().map(|n| match n.name.as_str() {
+            "" => n.kind.to_string(),
+            name => format!("{} {name}", n.kind),
+        })
+    };
+    let verdict = |kind, s: Option<&ThreadState>, summary: String| HangVerdict {
+        kind,
+        blocking_thread: s.map(|s| s.tid),
+        resource: s.and_then(resource),
+        summary,
+        threads: vec![],
+    };
+
+    let mut result = if let Some(s) = states.iter().find(|s| s.wait_chain.cycle) {
+        let tids = s
+            .wait_chain
+            .threads()
+            .map(|t| format!("~{t}"))
+            .collect::<Vec<_>>();
+        verdict(
+            HangKind::Deadlock,
+            Some(s),
+            format!("deadlock: {}", tids.join(" -> ")),
+        )
+    } else if let Some(s) = states.iter().find(|s| !s.hung_windows.is_empty()) {
+        // the UI thread may wait for another thread, which is the real culprit
+        let owner = s
+            .wait_chain
+            .owner()
+            .filter(|n| n.pid.map_or(true, |p| p == pid))
+            .and_then(|n| states.iter().find(|s| Some(s.tid) == n.tid))
+            .unwrap_or(s);
+        let mut v = verdict(
+            HangKind::MessageLoopBlocked,
+            Some(owner),
+            format!(
+                "{} window(s) of ~{} not responding",
+                s.hung_windows.len(),
+                s.tid
+            ),
+        );
+        v.resource = resource(s).or(v.resource);
+        v
+    } else if let Some(s) = states
+        .iter()
+        .find(|s| s.cpu.as_secs_f64() >= opts.window.as_secs_f64() * opts.busy_ratio)
+    {
+        verdict(
+            HangKind::BusyLoop,
+            Some(s),
+            format!("~{} used {:?} CPU in {:?}", s.tid, s.cpu, opts.window),
+        )
+    } else if let Some(s) = states.iter().find(|s| s.wait_chain.is_blocked()) {
+        verdict(HangKind::Blocked, Some(s), format!("~{} is blocked", s.tid))
+    } else {
+        verdict(HangKind::Responsive, None, "no stuck thread found".into())
+    };
+    if let Some(r) = result.resource.as_ref() {
+        result.summary = format!("{}, waiting for {r}", result.summary);
+    }
+    result.threads = states;
+    Ok(result)
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::*;
+    use crate::os::windows::open_thread;
+
+    use core::mem::zeroed;
+    use winapi::shared::minwindef::{BOOL, FILETIME, LPARAM, TRUE};
+    use winapi::shared::windef::HWND;
+    use winapi::um::processthreadsapi::GetThreadTimes;
+    use winapi::um::winnt::THREAD_QUERY_LIMITED_INFORMATION;
+    use winapi::um::winuser::{EnumWindows, GetWindowThreadProcessId, IsHungAppWindow};
+
+    pub fn thread_cpu_time(_pid: pid_t, tid: tid_t) -> Option<Duration> {
+        let handle = open_thread(tid, THREAD_QUERY_LIMITED_INFORMATION, false);
+        if !handle.is_valid() {
+            return None;
+        }
+        unsafe {
+            let (mut create, mut exit, mut kernel, mut user) =
+                (zeroed(), zeroed(), zeroed(), zeroed());
+            if GetThreadTimes(*handle, &mut create, &mut exit, &mut kernel, &mut user) == 0 {
+                return None;
+            }
+            let ticks = |t: FILETIME| (t.dwHighDateTime as u64) << 32 | t.dwLowDateTime as u64;
+            // in 100ns
+            Some(Duration::from_nanos((ticks(kernel) + ticks(user)) * 100))
+        }
+    }
+
+    /// Top-level windows of process which don't respond, with the owner thread
+    pub fn hung_windows(pid: pid_t) -> Vec<(usize, tid_t)> {
+        unsafe extern "system" fn callback(hwnd: HWND, param: LPARAM) -> BOOL {
+            let (pid, result) = &mut *(param as *mut (pid_t, Vec<(usize, tid_t)>));
+            let mut owner = 0;
+            let tid = GetWindowThreadProcessId(hwnd, &mut owner);
+            if owner == *pid && IsHungAppWindow(hwnd) != 0 {
+                result.push((hwnd as usize, tid));
+            }
+            TRUE
+        }
+
+        let mut param = (pid, vec![]);
+        unsafe {
+            EnumWindows(Some(callback), &mut param as *mut _ as LPARAM);
+        }
+        param.1
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use super::*;
+
+    /// Fields of `/proc/<pid>/task/<tid>/stat` after the command name
+    fn task_stat(pid: pid_t, tid: tid_t) -> Option<Vec<String>> {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/task/{tid}/stat")).ok()?;
+        let (_, fields) = stat.rsplit_once(')')?;
+        Some(fields.split_whitespace().map(Into::into).collect())
+    }
+
+    pub fn thread_cpu_time(pid: pid_t, tid: tid_t) -> Option<Duration> {
+        let fields = task_stat(pid, tid)?;
+        // utime and stime, the 14th and 15th fields of stat
+        let ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+        let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+        Some(Duration::from_micros(ticks * 1_000_000 / hz))
+    }
+
+    pub fn hung_windows(_pid: pid_t) -> Vec<(usize, tid_t)> {
+        vec![]
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android")))]
+mod sys {
+    use super::*;
+
+    pub fn thread_cpu_time(_pid: pid_t, _tid: tid_t) -> Option<Duration> {
+        None
+    }
+
+    pub fn hung_windows(_pid: pid_t) -> Vec<(usize, tid_t)> {
+        vec![]
+    }
+}
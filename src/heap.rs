@@ -0,0 +1,416 @@
+//!
+//! Heap diffing between two points in time, to investigate the memory growth of a live target.
+//!
+//! [`HeapSnapshot::capture`] enumerates the busy blocks by the system: the toolhelp heap walk on
+//! Windows, and the chunks of the main arena of glibc on Linux. [`AllocTracker`] hooks the
+//! allocation functions by [`ApiTracer`] and keeps the live blocks with their allocation stacks,
+//! so the [`HeapDiff`] of its snapshots could be grouped by the stacks.
+//!
+//! The toolhelp heap walk runs a thread in target, it must not be done in the event callback.
+//! The chunks in the tcache and fastbins of glibc are counted as busy, they're not distinguished.
+//!
+
+use crate::{
+    apitrace::{ApiCall, ApiTracer},
+    prelude::*,
+};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Index of an allocation stack in [`AllocTracker`]
+pub type StackId = u32;
+
+/// The functions hooked by [`AllocTracker::start`]
+#[cfg(windows)]
+pub const ALLOC_FUNCTIONS: &[&str] = &[
+    "ntdll!RtlAllocateHeap",
+    "ntdll!RtlReAllocateHeap",
+    "ntdll!RtlFreeHeap",
+];
+/// The functions hooked by [`AllocTracker::start`]
+#[cfg(not(windows))]
+pub const ALLOC_FUNCTIONS: &[&str] = &["libc!malloc", "libc!calloc", "libc!realloc", "libc!free"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HeapBlock {
+    pub address: usize,
+    pub size: usize,
+    /// The allocation stack, if the block is tracked by [`AllocTracker`]
+    pub stack: Option<StackId>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HeapSnapshot {
+    pub time: SystemTime,
+    /// The busy blocks by address
+    pub blocks: BTreeMap<usize, HeapBlock>,
+}
+
+impl HeapSnapshot {
+    /// Enumerate the busy blocks of the heaps in target
+    pub fn capture<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<Self> {
+        Ok(Self::from_blocks(sys::enum_blocks(target)?))
+    }
+
+    pub fn from_blocks(blocks: impl IntoIterator<Item = HeapBlock>) -> Self {
+        Self {
+            time: SystemTime::now(),
+            blocks: blocks.into_iter().map(|b| (b.address, b)).collect(),
+        }
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.blocks.values().map(|b| b.size).sum()
+    }
+
+    /// The blocks allocated and freed since an `earlier` snapshot, a block reallocated at the
+    /// same address with another size or stack is both
+    pub fn diff(&self, earlier: &Self) -> HeapDiff {
+        let changed = |a: &Self, b: &Self| {
+            a.blocks
+                .values()
+                .filter(|x| b.blocks.get(&x.address) != Some(x))
+                .copied()
+                .collect()
+        };
+        HeapDiff {
+            new: changed(self, earlier),
+            freed: changed(earlier, self),
+        }
+    }
+}
+
+/// Count and size of the blocks in a bucket of histogram
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SizeBucket {
+    /// Upper bound of the block sizes, a power of two
+    pub max_size: usize,
+    pub count: usize,
+    pub size: usize,
+}
+
+fn size_histogram<'a>(blocks: impl Iterator<Item = &'a HeapBlock>) -> Vec<SizeBucket> {
+    let mut buckets = BTreeMap::<usize, SizeBucket>::new();
+    for b in blocks {
+        let max_size = b.size.max(1).next_power_of_two();
+        let bucket = buckets.entry(max_size).or_insert(SizeBucket {
+            max_size,
+            ..Default::default()
+        });
+        bucket.count += 1;
+        bucket.size += b.size;
+    }
+    buckets.into_values().collect()
+}
+
+/// The changes of blocks allocated by a stack
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StackGrowth {
+    /// None for the blocks not tracked
+    pub stack: Option<StackId>,
+    pub new_count: usize,
+    pub new_size: usize,
+    pub freed_count: usize,
+    pub freed_size: usize,
+    /// Sizes of the new blocks
+    pub histogram: Vec<SizeBucket>,
+}
+
+impl StackGrowth {
+    #[inline]
+    pub fn growth(&self) -> isize {
+        self.new_size as isize - self.freed_size as isize
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HeapDiff {
+    pub new: Vec<HeapBlock>,
+    pub freed: Vec<HeapBlock>,
+}
+
+impl HeapDiff {
+    pub fn new_size(&self) -> usize {
+        self.new.iter().map(|b| b.size).sum()
+    }
+
+    pub fn freed_size(&self) -> usize {
+        self.freed.iter().map(|b| b.size).sum()
+    }
+
+    /// Net growth in bytes
+    #[inline]
+    pub fn growth(&self) -> isize {
+        self.new_size() as isize - self.freed_size() as isize
+    }
+
+    /// Histogram of the new blocks by size
+    pub fn size_histogram(&self) -> Vec<SizeBucket> {
+        size_histogram(self.new.iter())
+    }
+
+    /// The changes grouped by the allocation stacks, the largest growth first
+    pub fn by_stack(&self) -> Vec<StackGrowth> {
+        let mut groups = HashMap::<Option<StackId>, StackGrowth>::new();
+        let mut new_blocks = HashMap::<Option<StackId>, Vec<&HeapBlock>>::new();
+        for b in self.new.iter() {
+            let group = groups.entry(b.stack).or_default();
+            group.new_count += 1;
+            group.new_size += b.size;
+            new_blocks.entry(b.stack).or_default().push(b);
+        }
+        for b in self.freed.iter() {
+            let group = groups.entry(b.stack).or_default();
+            group.freed_count += 1;
+            group.freed_size += b.size;
+        }
+        let mut result = groups
+            .into_iter()
+            .map(|(stack, group)| StackGrowth {
+                stack,
+                histogram: new_blocks
+                    .get(&stack)
+                    .map(|blocks| size_histogram(blocks.iter().copied()))
+                    .unwrap_or_default(),
+                ..group
+            })
+            .collect::<Vec<_>>();
+        result.sort_by_key(|g| core::cmp::Reverse(g.growth()));
+        result
+    }
+}
+
+/// Tracker of the live heap blocks and their allocation stacks, by hooking [`ALLOC_FUNCTIONS`].
+/// Each allocation stops the target and unwinds its stack, which slows the target down heavily
+pub struct AllocTracker {
+    target: Arc<dyn UDbgTarget>,
+    tracer: ApiTracer,
+    live: BTreeMap<usize, HeapBlock>,
+    stacks: Vec<Arc<[usize]>>,
+    stack_ids: HashMap<Arc<[usize]>, StackId>,
+    /// Max frames of the allocation stacks
+    pub max_depth: usize,
+}
+
+impl AllocTracker {
+    pub fn new(target: Arc<dyn UDbgTarget>) -> Self {
+        Self {
+            tracer: ApiTracer::new(target.clone()),
+            target,
+            live: Default::default(),
+            stacks: vec![],
+            stack_ids: Default::default(),
+            max_depth: 16,
+        }
+    }
+
+    /// Hook the allocation functions, returns the count of hooked ones, could be called again
+    /// after the modules loaded
+    pub fn start(&mut self) -> usize {
+        self.tracer.trace(ALLOC_FUNCTIONS.iter().copied())
+    }
+
+    /// Remove the hooks, the blocks tracked are kept
+    pub fn stop(&mut self) {
+        self.tracer.uninstall();
+    }
+
+    /// If the breakpoint at `address` is set by this tracker
+    #[inline]
+    pub fn owns(&self, address: usize) -> bool {
+        self.tracer.owns(address)
+    }
+
+    /// Handle an event, returns true if it's a breakpoint of this tracker, which should be
+    /// continued silently
+    pub fn on_event(&mut self, event: &UEvent, ctx: &mut dyn TraceContext) -> bool {
+        let bp = match event {
+            UEvent::Breakpoint(bp) if self.owns(bp.address()) => bp,
+            _ => return false,
+        };
+        if let Some(call) = self.tracer.on_breakpoint(bp.as_ref(), ctx) {
+            self.record(&call);
+        }
+        true
+    }
+
+    fn record(&mut self, call: &ApiCall) {
+        let arg = |i: usize| call.args.get(i).map_or(0, |a| a.raw);
+        let name = call.function.rsplit('!').next().unwrap_or_default();
+        // the block freed, and the size allocated
+        let (freed, size) = match name {
+            "malloc" => (0, Some(arg(0))),
+            "calloc" => (0, Some(arg(0).wrapping_mul(arg(1)))),
+            "realloc" => (arg(0), Some(arg(1))),
+            "free" => (arg(0), None),
+            "RtlAllocateHeap" => (0, Some(arg(2))),
+            "RtlReAllocateHeap" => (arg(2), Some(arg(3))),
+            "RtlFreeHeap" => (arg(2), None),
+            _ => return,
+        };
+        let address = call.ret.unwrap_or_default();
+        // the old block is kept if the reallocation failed
+        if size.is_some() && address == 0 {
+            return;
+        }
+        if freed != 0 {
+            self.live.remove(&freed);
+        }
+        if let Some(size) = size {
+            let stack = self.capture_stack(call.tid);
+            self.live.insert(
+                address,
+                HeapBlock {
+                    address,
+                    size,
+                    stack,
+                },
+            );
+        }
+    }
+
+    fn capture_stack(&mut self, tid: tid_t) -> Option<StackId> {
+        let thread = self
+            .target
+            .open_thread(tid)
+            .log_error_with(|err| format!("open ~{tid}: {err:?}"))?;
+        let frames = self
+            .target
+            .stack_trace(thread.as_ref(), self.max_depth)
+            .ok()?
+            .iter()
+            .map(|f| f.pc)
+            .collect::<Arc<[usize]>>();
+        if let Some(&id) = self.stack_ids.get(&frames) {
+            return Some(id);
+        }
+        let id = self.stacks.len() as StackId;
+        self.stacks.push(frames.clone());
+        self.stack_ids.insert(frames, id);
+        Some(id)
+    }
+
+    /// The return addresses of an allocation stack, the innermost first
+    pub fn stack(&self, id: StackId) -> Option<&[usize]> {
+        self.stacks.get(id as usize).map(|s| &**s)
+    }
+
+    /// The blocks allocated since started and not freed
+    pub fn live(&self) -> impl Iterator<Item = &HeapBlock> {
+        self.live.values()
+    }
+
+    /// Snapshot of the tracked blocks, with their stacks
+    pub fn snapshot(&self) -> HeapSnapshot {
+        HeapSnapshot {
+            time: SystemTime::now(),
+            blocks: self.live.clone(),
+        }
+    }
+
+    /// Attach the stacks of the tracked blocks to a snapshot captured from the system
+    pub fn annotate(&self, snapshot: &mut HeapSnapshot) {
+        for b in snapshot.blocks.values_mut() {
+            if let Some(tracked) = self.live.get(&b.address) {
+                b.stack = tracked.stack;
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::*;
+    use crate::os::windows::Handle;
+
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::tlhelp32::*;
+
+    pub fn enum_blocks<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<Vec<HeapBlock>> {
+        let pid = target.base().pid.get() as u32;
+        let mut result = vec![];
+        unsafe {
+            let snap = CreateToolhelp32Snapshot(TH32CS_SNAPHEAPLIST, pid);
+            if snap == INVALID_HANDLE_VALUE {
+                return Err(UDbgError::system());
+            }
+            let snap = Handle::from_raw_handle(snap);
+            let mut list: HEAPLIST32 = core::mem::zeroed();
+            list.dwSize = core::mem::size_of_val(&list) as _;
+            let mut more_heaps = Heap32ListFirst(*snap, &mut list) != 0;
+            while more_heaps {
+                let mut entry: HEAPENTRY32 = core::mem::zeroed();
+                entry.dwSize = core::mem::size_of_val(&entry) as _;
+                let mut more_blocks = Heap32First(&mut entry, pid, list.th32HeapID) != 0;
+                while more_blocks {
+                    if entry.dwFlags & LF32_FREE == 0 {
+                        result.push(HeapBlock {
+                            address: entry.dwAddress,
+                            size: entry.dwBlockSize,
+                            stack: None,
+                        });
+                    }
+                    more_blocks = Heap32Next(&mut entry) != 0;
+                }
+                more_heaps = Heap32ListNext(*snap, &mut list) != 0;
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use super::*;
+
+    /// The previous chunk is in use, in the size field of a chunk
+    const PREV_INUSE: usize = 1;
+
+    fn word(data: &[u8], offset: usize, ps: usize) -> usize {
+        let mut buf = [0u8; 8];
+        buf[..ps].copy_from_slice(&data[offset..offset + ps]);
+        u64::from_le_bytes(buf) as usize
+    }
+
+    /// Walk the chunks in the `[heap]` of the main arena, the other arenas and the chunks
+    /// allocated by `mmap` are not walked
+    pub fn enum_blocks<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<Vec<HeapBlock>> {
+        let ps = target.base().pointer_size();
+        let mut result = vec![];
+        for page in target
+            .enum_memory()?
+            .filter(|p| p.flags.contains(MemoryFlags::HEAP))
+        {
+            let data = target.read_bytes(page.base, page.size);
+            let mut offset = 0;
+            while offset + 2 * ps <= data.len() {
+                let size = word(&data, offset + ps, ps) & !7;
+                let next = offset + size;
+                // the top chunk extends to the end
+                if size < 2 * ps || next + 2 * ps > data.len() {
+                    break;
+                }
+                if word(&data, next + ps, ps) & PREV_INUSE != 0 {
+                    result.push(HeapBlock {
+                        address: page.base + offset + 2 * ps,
+                        size: size - ps,
+                        stack: None,
+                    });
+                }
+                offset = next;
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android")))]
+mod sys {
+    use super::*;
+
+    pub fn enum_blocks<T: UDbgTarget + ?Sized>(_: &T) -> UDbgResult<Vec<HeapBlock>> {
+        Err(UDbgError::NotSupport)
+    }
+}
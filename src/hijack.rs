@@ -0,0 +1,236 @@
+//!
+//! Detection of APC injection and thread hijacking in target.
+//!
+//! [`HijackMonitor`] sets breakpoints on the APIs which queue APCs or modify thread contexts,
+//! these catch the calls made inside target, e.g. by the injected code. The calls from other
+//! processes can't be intercepted by the debugger, so the monitor also checks the pc of all
+//! threads periodically, a thread running outside any module or in writable code is hijacked.
+//!
+
+use crate::{
+    poll::{PollHandle, PollOptions, PollScheduler},
+    prelude::*,
+    provenance::{address_signs, InjectionSign},
+    target::call_args,
+};
+
+use core::time::Duration;
+use spin::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Api {
+    QueueApc,
+    QueueApcEx,
+    SetContext,
+}
+
+cfg_if! {
+    if #[cfg(windows)] {
+        const WATCHED_APIS: &[(&str, Api)] = &[
+            ("ntdll!NtQueueApcThread", Api::QueueApc),
+            ("ntdll!NtQueueApcThreadEx", Api::QueueApcEx),
+            ("ntdll!NtSetContextThread", Api::SetContext),
+        ];
+    } else {
+        const WATCHED_APIS: &[(&str, Api)] = &[];
+    }
+}
+
+/// The pseudo handle of current thread, `GetCurrentThread()`
+const CURRENT_THREAD: usize = usize::MAX - 1;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum AlertKind {
+    /// An APC is queued to a thread, by its handle in target
+    QueueApc { thread_handle: usize },
+    /// The context of a thread is set, by its handle in target
+    SetContext { thread_handle: usize },
+    /// A thread is found running at a suspicious address
+    SuspiciousPc,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HijackAlert {
+    pub time: SystemTime,
+    pub kind: AlertKind,
+    /// The thread calling the API, or the hijacked thread for [`AlertKind::SuspiciousPc`]
+    pub tid: tid_t,
+    /// The APC routine, the new pc of context, or the current pc
+    pub address: usize,
+    pub symbol: Option<String>,
+    pub signs: Vec<InjectionSign>,
+}
+
+impl HijackAlert {
+    fn new<T: UDbgTarget + ?Sized>(
+        target: &T,
+        kind: AlertKind,
+        tid: tid_t,
+        address: usize,
+    ) -> Self {
+        let sym = target.get_symbol_(address, None);
+        Self {
+            time: SystemTime::now(),
+            kind,
+            tid,
+            address,
+            symbol: sym.as_ref().map(|s| s.to_string(address)),
+            signs: address_signs(target, address, sym.as_ref()),
+        }
+    }
+
+    /// If the address is suspicious, or the API targets another thread
+    pub fn is_suspicious(&self) -> bool {
+        !self.signs.is_empty()
+            || match self.kind {
+                AlertKind::SetContext { thread_handle } => thread_handle != CURRENT_THREAD,
+                _ => false,
+            }
+    }
+}
+
+/// Offset of the pc in the `CONTEXT` structure
+fn context_pc_offset(arch: u32) -> usize {
+    match arch {
+        ARCH_X64 => 0xF8,
+        ARCH_ARM64 => 0x108,
+        _ => 0xB8,
+    }
+}
+
+/// Watch the APC and context APIs and the pc of threads of a target
+#[derive(Default)]
+pub struct HijackMonitor {
+    apis: Mutex<HashMap<BpID, Api>>,
+    alerts: Arc<Mutex<Vec<HijackAlert>>>,
+    pc_check: Mutex<Option<PollHandle>>,
+}
+
+impl HijackMonitor {
+    /// Set breakpoints on the watched APIs, returns the count of them set
+    pub fn watch_apis<T: UDbgTarget + ?Sized>(&self, target: &T) -> UDbgResult<usize> {
+        if WATCHED_APIS.is_empty() {
+            return Err(UDbgError::NotSupport);
+        }
+        let mut apis = self.apis.lock();
+        for &(name, api) in WATCHED_APIS {
+            // NtQueueApcThreadEx doesn't exist before Windows 7
+            let address = match target.get_address_by_symbol(name) {
+                Some(a) => a,
+                None => continue,
+            };
+            if let Some(bp) = target
+                .add_breakpoint(address.into())
+                .log_error_with(|err| format!("bp at {name}: {err:?}"))
+            {
+                apis.insert(bp.get_id(), api);
+            }
+        }
+        Ok(apis.len())
+    }
+
+    /// Check the event from the debug loop, returns the alert if it's a call of watched APIs
+    pub fn on_event(&self, ctx: &mut dyn TraceContext, event: &UEvent) -> Option<HijackAlert> {
+        let api = match event {
+            UEvent::Breakpoint(bp) => *self.apis.lock().get(&bp.get_id())?,
+            _ => return None,
+        };
+        let target = ctx.target();
+        let arch = ctx.arch();
+        let regs = &*ctx.register()?;
+        let arg = call_args(target.as_ref(), regs, arch);
+        let thread_handle = arg(1)?;
+        let (kind, address) = match api {
+            // NtQueueApcThread(ThreadHandle, ApcRoutine, ...)
+            Api::QueueApc => (AlertKind::QueueApc { thread_handle }, arg(2)?),
+            // NtQueueApcThreadEx(ThreadHandle, ReserveHandle, ApcRoutine, ...)
+            Api::QueueApcEx => (AlertKind::QueueApc { thread_handle }, arg(3)?),
+            // NtSetContextThread(ThreadHandle, Context)
+            Api::SetContext => {
+                let context = arg(2)?;
+                let pc = match arch {
+                    ARCH_X86 | ARCH_ARM => target
+                        .read_value::<u32>(context + context_pc_offset(arch))
+                        .map(|p| p as usize),
+                    _ => target.read_copy::<usize>(context + context_pc_offset(arch)),
+                };
+                (AlertKind::SetContext { thread_handle }, pc?)
+            }
+        };
+        let alert = HijackAlert::new(
+            target.as_ref(),
+            kind,
+            target.base().event_tid.get(),
+            address,
+        );
+        push_alert(&self.alerts, alert.clone());
+        Some(alert)
+    }
+
+    /// Check the pc of all threads every `interval`, each suspicious pc is alerted once
+    pub fn start_pc_check(&self, target: Arc<dyn UDbgTarget>, interval: Duration) {
+        let alerts = self.alerts.clone();
+        let mut alerted = HashSet::new();
+        let handle = PollScheduler::global().add(
+            &format!("hijack-pc-check-{}", target.pid()),
+            PollOptions::interval(interval),
+            move || {
+                if target.base().status.get() == UDbgStatus::Detached {
+                    return false;
+                }
+                let threads = match target.enum_thread(false) {
+                    Ok(t) => t,
+                    Err(_) => return false,
+                };
+                for t in threads {
+                    let pc = match t.reg_value("_pc") {
+                        Ok(pc) => pc,
+                        Err(_) => continue,
+                    };
+                    if alerted.contains(&(t.tid, pc)) {
+                        continue;
+                    }
+                    let alert =
+                        HijackAlert::new(target.as_ref(), AlertKind::SuspiciousPc, t.tid, pc);
+                    if alert.is_suspicious() {
+                        alerted.insert((t.tid, pc));
+                        push_alert(&alerts, alert);
+                    }
+                }
+                true
+            },
+        );
+        *self.pc_check.lock() = Some(handle);
+    }
+
+    pub fn stop_pc_check(&self) {
+        self.pc_check.lock().take();
+    }
+
+    /// All the alerts, from the oldest
+    pub fn alerts(&self) -> Vec<HijackAlert> {
+        self.alerts.lock().clone()
+    }
+
+    /// Take the alerts since last call
+    pub fn take_alerts(&self) -> Vec<HijackAlert> {
+        core::mem::take(&mut *self.alerts.lock())
+    }
+}
+
+fn push_alert(alerts: &Mutex<Vec<HijackAlert>>, alert: HijackAlert) {
+    if alert.is_suspicious() {
+        warn!(
+            "~{} {:?} at {:x} {}: {:?}",
+            alert.tid,
+            alert.kind,
+            alert.address,
+            alert.symbol.as_deref().unwrap_or_default(),
+            alert.signs
+        );
+    }
+    alerts.lock().push(alert);
+}
@@ -0,0 +1,387 @@
+//!
+//! Inline hooks of the functions in target, handled by the callbacks in debugger.
+//!
+//! [`InlineHooks::install`] copies the leading instructions of function to a trampoline in target,
+//! relocated by the block encoder of iced and followed by a jump back, then patches the entry with
+//! a jump to the trampoline. A breakpoint at the trampoline routes each call to the callback, which
+//! could inspect and modify the arguments, return to the caller directly, or wait for the return
+//! to modify the return value. The hits are handled by the engine and never reach the user
+//! callback.
+//!
+//! The hooks are removed when the target is detached: the entries are restored, the trampolines
+//! are left allocated for the threads running in them. Only x86 and x86_64 are supported.
+//!
+
+use crate::{
+    breakpoint::{return_address, ReturnWaiters},
+    disasm::read_code,
+    inject::alloc_code,
+    ipc::return_reg,
+    prelude::*,
+    register::regid,
+    target::call_args,
+};
+
+use core::ops::Range;
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Decoder, DecoderOptions, FlowControl, InstructionBlock,
+};
+use spin::Mutex;
+use std::sync::Arc;
+
+/// Size of the memory allocated for a trampoline
+const TRAMPOLINE_SIZE: usize = 0x80;
+
+pub type HookId = usize;
+
+pub type HookCallback = Box<dyn FnMut(&mut HookCall<'_>) -> HookAction + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum HookPhase {
+    /// At the entry of function, the arguments are valid
+    Entry,
+    /// At the return address, the return value is valid
+    Return,
+}
+
+/// What to do after the callback at the entry, ignored at the return
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    /// Run the original function
+    Continue,
+    /// Run the original function, and call back again at its return
+    WaitReturn,
+    /// Return to the caller with the value without running the function, `stack_args` is the
+    /// count of the arguments popped by the callee, such as the x86 stdcall functions
+    Return { value: usize, stack_args: usize },
+}
+
+/// A call of hooked function, passed to the callback
+pub struct HookCall<'a> {
+    pub id: HookId,
+    pub tid: tid_t,
+    pub phase: HookPhase,
+    /// Address of the hooked function
+    pub address: usize,
+    ctx: &'a mut dyn TraceContext,
+    target: Arc<dyn UDbgTarget>,
+}
+
+impl HookCall<'_> {
+    #[inline]
+    pub fn target(&self) -> &dyn UDbgTarget {
+        self.target.as_ref()
+    }
+
+    /// Registers of the calling thread, modified ones are written back when continued
+    #[inline]
+    pub fn regs(&mut self) -> Option<&mut dyn UDbgRegs> {
+        self.ctx.register()
+    }
+
+    fn convention(&self) -> Option<CallingConv> {
+        (self.ctx.arch() == ARCH_X86).then(|| CallingConv::StdCall)
+    }
+
+    /// The `i`th argument from 1, at the entry
+    pub fn arg(&mut self, i: usize) -> Option<usize> {
+        let arch = self.ctx.arch();
+        let target = self.target.clone();
+        let regs = self.ctx.register()?;
+        call_args(target.as_ref(), regs, arch)(i)
+    }
+
+    /// Modify the `i`th argument from 1, at the entry
+    pub fn set_arg(&mut self, i: usize, value: usize) -> UDbgResult<()> {
+        let cc = self.convention();
+        let ps = self.ctx.pointer_size();
+        let regs = self.ctx.register().ok_or(UDbgError::NotSupport)?;
+        match regs.argument(i, cc) {
+            Ok(id) => regs.set_reg(id, CpuReg::Int(value)),
+            Err(n) => {
+                let sp = regs
+                    .get_reg(regid::COMM_REG_SP)
+                    .ok_or(UDbgError::NotSupport)?
+                    .as_int();
+                let bytes = value.to_le_bytes();
+                if self.target.write_memory(sp + n * ps, &bytes[..ps]) != Some(ps) {
+                    return Err(UDbgError::MemoryError);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The return value, at the return
+    pub fn ret(&mut self) -> Option<usize> {
+        let reg = return_reg(self.ctx.arch());
+        Some(self.ctx.register()?.get_reg(reg)?.as_int())
+    }
+
+    /// Modify the return value, at the return
+    pub fn set_ret(&mut self, value: usize) -> UDbgResult<()> {
+        let reg = return_reg(self.ctx.arch());
+        self.ctx
+            .register()
+            .ok_or(UDbgError::NotSupport)?
+            .set_reg(reg, CpuReg::Int(value));
+        Ok(())
+    }
+
+    /// Return to the caller at the entry, as if the function returned
+    fn return_now(&mut self, value: usize, stack_args: usize) -> UDbgResult<()> {
+        let ps = self.ctx.pointer_size();
+        let regs = self.ctx.register().ok_or(UDbgError::NotSupport)?;
+        let sp = regs
+            .get_reg(regid::COMM_REG_SP)
+            .ok_or(UDbgError::NotSupport)?
+            .as_int();
+        let ret = self.target.read_ptr(sp).ok_or(UDbgError::MemoryError)?;
+        regs.set_reg(regid::COMM_REG_PC, CpuReg::Int(ret));
+        regs.set_reg(regid::COMM_REG_SP, CpuReg::Int(sp + (1 + stack_args) * ps));
+        self.set_ret(value)
+    }
+}
+
+/// An installed hook
+pub struct InlineHook {
+    pub id: HookId,
+    pub address: usize,
+    /// The relocated leading instructions and the jump back, calling it runs the original function
+    pub trampoline: Range<usize>,
+    pub hits: usize,
+    /// The bytes of entry overwritten by the jump
+    original: Vec<u8>,
+    bp: Arc<dyn UDbgBreakpoint>,
+    callback: Arc<Mutex<HookCallback>>,
+}
+
+/// The inline hooks of a target
+#[derive(Default)]
+pub struct InlineHooks {
+    hooks: Mutex<Vec<InlineHook>>,
+    /// The calls waiting for their returns, by the hook
+    returns: Mutex<ReturnWaiters<HookId>>,
+    next_id: Mutex<HookId>,
+}
+
+/// An absolute jump from `from` to `to`, `jmp [rip]` with the address following on x86_64
+fn jump(bitness: u32, from: usize, to: usize) -> Vec<u8> {
+    if bitness == 64 {
+        let mut code = vec![0xFF, 0x25, 0, 0, 0, 0];
+        code.extend_from_slice(&(to as u64).to_le_bytes());
+        code
+    } else {
+        let rel = (to as u32).wrapping_sub(from as u32 + 5);
+        let mut code = vec![0xE9];
+        code.extend_from_slice(&rel.to_le_bytes());
+        code
+    }
+}
+
+impl InlineHooks {
+    /// Information of the installed hooks, `(id, address, trampoline, hits)`
+    pub fn list(&self) -> Vec<(HookId, usize, Range<usize>, usize)> {
+        self.hooks
+            .lock()
+            .iter()
+            .map(|h| (h.id, h.address, h.trampoline.clone(), h.hits))
+            .collect()
+    }
+
+    /// Hook the function at `address`, the target should be stopped in a debug event
+    pub fn install<T: UDbgTarget + ?Sized>(
+        &self,
+        target: &T,
+        address: usize,
+        callback: impl FnMut(&mut HookCall<'_>) -> HookAction + Send + 'static,
+    ) -> UDbgResult<HookId> {
+        if !cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+            return Err(UDbgError::NotSupport);
+        }
+        if self.hooks.lock().iter().any(|h| h.address == address) {
+            return Err(UDbgError::BpExists);
+        }
+        let bitness = if target.base().is_ptr32() { 32 } else { 64 };
+        let patch_len = jump(bitness, 0, 0).len();
+
+        // the instructions overwritten by the jump, a breakpoint at the entry is not copied
+        let code = read_code(target, address, patch_len + 15);
+        let mut decoder = Decoder::with_ip(bitness, &code, address as u64, DecoderOptions::NONE);
+        let mut stolen = vec![];
+        let mut len = 0;
+        while len < patch_len {
+            let insn = decoder.decode();
+            if insn.is_invalid() {
+                return Err(format!("invalid instruction at {:x}", insn.ip()).into());
+            }
+            len += insn.len();
+            let ends = matches!(
+                insn.flow_control(),
+                FlowControl::Return
+                    | FlowControl::UnconditionalBranch
+                    | FlowControl::IndirectBranch
+                    | FlowControl::Interrupt
+                    | FlowControl::Exception
+            );
+            stolen.push(insn);
+            if ends && len < patch_len {
+                return Err(format!("function at {address:x} is too short to hook").into());
+            }
+        }
+
+        let trampoline = alloc_code(target, TRAMPOLINE_SIZE)?;
+        let result = (|| {
+            let block = InstructionBlock::new(&stolen, trampoline.start as u64);
+            let mut relocated = BlockEncoder::encode(bitness, block, BlockEncoderOptions::NONE)
+                .map_err(|err| format!("relocate: {err}"))?
+                .code_buffer;
+            let back = trampoline.start + relocated.len();
+            relocated.extend(jump(bitness, back, address + len));
+            if relocated.len() > TRAMPOLINE_SIZE {
+                return Err(UDbgError::from("trampoline is too large"));
+            }
+            if target.write_code(trampoline.start, &relocated) != Some(relocated.len()) {
+                return Err(UDbgError::MemoryError);
+            }
+            let bp = target.add_bp(trampoline.start)?;
+            let patch = jump(bitness, address, trampoline.start);
+            if let Err(err) = target.safe_patch(address, &patch, false) {
+                bp.remove().log_error("remove hook bp");
+                return Err(err);
+            }
+            Ok(bp)
+        })();
+        let bp = match result {
+            Ok(bp) => bp,
+            Err(err) => {
+                crate::inject::free_code(target, trampoline).log_error("free trampoline");
+                return Err(err);
+            }
+        };
+
+        let id = {
+            let mut next_id = self.next_id.lock();
+            *next_id += 1;
+            *next_id
+        };
+        self.hooks.lock().push(InlineHook {
+            id,
+            address,
+            trampoline,
+            hits: 0,
+            original: code[..patch_len].to_vec(),
+            bp,
+            callback: Arc::new(Mutex::new(Box::new(callback))),
+        });
+        Ok(id)
+    }
+
+    fn restore<T: UDbgTarget + ?Sized>(target: &T, hook: &InlineHook) -> UDbgResult<()> {
+        // a thread can't be in the middle of the jump, so it's safe to write back at any time
+        if target.write_code(hook.address, &hook.original) != Some(hook.original.len()) {
+            return Err(UDbgError::MemoryError);
+        }
+        hook.bp.remove()
+    }
+
+    /// Remove a hook, its trampoline is left allocated for the threads running in it
+    pub fn remove<T: UDbgTarget + ?Sized>(&self, target: &T, id: HookId) -> UDbgResult<()> {
+        let hook = {
+            let mut hooks = self.hooks.lock();
+            let i = hooks
+                .iter()
+                .position(|h| h.id == id)
+                .ok_or(UDbgError::NotFound)?;
+            hooks.remove(i)
+        };
+        Self::restore(target, &hook)?;
+        self.returns.lock().retain(|_, &w| w != id);
+        Ok(())
+    }
+
+    /// Remove all the hooks, called by the engine when detaching
+    pub fn remove_all<T: UDbgTarget + ?Sized>(&self, target: &T) {
+        for hook in core::mem::take(&mut *self.hooks.lock()) {
+            Self::restore(target, &hook)
+                .log_error_with(|err| format!("unhook {:x}: {err:?}", hook.address));
+        }
+        self.returns.lock().clear();
+    }
+
+    /// Called by the engine before the user callback, returns the reply if the event is a hit of
+    /// the hooks
+    pub(crate) fn on_event(
+        &self,
+        ctx: &mut dyn TraceContext,
+        event: &UEvent,
+    ) -> Option<(UserReply, Option<UEvent>)> {
+        let address = match event {
+            UEvent::Breakpoint(bp) => bp.address(),
+            _ => return None,
+        };
+        let target = ctx.target();
+        let tid = target.base().event_tid.get();
+
+        let entry = self
+            .hooks
+            .lock()
+            .iter_mut()
+            .find(|h| h.trampoline.start == address)
+            .map(|h| {
+                h.hits += 1;
+                (h.id, h.address, h.callback.clone())
+            });
+        if let Some((id, function, callback)) = entry {
+            let mut call = HookCall {
+                id,
+                tid,
+                phase: HookPhase::Entry,
+                address: function,
+                ctx,
+                target: target.clone(),
+            };
+            let action = (callback.lock())(&mut call);
+            let result = match action {
+                HookAction::Continue => Ok(()),
+                HookAction::WaitReturn => {
+                    let arch = call.ctx.arch();
+                    call.regs()
+                        .and_then(|r| return_address(target.as_ref(), r, arch))
+                        .ok_or(UDbgError::MemoryError)
+                        .and_then(|ret| self.returns.lock().wait(target.as_ref(), ret, tid, id))
+                }
+                HookAction::Return { value, stack_args } => call.return_now(value, stack_args),
+            };
+            result.log_error_with(|err| format!("hook {function:x}: {err:?}"));
+            return Some((UserReply::Run(false), None));
+        }
+
+        let id = {
+            let mut returns = self.returns.lock();
+            if !returns.contains(address) {
+                return None;
+            }
+            returns.take(address, tid)
+        };
+        let hook = id.and_then(|id| {
+            self.hooks
+                .lock()
+                .iter()
+                .find(|h| h.id == id)
+                .map(|h| (h.address, h.callback.clone()))
+        });
+        if let Some((function, callback)) = hook {
+            let mut call = HookCall {
+                id: id.unwrap_or_default(),
+                tid,
+                phase: HookPhase::Return,
+                address: function,
+                ctx,
+                target,
+            };
+            (callback.lock())(&mut call);
+        }
+        Some((UserReply::Run(false), None))
+    }
+}
@@ -0,0 +1,291 @@
+//!
+//! Import table reconstruction from the runtime IAT, to fix the dumped PE modules like Scylla does.
+//!
+//! The IAT is located by the data directory or by scanning the sections for the pointer arrays
+//! which point to the exports of other modules, each slot is resolved to `module!export`,
+//! then a new import directory is built for the resolved slots, see [`crate::dump::dump_module`].
+//! The exports by ordinal only and the forwarded exports are not resolved.
+//!
+
+use crate::{prelude::*, unpack::read_sections};
+
+use core::ops::Range;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const IMAGE_DIRECTORY_ENTRY_IAT: usize = 12;
+/// Resolved slots needed to accept a scanned pointer array as IAT
+const MIN_IAT_SLOTS: usize = 4;
+
+/// A slot of IAT
+#[derive(Debug, Clone, Serialize)]
+pub struct IatSlot {
+    pub address: usize,
+    /// The runtime value of slot
+    pub value: usize,
+    /// `(module, export)` the value points to
+    pub import: Option<(Arc<str>, Arc<str>)>,
+}
+
+/// Consecutive slots importing from the same module, becomes an import descriptor
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportModule {
+    pub name: Arc<str>,
+    /// Address of the first slot
+    pub first_thunk: usize,
+    pub functions: Vec<Arc<str>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportTable {
+    pub iat: Range<usize>,
+    pub slots: Vec<IatSlot>,
+    pub modules: Vec<ImportModule>,
+}
+
+/// The import directory built by [`ImportTable::build`]
+pub struct ImportSection {
+    pub data: Vec<u8>,
+    /// RVA and size of the import descriptors
+    pub directory: (u32, u32),
+    /// RVA and value to write into each resolved IAT slot, so the dumped file is valid on disk
+    pub thunks: Vec<(usize, u64)>,
+}
+
+/// Resolve the value of IAT slots to the exports of modules other than `module`
+struct Resolver<'a, T: ?Sized> {
+    target: &'a T,
+    module: Range<usize>,
+    cache: HashMap<usize, Option<(Arc<str>, Arc<str>)>>,
+}
+
+impl<'a, T: UDbgTarget + ?Sized> Resolver<'a, T> {
+    fn resolve(&mut self, value: usize) -> Option<(Arc<str>, Arc<str>)> {
+        if value == 0 || self.module.contains(&value) {
+            return None;
+        }
+        let target = self.target;
+        self.cache
+            .entry(value)
+            .or_insert_with(|| {
+                let m = target.find_module(value)?;
+                let data = m.data();
+                let sym = m
+                    .symbols_data()?
+                    .exports
+                    .find_symbol(value - data.base, 0)?;
+                Some((data.name.clone(), sym.name))
+            })
+            .clone()
+    }
+}
+
+/// Range of the IAT in data directory
+pub(crate) fn directory_iat<T: ReadMemory + ?Sized>(
+    target: &T,
+    base: usize,
+) -> Option<Range<usize>> {
+    if target.read_value::<u16>(base)? != 0x5A4D {
+        return None;
+    }
+    let optional = base + target.read_value::<u32>(base + 0x3C)? as usize + 24;
+    let dirs = match target.read_value::<u16>(optional)? {
+        // PE32+
+        0x20B => optional + 112,
+        _ => optional + 96,
+    };
+    let dir = dirs + IMAGE_DIRECTORY_ENTRY_IAT * 8;
+    let rva = target.read_value::<u32>(dir)? as usize;
+    let size = target.read_value::<u32>(dir + 4)? as usize;
+    (rva > 0 && size > 0).then(|| base + rva..base + rva + size)
+}
+
+impl ImportTable {
+    /// Locate the IAT of module, the candidate with the most resolved slots wins
+    pub fn find_iat<T: UDbgTarget + ?Sized>(
+        target: &T,
+        module: &dyn UDbgModule,
+    ) -> Option<Range<usize>> {
+        let data = module.data();
+        let ps = target.base().pointer_size();
+        let mut resolver = Resolver {
+            target,
+            module: data.base..data.base + data.size,
+            cache: Default::default(),
+        };
+        let mut best: Option<(Range<usize>, usize)> = None;
+        let mut update = |range: Range<usize>, count: usize| {
+            if count >= MIN_IAT_SLOTS && best.as_ref().map_or(true, |(_, c)| count > *c) {
+                best = Some((range, count));
+            }
+        };
+
+        if let Some(iat) = directory_iat(target, data.base) {
+            let count = target
+                .read_bytes(iat.start, iat.end - iat.start)
+                .chunks_exact(ps)
+                .filter(|s| resolver.resolve(read_ptr(s)).is_some())
+                .count();
+            update(iat, count);
+        }
+
+        for s in read_sections(target, data.base).unwrap_or_default() {
+            let bytes = target.read_bytes(s.address, s.virtual_size.max(s.raw_size));
+            let (mut start, mut count, mut zeros) = (None, 0, 0);
+            for (i, slot) in bytes.chunks_exact(ps).enumerate() {
+                let address = s.address + i * ps;
+                let value = read_ptr(slot);
+                if resolver.resolve(value).is_some() {
+                    start.get_or_insert(address);
+                    count += 1;
+                    zeros = 0;
+                } else if value == 0 && start.is_some() && zeros == 0 {
+                    // the terminator of a descriptor
+                    zeros += 1;
+                } else {
+                    if let Some(start) = start.take() {
+                        update(start..address - zeros * ps, count);
+                    }
+                    count = 0;
+                    zeros = 0;
+                }
+            }
+            if let Some(start) = start {
+                update(start..s.address + bytes.len() / ps * ps - zeros * ps, count);
+            }
+        }
+        best.map(|(r, _)| r)
+    }
+
+    /// Resolve the slots of IAT, and group them into import modules,
+    /// the IAT is located by [`Self::find_iat`] if not specified
+    pub fn scan<T: UDbgTarget + ?Sized>(
+        target: &T,
+        module: &dyn UDbgModule,
+        iat: Option<Range<usize>>,
+    ) -> UDbgResult<Self> {
+        let iat = iat
+            .or_else(|| Self::find_iat(target, module))
+            .ok_or(UDbgError::NotFound)?;
+        let data = module.data();
+        let ps = target.base().pointer_size();
+        let mut resolver = Resolver {
+            target,
+            module: data.base..data.base + data.size,
+            cache: Default::default(),
+        };
+
+        let bytes = target.read_bytes(iat.start, iat.end - iat.start);
+        let slots = bytes
+            .chunks_exact(ps)
+            .enumerate()
+            .map(|(i, s)| {
+                let value = read_ptr(s);
+                IatSlot {
+                    address: iat.start + i * ps,
+                    value,
+                    import: resolver.resolve(value),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut modules = Vec::<ImportModule>::new();
+        let mut prev_resolved = false;
+        for s in slots.iter() {
+            let (name, func) = match s.import.as_ref() {
+                Some(i) => i,
+                None => {
+                    prev_resolved = false;
+                    if s.value != 0 {
+                        warn!("unresolved IAT slot {:x}: {:x}", s.address, s.value);
+                    }
+                    continue;
+                }
+            };
+            match modules.last_mut() {
+                Some(m) if prev_resolved && m.name == *name => m.functions.push(func.clone()),
+                _ => modules.push(ImportModule {
+                    name: name.clone(),
+                    first_thunk: s.address,
+                    functions: vec![func.clone()],
+                }),
+            }
+            prev_resolved = true;
+        }
+        Ok(Self {
+            iat,
+            slots,
+            modules,
+        })
+    }
+
+    /// Count of the slots which can't be resolved
+    pub fn unresolved(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|s| s.value != 0 && s.import.is_none())
+            .count()
+    }
+
+    /// Build the import descriptors, name thunks and names placed at `rva` of the module at `base`
+    pub fn build(&self, base: usize, rva: usize, pointer_size: usize) -> ImportSection {
+        const DESCRIPTOR_SIZE: usize = 20;
+
+        let descriptors = (self.modules.len() + 1) * DESCRIPTOR_SIZE;
+        let thunks_size = self
+            .modules
+            .iter()
+            .map(|m| (m.functions.len() + 1) * pointer_size)
+            .sum::<usize>();
+        let strings_rva = rva + descriptors + thunks_size;
+
+        let mut data = vec![0u8; descriptors + thunks_size];
+        let mut strings = vec![];
+        let mut thunks = vec![];
+        let mut thunk_offset = descriptors;
+        for (i, m) in self.modules.iter().enumerate() {
+            let original_first_thunk = rva + thunk_offset;
+            for (j, func) in m.functions.iter().enumerate() {
+                // IMAGE_IMPORT_BY_NAME: hint and name, aligned to 2
+                let name_rva = (strings_rva + strings.len()) as u64;
+                strings.extend_from_slice(&[0, 0]);
+                strings.extend_from_slice(func.as_bytes());
+                strings.push(0);
+                if strings.len() % 2 > 0 {
+                    strings.push(0);
+                }
+                data[thunk_offset..thunk_offset + pointer_size]
+                    .copy_from_slice(&name_rva.to_le_bytes()[..pointer_size]);
+                thunks.push((m.first_thunk - base + j * pointer_size, name_rva));
+                thunk_offset += pointer_size;
+            }
+            thunk_offset += pointer_size;
+
+            let dll_rva = (strings_rva + strings.len()) as u32;
+            strings.extend_from_slice(m.name.as_bytes());
+            strings.push(0);
+            if strings.len() % 2 > 0 {
+                strings.push(0);
+            }
+
+            let d = &mut data[i * DESCRIPTOR_SIZE..(i + 1) * DESCRIPTOR_SIZE];
+            d[0..4].copy_from_slice(&(original_first_thunk as u32).to_le_bytes());
+            d[12..16].copy_from_slice(&dll_rva.to_le_bytes());
+            d[16..20].copy_from_slice(&((m.first_thunk - base) as u32).to_le_bytes());
+        }
+        data.extend_from_slice(&strings);
+
+        ImportSection {
+            data,
+            directory: (rva as u32, descriptors as u32),
+            thunks,
+        }
+    }
+}
+
+fn read_ptr(slot: &[u8]) -> usize {
+    match slot.len() {
+        4 => u32::from_le_bytes(slot.try_into().unwrap()) as usize,
+        _ => u64::from_le_bytes(slot.try_into().unwrap()) as usize,
+    }
+}
@@ -0,0 +1,536 @@
+//!
+//! Export and import tables of the images loaded in target, parsed from the memory directly, so the
+//! API addresses could be resolved without the files on disk, and the runtime changes are seen.
+//!
+//! PE: the export directory with the forwarders, and the import descriptors, the delay imports are
+//! not included. ELF: the defined symbols of dynsym as exports, and the relocations against the
+//! undefined symbols in `DT_JMPREL` and `DT_RELA`/`DT_REL` as imports.
+//!
+
+use crate::prelude::*;
+
+use core::ops::Range;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const MAX_EXPORTS: usize = 0x10000;
+const MAX_DESCRIPTORS: usize = 0x1000;
+const MAX_THUNKS: usize = 0x10000;
+const MAX_DYNAMIC: usize = 0x400;
+const MAX_SYMBOLS: usize = 0x100000;
+const MAX_NAME: usize = 0x400;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+
+const DT_NULL: usize = 0;
+const DT_NEEDED: usize = 1;
+const DT_PLTRELSZ: usize = 2;
+const DT_HASH: usize = 4;
+const DT_STRTAB: usize = 5;
+const DT_SYMTAB: usize = 6;
+const DT_RELA: usize = 7;
+const DT_RELASZ: usize = 8;
+const DT_REL: usize = 17;
+const DT_RELSZ: usize = 18;
+const DT_PLTREL: usize = 20;
+const DT_JMPREL: usize = 23;
+const DT_GNU_HASH: usize = 0x6FFF_FEF5;
+
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const STB_GNU_UNIQUE: u8 = 10;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const STT_TLS: u8 = 6;
+
+/// An exported function or variable
+#[derive(Debug, Clone, Serialize)]
+pub struct Export {
+    /// None if it's exported by ordinal only
+    pub name: Option<String>,
+    /// Ordinal of PE, or index in the dynsym of ELF
+    pub ordinal: u32,
+    pub address: usize,
+    /// Address of the entry in export address table, PE only
+    pub entry: Option<usize>,
+    /// The `module.function` it's forwarded to, PE only
+    pub forward: Option<String>,
+}
+
+/// An imported function or variable
+#[derive(Debug, Clone, Serialize)]
+pub struct Import {
+    /// The library of PE import descriptor; for ELF whose symbols aren't bound to a library,
+    /// the module which the slot points to
+    pub module: Option<Arc<str>>,
+    pub name: Option<String>,
+    /// Imported by ordinal, PE only
+    pub ordinal: Option<u32>,
+    /// Address of the IAT slot or GOT entry
+    pub slot: usize,
+    /// The runtime value of slot
+    pub value: usize,
+}
+
+fn u16_at(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes(b[i..i + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(b[i..i + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], i: usize) -> u64 {
+    u64::from_le_bytes(b[i..i + 8].try_into().unwrap())
+}
+
+fn read_u32s<T: ReadMemory + ?Sized>(target: &T, address: usize, count: usize) -> Vec<u32> {
+    target
+        .read_bytes(address, count * 4)
+        .chunks_exact(4)
+        .map(|c| u32_at(c, 0))
+        .collect()
+}
+
+fn read_ptr<T: ReadMemory + ?Sized>(target: &T, address: usize, is_64: bool) -> Option<u64> {
+    if is_64 {
+        target.read_value::<u64>(address)
+    } else {
+        target.read_value::<u32>(address).map(|p| p as u64)
+    }
+}
+
+/// The data directories of PE, in RVA
+struct PeTables {
+    pe32plus: bool,
+    exports: Range<usize>,
+    imports: usize,
+}
+
+impl PeTables {
+    fn parse<T: ReadMemory + ?Sized>(target: &T, base: usize) -> UDbgResult<Self> {
+        let nt = target
+            .read_value::<u32>(base + 0x3C)
+            .ok_or(UDbgError::MemoryError)?;
+        let nt = base + nt as usize;
+        if target.read_value::<u32>(nt) != Some(0x4550) {
+            return Err("invalid PE header".into());
+        }
+        let optional = nt + 24;
+        let pe32plus = target.read_value::<u16>(optional) == Some(0x20B);
+        let dirs = optional + if pe32plus { 112 } else { 96 };
+        let dir = |i: usize| match &read_u32s(target, dirs + i * 8, 2)[..] {
+            &[rva, size] => (rva as usize, size as usize),
+            _ => (0, 0),
+        };
+        let (rva, size) = dir(0);
+        Ok(Self {
+            pe32plus,
+            exports: rva..rva + size,
+            imports: dir(1).0,
+        })
+    }
+}
+
+/// A relocation table of ELF
+struct ElfRelocs {
+    address: usize,
+    size: usize,
+    rela: bool,
+}
+
+struct ElfSymbol {
+    name: u32,
+    info: u8,
+    shndx: u16,
+    value: u64,
+}
+
+/// The dynamic tables of ELF, in runtime address
+struct ElfTables {
+    is_64: bool,
+    /// Difference between the runtime address and the virtual address in file
+    bias: usize,
+    symtab: usize,
+    strtab: usize,
+    symbols: usize,
+    relocs: Vec<ElfRelocs>,
+    /// The names of `DT_NEEDED` in strtab
+    needed: Vec<usize>,
+}
+
+impl ElfTables {
+    fn parse<T: ReadMemory + ?Sized>(target: &T, base: usize) -> UDbgResult<Self> {
+        let is_64 = target.read_value::<u8>(base + 4) == Some(2);
+        let header = target.read_bytes(base, if is_64 { 0x40 } else { 0x34 });
+        if header.len() < 0x34 {
+            return Err(UDbgError::MemoryError);
+        }
+        let (phoff, phentsize, phnum) = if is_64 {
+            let phoff = u64_at(&header, 0x20) as usize;
+            (phoff, u16_at(&header, 0x36), u16_at(&header, 0x38))
+        } else {
+            let phoff = u32_at(&header, 0x1C) as usize;
+            (phoff, u16_at(&header, 0x2A), u16_at(&header, 0x2C))
+        };
+
+        let (mut first_load, mut dynamic) = (None, None);
+        for i in 0..phnum.min(64) as usize {
+            let ph = base + phoff + i * phentsize as usize;
+            let vaddr = if is_64 {
+                read_ptr(target, ph + 16, true)
+            } else {
+                read_ptr(target, ph + 8, false)
+            };
+            match (target.read_value::<u32>(ph), vaddr) {
+                (Some(PT_LOAD), Some(vaddr)) => {
+                    first_load.get_or_insert(vaddr as usize);
+                }
+                (Some(PT_DYNAMIC), Some(vaddr)) => dynamic = Some(vaddr as usize),
+                _ => {}
+            }
+        }
+        let bias = base.wrapping_sub(first_load.unwrap_or_default() & !0xFFF);
+        let dynamic = bias.wrapping_add(dynamic.ok_or(UDbgError::NotFound)?);
+
+        let (mut dt, mut needed) = (HashMap::new(), vec![]);
+        let entsize = if is_64 { 16 } else { 8 };
+        for i in 0..MAX_DYNAMIC {
+            let entry = dynamic + i * entsize;
+            let tag = read_ptr(target, entry, is_64).ok_or(UDbgError::MemoryError)? as usize;
+            if tag == DT_NULL {
+                break;
+            }
+            let value = read_ptr(target, entry + entsize / 2, is_64).unwrap_or_default();
+            if tag == DT_NEEDED {
+                needed.push(value as usize);
+            }
+            dt.entry(tag).or_insert(value as usize);
+        }
+        // glibc relocates the pointers in dynamic section, musl doesn't
+        let address = |tag| {
+            dt.get(&tag)
+                .map(|&v| if v < base { bias.wrapping_add(v) } else { v })
+        };
+
+        let symbols = match (address(DT_HASH), address(DT_GNU_HASH)) {
+            (Some(hash), _) => target.read_value::<u32>(hash + 4).unwrap_or_default() as usize,
+            (None, Some(hash)) => gnu_hash_symbols(target, hash, is_64).unwrap_or_default(),
+            _ => 0,
+        };
+        let plt_rela = dt.get(&DT_PLTREL) == Some(&DT_RELA);
+        let relocs = [
+            (DT_JMPREL, DT_PLTRELSZ, plt_rela),
+            (DT_RELA, DT_RELASZ, true),
+            (DT_REL, DT_RELSZ, false),
+        ]
+        .into_iter()
+        .filter_map(|(table, size, rela)| {
+            Some(ElfRelocs {
+                address: address(table)?,
+                size: *dt.get(&size)?,
+                rela,
+            })
+        })
+        .collect();
+
+        Ok(Self {
+            is_64,
+            bias,
+            symtab: address(DT_SYMTAB).ok_or(UDbgError::NotFound)?,
+            strtab: address(DT_STRTAB).ok_or(UDbgError::NotFound)?,
+            symbols: symbols.min(MAX_SYMBOLS),
+            relocs,
+            needed,
+        })
+    }
+
+    fn read_symbols<T: ReadMemory + ?Sized>(&self, target: &T) -> Vec<ElfSymbol> {
+        let size = if self.is_64 { 24 } else { 16 };
+        let data = target.read_bytes(self.symtab, self.symbols * size);
+        data.chunks_exact(size)
+            .map(|s| {
+                if self.is_64 {
+                    ElfSymbol {
+                        name: u32_at(s, 0),
+                        info: s[4],
+                        shndx: u16_at(s, 6),
+                        value: u64_at(s, 8),
+                    }
+                } else {
+                    ElfSymbol {
+                        name: u32_at(s, 0),
+                        info: s[12],
+                        shndx: u16_at(s, 14),
+                        value: u32_at(s, 4) as u64,
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// The relocated slots and their symbol indexes, sorted by slot
+    fn read_slots<T: ReadMemory + ?Sized>(&self, target: &T) -> Vec<(usize, usize)> {
+        let mut slots = vec![];
+        for r in self.relocs.iter() {
+            let entsize = match (self.is_64, r.rela) {
+                (true, true) => 24,
+                (true, false) => 16,
+                (false, true) => 12,
+                (false, false) => 8,
+            };
+            let data = target.read_bytes(r.address, r.size.min(MAX_THUNKS * entsize));
+            for e in data.chunks_exact(entsize) {
+                let (offset, symbol) = if self.is_64 {
+                    (u64_at(e, 0) as usize, (u64_at(e, 8) >> 32) as usize)
+                } else {
+                    (u32_at(e, 0) as usize, (u32_at(e, 4) >> 8) as usize)
+                };
+                slots.push((self.bias.wrapping_add(offset), symbol));
+            }
+        }
+        slots.sort_unstable();
+        slots.dedup_by_key(|s| s.0);
+        slots
+    }
+}
+
+/// Count of the symbols in dynsym, by the last chain of `DT_GNU_HASH`
+fn gnu_hash_symbols<T: ReadMemory + ?Sized>(
+    target: &T,
+    address: usize,
+    is_64: bool,
+) -> Option<usize> {
+    let (buckets, offset, bloom) = match &read_u32s(target, address, 3)[..] {
+        &[buckets, offset, bloom] => (buckets as usize, offset as usize, bloom as usize),
+        _ => return None,
+    };
+    let table = address + 16 + bloom * if is_64 { 8 } else { 4 };
+    let last = read_u32s(target, table, buckets.min(MAX_SYMBOLS))
+        .into_iter()
+        .max()? as usize;
+    if last < offset {
+        return Some(offset);
+    }
+    let chain = table + buckets * 4;
+    (last..MAX_SYMBOLS)
+        .find(|i| {
+            target
+                .read_value::<u32>(chain + (i - offset) * 4)
+                .map_or(true, |h| h & 1 != 0)
+        })
+        .map(|i| i + 1)
+}
+
+enum Tables {
+    Pe(PeTables),
+    Elf(ElfTables),
+}
+
+/// An image loaded in target, PE or ELF
+pub struct RemoteImage<'a, T: ?Sized> {
+    target: &'a T,
+    pub base: usize,
+    tables: Tables,
+}
+
+impl<'a, T: UDbgTarget + ?Sized> RemoteImage<'a, T> {
+    /// Parse the headers of the image loaded at `base`
+    pub fn new(target: &'a T, base: usize) -> UDbgResult<Self> {
+        let magic = target
+            .read_value::<u32>(base)
+            .ok_or(UDbgError::InvalidAddress)?;
+        let tables = match magic {
+            m if m as u16 == 0x5A4D => Tables::Pe(PeTables::parse(target, base)?),
+            0x464C457F => Tables::Elf(ElfTables::parse(target, base)?),
+            _ => return Err(UDbgError::NotSupport),
+        };
+        Ok(Self {
+            target,
+            base,
+            tables,
+        })
+    }
+
+    /// The image of a module
+    #[inline]
+    pub fn of(target: &'a T, module: &dyn UDbgModule) -> UDbgResult<Self> {
+        Self::new(target, module.data().base)
+    }
+
+    #[inline]
+    pub fn is_pe(&self) -> bool {
+        matches!(self.tables, Tables::Pe(_))
+    }
+
+    pub fn exports(&self) -> Box<dyn Iterator<Item = Export> + 'a> {
+        match &self.tables {
+            Tables::Pe(pe) => self.pe_exports(pe),
+            Tables::Elf(elf) => self.elf_exports(elf),
+        }
+    }
+
+    pub fn imports(&self) -> Box<dyn Iterator<Item = Import> + 'a> {
+        match &self.tables {
+            Tables::Pe(pe) => self.pe_imports(pe),
+            Tables::Elf(elf) => self.elf_imports(elf),
+        }
+    }
+
+    /// Names of the libraries it depends on: the import descriptors of PE, `DT_NEEDED` of ELF
+    pub fn dependencies(&self) -> Vec<String> {
+        match &self.tables {
+            Tables::Pe(pe) => self
+                .pe_descriptors(pe)
+                .into_iter()
+                .filter_map(|d| self.target.read_utf8(self.base + d[3] as usize, MAX_NAME))
+                .collect(),
+            Tables::Elf(elf) => elf
+                .needed
+                .iter()
+                .filter_map(|&n| self.target.read_utf8(elf.strtab + n, MAX_NAME))
+                .collect(),
+        }
+    }
+
+    /// Find an export by name
+    pub fn get_export(&self, name: &str) -> Option<Export> {
+        self.exports().find(|e| e.name.as_deref() == Some(name))
+    }
+
+    fn pe_exports(&self, pe: &PeTables) -> Box<dyn Iterator<Item = Export> + 'a> {
+        let (target, base) = (self.target, self.base);
+        if pe.exports.start == 0 {
+            return Box::new(core::iter::empty());
+        }
+        let dir = read_u32s(target, base + pe.exports.start, 10);
+        if dir.len() < 10 {
+            return Box::new(core::iter::empty());
+        }
+        let ordinal_base = dir[4];
+        let names = (dir[6] as usize).min(MAX_EXPORTS);
+        let table = base + dir[7] as usize;
+        let functions = read_u32s(target, table, (dir[5] as usize).min(MAX_EXPORTS));
+        let name_rvas = read_u32s(target, base + dir[8] as usize, names);
+        let named = target
+            .read_bytes(base + dir[9] as usize, names * 2)
+            .chunks_exact(2)
+            .map(|o| u16_at(o, 0) as usize)
+            .zip(name_rvas)
+            .collect::<HashMap<_, _>>();
+
+        let range = pe.exports.clone();
+        Box::new(
+            functions
+                .into_iter()
+                .enumerate()
+                .filter(|&(_, rva)| rva != 0)
+                .map(move |(i, rva)| {
+                    let rva = rva as usize;
+                    Export {
+                        name: named
+                            .get(&i)
+                            .and_then(|&n| target.read_utf8(base + n as usize, MAX_NAME)),
+                        ordinal: ordinal_base + i as u32,
+                        address: base + rva,
+                        entry: Some(table + i * 4),
+                        forward: range
+                            .contains(&rva)
+                            .then(|| target.read_utf8(base + rva, MAX_NAME))
+                            .flatten(),
+                    }
+                }),
+        )
+    }
+
+    /// The import descriptors, as 5 u32s
+    fn pe_descriptors(&self, pe: &PeTables) -> Vec<Vec<u32>> {
+        if pe.imports == 0 {
+            return vec![];
+        }
+        (0..MAX_DESCRIPTORS)
+            .map(|i| read_u32s(self.target, self.base + pe.imports + i * 20, 5))
+            .take_while(|d| d.len() == 5 && (d[3] != 0 || d[4] != 0))
+            .collect()
+    }
+
+    fn pe_imports(&self, pe: &PeTables) -> Box<dyn Iterator<Item = Import> + 'a> {
+        let (target, base, is_64) = (self.target, self.base, pe.pe32plus);
+        let ps = if is_64 { 8 } else { 4 };
+        let ordinal_flag = 1u64 << (ps * 8 - 1);
+        Box::new(self.pe_descriptors(pe).into_iter().flat_map(move |d| {
+            let module = target
+                .read_utf8(base + d[3] as usize, MAX_NAME)
+                .map(Arc::<str>::from);
+            // the name thunks, or the IAT itself if it's not bound
+            let lookup = base + if d[0] != 0 { d[0] } else { d[4] } as usize;
+            let iat = base + d[4] as usize;
+            (0..MAX_THUNKS).map_while(move |i| {
+                let thunk = read_ptr(target, lookup + i * ps, is_64).filter(|&t| t != 0)?;
+                let (name, ordinal) = if thunk & ordinal_flag != 0 {
+                    (None, Some(thunk as u16 as u32))
+                } else {
+                    // IMAGE_IMPORT_BY_NAME: hint and name
+                    let name = base + thunk as u32 as usize + 2;
+                    (target.read_utf8(name, MAX_NAME), None)
+                };
+                let slot = iat + i * ps;
+                Some(Import {
+                    module: module.clone(),
+                    name,
+                    ordinal,
+                    slot,
+                    value: read_ptr(target, slot, is_64).unwrap_or_default() as usize,
+                })
+            })
+        }))
+    }
+
+    fn elf_exports(&self, elf: &ElfTables) -> Box<dyn Iterator<Item = Export> + 'a> {
+        let (target, bias, strtab) = (self.target, elf.bias, elf.strtab);
+        Box::new(
+            elf.read_symbols(target)
+                .into_iter()
+                .enumerate()
+                .filter(|(_, s)| {
+                    s.shndx != 0
+                        && s.value != 0
+                        && matches!(s.info >> 4, STB_GLOBAL | STB_WEAK | STB_GNU_UNIQUE)
+                        && !matches!(s.info & 0xF, STT_SECTION | STT_FILE | STT_TLS)
+                })
+                .map(move |(i, s)| Export {
+                    name: target.read_utf8(strtab + s.name as usize, MAX_NAME),
+                    ordinal: i as u32,
+                    address: bias.wrapping_add(s.value as usize),
+                    entry: None,
+                    forward: None,
+                }),
+        )
+    }
+
+    fn elf_imports(&self, elf: &ElfTables) -> Box<dyn Iterator<Item = Import> + 'a> {
+        let (target, base, is_64, strtab) = (self.target, self.base, elf.is_64, elf.strtab);
+        let symbols = elf.read_symbols(target);
+        Box::new(
+            elf.read_slots(target)
+                .into_iter()
+                .filter_map(move |(slot, symbol)| {
+                    let s = symbols
+                        .get(symbol)
+                        .filter(|s| symbol != 0 && s.shndx == 0)?;
+                    let value = read_ptr(target, slot, is_64).unwrap_or_default() as usize;
+                    Some(Import {
+                        module: target
+                            .find_module(value)
+                            .filter(|m| m.data().base != base)
+                            .map(|m| m.data().name.clone()),
+                        name: target.read_utf8(strtab + s.name as usize, MAX_NAME),
+                        ordinal: None,
+                        slot,
+                        value,
+                    })
+                }),
+        )
+    }
+}
@@ -0,0 +1,517 @@
+//!
+//! Remote thread creation and code injection.
+//!
+//! On Windows the thread is created by `CreateRemoteThread`, or `NtCreateThreadEx` which also
+//! works across the sessions. Waiting for it must not be done in the event callback, the new thread
+//! doesn't run until its creation is continued by the event loop.
+//!
+//! On Linux the calls of `mmap` and `pthread_create` are made by the thread of current event
+//! through ptrace, so the target must be stopped in a debug event. The new thread is reported
+//! by the event loop later, unless it's waited and exited.
+//!
+//! A library is injected by `LoadLibraryW` in a new thread on Windows, and by `dlopen` called
+//! through ptrace on Linux, so it's reported by the normal [`UEvent::ModuleLoad`] of the event
+//! loop.
+//! Manual mapping is not provided, the module mapped so is invisible to the loader and the events.
+//!
+
+use crate::{audit::AuditAction, prelude::*};
+
+use core::ops::Range;
+use core::time::Duration;
+
+/// A thread created in target
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteThread {
+    pub tid: tid_t,
+    /// Exit code of the thread if it exited in the wait
+    pub exit_code: Option<usize>,
+    /// The memory of code injected by [`inject_code`], which is kept while the thread may be
+    /// running, free it by [`free_code`]
+    pub memory: Option<Range<usize>>,
+}
+
+/// A library injected by [`inject_library`]
+#[derive(Debug, Clone, Serialize)]
+pub struct InjectedLibrary {
+    /// Base of the module, None if it's still loading by the thread
+    pub base: Option<usize>,
+    /// The thread loading the library on Windows, its memory holds the path until it's exited
+    pub thread: Option<RemoteThread>,
+}
+
+/// Create a thread at `address` with the parameter `param`, and wait for its exit if `wait`
+pub fn create_remote_thread<T: UDbgTarget + ?Sized>(
+    target: &T,
+    address: usize,
+    param: usize,
+    wait: Option<Duration>,
+) -> UDbgResult<RemoteThread> {
+    let (tid, exit_code) = os::create_thread(target, address, param, wait)?;
+    audit(
+        target,
+        AuditAction::Inject {
+            kind: "thread".into(),
+            address,
+            detail: format!("~{tid} param {param:x}"),
+        },
+    );
+    Ok(RemoteThread {
+        tid,
+        exit_code,
+        memory: None,
+    })
+}
+
+/// Copy `code` to a new executable memory, and run it by a thread from `entry_offset`. The memory
+/// is freed if the thread is exited in the wait, or it's failed
+pub fn inject_code<T: UDbgTarget + ?Sized>(
+    target: &T,
+    code: &[u8],
+    entry_offset: usize,
+    param: usize,
+    wait: Option<Duration>,
+) -> UDbgResult<RemoteThread> {
+    if entry_offset >= code.len() {
+        return Err(UDbgError::InvalidAddress);
+    }
+    let base = os::alloc_code(target, code.len())?;
+    let memory = base..base + code.len();
+    let result = (|| {
+        if target.write_memory(base, code) != Some(code.len()) {
+            return Err(UDbgError::MemoryError);
+        }
+        target.flush_cache(base, code.len())?;
+        audit(
+            target,
+            AuditAction::Inject {
+                kind: "code".into(),
+                address: base,
+                detail: format!("{:x} bytes", code.len()),
+            },
+        );
+        create_remote_thread(target, base + entry_offset, param, wait)
+    })();
+    match result {
+        Ok(thread) if thread.exit_code.is_none() => Ok(RemoteThread {
+            memory: Some(memory),
+            ..thread
+        }),
+        result => {
+            free_code(target, memory).log_error("free injected code");
+            result
+        }
+    }
+}
+
+/// Load the library at `path` into target by the loader of system, wait for it if `wait` on
+/// Windows, it's loaded by the thread of current event synchronously on Linux
+pub fn inject_library<T: UDbgTarget + ?Sized>(
+    target: &T,
+    path: &str,
+    wait: Option<Duration>,
+) -> UDbgResult<InjectedLibrary> {
+    let library = os::load_library(target, path, wait)?;
+    audit(
+        target,
+        AuditAction::Inject {
+            kind: "library".into(),
+            address: library.base.unwrap_or_default(),
+            detail: path.into(),
+        },
+    );
+    Ok(library)
+}
+
+/// Allocate executable memory in target, free it by [`free_code`]
+pub fn alloc_code<T: UDbgTarget + ?Sized>(target: &T, size: usize) -> UDbgResult<Range<usize>> {
+    let base = os::alloc_code(target, size)?;
+    Ok(base..base + size)
+}
+
+/// Free the memory of code injected, after its thread is exited
+pub fn free_code<T: UDbgTarget + ?Sized>(target: &T, memory: Range<usize>) -> UDbgResult<()> {
+    os::free_code(target, memory)
+}
+
+fn audit<T: UDbgTarget + ?Sized>(target: &T, action: AuditAction) {
+    let base = target.base();
+    base.audit.record(base.pid.get(), action);
+}
+
+#[cfg(windows)]
+mod os {
+    use super::*;
+    use crate::os::windows::{ntdll::NtCreateThreadEx, Handle, Process, ProcessAccess};
+    use crate::peb::enum_ldr_modules;
+
+    use core::ptr::null_mut;
+    use winapi::shared::ntdef::NT_SUCCESS;
+    use winapi::um::processthreadsapi::{CreateRemoteThread, GetExitCodeThread, GetThreadId};
+    use winapi::um::synchapi::WaitForSingleObject;
+    use winapi::um::winbase::WAIT_OBJECT_0;
+    use winapi::um::winnt::{MEM_COMMIT, MEM_RESERVE, PAGE_EXECUTE_READWRITE, THREAD_ALL_ACCESS};
+
+    pub fn alloc_code<T: UDbgTarget + ?Sized>(target: &T, size: usize) -> UDbgResult<usize> {
+        let process = target.process().ok_or(UDbgError::NotSupport)?;
+        match process.virtual_alloc(0, size, MEM_COMMIT | MEM_RESERVE, PAGE_EXECUTE_READWRITE) {
+            0 => Err(UDbgError::system()),
+            address => {
+                audit(target, AuditAction::VirtualAlloc { address, size });
+                Ok(address)
+            }
+        }
+    }
+
+    pub fn free_code<T: UDbgTarget + ?Sized>(target: &T, memory: Range<usize>) -> UDbgResult<()> {
+        let process = target.process().ok_or(UDbgError::NotSupport)?;
+        if process.virtual_free(memory.start) {
+            let address = memory.start;
+            audit(target, AuditAction::VirtualFree { address });
+            Ok(())
+        } else {
+            Err(UDbgError::system())
+        }
+    }
+
+    pub fn create_thread<T: UDbgTarget + ?Sized>(
+        target: &T,
+        address: usize,
+        param: usize,
+        wait: Option<Duration>,
+    ) -> UDbgResult<(tid_t, Option<usize>)> {
+        let process = target.process().ok_or(UDbgError::NotSupport)?;
+        let handle = process.handle_with(ProcessAccess::CREATE_THREAD)?;
+        let thread = unsafe {
+            let mut tid = 0;
+            let mut thread = CreateRemoteThread(
+                handle,
+                null_mut(),
+                0,
+                core::mem::transmute(address),
+                param as _,
+                0,
+                &mut tid,
+            );
+            if thread.is_null() {
+                // CreateRemoteThread fails across the sessions
+                let status = NtCreateThreadEx(
+                    &mut thread,
+                    THREAD_ALL_ACCESS,
+                    null_mut(),
+                    handle,
+                    address as _,
+                    param as _,
+                    0,
+                    0,
+                    0,
+                    0,
+                    null_mut(),
+                );
+                if !NT_SUCCESS(status) {
+                    return Err(format!("NtCreateThreadEx: {status:x}").into());
+                }
+            }
+            Handle::from_raw_handle(thread)
+        };
+        let tid = unsafe { GetThreadId(*thread) } as tid_t;
+        let exit_code = wait.and_then(|timeout| unsafe {
+            if WaitForSingleObject(*thread, timeout.as_millis() as u32) != WAIT_OBJECT_0 {
+                return None;
+            }
+            let mut code = 0;
+            (GetExitCodeThread(*thread, &mut code) != 0).then_some(code as usize)
+        });
+        Ok((tid, exit_code))
+    }
+
+    /// Address of a function exported by the kernel32 of target, the 32-bit one in WOW64
+    fn kernel32_function<T: UDbgTarget + ?Sized>(
+        target: &T,
+        process: &Process,
+        name: &str,
+    ) -> UDbgResult<usize> {
+        let wow64 = cfg!(target_pointer_width = "64") && process.is_wow64();
+        enum_ldr_modules(process)?
+            .into_iter()
+            .filter(|m| m.is_32bit == wow64 && m.base_name.eq_ignore_ascii_case("kernel32.dll"))
+            .find_map(|m| {
+                let module = target.find_module(m.base)?;
+                Some(m.base + module.get_symbol(name)?.offset as usize)
+            })
+            .ok_or_else(|| format!("{name} not found").into())
+    }
+
+    pub fn load_library<T: UDbgTarget + ?Sized>(
+        target: &T,
+        path: &str,
+        wait: Option<Duration>,
+    ) -> UDbgResult<InjectedLibrary> {
+        let process = target.process().ok_or(UDbgError::NotSupport)?;
+        let load = kernel32_function(target, process, "LoadLibraryW")?;
+        let wide = path
+            .encode_utf16()
+            .chain(Some(0))
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<_>>();
+        let address = alloc_code(target, wide.len())?;
+        let memory = address..address + wide.len();
+        let result = (|| {
+            if target.write_memory(address, &wide) != Some(wide.len()) {
+                return Err(UDbgError::MemoryError);
+            }
+            create_thread(target, load, address, wait)
+        })();
+        let (tid, exit_code) = match result {
+            Ok(r) => r,
+            Err(err) => {
+                free_code(target, memory).log_error("free library path");
+                return Err(err);
+            }
+        };
+        let code = match exit_code {
+            Some(code) => code,
+            None => {
+                return Ok(InjectedLibrary {
+                    base: None,
+                    thread: Some(RemoteThread {
+                        tid,
+                        exit_code,
+                        memory: Some(memory),
+                    }),
+                })
+            }
+        };
+        free_code(target, memory).log_error("free library path");
+        if code == 0 {
+            return Err(format!("LoadLibraryW {path} failed").into());
+        }
+        // the exit code is the module handle truncated to 32 bits
+        let wow64 = cfg!(target_pointer_width = "64") && process.is_wow64();
+        let base = enum_ldr_modules(process)?
+            .into_iter()
+            .find(|m| m.is_32bit == wow64 && m.base as u32 == code as u32)
+            .map_or(code, |m| m.base);
+        Ok(InjectedLibrary {
+            base: Some(base),
+            thread: Some(RemoteThread {
+                tid,
+                exit_code,
+                memory: None,
+            }),
+        })
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod os {
+    use super::*;
+    use crate::os::{call_remote, hold_status};
+    use crate::register::reg_t;
+
+    use nix::sys::ptrace;
+    use nix::sys::signal::Signal;
+    use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+    use nix::unistd::Pid;
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    /// Address of a function exported by libc, or libpthread and libdl before glibc 2.34
+    fn libc_function<T: UDbgTarget + ?Sized>(target: &T, name: &str) -> UDbgResult<usize> {
+        target
+            .enum_module()?
+            .filter(|m| {
+                let n = m.data().name.as_ref();
+                n.starts_with("libc.")
+                    || n.starts_with("libc-")
+                    || n.starts_with("libpthread")
+                    || n.starts_with("libdl")
+            })
+            .find_map(|m| Some(m.data().base + m.get_symbol(name)?.offset as usize))
+            .ok_or_else(|| format!("{name} not found").into())
+    }
+
+    /// Call a function of libc by the thread of current event
+    fn call<T: UDbgTarget + ?Sized>(target: &T, name: &str, args: &[reg_t]) -> UDbgResult<reg_t> {
+        let tid = target.base().event_tid.get();
+        if tid == 0 {
+            return Err(UDbgError::NotAttached);
+        }
+        Ok(call_remote(tid, libc_function(target, name)?, 0, args)?)
+    }
+
+    fn task_ids(pid: pid_t) -> HashSet<tid_t> {
+        std::fs::read_dir(format!("/proc/{pid}/task"))
+            .map(|dir| {
+                dir.filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn alloc_code<T: UDbgTarget + ?Sized>(target: &T, size: usize) -> UDbgResult<usize> {
+        let address = call(
+            target,
+            "mmap",
+            &[
+                0,
+                size as reg_t,
+                (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as reg_t,
+                (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as reg_t,
+                !0,
+                0,
+            ],
+        )?;
+        if address as usize == libc::MAP_FAILED as usize {
+            return Err("mmap failed".into());
+        }
+        let address = address as usize;
+        audit(target, AuditAction::VirtualAlloc { address, size });
+        Ok(address)
+    }
+
+    pub fn free_code<T: UDbgTarget + ?Sized>(target: &T, memory: Range<usize>) -> UDbgResult<()> {
+        let len = (memory.end - memory.start) as reg_t;
+        match call(target, "munmap", &[memory.start as reg_t, len])? {
+            0 => {
+                let address = memory.start;
+                audit(target, AuditAction::VirtualFree { address });
+                Ok(())
+            }
+            _ => Err("munmap failed".into()),
+        }
+    }
+
+    /// Run a new thread stopped at its creation until it exits or the timeout, returns whether
+    /// it has exited. The signals are delivered to it as it were not waited; its traps, event
+    /// stops and exit are left to the event loop by [`hold_status`], which ends the waiting
+    fn run_until_exit(pid: pid_t, tid: tid_t, timeout: Duration) -> bool {
+        let tid = Pid::from_raw(tid);
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            match waitpid(tid, Some(WaitPidFlag::__WALL | WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) => std::thread::sleep(Duration::from_millis(1)),
+                Ok(WaitStatus::Stopped(_, sig)) if sig != Signal::SIGTRAP => {
+                    let sig = Some(sig).filter(|&s| s != Signal::SIGSTOP);
+                    ptrace::cont(tid, sig).log_error("cont remote thread");
+                }
+                Ok(WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_STOP))
+                | Ok(WaitStatus::PtraceSyscall(_)) => {
+                    ptrace::cont(tid, None).log_error("cont remote thread");
+                }
+                Ok(status) => {
+                    hold_status(pid, status);
+                    return matches!(status, WaitStatus::Exited(..) | WaitStatus::Signaled(..));
+                }
+                // reaped by others
+                Err(_) => return true,
+            }
+        }
+        false
+    }
+
+    pub fn create_thread<T: UDbgTarget + ?Sized>(
+        target: &T,
+        address: usize,
+        param: usize,
+        wait: Option<Duration>,
+    ) -> UDbgResult<(tid_t, Option<usize>)> {
+        let pid = target.base().pid.get();
+        // the pthread_t and the return value of thread
+        let scratch = alloc_code(target, 2 * core::mem::size_of::<usize>())?;
+        let result = (|| {
+            let before = task_ids(pid);
+            let args = [scratch as reg_t, 0, address as reg_t, param as reg_t];
+            match call(target, "pthread_create", &args)? {
+                0 => {}
+                err => return Err(format!("pthread_create: {err}").into()),
+            }
+            let thread = target.read_ptr(scratch).ok_or(UDbgError::MemoryError)? as reg_t;
+            let tid = task_ids(pid)
+                .difference(&before)
+                .next()
+                .copied()
+                .unwrap_or_default();
+
+            let exited = match wait {
+                Some(timeout) if tid != 0 => run_until_exit(pid, tid, timeout),
+                _ => false,
+            };
+            if !exited {
+                call(target, "pthread_detach", &[thread])?;
+                return Ok((tid, None));
+            }
+            let retval = scratch + core::mem::size_of::<usize>();
+            call(target, "pthread_join", &[thread, retval as reg_t])?;
+            Ok((tid, target.read_ptr(retval)))
+        })();
+        free_code(target, scratch..scratch + 2 * core::mem::size_of::<usize>())
+            .log_error("free scratch");
+        result
+    }
+
+    pub fn load_library<T: UDbgTarget + ?Sized>(
+        target: &T,
+        path: &str,
+        _wait: Option<Duration>,
+    ) -> UDbgResult<InjectedLibrary> {
+        if path.contains('\0') {
+            return Err(format!("invalid path: {path}").into());
+        }
+        let mut cpath = path.as_bytes().to_vec();
+        cpath.push(0);
+        let address = alloc_code(target, cpath.len())?;
+        let result = (|| {
+            if target.write_memory(address, &cpath) != Some(cpath.len()) {
+                return Err(UDbgError::MemoryError);
+            }
+            call(
+                target,
+                "dlopen",
+                &[address as reg_t, libc::RTLD_NOW as reg_t],
+            )
+        })();
+        free_code(target, address..address + cpath.len()).log_error("free library path");
+        if result? == 0 {
+            return Err(format!("dlopen {path} failed").into());
+        }
+
+        // the modules are updated by enumerating, and the new one is reported by the event loop
+        let real = std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        let base = target
+            .enum_module()?
+            .find(|m| std::path::Path::new(&*m.data().path) == real)
+            .map(|m| m.data().base);
+        Ok(InjectedLibrary { base, thread: None })
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android")))]
+mod os {
+    use super::*;
+
+    pub fn alloc_code<T: UDbgTarget + ?Sized>(_: &T, _: usize) -> UDbgResult<usize> {
+        Err(UDbgError::NotSupport)
+    }
+
+    pub fn free_code<T: UDbgTarget + ?Sized>(_: &T, _: Range<usize>) -> UDbgResult<()> {
+        Err(UDbgError::NotSupport)
+    }
+
+    pub fn create_thread<T: UDbgTarget + ?Sized>(
+        _: &T,
+        _: usize,
+        _: usize,
+        _: Option<Duration>,
+    ) -> UDbgResult<(tid_t, Option<usize>)> {
+        Err(UDbgError::NotSupport)
+    }
+
+    pub fn load_library<T: UDbgTarget + ?Sized>(
+        _: &T,
+        _: &str,
+        _: Option<Duration>,
+    ) -> UDbgResult<InjectedLibrary> {
+        Err(UDbgError::NotSupport)
+    }
+}
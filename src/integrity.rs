@@ -0,0 +1,352 @@
+//!
+//! Hook detection, for the malware analysis: the code and import tables of modules in memory are
+//! checked against the files on disk and the exports of the imported modules.
+//!
+//! - The code and read-only sections of PE modules are compared with the files by
+//!   [`crate::compare`], so the relocated pointers are not reported. The patched bytes decoded as
+//!   a jump, on x86 only, are reported as inline hooks.
+//! - The IAT slots of PE are resolved by the exports of the imported modules, following the
+//!   forwarders. The GOT entries of ELF are checked to point to a module exporting the imported
+//!   name, so a library preloaded to interpose the symbols is not reported.
+//! - The exports of PE pointing out of the module are reported as EAT hooks.
+//!
+//! The software breakpoints of debugger are skipped, the inline hooks of [`crate::hook`] are not.
+//!
+
+use crate::{
+    compare::{compare_module, CompareOptions, DiffKind},
+    image::{Export, RemoteImage},
+    pe::PETarget,
+    prelude::*,
+    unpack::read_sections,
+};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const MAX_FORWARDS: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum HookKind {
+    /// Bytes of code or read-only data differ from the file
+    Patch,
+    /// A jump patched into code
+    Inline,
+    /// An IAT slot or GOT entry doesn't point to the function it imports
+    Import {
+        name: String,
+        /// The export it should point to, if resolved
+        expected: Option<usize>,
+    },
+    /// An entry of export address table points out of the module
+    Export { name: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectedHook {
+    pub module: Arc<str>,
+    /// Address of the patched bytes, or the slot of a table
+    pub address: usize,
+    pub kind: HookKind,
+    /// Symbol of `address`
+    pub symbol: Option<String>,
+    /// Bytes in file, for the patches only
+    pub original: Vec<u8>,
+    pub current: Vec<u8>,
+    /// Where the hook redirects to
+    pub destination: Option<usize>,
+    /// Module containing the destination, None if it's out of any module
+    pub owner: Option<Arc<str>>,
+}
+
+#[derive(Default)]
+struct ModuleExports {
+    names: HashMap<String, Export>,
+    ordinals: HashMap<u32, Export>,
+}
+
+struct Scanner<'a, T: ?Sized> {
+    target: &'a T,
+    /// Exports of the modules by base
+    exports: HashMap<usize, ModuleExports>,
+    hooks: Vec<DetectedHook>,
+}
+
+impl<'a, T: UDbgTarget + ?Sized> Scanner<'a, T> {
+    fn new(target: &'a T) -> Self {
+        Self {
+            target,
+            exports: Default::default(),
+            hooks: vec![],
+        }
+    }
+
+    fn owner(&self, address: usize) -> Option<Arc<str>> {
+        self.target
+            .find_module(address)
+            .map(|m| m.data().name.clone())
+    }
+
+    fn module_exports(&mut self, module: &dyn UDbgModule) -> &ModuleExports {
+        let target = self.target;
+        self.exports.entry(module.data().base).or_insert_with(|| {
+            let mut result = ModuleExports::default();
+            let image = match RemoteImage::of(target, module) {
+                Ok(image) => image,
+                Err(_) => return result,
+            };
+            for e in image.exports() {
+                if let Some(name) = e.name.clone() {
+                    result.names.insert(name, e.clone());
+                }
+                result.ordinals.insert(e.ordinal, e);
+            }
+            result
+        })
+    }
+
+    /// Address of an export by name or ordinal, the forwarders are followed
+    fn resolve(&mut self, module: &str, name: Option<&str>, ordinal: Option<u32>) -> Option<usize> {
+        let (mut module, mut name, mut ordinal) =
+            (module.to_string(), name.map(String::from), ordinal);
+        for _ in 0..MAX_FORWARDS {
+            let exporter = self.target.get_module(&module)?;
+            let exports = self.module_exports(exporter.as_ref());
+            let export = match (name.as_deref(), ordinal) {
+                (Some(name), _) => exports.names.get(name),
+                (None, Some(ordinal)) => exports.ordinals.get(&ordinal),
+                _ => None,
+            }?;
+            let forward = match export.forward.as_ref() {
+                Some(forward) => forward.clone(),
+                None => return Some(export.address),
+            };
+            // `module.function` or `module.#ordinal`
+            let (m, f) = forward.rsplit_once('.')?;
+            module = m.into();
+            match f.strip_prefix('#') {
+                Some(o) => (name, ordinal) = (None, Some(o.parse().ok()?)),
+                None => (name, ordinal) = (Some(f.into()), None),
+            }
+        }
+        None
+    }
+
+    fn scan(&mut self, module: &dyn UDbgModule) -> UDbgResult<()> {
+        let image = RemoteImage::of(self.target, module)?;
+        self.check_imports(module, &image);
+        if image.is_pe() {
+            self.check_exports(module, &image);
+            self.check_code(module)
+                .log_error_with(|err| format!("compare {}: {err:?}", module.data().name));
+        }
+        Ok(())
+    }
+
+    fn check_imports(&mut self, module: &dyn UDbgModule, image: &RemoteImage<'a, T>) {
+        let data = module.data();
+        let own = data.base..data.base + data.size;
+        for import in image.imports() {
+            // not bound yet, or bound lazily by the stub in module
+            if import.value == 0 || own.contains(&import.value) {
+                continue;
+            }
+            let owner = self.target.find_module(import.value);
+            let (hooked, expected) = if image.is_pe() {
+                let expected = import
+                    .module
+                    .as_deref()
+                    .and_then(|m| self.resolve(m, import.name.as_deref(), import.ordinal));
+                let hooked = match expected {
+                    Some(expected) => expected != import.value,
+                    None => owner.is_none(),
+                };
+                (hooked, expected)
+            } else {
+                let exported = owner
+                    .as_ref()
+                    .and_then(|m| self.resolve(&m.data().name, import.name.as_deref(), None));
+                (exported.is_none(), None)
+            };
+            if !hooked {
+                continue;
+            }
+            let name = import
+                .name
+                .clone()
+                .or_else(|| import.ordinal.map(|o| format!("#{o}")))
+                .unwrap_or_default();
+            self.hooks.push(DetectedHook {
+                module: data.name.clone(),
+                address: import.slot,
+                kind: HookKind::Import { name, expected },
+                symbol: self.target.get_symbol_string(import.slot),
+                original: vec![],
+                current: vec![],
+                destination: Some(import.value),
+                owner: owner.map(|m| m.data().name.clone()),
+            });
+        }
+    }
+
+    fn check_exports(&mut self, module: &dyn UDbgModule, image: &RemoteImage<'a, T>) {
+        let data = module.data();
+        let own = data.base..data.base + data.size;
+        for export in image.exports() {
+            if export.forward.is_some() || own.contains(&export.address) {
+                continue;
+            }
+            let name = export
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("#{}", export.ordinal));
+            let address = export.entry.unwrap_or(export.address);
+            self.hooks.push(DetectedHook {
+                module: data.name.clone(),
+                address,
+                kind: HookKind::Export { name },
+                symbol: None,
+                original: vec![],
+                current: vec![],
+                destination: Some(export.address),
+                owner: self.owner(export.address),
+            });
+        }
+    }
+
+    fn check_code(&mut self, module: &dyn UDbgModule) -> UDbgResult<()> {
+        let target = self.target;
+        let data = module.data();
+        let sections = read_sections(target, data.base).ok_or(UDbgError::NotSupport)?;
+        let file = PETarget::new(&*data.path)?;
+        let file_module = file.enum_module().next().ok_or(UDbgError::NotFound)?;
+        let diff = compare_module(
+            target,
+            module,
+            &file,
+            file_module.as_ref(),
+            &CompareOptions::default(),
+        )?;
+
+        for d in diff.diffs {
+            let address = data.base + d.offset;
+            if d.kind == DiffKind::Import || is_breakpoint(target, address, &d.a, &d.b) {
+                continue;
+            }
+            let executable = sections
+                .iter()
+                .any(|s| s.is_executable() && s.range().contains(&address));
+            let destination = executable
+                .then(|| inline_destination(target, address))
+                .flatten();
+            self.hooks.push(DetectedHook {
+                module: data.name.clone(),
+                address,
+                kind: if destination.is_some() {
+                    HookKind::Inline
+                } else {
+                    HookKind::Patch
+                },
+                symbol: target.get_symbol_string(address),
+                original: d.b,
+                current: d.a,
+                destination,
+                owner: destination.and_then(|a| self.owner(a)),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The difference is a software breakpoint of debugger
+fn is_breakpoint<T: UDbgTarget + ?Sized>(
+    target: &T,
+    address: usize,
+    current: &[u8],
+    original: &[u8],
+) -> bool {
+    current.len() == 1
+        && target.get_bp_by_address(address).map_or(false, |bp| {
+            bp.origin_bytes()
+                .map_or(false, |o| o.first() == original.first())
+        })
+}
+
+/// Destination of the jump patched at `address`: `jmp`/`call` by relative or absolute address,
+/// `push imm; ret` and `mov reg, imm; jmp reg`
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn inline_destination<T: UDbgTarget + ?Sized>(target: &T, address: usize) -> Option<usize> {
+    use iced_x86::{Decoder, DecoderOptions, Mnemonic, OpKind, Register};
+
+    // the leading bytes of a patched jump may be equal to the original ones
+    const MAX_LEADING: usize = 4;
+
+    let bitness = if target.base().is_ptr32() { 32 } else { 64 };
+    (0..=MAX_LEADING).find_map(|back| {
+        let start = address.checked_sub(back)?;
+        let buffer = target.read_bytes(start, 2 * MAX_INSN_SIZE);
+        let mut decoder = Decoder::with_ip(bitness, &buffer, start as u64, DecoderOptions::NONE);
+        let (first, second) = (decoder.decode(), decoder.decode());
+        if first.is_invalid() || start + first.len() <= address {
+            return None;
+        }
+        match (first.mnemonic(), first.op0_kind()) {
+            (
+                Mnemonic::Jmp | Mnemonic::Call,
+                OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64,
+            ) => Some(first.near_branch_target() as usize),
+            (Mnemonic::Jmp | Mnemonic::Call, OpKind::Memory) => {
+                let slot = if first.is_ip_rel_memory_operand() {
+                    first.ip_rel_memory_address()
+                } else if first.memory_base() == Register::None
+                    && first.memory_index() == Register::None
+                {
+                    first.memory_displacement64()
+                } else {
+                    return None;
+                };
+                target.read_pointer(slot as usize, target.base().data_layout())
+            }
+            (Mnemonic::Push, OpKind::Immediate32 | OpKind::Immediate8to32)
+                if second.mnemonic() == Mnemonic::Ret =>
+            {
+                Some(first.immediate32() as usize)
+            }
+            (Mnemonic::Mov, OpKind::Register)
+                if first.op1_kind() == OpKind::Immediate64
+                    && second.mnemonic() == Mnemonic::Jmp
+                    && second.op0_kind() == OpKind::Register
+                    && second.op0_register() == first.op0_register() =>
+            {
+                Some(first.immediate64() as usize)
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn inline_destination<T: UDbgTarget + ?Sized>(_target: &T, _address: usize) -> Option<usize> {
+    None
+}
+
+/// Check a module for the hooks
+pub fn scan_module<T: UDbgTarget + ?Sized>(
+    target: &T,
+    module: &dyn UDbgModule,
+) -> UDbgResult<Vec<DetectedHook>> {
+    let mut scanner = Scanner::new(target);
+    scanner.scan(module)?;
+    Ok(scanner.hooks)
+}
+
+/// Check all the modules for the hooks, the modules failed to parse are skipped
+pub fn detect_hooks<T: UDbgTarget + ?Sized>(target: &T) -> Vec<DetectedHook> {
+    let mut scanner = Scanner::new(target);
+    for m in target.enum_module() {
+        scanner
+            .scan(m.as_ref())
+            .log_error_with(|err| format!("scan {}: {err:?}", m.data().name));
+    }
+    scanner.hooks
+}
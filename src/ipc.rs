@@ -0,0 +1,487 @@
+//!
+//! IPC tap: intercept the messages through named pipes, ALPC ports and unix sockets, for debugging client/server protocols.
+//!
+//! [`IpcTap`] sets breakpoints on the send/receive APIs, the sent payload is read at the entry of API,
+//! and the received payload is read at its return address, both are reconstructed into [`IpcMessage`]
+//! with the direction and peer information, and could be wrapped as [`UEvent::Custom`] of kind [`IPC_EVENT`].
+//!
+
+use crate::{
+    breakpoint::{return_address, ReturnWaiters},
+    prelude::*,
+    register::regid::*,
+    target::call_args,
+};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Kind of the custom event which carries [`IpcMessage`]
+pub const IPC_EVENT: &str = "ipc";
+
+/// Max bytes of payload to read by default
+pub const MAX_PAYLOAD: usize = 0x10000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum IpcKind {
+    Pipe,
+    Alpc,
+    UnixSocket,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum IpcDirection {
+    Send,
+    Recv,
+}
+
+/// A message sent or received by target
+#[derive(Debug, Clone, Serialize)]
+pub struct IpcMessage {
+    pub kind: IpcKind,
+    pub direction: IpcDirection,
+    pub tid: tid_t,
+    /// Handle or file descriptor of the channel in target
+    pub handle: usize,
+    /// Name of the channel, such as pipe path, port name, socket path
+    pub channel: String,
+    /// Process at the other end, if known
+    pub peer_pid: Option<pid_t>,
+    pub data: Vec<u8>,
+    /// The payload is larger than [`IpcTap::max_payload`]
+    pub truncated: bool,
+}
+
+impl IpcMessage {
+    #[inline]
+    pub fn into_event(self) -> UEvent {
+        UEvent::custom(IPC_EVENT, self)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Api {
+    WriteFile,
+    ReadFile,
+    AlpcSendWaitReceive,
+    Send,
+    Recv,
+}
+
+impl Api {
+    fn symbols(self) -> &'static [&'static str] {
+        match self {
+            Self::WriteFile => &["kernelbase!WriteFile", "kernel32!WriteFile"],
+            Self::ReadFile => &["kernelbase!ReadFile", "kernel32!ReadFile"],
+            Self::AlpcSendWaitReceive => &["ntdll!NtAlpcSendWaitReceivePort"],
+            // sendto/recvfrom have the same leading arguments
+            Self::Send => &["send", "sendto"],
+            Self::Recv => &["recv", "recvfrom"],
+        }
+    }
+
+    fn all() -> &'static [Self] {
+        cfg_if! {
+            if #[cfg(windows)] {
+                &[Self::WriteFile, Self::ReadFile, Self::AlpcSendWaitReceive]
+            } else {
+                &[Self::Send, Self::Recv]
+            }
+        }
+    }
+}
+
+/// Call waiting for return to read the received payload
+struct Pending {
+    api: Api,
+    handle: usize,
+    /// Buffer or receive message
+    buffer: usize,
+    /// Size of buffer, or pointer to the received size
+    size: usize,
+}
+
+/// Breakpoint hit of [`ApiHooks`]
+pub(crate) enum ApiHit<A, P> {
+    /// Entry of the hooked API
+    Entry(A),
+    /// Return of a pending call
+    Return(P),
+}
+
+/// Breakpoints on the entries of APIs and the return addresses of their pending calls, shared by the taps
+pub(crate) struct ApiHooks<A, P> {
+    target: Arc<dyn UDbgTarget>,
+    hooks: HashMap<usize, (A, Arc<dyn UDbgBreakpoint>)>,
+    returns: ReturnWaiters<P>,
+}
+
+impl<A: Copy, P> ApiHooks<A, P> {
+    pub fn new(target: Arc<dyn UDbgTarget>) -> Self {
+        Self {
+            target,
+            hooks: Default::default(),
+            returns: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn target(&self) -> &Arc<dyn UDbgTarget> {
+        &self.target
+    }
+
+    /// Count of hooked APIs
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    /// Set breakpoint at the entry of an API, returns true if it's hooked
+    pub fn hook(&mut self, address: usize, api: A, name: &str) -> bool {
+        if self.hooks.contains_key(&address) {
+            return true;
+        }
+        match self.target.add_bp(address) {
+            Ok(bp) => {
+                self.hooks.insert(address, (api, bp));
+                true
+            }
+            Err(err) => {
+                warn!("hook {name} {address:x}: {err:?}");
+                false
+            }
+        }
+    }
+
+    #[inline]
+    pub fn owns(&self, address: usize) -> bool {
+        self.hooks.contains_key(&address) || self.returns.contains(address)
+    }
+
+    /// Dispatch a breakpoint hit of thread `tid`
+    pub fn hit(&mut self, address: usize, tid: tid_t) -> Option<ApiHit<A, P>> {
+        if let Some(&(api, _)) = self.hooks.get(&address) {
+            return Some(ApiHit::Entry(api));
+        }
+        self.returns.take(address, tid).map(ApiHit::Return)
+    }
+
+    /// Set a breakpoint at the return address of current call, the pending is returned when it's hit by the same thread
+    pub fn wait_return(&mut self, tid: tid_t, regs: &dyn UDbgRegs, arch: u32, pending: P) {
+        let ret = match return_address(self.target.as_ref(), regs, arch) {
+            Some(r) => r,
+            None => return,
+        };
+        if let Err(err) = self.returns.wait(self.target.as_ref(), ret, tid, pending) {
+            warn!("return bp {ret:x}: {err:?}");
+        }
+    }
+}
+
+impl<A, P> ApiHooks<A, P> {
+    /// Remove all the breakpoints
+    pub fn unhook_all(&mut self) {
+        for (_, (_, bp)) in self.hooks.drain() {
+            bp.remove().log_error("remove api hook");
+        }
+        self.returns.clear();
+    }
+}
+
+impl<A, P> Drop for ApiHooks<A, P> {
+    fn drop(&mut self) {
+        self.unhook_all();
+    }
+}
+
+/// Register of the return value
+pub(crate) fn return_reg(arch: u32) -> u32 {
+    match arch {
+        ARCH_X86 => X86_REG_EAX,
+        ARCH_ARM => ARM_REG_R0,
+        ARCH_ARM64 => ARM64_REG_X0,
+        _ => X86_REG_RAX,
+    }
+}
+
+/// Interceptor of the IPC messages in a target
+pub struct IpcTap {
+    hooks: ApiHooks<Api, Pending>,
+    /// Max bytes of payload to read for each message
+    pub max_payload: usize,
+}
+
+impl IpcTap {
+    pub fn new(target: Arc<dyn UDbgTarget>) -> Self {
+        Self {
+            hooks: ApiHooks::new(target),
+            max_payload: MAX_PAYLOAD,
+        }
+    }
+
+    #[inline]
+    fn target(&self) -> &dyn UDbgTarget {
+        self.hooks.target().as_ref()
+    }
+
+    /// Set breakpoints on the IPC APIs found in target, returns the count of hooked functions,
+    /// could be called again after the modules such as libc loaded
+    pub fn install(&mut self) -> usize {
+        for &api in Api::all() {
+            for sym in api.symbols() {
+                let address = match self.target().get_address_by_symbol(sym) {
+                    Some(a) => a,
+                    None => continue,
+                };
+                // hook only the first found on Windows, kernel32 forwards to kernelbase
+                if self.hooks.hook(address, api, sym) && cfg!(windows) {
+                    break;
+                }
+            }
+        }
+        self.hooks.len()
+    }
+
+    /// Remove all the breakpoints set by this tap
+    #[inline]
+    pub fn uninstall(&mut self) {
+        self.hooks.unhook_all();
+    }
+
+    /// If the breakpoint at `address` is set by this tap, the event should be continued silently then
+    #[inline]
+    pub fn owns(&self, address: usize) -> bool {
+        self.hooks.owns(address)
+    }
+
+    /// Handle a breakpoint event, returns the message if a complete one is intercepted
+    pub fn on_breakpoint(
+        &mut self,
+        bp: &dyn UDbgBreakpoint,
+        ctx: &mut dyn TraceContext,
+    ) -> Option<IpcMessage> {
+        let tid = self.target().base().event_tid.get();
+        match self.hooks.hit(bp.address(), tid)? {
+            ApiHit::Entry(api) => self.on_entry(api, tid, ctx),
+            ApiHit::Return(pending) => self.on_return(pending, tid, ctx),
+        }
+    }
+
+    /// Handle an event, returns the [`IPC_EVENT`] if a message intercepted
+    pub fn on_event(&mut self, event: &UEvent, ctx: &mut dyn TraceContext) -> Option<UEvent> {
+        match event {
+            UEvent::Breakpoint(bp) => self
+                .on_breakpoint(bp.as_ref(), ctx)
+                .map(IpcMessage::into_event),
+            _ => None,
+        }
+    }
+
+    fn on_entry(&mut self, api: Api, tid: tid_t, ctx: &mut dyn TraceContext) -> Option<IpcMessage> {
+        let arch = ctx.arch();
+        let regs: &dyn UDbgRegs = ctx.register()?;
+        let target = self.hooks.target().clone();
+        let arg = call_args(target.as_ref(), regs, arch);
+
+        let handle = arg(1)?;
+        let (kind, channel, peer_pid) = self.resolve_channel(api, handle)?;
+        match api {
+            Api::WriteFile | Api::Send => {
+                let (data, truncated) = self.read_payload(arg(2)?, arg(3)?);
+                Some(IpcMessage {
+                    kind,
+                    direction: IpcDirection::Send,
+                    tid,
+                    handle,
+                    channel,
+                    peer_pid,
+                    data,
+                    truncated,
+                })
+            }
+            Api::ReadFile => {
+                // overlapped reading without the size pointer completes asynchronously
+                let size = arg(4).filter(|&p| p != 0)?;
+                let pending = Pending {
+                    api,
+                    handle,
+                    buffer: arg(2)?,
+                    size,
+                };
+                self.hooks.wait_return(tid, regs, arch, pending);
+                None
+            }
+            Api::Recv => {
+                let pending = Pending {
+                    api,
+                    handle,
+                    buffer: arg(2)?,
+                    size: arg(3)?,
+                };
+                self.hooks.wait_return(tid, regs, arch, pending);
+                None
+            }
+            Api::AlpcSendWaitReceive => {
+                let (send, recv) = (arg(3).unwrap_or(0), arg(5).unwrap_or(0));
+                if recv != 0 {
+                    let pending = Pending {
+                        api,
+                        handle,
+                        buffer: recv,
+                        size: 0,
+                    };
+                    self.hooks.wait_return(tid, regs, arch, pending);
+                }
+                if send == 0 {
+                    return None;
+                }
+                let (data, truncated, _) = self.read_port_message(send)?;
+                Some(IpcMessage {
+                    kind,
+                    direction: IpcDirection::Send,
+                    tid,
+                    handle,
+                    channel,
+                    peer_pid,
+                    data,
+                    truncated,
+                })
+            }
+        }
+    }
+
+    fn on_return(
+        &mut self,
+        pending: Pending,
+        tid: tid_t,
+        ctx: &mut dyn TraceContext,
+    ) -> Option<IpcMessage> {
+        let arch = ctx.arch();
+        let regs: &dyn UDbgRegs = ctx.register()?;
+        let result = regs.get_reg(return_reg(arch))?.as_int();
+        let (kind, channel, mut peer_pid) = self.resolve_channel(pending.api, pending.handle)?;
+        let (data, truncated) = match pending.api {
+            Api::ReadFile => {
+                if result as u32 == 0 {
+                    return None;
+                }
+                let size = self.target().read_value::<u32>(pending.size)?;
+                self.read_payload(pending.buffer, size as usize)
+            }
+            Api::Recv => {
+                let size = match self.target().base().pointer_size() {
+                    4 => result as u32 as i32 as isize,
+                    _ => result as isize,
+                };
+                if size <= 0 {
+                    return None;
+                }
+                self.read_payload(pending.buffer, size.min(pending.size as isize) as usize)
+            }
+            Api::AlpcSendWaitReceive => {
+                // NT_SUCCESS
+                if (result as u32 as i32) < 0 {
+                    return None;
+                }
+                let (data, truncated, client) = self.read_port_message(pending.buffer)?;
+                peer_pid = client.or(peer_pid);
+                (data, truncated)
+            }
+            _ => return None,
+        };
+        Some(IpcMessage {
+            kind,
+            direction: IpcDirection::Recv,
+            tid,
+            handle: pending.handle,
+            channel,
+            peer_pid,
+            data,
+            truncated,
+        })
+    }
+
+    fn read_payload(&self, address: usize, size: usize) -> (Vec<u8>, bool) {
+        let len = size.min(self.max_payload);
+        (self.target().read_bytes(address, len), size > len)
+    }
+
+    /// Read the data and client process of a `PORT_MESSAGE`
+    fn read_port_message(&self, address: usize) -> Option<(Vec<u8>, bool, Option<pid_t>)> {
+        let psize = self.target().base().pointer_size();
+        // u1.DataLength, u1.TotalLength, u2, ClientId, MessageId, ClientViewSize
+        let header = if psize == 4 { 0x18 } else { 0x28 };
+        let len = self.target().read_value::<u16>(address)? as usize;
+        let client = self
+            .target
+            .read_ptr(address + 8)
+            .map(|p| if psize == 4 { p as u32 as usize } else { p })
+            .filter(|&p| p != 0)
+            .map(|p| p as pid_t);
+        let (data, truncated) = self.read_payload(address + header, len);
+        Some((data, truncated, client))
+    }
+
+    /// Check the channel type of handle, returns its kind, name and peer process
+    fn resolve_channel(&self, api: Api, handle: usize) -> Option<(IpcKind, String, Option<pid_t>)> {
+        cfg_if! {
+            if #[cfg(windows)] {
+                let process = self.target().process()?;
+                match api {
+                    Api::AlpcSendWaitReceive => {
+                        let name = process
+                            .query_object(handle as _)
+                            .map(|o| o.name)
+                            .unwrap_or_default();
+                        Some((IpcKind::Alpc, name, None))
+                    }
+                    _ => {
+                        let object = process.query_object(handle as _).ok()?;
+                        if object.type_name != "File" || !object.name.starts_with(r"\Device\NamedPipe\") {
+                            return None;
+                        }
+                        Some((IpcKind::Pipe, object.name, pipe_peer(process, handle)))
+                    }
+                }
+            } else if #[cfg(any(target_os = "linux", target_os = "android"))] {
+                let _ = api;
+                let pid = self.target().base().pid.get();
+                let link = std::fs::read_link(format!("/proc/{pid}/fd/{handle}")).ok()?;
+                let inode = link
+                    .to_str()?
+                    .strip_prefix("socket:[")?
+                    .strip_suffix(']')?
+                    .to_string();
+                // Num RefCount Protocol Flags Type St Inode Path
+                let path = Utils::file_lines(format!("/proc/{pid}/net/unix"))
+                    .ok()?
+                    .find_map(|line| {
+                        let mut fields = line.split_whitespace().skip(6);
+                        (fields.next()? == inode).then(|| fields.next().unwrap_or_default().to_string())
+                    })?;
+                Some((IpcKind::UnixSocket, path, None))
+            } else {
+                let _ = (api, handle);
+                None
+            }
+        }
+    }
+}
+
+/// Process at the other end of a pipe
+#[cfg(windows)]
+fn pipe_peer(process: &Process, handle: usize) -> Option<pid_t> {
+    use winapi::um::processthreadsapi::GetCurrentProcess;
+    use winapi::um::winbase::{GetNamedPipeClientProcessId, GetNamedPipeServerProcessId};
+
+    let handle = process.duplicate_handle(handle as _, unsafe { GetCurrentProcess() })?;
+    let handle = unsafe { crate::os::Handle::from_raw_handle(handle) };
+    let (mut server, mut client) = (0u32, 0u32);
+    unsafe {
+        GetNamedPipeServerProcessId(*handle, &mut server);
+        GetNamedPipeClientProcessId(*handle, &mut client);
+    }
+    [server, client]
+        .into_iter()
+        .find(|&p| p != 0 && p != process.pid())
+}
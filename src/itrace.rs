@@ -0,0 +1,557 @@
+//!
+//! Instruction trace: record a thread by single-stepping, and inspect the run backward and
+//! forward afterwards.
+//!
+//! [`TraceRecorder`] writes a record for each instruction executed: its address, the general
+//! registers changed since the previous one and the bytes written by the previous instruction,
+//! old and new. The memory operands are decoded by iced on x86 only, on the other architectures
+//! the trace has no memory writes. The accesses through `fs`/`gs` are not recorded either.
+//!
+//! [`TraceReader`] loads the trace and keeps the registers and the known memory at a position,
+//! [`TraceReader::seek`] and [`TraceReader::step_back`] move through it.
+//!
+//! The file starts with [`MAGIC`] and the architecture as u32, then the records in little endian:
+//! `pc: u64, regs: u8, [id: u16, value: u64], writes: u8, [address: u64, len: u16, old, new]`.
+//!
+
+use crate::prelude::*;
+use crate::register::{general_regs, regid};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+pub const MAGIC: &[u8; 8] = b"UDBGITR1";
+
+/// Max bytes of a memory write
+const MAX_WRITE: usize = 0x400;
+
+#[derive(Debug, Clone)]
+pub struct MemWrite {
+    pub address: usize,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+/// The record of an instruction
+#[derive(Debug, Clone, Default)]
+pub struct TraceStep {
+    pub pc: usize,
+    /// The registers changed by the previous instruction, all of them in the first record
+    pub regs: Vec<(u32, u64)>,
+    /// The memory written by the previous instruction
+    pub writes: Vec<MemWrite>,
+}
+
+impl TraceStep {
+    fn write_to(&self, w: &mut impl Write) -> std::io::Result<()> {
+        w.write_all(&(self.pc as u64).to_le_bytes())?;
+        w.write_all(&[self.regs.len() as u8])?;
+        for &(id, value) in self.regs.iter() {
+            w.write_all(&(id as u16).to_le_bytes())?;
+            w.write_all(&value.to_le_bytes())?;
+        }
+        w.write_all(&[self.writes.len() as u8])?;
+        for m in self.writes.iter() {
+            w.write_all(&(m.address as u64).to_le_bytes())?;
+            w.write_all(&(m.new.len() as u16).to_le_bytes())?;
+            w.write_all(&m.old)?;
+            w.write_all(&m.new)?;
+        }
+        Ok(())
+    }
+
+    /// None at the end of file
+    fn read_from(r: &mut impl Read) -> std::io::Result<Option<Self>> {
+        fn read<const N: usize>(r: &mut impl Read) -> std::io::Result<[u8; N]> {
+            let mut buf = [0u8; N];
+            r.read_exact(&mut buf)?;
+            Ok(buf)
+        }
+
+        let pc = match read::<8>(r) {
+            Ok(pc) => u64::from_le_bytes(pc) as usize,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut step = Self {
+            pc,
+            ..Default::default()
+        };
+        for _ in 0..read::<1>(r)?[0] {
+            let id = u16::from_le_bytes(read(r)?) as u32;
+            step.regs.push((id, u64::from_le_bytes(read(r)?)));
+        }
+        for _ in 0..read::<1>(r)?[0] {
+            let address = u64::from_le_bytes(read(r)?) as usize;
+            let len = u16::from_le_bytes(read(r)?) as usize;
+            let mut old = vec![0u8; len];
+            r.read_exact(&mut old)?;
+            let mut new = vec![0u8; len];
+            r.read_exact(&mut new)?;
+            step.writes.push(MemWrite { address, old, new });
+        }
+        Ok(Some(step))
+    }
+}
+
+fn trace_regs(arch: u32) -> Vec<u32> {
+    let mut regs = general_regs(arch)
+        .iter()
+        .map(|&(_, id)| id)
+        .collect::<Vec<_>>();
+    if matches!(arch, ARCH_X86 | ARCH_X64) {
+        regs.push(regid::X86_REG_EFLAGS);
+    }
+    regs
+}
+
+/// Record a thread by single-stepping, into a trace file. [`Self::trace_thread`] records by the
+/// step tracer of target, which steps the thread in the engine, or feed the events by
+/// [`Self::handle`] to step it in the callback of user
+///
+/// ```ignore
+/// let mut recorder = TraceRecorder::create("run.itrace", 100_000)?;
+/// engine.event_loop(&mut |ctx, event| {
+///     if let Some(reply) = recorder.handle(ctx, &event) {
+///         return reply;
+///     }
+///     match event {
+///         UEvent::Breakpoint(bp) if bp.address() == start => recorder.start(ctx),
+///         _ => UserReply::Run(false),
+///     }
+/// })?;
+/// ```
+pub struct TraceRecorder {
+    file: BufWriter<File>,
+    arch: Option<u32>,
+    tid: Option<tid_t>,
+    max_steps: usize,
+    steps: usize,
+    last: HashMap<u32, u64>,
+    /// The memory to be written by the stepping instruction, with the old bytes
+    pending: Vec<(usize, Vec<u8>)>,
+}
+
+impl TraceRecorder {
+    /// Create the trace file, the recording stops after `max_steps` instructions
+    pub fn create(path: impl AsRef<Path>, max_steps: usize) -> UDbgResult<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            arch: None,
+            tid: None,
+            max_steps,
+            steps: 0,
+            last: HashMap::new(),
+            pending: vec![],
+        })
+    }
+
+    #[inline]
+    pub fn is_recording(&self) -> bool {
+        self.tid.is_some()
+    }
+
+    #[inline]
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Start recording the event thread at the current instruction, return the reply to the event
+    pub fn start(&mut self, ctx: &mut dyn TraceContext) -> UserReply {
+        let tid = ctx.target().base().event_tid.get();
+        if !self.begin(ctx.arch(), tid) {
+            return UserReply::Run(false);
+        }
+        self.step(ctx)
+    }
+
+    /// Record the thread by [`TargetUtil::trace`], from its current instruction if it's the event
+    /// thread, otherwise from its next event. The recorder is owned by the step tracer, the file is
+    /// flushed when it stops after `max_steps` instructions or the thread exits
+    #[cfg(not(feature = "passive"))]
+    pub fn trace_thread<T: UDbgTarget + ?Sized>(
+        mut self,
+        target: &T,
+        tid: tid_t,
+    ) -> UDbgResult<()> {
+        use crate::steptrace::StepAction;
+
+        target.trace(tid, move |insn| {
+            if insn.index == 0 && !self.begin(insn.arch, insn.tid) {
+                return StepAction::Stop;
+            }
+            if self.steps >= self.max_steps {
+                info!("itrace: {} steps recorded", self.steps);
+                self.stop().log_error("itrace");
+                return StepAction::Stop;
+            }
+            if self.record(insn.target, insn.arch, insn.regs) {
+                StepAction::Continue
+            } else {
+                StepAction::Stop
+            }
+        })
+    }
+
+    fn begin(&mut self, arch: u32, tid: tid_t) -> bool {
+        match self.arch {
+            Some(a) if a != arch => {
+                warn!("itrace: the architecture changed");
+                return false;
+            }
+            Some(_) => {}
+            None => {
+                let header = [MAGIC.as_slice(), &arch.to_le_bytes()].concat();
+                if self.file.write_all(&header).log_error("itrace").is_none() {
+                    return false;
+                }
+                self.arch = Some(arch);
+            }
+        }
+        self.tid = Some(tid);
+        self.last.clear();
+        self.pending.clear();
+        true
+    }
+
+    /// Stop recording and flush the trace file
+    pub fn stop(&mut self) -> UDbgResult<()> {
+        self.tid = None;
+        self.pending.clear();
+        Ok(self.file.flush()?)
+    }
+
+    /// Handle the event from the debug loop, returns the reply if it's a step of the recording
+    /// thread. The breakpoints hit by the thread are recorded and stepped over too.
+    pub fn handle(&mut self, ctx: &mut dyn TraceContext, event: &UEvent) -> Option<UserReply> {
+        let tid = self.tid?;
+        if !matches!(event, UEvent::Step | UEvent::Breakpoint(_))
+            || ctx.target().base().event_tid.get() != tid
+        {
+            return None;
+        }
+        if self.steps >= self.max_steps {
+            info!("itrace: {} steps recorded", self.steps);
+            self.stop().log_error("itrace");
+            return Some(UserReply::Run(false));
+        }
+        Some(self.step(ctx))
+    }
+
+    fn step(&mut self, ctx: &mut dyn TraceContext) -> UserReply {
+        let target = ctx.target();
+        let arch = ctx.arch();
+        let recorded = match ctx.register() {
+            Some(regs) => self.record(target.as_ref(), arch, regs),
+            None => {
+                self.stop().log_error("itrace");
+                false
+            }
+        };
+        if recorded {
+            UserReply::StepIn
+        } else {
+            UserReply::Run(false)
+        }
+    }
+
+    /// Record the instruction about to execute, false if the trace file fails and it's stopped
+    fn record(&mut self, target: &dyn UDbgTarget, arch: u32, regs: &dyn UDbgRegs) -> bool {
+        let pc = regs
+            .get_reg(regid::COMM_REG_PC)
+            .map(|v| v.as_int())
+            .unwrap_or(0);
+        let values = trace_regs(arch)
+            .into_iter()
+            .filter_map(|id| Some((id, regs.get_reg(id)?.as_int() as u64)))
+            .collect::<Vec<_>>();
+
+        let mut step = TraceStep {
+            pc,
+            ..Default::default()
+        };
+        for (id, value) in values {
+            if self.last.insert(id, value) != Some(value) {
+                step.regs.push((id, value));
+            }
+        }
+        for (address, old) in core::mem::take(&mut self.pending) {
+            let new = target.read_bytes(address, old.len());
+            if new.len() == old.len() && new != old {
+                step.writes.push(MemWrite { address, old, new });
+            }
+        }
+        if step.write_to(&mut self.file).log_error("itrace").is_none() {
+            self.tid = None;
+            return false;
+        }
+        self.steps += 1;
+
+        for (address, size) in sys::mem_writes(target, pc, &self.last) {
+            let old = target.read_bytes(address, size.min(MAX_WRITE));
+            if !old.is_empty() {
+                self.pending.push((address, old));
+            }
+        }
+        true
+    }
+}
+
+impl Drop for TraceRecorder {
+    fn drop(&mut self) {
+        self.file.flush().log_error("itrace");
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod sys {
+    use super::*;
+    use crate::register::get_regid;
+
+    use iced_x86::{Decoder, DecoderOptions, InstructionInfoFactory, OpAccess, Register};
+
+    /// The memory to be written by the instruction at `pc`, in address and size
+    pub fn mem_writes(
+        target: &dyn UDbgTarget,
+        pc: usize,
+        regs: &HashMap<u32, u64>,
+    ) -> Vec<(usize, usize)> {
+        let buffer = crate::disasm::read_code(target, pc, MAX_INSN_SIZE);
+        let ptr32 = target.base().is_ptr32();
+        let bitness = if ptr32 { 32 } else { 64 };
+        let insn = Decoder::with_ip(bitness, &buffer, pc as u64, DecoderOptions::NONE).decode();
+        if insn.is_invalid() {
+            return vec![];
+        }
+
+        let value = |reg: Register, _: usize, _: usize| -> Option<u64> {
+            match reg {
+                Register::FS | Register::GS => None,
+                r if r.is_segment_register() => Some(0),
+                r => {
+                    let full = if ptr32 {
+                        r.full_register32()
+                    } else {
+                        r.full_register()
+                    };
+                    let value = *regs.get(&get_regid(&format!("{full:?}").to_lowercase())?)?;
+                    Some(match r.size() {
+                        4 => value & 0xFFFF_FFFF,
+                        2 => value & 0xFFFF,
+                        _ => value,
+                    })
+                }
+            }
+        };
+        let mut factory = InstructionInfoFactory::new();
+        factory
+            .info(&insn)
+            .used_memory()
+            .iter()
+            .filter(|m| {
+                matches!(
+                    m.access(),
+                    OpAccess::Write
+                        | OpAccess::CondWrite
+                        | OpAccess::ReadWrite
+                        | OpAccess::ReadCondWrite
+                )
+            })
+            .filter_map(|m| {
+                let address = m.virtual_address(0, value)?;
+                Some((address as usize, m.memory_size().size()))
+            })
+            .filter(|&(_, size)| size > 0)
+            .collect()
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+mod sys {
+    use super::*;
+
+    pub fn mem_writes(
+        _target: &dyn UDbgTarget,
+        _pc: usize,
+        _regs: &HashMap<u32, u64>,
+    ) -> Vec<(usize, usize)> {
+        vec![]
+    }
+}
+
+/// Time-travel inspection of a recorded trace
+pub struct TraceReader {
+    arch: u32,
+    steps: Vec<TraceStep>,
+    /// Current position, the instruction at `steps[pos].pc` is about to execute
+    pos: usize,
+    regs: HashMap<u32, u64>,
+    /// The bytes ever written in the trace, with their value at the position
+    memory: BTreeMap<usize, u8>,
+}
+
+impl TraceReader {
+    pub fn open(path: impl AsRef<Path>) -> UDbgResult<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 12];
+        file.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err("not an instruction trace".into());
+        }
+        let arch = u32::from_le_bytes(header[8..].try_into().unwrap());
+        let mut steps = vec![];
+        loop {
+            match TraceStep::read_from(&mut file) {
+                Ok(Some(step)) => steps.push(step),
+                Ok(None) => break,
+                // the recording was interrupted
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    warn!("itrace: truncated after {} steps", steps.len());
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if steps.is_empty() {
+            return Err("empty trace".into());
+        }
+
+        // the memory starts with the old bytes of the first writes
+        let mut memory = BTreeMap::new();
+        for m in steps.iter().flat_map(|s| s.writes.iter()) {
+            for (i, &b) in m.old.iter().enumerate() {
+                memory.entry(m.address + i).or_insert(b);
+            }
+        }
+        let mut this = Self {
+            arch,
+            steps,
+            pos: 0,
+            regs: HashMap::new(),
+            memory,
+        };
+        this.apply(0);
+        Ok(this)
+    }
+
+    #[inline]
+    pub fn arch(&self) -> u32 {
+        self.arch
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    #[inline]
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    #[inline]
+    pub fn current(&self) -> &TraceStep {
+        &self.steps[self.pos]
+    }
+
+    #[inline]
+    pub fn steps(&self) -> &[TraceStep] {
+        &self.steps
+    }
+
+    #[inline]
+    pub fn pc(&self) -> usize {
+        self.current().pc
+    }
+
+    /// Value of register at the position by name
+    pub fn reg(&self, name: &str) -> Option<u64> {
+        let &(_, id) = general_regs(self.arch).iter().find(|r| r.0 == name)?;
+        self.reg_by_id(id)
+    }
+
+    #[inline]
+    pub fn reg_by_id(&self, id: u32) -> Option<u64> {
+        self.regs.get(&id).copied()
+    }
+
+    /// Read the memory at the position, None if any byte is never written in the trace
+    pub fn read_memory(&self, address: usize, size: usize) -> Option<Vec<u8>> {
+        (address..address + size)
+            .map(|a| self.memory.get(&a).copied())
+            .collect()
+    }
+
+    /// Move to the position, which is clamped to the last step
+    pub fn seek(&mut self, pos: usize) {
+        let pos = pos.min(self.steps.len() - 1);
+        while self.pos < pos {
+            self.pos += 1;
+            self.apply(self.pos);
+        }
+        while self.pos > pos {
+            self.step_back();
+        }
+    }
+
+    /// Execute the instruction at the position, false at the end
+    pub fn step(&mut self) -> bool {
+        if self.pos + 1 >= self.steps.len() {
+            return false;
+        }
+        self.pos += 1;
+        self.apply(self.pos);
+        true
+    }
+
+    /// Undo the previous instruction, false at the beginning
+    pub fn step_back(&mut self) -> bool {
+        if self.pos == 0 {
+            return false;
+        }
+        let step = &self.steps[self.pos];
+        for m in step.writes.iter() {
+            for (i, &b) in m.old.iter().enumerate() {
+                self.memory.insert(m.address + i, b);
+            }
+        }
+        for &(id, _) in step.regs.iter() {
+            let prev = self.steps[..self.pos]
+                .iter()
+                .rev()
+                .find_map(|s| s.regs.iter().find(|r| r.0 == id).map(|r| r.1));
+            match prev {
+                Some(value) => self.regs.insert(id, value),
+                None => self.regs.remove(&id),
+            };
+        }
+        self.pos -= 1;
+        true
+    }
+
+    /// Find the next position executing at the address, backward if `back`
+    pub fn find_pc(&self, address: usize, back: bool) -> Option<usize> {
+        if back {
+            self.steps[..self.pos].iter().rposition(|s| s.pc == address)
+        } else {
+            self.steps[self.pos + 1..]
+                .iter()
+                .position(|s| s.pc == address)
+                .map(|i| i + self.pos + 1)
+        }
+    }
+
+    fn apply(&mut self, pos: usize) {
+        let step = &self.steps[pos];
+        for m in step.writes.iter() {
+            for (i, &b) in m.new.iter().enumerate() {
+                self.memory.insert(m.address + i, b);
+            }
+        }
+        for &(id, value) in step.regs.iter() {
+            self.regs.insert(id, value);
+        }
+    }
+}
@@ -0,0 +1,478 @@
+//!
+//! Structure layout inferred from the accesses to an object: the offsets and sizes accessed, and
+//! what the fields look like by their values, exported as a C skeleton or the types of
+//! [`SymbolFile`].
+//!
+//! The accesses come from an instruction trace of [`crate::itrace`], decoded by iced on x86, or
+//! from the hits of [`crate::memwatch`] on the object. The fields of pointer size holding the
+//! addresses of mapped memory are taken as pointers, and as function pointers if the memory is
+//! executable. An offset accessed by different sizes gets the largest one, the accesses overlapping
+//! a previous field are kept but left out of the skeleton, they are likely unions or `memcpy`.
+//!
+
+use crate::{
+    itrace::TraceReader,
+    memwatch::{MemWatchHit, WatchAccess},
+    prelude::*,
+    register::general_regs,
+};
+
+use core::fmt::Write;
+use iced_x86::{
+    Decoder, DecoderOptions, Instruction, InstructionInfoFactory, MemorySize, OpAccess, Register,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// The distinct values kept for each field
+const MAX_VALUES: usize = 16;
+const MAX_X86_INSN: usize = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldKind {
+    Integer,
+    Float,
+    Pointer,
+    FunctionPointer,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InferredField {
+    pub offset: usize,
+    pub size: usize,
+    pub kind: FieldKind,
+    pub reads: usize,
+    pub writes: usize,
+    /// The instructions accessing it
+    pub sites: Vec<usize>,
+    /// Some of the values seen
+    pub values: Vec<u64>,
+    /// Overlaps the previous field
+    pub overlapped: bool,
+}
+
+impl InferredField {
+    fn c_type(&self) -> Option<&'static str> {
+        Some(match (self.kind, self.size) {
+            (FieldKind::Pointer, _) => "void *",
+            (FieldKind::Float, 4) => "float",
+            (FieldKind::Float, 8) => "double",
+            (_, 1) => "uint8_t",
+            (_, 2) => "uint16_t",
+            (_, 4) => "uint32_t",
+            (_, 8) => "uint64_t",
+            _ => return None,
+        })
+    }
+
+    /// Declaration in C, such as `uint32_t field_8`
+    pub fn declaration(&self) -> String {
+        let name = format!("field_{:x}", self.offset);
+        if self.kind == FieldKind::FunctionPointer {
+            return format!("void (*{name})(void)");
+        }
+        match self.c_type() {
+            Some(ty) if ty.ends_with('*') => format!("{ty}{name}"),
+            Some(ty) => format!("{ty} {name}"),
+            None => format!("uint8_t {name}[{}]", self.size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StructLayout {
+    pub name: String,
+    /// Address of the object observed
+    pub base: usize,
+    /// Size by the last field accessed
+    pub size: usize,
+    pub fields: Vec<InferredField>,
+}
+
+impl StructLayout {
+    /// The skeleton in C, the gaps are filled by the `_pad` arrays
+    pub fn to_c(&self) -> String {
+        let mut result = format!("struct {} {{\n", self.name);
+        let mut end = 0;
+        for f in self.fields.iter() {
+            if f.overlapped {
+                writeln!(result, "    // 0x{:x}: {};", f.offset, f.declaration()).ok();
+                continue;
+            }
+            if f.offset > end {
+                writeln!(result, "    uint8_t _pad_{end:x}[0x{:x}];", f.offset - end).ok();
+            }
+            writeln!(
+                result,
+                "    {}; // 0x{:x} r{} w{}",
+                f.declaration(),
+                f.offset,
+                f.reads,
+                f.writes
+            )
+            .ok();
+            end = f.offset + f.size;
+        }
+        result.push_str("};\n");
+        result
+    }
+}
+
+#[derive(Default)]
+struct Accesses {
+    size: usize,
+    float: bool,
+    reads: usize,
+    writes: usize,
+    sites: Vec<usize>,
+    values: Vec<u64>,
+}
+
+/// Collect the accesses to an object and infer its layout
+pub struct LayoutInference {
+    /// Address of the object
+    pub base: usize,
+    /// The accesses out of `base..base + max_size` are ignored
+    pub max_size: usize,
+    accesses: BTreeMap<usize, Accesses>,
+}
+
+impl LayoutInference {
+    pub fn new(base: usize, max_size: usize) -> Self {
+        Self {
+            base,
+            max_size,
+            accesses: Default::default(),
+        }
+    }
+
+    /// Record an access to `address`, returns false if it's out of the object
+    pub fn record(
+        &mut self,
+        address: usize,
+        size: usize,
+        write: bool,
+        pc: usize,
+        value: Option<u64>,
+    ) -> bool {
+        let offset = match address.checked_sub(self.base) {
+            Some(offset) if offset < self.max_size && size > 0 => offset,
+            _ => return false,
+        };
+        let a = self.accesses.entry(offset).or_default();
+        a.size = a.size.max(size.min(self.max_size - offset));
+        if write {
+            a.writes += 1;
+        } else {
+            a.reads += 1;
+        }
+        if !a.sites.contains(&pc) {
+            a.sites.push(pc);
+        }
+        if let Some(value) = value {
+            if a.values.len() < MAX_VALUES && !a.values.contains(&value) {
+                a.values.push(value);
+            }
+        }
+        true
+    }
+
+    fn record_insn(
+        &mut self,
+        insn: &Instruction,
+        factory: &mut InstructionInfoFactory,
+        value: impl Fn(Register, usize, usize) -> Option<u64> + Copy,
+        written: impl Fn(usize, usize) -> Option<u64>,
+    ) {
+        for m in factory.info(insn).used_memory() {
+            let address = match m.virtual_address(0, value) {
+                Some(address) => address as usize,
+                None => continue,
+            };
+            let size = m.memory_size().size();
+            let float = matches!(m.memory_size(), MemorySize::Float32 | MemorySize::Float64);
+            let (read, write) = match m.access() {
+                OpAccess::Read | OpAccess::CondRead => (true, false),
+                OpAccess::Write | OpAccess::CondWrite => (false, true),
+                OpAccess::ReadWrite | OpAccess::ReadCondWrite => (true, true),
+                _ => continue,
+            };
+            let pc = insn.ip() as usize;
+            if read {
+                self.record(address, size, false, pc, None);
+            }
+            if write {
+                self.record(address, size, true, pc, written(address, size));
+            }
+            if float {
+                if let Some(a) = address
+                    .checked_sub(self.base)
+                    .and_then(|o| self.accesses.get_mut(&o))
+                {
+                    a.float = true;
+                }
+            }
+        }
+    }
+
+    /// Collect the accesses from the trace of `reader`, from its current position, the code is
+    /// read from `code`
+    pub fn add_trace<T: ReadMemory + ?Sized>(
+        &mut self,
+        reader: &TraceReader,
+        code: &T,
+    ) -> UDbgResult<()> {
+        let arch = reader.arch();
+        let bitness = match arch {
+            ARCH_X86 => 32,
+            ARCH_X64 => 64,
+            _ => return Err(UDbgError::NotSupport),
+        };
+        let reg_ids = general_regs(arch)
+            .iter()
+            .copied()
+            .collect::<HashMap<_, _>>();
+
+        let steps = reader.steps();
+        let start = reader.position();
+        let mut regs = HashMap::new();
+        for s in steps[..=start].iter() {
+            regs.extend(s.regs.iter().copied());
+        }
+        let mut factory = InstructionInfoFactory::new();
+        for position in start..steps.len() {
+            if position > start {
+                regs.extend(steps[position].regs.iter().copied());
+            }
+            let pc = steps[position].pc;
+            let bytes = code.read_bytes(pc, MAX_X86_INSN);
+            let insn = Decoder::with_ip(bitness, &bytes, pc as u64, DecoderOptions::NONE).decode();
+            if insn.is_invalid() {
+                continue;
+            }
+            let value = |reg: Register, _: usize, _: usize| -> Option<u64> {
+                match reg {
+                    Register::FS | Register::GS => None,
+                    r if r.is_segment_register() => Some(0),
+                    r => {
+                        let full = if bitness == 32 {
+                            r.full_register32()
+                        } else {
+                            r.full_register()
+                        };
+                        let name = format!("{full:?}").to_lowercase();
+                        let value = *regs.get(reg_ids.get(name.as_str())?)?;
+                        Some(match r.size() {
+                            4 => value & 0xFFFF_FFFF,
+                            2 => value & 0xFFFF,
+                            _ => value,
+                        })
+                    }
+                }
+            };
+            // the effects of an instruction are in the next record
+            let written = |address: usize, size: usize| {
+                let w = steps
+                    .get(position + 1)?
+                    .writes
+                    .iter()
+                    .find(|w| w.address == address && w.new.len() >= size)?;
+                let mut buf = [0u8; 8];
+                let len = size.min(8);
+                buf[..len].copy_from_slice(&w.new[..len]);
+                Some(u64::from_le_bytes(buf))
+            };
+            self.record_insn(&insn, &mut factory, value, written);
+        }
+        Ok(())
+    }
+
+    /// Record a hit of memory watch on the object, the size of access is decoded from the
+    /// instruction on x86, one byte elsewhere
+    pub fn add_watch_hit<T: UDbgTarget + ?Sized>(&mut self, target: &T, hit: &MemWatchHit) {
+        let write = hit.access == WatchAccess::Write;
+        let size = if cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+            let bitness = if target.base().is_ptr32() { 32 } else { 64 };
+            let bytes = target.read_bytes(hit.pc, MAX_X86_INSN);
+            let insn =
+                Decoder::with_ip(bitness, &bytes, hit.pc as u64, DecoderOptions::NONE).decode();
+            let mut factory = InstructionInfoFactory::new();
+            let size = factory
+                .info(&insn)
+                .used_memory()
+                .iter()
+                .map(|m| m.memory_size().size())
+                .max();
+            size.unwrap_or(1)
+        } else {
+            1
+        };
+        self.record(hit.address, size, write, hit.pc, None);
+    }
+
+    /// Infer the layout, the current values of fields in `target` are added to the values seen
+    pub fn finish<T: UDbgTarget + ?Sized>(&self, target: &T, name: &str) -> StructLayout {
+        let pointer_size = target.base().pointer_size();
+        let mut fields = Vec::<InferredField>::new();
+        let mut end = 0;
+        for (&offset, a) in self.accesses.iter() {
+            let mut values = a.values.clone();
+            if a.size <= 8 {
+                let mut buf = [0u8; 8];
+                if target
+                    .read_memory(self.base + offset, &mut buf[..a.size])
+                    .map_or(false, |r| r.len() == a.size)
+                {
+                    let value = u64::from_le_bytes(buf);
+                    if !values.contains(&value) {
+                        values.push(value);
+                    }
+                }
+            }
+
+            let pages = values
+                .iter()
+                .filter(|&&v| v != 0)
+                .map(|&v| target.virtual_query(v as usize))
+                .collect::<Vec<_>>();
+            let kind = if a.float {
+                FieldKind::Float
+            } else if a.size == pointer_size
+                && !pages.is_empty()
+                && pages.iter().all(Option::is_some)
+            {
+                if pages.iter().flatten().all(|p| p.is_executable()) {
+                    FieldKind::FunctionPointer
+                } else {
+                    FieldKind::Pointer
+                }
+            } else {
+                FieldKind::Integer
+            };
+            fields.push(InferredField {
+                offset,
+                size: a.size,
+                kind,
+                reads: a.reads,
+                writes: a.writes,
+                sites: a.sites.clone(),
+                values,
+                overlapped: offset < end,
+            });
+            end = end.max(offset + a.size);
+        }
+        StructLayout {
+            name: name.into(),
+            base: self.base,
+            size: end,
+            fields,
+        }
+    }
+}
+
+/// The inferred layouts as a [`SymbolFile`], the type id of a structure is its index plus 1, the
+/// field types are the primitive types with [`PRIMITIVE_TYPE`] set in id
+pub struct InferredTypes {
+    pub layouts: Vec<StructLayout>,
+}
+
+/// Flag of the ids of the primitive types in [`InferredTypes`], with the [`FieldKind`] in bits 8
+/// to 15 and the size in bits 0 to 7, a larger one is an array of bytes
+pub const PRIMITIVE_TYPE: u32 = 0x8000_0000;
+
+impl InferredTypes {
+    fn primitive_id(field: &InferredField) -> u32 {
+        let kind = match field.kind {
+            FieldKind::Integer => 0,
+            FieldKind::Float => 1,
+            FieldKind::Pointer => 2,
+            FieldKind::FunctionPointer => 3,
+        };
+        PRIMITIVE_TYPE | kind << 8 | field.size.min(0xFF) as u32
+    }
+
+    fn layout(&self, id: u32) -> Option<&StructLayout> {
+        self.layouts.get((id as usize).checked_sub(1)?)
+    }
+}
+
+impl SymbolFile for InferredTypes {
+    fn path(&self) -> &str {
+        "<inferred>"
+    }
+
+    fn global(&self) -> anyhow::Result<Arc<SymbolMap>> {
+        Ok(Arc::new(SymbolMap(Default::default())))
+    }
+
+    fn find_type(&self, name: &str) -> Vec<TypeInfo> {
+        self.layouts
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.name == name)
+            .filter_map(|(i, _)| self.get_type(i as u32 + 1))
+            .collect()
+    }
+
+    fn get_type(&self, id: u32) -> Option<TypeInfo> {
+        if id & PRIMITIVE_TYPE == 0 {
+            let layout = self.layout(id)?;
+            return Some(TypeInfo {
+                id,
+                name: layout.name.clone(),
+                kind: TypeKind::Class {
+                    fields: Some(id),
+                    vtable: None,
+                    derive: None,
+                    size: layout.size.min(u16::MAX as usize) as u16,
+                },
+            });
+        }
+        let size = id & 0xFF;
+        let field = InferredField {
+            offset: 0,
+            size: size as usize,
+            kind: match (id >> 8) & 0xFF {
+                0 => FieldKind::Integer,
+                1 => FieldKind::Float,
+                2 => FieldKind::Pointer,
+                3 => FieldKind::FunctionPointer,
+                _ => return None,
+            },
+            reads: 0,
+            writes: 0,
+            sites: vec![],
+            values: vec![],
+            overlapped: false,
+        };
+        let kind = match field.c_type() {
+            Some(_) => TypeKind::Primitive {
+                pointer: matches!(field.kind, FieldKind::Pointer | FieldKind::FunctionPointer),
+            },
+            None => TypeKind::Array {
+                tid: PRIMITIVE_TYPE | 1,
+                dimensions: vec![size],
+            },
+        };
+        let name = match field.kind {
+            FieldKind::FunctionPointer => "void (*)(void)".into(),
+            _ => field.c_type().unwrap_or("uint8_t").trim_end().to_string(),
+        };
+        Some(TypeInfo { id, name, kind })
+    }
+
+    fn get_field(&self, id: u32, index: usize) -> Option<FieldInfo> {
+        let field = self
+            .layout(id)?
+            .fields
+            .iter()
+            .filter(|f| !f.overlapped)
+            .nth(index)?;
+        Some(FieldInfo {
+            type_id: Self::primitive_id(field),
+            offset: field.offset as u32,
+            name: format!("field_{:x}", field.offset),
+        })
+    }
+}
@@ -0,0 +1,358 @@
+//!
+//! Branch history of threads by the Last Branch Record of CPU, available with the `lbr` feature.
+//!
+//! On Linux the branches are sampled by perf with the branch stack, into an overwritable ring,
+//! the newest sample is taken at most [`LbrOptions::period`] branches before the thread stops.
+//! On Windows x64 the LBR is enabled by `CONTEXT.DebugControl`, which only keeps the last branch
+//! and the last exception branch.
+//!
+
+use crate::prelude::*;
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct LbrOptions {
+    /// Branches per sample on Linux, 1 gives the exact last branches but slows down target
+    pub period: u64,
+    /// Pages of the perf ring on Linux, must be a power of 2
+    pub pages: usize,
+}
+
+impl Default for LbrOptions {
+    fn default() -> Self {
+        Self {
+            period: 1,
+            pages: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BranchRecord {
+    pub from: usize,
+    pub to: usize,
+    pub mispredicted: bool,
+}
+
+/// LBR capture of a thread
+pub struct LbrSession {
+    pub tid: tid_t,
+    inner: sys::Session,
+}
+
+impl LbrSession {
+    pub fn open<T: UDbgTarget + ?Sized>(
+        target: &T,
+        tid: tid_t,
+        opts: &LbrOptions,
+    ) -> UDbgResult<Self> {
+        Ok(Self {
+            tid,
+            inner: sys::Session::open(target, tid, opts)?,
+        })
+    }
+
+    /// The last branches of thread, from the newest
+    pub fn branches(&self) -> UDbgResult<Vec<BranchRecord>> {
+        self.inner.branches()
+    }
+}
+
+/// LBR capture of all threads in target, so the branches are available at each stop
+pub struct LbrCapture {
+    opts: LbrOptions,
+    sessions: HashMap<tid_t, LbrSession>,
+}
+
+impl LbrCapture {
+    /// Open the sessions of the existing threads
+    pub fn new<T: UDbgTarget + ?Sized>(target: &T, opts: LbrOptions) -> UDbgResult<Self> {
+        let mut result = Self {
+            opts,
+            sessions: HashMap::new(),
+        };
+        for t in target.enum_thread(false)? {
+            result.attach(target, t.tid)?;
+        }
+        Ok(result)
+    }
+
+    pub fn attach<T: UDbgTarget + ?Sized>(&mut self, target: &T, tid: tid_t) -> UDbgResult<()> {
+        let session = LbrSession::open(target, tid, &self.opts)?;
+        self.sessions.insert(tid, session);
+        Ok(())
+    }
+
+    /// Follow the thread creations and exits from the debug loop
+    pub fn on_event<T: UDbgTarget + ?Sized>(&mut self, target: &T, event: &UEvent) {
+        match *event {
+            UEvent::ThreadCreate(tid) => {
+                self.attach(target, tid)
+                    .log_error_with(|err| format!("lbr of ~{tid}: {err:?}"));
+            }
+            UEvent::ThreadExit(_) => {
+                self.sessions.remove(&target.base().event_tid.get());
+            }
+            _ => {}
+        }
+    }
+
+    /// The last branches of thread, from the newest
+    pub fn branches(&self, tid: tid_t) -> UDbgResult<Vec<BranchRecord>> {
+        self.sessions
+            .get(&tid)
+            .ok_or(UDbgError::NotFound)?
+            .branches()
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use super::*;
+
+    use core::sync::atomic::{fence, Ordering};
+    use std::fs::File;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    const PERF_TYPE_HARDWARE: u32 = 0;
+    const PERF_COUNT_HW_BRANCH_INSTRUCTIONS: u64 = 4;
+    const PERF_SAMPLE_IP: u64 = 1 << 0;
+    const PERF_SAMPLE_BRANCH_STACK: u64 = 1 << 11;
+    const PERF_SAMPLE_BRANCH_USER: u64 = 1 << 0;
+    const PERF_SAMPLE_BRANCH_ANY: u64 = 1 << 3;
+    const PERF_RECORD_SAMPLE: u32 = 9;
+
+    const FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+    const FLAG_EXCLUDE_HV: u64 = 1 << 6;
+    const FLAG_WRITE_BACKWARD: u64 = 1 << 27;
+
+    /// `struct perf_event_attr`, PERF_ATTR_SIZE_VER5
+    #[repr(C)]
+    #[derive(Default)]
+    struct PerfEventAttr {
+        ty: u32,
+        size: u32,
+        config: u64,
+        sample_period: u64,
+        sample_type: u64,
+        read_format: u64,
+        flags: u64,
+        wakeup_events: u32,
+        bp_type: u32,
+        config1: u64,
+        config2: u64,
+        branch_sample_type: u64,
+        sample_regs_user: u64,
+        sample_stack_user: u32,
+        clockid: i32,
+        sample_regs_intr: u64,
+        aux_watermark: u32,
+        sample_max_stack: u16,
+        reserved: u16,
+    }
+
+    /// Offset of `data_head` in `struct perf_event_mmap_page`
+    const DATA_HEAD_OFFSET: usize = 1024;
+
+    pub struct Session {
+        _file: File,
+        map: *mut u8,
+        map_size: usize,
+        page_size: usize,
+    }
+
+    unsafe impl Send for Session {}
+    unsafe impl Sync for Session {}
+
+    impl Session {
+        pub fn open<T: UDbgTarget + ?Sized>(
+            _target: &T,
+            tid: tid_t,
+            opts: &LbrOptions,
+        ) -> UDbgResult<Self> {
+            if !opts.pages.is_power_of_two() {
+                return Err("pages must be a power of 2".into());
+            }
+            let attr = PerfEventAttr {
+                ty: PERF_TYPE_HARDWARE,
+                size: core::mem::size_of::<PerfEventAttr>() as u32,
+                config: PERF_COUNT_HW_BRANCH_INSTRUCTIONS,
+                sample_period: opts.period.max(1),
+                sample_type: PERF_SAMPLE_IP | PERF_SAMPLE_BRANCH_STACK,
+                flags: FLAG_EXCLUDE_KERNEL | FLAG_EXCLUDE_HV | FLAG_WRITE_BACKWARD,
+                branch_sample_type: PERF_SAMPLE_BRANCH_USER | PERF_SAMPLE_BRANCH_ANY,
+                ..Default::default()
+            };
+            unsafe {
+                let fd = libc::syscall(
+                    libc::SYS_perf_event_open,
+                    &attr as *const PerfEventAttr,
+                    tid,
+                    -1,
+                    -1,
+                    0,
+                );
+                if fd < 0 {
+                    return Err(UDbgError::system());
+                }
+                let file = File::from_raw_fd(fd as i32);
+                let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+                let map_size = page_size * (opts.pages + 1);
+                // read-only mapping makes the ring overwritable
+                let map = libc::mmap(
+                    core::ptr::null_mut(),
+                    map_size,
+                    libc::PROT_READ,
+                    libc::MAP_SHARED,
+                    file.as_raw_fd(),
+                    0,
+                );
+                if map == libc::MAP_FAILED {
+                    return Err(UDbgError::system());
+                }
+                Ok(Self {
+                    _file: file,
+                    map: map.cast(),
+                    map_size,
+                    page_size,
+                })
+            }
+        }
+
+        fn data_size(&self) -> usize {
+            self.map_size - self.page_size
+        }
+
+        /// Copy from the ring, `offset` wraps around
+        fn read(&self, offset: u64, buf: &mut [u8]) {
+            let size = self.data_size();
+            for (i, b) in buf.iter_mut().enumerate() {
+                let pos = (offset as usize).wrapping_add(i) % size;
+                *b = unsafe { *self.map.add(self.page_size + pos) };
+            }
+        }
+
+        fn read_u64(&self, offset: u64) -> u64 {
+            let mut buf = [0u8; 8];
+            self.read(offset, &mut buf);
+            u64::from_ne_bytes(buf)
+        }
+
+        pub fn branches(&self) -> UDbgResult<Vec<BranchRecord>> {
+            let head =
+                unsafe { core::ptr::read_volatile(self.map.add(DATA_HEAD_OFFSET) as *const u64) };
+            fence(Ordering::Acquire);
+
+            // the ring is written backward, so the newest record starts at head
+            let mut offset = head;
+            let mut walked = 0;
+            while head != 0 && walked < self.data_size() {
+                let mut header = [0u8; 8];
+                self.read(offset, &mut header);
+                let ty = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+                let size = u16::from_ne_bytes([header[6], header[7]]) as u64;
+                if size == 0 {
+                    break;
+                }
+                if ty == PERF_RECORD_SAMPLE {
+                    // header, ip, nr, then nr * (from, to, flags)
+                    let nr = self.read_u64(offset + 16).min(size / 24);
+                    return Ok((0..nr)
+                        .map(|i| {
+                            let entry = offset + 24 + i * 24;
+                            BranchRecord {
+                                from: self.read_u64(entry) as usize,
+                                to: self.read_u64(entry + 8) as usize,
+                                mispredicted: self.read_u64(entry + 16) & 1 != 0,
+                            }
+                        })
+                        .collect());
+                }
+                offset += size;
+                walked += size as usize;
+            }
+            Ok(vec![])
+        }
+    }
+
+    impl Drop for Session {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.map.cast(), self.map_size);
+            }
+        }
+    }
+}
+
+#[cfg(all(windows, target_arch = "x86_64"))]
+mod sys {
+    use super::*;
+    use crate::os::windows::Align16;
+
+    /// The LBR bit of DebugCtl
+    const DEBUG_CONTROL_LBR: u64 = 1;
+
+    pub struct Session {
+        thread: Box<dyn UDbgThread>,
+    }
+
+    impl Session {
+        pub fn open<T: UDbgTarget + ?Sized>(
+            target: &T,
+            tid: tid_t,
+            _opts: &LbrOptions,
+        ) -> UDbgResult<Self> {
+            let thread = target.open_thread(tid)?;
+            let mut cx = Align16::<ThreadContext>::new();
+            let context = cx.as_mut();
+            thread.get_context(context)?;
+            context.DebugControl |= DEBUG_CONTROL_LBR;
+            thread.set_context(context)?;
+            Ok(Self { thread })
+        }
+
+        pub fn branches(&self) -> UDbgResult<Vec<BranchRecord>> {
+            let mut cx = Align16::<ThreadContext>::new();
+            let context = cx.as_mut();
+            self.thread.get_context(context)?;
+            Ok([
+                (context.LastBranchFromRip, context.LastBranchToRip),
+                (context.LastExceptionFromRip, context.LastExceptionToRip),
+            ]
+            .into_iter()
+            .filter(|&(from, to)| from != 0 || to != 0)
+            .map(|(from, to)| BranchRecord {
+                from: from as usize,
+                to: to as usize,
+                mispredicted: false,
+            })
+            .collect())
+        }
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    all(windows, target_arch = "x86_64")
+)))]
+mod sys {
+    use super::*;
+
+    pub struct Session;
+
+    impl Session {
+        pub fn open<T: UDbgTarget + ?Sized>(
+            _target: &T,
+            _tid: tid_t,
+            _opts: &LbrOptions,
+        ) -> UDbgResult<Self> {
+            Err(UDbgError::NotSupport)
+        }
+
+        pub fn branches(&self) -> UDbgResult<Vec<BranchRecord>> {
+            Err(UDbgError::NotSupport)
+        }
+    }
+}
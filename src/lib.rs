@@ -30,25 +30,111 @@ extern crate log;
 #[macro_use]
 extern crate cstrptr;
 
+#[cfg(all(feature = "passive", any(feature = "tls-tap", feature = "dbgeng")))]
+compile_error!("feature `passive` conflicts with `tls-tap` and `dbgeng`");
+
+pub mod annotate;
+pub mod apitrace;
+pub mod audit;
+pub mod auth;
+pub mod bookmark;
+pub mod breakpad;
 pub mod breakpoint;
+pub mod callgraph;
 #[cfg(feature = "capstone")]
 pub mod capstone;
+pub mod comment;
+pub mod compare;
+pub mod coverage;
+pub mod crash;
+pub mod crypto;
+#[cfg(not(feature = "passive"))]
+pub mod dap;
+pub mod decompile;
+pub mod disasm;
+pub mod dump;
+pub mod dwarf;
 pub mod elf;
+pub mod entropy;
 pub mod error;
 pub mod event;
+pub mod exception;
+#[cfg(not(feature = "passive"))]
+pub mod fieldwatch;
+pub mod flirt;
+pub mod freeze;
+pub mod functrace;
+pub mod gfx;
+pub mod guard;
+pub mod hang;
+pub mod heap;
+#[cfg(not(feature = "passive"))]
+pub mod hook;
+pub mod hijack;
+pub mod iat;
+pub mod image;
+pub mod integrity;
+#[cfg(not(feature = "passive"))]
+pub mod inject;
+pub mod itrace;
+#[cfg(not(feature = "passive"))]
+pub mod ipc;
+pub mod layout;
+#[cfg(feature = "lbr")]
+pub mod lbr;
+#[cfg(windows)]
+pub mod loadfail;
+#[cfg(feature = "lua")]
 pub mod lua;
 pub mod memory;
+pub mod memwatch;
+pub mod metrics;
 pub mod minidump;
+pub mod modgraph;
+pub mod offline;
 pub mod os;
 pub mod pdbfile;
 pub mod pe;
+#[cfg(windows)]
+pub mod peb;
+pub mod poll;
 pub mod prelude;
+pub mod project;
+pub mod provenance;
 pub mod range;
+pub mod readonly;
+pub mod recorder;
+pub mod refs;
+pub mod regfmt;
 pub mod register;
+pub mod scan;
+pub mod session;
 pub mod shell;
+pub mod snapshot;
+pub mod space;
+#[cfg(not(feature = "passive"))]
+pub mod steptrace;
+pub mod stopbatch;
 pub mod string;
+pub mod supervisor;
 pub mod symbol;
+pub mod symfile;
+#[cfg(feature = "symproxy")]
+pub mod symproxy;
+pub mod symbolize;
+pub mod syscall;
+pub mod taint;
 pub mod target;
+pub mod throttle;
+pub mod timeline;
+#[cfg(feature = "tls-tap")]
+pub mod tls;
+pub mod transfer;
+pub mod unpack;
+pub mod unwind;
+pub mod valuesearch;
+pub mod vtable;
+pub mod waitchain;
 
 /// Constants for current environment
 pub mod consts {
@@ -63,6 +149,9 @@ pub mod consts {
     pub const ARCH_ARM: u32 = 2;
     pub const ARCH_ARM64: u32 = 3;
 
+    /// Built with the feature `passive`, the target can only be observed
+    pub const PASSIVE: bool = cfg!(feature = "passive");
+
     #[cfg(target_arch = "x86_64")]
     pub const UDBG_ARCH: u32 = ARCH_X64;
     #[cfg(target_arch = "x86")]
@@ -0,0 +1,354 @@
+//!
+//! Diagnosis of a failed `LoadLibrary` in target, telling why the library can't be loaded instead
+//! of the bare error code.
+//!
+//! The library is located as the loader would: the application directory, the system directory,
+//! the Windows directory, the current directory and `PATH`, read from the PEB of target. Its
+//! imports are walked recursively against the same search path, checking each file for the
+//! architecture of target and each named import for the export of dependency; the modules loaded
+//! already are checked in memory. The error code adds the causes which can't be seen from the
+//! files, such as the code integrity policy and the activation context.
+//!
+
+use crate::{image::RemoteImage, pe::PeHelper, peb::Peb, prelude::*};
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const ERROR_ACCESS_DENIED: u32 = 5;
+const ERROR_MOD_NOT_FOUND: u32 = 126;
+const ERROR_PROC_NOT_FOUND: u32 = 127;
+const ERROR_BAD_EXE_FORMAT: u32 = 193;
+const ERROR_EXE_MACHINE_TYPE_MISMATCH: u32 = 216;
+const ERROR_VIRUS_INFECTED: u32 = 225;
+const ERROR_VIRUS_DELETED: u32 = 226;
+const ERROR_INVALID_IMAGE_HASH: u32 = 577;
+const ERROR_DLL_INIT_FAILED: u32 = 1114;
+const ERROR_ACCESS_DISABLED_BY_POLICY: u32 = 1260;
+/// The `ERROR_SXS_*` codes of the side-by-side assemblies
+const ERROR_SXS: core::ops::RangeInclusive<u32> = 14000..=14110;
+
+/// Max depth of the dependencies walked
+const MAX_DEPTH: usize = 0x20;
+
+/// A cause of the load failure
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LoadFailure {
+    /// The library itself is not found
+    NotFound,
+    /// A dependency is not found, `chain` is from the library to the missing one
+    MissingDependency { chain: Vec<String> },
+    /// A function imported by name is not exported by the dependency
+    MissingExport {
+        importer: String,
+        module: String,
+        function: String,
+    },
+    /// A library of another architecture, such as a 64-bit DLL in a 32-bit process
+    ArchitectureMismatch {
+        path: PathBuf,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// The file is not a valid PE
+    InvalidImage { path: PathBuf, reason: String },
+    /// Rejected by the code integrity, the software restriction or the antivirus
+    BlockedByPolicy { code: u32, reason: &'static str },
+    /// The activation context can't be created, usually a missing side-by-side assembly; the
+    /// external manifest of the library if there is one
+    ActivationContext {
+        code: u32,
+        manifest: Option<PathBuf>,
+    },
+    /// The `DllMain` of the library or a dependency returned FALSE
+    InitFailed,
+    /// Nothing found for the error code
+    Unexplained { code: u32 },
+}
+
+/// The result of [`diagnose`]
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadDiagnosis {
+    pub library: String,
+    pub error: u32,
+    /// The file the loader would load, None if it's not found
+    pub path: Option<PathBuf>,
+    /// The directories searched, in order
+    pub search_path: Vec<PathBuf>,
+    /// The causes found, the ones matching the error code first
+    pub causes: Vec<LoadFailure>,
+}
+
+impl LoadFailure {
+    /// The cause matches the error code
+    fn explains(&self, code: u32) -> bool {
+        match self {
+            Self::NotFound | Self::MissingDependency { .. } => code == ERROR_MOD_NOT_FOUND,
+            Self::MissingExport { .. } => code == ERROR_PROC_NOT_FOUND,
+            Self::ArchitectureMismatch { .. } | Self::InvalidImage { .. } => {
+                code == ERROR_BAD_EXE_FORMAT || code == ERROR_EXE_MACHINE_TYPE_MISMATCH
+            }
+            _ => true,
+        }
+    }
+
+    /// The causes told by the error code only
+    fn from_code(code: u32, path: Option<&Path>) -> Option<Self> {
+        let policy = |reason| Some(Self::BlockedByPolicy { code, reason });
+        match code {
+            ERROR_INVALID_IMAGE_HASH => policy("the signature is rejected by the code integrity"),
+            ERROR_ACCESS_DISABLED_BY_POLICY => policy("blocked by the software restriction"),
+            ERROR_VIRUS_INFECTED | ERROR_VIRUS_DELETED => policy("blocked by the antivirus"),
+            ERROR_ACCESS_DENIED => policy("the file or its directory can't be accessed"),
+            ERROR_DLL_INIT_FAILED => Some(Self::InitFailed),
+            code if ERROR_SXS.contains(&code) => Some(Self::ActivationContext {
+                code,
+                manifest: path
+                    .map(|p| PathBuf::from(format!("{}.manifest", p.display())))
+                    .filter(|p| p.is_file()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn is_api_set(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.starts_with("api-ms-") || name.starts_with("ext-ms-")
+}
+
+/// The file name the loader looks for, `.dll` is appended if there's no extension
+fn file_name(library: &str) -> String {
+    match library.strip_suffix('.') {
+        Some(name) => name.to_string(),
+        None if Path::new(library).extension().is_none() => format!("{library}.dll"),
+        None => library.to_string(),
+    }
+}
+
+/// The search path of the loader in safe search mode, and the current directory of target
+fn search_path<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<(Vec<PathBuf>, PathBuf)> {
+    let peb = Peb::of(target)?;
+    let params = peb.process_parameters()?;
+    let env = peb.environment().unwrap_or_default();
+    let var = |name: &str| {
+        env.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+    };
+    let windows = PathBuf::from(var("SystemRoot").unwrap_or_else(|| "C:\\Windows".into()));
+    // the 32-bit PEB is read for a WOW64 target
+    let system = if cfg!(target_pointer_width = "64") && peb.is_32bit() {
+        "SysWOW64"
+    } else {
+        "System32"
+    };
+    let current = PathBuf::from(&params.current_directory);
+
+    let mut result = params
+        .dll_path
+        .split(';')
+        .filter(|s| !s.is_empty())
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    result.extend(
+        Path::new(&params.image_path)
+            .parent()
+            .map(Path::to_path_buf),
+    );
+    result.push(windows.join(system));
+    result.push(windows.clone());
+    result.push(current.clone());
+    for dir in var("Path").unwrap_or_default().split(';') {
+        if !dir.is_empty() {
+            result.push(dir.into());
+        }
+    }
+    let mut seen = HashSet::new();
+    result.retain(|d| seen.insert(d.to_string_lossy().to_ascii_lowercase()));
+    Ok((result, current))
+}
+
+struct Walker<'a, T: ?Sized> {
+    target: &'a T,
+    search_path: &'a [PathBuf],
+    expected: Option<&'static str>,
+    visited: HashSet<String>,
+    /// The exports of dependencies by lowercase name, None if they can't be read
+    exports: HashMap<String, Option<HashSet<String>>>,
+    causes: Vec<LoadFailure>,
+}
+
+impl<T: UDbgTarget + ?Sized> Walker<'_, T> {
+    fn find(&self, name: &str) -> Option<PathBuf> {
+        self.search_path
+            .iter()
+            .map(|d| d.join(name))
+            .find(|p| p.is_file())
+    }
+
+    fn loaded(&self, name: &str) -> Option<Arc<dyn UDbgModule>> {
+        let stem = name.rsplit_once('.').map_or(name, |(s, _)| s);
+        self.target
+            .get_module(name)
+            .or_else(|| self.target.get_module(stem))
+    }
+
+    /// Check the file at the end of `chain` and its dependencies not loaded
+    fn walk(&mut self, path: &Path, chain: &mut Vec<String>) -> Option<HashSet<String>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) => {
+                self.causes.push(LoadFailure::InvalidImage {
+                    path: path.into(),
+                    reason: err.to_string(),
+                });
+                return None;
+            }
+        };
+        let pe = match PeHelper::parse(&data) {
+            Ok(pe) => pe,
+            Err(err) => {
+                self.causes.push(LoadFailure::InvalidImage {
+                    path: path.into(),
+                    reason: err.to_string(),
+                });
+                return None;
+            }
+        };
+        if let (Some(expected), found) = (self.expected, pe.get_arch().unwrap_or("unknown")) {
+            if found != expected {
+                self.causes.push(LoadFailure::ArchitectureMismatch {
+                    path: path.into(),
+                    expected,
+                    found,
+                });
+            }
+        }
+        let exports = pe
+            .exports
+            .iter()
+            .filter_map(|e| e.name.map(str::to_string))
+            .collect::<HashSet<_>>();
+        if chain.len() >= MAX_DEPTH {
+            return Some(exports);
+        }
+
+        let importer = chain.last().cloned().unwrap_or_default();
+        for dll in pe.libraries.iter().copied() {
+            if is_api_set(dll) {
+                continue;
+            }
+            chain.push(dll.to_string());
+            let exports = self.dependency(dll, chain);
+            chain.pop();
+            let exports = match exports {
+                Some(exports) => exports,
+                None => continue,
+            };
+            for import in pe
+                .imports
+                .iter()
+                .filter(|i| i.dll.eq_ignore_ascii_case(dll))
+            {
+                // the ones by ordinal are named `ORDINAL n`
+                if import.name.starts_with("ORDINAL ") || exports.contains(import.name.as_ref()) {
+                    continue;
+                }
+                self.causes.push(LoadFailure::MissingExport {
+                    importer: importer.clone(),
+                    module: dll.to_string(),
+                    function: import.name.to_string(),
+                });
+            }
+        }
+        Some(exports)
+    }
+
+    /// The exports of a dependency, which is walked at the first time
+    fn dependency(&mut self, name: &str, chain: &mut Vec<String>) -> Option<HashSet<String>> {
+        let key = name.to_ascii_lowercase();
+        if !self.visited.insert(key.clone()) {
+            return self.exports.get(&key).cloned().flatten();
+        }
+        let exports = if let Some(m) = self.loaded(name) {
+            RemoteImage::of(self.target, m.as_ref())
+                .log_error_with(|err| format!("parse {name}: {err:?}"))
+                .map(|image| image.exports().filter_map(|e| e.name).collect())
+        } else if let Some(path) = self.find(name) {
+            self.walk(&path, chain)
+        } else {
+            self.causes.push(LoadFailure::MissingDependency {
+                chain: chain.clone(),
+            });
+            None
+        };
+        self.exports.insert(key, exports.clone());
+        exports
+    }
+}
+
+/// Diagnose why `library` failed to load with the error code, which is the last error of the event
+/// thread if None
+pub fn diagnose<T: UDbgTarget + ?Sized>(
+    target: &T,
+    library: &str,
+    error: Option<u32>,
+) -> UDbgResult<LoadDiagnosis> {
+    let error = match error {
+        Some(code) => code,
+        None => target
+            .open_thread(target.base().event_tid.get())?
+            .last_error()
+            .ok_or(UDbgError::NotSupport)?,
+    };
+    let (search_path, current) = search_path(target)?;
+    let name = file_name(library);
+    let path = if name.contains(['\\', '/']) {
+        Some(current.join(&name)).filter(|p| p.is_file())
+    } else {
+        search_path
+            .iter()
+            .map(|d| d.join(&name))
+            .find(|p| p.is_file())
+    };
+
+    let expected = target
+        .find_module(target.base().image_base)
+        .map(|m| m.data().arch)
+        .filter(|a| !a.is_empty());
+    let mut walker = Walker {
+        target,
+        search_path: &search_path,
+        expected,
+        visited: HashSet::new(),
+        exports: HashMap::new(),
+        causes: vec![],
+    };
+    match &path {
+        Some(path) => {
+            let file = Path::new(&name).file_name().unwrap_or_default();
+            let file = file.to_string_lossy().to_string();
+            walker.visited.insert(file.to_ascii_lowercase());
+            walker.walk(path, &mut vec![file]);
+        }
+        None => walker.causes.push(LoadFailure::NotFound),
+    }
+
+    let mut causes = walker.causes;
+    causes.extend(LoadFailure::from_code(error, path.as_deref()));
+    // stable, the order of the walk is kept
+    causes.sort_by_key(|c| !c.explains(error));
+    if !causes.iter().any(|c| c.explains(error)) {
+        causes.insert(0, LoadFailure::Unexplained { code: error });
+    }
+    Ok(LoadDiagnosis {
+        library: library.to_string(),
+        error,
+        path,
+        search_path,
+        causes,
+    })
+}
@@ -0,0 +1,162 @@
+//! Lua snippets attached to breakpoints, executed on hit without recompiling the host
+
+use super::*;
+use crate::register::general_regs;
+
+use spin::Mutex;
+use std::collections::HashMap;
+
+/// Arguments of the hit snapshotted for `hit:arg(i)`, from 1 as `read_argument`
+const MAX_ARGS: usize = 8;
+
+#[derive(Default)]
+struct HitState {
+    regs: Vec<(&'static str, usize)>,
+    args: Vec<Option<usize>>,
+    /// Registers set by the snippet, written back after it returns
+    writes: Vec<(String, usize)>,
+    stop: bool,
+}
+
+/// The `hit` global of snippet: `hit.tid`, `hit:reg(name)`, `hit:set_reg(name, value)`,
+/// `hit:arg(i)` and `hit:stop()` to pass the event to the user callback
+#[derive(Clone)]
+pub struct LuaBpHit {
+    tid: tid_t,
+    state: Arc<Mutex<HitState>>,
+}
+
+impl UserData for LuaBpHit {
+    const TYPE_NAME: &'static str = "UDbgBpHit*";
+
+    fn getter(fields: &ValRef) {
+        fields.register("tid", |this: &Self| this.tid);
+    }
+
+    fn methods(mt: &ValRef) {
+        mt.register("reg", |this: &Self, name: &str| {
+            let state = this.state.lock();
+            state
+                .writes
+                .iter()
+                .rev()
+                .find(|r| r.0 == name)
+                .map(|r| r.1)
+                .or_else(|| state.regs.iter().find(|r| r.0 == name).map(|r| r.1))
+        });
+        mt.register("set_reg", |this: &Self, name: &str, value: usize| {
+            this.state.lock().writes.push((name.into(), value));
+        });
+        mt.register("arg", |this: &Self, i: usize| {
+            this.state
+                .lock()
+                .args
+                .get(i.checked_sub(1)?)
+                .copied()
+                .flatten()
+        });
+        mt.register("stop", |this: &Self| this.state.lock().stop = true);
+    }
+}
+
+/// The breakpoints with Lua actions, globals `target`, `bp` and `hit` are set for the snippets
+///
+/// ```ignore
+/// let mut actions = BpActions::new();
+/// actions.set(bp.get_id(), "print(bp.address, hit:arg(1))");
+/// engine.event_loop(&mut |ctx, event| {
+///     actions.handle(ctx, &event).unwrap_or_else(|| callback(ctx, event))
+/// })?;
+/// ```
+pub struct BpActions {
+    lua: State,
+    scripts: HashMap<BpID, String>,
+}
+
+impl BpActions {
+    pub fn new() -> Self {
+        let lua = State::new();
+        lua.open_libs();
+        let udbg = lua.table(0, 8);
+        init_udbg(&udbg);
+        lua.global().set("udbg", udbg);
+        Self {
+            lua,
+            scripts: HashMap::new(),
+        }
+    }
+
+    /// The Lua state running the snippets, to define the shared functions
+    #[inline]
+    pub fn state(&self) -> &State {
+        &self.lua
+    }
+
+    /// Attach a snippet to the breakpoint, replacing the previous one
+    pub fn set(&mut self, id: BpID, code: impl Into<String>) {
+        self.scripts.insert(id, code.into());
+    }
+
+    pub fn remove(&mut self, id: BpID) -> Option<String> {
+        self.scripts.remove(&id)
+    }
+
+    /// Run the action of breakpoint event, returns the reply if it's consumed by the action,
+    /// None to pass it to the user callback
+    pub fn handle(&mut self, ctx: &mut dyn TraceContext, event: &UEvent) -> Option<UserReply> {
+        let bp = match event {
+            UEvent::Breakpoint(bp) => bp,
+            _ => return None,
+        };
+        let code = self.scripts.get(&bp.get_id())?;
+        let target = ctx.target();
+        let tid = target.base().event_tid.get();
+        let arch = ctx.arch();
+
+        let mut state = HitState::default();
+        if let Some(regs) = ctx.register() {
+            let regs = &*regs;
+            state.regs = general_regs(arch)
+                .iter()
+                .filter_map(|&(name, id)| Some((name, regs.get_reg(id)?.as_int())))
+                .collect();
+            state.args = (1..=MAX_ARGS)
+                .map(|i| target.read_argument(regs, i, None))
+                .collect();
+        }
+        let state = Arc::new(Mutex::new(state));
+
+        let global = self.lua.global();
+        global.set("target", ArcTarget(target.clone()));
+        global.set("bp", ArcBreakpoint(bp.clone()));
+        global.set(
+            "hit",
+            LuaBpHit {
+                tid,
+                state: state.clone(),
+            },
+        );
+        let status = self.lua.do_string(code);
+        if !matches!(status, ThreadStatus::Ok) {
+            let err = self.lua.to_str(-1).unwrap_or_default().to_string();
+            self.lua.pop(1);
+            warn!("action of bp {:x}: {err}", bp.address());
+            // let the user see the breakpoint with the broken action
+            return None;
+        }
+
+        let state = state.lock();
+        if let Some(regs) = ctx.register() {
+            for (name, value) in state.writes.iter() {
+                regs.set(name, (*value).into());
+            }
+        }
+        (!state.stop).then_some(UserReply::Run(false))
+    }
+}
+
+impl Default for BpActions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -12,6 +12,9 @@ use std::sync::Arc;
 
 pub extern crate llua;
 
+mod action;
+pub use self::action::*;
+
 pub const STACK_BUFFER_SIZE: usize = 2000;
 
 pub const INIT_BP: lua_Integer = 1;
@@ -24,6 +27,10 @@ pub const MODULE_LOAD: lua_Integer = 7;
 pub const MODULE_UNLOAD: lua_Integer = 8;
 pub const EXCEPTION: lua_Integer = 9;
 pub const STEP: lua_Integer = 10;
+pub const CUSTOM: lua_Integer = 11;
+pub const POSSIBLE_OEP: lua_Integer = 12;
+pub const SYSCALL_ENTER: lua_Integer = 13;
+pub const SYSCALL_EXIT: lua_Integer = 14;
 
 pub fn init_udbg(t: &ValRef) {
     t.set("SymbolFile", ArcSymbolFile::metatable());
@@ -64,6 +71,10 @@ pub fn init_udbg(t: &ValRef) {
         t.set("MODULE_UNLOAD", MODULE_UNLOAD);
         t.set("EXCEPTION", EXCEPTION);
         t.set("STEP", STEP);
+        t.set("CUSTOM", CUSTOM);
+        t.set("POSSIBLE_OEP", POSSIBLE_OEP);
+        t.set("SYSCALL_ENTER", SYSCALL_ENTER);
+        t.set("SYSCALL_EXIT", SYSCALL_EXIT);
     }
     t.set("Event", TopVal);
 }
@@ -242,6 +253,7 @@ impl ToLuaMulti for HandleInfo {
             self.ty,
             self.type_name.as_str(),
             self.name.as_str(),
+            self.access,
         ))
     }
 }
@@ -519,6 +531,10 @@ impl ToLuaMulti for UEvent {
             ThreadCreate(tid) => s.pushx((THREAD_CREATE, tid)),
             ThreadExit(code) => s.pushx((THREAD_EXIT, code)),
             Exception { first, code } => s.pushx((EXCEPTION, code, first)),
+            Custom(e) => s.pushx((CUSTOM, e.kind())),
+            PossibleOEP(address) => s.pushx((POSSIBLE_OEP, address)),
+            SyscallEnter(info) => s.pushx((SYSCALL_ENTER, info.nr, info.name.as_deref())),
+            SyscallExit(info) => s.pushx((SYSCALL_EXIT, info.nr, info.ret)),
         }
     }
 }
@@ -587,9 +603,11 @@ impl UserData for ArcTarget {
             .register("suspend", <dyn UDbgTarget>::suspend)
             .register("wait_exit", <dyn UDbgTarget>::wait_exit);
 
+        #[cfg(not(feature = "passive"))]
         fn write_value<T>(this: &ArcTarget, a: usize, val: T) {
             this.write_value(a, &val);
         }
+        #[cfg(not(feature = "passive"))]
         mt.register("write_u8", write_value::<u8>)
             .register("write_u16", write_value::<u16>)
             .register("write_u32", write_value::<u32>)
@@ -679,10 +697,11 @@ impl UserData for ArcTarget {
             "read_wstring",
             |this: &Self, a: usize, size: Option<usize>| this.read_wstring(a, size.unwrap_or(1000)),
         );
+        #[cfg(not(feature = "passive"))]
         mt.register("write_string", |this: &Self, a: usize, buf: &[u8]| {
             this.write_cstring(a, buf)
         });
-        #[cfg(windows)]
+        #[cfg(all(windows, not(feature = "passive")))]
         mt.register("write_wstring", |this: &Self, a: usize, buf: &str| {
             this.write_wstring(a, buf)
         });
@@ -820,6 +839,7 @@ impl UserData for ArcTarget {
             },
         );
 
+        #[cfg(not(feature = "passive"))]
         mt.register(
             "write_bytes",
             |this: &Self, a: usize, buf: &[u8], len: Option<usize>| {
@@ -827,6 +847,7 @@ impl UserData for ArcTarget {
             },
         );
 
+        #[cfg(not(feature = "passive"))]
         mt.register(
             "write_type",
             |s: &State, this: &Self, a: usize, ty: &str| {
@@ -930,8 +951,9 @@ impl UserData for BoxEngine {
                 let action = s.args::<Option<&str>>(1);
                 match action.unwrap_or_default() {
                     "step" | "stepin" => UserReply::StepIn,
+                    "stepover" => UserReply::StepOver,
                     "stepout" => UserReply::StepOut,
-                    "goto" => UserReply::Goto(s.to_integer(2) as usize),
+                    "goto" | "runto" => UserReply::RunTo(s.to_integer(2) as usize),
                     "native" => UserReply::Native(s.to_integer(2) as usize),
                     "run" | _ => UserReply::Run(s.to_bool(2)),
                 }
@@ -15,13 +15,18 @@ use core::{
 /// Abstracted interface to read memory
 pub trait ReadMemory {
     fn read_memory<'a>(&self, addr: usize, data: &'a mut [u8]) -> Option<&'a mut [u8]>;
+
+    /// Endianness and pointer width of the data read, used by [`ReadMemoryUtils::read_value`]
+    fn data_layout(&self) -> DataLayout {
+        DataLayout::default()
+    }
 }
 
 impl ReadMemory for [u8] {
     fn read_memory<'a>(&self, addr: usize, data: &'a mut [u8]) -> Option<&'a mut [u8]> {
-        let rest = &self[addr..];
+        let rest = self.get(addr..)?;
         let len = rest.len().min(data.len());
-        data.copy_from_slice(&rest[..len]);
+        data[..len].copy_from_slice(&rest[..len]);
         Some(&mut data[..len])
     }
 }
@@ -37,6 +42,27 @@ impl<T: Copy> ReadValue for T {
     }
 }
 
+macro_rules! impl_read_value {
+    ($($t:ty)*) => {
+        $(impl ReadValue for $t {
+            #[inline]
+            fn read_value<R: ReadMemoryUtils + ?Sized>(r: &R, address: usize) -> Option<$t> {
+                r.read_endian(address, r.data_layout().endian)
+            }
+        })*
+    };
+}
+
+impl_read_value!(u8 i8 u16 i16 u32 i32 u64 i64 u128 i128 f32 f64);
+
+/// A `usize` of target is a pointer, read in its pointer width
+impl ReadValue for usize {
+    #[inline]
+    fn read_value<R: ReadMemoryUtils + ?Sized>(r: &R, address: usize) -> Option<usize> {
+        r.read_pointer(address, r.data_layout())
+    }
+}
+
 /// Practical functions based on [`ReadMemory`]
 #[allow(invalid_type_param_default)]
 pub trait ReadMemoryUtils: ReadMemory {
@@ -181,6 +207,41 @@ pub trait ReadMemoryUtils: ReadMemory {
         Some(String::from_utf16_lossy(&result))
     }
 
+    /// read a integer/float value in specific endianness
+    fn read_endian<T: EndianValue>(&self, address: usize, endian: Endian) -> Option<T> {
+        let mut buf = [0u8; 16];
+        let buf = &mut buf[..size_of::<T>()];
+        let data = self.read_memory(address, buf)?;
+        if data.len() < size_of::<T>() {
+            return None;
+        }
+        Some(T::from_bytes(data, endian))
+    }
+
+    /// read a pointer of target, in its endianness and pointer width
+    fn read_pointer(&self, address: usize, layout: DataLayout) -> Option<usize> {
+        if layout.pointer_size == 4 {
+            self.read_endian::<u32>(address, layout.endian)
+                .map(|p| p as usize)
+        } else {
+            self.read_endian::<u64>(address, layout.endian)
+                .map(|p| p as usize)
+        }
+    }
+
+    /// read continuous values in specific endianness
+    fn read_endian_array<T: EndianValue>(
+        &self,
+        address: usize,
+        count: usize,
+        endian: Endian,
+    ) -> Vec<T> {
+        self.read_bytes(address, count * size_of::<T>())
+            .chunks_exact(size_of::<T>())
+            .map(|b| T::from_bytes(b, endian))
+            .collect()
+    }
+
     /// read multiple-level pointer
     fn read_multilevel<T: ReadValue<O>, O = T>(
         &self,
@@ -207,10 +268,25 @@ pub use crate::os::windows::ReadMemUtilsWin;
 
 /// Abstracted interface to write memory
 pub trait WriteMemory {
+    /// Write data into memory. The processes keep the instruction cache coherent if the page is
+    /// executable: flushed by the write on Windows and macOS, by the kernel on Linux
     fn write_memory(&self, address: usize, data: &[u8]) -> Option<usize>;
+
+    /// Do the cache maintenance required after modified the executable memory, such as flushing icache on ARM
     fn flush_cache(&self, address: usize, len: usize) -> std::io::Result<()> {
         Ok(())
     }
+
+    /// Write executable memory, and make sure the written instructions are visible to target even
+    /// if the page is not executable yet. Returns the count written, a failure of the flush is
+    /// logged, not reported as a failed write
+    fn write_code(&self, address: usize, data: &[u8]) -> Option<usize> {
+        let written = self.write_memory(address, data)?;
+        if let Err(err) = self.flush_cache(address, written) {
+            warn!("flush cache {address:x}: {err:?}");
+        }
+        Some(written)
+    }
 }
 
 /// Practical functions based on [`WriteMemory`]
@@ -237,11 +313,106 @@ pub trait WriteMemoryUtils: WriteMemory {
         use crate::string::ToUnicode;
         self.write_array(address, data.to_unicode_with_null().as_slice())
     }
+
+    /// write a integer/float value in specific endianness
+    fn write_endian<T: EndianValue>(
+        &self,
+        address: usize,
+        val: T,
+        endian: Endian,
+    ) -> Option<usize> {
+        let mut buf = [0u8; 16];
+        let buf = &mut buf[..size_of::<T>()];
+        val.to_bytes(buf, endian);
+        self.write_memory(address, buf)
+    }
+
+    /// write a pointer of target, in its endianness and pointer width
+    fn write_pointer(&self, address: usize, p: usize, layout: DataLayout) -> Option<usize> {
+        if layout.pointer_size == 4 {
+            self.write_endian(address, p as u32, layout.endian)
+        } else {
+            self.write_endian(address, p as u64, layout.endian)
+        }
+    }
 }
 
 impl<T: ReadMemory + ?Sized> ReadMemoryUtils for T {}
 impl<T: WriteMemory + ?Sized> WriteMemoryUtils for T {}
 
+/// Byte order of target data
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    #[cfg(target_endian = "little")]
+    pub const NATIVE: Endian = Endian::Little;
+    #[cfg(target_endian = "big")]
+    pub const NATIVE: Endian = Endian::Big;
+
+    #[inline]
+    pub fn is_native(self) -> bool {
+        self == Self::NATIVE
+    }
+}
+
+impl Default for Endian {
+    fn default() -> Self {
+        Self::NATIVE
+    }
+}
+
+/// Endianness and pointer width of target, which may differ from the host,
+/// such as a big-endian target of gdb-remote, or a 32-bit core dump
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataLayout {
+    pub endian: Endian,
+    pub pointer_size: usize,
+}
+
+impl Default for DataLayout {
+    fn default() -> Self {
+        Self {
+            endian: Endian::NATIVE,
+            pointer_size: size_of::<usize>(),
+        }
+    }
+}
+
+/// Numeric types which could be converted from/to bytes in specific endianness
+pub trait EndianValue: Copy + Sized {
+    fn from_bytes(b: &[u8], endian: Endian) -> Self;
+    fn to_bytes(self, b: &mut [u8], endian: Endian);
+}
+
+macro_rules! impl_endian_value {
+    ($($t:ty)*) => {
+        $(impl EndianValue for $t {
+            #[inline]
+            fn from_bytes(b: &[u8], endian: Endian) -> Self {
+                let b = b[..size_of::<$t>()].try_into().unwrap();
+                match endian {
+                    Endian::Little => <$t>::from_le_bytes(b),
+                    Endian::Big => <$t>::from_be_bytes(b),
+                }
+            }
+
+            #[inline]
+            fn to_bytes(self, b: &mut [u8], endian: Endian) {
+                b[..size_of::<$t>()].copy_from_slice(&match endian {
+                    Endian::Little => self.to_le_bytes(),
+                    Endian::Big => self.to_be_bytes(),
+                });
+            }
+        })*
+    };
+}
+
+impl_endian_value!(u8 i8 u16 i16 u32 i32 u64 i64 u128 i128 f32 f64);
+
 /// Interfaces of memory operation
 pub trait TargetMemory: ReadMemory + WriteMemory {
     /// Enumerate the memory page in target memory space
@@ -273,6 +444,8 @@ bitflags! {
         const HEAP = 1 << 6;
         const PEB = 1 << 7;
         const TEB = 1 << 8;
+        /// Contains data with high entropy, see [`crate::entropy::EntropyMap::annotate_pages`]
+        const HIGH_ENTROPY = 1 << 9;
     }
 }
 
@@ -398,3 +571,39 @@ impl<T: Sized> AsByteArrayMut for T {
         unsafe { from_raw_parts_mut(self as *mut T as *mut u8, size_of::<T>()) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct BigEndian32(Vec<u8>);
+
+    impl ReadMemory for BigEndian32 {
+        fn read_memory<'a>(&self, addr: usize, data: &'a mut [u8]) -> Option<&'a mut [u8]> {
+            self.0.read_memory(addr, data)
+        }
+
+        fn data_layout(&self) -> DataLayout {
+            DataLayout {
+                endian: Endian::Big,
+                pointer_size: 4,
+            }
+        }
+    }
+
+    #[test]
+    fn read_value_layout() {
+        let data = [0x12u8, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0];
+        let r = BigEndian32(data.to_vec());
+        assert_eq!(r.read_value::<u16>(0), Some(0x1234));
+        assert_eq!(r.read_value::<u32>(0), Some(0x12345678));
+        assert_eq!(r.read_value::<usize>(4), Some(0x9ABCDEF0));
+        assert_eq!(r.read_value::<u64>(4), None);
+
+        let native = &data[..];
+        assert_eq!(
+            native.read_value::<u32>(0),
+            Some(u32::from_ne_bytes([0x12, 0x34, 0x56, 0x78]))
+        );
+    }
+}
@@ -0,0 +1,433 @@
+//!
+//! Software watchpoints on memory ranges too large for the hardware breakpoint slots.
+//!
+//! The pages of watched range are made to trap on access, by `PAGE_GUARD` on Windows and by
+//! `mprotect(PROT_NONE)` called in the target on Linux. When a page traps, the engine reports a
+//! [`MemWatchHit`] if the address is in a watched range, single-steps the faulting instruction
+//! with the original protection, then arms the page again.
+//! Any access to the other bytes of watched pages is trapped too, so a watch slows the target
+//! down in proportion to the traffic of its pages.
+//!
+
+use crate::{prelude::*, register::regid};
+
+use core::ops::Range;
+use spin::Mutex;
+use std::collections::{BTreeMap, HashMap};
+
+/// Kind of the custom event which carries [`MemWatchHit`]
+pub const MEM_WATCH_EVENT: &str = "mem_watch";
+
+const PAGE_SIZE: usize = 0x1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchAccess {
+    Read,
+    Write,
+    Execute,
+    /// Any access for watches, or the kind is unknown for hits
+    Access,
+}
+
+impl WatchAccess {
+    fn matches(self, hit: WatchAccess) -> bool {
+        self == hit || self == WatchAccess::Access || hit == WatchAccess::Access
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemWatch {
+    pub id: usize,
+    pub range: Range<usize>,
+    pub access: WatchAccess,
+    pub hits: usize,
+}
+
+/// An access to watched range
+#[derive(Debug, Clone, Serialize)]
+pub struct MemWatchHit {
+    pub id: usize,
+    pub tid: tid_t,
+    /// Address of the faulting instruction
+    pub pc: usize,
+    /// The accessed address
+    pub address: usize,
+    pub access: WatchAccess,
+    /// Disassembly of the faulting instruction, on x86 only
+    pub instruction: Option<String>,
+}
+
+impl MemWatchHit {
+    #[inline]
+    pub fn into_event(self) -> UEvent {
+        UEvent::custom(MEM_WATCH_EVENT, self)
+    }
+}
+
+/// The memory watches of a target
+#[derive(Default)]
+pub struct MemWatches {
+    watches: Mutex<Vec<MemWatch>>,
+    /// Armed pages and their original protection
+    pages: Mutex<BTreeMap<usize, u32>>,
+    /// The disarmed pages to arm again after the thread steps
+    rearm: Mutex<HashMap<tid_t, Vec<usize>>>,
+    next_id: Mutex<usize>,
+}
+
+fn pages_of(range: &Range<usize>) -> impl Iterator<Item = usize> {
+    let end = (range.end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    (range.start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE)
+}
+
+impl MemWatches {
+    pub fn list(&self) -> Vec<MemWatch> {
+        self.watches.lock().clone()
+    }
+
+    /// Watch the access to `range`, should be called when the target is stopped
+    pub fn add<T: UDbgTarget + ?Sized>(
+        &self,
+        target: &T,
+        range: Range<usize>,
+        access: WatchAccess,
+    ) -> UDbgResult<usize> {
+        if range.is_empty() {
+            return Err(UDbgError::InvalidAddress);
+        }
+        let mut armed = vec![];
+        for page in pages_of(&range) {
+            if self.pages.lock().contains_key(&page) {
+                continue;
+            }
+            match sys::arm(target, page) {
+                Ok(protect) => {
+                    self.pages.lock().insert(page, protect);
+                    armed.push(page);
+                }
+                Err(err) => {
+                    // leave nothing armed for a failed watch
+                    for page in armed {
+                        if let Some(protect) = self.pages.lock().remove(&page) {
+                            sys::restore(target, page, protect).log_error("restore page");
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        let mut next_id = self.next_id.lock();
+        *next_id += 1;
+        let id = *next_id;
+        self.watches.lock().push(MemWatch {
+            id,
+            range,
+            access,
+            hits: 0,
+        });
+        Ok(id)
+    }
+
+    /// Remove a watch, the pages not used by other watches are restored
+    pub fn remove<T: UDbgTarget + ?Sized>(&self, target: &T, id: usize) -> UDbgResult<()> {
+        let mut watches = self.watches.lock();
+        let i = watches
+            .iter()
+            .position(|w| w.id == id)
+            .ok_or(UDbgError::NotFound)?;
+        let watch = watches.remove(i);
+        for page in pages_of(&watch.range) {
+            if watches
+                .iter()
+                .any(|w| pages_of(&w.range).any(|p| p == page))
+            {
+                continue;
+            }
+            if let Some(protect) = self.pages.lock().remove(&page) {
+                // the pages waiting to arm again are already restored
+                let waiting = self.rearm.lock().values().any(|v| v.contains(&page));
+                if !waiting {
+                    sys::restore(target, page, protect)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove all the watches and restore their pages, called by the engine when detaching
+    pub fn remove_all<T: UDbgTarget + ?Sized>(&self, target: &T) {
+        self.watches.lock().clear();
+        let rearm = core::mem::take(&mut *self.rearm.lock());
+        for (page, protect) in core::mem::take(&mut *self.pages.lock()) {
+            // the pages waiting to arm again are already restored
+            if rearm.values().any(|v| v.contains(&page)) {
+                continue;
+            }
+            sys::restore(target, page, protect)
+                .log_error_with(|err| format!("restore page {page:x}: {err:?}"));
+        }
+    }
+
+    /// Called by the engine before the user callback, returns the reply if the event is caused by
+    /// the watches, with the hit event to pass to the user callback
+    pub(crate) fn on_event(
+        &self,
+        ctx: &mut dyn TraceContext,
+        event: &UEvent,
+    ) -> Option<(UserReply, Option<UEvent>)> {
+        if self.pages.lock().is_empty() && self.rearm.lock().is_empty() {
+            return None;
+        }
+        let target = ctx.target();
+        let tid = target.base().event_tid.get();
+
+        if sys::is_step(event) {
+            let pages = self.rearm.lock().remove(&tid)?;
+            for page in pages {
+                // removed while stepping
+                if !self.pages.lock().contains_key(&page) {
+                    continue;
+                }
+                sys::arm(target.as_ref(), page).log_error("arm page");
+            }
+            return Some((UserReply::Run(true), None));
+        }
+
+        let (address, access) = sys::fault(ctx, event)?;
+        let page = address & !(PAGE_SIZE - 1);
+        let protect = *self.pages.lock().get(&page)?;
+        if !sys::AUTO_DISARM {
+            sys::restore(target.as_ref(), page, protect).log_error("disarm page")?;
+        }
+        self.rearm.lock().entry(tid).or_default().push(page);
+
+        let hit = self.watches.lock().iter_mut().find_map(|w| {
+            if !w.range.contains(&address) || !w.access.matches(access) {
+                return None;
+            }
+            w.hits += 1;
+            Some(w.id)
+        });
+        let hit = hit.map(|id| {
+            let pc = ctx
+                .register()
+                .and_then(|r| r.get_reg(regid::COMM_REG_PC))
+                .map_or(0, |r| r.as_int());
+            MemWatchHit {
+                id,
+                tid,
+                pc,
+                address,
+                access,
+                instruction: instruction(target.as_ref(), pc),
+            }
+            .into_event()
+        });
+        Some((sys::step(ctx), hit))
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn instruction<T: UDbgTarget + ?Sized>(target: &T, pc: usize) -> Option<String> {
+    target
+        .disasm_lines(pc, 1)
+        .into_iter()
+        .next()
+        .map(|l| l.text)
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn instruction<T: UDbgTarget + ?Sized>(_target: &T, _pc: usize) -> Option<String> {
+    None
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::*;
+
+    use winapi::um::winnt::PAGE_GUARD;
+
+    const STATUS_GUARD_PAGE_VIOLATION: u32 = 0x80000001;
+    const STATUS_SINGLE_STEP: u32 = 0x80000004;
+    const STATUS_WX86_SINGLE_STEP: u32 = 0x4000001E;
+    const TRAP_FLAG: usize = 0x100;
+
+    /// The guard is cleared by the system when it's hit
+    pub const AUTO_DISARM: bool = true;
+
+    pub fn arm<T: UDbgTarget + ?Sized>(target: &T, page: usize) -> UDbgResult<u32> {
+        if !cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+            return Err(UDbgError::NotSupport);
+        }
+        let protect = target
+            .virtual_query(page)
+            .ok_or(UDbgError::InvalidAddress)?
+            .protect
+            & !PAGE_GUARD;
+        target
+            .process()
+            .ok_or(UDbgError::NotSupport)?
+            .protect_memory(page, PAGE_SIZE, protect | PAGE_GUARD)
+            .ok_or_else(UDbgError::system)?;
+        Ok(protect)
+    }
+
+    pub fn restore<T: UDbgTarget + ?Sized>(
+        target: &T,
+        page: usize,
+        protect: u32,
+    ) -> UDbgResult<()> {
+        target
+            .process()
+            .ok_or(UDbgError::NotSupport)?
+            .protect_memory(page, PAGE_SIZE, protect)
+            .ok_or_else(UDbgError::system)?;
+        Ok(())
+    }
+
+    pub fn is_step(event: &UEvent) -> bool {
+        matches!(
+            event,
+            UEvent::Step
+                | UEvent::Exception {
+                    code: STATUS_SINGLE_STEP | STATUS_WX86_SINGLE_STEP,
+                    ..
+                }
+        )
+    }
+
+    /// The accessed address and access kind of a guard page violation
+    pub fn fault(ctx: &dyn TraceContext, event: &UEvent) -> Option<(usize, WatchAccess)> {
+        match event {
+            UEvent::Exception {
+                first: true,
+                code: STATUS_GUARD_PAGE_VIOLATION,
+            } => {}
+            _ => return None,
+        }
+        let access = match ctx.exception_param(0)? {
+            0 => WatchAccess::Read,
+            1 => WatchAccess::Write,
+            8 => WatchAccess::Execute,
+            _ => WatchAccess::Access,
+        };
+        Some((ctx.exception_param(1)?, access))
+    }
+
+    /// Set the trap flag, the exceptions replied by `StepIn` are not stepped
+    pub fn step(ctx: &mut dyn TraceContext) -> UserReply {
+        if let Some(regs) = ctx.register() {
+            let flags = regs
+                .get_reg(regid::X86_REG_EFLAGS)
+                .map_or(0, |r| r.as_int());
+            regs.set_reg(regid::X86_REG_EFLAGS, (flags | TRAP_FLAG).into());
+        }
+        UserReply::Run(true)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use super::*;
+    use crate::os::call_remote;
+
+    use libc::{PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE, SIGSEGV};
+
+    /// The original protection is restored by calling mprotect before stepping
+    pub const AUTO_DISARM: bool = false;
+
+    fn mprotect<T: UDbgTarget + ?Sized>(target: &T, page: usize, protect: u32) -> UDbgResult<()> {
+        let libc = target
+            .enum_module()?
+            .find(|m| m.data().name.starts_with("libc"))
+            .ok_or(UDbgError::NotSupport)?;
+        let address = libc.data().base
+            + libc
+                .get_symbol("mprotect")
+                .ok_or(UDbgError::NotSupport)?
+                .offset as usize;
+        let tid = target.base().event_tid.get();
+        let ret = call_remote(
+            tid,
+            address,
+            0,
+            &[page as reg_t, PAGE_SIZE as reg_t, protect as reg_t],
+        )?;
+        if ret as i32 != 0 {
+            return Err(UDbgError::Text(format!("mprotect {page:x}: {ret}")));
+        }
+        Ok(())
+    }
+
+    pub fn arm<T: UDbgTarget + ?Sized>(target: &T, page: usize) -> UDbgResult<u32> {
+        let info = target
+            .virtual_query(page)
+            .ok_or(UDbgError::InvalidAddress)?;
+        let prot = info.as_linux_protect();
+        let mut protect = PROT_NONE;
+        for (c, bit) in [(b'r', PROT_READ), (b'w', PROT_WRITE), (b'x', PROT_EXEC)] {
+            if prot.contains(&c) {
+                protect |= bit;
+            }
+        }
+        mprotect(target, page, PROT_NONE as u32)?;
+        Ok(protect as u32)
+    }
+
+    pub fn restore<T: UDbgTarget + ?Sized>(
+        target: &T,
+        page: usize,
+        protect: u32,
+    ) -> UDbgResult<()> {
+        mprotect(target, page, protect)
+    }
+
+    pub fn is_step(event: &UEvent) -> bool {
+        matches!(event, UEvent::Step)
+    }
+
+    /// The kind of access is not provided by SIGSEGV
+    pub fn fault(ctx: &dyn TraceContext, event: &UEvent) -> Option<(usize, WatchAccess)> {
+        match *event {
+            UEvent::Exception { first: true, code } if code == SIGSEGV as u32 => {}
+            _ => return None,
+        }
+        Some((ctx.exception_param(1)?, WatchAccess::Access))
+    }
+
+    pub fn step(_ctx: &mut dyn TraceContext) -> UserReply {
+        UserReply::StepIn
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android")))]
+mod sys {
+    use super::*;
+
+    pub const AUTO_DISARM: bool = false;
+
+    pub fn arm<T: UDbgTarget + ?Sized>(_target: &T, _page: usize) -> UDbgResult<u32> {
+        Err(UDbgError::NotSupport)
+    }
+
+    pub fn restore<T: UDbgTarget + ?Sized>(
+        _target: &T,
+        _page: usize,
+        _protect: u32,
+    ) -> UDbgResult<()> {
+        Err(UDbgError::NotSupport)
+    }
+
+    pub fn is_step(_event: &UEvent) -> bool {
+        false
+    }
+
+    pub fn fault(_ctx: &dyn TraceContext, _event: &UEvent) -> Option<(usize, WatchAccess)> {
+        None
+    }
+
+    pub fn step(_ctx: &mut dyn TraceContext) -> UserReply {
+        UserReply::Run(true)
+    }
+}
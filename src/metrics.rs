@@ -0,0 +1,258 @@
+//!
+//! Counters of the engine itself, to monitor the overhead of debugger in the long-running
+//! deployments such as the crash-catching services.
+//!
+//! The counters are process-wide and always on. Take a [`snapshot`] periodically, and compare it
+//! with the previous one by [`MetricsSnapshot::rates_since`] to get the rates.
+//!
+//! The time of each stop is broken down into the phases of [`StopTiming`], the recent ones are
+//! kept for [`recent_stops`], to find where the time of a slow event loop goes.
+//!
+
+use crate::prelude::*;
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use core::time::Duration;
+use spin::Mutex;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Count of the stops kept by [`recent_stops`]
+pub const RECENT_STOPS: usize = 256;
+
+struct Metrics {
+    events: AtomicU64,
+    bp_hits: AtomicU64,
+    read_bytes: AtomicU64,
+    symbol_lookups: AtomicU64,
+    symbol_cache_hits: AtomicU64,
+    /// Total nanoseconds of the targets stopped in the event handlers
+    stop_nanos: AtomicU64,
+}
+
+static METRICS: Metrics = Metrics {
+    events: AtomicU64::new(0),
+    bp_hits: AtomicU64::new(0),
+    read_bytes: AtomicU64::new(0),
+    symbol_lookups: AtomicU64::new(0),
+    symbol_cache_hits: AtomicU64::new(0),
+    stop_nanos: AtomicU64::new(0),
+};
+
+static RECENT: Mutex<VecDeque<StopTiming>> = Mutex::new(VecDeque::new());
+
+thread_local! {
+    /// When the current stop was fetched from the OS
+    static STOP_BEGIN: Cell<Option<Instant>> = Cell::new(None);
+    /// Total nanoseconds of the symbol resolution in this thread
+    static SYMBOL_NANOS: Cell<u64> = Cell::new(0);
+}
+
+fn record_event(is_bp: bool, stopped: Duration) {
+    METRICS.events.fetch_add(1, Relaxed);
+    if is_bp {
+        METRICS.bp_hits.fetch_add(1, Relaxed);
+    }
+    METRICS
+        .stop_nanos
+        .fetch_add(stopped.as_nanos() as u64, Relaxed);
+}
+
+#[inline]
+pub(crate) fn record_read(bytes: usize) {
+    METRICS.read_bytes.fetch_add(bytes as u64, Relaxed);
+}
+
+/// A lookup of symbol file, `hit` if it's found without downloading
+pub(crate) fn record_symbol_lookup(hit: bool) {
+    METRICS.symbol_lookups.fetch_add(1, Relaxed);
+    if hit {
+        METRICS.symbol_cache_hits.fetch_add(1, Relaxed);
+    }
+}
+
+/// Mark a stop fetched from the OS by the event loop, before the engine decodes it
+#[inline]
+pub(crate) fn stop_begin() {
+    STOP_BEGIN.with(|b| b.set(Some(Instant::now())));
+}
+
+/// Resolve symbols, the time is counted into the current stop
+#[inline]
+pub(crate) fn time_symbols<R>(f: impl FnOnce() -> R) -> R {
+    let start = Instant::now();
+    let result = f();
+    let nanos = start.elapsed().as_nanos() as u64;
+    SYMBOL_NANOS.with(|n| n.set(n.get() + nanos));
+    result
+}
+
+/// Time spent in the phases of a stop
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct StopTiming {
+    pub tid: tid_t,
+    /// From the stop fetched from the OS to its dispatch, the engine decoding the event
+    pub engine: Duration,
+    /// The hooks of engine, such as the breakpoint rate guard, memory watches and crash capture
+    pub middleware: Duration,
+    /// The symbol resolution during the stop, mostly by the user callback
+    pub symbols: Duration,
+    /// The user callback, excluding its symbol resolution
+    pub callback: Duration,
+    pub total: Duration,
+}
+
+/// Timing of a stop being dispatched
+pub(crate) struct StopTimer {
+    tid: tid_t,
+    is_bp: bool,
+    start: Instant,
+    engine: Duration,
+    symbol_nanos: u64,
+}
+
+impl StopTimer {
+    pub(crate) fn start(tid: tid_t, is_bp: bool) -> Self {
+        let start = Instant::now();
+        Self {
+            tid,
+            is_bp,
+            start,
+            engine: STOP_BEGIN
+                .with(Cell::take)
+                .map(|b| start.duration_since(b))
+                .unwrap_or_default(),
+            symbol_nanos: SYMBOL_NANOS.with(Cell::get),
+        }
+    }
+
+    /// Finish the stop, `callback` is the time spent in the user callback
+    pub(crate) fn finish(self, callback: Duration) {
+        let dispatch = self.start.elapsed();
+        let symbols = Duration::from_nanos(
+            SYMBOL_NANOS
+                .with(Cell::get)
+                .saturating_sub(self.symbol_nanos),
+        );
+        let timing = StopTiming {
+            tid: self.tid,
+            engine: self.engine,
+            middleware: dispatch.saturating_sub(callback),
+            symbols,
+            callback: callback.saturating_sub(symbols),
+            total: self.engine + dispatch,
+        };
+        record_event(self.is_bp, timing.total);
+        let mut recent = RECENT.lock();
+        if recent.len() >= RECENT_STOPS {
+            recent.pop_front();
+        }
+        recent.push_back(timing);
+    }
+}
+
+/// Timing of the recent stops, the oldest first
+pub fn recent_stops() -> Vec<StopTiming> {
+    RECENT.lock().iter().copied().collect()
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MetricsSnapshot {
+    #[serde(skip)]
+    pub time: Instant,
+    pub events: u64,
+    pub bp_hits: u64,
+    /// Bytes read from the targets
+    pub read_bytes: u64,
+    pub symbol_lookups: u64,
+    pub symbol_cache_hits: u64,
+    /// Total time of the targets stopped in the event handlers
+    pub stop_time: Duration,
+}
+
+/// The rates between two snapshots
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MetricsRates {
+    pub events_per_sec: f64,
+    pub bp_hits_per_sec: f64,
+    pub read_bytes_per_sec: f64,
+    pub symbol_cache_hit_rate: f64,
+    pub average_stop_latency: Duration,
+}
+
+fn ratio(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+fn average(time: Duration, count: u64) -> Duration {
+    match count {
+        0 => Duration::ZERO,
+        n => Duration::from_nanos((time.as_nanos() / n as u128) as u64),
+    }
+}
+
+impl MetricsSnapshot {
+    #[inline]
+    pub fn symbol_cache_hit_rate(&self) -> f64 {
+        ratio(self.symbol_cache_hits, self.symbol_lookups)
+    }
+
+    pub fn average_stop_latency(&self) -> Duration {
+        average(self.stop_time, self.events)
+    }
+
+    /// The rates from an `earlier` snapshot to this
+    pub fn rates_since(&self, earlier: &Self) -> MetricsRates {
+        let secs = self.time.duration_since(earlier.time).as_secs_f64();
+        let per_sec = |now: u64, then: u64| match secs {
+            s if s > 0.0 => now.saturating_sub(then) as f64 / s,
+            _ => 0.0,
+        };
+        MetricsRates {
+            events_per_sec: per_sec(self.events, earlier.events),
+            bp_hits_per_sec: per_sec(self.bp_hits, earlier.bp_hits),
+            read_bytes_per_sec: per_sec(self.read_bytes, earlier.read_bytes),
+            symbol_cache_hit_rate: ratio(
+                self.symbol_cache_hits
+                    .saturating_sub(earlier.symbol_cache_hits),
+                self.symbol_lookups.saturating_sub(earlier.symbol_lookups),
+            ),
+            average_stop_latency: average(
+                self.stop_time.saturating_sub(earlier.stop_time),
+                self.events.saturating_sub(earlier.events),
+            ),
+        }
+    }
+}
+
+pub fn snapshot() -> MetricsSnapshot {
+    MetricsSnapshot {
+        time: Instant::now(),
+        events: METRICS.events.load(Relaxed),
+        bp_hits: METRICS.bp_hits.load(Relaxed),
+        read_bytes: METRICS.read_bytes.load(Relaxed),
+        symbol_lookups: METRICS.symbol_lookups.load(Relaxed),
+        symbol_cache_hits: METRICS.symbol_cache_hits.load(Relaxed),
+        stop_time: Duration::from_nanos(METRICS.stop_nanos.load(Relaxed)),
+    }
+}
+
+/// Reset all the counters to zero
+pub fn reset() {
+    for c in [
+        &METRICS.events,
+        &METRICS.bp_hits,
+        &METRICS.read_bytes,
+        &METRICS.symbol_lookups,
+        &METRICS.symbol_cache_hits,
+        &METRICS.stop_nanos,
+    ] {
+        c.store(0, Relaxed);
+    }
+    RECENT.lock().clear();
+}
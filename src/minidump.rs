@@ -1,12 +1,19 @@
 //! [`MiniDumpTarget`] implementation
 
-use crate::{os::priority_t, pe::*, prelude::*, range::RangeValue};
+use crate::{
+    os::priority_t,
+    pe::*,
+    prelude::*,
+    range::RangeValue,
+    readonly::ReadOnlyTarget,
+    space::{AddressSpace, MemoryRun, MemoryRuns},
+};
 
 use anyhow::Context;
 use memmap2::Mmap;
 use minidump::*;
 use serde_value::Value as SerdeValue;
-use std::{path::Path, sync::Arc};
+use std::{collections::BTreeMap, path::Path, sync::Arc};
 
 #[derive(Deref)]
 pub struct MiniDumpTarget {
@@ -14,6 +21,8 @@ pub struct MiniDumpTarget {
     #[deref]
     dump: Minidump<'static, Mmap>,
     memory: Vec<MemoryPage>,
+    /// The memory of process, translated to the file by the memory list
+    space: AddressSpace<Mmap, MemoryRuns>,
 }
 
 unsafe impl Send for MiniDumpTarget {}
@@ -21,18 +30,10 @@ unsafe impl Sync for MiniDumpTarget {}
 
 impl ReadMemory for MiniDumpTarget {
     fn read_memory<'a>(&self, addr: usize, data: &'a mut [u8]) -> Option<&'a mut [u8]> {
-        let mem = self
-            .dump
-            .get_stream::<MinidumpMemoryList>()
-            .context("memory")
-            .unwrap();
-
-        let m = mem.memory_at_address(addr as _)?;
-        let offset = addr - m.base_address as usize;
-        let src = &m.bytes[offset..m.bytes.len().min(offset + data.len())];
-        let dst = &mut data[..src.len()];
-        dst.copy_from_slice(src);
-        Some(dst)
+        self.space.read_memory(addr, data)
+    }
+    fn data_layout(&self) -> DataLayout {
+        self.base.data_layout()
     }
 }
 
@@ -75,6 +76,8 @@ impl TargetMemory for MiniDumpTarget {
 impl GetProp for MiniDumpTarget {
     fn get_prop(&self, key: &str) -> UDbgResult<SerdeValue> {
         match key {
+            "crash_info" => serde_value::to_value(self.crash_info())
+                .map_err(|err| UDbgError::Text(err.to_string())),
             _ => Ok(SerdeValue::Unit),
         }
     }
@@ -253,10 +256,116 @@ impl UDbgThread for MiniDumpThread {
     }
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct DumpException {
+    pub tid: tid_t,
+    pub code: u32,
+    pub address: usize,
+}
+
+/// The crash information of dump, from the exception stream and the Breakpad/Crashpad streams
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DumpCrashInfo {
+    pub exception: Option<DumpException>,
+    /// The thread which wrote the dump, from the Breakpad info stream
+    pub dump_thread: Option<tid_t>,
+    /// The thread which requested the dump, from the Breakpad info stream
+    pub requesting_thread: Option<tid_t>,
+    /// The invalid parameter or pure virtual call of Breakpad on Windows
+    pub assertion: Option<String>,
+    pub report_id: Option<String>,
+    pub client_id: Option<String>,
+    /// The simple annotations of Crashpad
+    pub annotations: BTreeMap<String, String>,
+    /// The simple and list annotations of Crashpad, by module name
+    pub module_annotations: BTreeMap<String, Vec<String>>,
+}
+
+fn format_guid(guid: &format::GUID) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{}-{}",
+        guid.data1,
+        guid.data2,
+        guid.data3,
+        hex::encode(&guid.data4[..2]),
+        hex::encode(&guid.data4[2..])
+    )
+}
+
 impl MiniDumpTarget {
+    /// Read the crash information from the streams
+    pub fn crash_info(&self) -> DumpCrashInfo {
+        let mut result = DumpCrashInfo::default();
+        if let Ok(e) = self.dump.get_stream::<MinidumpException>() {
+            result.exception = Some(DumpException {
+                tid: e.thread_id as _,
+                code: e.raw.exception_record.exception_code,
+                address: e.raw.exception_record.exception_address as _,
+            });
+        }
+        if let Ok(info) = self.dump.get_stream::<MinidumpBreakpadInfo>() {
+            result.dump_thread = info.dump_thread_id.map(|t| t as _);
+            result.requesting_thread = info.requesting_thread_id.map(|t| t as _);
+        }
+        if let Ok(a) = self.dump.get_stream::<MinidumpAssertion>() {
+            result.assertion = Some(format!(
+                "{} in {} at {}:{}",
+                a.expression().unwrap_or_default(),
+                a.function().unwrap_or_default(),
+                a.file().unwrap_or_default(),
+                a.raw.line
+            ));
+        }
+        if let Ok(info) = self.dump.get_stream::<MinidumpCrashpadInfo>() {
+            result.report_id = Some(format_guid(&info.raw.report_id));
+            result.client_id = Some(format_guid(&info.raw.client_id));
+            result.annotations = info.simple_annotations.clone();
+            let modules = self.dump.get_stream::<MinidumpModuleList>().ok();
+            for m in info.module_list.iter() {
+                let name = modules
+                    .as_ref()
+                    .and_then(|l| l.iter().nth(m.module_index))
+                    .map(|m| m.name.clone())
+                    .unwrap_or_else(|| format!("#{}", m.module_index));
+                let annotations = m
+                    .simple_annotations
+                    .iter()
+                    .map(|(k, v)| format!("{k}={v}"))
+                    .chain(m.list_annotations.iter().cloned())
+                    .collect::<Vec<_>>();
+                if !annotations.is_empty() {
+                    result.module_annotations.insert(name, annotations);
+                }
+            }
+        }
+        result
+    }
+
+    /// Open a dump file as [`ReadOnlyTarget`]
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<ReadOnlyTarget> {
+        Ok(ReadOnlyTarget::new(Arc::new(Self::new(path)?)))
+    }
+
     pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
         let dump = Minidump::read_path(path)?;
+        // mapped again as the backing of address space, the one of dump is private
+        let file = unsafe { Mmap::map(&std::fs::File::open(path)?)? };
+        let runs = dump
+            .get_stream::<MinidumpMemoryList>()
+            .map(|mem| {
+                mem.iter()
+                    .map(|m| MemoryRun {
+                        address: m.base_address as _,
+                        size: m.bytes.len(),
+                        offset: m.desc.memory.rva as _,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
         let base = TargetBase::default();
+        // the minidump format is little endian, so are the architectures it records
+        base.endian.set(crate::memory::Endian::Little);
 
         base.pid.set(
             dump.get_stream::<MinidumpMiscInfo>()
@@ -312,6 +421,7 @@ impl MiniDumpTarget {
             base,
             dump,
             memory: vec![],
+            space: AddressSpace::new(file, MemoryRuns::new(runs)),
         };
         for m in Target::enum_module(&this).into_iter().flatten() {
             let md = m.data();
@@ -327,6 +437,11 @@ impl MiniDumpTarget {
             });
         }
         this.memory = memory;
+        // the crashed thread, or the one requested the dump by Breakpad
+        let info = this.crash_info();
+        if let Some(tid) = info.exception.map(|e| e.tid).or(info.requesting_thread) {
+            this.base.event_tid.set(tid);
+        }
         Ok(this)
     }
 }
@@ -0,0 +1,301 @@
+//!
+//! Module dependency graph and load order of target, exported to DOT or JSON, for debugging the
+//! load failures and the cyclic initialization.
+//!
+//! The dependencies are the import descriptors of PE and `DT_NEEDED` of ELF, read from the memory
+//! by [`crate::image`], resolved by the names of loaded modules. The load order comes from the
+//! module load events recorded in [`TargetBase::module_loads`], which is complete only if the
+//! events were observed from the creation of process. On Windows the actual initialization order
+//! is read from the loader list of PEB.
+//!
+
+use crate::{image::RemoteImage, prelude::*};
+
+use core::fmt::Write;
+use spin::Mutex;
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::SystemTime;
+
+/// A module load or unload observed
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadRecord {
+    pub time: SystemTime,
+    pub name: Arc<str>,
+    pub path: Arc<str>,
+    pub base: usize,
+    pub unload: bool,
+}
+
+/// The module events of a target, recorded by the engine all the time
+#[derive(Default)]
+pub struct LoadLog {
+    records: Mutex<Vec<LoadRecord>>,
+    from_start: AtomicBool,
+}
+
+impl LoadLog {
+    /// The records, from the oldest
+    pub fn records(&self) -> Vec<LoadRecord> {
+        self.records.lock().clone()
+    }
+
+    /// The events were observed since the creation of process
+    pub fn is_complete(&self) -> bool {
+        self.from_start.load(Ordering::Relaxed)
+    }
+
+    pub fn clear(&self) {
+        self.records.lock().clear();
+        self.from_start.store(false, Ordering::Relaxed);
+    }
+
+    /// Called by the engine before the user callback
+    pub(crate) fn on_event(&self, event: &UEvent) {
+        let (module, unload) = match event {
+            UEvent::ProcessCreate => {
+                self.records.lock().clear();
+                self.from_start.store(true, Ordering::Relaxed);
+                return;
+            }
+            UEvent::ModuleLoad(m) => (m, false),
+            UEvent::ModuleUnload(m) => (m, true),
+            _ => return,
+        };
+        let data = module.data();
+        self.records.lock().push(LoadRecord {
+            time: SystemTime::now(),
+            name: data.name.clone(),
+            path: data.path.clone(),
+            base: data.base,
+            unload,
+        });
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleNode {
+    pub name: Arc<str>,
+    pub path: Arc<str>,
+    pub base: usize,
+    /// Index in the load order, None if its load wasn't observed
+    pub load_index: Option<usize>,
+    /// Index in the initialization order, Windows only
+    pub init_index: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleEdge {
+    pub from: Arc<str>,
+    /// The name as imported
+    pub to: String,
+    /// The loaded module of `to`, None if it's not loaded
+    pub resolved: Option<Arc<str>>,
+}
+
+impl ModuleEdge {
+    /// An API set of Windows, which is resolved by the loader to another module
+    pub fn is_api_set(&self) -> bool {
+        let name = self.to.to_ascii_lowercase();
+        name.starts_with("api-ms-") || name.starts_with("ext-ms-")
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleGraph {
+    /// In load order, then the modules whose load wasn't observed
+    pub nodes: Vec<ModuleNode>,
+    pub edges: Vec<ModuleEdge>,
+    /// The load order is observed from the creation of process
+    pub complete: bool,
+}
+
+impl ModuleGraph {
+    /// Build the graph of the modules loaded now, the modules failed to parse have no edges
+    pub fn build<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<Self> {
+        let log = &target.base().module_loads;
+        let mut order = HashMap::new();
+        for r in log.records().iter().filter(|r| !r.unload) {
+            let next = order.len();
+            order.entry(r.base).or_insert(next);
+        }
+        let init_order = init_order(target);
+
+        let mut nodes = vec![];
+        let mut edges = vec![];
+        for m in target.enum_module()? {
+            let data = m.data();
+            nodes.push(ModuleNode {
+                name: data.name.clone(),
+                path: data.path.clone(),
+                base: data.base,
+                load_index: order.get(&data.base).copied(),
+                init_index: init_order.get(&data.base).copied(),
+            });
+            let image = match RemoteImage::of(target, m.as_ref()) {
+                Ok(image) => image,
+                Err(err) => {
+                    warn!("parse {}: {err:?}", data.name);
+                    continue;
+                }
+            };
+            let mut deps = image.dependencies();
+            deps.sort();
+            deps.dedup();
+            for to in deps {
+                edges.push(ModuleEdge {
+                    from: data.name.clone(),
+                    resolved: target.get_module(&to).map(|m| m.data().name.clone()),
+                    to,
+                });
+            }
+        }
+        nodes.sort_by_key(|n| (n.load_index.is_none(), n.load_index, n.base));
+        Ok(Self {
+            nodes,
+            edges,
+            complete: log.is_complete(),
+        })
+    }
+
+    /// The dependencies not loaded, except the API sets
+    pub fn missing(&self) -> impl Iterator<Item = &ModuleEdge> {
+        self.edges
+            .iter()
+            .filter(|e| e.resolved.is_none() && !e.is_api_set())
+    }
+
+    /// The dependency cycles, as the strongly connected components of more than one module
+    pub fn cycles(&self) -> Vec<Vec<Arc<str>>> {
+        let index = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.name.clone(), i))
+            .collect::<HashMap<_, _>>();
+        let mut adjacent = vec![vec![]; self.nodes.len()];
+        for e in self.edges.iter() {
+            if let (Some(&from), Some(to)) = (
+                index.get(&e.from),
+                e.resolved.as_ref().and_then(|r| index.get(r)),
+            ) {
+                adjacent[from].push(*to);
+            }
+        }
+        strong_components(&adjacent)
+            .into_iter()
+            .filter(|c| c.len() > 1)
+            .map(|c| c.into_iter().map(|i| self.nodes[i].name.clone()).collect())
+            .collect()
+    }
+
+    /// Graphviz DOT, the nodes are labeled with the load and initialization order, the missing
+    /// dependencies are dashed
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph modules {\n    node [shape=box];\n");
+        let order = |i: Option<usize>| i.map_or("-".into(), |i| i.to_string());
+        for n in self.nodes.iter() {
+            writeln!(
+                out,
+                "    \"{}\" [label=\"{}\\nload {} init {}\"];",
+                n.name.replace('"', "\\\""),
+                n.name.replace('"', "\\\""),
+                order(n.load_index),
+                order(n.init_index)
+            )
+            .ok();
+        }
+        for e in self.edges.iter().filter(|e| !e.is_api_set()) {
+            let from = e.from.replace('"', "\\\"");
+            match &e.resolved {
+                Some(to) => writeln!(out, "    \"{from}\" -> \"{}\";", to.replace('"', "\\\"")),
+                None => writeln!(
+                    out,
+                    "    \"{from}\" -> \"{}\" [style=dashed, color=red];",
+                    e.to.replace('"', "\\\"")
+                ),
+            }
+            .ok();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_json(&self) -> UDbgResult<String> {
+        serde_json::to_string_pretty(self).map_err(|err| err.to_string().into())
+    }
+}
+
+/// Tarjan's algorithm, iterative
+fn strong_components(adjacent: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let n = adjacent.len();
+    let (mut index, mut low) = (vec![usize::MAX; n], vec![0; n]);
+    let mut on_stack = vec![false; n];
+    let (mut stack, mut result, mut next) = (vec![], vec![], 0);
+    for root in 0..n {
+        if index[root] != usize::MAX {
+            continue;
+        }
+        // the nodes being visited and the next edge of each
+        let mut visiting = vec![(root, 0)];
+        index[root] = next;
+        low[root] = next;
+        next += 1;
+        stack.push(root);
+        on_stack[root] = true;
+        while let Some(&(v, edge)) = visiting.last() {
+            if let Some(&w) = adjacent[v].get(edge) {
+                let top = visiting.len() - 1;
+                visiting[top].1 += 1;
+                if index[w] == usize::MAX {
+                    index[w] = next;
+                    low[w] = next;
+                    next += 1;
+                    stack.push(w);
+                    on_stack[w] = true;
+                    visiting.push((w, 0));
+                } else if on_stack[w] {
+                    low[v] = low[v].min(index[w]);
+                }
+                continue;
+            }
+            visiting.pop();
+            if let Some(&(parent, _)) = visiting.last() {
+                low[parent] = low[parent].min(low[v]);
+            }
+            if low[v] == index[v] {
+                let mut component = vec![];
+                while let Some(w) = stack.pop() {
+                    on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                result.push(component);
+            }
+        }
+    }
+    result
+}
+
+/// Index of the modules by base in the initialization order
+#[cfg(windows)]
+fn init_order<T: UDbgTarget + ?Sized>(target: &T) -> HashMap<usize, usize> {
+    crate::peb::Peb::of(target)
+        .and_then(|peb| peb.ldr_init_order_modules())
+        .log_error("init order")
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+        .map(|(i, m)| (m.base, i))
+        .collect()
+}
+
+#[cfg(not(windows))]
+fn init_order<T: UDbgTarget + ?Sized>(_target: &T) -> HashMap<usize, usize> {
+    HashMap::new()
+}
@@ -0,0 +1,512 @@
+//!
+//! Offline target: load the PE/ELF files at chosen bases without any process, so the addresses in logs
+//! or crash reports can be resolved with the same module, symbol and disassembly APIs of live targets.
+//!
+//! Each file is laid out as the loader maps it, and relocated to the chosen base: the base relocations
+//! of PE and the relative relocations of ELF are applied, so the absolute addresses in the disassembly
+//! are the same as in the original process.
+//!
+
+use crate::{elf::ElfHelper, pe::*, prelude::*};
+
+use goblin::elf::{header::*, program_header::*};
+use goblin::pe::section_table::*;
+use std::path::Path;
+use std::sync::Arc;
+
+const PAGE_MASK: usize = 0xFFF;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+const R_X86_64_RELATIVE: u32 = 8;
+const R_AARCH64_RELATIVE: u32 = 1027;
+
+/// A file loaded into [`OfflineTarget`]
+pub struct ImageModule {
+    pub data: ModuleData,
+    pub syms: SymbolsData,
+    /// Byte order of the file, always little for PE
+    pub endian: Endian,
+    image: Vec<u8>,
+    pages: Vec<MemoryPage>,
+}
+
+impl ImageModule {
+    /// Load a PE or ELF file at `base`, or at its preferred base if None
+    pub fn load<P: AsRef<Path>>(path: P, base: Option<usize>) -> UDbgResult<Self> {
+        let path = path.as_ref();
+        let file = std::fs::read(path)?;
+        match file.get(..4) {
+            Some([b'M', b'Z', ..]) => Self::load_pe(path, &file, base),
+            Some(b"\x7fELF") => Self::load_elf(path, &file, base),
+            _ => Err(UDbgError::NotSupport),
+        }
+    }
+
+    fn module_data(
+        path: &Path,
+        base: usize,
+        size: usize,
+        entry: usize,
+        arch: &'static str,
+    ) -> ModuleData {
+        let pathstr = path.to_string_lossy();
+        ModuleData {
+            user_module: false.into(),
+            base,
+            size,
+            entry,
+            arch,
+            name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into(),
+            path: pathstr.as_ref().into(),
+        }
+    }
+
+    fn load_pe(path: &Path, file: &[u8], base: Option<usize>) -> UDbgResult<Self> {
+        let pe = PeHelper::parse(file)?;
+        let opt = pe
+            .header
+            .optional_header
+            .as_ref()
+            .ok_or(UDbgError::NotSupport)?;
+        let size = opt.windows_fields.size_of_image as usize;
+        let base = base.unwrap_or(pe.image_base);
+
+        let mut image = vec![0u8; size];
+        let headers = (opt.windows_fields.size_of_headers as usize)
+            .min(file.len())
+            .min(size);
+        image[..headers].copy_from_slice(&file[..headers]);
+        let mut pages = vec![MemoryPage {
+            alloc_base: base,
+            base,
+            size: pe
+                .sections
+                .first()
+                .map_or(headers, |s| s.virtual_address as usize),
+            type_: MEM_IMAGE,
+            state: MEM_COMMIT,
+            protect: PAGE_READONLY,
+            alloc_protect: PAGE_READONLY,
+            info: Some(path.to_string_lossy().into()),
+            flags: MemoryFlags::IMAGE,
+        }];
+        for sec in pe.sections.iter() {
+            let rva = sec.virtual_address as usize;
+            let raw = sec.pointer_to_raw_data as usize;
+            let len = match sec.virtual_size {
+                0 => sec.size_of_raw_data,
+                vsize => sec.size_of_raw_data.min(vsize),
+            } as usize;
+            let len = len
+                .min(file.len().saturating_sub(raw))
+                .min(size.saturating_sub(rva));
+            // the raw data or the section is out of the file or image
+            if let (Some(dst), Some(src)) =
+                (image.get_mut(rva..rva + len), file.get(raw..raw + len))
+            {
+                dst.copy_from_slice(src);
+            }
+
+            let ch = sec.characteristics;
+            let protect = match (
+                IMAGE_SCN_MEM_EXECUTE & ch != 0,
+                IMAGE_SCN_MEM_WRITE & ch != 0,
+            ) {
+                (true, true) => PAGE_EXECUTE_READWRITE,
+                (true, false) => PAGE_EXECUTE_READ,
+                (false, true) => PAGE_READWRITE,
+                (false, false) => PAGE_READONLY,
+            };
+            pages.push(MemoryPage {
+                alloc_base: base,
+                alloc_protect: protect,
+                base: base + rva,
+                protect,
+                size: (sec.virtual_size as usize + PAGE_MASK) & !PAGE_MASK,
+                type_: MEM_IMAGE,
+                state: MEM_COMMIT,
+                info: Some(sec.name().unwrap_or_default().into()),
+                flags: MemoryFlags::IMAGE,
+            });
+        }
+
+        let delta = (base as u64).wrapping_sub(pe.image_base as u64);
+        if delta != 0 {
+            if let Some(dir) = opt.data_directories.get_base_relocation_table() {
+                relocate_pe(
+                    &mut image,
+                    dir.virtual_address as usize,
+                    dir.size as usize,
+                    delta,
+                );
+            }
+        }
+
+        Ok(Self {
+            data: Self::module_data(
+                path,
+                base,
+                size,
+                opt.standard_fields.address_of_entry_point as _,
+                pe.get_arch().unwrap_or_default(),
+            ),
+            syms: pe.symbols_data(&path.to_string_lossy()),
+            endian: Endian::Little,
+            image,
+            pages,
+        })
+    }
+
+    fn load_elf(path: &Path, file: &[u8], base: Option<usize>) -> UDbgResult<Self> {
+        let elf = ElfHelper::parse(file).ok_or(UDbgError::NotSupport)?;
+        let loads = elf
+            .program_headers
+            .iter()
+            .filter(|p| p.p_type == PT_LOAD)
+            .collect::<Vec<_>>();
+        let bias = loads
+            .iter()
+            .map(|p| p.p_vaddr as usize)
+            .min()
+            .unwrap_or_default()
+            & !PAGE_MASK;
+        let end = loads
+            .iter()
+            .map(|p| p.p_vaddr.saturating_add(p.p_memsz) as usize)
+            .max()
+            .ok_or(UDbgError::NotSupport)?;
+        let size = (end - bias + PAGE_MASK) & !PAGE_MASK;
+        // the executables are not position independent
+        let base = match base {
+            Some(base) if elf.header.e_type == ET_EXEC && base != bias => {
+                return Err(UDbgError::InvalidAddress)
+            }
+            Some(base) => base,
+            None => bias,
+        };
+
+        let mut image = vec![0u8; size];
+        let mut pages = vec![];
+        for p in loads {
+            let offset = p.p_vaddr as usize - bias;
+            let raw = p.p_offset as usize;
+            let len = (p.p_filesz.min(p.p_memsz) as usize).min(file.len().saturating_sub(raw));
+            // the segment data is out of the file
+            if let (Some(dst), Some(src)) = (
+                image.get_mut(offset..offset + len),
+                file.get(raw..raw + len),
+            ) {
+                dst.copy_from_slice(src);
+            }
+
+            let mut protect = *b"---p";
+            if p.is_read() {
+                protect[0] = b'r';
+            }
+            if p.is_write() {
+                protect[1] = b'w';
+            }
+            if p.is_executable() {
+                protect[2] = b'x';
+            }
+            let start = offset & !PAGE_MASK;
+            let end = (offset + p.p_memsz as usize + PAGE_MASK) & !PAGE_MASK;
+            pages.push(MemoryPage {
+                alloc_base: base,
+                base: base + start,
+                size: end - start,
+                type_: 0,
+                state: 0,
+                protect: u32::from_ne_bytes(protect),
+                alloc_protect: 0,
+                info: Some(path.to_string_lossy().into()),
+                flags: MemoryFlags::IMAGE,
+            });
+        }
+
+        let delta = base.wrapping_sub(bias);
+        if delta != 0 {
+            let relative = match elf.header.e_machine {
+                EM_X86_64 => R_X86_64_RELATIVE,
+                EM_AARCH64 => R_AARCH64_RELATIVE,
+                _ => {
+                    warn!("relocations of {} are not applied", path.display());
+                    0
+                }
+            };
+            for r in elf.dynrelas.iter().filter(|r| r.r_type == relative) {
+                let offset = (r.r_offset as usize).wrapping_sub(bias);
+                let value = (r.r_addend.unwrap_or_default() as usize).wrapping_add(base);
+                if let Some(slot) = image.get_mut(offset..offset + 8) {
+                    slot.copy_from_slice(&(value as u64).to_le_bytes());
+                }
+            }
+        }
+
+        Ok(Self {
+            data: Self::module_data(
+                path,
+                base,
+                size,
+                (elf.entry() as usize).wrapping_sub(bias),
+                elf.arch().unwrap_or_default(),
+            ),
+            syms: SymbolsData {
+                exports: elf.symbols(bias),
+                ..Default::default()
+            },
+            endian: if elf.little_endian {
+                Endian::Little
+            } else {
+                Endian::Big
+            },
+            image,
+            pages,
+        })
+    }
+}
+
+/// Apply the base relocations in the laid out image
+fn relocate_pe(image: &mut [u8], rva: usize, size: usize, delta: u64) {
+    let mut offset = rva;
+    let end = (rva + size).min(image.len());
+    while offset + 8 <= end {
+        let page = u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap()) as usize;
+        let block = u32::from_le_bytes(image[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        if block < 8 {
+            break;
+        }
+        // the last byte of an odd-sized block is not an entry
+        for i in (offset + 8..(offset + block).min(end) - 1).step_by(2) {
+            let entry = u16::from_le_bytes([image[i], image[i + 1]]);
+            let target = page + (entry & 0xFFF) as usize;
+            match entry >> 12 {
+                IMAGE_REL_BASED_HIGHLOW => {
+                    if let Some(slot) = image.get_mut(target..target + 4) {
+                        let value = u32::from_le_bytes(slot.try_into().unwrap());
+                        slot.copy_from_slice(&value.wrapping_add(delta as u32).to_le_bytes());
+                    }
+                }
+                IMAGE_REL_BASED_DIR64 => {
+                    if let Some(slot) = image.get_mut(target..target + 8) {
+                        let value = u64::from_le_bytes(slot.try_into().unwrap());
+                        slot.copy_from_slice(&value.wrapping_add(delta).to_le_bytes());
+                    }
+                }
+                _ => {}
+            }
+        }
+        offset += block;
+    }
+}
+
+impl GetProp for ImageModule {
+    fn get_prop(&self, key: &str) -> UDbgResult<serde_value::Value> {
+        Ok(serde_value::Value::Unit)
+    }
+}
+
+impl UDbgModule for ImageModule {
+    fn data(&self) -> &ModuleData {
+        &self.data
+    }
+
+    fn symbols_data(&self) -> Option<&SymbolsData> {
+        Some(&self.syms)
+    }
+
+    fn symbol_status(&self) -> SymbolStatus {
+        if self.syms.pdb.read().is_some() {
+            SymbolStatus::Loaded
+        } else {
+            SymbolStatus::Unload
+        }
+    }
+}
+
+/// A target of the modules loaded from files, which has no process, no threads and is read-only
+#[derive(Default)]
+pub struct OfflineTarget {
+    base: TargetBase,
+    symgr: SymbolManager<ImageModule>,
+}
+
+unsafe impl Send for OfflineTarget {}
+unsafe impl Sync for OfflineTarget {}
+
+impl OfflineTarget {
+    /// Load a module at `base` or its preferred base, it's failed if the module overlaps a loaded one.
+    /// The pointer size and byte order of target follow the first loaded module
+    pub fn load<P: AsRef<Path>>(
+        &self,
+        path: P,
+        base: Option<usize>,
+    ) -> UDbgResult<Arc<dyn UDbgModule>> {
+        let module = ImageModule::load(path, base)?;
+        let (address, size, arch) = (module.data.base, module.data.size, module.data.arch);
+        let endian = module.endian;
+        let mut modules = self.symgr.base.write();
+        if modules.list.iter().any(|m| {
+            let m = m.data();
+            m.base < address + size && address < m.base + m.size
+        }) {
+            return Err(UDbgError::InvalidAddress);
+        }
+        if modules.list.is_empty() {
+            self.base.context_arch.set(match arch {
+                "x86" => ARCH_X86,
+                "arm" => ARCH_ARM,
+                "arm64" => ARCH_ARM64,
+                _ => ARCH_X64,
+            });
+            self.base.endian.set(endian);
+        }
+        modules.add(module);
+        Ok(modules.find_module(address).ok_or(UDbgError::NotFound)?)
+    }
+
+    pub fn module(&self, address: usize) -> Option<Arc<ImageModule>> {
+        SymbolManager::find_module(&self.symgr, address)
+    }
+}
+
+impl ReadMemory for OfflineTarget {
+    fn read_memory<'a>(&self, addr: usize, data: &'a mut [u8]) -> Option<&'a mut [u8]> {
+        let m = self.module(addr)?;
+        let src = m.image.get(addr - m.data.base..)?;
+        let len = data.len().min(src.len());
+        let res = &mut data[..len];
+        res.copy_from_slice(&src[..len]);
+        Some(res)
+    }
+    fn data_layout(&self) -> DataLayout {
+        self.base.data_layout()
+    }
+}
+
+impl WriteMemory for OfflineTarget {
+    fn write_memory(&self, address: usize, data: &[u8]) -> Option<usize> {
+        None
+    }
+}
+
+impl TargetMemory for OfflineTarget {
+    fn enum_memory(&self) -> UDbgResult<Box<dyn Iterator<Item = MemoryPage> + '_>> {
+        Ok(Box::new(self.collect_memory_info().into_iter()))
+    }
+
+    fn virtual_query(&self, address: usize) -> Option<MemoryPage> {
+        self.module(address)?
+            .pages
+            .iter()
+            .find(|p| p.base <= address && address < p.base + p.size)
+            .cloned()
+    }
+
+    fn collect_memory_info(&self) -> Vec<MemoryPage> {
+        self.symgr
+            .base
+            .read()
+            .list
+            .iter()
+            .flat_map(|m| m.pages.iter().cloned())
+            .collect()
+    }
+}
+
+impl TargetControl for OfflineTarget {
+    fn detach(&self) -> UDbgResult<()> {
+        self.base.status.set(UDbgStatus::Detaching);
+        Ok(())
+    }
+
+    fn kill(&self) -> UDbgResult<()> {
+        Err(UDbgError::NotSupport)
+    }
+}
+
+impl Target for OfflineTarget {
+    fn base(&self) -> &TargetBase {
+        &self.base
+    }
+
+    /// Path of the first loaded module
+    fn image_path(&self) -> UDbgResult<String> {
+        let modules = self.symgr.base.read();
+        let first = modules.list.first().ok_or(UDbgError::NotFound)?;
+        Ok(first.data.path.to_string())
+    }
+
+    fn enum_thread(
+        &self,
+        detail: bool,
+    ) -> UDbgResult<Box<dyn Iterator<Item = Box<dyn UDbgThread>> + '_>> {
+        Ok(Box::new(core::iter::empty()))
+    }
+
+    fn symbol_manager(&self) -> Option<&dyn TargetSymbol> {
+        Some(&self.symgr)
+    }
+}
+
+impl GetProp for OfflineTarget {
+    fn get_prop(&self, key: &str) -> UDbgResult<serde_value::Value> {
+        Ok(serde_value::Value::Unit)
+    }
+}
+
+impl BreakpointManager for OfflineTarget {}
+
+impl UDbgTarget for OfflineTarget {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(page: u32, entries: &[u16]) -> Vec<u8> {
+        let mut result = page.to_le_bytes().to_vec();
+        result.extend(((8 + entries.len() * 2) as u32).to_le_bytes());
+        result.extend(entries.iter().flat_map(|e| e.to_le_bytes()));
+        result
+    }
+
+    #[test]
+    fn relocate() {
+        let mut image = vec![0u8; 0x2000];
+        image[0x10..0x14].copy_from_slice(&0x401000u32.to_le_bytes());
+        image[0x1008..0x1010].copy_from_slice(&0x140001000u64.to_le_bytes());
+        let mut relocs = block(0, &[0x3010, 0]);
+        relocs.extend(block(0x1000, &[0xA008]));
+        let rva = 0x1800;
+        image[rva..rva + relocs.len()].copy_from_slice(&relocs);
+
+        relocate_pe(&mut image, rva, relocs.len(), 0x10000);
+        assert_eq!(image[0x10..0x14], 0x411000u32.to_le_bytes());
+        assert_eq!(image[0x1008..0x1010], 0x140011000u64.to_le_bytes());
+    }
+
+    #[test]
+    fn relocate_malformed() {
+        // an odd-sized block ends at the end of image
+        let mut image = vec![0u8; 0x100];
+        let mut relocs = block(0, &[0x3010, 0x3020]);
+        relocs.pop();
+        let rva = image.len() - relocs.len();
+        let size = relocs.len() as u32;
+        relocs[4..8].copy_from_slice(&size.to_le_bytes());
+        image[rva..].copy_from_slice(&relocs);
+        relocate_pe(&mut image, rva, relocs.len(), 0x10000);
+        assert_eq!(image[0x10..0x14], 0x10000u32.to_le_bytes());
+
+        // the directory and the targets out of image
+        relocate_pe(&mut image, 0x1000, 0x10, 0x10000);
+        let mut image = block(0x1000, &[0xA000, 0x3000, 0x3008]);
+        let size = image.len();
+        relocate_pe(&mut image, 0, size, 0x10000);
+        assert_eq!(image, block(0x1000, &[0xA000, 0x3000, 0x3008]));
+    }
+}
@@ -178,6 +178,121 @@ pub fn ptrace_attach_wait(tid: pid_t, opt: c_int) -> nix::Result<WaitStatus> {
     Ok(status)
 }
 
+/// Bytes below the stack pointer which may be still used by the interrupted function
+#[cfg(target_arch = "x86_64")]
+pub const STACK_RED_ZONE: usize = 128;
+#[cfg(not(target_arch = "x86_64"))]
+pub const STACK_RED_ZONE: usize = 0;
+
+/// Stack alignment required by ABI at the function call
+pub const STACK_ALIGN: usize = 16;
+
+/// Wait for the thread of a remote call to stop by the fault at the return address `ret`. The
+/// ptrace-event stops and the group stops during the call are continued, the other signals are
+/// delivered to the thread as usual. Fails if the thread is gone, or it traps in the call, such as
+/// at a breakpoint, which can't be handled out of the event loop
+pub fn wait_remote_call(pid: pid_t, ret: usize) -> anyhow::Result<()> {
+    let tid = Pid::from_raw(pid);
+    let mut status = 0;
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, WUNTRACED | __WALL) } < 0 {
+            return Err(Errno::last()).context("wait remote call");
+        }
+        if WIFEXITED(status) || WIFSIGNALED(status) {
+            anyhow::bail!("thread {pid} exited in remote call, status {status:x}");
+        }
+        let mut sig = None;
+        // the event stops are reported as `SIGTRAP | event << 8`
+        if WIFSTOPPED(status) && status >> 16 == 0 {
+            let signo = Signal::try_from(WSTOPSIG(status)).context("stop signal")?;
+            match signo {
+                Signal::SIGSEGV | Signal::SIGBUS => {
+                    let si = ptrace::getsiginfo(tid).context("getsiginfo")?;
+                    // the thumb bit of the return address is not in the fault address
+                    if unsafe { si.si_addr() } as usize & !1 == ret & !1 {
+                        return Ok(());
+                    }
+                    sig = Some(signo);
+                }
+                Signal::SIGTRAP => anyhow::bail!("thread {pid} trapped in remote call"),
+                Signal::SIGSTOP => {}
+                _ => sig = Some(signo),
+            }
+        }
+        ptrace::cont(tid, sig).context("cont remote call")?;
+    }
+}
+
+/// Gap kept by the kernel under a growing stack, `stack_guard_gap` of 256 pages by default
+const STACK_GUARD_GAP: usize = 256 << 12;
+
+/// Soft limit of the stack size of a process, `usize::MAX` if unlimited
+fn stack_limit(pid: pid_t) -> Option<usize> {
+    Utils::file_lines(format!("/proc/{pid}/limits"))
+        .ok()?
+        .find_map(|line| {
+            let soft = line
+                .strip_prefix("Max stack size")?
+                .split_whitespace()
+                .next()?;
+            Some(soft.parse().unwrap_or(usize::MAX))
+        })
+}
+
+/// Reserve `size` bytes on the stack of a stopped thread for remote call:
+/// skip the red zone, keep `(result + misalign) % STACK_ALIGN == 0` as the ABI required,
+/// and ensure the reserved range lies in writable memory. The range in the gap under the main
+/// stack is accepted within the stack limit, the kernel grows the stack at the write of ptrace;
+/// the guard gap and the other unmapped memory are refused.
+///
+/// Returns the lowest address of reserved range, which is the new stack pointer
+pub fn reserve_call_stack(
+    pid: pid_t,
+    sp: usize,
+    size: usize,
+    misalign: usize,
+) -> anyhow::Result<usize> {
+    let top = sp
+        .checked_sub(STACK_RED_ZONE + size + misalign)
+        .context("stack underflow")?;
+    // not above `top`, so the range ends below the red zone
+    let result = (top & !(STACK_ALIGN - 1)) + misalign;
+
+    let pages = Process::from_pid(pid)?.enum_memory()?.collect::<Vec<_>>();
+    let limit = stack_limit(pid).unwrap_or(0);
+    let mut address = result;
+    let end = result + size;
+    for (i, page) in pages.iter().enumerate() {
+        if address >= end {
+            break;
+        }
+        if page.base + page.size <= address {
+            continue;
+        }
+        if page.base > address {
+            let below = i
+                .checked_sub(1)
+                .map_or(0, |i| pages[i].base + pages[i].size);
+            let growable = page.info.as_deref() == Some("[stack]")
+                && page.base + page.size - result <= limit
+                && below.saturating_add(STACK_GUARD_GAP) <= result;
+            if !growable {
+                anyhow::bail!(
+                    "stack memory {address:x} is not mapped, maybe in guard gap or stack overflow"
+                );
+            }
+        }
+        if !page.is_writable() {
+            anyhow::bail!("stack memory {address:x} is not writable, maybe in guard page");
+        }
+        address = page.base + page.size;
+    }
+    if address < end {
+        anyhow::bail!("stack memory {address:x} is not mapped");
+    }
+    Ok(result)
+}
+
 impl ProcessInfo {
     pub fn enumerate() -> IoResult<impl Iterator<Item = Self>> {
         Ok(PidIter::proc()?.map(|pid| Self {
@@ -206,9 +321,19 @@ impl TraceBuf<'_> {
     }
 }
 
+impl TraceBuf<'_> {
+    /// Record the registers changed in the event before writing them back
+    fn audit_regs(&self, tid: tid_t, old: &dyn UDbgRegs) {
+        let base = &self.target.base;
+        base.audit
+            .record_regs(base.pid.get(), tid, UDBG_ARCH, old, &self.user.regs);
+    }
+}
+
 #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
 mod arch_util {
     use super::*;
+    use core::mem::size_of;
 
     pub type user_regs = libc::user;
     pub type user_hwdebug_state = [reg_t; 8];
@@ -298,6 +423,11 @@ mod arch_util {
         }
 
         pub fn write_regs(&self, tid: tid_t) {
+            if self.target.base.audit.is_enabled() {
+                if let Ok(old) = ptrace::getregs(Pid::from_raw(tid)) {
+                    self.audit_regs(tid, &old);
+                }
+            }
             ptrace::setregs(Pid::from_raw(tid), self.user.regs);
         }
     }
@@ -347,8 +477,77 @@ mod arch_util {
         }
     }
 
+    #[cfg(target_arch = "x86_64")]
     pub fn call_remote(pid: pid_t, fp: usize, ret: usize, args: &[reg_t]) -> anyhow::Result<reg_t> {
-        unimplemented!();
+        const REGS_ARG_NUM: usize = 6;
+
+        let tid = Pid::from_raw(pid);
+        let mut regs = ptrace::getregs(tid).context("getregs")?;
+        let bak = regs;
+        {
+            let r = &mut regs;
+            let arg_regs = [&mut r.rdi, &mut r.rsi, &mut r.rdx, &mut r.rcx, &mut r.r8, &mut r.r9];
+            for (reg, &arg) in arg_regs.into_iter().zip(args.iter()) {
+                *reg = arg;
+            }
+        }
+        // return address, then the arguments on stack
+        let mut stack = vec![ret as reg_t];
+        stack.extend_from_slice(args.get(REGS_ARG_NUM..).unwrap_or_default());
+        let size = stack.len() * size_of::<reg_t>();
+        // (rsp + 8) should be aligned at function entry
+        let sp = reserve_call_stack(pid, regs.rsp as usize, size, size_of::<reg_t>())?;
+        ptrace_write(pid, sp, stack.as_byte_array())
+            .map_err(|_| anyhow::Error::msg("write stack"))?;
+
+        regs.rsp = sp as reg_t;
+        regs.rip = fp as reg_t;
+        // number of vector registers used by variadic function
+        regs.rax = 0;
+        // avoid the kernel restarting an interrupted syscall
+        regs.orig_rax = reg_t::MAX;
+
+        ptrace::setregs(tid, regs).context("setregs")?;
+        ptrace::cont(tid, None).context("cont")?;
+
+        if let Err(err) = wait_remote_call(pid, ret) {
+            ptrace::setregs(tid, bak).ok();
+            return Err(err);
+        }
+        let result = ptrace::getregs(tid).context("getregs")?;
+        ptrace::setregs(tid, bak).context("setregs")?;
+
+        Ok(result.rax)
+    }
+
+    #[cfg(target_arch = "x86")]
+    pub fn call_remote(pid: pid_t, fp: usize, ret: usize, args: &[reg_t]) -> anyhow::Result<reg_t> {
+        let tid = Pid::from_raw(pid);
+        let mut regs = ptrace::getregs(tid).context("getregs")?;
+        let bak = regs;
+        // cdecl: return address, then all the arguments on stack
+        let mut stack = vec![ret as reg_t];
+        stack.extend_from_slice(args);
+        let size = stack.len() * size_of::<reg_t>();
+        let sp = reserve_call_stack(pid, regs.esp as usize, size, size_of::<reg_t>())?;
+        ptrace_write(pid, sp, stack.as_byte_array())
+            .map_err(|_| anyhow::Error::msg("write stack"))?;
+
+        regs.esp = sp as _;
+        regs.eip = fp as _;
+        regs.orig_eax = -1;
+
+        ptrace::setregs(tid, regs).context("setregs")?;
+        ptrace::cont(tid, None).context("cont")?;
+
+        if let Err(err) = wait_remote_call(pid, ret) {
+            ptrace::setregs(tid, bak).ok();
+            return Err(err);
+        }
+        let result = ptrace::getregs(tid).context("getregs")?;
+        ptrace::setregs(tid, bak).context("setregs")?;
+
+        Ok(result.eax as reg_t)
     }
 }
 
@@ -465,6 +664,12 @@ mod arch_util {
         }
 
         pub fn write_regs(&self, tid: tid_t) {
+            if self.target.base.audit.is_enabled() {
+                let mut old: user_regs_struct = unsafe { zeroed() };
+                if ptrace_getregs(tid, &mut old).is_ok() {
+                    self.audit_regs(tid, &old);
+                }
+            }
             ptrace_setregs(tid, &self.user.regs);
         }
     }
@@ -590,20 +795,25 @@ mod arch_util {
             for i in 0..REGS_ARG_NUM.min(args.len()) {
                 regs.regs[i] = args[i];
             }
-            if args.len() > REGS_ARG_NUM {
-                let stack_num = args.len() - REGS_ARG_NUM;
-                *regs.sp() -= (size_of::<reg_t>() * stack_num) as reg_t;
-                ptrace_write(pid, *regs.sp() as _, args[REGS_ARG_NUM..].as_byte_array());
+            let stack = args.get(REGS_ARG_NUM..).unwrap_or_default();
+            let sp = reserve_call_stack(pid, *regs.sp() as usize, size_of_val(stack), 0)?;
+            if !stack.is_empty() {
+                ptrace_write(pid, sp, stack.as_byte_array())
+                    .map_err(|_| anyhow::Error::msg("write stack"))?;
             }
+            *regs.sp() = sp as reg_t;
 
             *regs.lr() = ret as reg_t;
             *regs.ip() = fp as reg_t;
 
             ptrace_setregs(pid, &regs).context("setregs")?;
-            ptrace::cont(Pid::from_raw(pid), None);
+            ptrace::cont(Pid::from_raw(pid), None).context("cont")?;
 
-            libc::waitpid(pid, core::ptr::null_mut(), WUNTRACED);
-            ptrace_getregs(pid, &mut regs);
+            if let Err(err) = wait_remote_call(pid, ret) {
+                ptrace_setregs(pid, &bak).ok();
+                return Err(err);
+            }
+            ptrace_getregs(pid, &mut regs).context("getregs")?;
             ptrace_setregs(pid, &bak).context("setregs")?;
 
             Ok(regs.regs[0])
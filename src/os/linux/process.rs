@@ -169,6 +169,50 @@ impl Process {
         }))
     }
 
+    /// Shared mappings of a process: (address range, device, inode)
+    fn shared_maps(
+        pid: pid_t,
+    ) -> IoResult<impl Iterator<Item = (core::ops::Range<usize>, String, u64)>> {
+        Ok(
+            Utils::file_lines(format!("/proc/{}/maps", pid))?.filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let (begin, end) = fields.next()?.split_once('-')?;
+                let range =
+                    usize::from_str_radix(begin, 16).ok()?..usize::from_str_radix(end, 16).ok()?;
+                if !fields.next()?.ends_with('s') {
+                    return None;
+                }
+                let dev = fields.nth(1)?.to_string();
+                let inode = fields.next()?.parse().ok()?;
+                Some((range, dev, inode))
+            }),
+        )
+    }
+
+    /// Find the processes which map the same shared memory at `address`, matched by device and inode of the mapping
+    pub fn shared_with(&self, address: usize) -> UDbgResult<Vec<SharedView>> {
+        let (dev, inode) = Self::shared_maps(self.pid)?
+            .find(|(r, ..)| r.contains(&address))
+            .map(|(_, dev, inode)| (dev, inode))
+            .ok_or(UDbgError::NotFound)?;
+        if inode == 0 {
+            return Err(UDbgError::NotSupport);
+        }
+        Ok(PidIter::proc()?
+            .filter(|&pid| pid != self.pid)
+            .filter_map(|pid| Some((pid, Self::shared_maps(pid).ok()?)))
+            .flat_map(|(pid, maps)| {
+                maps.filter(|(_, d, i)| *i == inode && d == &dev)
+                    .map(move |(r, ..)| SharedView {
+                        pid,
+                        handle: None,
+                        address: Some(r.start),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+
     pub fn enum_module(&self) -> IoResult<impl Iterator<Item = Module> + '_> {
         Ok(ModuleIter {
             f: Utils::file_lines(format!("/proc/{}/maps", self.pid))?,
@@ -201,6 +245,9 @@ impl ReadMemory for Process {
 
 impl WriteMemory for Process {
     fn write_memory(&self, address: usize, data: &[u8]) -> Option<usize> {
+        if crate::consts::PASSIVE {
+            return None;
+        }
         self.open_mem()?;
         self.mem.read().as_ref().and_then(move |f| unsafe {
             let n = pwrite64(
@@ -223,6 +270,12 @@ impl WriteMemory for Process {
     //         err => Err(std::io::Error::last_os_error()),
     //     }
     // }
+
+    fn flush_cache(&self, address: usize, len: usize) -> IoResult<()> {
+        // writes through /proc/pid/mem and ptrace go to copy_to_user_page() in kernel,
+        // which already synchronizes the icache of executable mapping on ARM/AArch64
+        Ok(())
+    }
 }
 
 struct LineParser<'a> {
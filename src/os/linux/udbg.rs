@@ -1,4 +1,6 @@
 use super::*;
+use crate::audit::{audit_write, AuditAction};
+use crate::dwarf::DwarfData;
 use crate::elf::*;
 use crate::os::udbg::{EventHandler, HandleResult};
 use crate::range::RangeValue;
@@ -7,11 +9,12 @@ use anyhow::Context;
 use goblin::elf::sym::Sym;
 use nix::sys::ptrace::Options;
 use nix::sys::wait::waitpid;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use procfs::process::{Stat as ThreadStat, Task};
 use serde_value::Value;
 use std::cell::{Cell, UnsafeCell};
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
+use std::io::{Error as IoError, ErrorKind};
 use std::mem::transmute;
 use std::ops::Deref;
 use std::time::{Duration, Instant};
@@ -56,6 +59,7 @@ impl From<ElfSym<'_>> for ElfSymbol {
 pub struct NixThread {
     #[deref]
     base: ThreadData,
+    pid: pid_t,
     stat: ThreadStat,
 }
 
@@ -68,12 +72,37 @@ impl TryFrom<Task> for NixThread {
                 tid: task.tid,
                 wow64: false,
             },
+            pid: task.pid,
             stat: task.stat()?,
         })
     }
 }
 
-impl GetProp for NixThread {}
+impl NixThread {
+    /// Registers of the thread, only available when it's traced and stopped
+    pub fn get_regs(&self) -> UDbgResult<user_regs_struct> {
+        #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+        let regs = ptrace::getregs(Pid::from_raw(self.tid)).context("getregs")?;
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+        let regs = unsafe {
+            let mut regs: user_regs_struct = core::mem::zeroed();
+            ptrace_getregs(self.tid, &mut regs).context("getregs")?;
+            regs
+        };
+        Ok(regs)
+    }
+}
+
+impl GetProp for NixThread {
+    fn get_prop(&self, key: &str) -> UDbgResult<serde_value::Value> {
+        if let Some(reg) = key.strip_prefix("@") {
+            let regs = self.get_regs()?;
+            Ok(serde_value::to_value(regs.get(reg).ok_or(UDbgError::InvalidRegister)?).unwrap())
+        } else {
+            Err(UDbgError::NotSupport)
+        }
+    }
+}
 
 impl UDbgThread for NixThread {
     fn name(&self) -> Arc<str> {
@@ -85,24 +114,57 @@ impl UDbgThread for NixThread {
     fn priority(&self) -> Option<i64> {
         Some(self.stat.priority)
     }
-}
 
-#[inline(always)]
-fn to_symbol(s: ElfSym) -> Symbol {
-    let flags = if s.is_function() {
-        SymbolFlags::FUNCTION
-    } else {
-        SymbolFlags::NONE
-    };
-    Symbol {
-        offset: s.st_value as u32,
-        name: s.name.into(),
-        flags: flags.bits(),
-        len: s.st_size as u32,
-        type_id: 0,
+    /// Stop the traced thread by SIGSTOP and wait it, on the tracer thread as the other ptrace
+    /// requests. A thread in a stop already, such as the one of current event, is left to the
+    /// event loop and not continued by [`UDbgThread::resume`]
+    fn suspend(&self) -> IoResult<i32> {
+        let state = Task::new(self.pid, self.tid)
+            .and_then(|t| t.stat())
+            .map_err(|err| IoError::new(ErrorKind::NotFound, err))?
+            .state;
+        if matches!(state, 't' | 'T') {
+            return Ok(1);
+        }
+        if unsafe { libc::syscall(libc::SYS_tgkill, self.pid, self.tid, SIGSTOP) } != 0 {
+            return Err(IoError::last_os_error());
+        }
+        let waited = loop {
+            match waitpid(Pid::from_raw(self.tid), Some(WaitPidFlag::__WALL))? {
+                WaitStatus::Stopped(_, Signal::SIGSTOP) => break None,
+                status @ (WaitStatus::Exited(..) | WaitStatus::Signaled(..)) => {
+                    hold_status(self.pid, status);
+                    return Err(ErrorKind::NotFound.into());
+                }
+                // stopped by another event before the SIGSTOP, reported when resumed
+                status => break Some(status),
+            }
+        };
+        SUSPENDED.lock().push((self.tid, waited));
+        Ok(1)
+    }
+
+    /// Continue the thread stopped by [`UDbgThread::suspend`], the stop waited in place of the
+    /// SIGSTOP is left to the event loop, and the SIGSTOP pending is reported as well
+    fn resume(&self) -> IoResult<u32> {
+        let waited = {
+            let mut suspended = SUSPENDED.lock();
+            match suspended.iter().position(|s| s.0 == self.tid) {
+                Some(i) => suspended.swap_remove(i).1,
+                None => return Ok(0),
+            }
+        };
+        match waited {
+            Some(status) => hold_status(self.pid, status),
+            None => ptrace::cont(Pid::from_raw(self.tid), None)?,
+        }
+        Ok(0)
     }
 }
 
+/// The threads stopped by [`NixThread::suspend`], with the stop waited in place of the SIGSTOP
+static SUSPENDED: spin::Mutex<Vec<(tid_t, Option<WaitStatus>)>> = spin::Mutex::new(Vec::new());
+
 impl SymbolsData {
     fn from_elf(path: &str) -> Self {
         let mut this = Self::default();
@@ -113,16 +175,11 @@ impl SymbolsData {
     fn load(&mut self, path: &str) -> anyhow::Result<()> {
         let map = Utils::mapfile(path.as_ref()).context("map")?;
         let e = ElfHelper::parse(&map).context("parse")?;
-        let mut push_symbol = |s: ElfSym| {
-            if s.name.starts_with("$x.") {
-                return;
-            }
-            self.exports
-                .entry(s.offset())
-                .or_insert_with(|| to_symbol(s));
-        };
-        e.enum_symbol().for_each(&mut push_symbol);
-        e.enum_export().for_each(&mut push_symbol);
+        self.exports = e.symbols(0);
+        // parsed on first use
+        if let Ok(dwarf) = DwarfData::load(path) {
+            *self.pdb.write() = Some(Arc::new(dwarf));
+        }
         Ok(())
     }
 }
@@ -157,10 +214,63 @@ pub struct TargetCommon {
     tc_memory: TimeCheck,
     mem_pages: RwLock<Vec<MemoryPage>>,
     waiting: Cell<bool>,
+    /// Base of the modules found by [`TargetCommon::update_module`], to be reported
+    loaded: Mutex<Vec<usize>>,
     pub trace_opts: Options,
     pub hwbps: UnsafeCell<user_hwdebug_state>,
 }
 
+/// Open flags of the file descriptor, in `/proc/<pid>/fdinfo/<fd>`
+fn fd_flags(pid: pid_t, fd: pid_t) -> Option<u32> {
+    let info = std::fs::read_to_string(format!("/proc/{pid}/fdinfo/{fd}")).ok()?;
+    info.lines()
+        .find_map(|l| l.strip_prefix("flags:"))
+        .and_then(|f| u32::from_str_radix(f.trim(), 8).ok())
+}
+
+/// Enumerate the file descriptors of process, not required to be traced
+pub fn enum_process_handle(pid: pid_t) -> UDbgResult<Box<dyn Iterator<Item = HandleInfo>>> {
+    use std::os::unix::fs::FileTypeExt;
+
+    Ok(Box::new(
+        PidIter::proc_fd(pid)?
+            .filter_map(move |id| Some((id, read_link(format!("/proc/{}/fd/{}", pid, id)).ok()?)))
+            .map(move |(id, path)| {
+                let ps = &path.to_string_lossy();
+                let ts = path
+                    .metadata()
+                    .map(|m| {
+                        let ft = m.file_type();
+                        if ft.is_fifo() {
+                            "FIFO"
+                        } else if ft.is_socket() {
+                            "Socket"
+                        } else if ft.is_block_device() {
+                            "Block"
+                        } else {
+                            "File"
+                        }
+                    })
+                    .unwrap_or_else(|_| {
+                        if ps.starts_with("socket:") {
+                            "Socket"
+                        } else if ps.starts_with("pipe:") {
+                            "Pipe"
+                        } else {
+                            ""
+                        }
+                    });
+                HandleInfo {
+                    ty: 0,
+                    handle: id as _,
+                    type_name: ts.to_string(),
+                    name: ps.to_string(),
+                    access: fd_flags(pid, id).unwrap_or_default(),
+                }
+            }),
+    ))
+}
+
 impl TargetCommon {
     pub fn new(ps: Process) -> Self {
         const TIMEOUT: Duration = Duration::from_secs(5);
@@ -177,7 +287,8 @@ impl TargetCommon {
             | Options::PTRACE_O_TRACECLONE
             | Options::PTRACE_O_TRACEEXEC
             | Options::PTRACE_O_TRACEVFORK
-            | Options::PTRACE_O_TRACEFORK;
+            | Options::PTRACE_O_TRACEFORK
+            | Options::PTRACE_O_TRACESYSGOOD;
         Self {
             _base: base,
             tc_module: TimeCheck::new(Duration::from_secs(10)),
@@ -186,6 +297,7 @@ impl TargetCommon {
             threads: RwLock::new(HashSet::new()),
             trace_opts,
             waiting: Cell::new(false),
+            loaded: Default::default(),
             hwbps: unsafe { core::mem::zeroed() },
         }
     }
@@ -291,8 +403,7 @@ impl TargetCommon {
                 loaded: false.into(),
                 syms: SymbolsData::from_elf(&path).into(),
             });
-            // TODO:
-            // self.base.module_load(&path, base);
+            self.loaded.lock().push(base);
         }
         Ok(())
     }
@@ -356,12 +467,13 @@ impl TargetCommon {
         };
 
         bp.hit_count.set(bp.hit_count.get() + 1);
-        if bp.temp.get() {
+        // a temporary breakpoint is kept until hit in its thread and frame
+        let hitted = bp.reports_hit(tid, *tb.user.sp() as usize);
+        if hitted && bp.temp.get() {
             self.remove_breakpoint(this, &bp);
         }
 
         // handle by user
-        let hitted = bp.hit_tid.map(|t| t == tid).unwrap_or(true);
         if hitted {
             self.handle_reply(this, tb.call(UEvent::Breakpoint(bp.clone())), &mut tb.user);
         }
@@ -433,47 +545,7 @@ impl TargetCommon {
     }
 
     fn enum_handle<'a>(&'a self) -> UDbgResult<Box<dyn Iterator<Item = HandleInfo> + 'a>> {
-        use std::os::unix::fs::FileTypeExt;
-
-        let pid = self.process.pid;
-        Ok(Box::new(
-            PidIter::proc_fd(pid)?
-                .filter_map(move |id| {
-                    Some((id, read_link(format!("/proc/{}/fd/{}", pid, id)).ok()?))
-                })
-                .map(|(id, path)| {
-                    let ps = &path.to_string_lossy();
-                    let ts = path
-                        .metadata()
-                        .map(|m| {
-                            let ft = m.file_type();
-                            if ft.is_fifo() {
-                                "FIFO"
-                            } else if ft.is_socket() {
-                                "Socket"
-                            } else if ft.is_block_device() {
-                                "Block"
-                            } else {
-                                "File"
-                            }
-                        })
-                        .unwrap_or_else(|_| {
-                            if ps.starts_with("socket:") {
-                                "Socket"
-                            } else if ps.starts_with("pipe:") {
-                                "Pipe"
-                            } else {
-                                ""
-                            }
-                        });
-                    HandleInfo {
-                        ty: 0,
-                        handle: id as _,
-                        type_name: ts.to_string(),
-                        name: ps.to_string(),
-                    }
-                }),
-        ))
+        enum_process_handle(self.process.pid)
     }
 
     pub fn enable_hwbp(
@@ -717,7 +789,9 @@ impl ProcessTarget {
 
 impl WriteMemory for ProcessTarget {
     fn write_memory(&self, addr: usize, data: &[u8]) -> Option<usize> {
-        self.process.write_memory(addr, data)
+        audit_write(&self.process, &self.base, addr, data.len(), false, || {
+            self.process.write_memory(addr, data)
+        })
         // ptrace_write(self.pid.get(), addr, data);
         // Some(data.len())
     }
@@ -751,6 +825,9 @@ impl GetProp for ProcessTarget {
 
 impl TargetControl for ProcessTarget {
     fn detach(&self) -> UDbgResult<()> {
+        self.base
+            .audit
+            .record(self.base.pid.get(), AuditAction::Detach);
         self.base.status.set(UDbgStatus::Detaching);
         if self.waiting.get() {
             self.breakk()
@@ -762,6 +839,9 @@ impl TargetControl for ProcessTarget {
 
     fn kill(&self) -> UDbgResult<()> {
         if unsafe { kill(self.process.pid, SIGKILL) } == 0 {
+            self.base
+                .audit
+                .record(self.base.pid.get(), AuditAction::Terminate);
             Ok(())
         } else {
             Err(UDbgError::system())
@@ -823,6 +903,7 @@ impl Target for ProcessTarget {
         let task = Task::new(self.process.pid, tid).context("task")?;
         Ok(Box::new(NixThread {
             base: ThreadData { tid, wow64: false },
+            pid: self.process.pid,
             stat: task.stat().context("stat")?,
         }))
     }
@@ -852,7 +933,12 @@ impl UDbgTarget for ProcessTarget {}
 impl EventHandler for DefaultEngine {
     fn fetch(&mut self, buf: &mut TraceBuf) -> Option<()> {
         loop {
-            self.status = waitpid(None, Some(WaitPidFlag::__WALL)).ok()?;
+            let held = HELD.lock().pop_front();
+            self.status = match held {
+                Some((_, status)) => status,
+                None => waitpid(None, Some(WaitPidFlag::__WALL)).ok()?,
+            };
+            crate::metrics::stop_begin();
             // info!("[status] {:?}", self.status);
             self.tid = self
                 .status
@@ -862,7 +948,8 @@ impl EventHandler for DefaultEngine {
 
             if matches!(
                 self.status,
-                WaitStatus::Stopped(_, _) //  | WaitStatus::Signaled(_, _, _)
+                WaitStatus::Stopped(_, _) | WaitStatus::PtraceSyscall(_)
+                //  | WaitStatus::Signaled(_, _, _)
             ) {
                 buf.update_regs(self.tid);
                 buf.update_siginfo(self.tid);
@@ -874,11 +961,16 @@ impl EventHandler for DefaultEngine {
                 // );
             }
 
-            let target = self
-                .targets
-                .iter()
-                .find(|&t| self.tid == t.pid() as tid_t || t.threads.read().contains(&self.tid))
-                .cloned()
+            let target = held
+                .and_then(|(pid, _)| self.targets.iter().find(|t| t.pid() == pid).cloned())
+                .or_else(|| {
+                    self.targets
+                        .iter()
+                        .find(|&t| {
+                            self.tid == t.pid() as tid_t || t.threads.read().contains(&self.tid)
+                        })
+                        .cloned()
+                })
                 .or_else(|| {
                     self.targets
                         .iter()
@@ -894,6 +986,14 @@ impl EventHandler for DefaultEngine {
                     break;
                 }
 
+                // the thread was run out of the event loop, its stop is reported as it is
+                if held.is_some() {
+                    if target.threads.write().insert(self.tid) {
+                        buf.call(UEvent::ThreadCreate(self.tid));
+                    }
+                    break;
+                }
+
                 let mut cont = false;
                 if target.base.status.get() < UDbgStatus::Attached {
                     target.base.status.set(UDbgStatus::Attached);
@@ -908,12 +1008,13 @@ impl EventHandler for DefaultEngine {
                 }
 
                 if cont {
-                    ptrace::cont(Pid::from_raw(self.tid), None);
+                    resume(&target, Pid::from_raw(self.tid), None);
                     continue;
                 }
 
                 break;
             } else {
+                // not a thread of the targets, no syscall is traced for it
                 udbg_ui().warn(format!("{} is not traced", self.tid));
                 ptrace::cont(Pid::from_raw(self.tid), None);
             }
@@ -987,6 +1088,12 @@ impl EventHandler for DefaultEngine {
                 }
                 None
             }
+            WaitStatus::PtraceSyscall(_) => {
+                if let Some(event) = this.base.syscall.on_syscall_stop(tid, &buf.user.regs) {
+                    buf.call(event);
+                }
+                None
+            }
             // exited with exception
             WaitStatus::Signaled(_, sig, coredump) => {
                 buf.call(UEvent::Exception {
@@ -1017,6 +1124,9 @@ impl EventHandler for DefaultEngine {
         let tid = Pid::from_raw(self.tid as _);
 
         if this.base.status.get() == UDbgStatus::Detaching {
+            #[cfg(not(feature = "passive"))]
+            this.base.hooks.remove_all(this.as_ref());
+            this.base.mem_watch.remove_all(this.as_ref());
             for bp in this.get_breakpoints() {
                 bp.enable(false);
             }
@@ -1030,6 +1140,24 @@ impl EventHandler for DefaultEngine {
             buf.write_regs(self.tid);
         }
 
+        resume(&this, tid, sig);
+    }
+}
+
+/// Stops of the traced threads waited out of the event loop, with the pid of their targets
+static HELD: spin::Mutex<VecDeque<(pid_t, WaitStatus)>> = spin::Mutex::new(VecDeque::new());
+
+/// Leave a stop waited out of the event loop, such as by waiting a remote thread, to the
+/// event loop, which reports it before waiting the next one
+pub fn hold_status(pid: pid_t, status: WaitStatus) {
+    HELD.lock().push_back((pid, status));
+}
+
+/// Continue the stopped thread, to the next syscall stop if tracing the syscalls
+fn resume(target: &ProcessTarget, tid: Pid, sig: Option<Signal>) {
+    if target.base.syscall.is_enabled() {
+        ptrace::syscall(tid, sig);
+    } else {
         ptrace::cont(tid, sig);
     }
 }
@@ -1042,6 +1170,18 @@ pub struct DefaultEngine {
     pub tid: tid_t,
 }
 
+impl DefaultEngine {
+    /// Report the modules loaded since the last stop, such as by `dlopen`
+    fn report_modules(buf: &mut TraceBuf) {
+        let loaded = core::mem::take(&mut *buf.target.loaded.lock());
+        for base in loaded {
+            if let Some(m) = buf.target.symgr.find_module(base) {
+                buf.call(UEvent::ModuleLoad(m));
+            }
+        }
+    }
+}
+
 impl Default for DefaultEngine {
     fn default() -> Self {
         Self {
@@ -1060,6 +1200,10 @@ impl UDbgEngine for DefaultEngine {
     }
 
     fn attach(&mut self, pid: pid_t) -> UDbgResult<Arc<dyn UDbgTarget>> {
+        if crate::consts::PASSIVE {
+            return Err(UDbgError::NotSupport);
+        }
+        check_self_debug(pid)?;
         let this = ProcessTarget::open(pid)?;
         // attach each of threads
         for tid in this.process.tasks()?.filter_map(|t| t.ok().map(|t| t.tid)) {
@@ -1078,6 +1222,9 @@ impl UDbgEngine for DefaultEngine {
         cwd: Option<&str>,
         args: &[&str],
     ) -> UDbgResult<Arc<dyn UDbgTarget>> {
+        if crate::consts::PASSIVE {
+            return Err(UDbgError::NotSupport);
+        }
         match unsafe { libc::fork() } {
             0 => unsafe {
                 use std::ffi::CString;
@@ -1106,6 +1253,7 @@ impl UDbgEngine for DefaultEngine {
     }
 
     fn event_loop<'a>(&mut self, callback: &mut UDbgCallback<'a>) -> UDbgResult<()> {
+        let _guard = EventLoopGuard::enter()?;
         self.targets.iter().for_each(|t| {
             t.update_module();
             t.update_memory_page();
@@ -1133,9 +1281,10 @@ impl UDbgEngine for DefaultEngine {
         buf.call(UEvent::ProcessCreate);
         buf.target.insert_thread(self.tid);
         buf.call(UEvent::ThreadCreate(self.tid));
-        ptrace::cont(Pid::from_raw(self.tid), None);
+        resume(&buf.target, Pid::from_raw(self.tid), None);
 
         while let Some(s) = self.fetch(buf).and_then(|_| self.handle(buf)) {
+            Self::report_modules(buf);
             self.cont(s, buf);
             if self.targets.is_empty() {
                 break;
@@ -130,3 +130,21 @@ pub struct vnode_fdinfowithpath {
     pub pfi: proc_fileinfo,
     pub pvip: vnode_info_path,
 }
+
+// https://opensource.apple.com/source/xnu/xnu-4570.41.2/osfmk/mach/vm_attributes.h
+
+pub type vm_machine_attribute_t = c_uint;
+pub type vm_machine_attribute_val_t = c_int;
+
+pub const MATTR_CACHE: vm_machine_attribute_t = 1;
+pub const MATTR_VAL_CACHE_FLUSH: vm_machine_attribute_val_t = 6;
+
+extern "C" {
+    pub fn mach_vm_machine_attribute(
+        target_task: mach_port_t,
+        address: u64,
+        size: u64,
+        attribute: vm_machine_attribute_t,
+        value: *mut vm_machine_attribute_val_t,
+    ) -> c_int;
+}
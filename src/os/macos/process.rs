@@ -43,15 +43,37 @@ impl ReadMemory for Process {
 
 impl WriteMemory for Process {
     fn write_memory(&self, address: usize, data: &[u8]) -> Option<usize> {
+        if crate::consts::PASSIVE {
+            return None;
+        }
         unsafe {
             if mach_vm_write(self.task, address as _, data.as_ptr() as _, data.len() as _)
-                == KERN_SUCCESS
+                != KERN_SUCCESS
             {
-                Some(data.len())
-            } else {
-                None
+                return None;
+            }
+        }
+        // executable page, keep the instruction cache coherent
+        if Process::virtual_query(self, address as _).map_or(false, |p| p.is_executable()) {
+            if let Err(err) = self.flush_cache(address, data.len()) {
+                warn!("flush cache {address:x}: {err:?}");
             }
         }
+        Some(data.len())
+    }
+
+    fn flush_cache(&self, address: usize, len: usize) -> IoResult<()> {
+        use super::ffi::{mach_vm_machine_attribute, MATTR_CACHE, MATTR_VAL_CACHE_FLUSH};
+
+        let mut value = MATTR_VAL_CACHE_FLUSH;
+        let r = unsafe {
+            mach_vm_machine_attribute(self.task, address as _, len as _, MATTR_CACHE, &mut value)
+        };
+        if r == KERN_SUCCESS {
+            Ok(())
+        } else {
+            Err(IoErr::from_raw_os_error(r))
+        }
     }
 }
 
@@ -312,6 +334,7 @@ impl Process {
                 handle: fd.proc_fd as _,
                 type_name,
                 name,
+                access: 0,
             }
         }))
     }
@@ -459,6 +459,10 @@ impl UDbgEngine for DefaultEngine {
     }
 
     fn attach(&mut self, pid: pid_t) -> UDbgResult<Arc<dyn UDbgTarget>> {
+        if crate::consts::PASSIVE {
+            return Err(UDbgError::NotSupport);
+        }
+        check_self_debug(pid)?;
         let this = ProcessTarget::open(pid)?;
         this.set_exception_port(self.excp_port.0)?;
         self.targets.push(this.clone());
@@ -471,6 +475,9 @@ impl UDbgEngine for DefaultEngine {
         cwd: Option<&str>,
         args: &[&str],
     ) -> UDbgResult<Arc<dyn UDbgTarget>> {
+        if crate::consts::PASSIVE {
+            return Err(UDbgError::NotSupport);
+        }
         let this = ProcessTarget::create(path, args)?;
         this.set_exception_port(self.excp_port.0)?;
         self.targets.push(this.clone());
@@ -479,6 +486,7 @@ impl UDbgEngine for DefaultEngine {
     }
 
     fn event_loop<'a>(&mut self, callback: &mut UDbgCallback<'a>) -> UDbgResult<()> {
+        let _guard = EventLoopGuard::enter()?;
         let mut buf = TraceBuf {
             callback,
             user: unsafe { core::mem::zeroed() },
@@ -4,6 +4,7 @@ use core::ops::Deref;
 use std::{cell::Cell, sync::Arc};
 
 use crate::{
+    audit::audit_write,
     prelude::*,
     register::{FromUsize, HWBPRegs},
 };
@@ -44,6 +45,79 @@ cfg_if! {
     }
 }
 
+/// Pass the event to the user callback through the hooks of engine: the inline hooks, the
+/// single-step tracing, the breakpoint rate guard, the memory watches, the syscall tracing, the stop
+/// batching and the crash capture
+pub(crate) fn dispatch_event(
+    ctx: &mut dyn TraceContext,
+    callback: &mut UDbgCallback<'_>,
+    event: UEvent,
+) -> UserReply {
+    let timer = crate::metrics::StopTimer::start(
+        ctx.target().base().event_tid.get(),
+        matches!(event, UEvent::Breakpoint(_)),
+    );
+    let mut callback_time = core::time::Duration::ZERO;
+    let reply = dispatch_hooks(
+        ctx,
+        &mut |ctx: &mut dyn TraceContext, event: UEvent| {
+            let start = std::time::Instant::now();
+            let reply = callback(ctx, event);
+            callback_time += start.elapsed();
+            reply
+        },
+        event,
+    );
+    timer.finish(callback_time);
+    reply
+}
+
+fn dispatch_hooks(
+    ctx: &mut dyn TraceContext,
+    callback: &mut UDbgCallback<'_>,
+    event: UEvent,
+) -> UserReply {
+    let target = ctx.target();
+    let base = target.base();
+    // the breakpoints of inline hooks are never throttled
+    #[cfg(not(feature = "passive"))]
+    let hooked = base
+        .hooks
+        .on_event(ctx, &event)
+        .or_else(|| base.step_trace.on_event(ctx, &event));
+    #[cfg(feature = "passive")]
+    let hooked = None;
+    let handled = hooked
+        .or_else(|| base.bp_rate.on_event(&event))
+        .or_else(|| base.mem_watch.on_event(ctx, &event))
+        .or_else(|| base.syscall.on_event(ctx, &event));
+    if let Some((reply, notice)) = handled {
+        // replied by the engine, the user only receives the notice
+        if let Some(notice) = notice {
+            callback(ctx, notice);
+        }
+        return reply;
+    }
+    if let Some(reply) = base.stop_batch.on_event(ctx, &event) {
+        return reply;
+    }
+    base.crash.on_event(ctx, &event);
+    base.timeline.on_event(ctx, &event);
+    base.module_loads.on_event(&event);
+    let reply = callback(ctx, event);
+    // the traced thread keeps stepping whatever the user replies
+    #[cfg(not(feature = "passive"))]
+    let reply = base.step_trace.on_reply(ctx, reply);
+    reply
+}
+
+/// Where the current function of the thread returns, by the frame of its caller unwound
+pub(crate) fn step_out_address(target: &dyn UDbgTarget, tid: tid_t) -> Option<usize> {
+    let thread = target.open_thread(tid).log_error("open thread")?;
+    let frames = crate::unwind::stack_trace(target, thread.as_ref(), 2).log_error("unwind")?;
+    frames.first()?.return_address
+}
+
 impl TargetCommon {
     pub fn add_soft_bp(&self, this: &dyn UDbgTarget, opt: &BpOpt) -> UDbgResult<Arc<Breakpoint>> {
         // software breakpoint
@@ -53,6 +127,7 @@ impl TargetCommon {
                 enabled: Cell::new(false),
                 temp: Cell::new(opt.temp),
                 hit_tid: opt.tid,
+                hit_sp: opt.sp,
                 hit_count: Cell::new(0),
                 bp_type: InnerBpType::Soft(raw_byte),
 
@@ -84,6 +159,7 @@ impl TargetCommon {
                     temp: Cell::new(opt.temp),
                     hit_count: Cell::new(0),
                     hit_tid: opt.tid,
+                    hit_sp: opt.sp,
                     bp_type: InnerBpType::Hard(HwbpInfo {
                         rw: rw as u8,
                         index: index as u8,
@@ -110,6 +186,7 @@ impl TargetCommon {
                 temp: Cell::new(opt.temp),
                 hit_count: Cell::new(0),
                 hit_tid: opt.tid,
+                hit_sp: opt.sp,
                 bp_type: InnerBpType::Table { index, origin },
 
                 target: unsafe { Utils::to_weak(this) },
@@ -139,13 +216,13 @@ impl TargetCommon {
         match bp.bp_type {
             InnerBpType::Soft(raw_byte) => {
                 let written = if enable {
-                    dbg.write_memory(bp.address, BP_INSN)
+                    self.base.stop_batch.on_enable(dbg, bp.address);
+                    dbg.write_code(bp.address, BP_INSN)
                 } else {
-                    dbg.write_memory(bp.address, &raw_byte)
+                    dbg.write_code(bp.address, &raw_byte)
                 }
                 .unwrap_or_default();
                 if written > 0 {
-                    dbg.flush_cache(bp.address, written)?;
                     bp.enabled.set(enable);
                     Ok(enable)
                 } else {
@@ -214,30 +291,44 @@ impl TargetCommon {
         context: &mut C,
     ) {
         let tid = self.base.event_tid.get();
+        // break at the address in this thread, with the stack not below `sp`, or step if it's unknown
+        let break_at = |address: Option<usize>, sp: usize, context: &mut C| match address {
+            Some(address) => {
+                context.set_step(false);
+                self.add_soft_bp(
+                    this,
+                    &BpOpt::int3(address)
+                        .temp(true)
+                        .enable(true)
+                        .thread(tid)
+                        .stack(sp),
+                )
+                .log_error("add bp");
+            }
+            None => {
+                context.set_step(true);
+                self.step_tid.set(tid);
+            }
+        };
         match reply {
             UserReply::StepIn => {
                 context.set_step(true);
                 self.step_tid.set(tid);
                 // info!("step_tid: {}", tid);
             }
+            UserReply::StepOver => {
+                let address = this.check_call(context.ip().to_usize());
+                let sp = context.sp().to_usize();
+                break_at(address, sp, context);
+            }
             UserReply::StepOut => {
-                if let Some(address) = this.check_call(context.ip().to_usize()) {
-                    context.set_step(false);
-                    self.add_soft_bp(
-                        this,
-                        &BpOpt::int3(address)
-                            .temp(true)
-                            .enable(true)
-                            .thread(self.base.event_tid.get()),
-                    )
-                    .log_error("add bp");
-                } else {
-                    context.set_step(true);
-                    self.step_tid.set(tid);
-                    // info!("step_tid out: {}", tid)
-                }
+                // a recursive call returns to the same address, in a frame below this one
+                let address = step_out_address(this, tid);
+                let sp = context.sp().to_usize();
+                break_at(address, sp, context);
             }
-            UserReply::Goto(address) => {
+            #[allow(deprecated)]
+            UserReply::RunTo(address) | UserReply::Goto(address) => {
                 self.add_soft_bp(this, &BpOpt::int3(address).temp(true).enable(true))
                     .log_error("add bp");
             }
@@ -251,6 +342,9 @@ where
     T: core::ops::Deref<Target = TargetCommon> + UDbgTarget,
 {
     default fn add_breakpoint(&self, opt: BpOpt) -> UDbgResult<Arc<dyn UDbgBreakpoint>> {
+        if PASSIVE {
+            return Err(UDbgError::NotSupport);
+        }
         Ok(self.deref().add_bp(self, &opt)?)
     }
 
@@ -273,7 +367,13 @@ where
     T: Deref<Target = TargetCommon>,
 {
     default fn read_memory<'a>(&self, addr: usize, data: &'a mut [u8]) -> Option<&'a mut [u8]> {
-        self.process.read_memory(addr, data)
+        let result = self.process.read_memory(addr, data)?;
+        crate::metrics::record_read(result.len());
+        Some(result)
+    }
+
+    default fn data_layout(&self) -> DataLayout {
+        self.base.data_layout()
     }
 }
 
@@ -282,10 +382,18 @@ where
     T: Deref<Target = TargetCommon>,
 {
     default fn write_memory(&self, addr: usize, data: &[u8]) -> Option<usize> {
-        WriteMemory::write_memory(&self.process, addr, data)
+        audit_write(&self.process, &self.base, addr, data.len(), false, || {
+            WriteMemory::write_memory(&self.process, addr, data)
+        })
     }
 
     default fn flush_cache(&self, address: usize, len: usize) -> std::io::Result<()> {
         self.process.flush_cache(address, len)
     }
+
+    default fn write_code(&self, address: usize, data: &[u8]) -> Option<usize> {
+        audit_write(&self.process, &self.base, address, data.len(), true, || {
+            self.process.write_code(address, data)
+        })
+    }
 }
@@ -75,11 +75,11 @@ impl UDbgModule for Module {
         self.syms.pdb.read().clone()
     }
 
-    fn enum_symbol(&self, pat: Option<&str>) -> UDbgResult<Box<dyn Iterator<Item = Symbol>>> {
-        Ok(Box::new(self.syms.enum_symbol(pat)?.into_iter()))
+    fn enum_symbol(&self, pat: Option<&str>) -> UDbgResult<Box<dyn Iterator<Item = Symbol> + '_>> {
+        Ok(Box::new(self.syms.enum_symbol(pat)?))
     }
-    fn get_exports(&self) -> Option<Vec<Symbol>> {
-        Some(self.syms.exports.iter().map(|i| i.1.clone()).collect())
+    fn enum_exports(&self) -> Option<Box<dyn Iterator<Item = Symbol> + '_>> {
+        Some(Box::new(self.syms.exports.values().cloned()))
     }
 
     // TODO: dwarf
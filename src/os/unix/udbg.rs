@@ -1,5 +1,6 @@
 use crate::{
     os::{user_regs, ProcessTarget},
+    poll::StoppedGuard,
     prelude::*,
 };
 
@@ -18,7 +19,9 @@ pub struct TraceBuf<'a> {
 impl TraceBuf<'_> {
     #[inline]
     pub fn call(&mut self, event: UEvent) -> UserReply {
-        unsafe { (self.callback.as_mut().unwrap())(self, event) }
+        let _stopped = StoppedGuard::new();
+        let callback = unsafe { self.callback.as_mut().unwrap() };
+        crate::os::dispatch_event(self, callback, event)
     }
 }
 
@@ -30,6 +33,15 @@ impl TraceContext for TraceBuf<'_> {
     fn target(&self) -> Arc<dyn UDbgTarget> {
         self.target.clone()
     }
+
+    /// The fault address of SIGSEGV/SIGBUS as the second one, like the access violations on Windows
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn exception_param(&self, i: usize) -> Option<usize> {
+        match self.si.si_signo {
+            SIGSEGV | SIGBUS if i == 1 => Some(unsafe { self.si.si_addr() as usize }),
+            _ => None,
+        }
+    }
 }
 
 pub type HandleResult = Option<Signal>;
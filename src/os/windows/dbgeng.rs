@@ -1,11 +1,13 @@
 //! Adaptive wrapper for microsoft's [dbgeng](https://docs.microsoft.com/en-us/windows-hardware/drivers/debugger/debugger-engine-overview)
 
-use super::Handle;
-use crate::prelude::*;
+use super::{Align16, Handle};
+use crate::{poll::StoppedGuard, prelude::*};
+use core::mem::size_of;
 use std::{
-    cell::Cell,
+    cell::{Cell, RefCell},
     collections::HashMap,
     ffi::CStr,
+    rc::Rc,
     sync::{self, Arc},
 };
 
@@ -24,34 +26,163 @@ type DebugCreateFn = extern "system" fn(
     *mut *mut ::core::ffi::c_void,
 ) -> ::windows::core::HRESULT;
 
-#[implement(IDebugEventCallbacksWide)]
-pub struct EventCallbacks(&'static mut UDbgCallback<'static>, *mut DebugEngine);
+const STATUS_BREAKPOINT: u32 = 0x80000003;
+const STATUS_WX86_BREAKPOINT: u32 = 0x4000001F;
 
-fn reply2status(reply: UserReply) -> HRESULT {
-    HRESULT(match reply {
+fn reply2status(reply: UserReply) -> u32 {
+    match reply {
         UserReply::Run(true) => DEBUG_STATUS_GO_HANDLED,
         UserReply::Run(false) => DEBUG_STATUS_GO_NOT_HANDLED,
         UserReply::StepIn => DEBUG_STATUS_STEP_INTO,
-        UserReply::StepOut => DEBUG_STATUS_STEP_OVER,
+        UserReply::StepOver => DEBUG_STATUS_STEP_OVER,
         UserReply::Native(code) => code as _,
-        _ => DEBUG_STATUS_BREAK,
-    } as _)
+        // the native engine never keeps the target stopped after the callback
+        _ => DEBUG_STATUS_GO,
+    }
 }
 
-impl EventCallbacks {
-    #[inline(always)]
-    fn call(&self, event: UEvent) -> Result<()> {
-        // TODO:
-        let target = Arc::new(DebugTarget::from(self.engine() as &DebugEngine));
-        reply2status(unsafe { mutable(self).0(target, event) }).ok()
+/// The execution status is returned to dbgeng as the HRESULT, it's not an error
+fn status_result(status: u32) -> Result<()> {
+    if status == DEBUG_STATUS_NO_CHANGE {
+        Ok(())
+    } else {
+        Err(Error::from(HRESULT(status as _)))
+    }
+}
+
+/// Context of an event from dbgeng, the registers of event thread are fetched on first access
+/// and written back after the callback
+struct EventContext {
+    target: Arc<DebugTarget>,
+    record: Option<EXCEPTION_RECORD64>,
+    context: Align16<ThreadContext>,
+    fetched: Option<bool>,
+}
+
+impl EventContext {
+    fn new(target: Arc<DebugTarget>, record: Option<EXCEPTION_RECORD64>) -> Self {
+        Self {
+            target,
+            record,
+            context: Align16::new(),
+            fetched: None,
+        }
+    }
+
+    fn write_back(&mut self) {
+        if self.fetched == Some(true) {
+            let cx = self.context.as_mut();
+            unsafe {
+                self.target
+                    .advanced
+                    .SetThreadContext(cx as *mut _ as _, size_of::<ThreadContext>() as _)
+                    .log_error("SetThreadContext");
+            }
+        }
+    }
+}
+
+impl TraceContext for EventContext {
+    fn register(&mut self) -> Option<&mut dyn UDbgRegs> {
+        if self.fetched.is_none() {
+            let cx = self.context.as_mut();
+            let result = unsafe {
+                self.target
+                    .advanced
+                    .GetThreadContext(cx as *mut _ as _, size_of::<ThreadContext>() as _)
+            };
+            self.fetched = Some(result.log_error("GetThreadContext").is_some());
+        }
+        if self.fetched == Some(true) {
+            Some(self.context.as_mut())
+        } else {
+            None
+        }
+    }
+
+    fn target(&self) -> Arc<dyn UDbgTarget> {
+        self.target.clone()
+    }
+
+    fn exception_param(&self, i: usize) -> Option<usize> {
+        let record = self.record.as_ref()?;
+        (i < record.NumberParameters as usize).then(|| record.ExceptionInformation[i] as usize)
+    }
+}
+
+/// State shared by the event callbacks and the event loop
+struct EventBridge {
+    callback: *mut UDbgCallback<'static>,
+    engine: DebugEngine,
+    /// The debuggee, kept across the events as the native engine
+    target: RefCell<Option<Arc<DebugTarget>>>,
+    init_bp: Cell<bool>,
+    /// The last reply steps, the next break without event is reported as [`UEvent::Step`]
+    stepping: Cell<bool>,
+    /// The reply to the events since the target is resumed, applied when it breaks in
+    status: Cell<Option<u32>>,
+}
+
+impl EventBridge {
+    fn target(&self) -> Arc<DebugTarget> {
+        self.target
+            .borrow_mut()
+            .get_or_insert_with(|| Arc::new(DebugTarget::from(&self.engine)))
+            .clone()
     }
 
-    #[inline(always)]
-    fn engine(&self) -> &mut DebugEngine {
-        unsafe { self.1.as_mut().unwrap() }
+    /// Pass the event to the user callback as the native engine, returns the execution status
+    fn call(&self, event: UEvent, record: Option<EXCEPTION_RECORD64>) -> u32 {
+        let _stopped = StoppedGuard::new();
+        let target = self.target();
+        unsafe {
+            target.base.event_tid.set(
+                self.engine
+                    .sysobjs
+                    .GetCurrentThreadSystemId()
+                    .unwrap_or_default(),
+            );
+        }
+        let mut ctx = EventContext::new(target.clone(), record);
+        let callback = unsafe { self.callback.as_mut().unwrap() };
+        let mut reply = crate::os::dispatch_event(&mut ctx, callback, event);
+        ctx.write_back();
+
+        let run_to = match reply {
+            #[allow(deprecated)]
+            UserReply::RunTo(address) | UserReply::Goto(address) => Some(address),
+            UserReply::StepOut => {
+                let tid = target.base().event_tid.get();
+                let address = crate::os::step_out_address(target.as_ref(), tid);
+                // the return address is unknown, steps over at least
+                if address.is_none() {
+                    reply = UserReply::StepOver;
+                }
+                address
+            }
+            _ => None,
+        };
+        if let Some(address) = run_to {
+            target
+                .add_breakpoint(BpOpt::int3(address).temp(true).enable(true))
+                .log_error("add bp");
+        }
+        self.stepping
+            .set(matches!(reply, UserReply::StepIn | UserReply::StepOver));
+        let status = reply2status(reply);
+        self.status.set(Some(status));
+        status
+    }
+
+    #[inline]
+    fn reply(&self, event: UEvent) -> Result<()> {
+        status_result(self.call(event, None))
     }
 }
 
+#[implement(IDebugEventCallbacksWide)]
+struct EventCallbacks(Rc<EventBridge>);
+
 impl IDebugEventCallbacksWide_Impl for EventCallbacks {
     fn GetInterestMask(&self) -> windows::core::Result<u32> {
         Ok(DEBUG_EVENT_BREAKPOINT
@@ -73,15 +204,13 @@ impl IDebugEventCallbacksWide_Impl for EventCallbacks {
         &self,
         bp: &core::option::Option<IDebugBreakpoint2>,
     ) -> windows::core::Result<()> {
-        bp.as_ref()
-            .map(Clone::clone)
-            .map(|bp| {
-                self.call(UEvent::Breakpoint(Arc::new(IDbgBpWrapper(
-                    bp,
-                    self.engine().ctrl.clone(),
-                ))))
-            })
-            .unwrap_or(HRESULT(DEBUG_STATUS_BREAK as _).ok())
+        match bp {
+            Some(bp) => self.0.reply(UEvent::Breakpoint(Arc::new(IDbgBpWrapper(
+                bp.clone(),
+                self.0.engine.ctrl.clone(),
+            )))),
+            None => status_result(DEBUG_STATUS_BREAK),
+        }
     }
 
     fn Exception(
@@ -89,13 +218,20 @@ impl IDebugEventCallbacksWide_Impl for EventCallbacks {
         exception: *const EXCEPTION_RECORD64,
         firstchance: u32,
     ) -> windows::core::Result<()> {
-        unsafe {
-            let e = exception.as_ref().unwrap();
-            self.call(UEvent::Exception {
-                first: firstchance != 0,
-                code: e.ExceptionCode.0 as _,
-            })
-        }
+        let record = unsafe { *exception.as_ref().unwrap() };
+        let code = record.ExceptionCode.0 as u32;
+        let first = firstchance != 0;
+        // the breakpoint of system when the process is created or attached
+        let event = if first
+            && !self.0.init_bp.get()
+            && matches!(code, STATUS_BREAKPOINT | STATUS_WX86_BREAKPOINT)
+        {
+            self.0.init_bp.set(true);
+            UEvent::InitBp
+        } else {
+            UEvent::Exception { first, code }
+        };
+        status_result(self.0.call(event, Some(record)))
     }
 
     fn CreateThread(
@@ -104,11 +240,12 @@ impl IDebugEventCallbacksWide_Impl for EventCallbacks {
         dataoffset: u64,
         startoffset: u64,
     ) -> windows::core::Result<()> {
-        self.call(UEvent::ThreadCreate(unsafe { GetThreadId(handle as _) }))
+        self.0
+            .reply(UEvent::ThreadCreate(unsafe { GetThreadId(handle as _) }))
     }
 
     fn ExitThread(&self, exitcode: u32) -> windows::core::Result<()> {
-        self.call(UEvent::ThreadExit(exitcode))
+        self.0.reply(UEvent::ThreadExit(exitcode))
     }
 
     fn CreateProcessA(
@@ -125,14 +262,16 @@ impl IDebugEventCallbacksWide_Impl for EventCallbacks {
         threaddataoffset: u64,
         startoffset: u64,
     ) -> windows::core::Result<()> {
-        let modulename = String::from_wide_ptr(modulename.0);
-        let imagename = String::from_wide_ptr(imagename.0);
-        println!("[load] image {modulename} {imagename}");
-        self.call(UEvent::ProcessCreate)
+        // a new debuggee, such as the child process
+        *self.0.target.borrow_mut() = None;
+        self.0.init_bp.set(false);
+        self.0.call(UEvent::ProcessCreate, None);
+        let tid = unsafe { GetThreadId(initialthreadhandle as _) };
+        self.0.reply(UEvent::ThreadCreate(tid))
     }
 
     fn ExitProcess(&self, exitcode: u32) -> windows::core::Result<()> {
-        self.call(UEvent::ProcessExit(exitcode))
+        self.0.reply(UEvent::ProcessExit(exitcode))
     }
 
     fn LoadModule(
@@ -145,10 +284,14 @@ impl IDebugEventCallbacksWide_Impl for EventCallbacks {
         checksum: u32,
         timedatestamp: u32,
     ) -> windows::core::Result<()> {
-        let modulename = String::from_wide_ptr(modulename.0);
-        let imagename = String::from_wide_ptr(imagename.0);
-        println!("[load] {modulename} {imagename}");
-        Ok(())
+        match self.0.target().module_from_base(baseoffset) {
+            Some(m) => self.0.reply(UEvent::ModuleLoad(Arc::new(m))),
+            None => {
+                let imagename = String::from_wide_ptr(imagename.0);
+                udbg_ui().warn(format!("module {imagename} at {baseoffset:x} not found"));
+                Ok(())
+            }
+        }
     }
 
     fn UnloadModule(
@@ -156,9 +299,10 @@ impl IDebugEventCallbacksWide_Impl for EventCallbacks {
         imagebasename: &windows::core::PCWSTR,
         baseoffset: u64,
     ) -> windows::core::Result<()> {
-        let imagebasename = String::from_wide_ptr(imagebasename.0);
-        println!("[load] {imagebasename}");
-        Ok(())
+        match self.0.target().module_from_base(baseoffset) {
+            Some(m) => self.0.reply(UEvent::ModuleUnload(Arc::new(m))),
+            None => Ok(()),
+        }
     }
 
     fn SystemError(&self, error: u32, level: u32) -> windows::core::Result<()> {
@@ -167,7 +311,7 @@ impl IDebugEventCallbacksWide_Impl for EventCallbacks {
     }
 
     fn SessionStatus(&self, status: u32) -> windows::core::Result<()> {
-        udbg_ui().error(format!("[SessionStatus] {status:x?}"));
+        udbg_ui().info(format!("[SessionStatus] {status:x?}"));
         Ok(())
     }
 
@@ -190,7 +334,19 @@ pub struct OutputCallbacks(sync::Weak<DebugEngine>);
 impl IDebugOutputCallbacksWide_Impl for OutputCallbacks {
     fn Output(&self, mask: u32, text: &windows::core::PCWSTR) -> windows::core::Result<()> {
         let text = String::from_wide_ptr(text.0);
-        udbg_ui().print(&text);
+        let ui = udbg_ui();
+        if mask & DEBUG_OUTPUT_ERROR != 0 {
+            ui.error(text.trim_end());
+        } else if mask & DEBUG_OUTPUT_WARNING != 0 {
+            ui.warn(text.trim_end());
+        } else if mask & DEBUG_OUTPUT_DEBUGGEE != 0 {
+            // OutputDebugString of the debuggee, as the native engine
+            ui.debug(&text);
+        } else if mask & DEBUG_OUTPUT_VERBOSE != 0 {
+            log::debug!("{}", text.trim_end());
+        } else {
+            ui.print(&text);
+        }
         Ok(())
     }
 }
@@ -246,6 +402,7 @@ impl UDbgTarget for DebugTarget {}
 
 impl UDbgEngine for DebugEngine {
     fn attach(&mut self, pid: u32) -> UDbgResult<Arc<dyn UDbgTarget>> {
+        check_self_debug(pid)?;
         unsafe {
             self.client
                 .AttachProcess(0, pid, DEBUG_ATTACH_DEFAULT)
@@ -304,17 +461,38 @@ impl UDbgEngine for DebugEngine {
     }
 
     fn event_loop(&mut self, callback: &mut UDbgCallback) -> UDbgResult<()> {
+        let _guard = EventLoopGuard::enter()?;
+        let bridge = Rc::new(EventBridge {
+            callback: unsafe { core::mem::transmute(callback) },
+            engine: self.clone(),
+            target: Default::default(),
+            init_bp: Default::default(),
+            stepping: Default::default(),
+            status: Default::default(),
+        });
         unsafe {
-            let event: IDebugEventCallbacksWide =
-                EventCallbacks(core::mem::transmute(callback), self).into();
-            self.client.SetEventCallbacksWide(event);
-
-            loop {
-                match self.ctrl.WaitForEvent(0, winapi::um::winbase::INFINITE) {
-                    Ok(_) => {}
-                    Err(err) => break,
+            let event: IDebugEventCallbacksWide = EventCallbacks(bridge.clone()).into();
+            self.client
+                .SetEventCallbacksWide(event)
+                .context("SetEventCallbacks")?;
+
+            while self
+                .ctrl
+                .WaitForEvent(0, winapi::um::winbase::INFINITE)
+                .is_ok()
+            {
+                // broken in, by the initial/final breakpoint, a step or the event filters
+                let status = match bridge.status.take() {
+                    Some(status) => status,
+                    None if bridge.stepping.get() => bridge.call(UEvent::Step, None),
+                    None => DEBUG_STATUS_GO,
+                };
+                if self.ctrl.SetExecutionStatus(status).is_err() {
+                    break;
                 }
+                bridge.status.set(None);
             }
+            self.client.SetEventCallbacksWide(None).ok();
         }
         Ok(())
     }
@@ -348,6 +526,8 @@ impl From<&DebugEngine> for DebugTarget {
                 base.image_path = buf.to_utf8();
                 base.pid
                     .set(eng.sysobjs.GetCurrentProcessSystemId().unwrap_or_default());
+                // the architectures of Windows are little endian
+                base.endian.set(Endian::Little);
                 if base.image_path.is_empty() {
                     udbg_ui().warn("GetImagePath failed");
                     // base.image_path = path.to_string();
@@ -381,6 +561,10 @@ impl ReadMemory for DebugTarget {
             }
         }
     }
+
+    fn data_layout(&self) -> DataLayout {
+        self.base.data_layout()
+    }
 }
 
 impl WriteMemory for DebugTarget {
@@ -7,6 +7,7 @@ mod util;
 #[cfg(feature = "dbgeng")]
 pub mod dbgeng;
 pub mod ntdll;
+pub mod object;
 pub mod string;
 pub mod symbol;
 
@@ -336,6 +337,10 @@ pub fn write_process_memory(handle: HANDLE, address: usize, data: &[u8]) -> usiz
             &mut written,
         );
         VirtualProtectEx(handle, address, data.len(), old_protect, &mut new_protect);
+        // executable page, keep the instruction cache coherent
+        if result > 0 && old_protect & 0xF0 > 0 {
+            FlushInstructionCache(handle, address, written);
+        }
         if result > 0 {
             written
         } else {
@@ -344,9 +349,85 @@ pub fn write_process_memory(handle: HANDLE, address: usize, data: &[u8]) -> usiz
     }
 }
 
-#[derive(Clone)]
+/// Access rights required by the operations of [`Process`]
+pub struct ProcessAccess;
+
+impl ProcessAccess {
+    pub const QUERY: u32 = PROCESS_QUERY_LIMITED_INFORMATION;
+    /// VirtualQueryEx, EnumProcessModules, etc.
+    pub const QUERY_INFO: u32 = PROCESS_QUERY_INFORMATION;
+    pub const READ: u32 = PROCESS_VM_READ;
+    pub const WRITE: u32 = PROCESS_VM_WRITE | PROCESS_VM_OPERATION;
+    /// VirtualProtectEx, VirtualAllocEx, VirtualFreeEx
+    pub const OPERATION: u32 = PROCESS_VM_OPERATION;
+    pub const DUP_HANDLE: u32 = PROCESS_DUP_HANDLE;
+    pub const TERMINATE: u32 = PROCESS_TERMINATE;
+    pub const CREATE_THREAD: u32 = PROCESS_CREATE_THREAD
+        | PROCESS_QUERY_INFORMATION
+        | PROCESS_VM_OPERATION
+        | PROCESS_VM_WRITE
+        | PROCESS_VM_READ;
+    /// Rights to open a process by default, others are requested on demand
+    pub const DEFAULT: u32 = PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_VM_READ | SYNCHRONIZE;
+
+    const NAMES: &'static [(u32, &'static str)] = &[
+        (PROCESS_TERMINATE, "PROCESS_TERMINATE"),
+        (PROCESS_CREATE_THREAD, "PROCESS_CREATE_THREAD"),
+        (PROCESS_VM_OPERATION, "PROCESS_VM_OPERATION"),
+        (PROCESS_VM_READ, "PROCESS_VM_READ"),
+        (PROCESS_VM_WRITE, "PROCESS_VM_WRITE"),
+        (PROCESS_DUP_HANDLE, "PROCESS_DUP_HANDLE"),
+        (PROCESS_CREATE_PROCESS, "PROCESS_CREATE_PROCESS"),
+        (PROCESS_SET_QUOTA, "PROCESS_SET_QUOTA"),
+        (PROCESS_SET_INFORMATION, "PROCESS_SET_INFORMATION"),
+        (PROCESS_QUERY_INFORMATION, "PROCESS_QUERY_INFORMATION"),
+        (PROCESS_SUSPEND_RESUME, "PROCESS_SUSPEND_RESUME"),
+        (
+            PROCESS_QUERY_LIMITED_INFORMATION,
+            "PROCESS_QUERY_LIMITED_INFORMATION",
+        ),
+        (SYNCHRONIZE, "SYNCHRONIZE"),
+    ];
+
+    /// Readable names of the access rights, such as "PROCESS_VM_READ|PROCESS_VM_WRITE"
+    pub fn names(access: u32) -> String {
+        let mut rest = access;
+        let mut result = Self::NAMES
+            .iter()
+            .filter(|(a, _)| access & a == *a)
+            .map(|&(a, name)| {
+                rest &= !a;
+                name
+            })
+            .collect::<Vec<_>>()
+            .join("|");
+        if rest != 0 {
+            if !result.is_empty() {
+                result.push('|');
+            }
+            result += &format!("{:#x}", rest);
+        }
+        result
+    }
+}
+
 pub struct Process {
     pub handle: Handle,
+    /// Access rights granted to `handle`
+    pub access: u32,
+    /// Handles reopened with more rights, live as long as the process,
+    /// None if reopening with the rights failed
+    reopened: spin::Mutex<Vec<(u32, Option<Handle>)>>,
+}
+
+impl Clone for Process {
+    fn clone(&self) -> Self {
+        Self {
+            handle: self.handle.clone(),
+            access: self.access,
+            reopened: Default::default(),
+        }
+    }
 }
 
 pub struct MemoryIter<'p> {
@@ -379,7 +460,8 @@ impl Iterator for MemoryIter<'_> {
 
 impl ReadMemory for Process {
     fn read_memory<'a>(&self, addr: usize, data: &'a mut [u8]) -> Option<&'a mut [u8]> {
-        let r = read_process_memory(*self.handle, addr, data);
+        let handle = self.handle_with(ProcessAccess::READ).ok()?;
+        let r = read_process_memory(handle, addr, data);
         if r > 0 {
             Some(&mut data[..r])
         } else {
@@ -399,7 +481,10 @@ impl WriteMemory for Process {
     }
 
     fn flush_cache(&self, address: usize, len: usize) -> IoResult<()> {
-        if unsafe { FlushInstructionCache(*self.handle, address as LPCVOID, len) > 0 } {
+        let handle = self
+            .handle_with(ProcessAccess::OPERATION)
+            .map_err(|_| IoError::from(std::io::ErrorKind::PermissionDenied))?;
+        if unsafe { FlushInstructionCache(handle, address as LPCVOID, len) > 0 } {
             Ok(())
         } else {
             Err(IoError::last_os_error())
@@ -408,9 +493,11 @@ impl WriteMemory for Process {
 }
 
 impl Process {
+    /// Open a process with `access` rights, [`ProcessAccess::DEFAULT`] if it's None,
+    /// the rights required by other operations are requested when they're used
     pub fn open(pid: u32, access: Option<u32>) -> Option<Process> {
         unsafe {
-            let handle = OpenProcess(access.unwrap_or(PROCESS_ALL_ACCESS), 0, pid);
+            let handle = OpenProcess(access.unwrap_or(ProcessAccess::DEFAULT), 0, pid);
             if handle.is_null() {
                 None
             } else {
@@ -441,7 +528,73 @@ impl Process {
                 return None;
             }
 
-            return Some(Process { handle });
+            let access = if handle.0 == GetCurrentProcess() {
+                PROCESS_ALL_ACCESS
+            } else {
+                query_handle_access(*handle).unwrap_or_default()
+            };
+            return Some(Process {
+                handle,
+                access,
+                reopened: Default::default(),
+            });
+        }
+    }
+
+    /// Get a handle which has the `access` rights, if the opened handle lacks some of them,
+    /// duplicate or reopen the process with more rights, and keep the new handle alive with this process.
+    /// A failure is remembered, the rights are not requested again
+    pub fn handle_with(&self, access: u32) -> UDbgResult<HANDLE> {
+        if self.access & access == access {
+            return Ok(*self.handle);
+        }
+
+        let access = access | self.access;
+        let denied = || UDbgError::AccessDenied(ProcessAccess::names(access & !self.access));
+        let mut reopened = self.reopened.lock();
+        for (a, h) in reopened.iter() {
+            match h {
+                Some(h) if a & access == access => return Ok(**h),
+                // more rights than the denied ones are denied too
+                None if a & access == *a => return Err(denied()),
+                _ => {}
+            }
+        }
+
+        let handle = self.reopen(access);
+        let result = handle.as_ref().map(|h| **h).ok_or_else(denied);
+        reopened.push((access, handle));
+        result
+    }
+
+    /// Check if the `access` rights are available, return the missing rights if not
+    #[inline]
+    pub fn check_access(&self, access: u32) -> UDbgResult<()> {
+        self.handle_with(access).map(|_| ())
+    }
+
+    fn reopen(&self, access: u32) -> Option<Handle> {
+        unsafe {
+            let mut result = null_mut();
+            if DuplicateHandle(
+                GetCurrentProcess(),
+                *self.handle,
+                GetCurrentProcess(),
+                &mut result,
+                access,
+                0,
+                0,
+            ) > 0
+            {
+                return Some(Handle::from_raw_handle(result));
+            }
+
+            let handle = OpenProcess(access, 0, self.pid());
+            if handle.is_null() {
+                None
+            } else {
+                Some(Handle::from_raw_handle(handle))
+            }
         }
     }
 
@@ -450,7 +603,8 @@ impl Process {
     }
 
     pub fn basic_information(&self) -> Option<PROCESS_BASIC_INFORMATION> {
-        query_process(*self.handle, ProcessInfoClass::BasicInformation, None)
+        let handle = self.handle_with(ProcessAccess::QUERY).ok()?;
+        query_process(handle, ProcessInfoClass::BasicInformation, None)
     }
 
     pub fn pid(&self) -> u32 {
@@ -472,10 +626,11 @@ impl Process {
     }
 
     pub fn get_module_name(&self, module: u64) -> Result<String> {
+        let handle = self.handle_with(ProcessAccess::QUERY_INFO | ProcessAccess::READ)?;
         unsafe {
             let mut name = [0 as u16; MAX_PATH];
             if GetModuleBaseNameW(
-                *self.handle,
+                handle,
                 module as HMODULE,
                 name.as_mut_ptr(),
                 MAX_PATH as u32,
@@ -488,34 +643,28 @@ impl Process {
         }
     }
 
-    // TODO: [bug] wow64进程下32位dll取到的是64位的路径
-    #[deprecated]
+    /// Full path of the module at `base` in the loader lists, the 32-bit modules of WOW64 process
+    /// get their own paths, instead of the 64-bit ones given by `GetModuleFileNameExW`
     pub fn get_module_path(&self, module: usize) -> Option<String> {
-        unsafe {
-            let mut path = [0 as u16; MAX_PATH];
-            if GetModuleFileNameExW(
-                *self.handle,
-                module as HMODULE,
-                path.as_mut_ptr(),
-                MAX_PATH as u32,
-            ) > 0
-            {
-                Some(path.as_ref().to_utf8())
-            } else {
-                None
-            }
-        }
+        crate::peb::enum_ldr_modules(self)
+            .ok()?
+            .into_iter()
+            .find(|m| m.base == module)
+            .map(|m| m.full_name)
     }
 
     /// use EnumProcessModulesEx
     pub fn get_module_list(&self, flag: u32) -> Option<Vec<usize>> {
+        let handle = self
+            .handle_with(ProcessAccess::QUERY_INFO | ProcessAccess::READ)
+            .ok()?;
         unsafe {
             let mut len = 0u32;
-            EnumProcessModulesEx(self.handle.0, null_mut(), 0, &mut len, flag);
+            EnumProcessModulesEx(handle, null_mut(), 0, &mut len, flag);
             let mut result = vec![0usize; len as usize];
             if len > 0 {
                 if EnumProcessModulesEx(
-                    self.handle.0,
+                    handle,
                     transmute(result.as_mut_ptr()),
                     result.len() as u32,
                     &mut len,
@@ -531,10 +680,13 @@ impl Process {
 
     /// use GetModuleInformation
     pub fn get_module_info(&self, base: usize) -> Option<MODULEINFO> {
+        let handle = self
+            .handle_with(ProcessAccess::QUERY_INFO | ProcessAccess::READ)
+            .ok()?;
         unsafe {
             let mut result: MODULEINFO = zeroed();
             if GetModuleInformation(
-                self.handle.0,
+                handle,
                 transmute(base),
                 &mut result,
                 size_of::<MODULEINFO>() as u32,
@@ -547,10 +699,11 @@ impl Process {
     }
 
     pub fn duplicate_handle(&self, src_handle: HANDLE, dst_ps: HANDLE) -> Option<HANDLE> {
+        let process = self.handle_with(ProcessAccess::DUP_HANDLE).ok()?;
         let mut handle: HANDLE = null_mut();
         unsafe {
             if 0 != DuplicateHandle(
-                self.handle.0,
+                process,
                 src_handle,
                 dst_ps,
                 &mut handle,
@@ -579,10 +732,11 @@ impl Process {
 
     /// Wrapper of QueryFullProcessImageNameW
     pub fn image_path(&self) -> UDbgResult<String> {
+        let handle = self.handle_with(ProcessAccess::QUERY)?;
         unsafe {
             let mut path = [0 as u16; MAX_PATH];
             let mut size = path.len() as u32;
-            if QueryFullProcessImageNameW(*self.handle, 0, path.as_mut_ptr(), &mut size) > 0 {
+            if QueryFullProcessImageNameW(handle, 0, path.as_mut_ptr(), &mut size) > 0 {
                 Ok(path.as_ref().to_utf8())
             } else {
                 Err(UDbgError::system())
@@ -606,9 +760,13 @@ impl Process {
     }
 
     pub fn protect_memory(&self, address: usize, size: usize, attr: u32) -> Option<u32> {
+        if crate::consts::PASSIVE {
+            return None;
+        }
+        let handle = self.handle_with(ProcessAccess::OPERATION).ok()?;
         unsafe {
             let mut oldattr = 0u32;
-            let r = VirtualProtectEx(*self.handle, address as LPVOID, size, attr, &mut oldattr);
+            let r = VirtualProtectEx(handle, address as LPVOID, size, attr, &mut oldattr);
             if r > 0 {
                 Some(oldattr)
             } else {
@@ -619,7 +777,12 @@ impl Process {
 
     #[inline]
     pub fn write_memory(&self, address: usize, data: &[u8]) -> usize {
-        write_process_memory(*self.handle, address, data)
+        if crate::consts::PASSIVE {
+            return 0;
+        }
+        self.handle_with(ProcessAccess::WRITE)
+            .map(|handle| write_process_memory(handle, address, data))
+            .unwrap_or_default()
     }
 
     pub fn enum_memory(&self, address: usize) -> MemoryIter {
@@ -630,17 +793,32 @@ impl Process {
     }
 
     pub fn virtual_alloc(&self, address: usize, size: usize, mem_type: u32, protect: u32) -> usize {
-        unsafe { VirtualAllocEx(*self.handle, address as LPVOID, size, mem_type, protect) as usize }
+        if crate::consts::PASSIVE {
+            return 0;
+        }
+        let handle = match self.handle_with(ProcessAccess::OPERATION) {
+            Ok(h) => h,
+            Err(_) => return 0,
+        };
+        unsafe { VirtualAllocEx(handle, address as LPVOID, size, mem_type, protect) as usize }
     }
 
     pub fn virtual_free(&self, address: usize) -> bool {
-        unsafe { VirtualFreeEx(*self.handle, address as LPVOID, 0, MEM_RELEASE) > 0 }
+        if crate::consts::PASSIVE {
+            return false;
+        }
+        let handle = match self.handle_with(ProcessAccess::OPERATION) {
+            Ok(h) => h,
+            Err(_) => return false,
+        };
+        unsafe { VirtualFreeEx(handle, address as LPVOID, 0, MEM_RELEASE) > 0 }
     }
 
     pub fn virtual_query(&self, address: usize) -> Option<MemoryPage> {
+        let handle = self.handle_with(ProcessAccess::QUERY_INFO).ok()?;
         unsafe {
             let mut mbi: MEMORY_BASIC_INFORMATION = zeroed();
-            match VirtualQueryEx(*self.handle, address as LPVOID, &mut mbi, size_of_val(&mbi)) {
+            match VirtualQueryEx(handle, address as LPVOID, &mut mbi, size_of_val(&mbi)) {
                 0 => None,
                 _ => Some(MemoryPage::from(&mbi)),
             }
@@ -649,7 +827,13 @@ impl Process {
 
     #[inline]
     pub fn terminate(&self) -> bool {
-        unsafe { TerminateProcess(*self.handle, 0) > 0 }
+        if crate::consts::PASSIVE {
+            return false;
+        }
+        match self.handle_with(ProcessAccess::TERMINATE) {
+            Ok(handle) => unsafe { TerminateProcess(handle, 0) > 0 },
+            Err(_) => false,
+        }
     }
 
     pub fn get_exit_code(&self) -> Option<u32> {
@@ -665,10 +849,11 @@ impl Process {
 
     // https://docs.microsoft.com/zh-cn/windows/win32/memory/obtaining-a-file-name-from-a-file-handle
     pub fn get_mapped_file_name(&self, address: usize) -> Option<String> {
+        let handle = self.handle_with(ProcessAccess::QUERY_INFO).ok()?;
         unsafe {
             let mut buf = [0u16; 300];
             let len = GetMappedFileNameW(
-                *self.handle,
+                handle,
                 address as LPVOID,
                 buf.as_mut_ptr(),
                 buf.len() as u32,
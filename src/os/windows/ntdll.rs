@@ -137,6 +137,25 @@ pub fn query_object_type(handle: HANDLE) -> WindowsResult<BufferType<OBJECT_TYPE
     read_object_info(handle, ObjectTypeInformation, 0).map(|r| BufferType::from_vec(r))
 }
 
+/// Get the access rights granted to a handle
+pub fn query_handle_access(handle: HANDLE) -> Option<u32> {
+    unsafe {
+        let mut info: OBJECT_BASIC_INFORMATION = zeroed();
+        let r = NtQueryObject(
+            handle,
+            ObjectBasicInformation,
+            transmute(&mut info),
+            size_of_val(&info) as u32,
+            null_mut(),
+        );
+        if NT_SUCCESS(r) {
+            Some(info.GrantedAccess)
+        } else {
+            None
+        }
+    }
+}
+
 // https://docs.rs/ntapi/0.3.3/ntapi/ntexapi/struct.SYSTEM_PROCESS_INFORMATION.html
 pub struct SystemProcessInfoIter<'a> {
     data: Vec<u8>,
@@ -243,6 +262,51 @@ impl<'a> Iterator for SystemHandleInfoIter<'a> {
     }
 }
 
+/// The handles of a process, iterated from the snapshot of `ProcessHandleInformation`
+pub struct ProcessHandleInfoIter {
+    data: Vec<u8>,
+    i: usize,
+}
+
+impl Iterator for ProcessHandleInfoIter {
+    type Item = PROCESS_HANDLE_TABLE_ENTRY_INFO;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            let this = &*(self.data.as_ptr() as *const PROCESS_HANDLE_SNAPSHOT_INFORMATION);
+            let r = from_raw_parts(this.Handles.as_ptr(), this.NumberOfHandles);
+            let result = *r.get(self.i)?;
+            self.i += 1;
+            Some(result)
+        }
+    }
+}
+
+/// Query only the handles of a process, instead of all the handles of system as
+/// [`system_handle_information`]. Windows 8 and later, requires `PROCESS_QUERY_INFORMATION`
+pub fn process_handle_information(process: HANDLE) -> WindowsResult<ProcessHandleInfoIter> {
+    let mut size = 0u32;
+    unsafe {
+        let mut data = vec![0u8; 0x1000];
+        let err = loop {
+            let err = ZwQueryInformationProcess(
+                process,
+                ProcessHandleInformation,
+                transmute(data.as_mut_ptr()),
+                data.len() as u32,
+                &mut size,
+            );
+            if err != STATUS_INFO_LENGTH_MISMATCH {
+                break err;
+            }
+            // some room for the handles opened in the meantime
+            data.resize(size as usize + 0x1000, 0u8);
+        };
+        err.ntstatus_result()?;
+        Ok(ProcessHandleInfoIter { data, i: 0 })
+    }
+}
+
 pub fn read_system_information(
     si: SYSTEM_INFORMATION_CLASS,
     extra_size: usize,
@@ -0,0 +1,295 @@
+//! Named kernel objects: resolve handles and addresses to the objects they refer to,
+//! and enumerate/open the objects in the object namespace, such as `\BaseNamedObjects`
+
+use super::{ntdll::*, string::*, Handle, Process, ProcessAccess};
+use crate::prelude::*;
+
+use core::mem::{size_of_val, transmute, zeroed};
+use core::ptr::null_mut;
+use failed_result::*;
+use ntapi::ntmmapi::{NtQuerySection, SectionBasicInformation, SECTION_BASIC_INFORMATION};
+use ntapi::ntobapi::*;
+use std::collections::HashMap;
+use winapi::shared::ntdef::*;
+use winapi::um::handleapi::DuplicateHandle;
+use winapi::um::processthreadsapi::{GetCurrentProcess, ProcessIdToSessionId};
+use winapi::um::winnt::*;
+
+/// A kernel object, with its type name such as "Section", "Event", "Mutant", "File"
+#[derive(Debug, Clone, Serialize)]
+pub struct KernelObject {
+    pub type_name: String,
+    /// Full path in object namespace, empty if the object is unnamed
+    pub name: String,
+}
+
+/// Object namespace directory which contains the named objects of a session
+pub fn base_named_objects(session_id: u32) -> String {
+    if session_id == 0 {
+        r"\BaseNamedObjects".into()
+    } else {
+        format!(r"\Sessions\{session_id}\BaseNamedObjects")
+    }
+}
+
+/// Query the type and name of a handle in current process,
+/// the querying of name could hang on some pipes, so it's called with timeout
+pub fn query_kernel_object(handle: HANDLE) -> UDbgResult<KernelObject> {
+    let type_name = query_object_type(handle)?.TypeName.to_string();
+    let name = if type_name == "File" {
+        super::query_object_name_timeout(handle)
+    } else {
+        query_object_name(handle)
+            .map(|n| n.to_string())
+            .unwrap_or_default()
+    };
+    Ok(KernelObject { type_name, name })
+}
+
+/// Size of a section object, rounded up to page size
+fn section_size(handle: HANDLE) -> Option<usize> {
+    let mut info: SECTION_BASIC_INFORMATION = unsafe { zeroed() };
+    unsafe {
+        NtQuerySection(
+            handle,
+            SectionBasicInformation,
+            transmute(&mut info),
+            size_of_val(&info),
+            null_mut(),
+        )
+        .ntstatus_result()
+        .ok()?;
+        Some((*info.MaximumSize.QuadPart() as usize + 0xFFF) & !0xFFF)
+    }
+}
+
+/// Run `callback` with object attributes of `path`
+fn with_object_attributes<T>(path: &str, callback: impl FnOnce(&mut OBJECT_ATTRIBUTES) -> T) -> T {
+    let path = path.to_unicode();
+    let mut name = UniStr::from_slice(&path);
+    unsafe {
+        let mut attr: OBJECT_ATTRIBUTES = zeroed();
+        InitializeObjectAttributes(
+            &mut attr,
+            name.as_mut_ptr(),
+            OBJ_CASE_INSENSITIVE,
+            null_mut(),
+            null_mut(),
+        );
+        callback(&mut attr)
+    }
+}
+
+/// Open a named object by its path and type name, e.g. `open_named_object(r"\BaseNamedObjects\xxx", "Event", EVENT_ALL_ACCESS)`
+pub fn open_named_object(path: &str, type_name: &str, access: u32) -> UDbgResult<Handle> {
+    use ntapi::ntexapi::{NtOpenEvent, NtOpenMutant, NtOpenSemaphore};
+    use ntapi::ntmmapi::NtOpenSection;
+
+    let mut handle = null_mut();
+    let status = with_object_attributes(path, |attr| unsafe {
+        match type_name {
+            "Event" => Ok(NtOpenEvent(&mut handle, access, attr)),
+            "Mutant" => Ok(NtOpenMutant(&mut handle, access, attr)),
+            "Semaphore" => Ok(NtOpenSemaphore(&mut handle, access, attr)),
+            "Section" => Ok(NtOpenSection(&mut handle, access, attr)),
+            "Directory" => Ok(NtOpenDirectoryObject(&mut handle, access, attr)),
+            _ => Err(UDbgError::NotSupport),
+        }
+    })?;
+    status.ntstatus_result()?;
+    Ok(unsafe { Handle::from_raw_handle(handle) })
+}
+
+/// A directory in object namespace
+pub struct ObjectDirectory {
+    handle: Handle,
+    path: String,
+}
+
+impl ObjectDirectory {
+    pub fn open(path: &str) -> UDbgResult<Self> {
+        Ok(Self {
+            handle: open_named_object(path, "Directory", DIRECTORY_QUERY)?,
+            path: path.trim_end_matches('\\').into(),
+        })
+    }
+
+    /// Enumerate the objects in this directory, the names are full paths
+    pub fn enum_object(&self) -> impl Iterator<Item = KernelObject> + '_ {
+        let mut context = 0u32;
+        let mut buf = vec![0u8; 0x1000];
+        core::iter::from_fn(move || unsafe {
+            let mut len = 0u32;
+            NtQueryDirectoryObject(
+                *self.handle,
+                buf.as_mut_ptr().cast(),
+                buf.len() as u32,
+                TRUE,
+                FALSE,
+                &mut context,
+                &mut len,
+            )
+            .ntstatus_result()
+            .ok()?;
+            let info: &OBJECT_DIRECTORY_INFORMATION = transmute(buf.as_ptr());
+            Some(KernelObject {
+                type_name: info.TypeName.to_string(),
+                name: format!("{}\\{}", self.path, info.Name.to_string()),
+            })
+        })
+    }
+}
+
+impl Process {
+    /// Session of this process, used to locate its `BaseNamedObjects`
+    pub fn session_id(&self) -> Option<u32> {
+        let mut result = 0u32;
+        unsafe {
+            if ProcessIdToSessionId(self.pid(), &mut result) > 0 {
+                Some(result)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Resolve a handle of this process to the kernel object it refers to
+    pub fn query_object(&self, handle: HANDLE) -> UDbgResult<KernelObject> {
+        let process = self.handle_with(ProcessAccess::DUP_HANDLE)?;
+        let mut result = null_mut();
+        unsafe {
+            if DuplicateHandle(
+                process,
+                handle,
+                GetCurrentProcess(),
+                &mut result,
+                0,
+                FALSE,
+                DUPLICATE_SAME_ACCESS,
+            ) == 0
+            {
+                return Err(UDbgError::system());
+            }
+            let handle = Handle::from_raw_handle(result);
+            query_kernel_object(*handle)
+        }
+    }
+
+    /// Resolve an address to the object mapped at it, the file of mapped view,
+    /// or the named section which has the same size of the allocation if it's unique
+    pub fn query_address_object(&self, address: usize) -> Option<KernelObject> {
+        let page = self.virtual_query(address)?;
+        if page.type_ != MEM_MAPPED && page.type_ != MEM_IMAGE {
+            return None;
+        }
+        if let Some(name) = self.get_mapped_file_name(address) {
+            return Some(KernelObject {
+                type_name: "File".into(),
+                name,
+            });
+        }
+
+        // pagefile-backed section, find it in the handles of this process
+        let size = self.allocation_size(page.alloc_base);
+        let process = self
+            .handle_with(ProcessAccess::DUP_HANDLE | ProcessAccess::QUERY_INFO)
+            .or_else(|_| self.handle_with(ProcessAccess::DUP_HANDLE))
+            .ok()?;
+        let mut found = super::enum_process_handle(self.pid(), process)
+            .ok()?
+            .filter(|h| h.type_name == "Section" && !h.name.is_empty())
+            .filter(|h| {
+                open_named_object(&h.name, "Section", SECTION_QUERY)
+                    .ok()
+                    .and_then(|h| section_size(*h))
+                    == Some(size)
+            });
+        let first = found.next()?;
+        found.next().is_none().then(|| KernelObject {
+            type_name: first.type_name,
+            name: first.name,
+        })
+    }
+
+    /// Total size of the regions in an allocation
+    fn allocation_size(&self, alloc_base: usize) -> usize {
+        self.enum_memory(alloc_base)
+            .take_while(|p| p.alloc_base == alloc_base)
+            .map(|p| p.size)
+            .sum()
+    }
+
+    /// Find the processes which hold the same section of the view at `address`.
+    ///
+    /// The section handles are cross-referenced by the object address in system handle table,
+    /// or by name if the addresses are hidden for non-elevated caller.
+    /// Processes which have closed the section handle after mapping it can not be found.
+    pub fn shared_with(&self, address: usize) -> UDbgResult<Vec<SharedView>> {
+        let page = self
+            .virtual_query(address)
+            .ok_or(UDbgError::InvalidAddress)?;
+        if page.type_ != MEM_MAPPED {
+            return Err(UDbgError::NotSupport);
+        }
+        let size = self.allocation_size(page.alloc_base);
+        let name = self
+            .query_address_object(address)
+            .filter(|o| o.type_name == "Section")
+            .map(|o| o.name);
+
+        let pid = self.pid();
+        let handles = system_handle_information().copied().collect::<Vec<_>>();
+        // the section handles in this process which the view is mapped from
+        let mut owned = handles.iter().filter(|h| h.pid() == pid).filter(|h| {
+            let handle =
+                match self.duplicate_handle(h.HandleValue as _, unsafe { GetCurrentProcess() }) {
+                    Some(h) => unsafe { Handle::from_raw_handle(h) },
+                    None => return false,
+                };
+            if !query_object_type(*handle).map_or(false, |t| t.TypeName.to_string() == "Section") {
+                return false;
+            }
+            match name.as_ref() {
+                Some(name) => query_object_name(*handle).map_or(false, |n| &n.to_string() == name),
+                None => section_size(*handle) == Some(size),
+            }
+        });
+        let section = *owned.next().ok_or(UDbgError::NotFound)?;
+        if owned.any(|h| h.Object != section.Object) {
+            return Err("multiple sections match the view".into());
+        }
+
+        let others = handles
+            .iter()
+            .filter(|h| h.pid() != pid && h.ObjectTypeIndex == section.ObjectTypeIndex);
+        let result = if !section.Object.is_null() {
+            others
+                .filter(|h| h.Object == section.Object)
+                .collect::<Vec<_>>()
+        } else if let Some(name) = name {
+            let mut processes = HashMap::<u32, Option<Process>>::new();
+            others
+                .filter(|h| {
+                    processes
+                        .entry(h.pid())
+                        .or_insert_with(|| Process::open(h.pid(), Some(ProcessAccess::DUP_HANDLE)))
+                        .as_ref()
+                        .and_then(|p| p.query_object(h.HandleValue as _).ok())
+                        .map_or(false, |o| o.name == name)
+                })
+                .collect()
+        } else {
+            return Err(UDbgError::AccessDenied(
+                "object addresses are hidden and the section is unnamed".into(),
+            ));
+        };
+        Ok(result
+            .into_iter()
+            .map(|h| SharedView {
+                pid: h.pid(),
+                handle: Some(h.HandleValue as usize),
+                address: None,
+            })
+            .collect())
+    }
+}
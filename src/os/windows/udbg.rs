@@ -28,6 +28,9 @@ use parking_lot::RwLock;
 use serde_value::Value as SerdeVal;
 
 use super::ntdll::*;
+use crate::audit::AuditAction;
+use crate::poll::StoppedGuard;
+use crate::unpack::OepFault;
 use crate::{pe::PeHelper, range::*, register::*, shell::udbg_ui};
 
 #[repr(u32)]
@@ -672,6 +675,9 @@ where
     T: Deref<Target = TargetCommon>,
 {
     default fn detach(&self) -> Result<(), UDbgError> {
+        self.base
+            .audit
+            .record(self.base.pid.get(), AuditAction::Detach);
         self.base.status.set(UDbgStatus::Detaching);
         Ok(())
     }
@@ -679,7 +685,8 @@ where
     default fn breakk(&self) -> Result<(), UDbgError> {
         self.base.check_attached()?;
         unsafe {
-            if DebugBreakProcess(*self.process.handle) > 0 {
+            let handle = self.process.handle_with(ProcessAccess::CREATE_THREAD)?;
+            if DebugBreakProcess(handle) > 0 {
                 Ok(())
             } else {
                 Err(UDbgError::system())
@@ -700,6 +707,10 @@ where
     }
 
     default fn wait_exit(&self, timeout: Option<u32>) -> UDbgResult<Option<u32>> {
+        if timeout.is_none() {
+            // the target never exits while its debug event is being handled
+            EventLoopGuard::check_outside()?;
+        }
         Ok(if self.wait_process_exit(timeout) {
             self.process.get_exit_code()
         } else {
@@ -770,6 +781,9 @@ impl TargetCommon {
 
     pub fn terminate_process(&self) -> UDbgResult<()> {
         self.process.terminate().last_error()?;
+        self.base
+            .audit
+            .record(self.base.pid.get(), AuditAction::Terminate);
         Ok(())
     }
 
@@ -866,7 +880,10 @@ impl TargetCommon {
         let this = this.as_ref();
 
         bp.hit_count.set(bp.hit_count.get() + 1);
-        if bp.temp.get() {
+        // a temporary breakpoint is kept until hit in its thread and frame
+        let tid = self.base.event_tid.get();
+        let hitted = bp.reports_hit(tid, context.sp().to_usize());
+        if hitted && bp.temp.get() {
             self.remove_breakpoint(this, &bp);
         }
 
@@ -881,8 +898,6 @@ impl TargetCommon {
         *context.ip() = pc;
 
         // handle by user
-        let tid = self.base.event_tid.get();
-        let hitted = bp.hit_tid.map(|t| t == tid).unwrap_or(true);
         if hitted {
             self.handle_reply(this, tb.call(UEvent::Breakpoint(bp.clone())), context);
         }
@@ -973,6 +988,42 @@ impl TargetCommon {
         }
     }
 
+    /// Detect the packer of a loaded module and arm the OEP heuristic for it
+    pub fn check_unpack<T: UDbgTarget>(&self, this: &T, base: usize) {
+        if !self.base.flags.get().contains(UDbgFlags::AUTO_UNPACK) {
+            return;
+        }
+        if let Some(m) = self.symgr.find_module(base) {
+            self.base.oep.lock().check_module(this, m.as_ref());
+        }
+    }
+
+    pub fn handle_possible_oep<C: DbgContext, T: Deref<Target = Self> + UDbgTarget>(
+        &self,
+        tb: &mut TraceBuf<T>,
+        context: &mut C,
+    ) -> HandleResult {
+        // not a DEP violation
+        if tb.record.params[0] != 8 {
+            return HandleResult::NotHandled;
+        }
+        let address = tb.record.params[1] as usize;
+        let this = tb.target.clone();
+        let fault = self.base.oep.lock().on_execute(this.as_ref(), address);
+        match fault {
+            Some(OepFault::PossibleOep) => {
+                self.handle_reply(
+                    this.as_ref(),
+                    tb.call(UEvent::PossibleOEP(address)),
+                    context,
+                );
+                HandleResult::Continue
+            }
+            Some(OepFault::Unchanged) => HandleResult::Continue,
+            None => HandleResult::NotHandled,
+        }
+    }
+
     pub fn open_thread(&self, tid: u32) -> UDbgResult<Box<WinThread>> {
         WinThread::open(&self.process, tid)
     }
@@ -1128,6 +1179,11 @@ impl TargetCommon {
         for m in self.symgr.enum_module() {
             rest_loaded.insert(m.data().base);
         }
+        let mut ldr_paths = HashMap::new();
+        for m in crate::peb::enum_ldr_modules(&self.process).unwrap_or_default() {
+            // the 32-bit view comes first
+            ldr_paths.entry(m.base).or_insert(m.full_name);
+        }
         // for m in self.process.enum_module() {
         //     let base = m.base();
         //     rest_loaded.remove(&base);
@@ -1143,12 +1199,11 @@ impl TargetCommon {
                 continue;
             }
 
-            #[allow(deprecated)]
-            let path = self
-                .process
-                .get_mapped_file_name(m)
-                // get_module_path() returns 64-bit path in WOW64 process, so put it last
-                .unwrap_or_else(|| self.process.get_module_path(m).unwrap_or_default());
+            let path = ldr_paths
+                .remove(&m)
+                .filter(|p| !p.is_empty())
+                .or_else(|| self.process.get_mapped_file_name(m))
+                .unwrap_or_default();
             self.process.get_module_info(m).map(|m| {
                 self.symgr.check_load_module(
                     dbg,
@@ -1261,7 +1316,7 @@ pub fn collect_memory_info(p: &Process, this: &dyn UDbgTarget) -> Vec<MemoryPage
 
     if heaps_num > 0 && heaps_num < MAX_HEAPS {
         let mut buf = vec![0usize; heaps_num];
-        this.read_value::<usize>(peb + FIELD_OFFSET!(PEB, ProcessHeaps))
+        this.read_copy::<usize>(peb + FIELD_OFFSET!(PEB, ProcessHeaps))
             .map(|p_heaps| {
                 let len = this.read_to_array(p_heaps, &mut buf);
                 buf.resize(len, 0);
@@ -1342,20 +1397,38 @@ pub fn query_object_name_timeout(handle: HANDLE) -> String {
     .unwrap_or_default()
 }
 
+/// Enumerate the handles of process `p` lazily, which is opened with `PROCESS_DUP_HANDLE`. Only
+/// the handles of the process are queried if `p` has `PROCESS_QUERY_INFORMATION` too, otherwise
+/// the handles of the whole system are filtered
 pub fn enum_process_handle<'a>(
     pid: u32,
     p: HANDLE,
 ) -> Result<Box<dyn Iterator<Item = HandleInfo> + 'a>, UDbgError> {
+    // (handle value, type index, access)
+    let entries: Box<dyn Iterator<Item = (usize, u32, u32)>> = match process_handle_information(p) {
+        Ok(iter) => {
+            Box::new(iter.map(|h| (h.HandleValue as usize, h.ObjectTypeIndex, h.GrantedAccess)))
+        }
+        Err(_) => Box::new(
+            system_handle_information()
+                .filter(move |h| h.pid() == pid)
+                .map(|h| {
+                    (
+                        h.HandleValue as usize,
+                        h.ObjectTypeIndex as u32,
+                        h.GrantedAccess,
+                    )
+                }),
+        ),
+    };
     let mut type_cache = HashMap::<u32, String>::new();
-    Ok(Box::new(system_handle_information().filter_map(move |h| {
-        if h.pid() != pid {
-            return None;
-        }
+    Ok(Box::new(entries.filter_map(move |h| {
+        let (value, type_index, access) = h;
         let mut handle = 0 as HANDLE;
         unsafe {
             let r = DuplicateHandle(
                 p,
-                h.HandleValue as HANDLE,
+                value as HANDLE,
                 GetCurrentProcess(),
                 &mut handle,
                 0,
@@ -1367,24 +1440,25 @@ pub fn enum_process_handle<'a>(
             }
 
             let handle = Handle::from_raw_handle(handle);
-            let et = type_cache
-                .entry(h.ObjectTypeIndex as u32)
-                .or_insert_with(|| {
-                    query_object_type(*handle)
-                        .map(|t| t.TypeName.to_string())
-                        .unwrap_or_default()
-                });
+            let et = type_cache.entry(type_index).or_insert_with(|| {
+                query_object_type(*handle)
+                    .map(|t| t.TypeName.to_string())
+                    .unwrap_or_default()
+            });
             let type_name = et.clone();
             let name = if type_name == "Process" {
-                Process { handle }.image_path().unwrap_or_default()
+                Process::from_handle(handle)
+                    .and_then(|p| p.image_path().ok())
+                    .unwrap_or_default()
             } else {
                 query_object_name_timeout(*handle)
             };
             Some(HandleInfo {
                 name,
                 type_name,
-                ty: h.ObjectTypeIndex as u32,
-                handle: h.HandleValue as usize,
+                ty: type_index,
+                handle: value,
+                access,
             })
         }
     })))
@@ -1514,7 +1588,16 @@ impl ProcessTarget {
         }
     }
 
-    pub fn set_context<C: DbgContext>(&self, tid: u32, c: &C) {
+    pub fn set_context<C: DbgContext + UDbgRegs>(&self, tid: u32, c: &C) {
+        if self.base.audit.is_enabled() {
+            let mut old: C = unsafe { core::mem::zeroed() };
+            if self.get_context(tid, &mut old) {
+                let arch = if C::IS_32 { ARCH_X86 } else { UDBG_ARCH };
+                self.base
+                    .audit
+                    .record_regs(self.base.pid.get(), tid, arch, &old, c);
+            }
+        }
         let suc = if let Some(t) = self.threads.borrow().get(&tid) {
             c.set_context(t.handle)
         } else {
@@ -1585,7 +1668,12 @@ impl Target for ProcessTarget {
     }
 
     fn enum_handle<'a>(&'a self) -> Result<Box<dyn Iterator<Item = HandleInfo> + 'a>, UDbgError> {
-        enum_process_handle(self.base.pid.get(), *self.process.handle)
+        enum_process_handle(
+            self.base.pid.get(),
+            self.process
+                .handle_with(ProcessAccess::DUP_HANDLE | ProcessAccess::QUERY_INFO)
+                .or_else(|_| self.process.handle_with(ProcessAccess::DUP_HANDLE))?,
+        )
     }
 }
 
@@ -1605,8 +1693,10 @@ pub struct TraceBuf<'a, T = ProcessTarget> {
 impl<T: UDbgTarget> TraceBuf<'_, T> {
     #[inline]
     pub fn call(&mut self, event: UEvent) -> UserReply {
+        let _stopped = StoppedGuard::new();
         self.target.base().context_arch.set(self.arch());
-        unsafe { (self.callback.as_mut().unwrap())(self, event) }
+        let callback = unsafe { self.callback.as_mut().unwrap() };
+        crate::os::dispatch_event(self, callback, event)
     }
 }
 
@@ -1725,6 +1815,10 @@ impl UDbgEngine for DefaultEngine {
     }
 
     fn attach(&mut self, pid: u32) -> UDbgResult<Arc<dyn UDbgTarget>> {
+        if crate::consts::PASSIVE {
+            return Err(UDbgError::NotSupport);
+        }
+        check_self_debug(pid)?;
         unsafe {
             DebugActiveProcess(pid).last_error()?;
             let result = ProcessTarget::open(pid)?;
@@ -1740,6 +1834,9 @@ impl UDbgEngine for DefaultEngine {
         cwd: Option<&str>,
         args: &[&str],
     ) -> UDbgResult<Arc<dyn UDbgTarget>> {
+        if crate::consts::PASSIVE {
+            return Err(UDbgError::NotSupport);
+        }
         let mut pi: PROCESS_INFORMATION = unsafe { core::mem::zeroed() };
         let ppid = udbg_ui().get_config("ppid");
         let result = ProcessTarget::new(create_debug_process(path, cwd, args, &mut pi, ppid)?);
@@ -1748,6 +1845,7 @@ impl UDbgEngine for DefaultEngine {
     }
 
     fn event_loop(&mut self, callback: &mut UDbgCallback) -> UDbgResult<()> {
+        let _guard = EventLoopGuard::enter()?;
         let mut cx = Align16::<CONTEXT>::new();
         let mut cx32 = unsafe { core::mem::zeroed() };
 
@@ -1781,6 +1879,7 @@ impl UDbgEngine for DefaultEngine {
 impl EventHandler for DefaultEngine {
     fn fetch(&mut self, tb: &mut TraceBuf) -> Option<()> {
         self.event = wait_for_debug_event(INFINITE)?;
+        crate::metrics::stop_begin();
         if self.event.dwDebugEventCode == CREATE_PROCESS_DEBUG_EVENT {
             if self
                 .targets
@@ -1847,6 +1946,7 @@ impl EventHandler for DefaultEngine {
                         info.hFile,
                         info.fUnicode > 0,
                     );
+                    this.check_unpack(this, info.lpBaseOfImage as usize);
                     self.update_context(tb);
                     tb.call(ProcessCreate);
                     tb.call(ThreadCreate(tid));
@@ -1892,6 +1992,7 @@ impl EventHandler for DefaultEngine {
                     if let Some(m) = this.symgr.find_module(info.lpBaseOfDll as usize) {
                         tb.call(ModuleLoad(m));
                     }
+                    this.check_unpack(this, info.lpBaseOfDll as usize);
                 }
                 UNLOAD_DLL_DEBUG_EVENT => {
                     self.update_context(tb);
@@ -2007,6 +2108,15 @@ impl EventHandler for DefaultEngine {
                             } else {
                                 HandleResult::NotHandled
                             };
+                            if result == HandleResult::NotHandled
+                                && code == EXCEPTION_ACCESS_VIOLATION
+                            {
+                                result = if wow64 {
+                                    this.handle_possible_oep(tb, cx32)
+                                } else {
+                                    this.handle_possible_oep(tb, cx)
+                                };
+                            }
                             if result == HandleResult::NotHandled
                                 && this.base.status.get() != UDbgStatus::Detaching
                             {
@@ -2025,6 +2135,14 @@ impl EventHandler for DefaultEngine {
 
     fn cont(&mut self, status: HandleResult, tb: &mut TraceBuf) {
         let this = tb.target.clone();
+        if this.status.get() == UDbgStatus::Detaching {
+            // restore the hooked functions, watched pages and pages armed for the OEP while all
+            // threads are stopped
+            #[cfg(not(feature = "passive"))]
+            this.base.hooks.remove_all(this.as_ref());
+            this.base.mem_watch.remove_all(this.as_ref());
+            this.base.oep.lock().disarm_all(this.as_ref());
+        }
         let cx32 = this.cx32.get();
         if !cx32.is_null() {
             this.set_context(self.event.dwThreadId, unsafe { &*cx32 });
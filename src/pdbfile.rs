@@ -6,6 +6,7 @@ use anyhow::Context;
 use pdb::{FallibleIterator, ItemIter, MemberType, SymbolData, TypeData, TypeIndex, PDB};
 
 use spin::Mutex;
+use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
 use std::{fs::File, io::ErrorKind};
 
@@ -135,6 +136,58 @@ impl PdbFile {
         Ok(result)
     }
 
+    /// Source lines of all the modules, keyed by RVA
+    pub fn lines(&mut self) -> anyhow::Result<BTreeMap<u32, SourceLine>> {
+        let pdb = &mut self.db;
+        let address_map = pdb.address_map().context("address_map failed")?;
+        let strings = pdb.string_table().context("string_table failed")?;
+        let dbi = pdb
+            .debug_information()
+            .context("debug_information failed")?;
+        let mut modules = dbi.modules().context("get modules failed")?;
+
+        let mut result = BTreeMap::new();
+        let mut files = HashSet::<Arc<str>>::new();
+        while let Ok(Some(module)) = modules.next() {
+            let program = match pdb.module_info(&module) {
+                Ok(Some(i)) => match i.line_program() {
+                    Ok(p) => p,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            let mut lines = program.lines();
+            while let Ok(Some(line)) = lines.next() {
+                let rva = match line.offset.to_rva(&address_map) {
+                    Some(rva) => rva.0,
+                    None => continue,
+                };
+                let file = match program
+                    .get_file_info(line.file_index)
+                    .and_then(|f| f.name.to_string_lossy(&strings))
+                {
+                    Ok(name) => match files.get(name.as_ref()) {
+                        Some(f) => f.clone(),
+                        None => {
+                            let f: Arc<str> = name.as_ref().into();
+                            files.insert(f.clone());
+                            f
+                        }
+                    },
+                    Err(_) => continue,
+                };
+                result.insert(
+                    rva,
+                    SourceLine {
+                        file,
+                        line: line.line_start,
+                    },
+                );
+            }
+        }
+        Ok(result)
+    }
+
     pub fn td2ti(&mut self, id: u32, data: TypeData, name: Option<&str>) -> Option<TypeInfo> {
         let (tn, kind) = match data {
             TypeData::Procedure(p) => (
@@ -327,6 +380,7 @@ pub struct PDBData {
     pub file: Mutex<PdbFile>,
     pub path: Arc<str>,
     pub global: Mutex<Option<Arc<SymbolMap>>>,
+    pub lines: Mutex<Option<Arc<BTreeMap<u32, SourceLine>>>>,
 }
 
 impl PDBData {
@@ -335,8 +389,25 @@ impl PDBData {
             file: PdbFile::load(path, pe)?.into(),
             path: path.into(),
             global: None.into(),
+            lines: None.into(),
         })
     }
+
+    /// Source lines by the offset they start at, loaded on first use
+    fn line_map(&self) -> Arc<BTreeMap<u32, SourceLine>> {
+        if let Some(lines) = self.lines.lock().clone() {
+            return lines;
+        }
+        let lines = Arc::new(
+            self.file
+                .lock()
+                .lines()
+                .log_error_with(|err| format!("load lines of {}: {err:?}", self.path))
+                .unwrap_or_default(),
+        );
+        *self.lines.lock() = lines.clone().into();
+        lines
+    }
 }
 
 impl SymbolFile for PDBData {
@@ -364,6 +435,17 @@ impl SymbolFile for PDBData {
         self.file.lock().find_field(id, name).ok()
     }
 
+    fn find_line(&self, offset: u32) -> Option<SourceLine> {
+        self.line_map().get(&offset).cloned()
+    }
+
+    fn line_at(&self, offset: u32) -> Option<SourceLine> {
+        self.line_map()
+            .range(..=offset)
+            .next_back()
+            .map(|(_, line)| line.clone())
+    }
+
     fn global(&self) -> anyhow::Result<Arc<SymbolMap>> {
         let result = self.global.lock().clone();
         match result {
@@ -408,11 +490,20 @@ impl pe::PeHelper<'_> {
         for p in paths.iter() {
             if p.exists() {
                 match PDBData::load(&p.to_string_lossy(), self.into()) {
-                    Ok(pdb) => return Ok(pdb.into()),
+                    Ok(pdb) => {
+                        crate::metrics::record_symbol_lookup(true);
+                        return Ok(pdb.into());
+                    }
                     Err(e) => err = Some(e),
                 }
             }
         }
+        crate::metrics::record_symbol_lookup(false);
+        // 5. fetch from the symbol server into cache
+        #[cfg(feature = "symproxy")]
+        if let Some(p) = self.fetch_pdb(pdbpath)? {
+            return Ok(PDBData::load(&p.to_string_lossy(), self.into())?.into());
+        }
 
         Err(err
             .map(Into::into)
@@ -420,6 +511,30 @@ impl pe::PeHelper<'_> {
     }
 }
 
+#[cfg(feature = "symproxy")]
+impl pe::PeHelper<'_> {
+    fn fetch_pdb(&self, pdbpath: Option<&str>) -> anyhow::Result<Option<std::path::PathBuf>> {
+        let base = udbg_ui().base();
+        let (server, cache) = match (base.symserver.as_ref(), base.symcache.as_ref()) {
+            (Some(s), Some(c)) => (s, c),
+            _ => return Ok(None),
+        };
+        let pdbname = match pdbpath.and_then(|p| p.rsplit(['/', '\\']).next()) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let sig = match self.get_pdb_signature() {
+            Some(sig) => sig,
+            None => return Ok(None),
+        };
+        Ok(crate::symproxy::fetch(
+            server,
+            &format!("{pdbname}/{sig}/{pdbname}"),
+            cache,
+        )?)
+    }
+}
+
 impl SymbolsData {
     pub fn load_from_pdb(&self, path: &str) -> anyhow::Result<SymbolMap> {
         let mut pdb = PdbFile::load(path, None)?;
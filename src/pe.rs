@@ -267,6 +267,7 @@ impl PETarget {
         let module = PEModule::new(&path)?;
         let base = TargetBase::default();
         base.pid.set(1);
+        base.endian.set(Endian::Little);
         // base.arch
         let symgr = SymbolManager::default();
         symgr.base.write().list.push(module.into());
@@ -306,6 +307,9 @@ impl ReadMemory for PETarget {
         res.copy_from_slice(&slice[..len]);
         Some(res)
     }
+    fn data_layout(&self) -> DataLayout {
+        self.base.data_layout()
+    }
 }
 
 impl WriteMemory for PETarget {
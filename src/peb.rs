@@ -0,0 +1,502 @@
+//!
+//! Typed accessors of PEB and TEB of the target process, decode the native structures in both the
+//! 64-bit and WOW64 32-bit layouts.
+//!
+//! The WOW64 processes have both the 64-bit and 32-bit structures, the 32-bit ones are read, which
+//! are maintained by the 32-bit code of target. [`enum_ldr_modules`] walks the loader lists of both.
+//!
+
+use crate::os::windows::{
+    ntdll::{query_process, ProcessInfoClass},
+    Process, ProcessAccess,
+};
+use crate::prelude::*;
+
+/// Offsets of the fields in a layout
+struct Layout {
+    ptr_size: usize,
+    peb_image_base: usize,
+    peb_ldr: usize,
+    peb_process_parameters: usize,
+    peb_process_heap: usize,
+    peb_nt_global_flag: usize,
+    peb_os_version: usize,
+    peb_session_id: usize,
+    ldr_load_order: usize,
+    ldr_init_order: usize,
+    /// `InInitializationOrderLinks` of `LDR_DATA_TABLE_ENTRY`
+    entry_init_links: usize,
+    entry_dll_base: usize,
+    entry_entry_point: usize,
+    entry_size_of_image: usize,
+    entry_full_name: usize,
+    entry_base_name: usize,
+    params_current_directory: usize,
+    params_dll_path: usize,
+    params_image_path: usize,
+    params_command_line: usize,
+    params_environment: usize,
+    params_window_title: usize,
+    params_environment_size: usize,
+    teb_stack_base: usize,
+    teb_stack_limit: usize,
+    teb_client_id: usize,
+    teb_tls_pointer: usize,
+    teb_peb: usize,
+    teb_last_error: usize,
+    teb_deallocation_stack: usize,
+    teb_tls_slots: usize,
+    teb_tls_expansion_slots: usize,
+}
+
+const X64: Layout = Layout {
+    ptr_size: 8,
+    peb_image_base: 0x10,
+    peb_ldr: 0x18,
+    peb_process_parameters: 0x20,
+    peb_process_heap: 0x30,
+    peb_nt_global_flag: 0xBC,
+    peb_os_version: 0x118,
+    peb_session_id: 0x2C0,
+    ldr_load_order: 0x10,
+    ldr_init_order: 0x30,
+    entry_init_links: 0x20,
+    entry_dll_base: 0x30,
+    entry_entry_point: 0x38,
+    entry_size_of_image: 0x40,
+    entry_full_name: 0x48,
+    entry_base_name: 0x58,
+    params_current_directory: 0x38,
+    params_dll_path: 0x50,
+    params_image_path: 0x60,
+    params_command_line: 0x70,
+    params_environment: 0x80,
+    params_window_title: 0xB0,
+    params_environment_size: 0x3F0,
+    teb_stack_base: 0x08,
+    teb_stack_limit: 0x10,
+    teb_client_id: 0x40,
+    teb_tls_pointer: 0x58,
+    teb_peb: 0x60,
+    teb_last_error: 0x68,
+    teb_deallocation_stack: 0x1478,
+    teb_tls_slots: 0x1480,
+    teb_tls_expansion_slots: 0x1780,
+};
+
+const X86: Layout = Layout {
+    ptr_size: 4,
+    peb_image_base: 0x08,
+    peb_ldr: 0x0C,
+    peb_process_parameters: 0x10,
+    peb_process_heap: 0x18,
+    peb_nt_global_flag: 0x68,
+    peb_os_version: 0xA4,
+    peb_session_id: 0x1D4,
+    ldr_load_order: 0x0C,
+    ldr_init_order: 0x1C,
+    entry_init_links: 0x10,
+    entry_dll_base: 0x18,
+    entry_entry_point: 0x1C,
+    entry_size_of_image: 0x20,
+    entry_full_name: 0x24,
+    entry_base_name: 0x2C,
+    params_current_directory: 0x24,
+    params_dll_path: 0x30,
+    params_image_path: 0x38,
+    params_command_line: 0x40,
+    params_environment: 0x48,
+    params_window_title: 0x70,
+    params_environment_size: 0x290,
+    teb_stack_base: 0x04,
+    teb_stack_limit: 0x08,
+    teb_client_id: 0x20,
+    teb_tls_pointer: 0x2C,
+    teb_peb: 0x30,
+    teb_last_error: 0x34,
+    teb_deallocation_stack: 0xE0C,
+    teb_tls_slots: 0xE10,
+    teb_tls_expansion_slots: 0xF94,
+};
+
+const NATIVE: &Layout = if cfg!(target_pointer_width = "64") {
+    &X64
+} else {
+    &X86
+};
+
+/// The 32-bit TEB of a WOW64 thread follows its 64-bit TEB
+const WOW64_TEB_OFFSET: usize = 0x2000;
+pub const TLS_SLOTS: usize = 64;
+pub const TLS_EXPANSION_SLOTS: usize = 1024;
+/// Max environment block read when its size is unknown
+const MAX_ENVIRONMENT: usize = 0x10000;
+/// Max modules in the loader list, in case of a corrupted list
+const MAX_LDR_MODULES: usize = 0x4000;
+
+/// Reader of a structure in the layout
+struct Reader<'a, T: ?Sized> {
+    target: &'a T,
+    layout: &'static Layout,
+}
+
+impl<T: ReadMemory + ?Sized> Reader<'_, T> {
+    fn ptr(&self, address: usize) -> Option<usize> {
+        if self.layout.ptr_size == 4 {
+            self.target.read_value::<u32>(address).map(|p| p as usize)
+        } else {
+            self.target.read_value::<u64>(address).map(|p| p as usize)
+        }
+    }
+
+    /// Read the UNICODE_STRING at address
+    fn unicode_string(&self, address: usize) -> Option<String> {
+        let len = self.target.read_value::<u16>(address)? as usize;
+        let buffer = self.ptr(address + self.layout.ptr_size)?;
+        if len == 0 || buffer == 0 {
+            return Some(String::new());
+        }
+        let data = self.target.read_bytes(buffer, len & !1);
+        let wide = data
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>();
+        Some(String::from_utf16_lossy(&wide))
+    }
+}
+
+/// A module in the loader list of PEB
+#[derive(Debug, Clone, Serialize)]
+pub struct LdrModule {
+    /// Address of the `LDR_DATA_TABLE_ENTRY`
+    pub entry: usize,
+    pub base: usize,
+    pub entry_point: usize,
+    pub size: usize,
+    pub full_name: String,
+    pub base_name: String,
+    /// In the 32-bit loader list of a WOW64 process
+    pub is_32bit: bool,
+}
+
+/// The decoded `RTL_USER_PROCESS_PARAMETERS`
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessParameters {
+    pub address: usize,
+    pub current_directory: String,
+    pub dll_path: String,
+    pub image_path: String,
+    pub command_line: String,
+    pub window_title: String,
+    /// Address of the environment block
+    pub environment: usize,
+    /// Size of the environment block, 0 before Vista
+    pub environment_size: usize,
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct StackLimits {
+    pub base: usize,
+    pub limit: usize,
+    /// Bottom of the reserved stack
+    pub deallocation: usize,
+}
+
+/// Process environment block of target
+pub struct Peb<'a, T: ?Sized> {
+    reader: Reader<'a, T>,
+    pub address: usize,
+}
+
+impl<'a, T: UDbgTarget + ?Sized> Peb<'a, T> {
+    /// The PEB of target process, the 32-bit one for WOW64 process
+    pub fn of(target: &'a T) -> UDbgResult<Self> {
+        let process = target.process().ok_or(UDbgError::NotSupport)?;
+        let wow64 = cfg!(target_pointer_width = "64") && process.is_wow64();
+        Self::locate(target, process, wow64)
+    }
+}
+
+impl<'a> Peb<'a, Process> {
+    /// The native PEB of process, the 64-bit one for WOW64 process
+    pub fn native(process: &'a Process) -> UDbgResult<Self> {
+        Self::locate(process, process, false)
+    }
+
+    /// The 32-bit PEB of a WOW64 process
+    pub fn wow64(process: &'a Process) -> UDbgResult<Self> {
+        Self::locate(process, process, true)
+    }
+}
+
+impl<'a, T: ReadMemory + ?Sized> Peb<'a, T> {
+    fn locate(target: &'a T, process: &Process, wow64: bool) -> UDbgResult<Self> {
+        let (address, layout) = if wow64 {
+            let handle = process.handle_with(ProcessAccess::QUERY)?;
+            let peb32 = query_process::<usize>(handle, ProcessInfoClass::Wow64Information, None);
+            (peb32.unwrap_or_default(), &X86)
+        } else {
+            (process.peb().unwrap_or_default(), NATIVE)
+        };
+        if address == 0 {
+            return Err(UDbgError::NotFound);
+        }
+        Ok(Self {
+            reader: Reader { target, layout },
+            address,
+        })
+    }
+
+    #[inline]
+    pub fn is_32bit(&self) -> bool {
+        self.reader.layout.ptr_size == 4
+    }
+
+    fn field(&self, offset: usize) -> UDbgResult<usize> {
+        self.reader
+            .ptr(self.address + offset)
+            .ok_or(UDbgError::MemoryError)
+    }
+
+    pub fn being_debugged(&self) -> UDbgResult<bool> {
+        self.reader
+            .target
+            .read_value::<u8>(self.address + 2)
+            .map(|b| b != 0)
+            .ok_or(UDbgError::MemoryError)
+    }
+
+    pub fn image_base(&self) -> UDbgResult<usize> {
+        self.field(self.reader.layout.peb_image_base)
+    }
+
+    pub fn process_heap(&self) -> UDbgResult<usize> {
+        self.field(self.reader.layout.peb_process_heap)
+    }
+
+    pub fn nt_global_flag(&self) -> UDbgResult<u32> {
+        self.reader
+            .target
+            .read_value::<u32>(self.address + self.reader.layout.peb_nt_global_flag)
+            .ok_or(UDbgError::MemoryError)
+    }
+
+    /// Major, minor and build number of OS
+    pub fn os_version(&self) -> UDbgResult<(u32, u32, u16)> {
+        let a = self.address + self.reader.layout.peb_os_version;
+        let t = self.reader.target;
+        let read = |a| t.read_value::<u32>(a).ok_or(UDbgError::MemoryError);
+        Ok((
+            read(a)?,
+            read(a + 4)?,
+            t.read_value::<u16>(a + 8).ok_or(UDbgError::MemoryError)?,
+        ))
+    }
+
+    pub fn session_id(&self) -> UDbgResult<u32> {
+        self.reader
+            .target
+            .read_value::<u32>(self.address + self.reader.layout.peb_session_id)
+            .ok_or(UDbgError::MemoryError)
+    }
+
+    /// The modules in load order of `PEB.Ldr`, the unlinked modules are missing
+    pub fn ldr_modules(&self) -> UDbgResult<Vec<LdrModule>> {
+        self.walk_ldr(self.reader.layout.ldr_load_order, 0)
+    }
+
+    /// The modules in initialization order of `PEB.Ldr`, the executable and the modules not
+    /// initialized yet are missing
+    pub fn ldr_init_order_modules(&self) -> UDbgResult<Vec<LdrModule>> {
+        let layout = self.reader.layout;
+        self.walk_ldr(layout.ldr_init_order, layout.entry_init_links)
+    }
+
+    /// Walk a list of `PEB_LDR_DATA` at offset `list`, linked by the entries at offset `links`
+    fn walk_ldr(&self, list: usize, links: usize) -> UDbgResult<Vec<LdrModule>> {
+        let layout = self.reader.layout;
+        let ldr = self.field(layout.peb_ldr)?;
+        if ldr == 0 {
+            // the loader isn't initialized yet
+            return Ok(vec![]);
+        }
+        let head = ldr + list;
+        let mut result = vec![];
+        let mut link = self.reader.ptr(head).ok_or(UDbgError::MemoryError)?;
+        while link != head && link != 0 && result.len() < MAX_LDR_MODULES {
+            let r = &self.reader;
+            let entry = link.wrapping_sub(links);
+            result.push(LdrModule {
+                entry,
+                base: r.ptr(entry + layout.entry_dll_base).unwrap_or_default(),
+                entry_point: r.ptr(entry + layout.entry_entry_point).unwrap_or_default(),
+                size: r
+                    .target
+                    .read_value::<u32>(entry + layout.entry_size_of_image)
+                    .unwrap_or_default() as usize,
+                full_name: r
+                    .unicode_string(entry + layout.entry_full_name)
+                    .unwrap_or_default(),
+                base_name: r
+                    .unicode_string(entry + layout.entry_base_name)
+                    .unwrap_or_default(),
+                is_32bit: self.is_32bit(),
+            });
+            link = match r.ptr(link) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        Ok(result)
+    }
+
+    pub fn process_parameters(&self) -> UDbgResult<ProcessParameters> {
+        let layout = self.reader.layout;
+        let address = self.field(layout.peb_process_parameters)?;
+        if address == 0 {
+            return Err(UDbgError::NotFound);
+        }
+        let r = &self.reader;
+        let string = |offset| r.unicode_string(address + offset).unwrap_or_default();
+        Ok(ProcessParameters {
+            address,
+            current_directory: string(layout.params_current_directory),
+            dll_path: string(layout.params_dll_path),
+            image_path: string(layout.params_image_path),
+            command_line: string(layout.params_command_line),
+            window_title: string(layout.params_window_title),
+            environment: r
+                .ptr(address + layout.params_environment)
+                .unwrap_or_default(),
+            environment_size: r
+                .ptr(address + layout.params_environment_size)
+                .unwrap_or_default(),
+        })
+    }
+
+    /// The environment variables, the hidden ones such as `=C:` are included
+    pub fn environment(&self) -> UDbgResult<Vec<(String, String)>> {
+        let params = self.process_parameters()?;
+        if params.environment == 0 {
+            return Ok(vec![]);
+        }
+        let size = match params.environment_size {
+            0 => MAX_ENVIRONMENT,
+            size => size.min(MAX_ENVIRONMENT),
+        };
+        let data = self.reader.target.read_bytes(params.environment, size);
+        let wide = data
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>();
+        Ok(wide
+            .split(|&c| c == 0)
+            .take_while(|s| !s.is_empty())
+            .map(|s| {
+                let var = String::from_utf16_lossy(s);
+                // the name of hidden variables starts with '='
+                match var.char_indices().skip(1).find(|&(_, c)| c == '=') {
+                    Some((i, _)) => (var[..i].to_string(), var[i + 1..].to_string()),
+                    None => (var, String::new()),
+                }
+            })
+            .collect())
+    }
+}
+
+/// The modules in both the WOW64 and native loader lists of process, tagged with their bitness.
+/// The 32-bit ones come first, the main image is in both lists
+pub fn enum_ldr_modules(process: &Process) -> UDbgResult<Vec<LdrModule>> {
+    let mut result = vec![];
+    if cfg!(target_pointer_width = "64") && process.is_wow64() {
+        result.extend(Peb::wow64(process)?.ldr_modules()?);
+    }
+    result.extend(Peb::native(process)?.ldr_modules()?);
+    Ok(result)
+}
+
+/// Thread environment block of a thread in target
+pub struct Teb<'a, T: ?Sized> {
+    reader: Reader<'a, T>,
+    pub address: usize,
+}
+
+impl<'a, T: UDbgTarget + ?Sized> Teb<'a, T> {
+    /// The TEB of thread, the 32-bit one for WOW64 process
+    pub fn of(target: &'a T, thread: &dyn UDbgThread) -> UDbgResult<Self> {
+        let teb = thread.teb().ok_or(UDbgError::NotFound)?;
+        let wow64 =
+            cfg!(target_pointer_width = "64") && target.process().map_or(false, |p| p.is_wow64());
+        let (address, layout) = if wow64 {
+            (teb + WOW64_TEB_OFFSET, &X86)
+        } else {
+            (teb, NATIVE)
+        };
+        Ok(Self {
+            reader: Reader { target, layout },
+            address,
+        })
+    }
+
+    #[inline]
+    pub fn is_32bit(&self) -> bool {
+        self.reader.layout.ptr_size == 4
+    }
+
+    fn field(&self, offset: usize) -> UDbgResult<usize> {
+        self.reader
+            .ptr(self.address + offset)
+            .ok_or(UDbgError::MemoryError)
+    }
+
+    pub fn stack_limits(&self) -> UDbgResult<StackLimits> {
+        let layout = self.reader.layout;
+        Ok(StackLimits {
+            base: self.field(layout.teb_stack_base)?,
+            limit: self.field(layout.teb_stack_limit)?,
+            deallocation: self.field(layout.teb_deallocation_stack)?,
+        })
+    }
+
+    /// Process and thread id in `ClientId`
+    pub fn client_id(&self) -> UDbgResult<(pid_t, tid_t)> {
+        let layout = self.reader.layout;
+        let pid = self.field(layout.teb_client_id)?;
+        let tid = self.field(layout.teb_client_id + layout.ptr_size)?;
+        Ok((pid as _, tid as _))
+    }
+
+    /// Address of the PEB of the same layout
+    pub fn peb_address(&self) -> UDbgResult<usize> {
+        self.field(self.reader.layout.teb_peb)
+    }
+
+    pub fn last_error(&self) -> UDbgResult<u32> {
+        self.reader
+            .target
+            .read_value::<u32>(self.address + self.reader.layout.teb_last_error)
+            .ok_or(UDbgError::MemoryError)
+    }
+
+    /// Address of the array of static TLS blocks, indexed by the TLS index of modules
+    pub fn tls_pointer(&self) -> UDbgResult<usize> {
+        self.field(self.reader.layout.teb_tls_pointer)
+    }
+
+    /// Values of the `TlsAlloc` slots, the expansion slots follow if they are allocated
+    pub fn tls_slots(&self) -> UDbgResult<Vec<usize>> {
+        let layout = self.reader.layout;
+        let mut result = (0..TLS_SLOTS)
+            .map(|i| self.field(layout.teb_tls_slots + i * layout.ptr_size))
+            .collect::<UDbgResult<Vec<_>>>()?;
+        let expansion = self.field(layout.teb_tls_expansion_slots)?;
+        if expansion != 0 {
+            result.extend((0..TLS_EXPANSION_SLOTS).map(|i| {
+                self.reader
+                    .ptr(expansion + i * layout.ptr_size)
+                    .unwrap_or_default()
+            }));
+        }
+        Ok(result)
+    }
+}
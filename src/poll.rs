@@ -0,0 +1,232 @@
+//!
+//! Shared scheduler for the polling subsystems, such as memory watch/freeze, metrics, process watcher, region monitor.
+//!
+//! All the tasks are run in one worker thread, with configurable intervals and jitter,
+//! and back off automatically while any target is stopped by debugger.
+//!
+
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use parking_lot::{Condvar, Mutex};
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::Arc;
+use std::time::Instant;
+
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Mark a target stopped, i.e. its debug event is being handled, while this guard alive
+pub struct StoppedGuard(());
+
+impl StoppedGuard {
+    #[inline]
+    pub fn new() -> Self {
+        STOPPED.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+
+    /// If any target is stopped
+    #[inline]
+    pub fn any_stopped() -> bool {
+        STOPPED.load(Ordering::Relaxed) > 0
+    }
+}
+
+impl Drop for StoppedGuard {
+    fn drop(&mut self) {
+        STOPPED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PollOptions {
+    pub interval: Duration,
+    /// Max random delay added to each interval, to spread the tasks
+    pub jitter: Duration,
+    /// Multiply the interval by it while target is stopped, 1 to disable backing off
+    pub stopped_backoff: u32,
+    /// Upper bound of the backed-off interval
+    pub max_interval: Duration,
+}
+
+impl Default for PollOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(100),
+            jitter: Duration::from_millis(10),
+            stopped_backoff: 8,
+            max_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+impl PollOptions {
+    pub fn interval(interval: Duration) -> Self {
+        Self {
+            interval,
+            jitter: interval / 10,
+            ..Default::default()
+        }
+    }
+
+    fn next_delay(&self, seed: &mut u64) -> Duration {
+        let mut delay = self.interval;
+        if StoppedGuard::any_stopped() && self.stopped_backoff > 1 {
+            delay = (delay * self.stopped_backoff).min(self.max_interval.max(self.interval));
+        }
+        let jitter = self.jitter.as_micros() as u64;
+        if jitter > 0 {
+            // xorshift
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            delay += Duration::from_micros(*seed % jitter);
+        }
+        delay
+    }
+}
+
+type PollFn = Box<dyn FnMut() -> bool + Send>;
+
+struct Task {
+    name: Arc<str>,
+    opts: PollOptions,
+    func: PollFn,
+    cancelled: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct Queue {
+    next_id: usize,
+    tasks: HashMap<usize, Task>,
+    timeline: BinaryHeap<Reverse<(Instant, usize)>>,
+}
+
+/// Scheduler of polling tasks, runs them in one worker thread
+pub struct PollScheduler {
+    queue: Mutex<Queue>,
+    wakeup: Condvar,
+}
+
+/// Handle of a polling task, the task is cancelled when it's dropped
+pub struct PollHandle {
+    name: Arc<str>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl PollHandle {
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Keep the task running after the handle dropped
+    pub fn detach(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for PollHandle {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl PollScheduler {
+    /// The scheduler shared by all the subsystems
+    pub fn global() -> &'static Self {
+        static GLOBAL: spin::Once<PollScheduler> = spin::Once::new();
+
+        GLOBAL.call_once(|| {
+            std::thread::Builder::new()
+                .name("udbg-poll".into())
+                .spawn(|| Self::global().run())
+                .expect("spawn poll thread");
+            Self {
+                queue: Default::default(),
+                wakeup: Condvar::new(),
+            }
+        })
+    }
+
+    /// Add a polling task, it's removed when returns false or its handle dropped
+    pub fn add(
+        &self,
+        name: &str,
+        opts: PollOptions,
+        func: impl FnMut() -> bool + Send + 'static,
+    ) -> PollHandle {
+        let name: Arc<str> = name.into();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut queue = self.queue.lock();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue
+            .timeline
+            .push(Reverse((Instant::now() + opts.interval, id)));
+        queue.tasks.insert(
+            id,
+            Task {
+                name: name.clone(),
+                opts,
+                func: Box::new(func),
+                cancelled: cancelled.clone(),
+            },
+        );
+        self.wakeup.notify_one();
+        PollHandle { name, cancelled }
+    }
+
+    /// Names of the running tasks
+    pub fn tasks(&self) -> Vec<Arc<str>> {
+        self.queue
+            .lock()
+            .tasks
+            .values()
+            .filter(|t| !t.cancelled.load(Ordering::Relaxed))
+            .map(|t| t.name.clone())
+            .collect()
+    }
+
+    fn run(&self) {
+        let mut seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+            | 1;
+        let mut queue = self.queue.lock();
+        loop {
+            let (due, id) = match queue.timeline.peek() {
+                Some(Reverse(x)) => *x,
+                None => {
+                    self.wakeup.wait(&mut queue);
+                    continue;
+                }
+            };
+            if due > Instant::now() {
+                self.wakeup.wait_until(&mut queue, due);
+                continue;
+            }
+            queue.timeline.pop();
+
+            let mut task = match queue.tasks.remove(&id) {
+                Some(t) if !t.cancelled.load(Ordering::Relaxed) => t,
+                _ => continue,
+            };
+            // run the task without lock, others could be added meanwhile
+            let keep = parking_lot::MutexGuard::unlocked(&mut queue, || (task.func)());
+            if keep && !task.cancelled.load(Ordering::Relaxed) {
+                let next = Instant::now() + task.opts.next_delay(&mut seed);
+                queue.timeline.push(Reverse((next, id)));
+                queue.tasks.insert(id, task);
+            } else {
+                task.cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//!
+//! Project: the work on binaries persisted to disk, such as user symbols, comments, breakpoints, patches and analysis results.
+//!
+//! Everything is stored relative to the module base, and keyed by [`BinaryId`] of the module,
+//! so it's reloaded whenever the same binary is loaded in any later session, regardless of its name or load address.
+//!
+
+use crate::{bookmark::*, comment::*, prelude::*};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Bytes of the module header to compute the identity if there's no PDB signature
+const HEADER_SIZE: usize = 0x1000;
+
+/// Identity of a binary, by the PDB signature, or the hash of the header which contains
+/// the timestamp/checksum of PE, or the build-id note of ELF usually
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BinaryId(pub String);
+
+impl BinaryId {
+    pub fn of_module<T: ReadMemory + ?Sized>(target: &T, module: &dyn UDbgModule) -> Option<Self> {
+        if let Some(sig) = module.symbols_data().filter(|s| !s.pdb_sig.is_empty()) {
+            return Some(Self(format!("pdb:{}", sig.pdb_sig)));
+        }
+        let data = module.data();
+        let header = target.read_bytes(data.base, HEADER_SIZE.min(data.size));
+        if header.is_empty() {
+            return None;
+        }
+        Some(Self::of_header(&header, data.size))
+    }
+
+    /// Identity by the hash of module header and the module size
+    fn of_header(header: &[u8], size: usize) -> Self {
+        // FNV-1a, stable across builds and platforms
+        let hash = header.iter().fold(0xcbf29ce484222325u64, |h, &b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        Self(format!("hdr:{hash:016x}:{size:x}"))
+    }
+}
+
+/// Breakpoint relative to module
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectBp {
+    pub offset: usize,
+    /// Type of breakpoint, formatted as [`BpType::to_string`]
+    pub kind: String,
+    pub enabled: bool,
+}
+
+impl ProjectBp {
+    pub(crate) fn to_opt(&self, address: usize) -> Option<BpOpt> {
+        let opt = match self.kind.as_str() {
+            "soft" => BpOpt::int3(address),
+            "table" => BpOpt {
+                table: true,
+                ..BpOpt::int3(address)
+            },
+            kind => {
+                let mut chars = kind.strip_prefix("hwbp:")?.chars();
+                let rw = match chars.next()? {
+                    'e' => HwbpType::Execute,
+                    'w' => HwbpType::Write,
+                    'a' => HwbpType::Access,
+                    #[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+                    'r' => HwbpType::Read,
+                    _ => return None,
+                };
+                let len = match chars.next()? {
+                    '1' => HwbpLen::L1,
+                    '2' => HwbpLen::L2,
+                    '4' => HwbpLen::L4,
+                    '8' => HwbpLen::L8,
+                    _ => return None,
+                };
+                BpOpt::hwbp(address, rw, Some(len))
+            }
+        };
+        Some(opt.enable(self.enabled))
+    }
+}
+
+/// Bytes patched in module
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectPatch {
+    pub offset: usize,
+    pub original: Vec<u8>,
+    pub patched: Vec<u8>,
+}
+
+/// The work on a binary
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModuleProject {
+    /// Name of module when it's added to project
+    pub name: String,
+    pub size: usize,
+    pub symbols: BTreeMap<usize, String>,
+    /// User comments
+    pub comments: BTreeMap<usize, String>,
+    /// Module-relative bookmarks, the module names are updated when restored
+    pub bookmarks: Vec<Bookmark>,
+    pub breakpoints: Vec<ProjectBp>,
+    pub patches: Vec<ProjectPatch>,
+    /// Analysis results in any serializable form, keyed by the analyzer
+    pub analysis: BTreeMap<String, serde_json::Value>,
+}
+
+/// Counts of the items restored to a module by [`Project::apply`]
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ApplyResult {
+    pub symbols: usize,
+    pub comments: usize,
+    pub bookmarks: usize,
+    pub breakpoints: usize,
+    pub patches: usize,
+    /// Patches skipped because the original bytes don't match
+    pub conflicts: usize,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Project {
+    #[serde(skip)]
+    path: PathBuf,
+    pub modules: HashMap<BinaryId, ModuleProject>,
+}
+
+impl Project {
+    /// Open a project file, an empty project is created if the file doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> UDbgResult<Self> {
+        let path = path.as_ref();
+        let mut result = if path.exists() {
+            let data = std::fs::read(path)?;
+            serde_json::from_slice::<Self>(&data).map_err(std::io::Error::from)?
+        } else {
+            Self::default()
+        };
+        result.path = path.into();
+        Ok(result)
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the project file, through a temporary file to keep the old one intact if failed
+    pub fn save(&self) -> UDbgResult<()> {
+        let data = serde_json::to_vec_pretty(self).map_err(std::io::Error::from)?;
+        let temp = self.path.with_extension("tmp");
+        std::fs::write(&temp, data)?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn module(&self, id: &BinaryId) -> Option<&ModuleProject> {
+        self.modules.get(id)
+    }
+
+    /// Project of a loaded module, created if not exists
+    pub fn module_mut<T: ReadMemory + ?Sized>(
+        &mut self,
+        target: &T,
+        module: &dyn UDbgModule,
+    ) -> UDbgResult<&mut ModuleProject> {
+        let id = BinaryId::of_module(target, module).ok_or(UDbgError::InvalidAddress)?;
+        Ok(self.modules.entry(id).or_insert_with(|| ModuleProject {
+            name: module.data().name.to_string(),
+            size: module.data().size,
+            ..Default::default()
+        }))
+    }
+
+    /// Record the user symbols, comments, bookmarks and breakpoints of a loaded module into project
+    pub fn capture<T: UDbgTarget + ?Sized>(
+        &mut self,
+        target: &T,
+        module: &dyn UDbgModule,
+    ) -> UDbgResult<()> {
+        let data = module.data();
+        let range = data.base..data.base + data.size;
+        let breakpoints = target
+            .get_breakpoints()
+            .into_iter()
+            .filter(|bp| range.contains(&bp.address()))
+            .map(|bp| ProjectBp {
+                offset: bp.address() - data.base,
+                kind: bp.get_type().to_string(),
+                enabled: bp.enabled(),
+            })
+            .collect();
+        let comments = target
+            .base()
+            .comments
+            .in_range(range)
+            .into_iter()
+            .filter(|(_, c)| c.source == CommentSource::User)
+            .map(|(a, c)| (a - data.base, c.text))
+            .collect();
+
+        let project = self.module_mut(target, module)?;
+        if let Some(syms) = module.symbols_data() {
+            for (&offset, s) in syms.user_syms.read().iter() {
+                project.symbols.insert(offset, s.name.to_string());
+            }
+        }
+        project.comments = comments;
+        project.bookmarks = target.base().bookmarks.in_module(&data.name);
+        project.breakpoints = breakpoints;
+        Ok(())
+    }
+
+    /// Patch the code of a loaded module and record it into project
+    #[cfg(not(feature = "passive"))]
+    pub fn patch<T: UDbgTarget + ?Sized>(
+        &mut self,
+        target: &T,
+        address: usize,
+        data: &[u8],
+    ) -> UDbgResult<()> {
+        let module = target
+            .find_module(address)
+            .ok_or(UDbgError::InvalidAddress)?;
+        let original = target.read_bytes(address, data.len());
+        if original.len() != data.len() {
+            return Err(UDbgError::InvalidAddress);
+        }
+        target.safe_patch(address, data, true)?;
+
+        let offset = address - module.data().base;
+        let project = self.module_mut(target, module.as_ref())?;
+        project.patches.retain(|p| p.offset != offset);
+        project.patches.push(ProjectPatch {
+            offset,
+            original,
+            patched: data.into(),
+        });
+        Ok(())
+    }
+
+    /// Restore the symbols, comments, bookmarks, breakpoints and patches to a loaded module if it's in project,
+    /// the breakpoints and patches are left out with the feature `passive`
+    pub fn apply<T: UDbgTarget + ?Sized>(
+        &self,
+        target: &T,
+        module: &dyn UDbgModule,
+    ) -> UDbgResult<ApplyResult> {
+        let mut result = ApplyResult::default();
+        let project = match BinaryId::of_module(target, module).and_then(|id| self.module(&id)) {
+            Some(p) => p,
+            None => return Ok(result),
+        };
+        let base = module.data().base;
+
+        for (&offset, name) in project.symbols.iter() {
+            if module.add_symbol(offset, name).is_ok() {
+                result.symbols += 1;
+            }
+        }
+        for (&offset, text) in project.comments.iter() {
+            target.base().comments.set(base + offset, text);
+            result.comments += 1;
+        }
+        for b in project.bookmarks.iter() {
+            if let BookmarkLocation::Module { rva, .. } = b.location {
+                target.base().bookmarks.add(Bookmark {
+                    location: BookmarkLocation::Module {
+                        module: module.data().name.to_string(),
+                        rva,
+                    },
+                    ..b.clone()
+                });
+                result.bookmarks += 1;
+            }
+        }
+        #[cfg(not(feature = "passive"))]
+        for bp in project.breakpoints.iter() {
+            let opt = match bp.to_opt(base + bp.offset) {
+                Some(opt) => opt,
+                None => continue,
+            };
+            match target.add_breakpoint(opt) {
+                Ok(_) | Err(UDbgError::BpExists) => result.breakpoints += 1,
+                Err(err) => warn!("restore bp {:x}: {err:?}", base + bp.offset),
+            }
+        }
+        #[cfg(not(feature = "passive"))]
+        for p in project.patches.iter() {
+            let address = base + p.offset;
+            let current = target.read_bytes(address, p.original.len());
+            if current == p.patched {
+                result.patches += 1;
+            } else if current == p.original {
+                target.safe_patch(address, &p.patched, true)?;
+                result.patches += 1;
+            } else {
+                warn!("patch {address:x} conflicts with the current bytes");
+                result.conflicts += 1;
+            }
+        }
+        Ok(result)
+    }
+
+    /// Apply the project to all the loaded modules
+    pub fn apply_all<T: UDbgTarget + ?Sized>(&self, target: &T) -> UDbgResult<ApplyResult> {
+        let mut result = ApplyResult::default();
+        for m in target.enum_module()? {
+            let r = self.apply(target, m.as_ref())?;
+            result.symbols += r.symbols;
+            result.comments += r.comments;
+            result.bookmarks += r.bookmarks;
+            result.breakpoints += r.breakpoints;
+            result.patches += r.patches;
+            result.conflicts += r.conflicts;
+        }
+        Ok(result)
+    }
+
+    /// Apply the project to the module being loaded, should be called in the event handler
+    pub fn on_event<T: UDbgTarget + ?Sized>(&self, target: &T, event: &UEvent) {
+        if let UEvent::ModuleLoad(m) = event {
+            self.apply(target, m.as_ref())
+                .log_error_with(|err| format!("apply project to {}: {err:?}", m.data().name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_id() {
+        // the test vectors of FNV-1a 64
+        assert_eq!(BinaryId::of_header(b"", 0).0, "hdr:cbf29ce484222325:0");
+        assert_eq!(
+            BinaryId::of_header(b"a", 0x1000).0,
+            "hdr:af63dc4c8601ec8c:1000"
+        );
+        assert_eq!(
+            BinaryId::of_header(b"foobar", 0x1000).0,
+            "hdr:85944171f73967e8:1000"
+        );
+        assert_ne!(
+            BinaryId::of_header(b"MZ\x90\0", 0x1000),
+            BinaryId::of_header(b"MZ\x90\0", 0x2000)
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//!
+//! Provenance of the threads created in target, to flag the ones which may be injected.
+//!
+//! A thread is suspicious if it starts outside any loaded module, in writable code,
+//! at a library loader such as `LoadLibraryW` (the classic `CreateRemoteThread` DLL injection),
+//! or if it's created by another process. The creator is known from the clone event on Linux,
+//! on Windows it could be reported by an external source such as the ETW ThreadStart events,
+//! see [`ProvenanceTracker::report_creator`].
+//!
+
+use crate::prelude::*;
+
+use spin::Mutex;
+use std::collections::HashMap;
+
+/// Functions which load a library, a thread starting at them is loading an injected one
+const LOADER_FUNCTIONS: &[&str] = &[
+    "LoadLibraryA",
+    "LoadLibraryW",
+    "LoadLibraryExA",
+    "LoadLibraryExW",
+    "LdrLoadDll",
+    "dlopen",
+    "__libc_dlopen_mode",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum InjectionSign {
+    /// Start address isn't in any loaded module
+    OutsideModules,
+    /// Start address is in writable and executable memory
+    WritableCode,
+    /// Thread starts at a library loader, with its name
+    LibraryLoader(String),
+    /// Thread is created by another process
+    RemoteCreator,
+}
+
+/// The process and thread which created a thread
+#[derive(Debug, Clone, Serialize)]
+pub struct Creator {
+    pub pid: pid_t,
+    pub tid: Option<tid_t>,
+    /// Image path of the creator process
+    pub image: Option<String>,
+}
+
+impl Creator {
+    pub fn new(pid: pid_t, tid: Option<tid_t>) -> Self {
+        Self {
+            pid,
+            tid,
+            image: process_image(pid),
+        }
+    }
+}
+
+fn process_image(pid: pid_t) -> Option<String> {
+    cfg_if! {
+        if #[cfg(windows)] {
+            crate::os::Process::open(pid, None)?.image_path().ok()
+        } else {
+            crate::os::Process::from_pid(pid).ok()?.image_path().ok()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadOrigin {
+    pub tid: tid_t,
+    pub start: usize,
+    /// Symbol of the start address, such as `kernel32!LoadLibraryW`
+    pub symbol: Option<String>,
+    pub creator: Option<Creator>,
+    pub signs: Vec<InjectionSign>,
+}
+
+impl ThreadOrigin {
+    /// If the thread may be injected
+    #[inline]
+    pub fn is_suspicious(&self) -> bool {
+        !self.signs.is_empty()
+    }
+}
+
+/// Start address of thread, or the current pc if the platform doesn't know
+fn thread_start(thread: &dyn UDbgThread) -> UDbgResult<usize> {
+    #[cfg(windows)]
+    if thread.entry() > 0 {
+        return Ok(thread.entry());
+    }
+    thread.reg_value("_pc")
+}
+
+/// Signs of injected code at an address, `sym` is the symbol of it if already resolved
+pub fn address_signs<T: UDbgTarget + ?Sized>(
+    target: &T,
+    address: usize,
+    sym: Option<&SymbolInfo>,
+) -> Vec<InjectionSign> {
+    let mut signs = vec![];
+    if target.find_module(address).is_none() {
+        signs.push(InjectionSign::OutsideModules);
+    }
+    if target
+        .virtual_query(address)
+        .map_or(false, |p| p.is_executable() && p.is_writable())
+    {
+        signs.push(InjectionSign::WritableCode);
+    }
+    if let Some(s) = sym.filter(|s| s.offset == 0) {
+        if LOADER_FUNCTIONS.contains(&s.symbol.as_ref()) {
+            signs.push(InjectionSign::LibraryLoader(s.symbol.to_string()));
+        }
+    }
+    signs
+}
+
+/// Inspect the start address and creator of a thread
+pub fn analyze_thread<T: UDbgTarget + ?Sized>(
+    target: &T,
+    tid: tid_t,
+    creator: Option<Creator>,
+) -> UDbgResult<ThreadOrigin> {
+    let thread = target.open_thread(tid)?;
+    let start = thread_start(thread.as_ref())?;
+    let sym = target.get_symbol_(start, None);
+
+    let mut signs = address_signs(target, start, sym.as_ref());
+    if creator.as_ref().map_or(false, |c| c.pid != target.pid()) {
+        signs.push(InjectionSign::RemoteCreator);
+    }
+
+    Ok(ThreadOrigin {
+        tid,
+        start,
+        symbol: sym.map(|s| s.to_string(start)),
+        creator,
+        signs,
+    })
+}
+
+/// Track the origins of the threads created in target, by the thread-create events
+pub struct ProvenanceTracker {
+    pid: pid_t,
+    /// Creators reported before the thread-create event
+    creators: Mutex<HashMap<tid_t, Creator>>,
+    origins: Mutex<HashMap<tid_t, ThreadOrigin>>,
+}
+
+impl ProvenanceTracker {
+    pub fn new(pid: pid_t) -> Self {
+        Self {
+            pid,
+            creators: Default::default(),
+            origins: Default::default(),
+        }
+    }
+
+    /// Report the creator of a thread, e.g. from an ETW ThreadStart event,
+    /// the thread analyzed already is updated
+    pub fn report_creator(&self, tid: tid_t, creator: Creator) {
+        let mut origins = self.origins.lock();
+        match origins.get_mut(&tid) {
+            Some(o) => {
+                if creator.pid != self.pid && !o.signs.contains(&InjectionSign::RemoteCreator) {
+                    o.signs.push(InjectionSign::RemoteCreator);
+                }
+                o.creator = Some(creator);
+            }
+            None => {
+                self.creators.lock().insert(tid, creator);
+            }
+        }
+    }
+
+    /// Analyze the created thread on [`UEvent::ThreadCreate`], returns its origin
+    pub fn on_event<T: UDbgTarget + ?Sized>(
+        &self,
+        target: &T,
+        event: &UEvent,
+    ) -> Option<ThreadOrigin> {
+        let tid = match event {
+            UEvent::ThreadCreate(tid) => *tid,
+            _ => return None,
+        };
+        // on Linux the event is reported by the parent thread
+        let event_tid = target.base().event_tid.get();
+        let creator = self.creators.lock().remove(&tid).or_else(|| {
+            (event_tid != tid && event_tid != 0)
+                .then(|| Creator::new(target.pid(), Some(event_tid)))
+        });
+        let origin = analyze_thread(target, tid, creator)
+            .log_error_with(|err| format!("analyze thread {tid}: {err:?}"))?;
+        if origin.is_suspicious() {
+            warn!(
+                "thread {tid} may be injected, start at {:x}: {:?}",
+                origin.start, origin.signs
+            );
+        }
+        self.origins.lock().insert(tid, origin.clone());
+        Some(origin)
+    }
+
+    pub fn origin(&self, tid: tid_t) -> Option<ThreadOrigin> {
+        self.origins.lock().get(&tid).cloned()
+    }
+
+    /// The threads which may be injected
+    pub fn suspicious(&self) -> Vec<ThreadOrigin> {
+        self.origins
+            .lock()
+            .values()
+            .filter(|o| o.is_suspicious())
+            .cloned()
+            .collect()
+    }
+
+    pub fn remove(&self, tid: tid_t) {
+        self.origins.lock().remove(&tid);
+        self.creators.lock().remove(&tid);
+    }
+}
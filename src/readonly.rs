@@ -0,0 +1,223 @@
+//!
+//! Read-only view of target, for forensic or observe-only usage.
+//!
+//! [`ReadOnlyTarget`] implements [`ReadMemory`] but not [`WriteMemory`], and exposes no breakpoint or control operations,
+//! so a target can not be modified accidentally through it.
+//!
+
+use crate::{annotate::Annotator, os::priority_t, prelude::*};
+use std::sync::Arc;
+
+/// Target which could only be observed
+#[derive(Clone)]
+pub struct ReadOnlyTarget(Arc<dyn UDbgTarget>);
+
+impl core::fmt::Debug for ReadOnlyTarget {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("ReadOnlyTarget").field(&self.0).finish()
+    }
+}
+
+impl From<Arc<dyn UDbgTarget>> for ReadOnlyTarget {
+    #[inline]
+    fn from(target: Arc<dyn UDbgTarget>) -> Self {
+        Self(target)
+    }
+}
+
+impl ReadMemory for ReadOnlyTarget {
+    #[inline]
+    fn read_memory<'a>(&self, addr: usize, data: &'a mut [u8]) -> Option<&'a mut [u8]> {
+        self.0.read_memory(addr, data)
+    }
+
+    #[inline]
+    fn data_layout(&self) -> DataLayout {
+        self.0.data_layout()
+    }
+}
+
+impl GetProp for ReadOnlyTarget {
+    #[inline]
+    fn get_prop(&self, key: &str) -> UDbgResult<serde_value::Value> {
+        self.0.get_prop(key)
+    }
+}
+
+impl ReadOnlyTarget {
+    #[inline]
+    pub fn new(target: Arc<dyn UDbgTarget>) -> Self {
+        Self(target)
+    }
+
+    #[inline]
+    pub fn base(&self) -> &TargetBase {
+        self.0.base()
+    }
+
+    #[inline]
+    pub fn pid(&self) -> pid_t {
+        self.base().pid.get()
+    }
+
+    #[inline]
+    pub fn image_path(&self) -> UDbgResult<String> {
+        self.0.image_path()
+    }
+
+    #[inline]
+    pub fn enum_memory(&self) -> UDbgResult<Box<dyn Iterator<Item = MemoryPage> + '_>> {
+        self.0.enum_memory()
+    }
+
+    #[inline]
+    pub fn virtual_query(&self, address: usize) -> Option<MemoryPage> {
+        self.0.virtual_query(address)
+    }
+
+    #[inline]
+    pub fn collect_memory_info(&self) -> Vec<MemoryPage> {
+        self.0.collect_memory_info()
+    }
+
+    #[inline]
+    pub fn enum_module(
+        &self,
+    ) -> UDbgResult<Box<dyn Iterator<Item = Arc<dyn UDbgModule + '_>> + '_>> {
+        self.0.enum_module()
+    }
+
+    #[inline]
+    pub fn find_module(&self, address: usize) -> Option<Arc<dyn UDbgModule>> {
+        self.0.find_module(address)
+    }
+
+    #[inline]
+    pub fn get_module(&self, name: &str) -> Option<Arc<dyn UDbgModule>> {
+        self.0.get_module(name)
+    }
+
+    #[inline]
+    pub fn get_address_by_symbol(&self, symbol: &str) -> Option<usize> {
+        self.0.get_address_by_symbol(symbol)
+    }
+
+    #[inline]
+    pub fn get_symbol(&self, address: usize, max_offset: usize) -> Option<SymbolInfo> {
+        self.0.get_symbol(address, max_offset)
+    }
+
+    #[inline]
+    pub fn get_symbol_string(&self, address: usize) -> Option<String> {
+        self.0.get_symbol_string(address)
+    }
+
+    #[inline]
+    pub fn read_ptr(&self, address: usize) -> Option<usize> {
+        self.0.read_ptr(address)
+    }
+
+    pub fn enum_thread(
+        &self,
+        detail: bool,
+    ) -> UDbgResult<Box<dyn Iterator<Item = ReadOnlyThread> + '_>> {
+        Ok(Box::new(self.0.enum_thread(detail)?.map(ReadOnlyThread)))
+    }
+
+    #[inline]
+    pub fn open_thread(&self, tid: tid_t) -> UDbgResult<ReadOnlyThread> {
+        self.0.open_thread(tid).map(ReadOnlyThread)
+    }
+
+    #[inline]
+    pub fn enum_handle(&self) -> UDbgResult<Box<dyn Iterator<Item = HandleInfo> + '_>> {
+        self.0.enum_handle()
+    }
+
+    /// Wait for target to exit, it's only observing
+    #[inline]
+    pub fn wait_exit(&self, timeout: Option<u32>) -> UDbgResult<Option<u32>> {
+        self.0.wait_exit(timeout)
+    }
+
+    /// Annotator to classify the values in target
+    #[inline]
+    pub fn annotator(&self) -> Annotator<'_> {
+        Annotator::new(self.0.as_ref())
+    }
+}
+
+/// Thread of [`ReadOnlyTarget`], which could not be suspended/resumed, or its context be modified
+pub struct ReadOnlyThread(Box<dyn UDbgThread>);
+
+impl core::ops::Deref for ReadOnlyThread {
+    type Target = ThreadData;
+
+    #[inline]
+    fn deref(&self) -> &ThreadData {
+        &self.0
+    }
+}
+
+impl GetProp for ReadOnlyThread {
+    #[inline]
+    fn get_prop(&self, key: &str) -> UDbgResult<serde_value::Value> {
+        self.0.get_prop(key)
+    }
+}
+
+impl ReadOnlyThread {
+    #[inline]
+    pub fn name(&self) -> Arc<str> {
+        self.0.name()
+    }
+
+    #[inline]
+    pub fn status(&self) -> Arc<str> {
+        self.0.status()
+    }
+
+    #[inline]
+    pub fn priority(&self) -> Option<priority_t> {
+        self.0.priority()
+    }
+
+    #[inline]
+    pub fn suspend_count(&self) -> usize {
+        self.0.suspend_count()
+    }
+
+    #[inline]
+    pub fn last_error(&self) -> Option<u32> {
+        self.0.last_error()
+    }
+
+    #[inline]
+    pub fn reg_value(&self, name: &str) -> UDbgResult<usize> {
+        self.0.reg_value(name)
+    }
+
+    #[cfg(windows)]
+    #[inline]
+    pub fn get_context(&self, cx: &mut ThreadContext) -> std::io::Result<()> {
+        self.0.get_context(cx)
+    }
+
+    #[cfg(windows)]
+    #[inline]
+    pub fn get_context32(&self, cx: &mut ThreadContext32) -> std::io::Result<()> {
+        self.0.get_context32(cx)
+    }
+
+    #[cfg(windows)]
+    #[inline]
+    pub fn teb(&self) -> Option<usize> {
+        self.0.teb()
+    }
+
+    #[cfg(windows)]
+    #[inline]
+    pub fn entry(&self) -> usize {
+        self.0.entry()
+    }
+}
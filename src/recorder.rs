@@ -0,0 +1,267 @@
+//!
+//! Flight recorder: sample the stacks of all threads and some metrics of target periodically,
+//! into a bounded ring on disk, so the last seconds before a hang or crash can be inspected later.
+//!
+//! The samples are written as JSON lines into two files in the recorder directory,
+//! the current file is rotated to the old one when it's full, so at most `2 * samples_per_file`
+//! samples are kept. The sampling runs in the [`PollScheduler`] worker.
+//!
+
+use crate::{
+    annotate::Annotator,
+    poll::{PollHandle, PollOptions, PollScheduler},
+    prelude::*,
+};
+
+use core::time::Duration;
+use spin::Mutex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+const CURRENT_FILE: &str = "flight.jsonl";
+const OLD_FILE: &str = "flight.old.jsonl";
+
+#[derive(Debug, Clone)]
+pub struct RecorderOptions {
+    pub interval: Duration,
+    /// Samples per file, the ring keeps up to twice of it
+    pub samples_per_file: usize,
+    /// Stack slots to scan for the return addresses of each thread
+    pub stack_slots: usize,
+    /// Max return addresses to keep of each thread
+    pub max_frames: usize,
+}
+
+impl Default for RecorderOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(500),
+            samples_per_file: 120,
+            stack_slots: 0x200,
+            max_frames: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StackFrame {
+    pub address: usize,
+    /// Symbol of the address, such as `ntdll!NtWaitForSingleObject+14`
+    pub desc: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadSample {
+    pub tid: tid_t,
+    pub pc: usize,
+    pub sp: usize,
+    /// Return addresses found by scanning the stack, from the innermost
+    pub frames: Vec<StackFrame>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Metrics {
+    pub threads: usize,
+    pub modules: usize,
+    /// Bytes of the committed memory
+    pub committed: usize,
+    /// Bytes of the committed private memory, e.g. heaps and stacks
+    pub private: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub time: SystemTime,
+    pub pid: pid_t,
+    pub metrics: Metrics,
+    pub threads: Vec<ThreadSample>,
+}
+
+/// Take a sample of target, each thread is suspended only while its registers are read
+pub fn sample<T: UDbgTarget + ?Sized>(target: &T, opts: &RecorderOptions) -> UDbgResult<Sample> {
+    let annotator = Annotator::new(target);
+    let threads = target.enum_thread(false)?.collect::<Vec<_>>();
+    let mut metrics = Metrics {
+        threads: threads.len(),
+        modules: target.enum_module()?.count(),
+        ..Default::default()
+    };
+    for p in annotator.pages.iter().filter(|p| p.is_commit()) {
+        metrics.committed += p.size;
+        if p.is_private() {
+            metrics.private += p.size;
+        }
+    }
+
+    let threads = threads
+        .iter()
+        .filter_map(|t| {
+            let suspended = t.suspend().is_ok();
+            let regs = t
+                .reg_value("_pc")
+                .and_then(|pc| Ok((pc, t.reg_value("_sp")?)));
+            if suspended {
+                t.resume()
+                    .log_error_with(|err| format!("resume ~{}: {err:?}", t.tid));
+            }
+            let (pc, sp) = regs.log_error_with(|err| format!("regs of ~{}: {err:?}", t.tid))?;
+            let frames = annotator
+                .stack_scan(sp, opts.stack_slots)
+                .into_iter()
+                .filter(|s| s.is_return_address())
+                .take(opts.max_frames)
+                .map(|s| StackFrame {
+                    address: s.value,
+                    desc: s.annotation.map(|a| a.desc).unwrap_or_default(),
+                })
+                .collect();
+            Some(ThreadSample {
+                tid: t.tid,
+                pc,
+                sp,
+                frames,
+            })
+        })
+        .collect();
+
+    Ok(Sample {
+        time: SystemTime::now(),
+        pid: target.pid(),
+        metrics,
+        threads,
+    })
+}
+
+/// The ring files of a recorder directory
+struct Ring {
+    dir: PathBuf,
+    file: File,
+    count: usize,
+    samples_per_file: usize,
+}
+
+impl Ring {
+    fn open(dir: &Path, samples_per_file: usize) -> UDbgResult<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(CURRENT_FILE);
+        let count = File::open(&path)
+            .map(|f| BufReader::new(f).lines().count())
+            .unwrap_or(0);
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            dir: dir.into(),
+            file,
+            count,
+            samples_per_file: samples_per_file.max(1),
+        })
+    }
+
+    fn push(&mut self, sample: &Sample) -> UDbgResult<()> {
+        if self.count >= self.samples_per_file {
+            let current = self.dir.join(CURRENT_FILE);
+            fs::rename(&current, self.dir.join(OLD_FILE))?;
+            self.file = File::create(current)?;
+            self.count = 0;
+        }
+        let line = serde_json::to_string(sample).map_err(|err| err.to_string())?;
+        writeln!(self.file, "{line}")?;
+        self.file.flush()?;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// A running flight recorder, stops when dropped or the target is gone
+pub struct FlightRecorder {
+    dir: PathBuf,
+    handle: PollHandle,
+    last_error: Arc<Mutex<Option<String>>>,
+}
+
+impl FlightRecorder {
+    /// Start recording target into `dir`, the samples of the previous recording there are kept
+    pub fn start(
+        target: Arc<dyn UDbgTarget>,
+        dir: impl AsRef<Path>,
+        opts: RecorderOptions,
+    ) -> UDbgResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut ring = Ring::open(&dir, opts.samples_per_file)?;
+        let last_error = Arc::new(Mutex::new(None));
+        let error = last_error.clone();
+        let handle = PollScheduler::global().add(
+            &format!("flight-recorder-{}", target.pid()),
+            PollOptions::interval(opts.interval),
+            move || {
+                if target.base().status.get() == UDbgStatus::Detached {
+                    return false;
+                }
+                match sample(target.as_ref(), &opts).and_then(|s| ring.push(&s)) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        warn!("flight recorder: {err:?}");
+                        *error.lock() = Some(format!("{err:?}"));
+                        // the threads can't be enumerated after the target exited
+                        target.enum_thread(false).is_ok()
+                    }
+                }
+            },
+        );
+        Ok(Self {
+            dir,
+            handle,
+            last_error,
+        })
+    }
+
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// If the recorder is still sampling
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        !self.handle.is_cancelled()
+    }
+
+    /// The error of last failed sample
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().clone()
+    }
+
+    /// Read the samples recorded in `dir`, from the oldest
+    pub fn load(dir: impl AsRef<Path>) -> UDbgResult<Vec<Sample>> {
+        let dir = dir.as_ref();
+        let mut result = vec![];
+        for name in [OLD_FILE, CURRENT_FILE] {
+            let file = match File::open(dir.join(name)) {
+                Ok(f) => f,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                // the last line may be truncated if the recorder was killed
+                if let Some(s) = serde_json::from_str(&line)
+                    .log_error_with(|err| format!("flight sample: {err:?}"))
+                {
+                    result.push(s);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Samples recorded in the last `duration` before the newest one
+    pub fn load_last(dir: impl AsRef<Path>, duration: Duration) -> UDbgResult<Vec<Sample>> {
+        let mut samples = Self::load(dir)?;
+        if let Some(last) = samples.last().map(|s| s.time) {
+            samples.retain(|s| last.duration_since(s.time).map_or(true, |d| d <= duration));
+        }
+        Ok(samples)
+    }
+}
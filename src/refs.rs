@@ -0,0 +1,152 @@
+//!
+//! Reference scanning, to find what references an object: the pointer-sized values in target
+//! memory which point to an address or into a range, each classified by the memory owning it.
+//!
+//! The owner is a heap block if a [`HeapSnapshot`] is given, then the references to that block
+//! could be found in turn, to walk the chain of objects up to a global variable or a stack.
+//!
+
+use crate::{
+    heap::HeapSnapshot,
+    prelude::*,
+    range::RangeValue,
+    scan::{scan_target, PointerMatcher, ScanOptions},
+};
+
+use core::ops::Range;
+use std::sync::Arc;
+
+/// The memory containing a reference
+#[derive(Debug, Clone, Serialize)]
+pub enum RefOwner {
+    /// In the image of a module, such as a global variable
+    Module {
+        name: Arc<str>,
+        base: usize,
+        /// Symbol of the reference address, if it's a known one
+        symbol: Option<String>,
+    },
+    HeapBlock {
+        address: usize,
+        size: usize,
+    },
+    /// In a heap region whose blocks are unknown
+    Heap {
+        region: usize,
+    },
+    Stack {
+        info: Option<Arc<str>>,
+    },
+    /// Other region
+    Memory {
+        region: usize,
+        info: Option<Arc<str>>,
+    },
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Reference {
+    /// Address of the pointer
+    pub address: usize,
+    /// The pointer, in the range searched
+    pub value: usize,
+    pub owner: RefOwner,
+}
+
+/// Classify the addresses by the memory layout of target
+struct Classifier<'a, T: ?Sized> {
+    target: &'a T,
+    pages: Vec<MemoryPage>,
+    heap: Option<&'a HeapSnapshot>,
+}
+
+impl<'a, T: UDbgTarget + ?Sized> Classifier<'a, T> {
+    fn new(target: &'a T, heap: Option<&'a HeapSnapshot>) -> Self {
+        let mut pages = target.collect_memory_info();
+        pages.sort_by_key(|p| p.base);
+        Self {
+            target,
+            pages,
+            heap,
+        }
+    }
+
+    fn owner(&self, address: usize) -> RefOwner {
+        let block = self
+            .heap
+            .and_then(|h| h.blocks.range(..=address).next_back())
+            .map(|(_, b)| b)
+            .filter(|b| address < b.address + b.size);
+        if let Some(b) = block {
+            return RefOwner::HeapBlock {
+                address: b.address,
+                size: b.size,
+            };
+        }
+        if let Some(m) = self.target.find_module(address) {
+            let data = m.data();
+            return RefOwner::Module {
+                name: data.name.clone(),
+                base: data.base,
+                symbol: self
+                    .target
+                    .get_symbol_(address, None)
+                    .filter(|s| !s.symbol.is_empty())
+                    .map(|s| s.to_string(address)),
+            };
+        }
+        let page = match RangeValue::binary_search(&self.pages, address) {
+            Some(p) => p,
+            None => return RefOwner::Unknown,
+        };
+        if page.flags.contains(MemoryFlags::STACK) {
+            RefOwner::Stack {
+                info: page.info.clone(),
+            }
+        } else if page.flags.contains(MemoryFlags::HEAP) {
+            RefOwner::Heap { region: page.base }
+        } else {
+            RefOwner::Memory {
+                region: page.base,
+                info: page.info.clone(),
+            }
+        }
+    }
+}
+
+/// Scan the readable memory for the pointers into `range`, at the addresses aligned with `align`,
+/// which is the pointer size if 0. The owners are classified by the blocks of `heap` if given
+pub fn find_references<T: UDbgTarget + ?Sized>(
+    target: &T,
+    range: Range<usize>,
+    align: usize,
+    heap: Option<&HeapSnapshot>,
+) -> UDbgResult<Vec<Reference>> {
+    let layout = target.base().data_layout();
+    let align = match align {
+        0 => layout.pointer_size,
+        a => a,
+    };
+    let matcher = PointerMatcher::new(range, layout, align);
+    let found = scan_target(
+        target,
+        &matcher,
+        &ScanOptions {
+            align,
+            ..Default::default()
+        },
+    )?;
+
+    let classifier = Classifier::new(target, heap);
+    Ok(found
+        .into_iter()
+        .filter_map(|address| {
+            Some(Reference {
+                address,
+                value: target.read_pointer(address, layout)?,
+                owner: classifier.owner(address),
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,330 @@
+//!
+//! Formatting of CPU contexts and context diffs, in WinDbg-style or GDB-style layouts.
+//!
+//! [`ContextView`] collects the registers of a context as structured data, so the UIs can render
+//! them by themselves, and [`ContextView::format`] gives the ready-made text for the shells.
+//! The vector registers are read through [`UDbgRegs::get_reg`], so only the low lane is shown.
+//!
+
+use crate::prelude::*;
+use crate::register::{general_regs, regid::*, CpuReg};
+
+use core::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RegLayout {
+    /// `rax=0000000000000000 rbx=...`, 3 registers per line
+    WinDbg,
+    /// `rax            0x0                 0`, 1 register per line
+    Gdb,
+}
+
+#[derive(Clone, Serialize)]
+pub struct RegValue {
+    pub name: &'static str,
+    pub value: CpuReg,
+}
+
+/// A bit of the flags register
+#[derive(Clone, Serialize)]
+pub struct FlagBit {
+    pub name: &'static str,
+    pub bit: u32,
+    pub set: bool,
+}
+
+/// x86 EFLAGS bits, with the WinDbg mnemonics when set and when clear
+const X86_FLAGS: &[(&str, u32, &str, &str)] = &[
+    ("OF", 11, "ov", "nv"),
+    ("DF", 10, "dn", "up"),
+    ("IF", 9, "ei", "di"),
+    ("SF", 7, "ng", "pl"),
+    ("ZF", 6, "zr", "nz"),
+    ("AF", 4, "ac", "na"),
+    ("PF", 2, "pe", "po"),
+    ("CF", 0, "cy", "nc"),
+];
+
+/// ARM CPSR/PSTATE condition bits
+const ARM_FLAGS: &[(&str, u32)] = &[("N", 31), ("Z", 30), ("C", 29), ("V", 28)];
+
+fn segment_regs(arch: u32) -> &'static [(&'static str, u32)] {
+    match arch {
+        ARCH_X86 | ARCH_X64 => &[
+            ("cs", X86_REG_CS),
+            ("ss", X86_REG_SS),
+            ("ds", X86_REG_DS),
+            ("es", X86_REG_ES),
+            ("fs", X86_REG_FS),
+            ("gs", X86_REG_GS),
+        ],
+        _ => &[],
+    }
+}
+
+fn flags_reg(arch: u32) -> Option<(&'static str, u32)> {
+    match arch {
+        ARCH_X86 | ARCH_X64 => Some(("efl", X86_REG_EFLAGS)),
+        ARCH_ARM => Some(("cpsr", ARM_REG_CPSR)),
+        ARCH_ARM64 => Some(("nzcv", ARM64_REG_NZCV)),
+        _ => None,
+    }
+}
+
+const VECTOR_NAMES: [&str; 32] = [
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13", "14", "15", "16",
+    "17", "18", "19", "20", "21", "22", "23", "24", "25", "26", "27", "28", "29", "30", "31",
+];
+
+fn vector_regs(arch: u32) -> Vec<(String, u32)> {
+    let (prefix, first, count) = match arch {
+        ARCH_X86 => ("xmm", X86_REG_XMM0, 8),
+        ARCH_X64 => ("xmm", X86_REG_XMM0, 16),
+        ARCH_ARM64 => ("v", ARM64_REG_V0, 32),
+        _ => return vec![],
+    };
+    VECTOR_NAMES[..count]
+        .iter()
+        .enumerate()
+        .map(|(i, n)| (format!("{prefix}{n}"), first + i as u32))
+        .collect()
+}
+
+/// Registers of a context, grouped for display
+#[derive(Clone, Serialize)]
+pub struct ContextView {
+    pub arch: u32,
+    pub general: Vec<RegValue>,
+    pub segments: Vec<RegValue>,
+    pub flags: Option<RegValue>,
+    /// The expanded bits of [`Self::flags`]
+    pub flag_bits: Vec<FlagBit>,
+    /// Vector registers with their low lane, empty if not requested
+    pub vectors: Vec<(String, f64)>,
+}
+
+impl ContextView {
+    pub fn new(regs: &dyn UDbgRegs, arch: u32, vectors: bool) -> Self {
+        let read = |list: &[(&'static str, u32)]| {
+            list.iter()
+                .filter_map(|&(name, id)| {
+                    Some(RegValue {
+                        name,
+                        value: regs.get_reg(id)?,
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        let flags = flags_reg(arch).and_then(|(name, id)| {
+            Some(RegValue {
+                name,
+                value: regs.get_reg(id)?,
+            })
+        });
+        let flag_bits = flags
+            .as_ref()
+            .map(|f| {
+                let value = f.value.as_int();
+                let bit = |name, bit| FlagBit {
+                    name,
+                    bit,
+                    set: value & (1 << bit) != 0,
+                };
+                match arch {
+                    ARCH_X86 | ARCH_X64 => X86_FLAGS.iter().map(|f| bit(f.0, f.1)).collect(),
+                    _ => ARM_FLAGS.iter().map(|f| bit(f.0, f.1)).collect(),
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            arch,
+            general: read(general_regs(arch)),
+            segments: read(segment_regs(arch)),
+            flags,
+            flag_bits,
+            vectors: if vectors {
+                vector_regs(arch)
+                    .into_iter()
+                    .filter_map(|(name, id)| Some((name, regs.get_reg(id)?.as_flt())))
+                    .collect()
+            } else {
+                vec![]
+            },
+        }
+    }
+
+    fn hex_width(&self) -> usize {
+        match self.arch {
+            ARCH_X64 | ARCH_ARM64 => 16,
+            _ => 8,
+        }
+    }
+
+    /// Names of the set flags, e.g. `[ ZF PF ]`
+    pub fn flags_string(&self) -> String {
+        let mut names = self
+            .flag_bits
+            .iter()
+            .filter(|b| b.set)
+            .map(|b| b.name)
+            .collect::<Vec<_>>();
+        // GDB lists the flags from the lowest bit
+        names.reverse();
+        format!("[ {} ]", names.join(" "))
+    }
+
+    pub fn format(&self, layout: RegLayout) -> String {
+        match layout {
+            RegLayout::WinDbg => self.windbg(),
+            RegLayout::Gdb => self.gdb(),
+        }
+    }
+
+    fn windbg(&self) -> String {
+        let width = self.hex_width();
+        let name_width = self.general.iter().map(|r| r.name.len()).max().unwrap_or(0);
+        let mut out = String::new();
+        for line in self.general.chunks(3) {
+            let items = line
+                .iter()
+                .map(|r| format!("{:>name_width$}={:0width$x}", r.name, r.value.as_int()))
+                .collect::<Vec<_>>();
+            writeln!(out, "{}", items.join(" ")).ok();
+        }
+
+        let value = self.flags.as_ref().map(|f| f.value.as_int());
+        if let Some(value) = value {
+            match self.arch {
+                ARCH_X86 | ARCH_X64 => {
+                    let bits = X86_FLAGS
+                        .iter()
+                        .map(|&(_, bit, set, clear)| match value & (1 << bit) {
+                            0 => clear,
+                            _ => set,
+                        })
+                        .collect::<Vec<_>>();
+                    writeln!(out, "iopl={:<9x} {}", (value >> 12) & 3, bits.join(" ")).ok();
+                }
+                _ => {
+                    writeln!(out, "{}", self.flags_string()).ok();
+                }
+            }
+        }
+        if !self.segments.is_empty() || value.is_some() {
+            let mut line = self
+                .segments
+                .iter()
+                .map(|r| format!("{}={:04x}", r.name, r.value.as_int()))
+                .collect::<Vec<_>>()
+                .join("  ");
+            if let Some(f) = self.flags.as_ref() {
+                if !line.is_empty() {
+                    line.push_str("             ");
+                }
+                write!(line, "{}={:08x}", f.name, f.value.as_int()).ok();
+            }
+            writeln!(out, "{line}").ok();
+        }
+
+        for (name, value) in self.vectors.iter() {
+            writeln!(out, "{name}={value}").ok();
+        }
+        out
+    }
+
+    fn gdb(&self) -> String {
+        let mut out = String::new();
+        let mut line = |name: &str, hex: String, natural: String| {
+            writeln!(out, "{name:<15}{hex:<19}{natural}").ok();
+        };
+        for r in self.general.iter() {
+            let v = r.value.as_int();
+            line(r.name, format!("{v:#x}"), format!("{}", v as isize));
+        }
+        if let Some(f) = self.flags.as_ref() {
+            let name = match self.arch {
+                ARCH_X86 | ARCH_X64 => "eflags",
+                _ => f.name,
+            };
+            line(
+                name,
+                format!("{:#x}", f.value.as_int()),
+                self.flags_string(),
+            );
+        }
+        for r in self.segments.iter() {
+            let v = r.value.as_int();
+            line(r.name, format!("{v:#x}"), format!("{v}"));
+        }
+        for (name, value) in self.vectors.iter() {
+            line(name, format!("{:#x}", value.to_bits()), format!("{value}"));
+        }
+        out
+    }
+}
+
+/// A register changed between two contexts
+#[derive(Clone, Serialize)]
+pub struct RegChange {
+    pub name: &'static str,
+    pub before: CpuReg,
+    pub after: CpuReg,
+}
+
+/// The changed registers between two contexts, e.g. before and after a step
+#[derive(Clone, Serialize)]
+pub struct ContextDiff {
+    pub arch: u32,
+    pub changes: Vec<RegChange>,
+}
+
+impl ContextDiff {
+    /// Compare the general, segment and flags registers
+    pub fn new(before: &dyn UDbgRegs, after: &dyn UDbgRegs, arch: u32) -> Self {
+        let changes = general_regs(arch)
+            .iter()
+            .chain(segment_regs(arch))
+            .copied()
+            .chain(flags_reg(arch))
+            .filter_map(|(name, id)| {
+                let (b, a) = (before.get_reg(id)?, after.get_reg(id)?);
+                (b.as_int() != a.as_int()).then(|| RegChange {
+                    name,
+                    before: b,
+                    after: a,
+                })
+            })
+            .collect();
+        Self { arch, changes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// One change per line: `rax: 0000000000000001 -> 0000000000000002`
+    pub fn format(&self) -> String {
+        let width = match self.arch {
+            ARCH_X64 | ARCH_ARM64 => 16,
+            _ => 8,
+        };
+        let mut out = String::new();
+        for c in self.changes.iter() {
+            writeln!(
+                out,
+                "{}: {:0width$x} -> {:0width$x}",
+                c.name,
+                c.before.as_int(),
+                c.after.as_int()
+            )
+            .ok();
+        }
+        out
+    }
+}
+
+impl core::fmt::Display for ContextDiff {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.format())
+    }
+}
@@ -847,6 +847,98 @@ pub mod regid {
 
 use regid::*;
 
+/// General purpose registers (name, regid) of the specific context architecture, one of udbg::consts::ARCH_*
+pub fn general_regs(arch: u32) -> &'static [(&'static str, u32)] {
+    use crate::consts::*;
+
+    match arch {
+        ARCH_X86 => &[
+            ("eax", X86_REG_EAX),
+            ("ebx", X86_REG_EBX),
+            ("ecx", X86_REG_ECX),
+            ("edx", X86_REG_EDX),
+            ("esi", X86_REG_ESI),
+            ("edi", X86_REG_EDI),
+            ("ebp", X86_REG_EBP),
+            ("esp", X86_REG_ESP),
+            ("eip", X86_REG_EIP),
+        ],
+        ARCH_X64 => &[
+            ("rax", X86_REG_RAX),
+            ("rbx", X86_REG_RBX),
+            ("rcx", X86_REG_RCX),
+            ("rdx", X86_REG_RDX),
+            ("rsi", X86_REG_RSI),
+            ("rdi", X86_REG_RDI),
+            ("rbp", X86_REG_RBP),
+            ("rsp", X86_REG_RSP),
+            ("r8", X86_REG_R8),
+            ("r9", X86_REG_R9),
+            ("r10", X86_REG_R10),
+            ("r11", X86_REG_R11),
+            ("r12", X86_REG_R12),
+            ("r13", X86_REG_R13),
+            ("r14", X86_REG_R14),
+            ("r15", X86_REG_R15),
+            ("rip", X86_REG_RIP),
+        ],
+        ARCH_ARM => &[
+            ("r0", ARM_REG_R0),
+            ("r1", ARM_REG_R1),
+            ("r2", ARM_REG_R2),
+            ("r3", ARM_REG_R3),
+            ("r4", ARM_REG_R4),
+            ("r5", ARM_REG_R5),
+            ("r6", ARM_REG_R6),
+            ("r7", ARM_REG_R7),
+            ("r8", ARM_REG_R8),
+            ("r9", ARM_REG_R9),
+            ("r10", ARM_REG_R10),
+            ("r11", ARM_REG_R11),
+            ("r12", ARM_REG_R12),
+            ("sp", ARM_REG_SP),
+            ("lr", ARM_REG_LR),
+            ("pc", ARM_REG_PC),
+        ],
+        ARCH_ARM64 => &[
+            ("x0", ARM64_REG_X0),
+            ("x1", ARM64_REG_X1),
+            ("x2", ARM64_REG_X2),
+            ("x3", ARM64_REG_X3),
+            ("x4", ARM64_REG_X4),
+            ("x5", ARM64_REG_X5),
+            ("x6", ARM64_REG_X6),
+            ("x7", ARM64_REG_X7),
+            ("x8", ARM64_REG_X8),
+            ("x9", ARM64_REG_X9),
+            ("x10", ARM64_REG_X10),
+            ("x11", ARM64_REG_X11),
+            ("x12", ARM64_REG_X12),
+            ("x13", ARM64_REG_X13),
+            ("x14", ARM64_REG_X14),
+            ("x15", ARM64_REG_X15),
+            ("x16", ARM64_REG_X16),
+            ("x17", ARM64_REG_X17),
+            ("x18", ARM64_REG_X18),
+            ("x19", ARM64_REG_X19),
+            ("x20", ARM64_REG_X20),
+            ("x21", ARM64_REG_X21),
+            ("x22", ARM64_REG_X22),
+            ("x23", ARM64_REG_X23),
+            ("x24", ARM64_REG_X24),
+            ("x25", ARM64_REG_X25),
+            ("x26", ARM64_REG_X26),
+            ("x27", ARM64_REG_X27),
+            ("x28", ARM64_REG_X28),
+            ("fp", ARM64_REG_FP),
+            ("lr", ARM64_REG_LR),
+            ("sp", ARM64_REG_SP),
+            ("pc", COMM_REG_PC),
+        ],
+        _ => &[],
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum CpuReg {
     Int(usize),
@@ -0,0 +1,491 @@
+//!
+//! Memory scanner, scans the memory regions of target in parallel, and streams the results as they're found
+//!
+//! The things to search are [`Matcher`]s: plain bytes, [`BytePattern`] with wildcards and masks,
+//! [`RegexMatcher`] over the raw bytes, and [`PointerMatcher`] for the pointers into a range.
+//! [`ScanIter`] scans the regions lazily in current thread.
+//!
+
+use crate::prelude::*;
+
+use core::ops::Range;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crossbeam::channel;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Something to search in memory
+pub trait Matcher: Sync {
+    /// Max length of the matched bytes, chunks are overlapped by it
+    fn max_len(&self) -> usize;
+
+    /// Find all the matched offsets in data
+    fn find(&self, data: &[u8], found: &mut dyn FnMut(usize));
+}
+
+impl Matcher for [u8] {
+    fn max_len(&self) -> usize {
+        self.len()
+    }
+
+    fn find(&self, data: &[u8], found: &mut dyn FnMut(usize)) {
+        if self.is_empty() {
+            return;
+        }
+        for (i, w) in data.windows(self.len()).enumerate() {
+            if w == self {
+                found(i);
+            }
+        }
+    }
+}
+
+impl Matcher for Vec<u8> {
+    #[inline]
+    fn max_len(&self) -> usize {
+        self.as_slice().max_len()
+    }
+
+    #[inline]
+    fn find(&self, data: &[u8], found: &mut dyn FnMut(usize)) {
+        self.as_slice().find(data, found)
+    }
+}
+
+/// Bytes with mask, the bits not set in mask are wildcards
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BytePattern {
+    bytes: Vec<u8>,
+    mask: Vec<u8>,
+}
+
+impl BytePattern {
+    /// None if the lengths are different or empty
+    pub fn masked(bytes: Vec<u8>, mask: Vec<u8>) -> Option<Self> {
+        (!bytes.is_empty() && bytes.len() == mask.len()).then(|| Self {
+            bytes: bytes.iter().zip(mask.iter()).map(|(b, m)| b & m).collect(),
+            mask,
+        })
+    }
+
+    /// Parse IDA-style pattern, such as `48 8B ?? ?? E8`, a `?` is a wildcard byte or nibble (`4?`)
+    pub fn parse(s: &str) -> UDbgResult<Self> {
+        let mut bytes = vec![];
+        let mut mask = vec![];
+        for item in s.split_whitespace() {
+            let item = if item == "?" { "??" } else { item };
+            let mut chars = item.chars();
+            let (hi, lo) = match (chars.next(), chars.next(), chars.next()) {
+                (Some(hi), Some(lo), None) => (hi, lo),
+                _ => return Err(format!("invalid pattern byte: {item}").into()),
+            };
+            let mut byte = 0;
+            let mut m = 0;
+            for (c, shift) in [(hi, 4), (lo, 0)] {
+                if c != '?' {
+                    let n = c
+                        .to_digit(16)
+                        .ok_or_else(|| format!("invalid pattern byte: {item}"))?;
+                    byte |= (n as u8) << shift;
+                    m |= 0xF << shift;
+                }
+            }
+            bytes.push(byte);
+            mask.push(m);
+        }
+        Self::masked(bytes, mask).ok_or_else(|| "empty pattern".into())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    #[inline]
+    pub fn is_match(&self, data: &[u8]) -> bool {
+        data.len() >= self.len()
+            && self
+                .bytes
+                .iter()
+                .zip(self.mask.iter())
+                .zip(data)
+                .all(|((b, m), d)| d & m == *b)
+    }
+}
+
+impl FromStr for BytePattern {
+    type Err = UDbgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl Matcher for BytePattern {
+    fn max_len(&self) -> usize {
+        self.len()
+    }
+
+    fn find(&self, data: &[u8], found: &mut dyn FnMut(usize)) {
+        let len = self.len();
+        if data.len() < len {
+            return;
+        }
+        // locate the candidates by a full byte, verify the others then
+        match self.mask.iter().position(|&m| m == 0xFF) {
+            Some(anchor) => {
+                let b = self.bytes[anchor];
+                for (i, _) in data[anchor..=data.len() - len + anchor]
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &d)| d == b)
+                {
+                    if self.is_match(&data[i..]) {
+                        found(i);
+                    }
+                }
+            }
+            None => {
+                for i in 0..=data.len() - len {
+                    if self.is_match(&data[i..]) {
+                        found(i);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Regex over the raw bytes, disable the unicode mode by `(?-u)` to match arbitrary bytes
+#[derive(Debug, Clone)]
+pub struct RegexMatcher {
+    regex: regex::bytes::Regex,
+    max_len: usize,
+}
+
+impl RegexMatcher {
+    /// The matches longer than `max_len` may be missed at the boundaries of chunks
+    pub fn new(pattern: &str, max_len: usize) -> UDbgResult<Self> {
+        Ok(Self {
+            regex: regex::bytes::Regex::new(pattern).map_err(|err| err.to_string())?,
+            max_len: max_len.max(1),
+        })
+    }
+}
+
+impl Matcher for RegexMatcher {
+    fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    fn find(&self, data: &[u8], found: &mut dyn FnMut(usize)) {
+        for m in self.regex.find_iter(data) {
+            found(m.start());
+        }
+    }
+}
+
+/// Pointer-sized values in a range, in the data layout of target. Only the offsets aligned with
+/// `align` are checked, the data is assumed to start aligned, as the chunks of [`scan_memory`]
+#[derive(Debug, Clone)]
+pub struct PointerMatcher {
+    pub range: Range<usize>,
+    pub layout: DataLayout,
+    pub align: usize,
+}
+
+impl PointerMatcher {
+    pub fn new(range: Range<usize>, layout: DataLayout, align: usize) -> Self {
+        Self {
+            range,
+            layout,
+            align: align.max(1),
+        }
+    }
+
+    /// Decode the pointer at the start of data
+    pub fn pointer(&self, data: &[u8]) -> usize {
+        if self.layout.pointer_size == 4 {
+            u32::from_bytes(data, self.layout.endian) as usize
+        } else {
+            u64::from_bytes(data, self.layout.endian) as usize
+        }
+    }
+}
+
+impl Matcher for PointerMatcher {
+    fn max_len(&self) -> usize {
+        self.layout.pointer_size
+    }
+
+    fn find(&self, data: &[u8], found: &mut dyn FnMut(usize)) {
+        let size = self.layout.pointer_size;
+        if data.len() < size {
+            return;
+        }
+        for i in (0..=data.len() - size).step_by(self.align) {
+            if self.range.contains(&self.pointer(&data[i..i + size])) {
+                found(i);
+            }
+        }
+    }
+}
+
+/// Cooperative cancellation of scanning, could be shared with other threads
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    #[inline]
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Number of worker threads, 0 for the number of CPUs
+    pub threads: usize,
+    /// Large region is split into chunks with this size
+    pub chunk_size: usize,
+    /// Only report the address aligned with it
+    pub align: usize,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            threads: 0,
+            chunk_size: 0x100000,
+            align: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ScanEvent {
+    /// Address of matched data
+    Found(usize),
+    /// A region is finished partially, `done` and `total` are in bytes
+    Progress {
+        region: Range<usize>,
+        done: usize,
+        total: usize,
+    },
+}
+
+struct Chunk {
+    region: usize,
+    range: Range<usize>,
+    /// Length without the overlapped tail, which belongs to next chunk
+    own: usize,
+}
+
+/// The readable and committed regions of target
+pub fn readable_regions<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<Vec<Range<usize>>> {
+    Ok(target
+        .enum_memory()?
+        .filter(|p| p.is_commit() && p.is_readable())
+        .map(|p| p.base..p.base + p.size)
+        .collect())
+}
+
+/// Scan the regions by a worker pool, the callback is called in current thread as soon as a result is found,
+/// return false from it to abort the scanning
+pub fn scan_memory<T: ReadMemory + Sync + ?Sized, M: Matcher + ?Sized>(
+    target: &T,
+    regions: &[Range<usize>],
+    matcher: &M,
+    opts: &ScanOptions,
+    cancel: &CancelToken,
+    mut callback: impl FnMut(ScanEvent) -> bool,
+) {
+    let overlap = matcher.max_len().saturating_sub(1);
+    let chunk_size = opts.chunk_size.max(overlap + 1);
+    let align = opts.align.max(1);
+    let threads = if opts.threads > 0 {
+        opts.threads
+    } else {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    };
+
+    let (chunk_tx, chunk_rx) = channel::unbounded::<Chunk>();
+    for (i, r) in regions.iter().enumerate() {
+        let mut start = r.start;
+        while start < r.end {
+            let end = (start + chunk_size).min(r.end);
+            chunk_tx
+                .send(Chunk {
+                    region: i,
+                    range: start..(end + overlap).min(r.end),
+                    own: end - start,
+                })
+                .ok();
+            start = end;
+        }
+    }
+    drop(chunk_tx);
+
+    let done = regions
+        .iter()
+        .map(|_| AtomicUsize::new(0))
+        .collect::<Vec<_>>();
+    let (event_tx, event_rx) = channel::bounded::<ScanEvent>(0x1000);
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            let chunk_rx = chunk_rx.clone();
+            let event_tx = event_tx.clone();
+            let done = &done;
+            s.spawn(move || {
+                let mut buf = vec![0u8; chunk_size + overlap];
+                for chunk in chunk_rx.iter() {
+                    if cancel.is_cancelled() {
+                        break;
+                    }
+                    let base = chunk.range.start;
+                    let len = chunk.range.len();
+                    // the receiver is dropped when the scanning is aborted
+                    let mut closed = false;
+                    if let Some(data) = target.read_memory(base, &mut buf[..len]) {
+                        matcher.find(data, &mut |offset| {
+                            let address = base + offset;
+                            if !closed && offset < chunk.own && address % align == 0 {
+                                closed = event_tx.send(ScanEvent::Found(address)).is_err();
+                            }
+                        });
+                    }
+
+                    let region = &regions[chunk.region];
+                    let finished =
+                        done[chunk.region].fetch_add(chunk.own, Ordering::Relaxed) + chunk.own;
+                    let progress = ScanEvent::Progress {
+                        region: region.clone(),
+                        done: finished.min(region.len()),
+                        total: region.len(),
+                    };
+                    if closed || event_tx.send(progress).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(event_tx);
+
+        for event in event_rx.iter() {
+            if !callback(event) {
+                cancel.cancel();
+                break;
+            }
+        }
+        // unblock the workers which are sending
+        drop(event_rx);
+    });
+}
+
+/// Scan all the readable memory of target, collect the matched addresses
+pub fn scan_target<T: UDbgTarget + ?Sized, M: Matcher + ?Sized>(
+    target: &T,
+    matcher: &M,
+    opts: &ScanOptions,
+) -> UDbgResult<Vec<usize>> {
+    let regions = readable_regions(target)?;
+    let mut result = vec![];
+    scan_memory(
+        target,
+        &regions,
+        matcher,
+        opts,
+        &CancelToken::default(),
+        |e| {
+            if let ScanEvent::Found(a) = e {
+                result.push(a);
+            }
+            true
+        },
+    );
+    result.sort();
+    Ok(result)
+}
+
+/// Scan the regions lazily in current thread, yields the matched addresses in order
+pub struct ScanIter<'a, T: ?Sized, M> {
+    target: &'a T,
+    matcher: M,
+    regions: Vec<Range<usize>>,
+    /// Index of current region, and the address to read next in it
+    region: usize,
+    next: usize,
+    chunk_size: usize,
+    buf: Vec<u8>,
+    found: VecDeque<usize>,
+}
+
+impl<'a, T: ReadMemory + ?Sized, M: Matcher> ScanIter<'a, T, M> {
+    pub fn new(target: &'a T, regions: Vec<Range<usize>>, matcher: M, chunk_size: usize) -> Self {
+        let next = regions.first().map(|r| r.start).unwrap_or_default();
+        Self {
+            target,
+            regions,
+            region: 0,
+            next,
+            chunk_size: chunk_size.max(matcher.max_len()),
+            buf: vec![],
+            found: Default::default(),
+            matcher,
+        }
+    }
+
+    /// Scan next chunk, false if all the regions are scanned
+    fn scan_chunk(&mut self) -> bool {
+        let region = match self.regions.get(self.region) {
+            Some(r) => r.clone(),
+            None => return false,
+        };
+        if self.next >= region.end {
+            self.region += 1;
+            if let Some(r) = self.regions.get(self.region) {
+                self.next = r.start;
+            }
+            return true;
+        }
+
+        let overlap = self.matcher.max_len().saturating_sub(1);
+        let base = self.next;
+        let own = self.chunk_size.min(region.end - base);
+        let len = (own + overlap).min(region.end - base);
+        self.buf.resize(len, 0);
+        if let Some(data) = self.target.read_memory(base, &mut self.buf) {
+            let found = &mut self.found;
+            self.matcher.find(data, &mut |offset| {
+                if offset < own {
+                    found.push_back(base + offset);
+                }
+            });
+        }
+        self.next = base + own;
+        true
+    }
+}
+
+impl<T: ReadMemory + ?Sized, M: Matcher> Iterator for ScanIter<'_, T, M> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        loop {
+            if let Some(a) = self.found.pop_front() {
+                return Some(a);
+            }
+            if !self.scan_chunk() {
+                return None;
+            }
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//!
+//! Multi-target session: track the processes debugged by an engine, such as the children traced,
+//! and group them to broadcast the operations, for the families of identical processes like the
+//! browser renderers or Electron workers.
+//!
+//! A [`TargetGroup`] selects the targets by a glob pattern of image path. The breakpoints
+//! broadcast to a group are set in every member, and in the targets joining it later, pending
+//! until their symbols are loaded. Feed the events of the engine to [`MultiSession::on_event`].
+//!
+//! With [`MultiSession::set_propagate`], the breakpoints remembered by [`MultiSession::share_bp`]
+//! are keyed by the [`BinaryId`] of their module, and armed in any process loading the same binary.
+//!
+
+use crate::project::{BinaryId, ProjectBp};
+use crate::{prelude::*, symbol::UDbgModule};
+
+use glob::{MatchOptions, Pattern};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: !cfg!(windows),
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+struct Member {
+    target: Arc<dyn UDbgTarget>,
+    image: String,
+}
+
+pub struct TargetGroup {
+    pub name: String,
+    pattern: Pattern,
+    /// Symbols of the breakpoints broadcast to the group
+    bps: Vec<String>,
+    /// The breakpoints set, by pid and symbol
+    applied: HashSet<(pid_t, String)>,
+}
+
+impl TargetGroup {
+    /// Matches the full path or the file name of image
+    pub fn matches(&self, image: &str) -> bool {
+        let name = image.rsplit(['/', '\\']).next().unwrap_or(image);
+        self.pattern.matches_with(image, MATCH_OPTIONS)
+            || self.pattern.matches_with(name, MATCH_OPTIONS)
+    }
+
+    #[inline]
+    pub fn breakpoints(&self) -> &[String] {
+        &self.bps
+    }
+
+    /// Set the pending breakpoints in a member, returns the results of the symbols tried
+    fn apply(&mut self, target: &dyn UDbgTarget) -> Vec<(String, UDbgResult<BpID>)> {
+        let pid = target.base().pid.get();
+        let mut result = vec![];
+        for symbol in self.bps.iter() {
+            if self.applied.contains(&(pid, symbol.clone())) {
+                continue;
+            }
+            // pending until the module is loaded
+            let address = match target.get_address_by_symbol(symbol) {
+                Some(a) => a,
+                None => continue,
+            };
+            let id = match target.add_bp(address) {
+                Ok(bp) => Ok(bp.get_id()),
+                Err(UDbgError::BpExists) => Ok(address as BpID),
+                Err(err) => Err(err),
+            };
+            if id.is_ok() {
+                self.applied.insert((pid, symbol.clone()));
+            }
+            result.push((symbol.clone(), id));
+        }
+        result
+    }
+}
+
+/// Stack of a thread in a group member
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupStack {
+    pub pid: pid_t,
+    pub tid: tid_t,
+    pub frames: Vec<crate::unwind::CallFrame>,
+}
+
+#[derive(Default)]
+pub struct MultiSession {
+    members: Vec<Member>,
+    groups: Vec<TargetGroup>,
+    propagate: bool,
+    /// Breakpoints shared across processes, by binary and offset in module
+    shared: HashMap<BinaryId, BTreeMap<usize, ProjectBp>>,
+}
+
+impl MultiSession {
+    pub fn targets(&self) -> impl Iterator<Item = &Arc<dyn UDbgTarget>> {
+        self.members.iter().map(|m| &m.target)
+    }
+
+    pub fn get_target(&self, pid: pid_t) -> Option<Arc<dyn UDbgTarget>> {
+        self.members
+            .iter()
+            .find(|m| m.target.base().pid.get() == pid)
+            .map(|m| m.target.clone())
+    }
+
+    /// Add a target to the session, and set the breakpoints of its groups
+    pub fn add_target(&mut self, target: Arc<dyn UDbgTarget>) {
+        let pid = target.base().pid.get();
+        if self.get_target(pid).is_some() {
+            return;
+        }
+        let image = target.image_path().unwrap_or_default();
+        for g in self.groups.iter_mut().filter(|g| g.matches(&image)) {
+            g.apply(target.as_ref());
+        }
+        if self.propagate && !self.shared.is_empty() {
+            for m in target.enum_module().into_iter().flatten() {
+                self.arm_shared(target.as_ref(), m.as_ref());
+            }
+        }
+        self.members.push(Member { target, image });
+    }
+
+    pub fn remove_target(&mut self, pid: pid_t) {
+        self.members.retain(|m| m.target.base().pid.get() != pid);
+        for g in self.groups.iter_mut() {
+            g.applied.retain(|(p, _)| *p != pid);
+        }
+    }
+
+    /// Track the targets by the events, and set the pending breakpoints when modules loaded
+    pub fn on_event(&mut self, ctx: &mut dyn TraceContext, event: &UEvent) {
+        let target = ctx.target();
+        let pid = target.base().pid.get();
+        match event {
+            UEvent::InitBp | UEvent::ProcessCreate => self.add_target(target),
+            UEvent::ModuleLoad(m) => {
+                self.arm_shared(target.as_ref(), m.as_ref());
+                let image = match self
+                    .members
+                    .iter()
+                    .find(|m| m.target.base().pid.get() == pid)
+                {
+                    Some(m) => m.image.clone(),
+                    None => return self.add_target(target),
+                };
+                for g in self.groups.iter_mut().filter(|g| g.matches(&image)) {
+                    for (symbol, result) in g.apply(target.as_ref()) {
+                        if let Err(err) = result {
+                            warn!("group {} bp {symbol} in {pid}: {err:?}", g.name);
+                        }
+                    }
+                }
+            }
+            UEvent::ProcessExit(_) => self.remove_target(pid),
+            _ => {}
+        }
+    }
+
+    /// Arm the breakpoints shared to the new processes, the remembered ones are kept when disabled
+    pub fn set_propagate(&mut self, enable: bool) {
+        self.propagate = enable;
+    }
+
+    /// Remember the breakpoint at `address` of target by the binary identity of its module, to arm
+    /// it in the processes loading the same binary later
+    pub fn share_bp(&mut self, target: &dyn UDbgTarget, address: usize) -> UDbgResult<()> {
+        let bp = target
+            .get_breakpoint(address as BpID)
+            .ok_or(UDbgError::NotFound)?;
+        let module = target
+            .find_module(address)
+            .ok_or(UDbgError::InvalidAddress)?;
+        let id = BinaryId::of_module(target, module.as_ref()).ok_or(UDbgError::InvalidAddress)?;
+        let offset = address - module.data().base;
+        self.shared.entry(id).or_default().insert(
+            offset,
+            ProjectBp {
+                offset,
+                kind: bp.get_type().to_string(),
+                enabled: bp.enabled(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Remember all the breakpoints in the modules of target, returns the count
+    pub fn share_all_bps(&mut self, target: &dyn UDbgTarget) -> usize {
+        target
+            .get_breakpoints()
+            .into_iter()
+            .filter(|bp| self.share_bp(target, bp.address()).is_ok())
+            .count()
+    }
+
+    /// Forget a shared breakpoint, the ones armed already are kept
+    pub fn unshare_bp(&mut self, binary: &BinaryId, offset: usize) {
+        if let Some(bps) = self.shared.get_mut(binary) {
+            bps.remove(&offset);
+            if bps.is_empty() {
+                self.shared.remove(binary);
+            }
+        }
+    }
+
+    pub fn shared_bps(&self) -> &HashMap<BinaryId, BTreeMap<usize, ProjectBp>> {
+        &self.shared
+    }
+
+    /// Arm the shared breakpoints of the binary of module, returns the count armed
+    fn arm_shared(&self, target: &dyn UDbgTarget, module: &dyn UDbgModule) -> usize {
+        if !self.propagate || self.shared.is_empty() {
+            return 0;
+        }
+        let bps = match BinaryId::of_module(target, module).and_then(|id| self.shared.get(&id)) {
+            Some(bps) => bps,
+            None => return 0,
+        };
+        let base = module.data().base;
+        let mut count = 0;
+        for bp in bps.values() {
+            let opt = match bp.to_opt(base + bp.offset) {
+                Some(opt) => opt,
+                None => continue,
+            };
+            match target.add_bp(opt) {
+                Ok(_) => count += 1,
+                Err(UDbgError::BpExists) => {}
+                Err(err) => warn!("shared bp {:x}: {err:?}", base + bp.offset),
+            }
+        }
+        count
+    }
+
+    /// Create a group of the targets whose image matches the glob `pattern`, replaces the one of
+    /// the same name
+    pub fn add_group(&mut self, name: &str, pattern: &str) -> UDbgResult<()> {
+        let pattern = Pattern::new(pattern).map_err(|e| format!("pattern: {e:?}"))?;
+        self.remove_group(name);
+        self.groups.push(TargetGroup {
+            name: name.into(),
+            pattern,
+            bps: vec![],
+            applied: HashSet::new(),
+        });
+        Ok(())
+    }
+
+    pub fn remove_group(&mut self, name: &str) {
+        self.groups.retain(|g| g.name != name);
+    }
+
+    pub fn group(&self, name: &str) -> Option<&TargetGroup> {
+        self.groups.iter().find(|g| g.name == name)
+    }
+
+    pub fn groups(&self) -> &[TargetGroup] {
+        &self.groups
+    }
+
+    /// The targets in group currently
+    pub fn members(&self, group: &str) -> UDbgResult<Vec<Arc<dyn UDbgTarget>>> {
+        let g = self.group(group).ok_or(UDbgError::NotFound)?;
+        Ok(self
+            .members
+            .iter()
+            .filter(|m| g.matches(&m.image))
+            .map(|m| m.target.clone())
+            .collect())
+    }
+
+    /// Set a breakpoint at `symbol` in every member of group, returns the result of each member
+    /// which has the symbol loaded, the others and the later members get it when it's loaded
+    pub fn broadcast_bp(
+        &mut self,
+        group: &str,
+        symbol: &str,
+    ) -> UDbgResult<Vec<(pid_t, UDbgResult<BpID>)>> {
+        let members = self.members(group)?;
+        let g = self
+            .groups
+            .iter_mut()
+            .find(|g| g.name == group)
+            .ok_or(UDbgError::NotFound)?;
+        if !g.bps.iter().any(|s| s == symbol) {
+            g.bps.push(symbol.into());
+        }
+        let mut result = vec![];
+        for t in members {
+            let pid = t.base().pid.get();
+            result.extend(
+                g.apply(t.as_ref())
+                    .into_iter()
+                    .filter(|(s, _)| s == symbol)
+                    .map(|(_, r)| (pid, r)),
+            );
+        }
+        Ok(result)
+    }
+
+    /// Remove a broadcast breakpoint from the members of group
+    pub fn remove_broadcast_bp(&mut self, group: &str, symbol: &str) -> UDbgResult<()> {
+        let members = self.members(group)?;
+        let g = self
+            .groups
+            .iter_mut()
+            .find(|g| g.name == group)
+            .ok_or(UDbgError::NotFound)?;
+        g.bps.retain(|s| s != symbol);
+        for t in members {
+            let pid = t.base().pid.get();
+            if !g.applied.remove(&(pid, symbol.to_string())) {
+                continue;
+            }
+            if let Some(bp) = t
+                .get_address_by_symbol(symbol)
+                .and_then(|a| t.get_breakpoint(a as BpID))
+            {
+                bp.remove().log_error("remove group bp");
+            }
+        }
+        Ok(())
+    }
+
+    fn for_each_member(
+        &self,
+        group: &str,
+        f: impl Fn(&dyn UDbgTarget) -> UDbgResult<()>,
+    ) -> UDbgResult<Vec<(pid_t, UDbgResult<()>)>> {
+        Ok(self
+            .members(group)?
+            .into_iter()
+            .map(|t| (t.base().pid.get(), f(t.as_ref())))
+            .collect())
+    }
+
+    /// Break into every member of group
+    pub fn pause_all(&self, group: &str) -> UDbgResult<Vec<(pid_t, UDbgResult<()>)>> {
+        self.for_each_member(group, |t| t.breakk())
+    }
+
+    /// Suspend every member of group, e.g. to freeze the siblings while one is inspected
+    pub fn suspend_all(&self, group: &str) -> UDbgResult<Vec<(pid_t, UDbgResult<()>)>> {
+        self.for_each_member(group, |t| t.suspend())
+    }
+
+    /// Resume every member of group suspended by [`Self::suspend_all`]
+    pub fn continue_all(&self, group: &str) -> UDbgResult<Vec<(pid_t, UDbgResult<()>)>> {
+        self.for_each_member(group, |t| t.resume())
+    }
+
+    /// Collect the stacks of all threads in the members of group, each member is suspended
+    /// while its stacks are unwound
+    #[cfg(not(feature = "passive"))]
+    pub fn collect_stacks(&self, group: &str, max_depth: usize) -> UDbgResult<Vec<GroupStack>> {
+        let mut result = vec![];
+        for t in self.members(group)? {
+            let pid = t.base().pid.get();
+            let stacks = t.with_suspended(|threads| {
+                Ok(threads
+                    .iter()
+                    .filter_map(|th| {
+                        let frames = t
+                            .stack_trace(th.as_ref(), max_depth)
+                            .log_error_with(|err| format!("stack of {pid}~{}: {err:?}", th.tid))?;
+                        Some(GroupStack {
+                            pid,
+                            tid: th.tid,
+                            frames,
+                        })
+                    })
+                    .collect::<Vec<_>>())
+            });
+            match stacks {
+                Ok(stacks) => result.extend(stacks),
+                Err(err) => warn!("stacks of {pid}: {err:?}"),
+            }
+        }
+        Ok(result)
+    }
+}
@@ -36,6 +36,8 @@ pub struct HandleInfo {
     pub type_name: String,
     /// Name of this handle, maybe file path, pipe name, socket number, etc.
     pub name: String,
+    /// Granted access mask on Windows, open flags of the file on Linux, 0 if unknown
+    pub access: u32,
 }
 
 bitflags! {
@@ -50,6 +52,8 @@ bitflags! {
         // const DISASM_SYMBOL = 1 << 3;
 
         const SHOW_OUTPUT = 1 << 16;
+        /// Detect the packed modules and arm the OEP heuristic when loaded, see [`crate::unpack`]
+        const AUTO_UNPACK = 1 << 17;
     }
 }
 
@@ -61,21 +65,27 @@ impl Default for UDbgFlags {
 
 pub struct ShellData {
     pub symcache: Option<PathBuf>,
+    /// The symbol server to fetch the missing symbols into `symcache`, with feature `symproxy`
+    pub symserver: Option<String>,
     pub trace_child: Cell<bool>,
 }
 
 impl Default for ShellData {
     fn default() -> Self {
         #[cfg(windows)]
-        let symcache = {
-            let var = std::env::var("_NT_SYMBOL_PATH").ok();
-            var.and_then(|s| s.split('*').nth(1).map(PathBuf::from))
-                .filter(|p| p.is_dir())
+        let (symcache, symserver) = {
+            let var = std::env::var("_NT_SYMBOL_PATH").unwrap_or_default();
+            let mut parts = var.split('*');
+            (
+                parts.nth(1).map(PathBuf::from).filter(|p| p.is_dir()),
+                parts.next().map(String::from),
+            )
         };
         #[cfg(not(windows))]
-        let symcache = None;
+        let (symcache, symserver) = (None, None);
         Self {
             symcache,
+            symserver,
             trace_child: false.into(),
         }
     }
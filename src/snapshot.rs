@@ -0,0 +1,525 @@
+//!
+//! Snapshot and restore of a stopped process, to run the same code repeatedly as a fuzzing harness.
+//!
+//! [`ProcessSnapshot::capture`] saves the content of the writable private memory, the context of
+//! each thread and the handle table. [`ProcessSnapshot::restore`] writes the memory and contexts
+//! back. The memory allocated and the handles opened since the snapshot are not freed or closed,
+//! they are reported by [`RestoreReport`] for the harness to deal with.
+//!
+//! Both should be called while the target is stopped in the event callback. The engine writes
+//! the registers of event thread back when it continues, so call [`ProcessSnapshot::restore_regs`]
+//! with the [`TraceContext`] to restore them too.
+//!
+//! [`MemoryDiff::diff`] compares the memory of two snapshots, to find what changed between them,
+//! e.g. after an action in the target, as the first step to locate a variable.
+//!
+
+use crate::prelude::*;
+
+use core::ops::Range;
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+pub struct RegionSnapshot {
+    pub base: usize,
+    pub protect: u32,
+    pub data: Vec<u8>,
+}
+
+impl RegionSnapshot {
+    #[inline]
+    pub fn range(&self) -> Range<usize> {
+        self.base..self.base + self.data.len()
+    }
+}
+
+pub struct ThreadSnapshot {
+    pub tid: tid_t,
+    context: sys::Context,
+}
+
+impl ThreadSnapshot {
+    /// The saved registers
+    #[inline]
+    pub fn regs(&self) -> &dyn UDbgRegs {
+        sys::regs(&self.context)
+    }
+}
+
+pub struct ProcessSnapshot {
+    pub pid: pid_t,
+    pub time: SystemTime,
+    pub regions: Vec<RegionSnapshot>,
+    pub threads: Vec<ThreadSnapshot>,
+    /// Empty if the target can't enumerate its handles
+    pub handles: Vec<HandleInfo>,
+}
+
+/// The differences found when restoring a snapshot
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreReport {
+    pub bytes: usize,
+    /// Base of the regions failed to write back, freed or protected since the snapshot
+    pub failed_regions: Vec<usize>,
+    /// The threads exited since the snapshot
+    pub missing_threads: Vec<tid_t>,
+    /// The threads created since the snapshot, left untouched
+    pub new_threads: Vec<tid_t>,
+    pub new_handles: Vec<HandleInfo>,
+    pub closed_handles: Vec<usize>,
+}
+
+impl ProcessSnapshot {
+    pub fn capture<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<Self> {
+        let mut regions = vec![];
+        for page in target.enum_memory()? {
+            if !page.is_commit() || !page.is_writable() || page.is_shared() {
+                continue;
+            }
+            if page.is_windows() && page.protect & sys::PAGE_GUARD > 0 {
+                continue;
+            }
+            let data = target.read_bytes(page.base, page.size);
+            if data.len() < page.size {
+                warn!(
+                    "snapshot {:x}: read {}/{}",
+                    page.base,
+                    data.len(),
+                    page.size
+                );
+            }
+            regions.push(RegionSnapshot {
+                base: page.base,
+                protect: page.protect,
+                data,
+            });
+        }
+
+        let mut threads = vec![];
+        for t in target.enum_thread(false)? {
+            match sys::get_context(target, t.as_ref()) {
+                Ok(context) => threads.push(ThreadSnapshot {
+                    tid: t.tid,
+                    context,
+                }),
+                Err(err) => warn!("snapshot ~{}: {err:?}", t.tid),
+            }
+        }
+
+        let handles = target
+            .enum_handle()
+            .map(|iter| iter.collect())
+            .unwrap_or_default();
+
+        Ok(Self {
+            pid: target.base().pid.get(),
+            time: SystemTime::now(),
+            regions,
+            threads,
+            handles,
+        })
+    }
+
+    /// Size of the saved memory
+    pub fn size(&self) -> usize {
+        self.regions.iter().map(|r| r.data.len()).sum()
+    }
+
+    /// Write the saved memory back in batches, and the contexts of the threads still alive, see
+    /// [`TargetUtil::write_memory_chunks`]
+    #[cfg(not(feature = "passive"))]
+    pub fn restore<T: UDbgTarget + ?Sized>(&self, target: &T) -> UDbgResult<RestoreReport> {
+        if target.base().pid.get() != self.pid {
+            return Err(UDbgError::Text(format!("snapshot of pid {}", self.pid)));
+        }
+        let mut report = RestoreReport::default();
+        let chunks = self
+            .regions
+            .iter()
+            .map(|r| (r.base, r.data.as_slice()))
+            .collect::<Vec<_>>();
+        for (r, result) in self.regions.iter().zip(target.write_memory_chunks(&chunks)) {
+            match result {
+                Ok(len) if len == r.data.len() => report.bytes += len,
+                _ => report.failed_regions.push(r.base),
+            }
+        }
+
+        let mut tids = HashSet::new();
+        for t in target.enum_thread(false)? {
+            tids.insert(t.tid);
+            match self.threads.iter().find(|s| s.tid == t.tid) {
+                Some(s) => sys::set_context(t.as_ref(), &s.context)
+                    .log_error_with(|err| format!("restore ~{}: {err:?}", t.tid))
+                    .unwrap_or_default(),
+                None => report.new_threads.push(t.tid),
+            }
+        }
+        report.missing_threads = self
+            .threads
+            .iter()
+            .map(|s| s.tid)
+            .filter(|tid| !tids.contains(tid))
+            .collect();
+
+        if let Ok(handles) = target.enum_handle() {
+            let mut current = HashSet::new();
+            for h in handles {
+                current.insert(h.handle);
+                if !self.handles.iter().any(|s| s.handle == h.handle) {
+                    report.new_handles.push(h);
+                }
+            }
+            report.closed_handles = self
+                .handles
+                .iter()
+                .map(|h| h.handle)
+                .filter(|h| !current.contains(h))
+                .collect();
+        }
+        Ok(report)
+    }
+
+    /// Restore the general registers of event thread in the engine's context
+    pub fn restore_regs(&self, ctx: &mut dyn TraceContext) -> UDbgResult<()> {
+        let tid = ctx.target().base().event_tid.get();
+        let arch = ctx.arch();
+        let saved = self
+            .threads
+            .iter()
+            .find(|s| s.tid == tid)
+            .ok_or(UDbgError::NotFound)?
+            .regs();
+        let regs = ctx.register().ok_or(UDbgError::NotSupport)?;
+        for &(_, id) in crate::register::general_regs(arch) {
+            if let Some(val) = saved.get_reg(id) {
+                regs.set_reg(id, val);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bytes compared at once to skip the unchanged ones
+const DIFF_BLOCK: usize = 0x1000;
+
+/// A range changed between two snapshots
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangedRange {
+    pub address: usize,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+impl ChangedRange {
+    #[inline]
+    pub fn range(&self) -> Range<usize> {
+        self.address..self.address + self.new.len()
+    }
+}
+
+/// The changes in a region of the later snapshot
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionDiff {
+    pub base: usize,
+    pub size: usize,
+    pub changes: Vec<ChangedRange>,
+}
+
+/// Differences of memory between two snapshots
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemoryDiff {
+    /// The regions containing changes
+    pub regions: Vec<RegionDiff>,
+    /// Bytes compared, in the ranges saved by both snapshots
+    pub compared: usize,
+    /// Base of the regions saved only by the later snapshot, allocated or made writable since
+    pub new_regions: Vec<usize>,
+    /// Base of the regions saved only by the earlier snapshot
+    pub freed_regions: Vec<usize>,
+}
+
+impl MemoryDiff {
+    /// Compare the memory of snapshot `a` with the later snapshot `b`, the changes separated by at
+    /// most 4 bytes are merged
+    pub fn diff(a: &ProcessSnapshot, b: &ProcessSnapshot) -> Self {
+        Self::diff_with(a, b, 4)
+    }
+
+    /// Compare the memory of two snapshots, the changes separated by at most `merge_gap` bytes are
+    /// merged. The regions are matched by address, so a region split or merged by the protection
+    /// changes is compared too
+    pub fn diff_with(a: &ProcessSnapshot, b: &ProcessSnapshot, merge_gap: usize) -> Self {
+        let mut old = a.regions.iter().collect::<Vec<_>>();
+        old.sort_by_key(|r| r.base);
+        let mut new = b.regions.iter().collect::<Vec<_>>();
+        new.sort_by_key(|r| r.base);
+
+        let overlapped = |regions: &[&RegionSnapshot], range: &Range<usize>| {
+            let i = regions.partition_point(|r| r.range().end <= range.start);
+            regions[i..]
+                .iter()
+                .take_while(|r| r.base < range.end)
+                .copied()
+                .collect::<Vec<_>>()
+        };
+
+        let mut result = Self::default();
+        for r in new.iter() {
+            let range = r.range();
+            let overlaps = overlapped(&old, &range);
+            if overlaps.is_empty() {
+                result.new_regions.push(r.base);
+                continue;
+            }
+            let mut changes = vec![];
+            for o in overlaps {
+                let start = o.base.max(range.start);
+                let end = o.range().end.min(range.end);
+                result.compared += end - start;
+                diff_bytes(
+                    start,
+                    &o.data[start - o.base..end - o.base],
+                    &r.data[start - r.base..end - r.base],
+                    merge_gap,
+                    &mut changes,
+                );
+            }
+            if !changes.is_empty() {
+                result.regions.push(RegionDiff {
+                    base: r.base,
+                    size: r.data.len(),
+                    changes,
+                });
+            }
+        }
+        result.freed_regions = old
+            .iter()
+            .filter(|r| overlapped(&new, &r.range()).is_empty())
+            .map(|r| r.base)
+            .collect();
+        result
+    }
+
+    /// All the changed ranges, by address
+    pub fn changes(&self) -> impl Iterator<Item = &ChangedRange> {
+        self.regions.iter().flat_map(|r| r.changes.iter())
+    }
+
+    /// Count of the changed bytes, including the unchanged ones merged in the ranges
+    pub fn changed_bytes(&self) -> usize {
+        self.changes().map(|c| c.new.len()).sum()
+    }
+}
+
+fn diff_bytes(
+    address: usize,
+    old: &[u8],
+    new: &[u8],
+    merge_gap: usize,
+    changes: &mut Vec<ChangedRange>,
+) {
+    let mut push = |r: Range<usize>| {
+        changes.push(ChangedRange {
+            address: address + r.start,
+            old: old[r.clone()].to_vec(),
+            new: new[r].to_vec(),
+        })
+    };
+    let mut run: Option<Range<usize>> = None;
+    for block in (0..old.len()).step_by(DIFF_BLOCK) {
+        let end = (block + DIFF_BLOCK).min(old.len());
+        if old[block..end] == new[block..end] {
+            continue;
+        }
+        for i in (block..end).filter(|&i| old[i] != new[i]) {
+            match run.as_mut() {
+                Some(r) if i - r.end <= merge_gap => r.end = i + 1,
+                _ => {
+                    if let Some(r) = run.replace(i..i + 1) {
+                        push(r);
+                    }
+                }
+            }
+        }
+    }
+    if let Some(r) = run {
+        push(r);
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::*;
+    use crate::os::windows::Align16;
+
+    pub use winapi::um::winnt::PAGE_GUARD;
+
+    pub enum Context {
+        Native(Box<ThreadContext>),
+        Wow64(Box<ThreadContext32>),
+    }
+
+    pub fn regs(context: &Context) -> &dyn UDbgRegs {
+        match context {
+            Context::Native(c) => &**c,
+            Context::Wow64(c) => &**c,
+        }
+    }
+
+    pub fn get_context<T: UDbgTarget + ?Sized>(
+        target: &T,
+        thread: &dyn UDbgThread,
+    ) -> UDbgResult<Context> {
+        Ok(
+            if cfg!(target_pointer_width = "64") && target.base().is_ptr32() {
+                let mut cx = Align16::<ThreadContext32>::new();
+                thread.get_context32(cx.as_mut())?;
+                Context::Wow64(Box::new(*cx.as_mut()))
+            } else {
+                let mut cx = Align16::<ThreadContext>::new();
+                thread.get_context(cx.as_mut())?;
+                Context::Native(Box::new(*cx.as_mut()))
+            },
+        )
+    }
+
+    pub fn set_context(thread: &dyn UDbgThread, context: &Context) -> UDbgResult<()> {
+        match context {
+            Context::Native(c) => {
+                let mut cx = Align16::<ThreadContext>::new();
+                *cx.as_mut() = **c;
+                thread.set_context(cx.as_mut())?;
+            }
+            Context::Wow64(c) => {
+                let mut cx = Align16::<ThreadContext32>::new();
+                *cx.as_mut() = **c;
+                thread.set_context32(cx.as_mut())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use super::*;
+
+    use anyhow::Context as _;
+    use libc::user_regs_struct;
+
+    pub type Context = user_regs_struct;
+
+    pub const PAGE_GUARD: u32 = 0;
+
+    pub fn regs(context: &Context) -> &dyn UDbgRegs {
+        context
+    }
+
+    pub fn get_context<T: UDbgTarget + ?Sized>(
+        _target: &T,
+        thread: &dyn UDbgThread,
+    ) -> UDbgResult<Context> {
+        #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+        let regs =
+            nix::sys::ptrace::getregs(nix::unistd::Pid::from_raw(thread.tid)).context("getregs")?;
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+        let regs = unsafe {
+            let mut regs: user_regs_struct = core::mem::zeroed();
+            crate::os::ptrace_getregs(thread.tid, &mut regs).context("getregs")?;
+            regs
+        };
+        Ok(regs)
+    }
+
+    pub fn set_context(thread: &dyn UDbgThread, context: &Context) -> UDbgResult<()> {
+        #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+        nix::sys::ptrace::setregs(nix::unistd::Pid::from_raw(thread.tid), *context)
+            .context("setregs")?;
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+        crate::os::ptrace_setregs(thread.tid, context).context("setregs")?;
+        Ok(())
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android")))]
+mod sys {
+    use super::*;
+
+    pub const PAGE_GUARD: u32 = 0;
+
+    pub enum Context {}
+
+    pub fn regs(context: &Context) -> &dyn UDbgRegs {
+        match *context {}
+    }
+
+    pub fn get_context<T: UDbgTarget + ?Sized>(
+        _target: &T,
+        _thread: &dyn UDbgThread,
+    ) -> UDbgResult<Context> {
+        Err(UDbgError::NotSupport)
+    }
+
+    pub fn set_context(_thread: &dyn UDbgThread, context: &Context) -> UDbgResult<()> {
+        match *context {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(regions: Vec<(usize, Vec<u8>)>) -> ProcessSnapshot {
+        ProcessSnapshot {
+            pid: 0,
+            time: SystemTime::now(),
+            regions: regions
+                .into_iter()
+                .map(|(base, data)| RegionSnapshot {
+                    base,
+                    protect: 0,
+                    data,
+                })
+                .collect(),
+            threads: vec![],
+            handles: vec![],
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let old = vec![0u8; 0x3000];
+        let mut new = old.clone();
+        new[0x10] = 1;
+        new[0x13] = 2;
+        new[0x20] = 3;
+        new[0x2FFF] = 4;
+
+        let a = snapshot(vec![(0x10000, old.clone()), (0x40000, vec![0; 0x10])]);
+        let b = snapshot(vec![
+            // split by a change of protection
+            (0x10000, new[..0x1000].to_vec()),
+            (0x11000, new[0x1000..].to_vec()),
+            (0x50000, vec![0; 0x10]),
+        ]);
+        let diff = MemoryDiff::diff(&a, &b);
+        assert_eq!(diff.compared, 0x3000);
+        assert_eq!(diff.new_regions, [0x50000]);
+        assert_eq!(diff.freed_regions, [0x40000]);
+        assert_eq!(diff.regions.len(), 2);
+
+        let changes = diff.changes().map(|c| c.range()).collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [0x10010..0x10014, 0x10020..0x10021, 0x12FFF..0x13000]
+        );
+        assert_eq!(diff.regions[0].changes[0].new, [1, 0, 0, 2]);
+        assert_eq!(diff.regions[0].changes[0].old, [0; 4]);
+        assert_eq!(diff.changed_bytes(), 6);
+
+        let diff = MemoryDiff::diff_with(&a, &b, 0x10);
+        assert_eq!(diff.regions[0].changes.len(), 1);
+        assert_eq!(diff.regions[0].changes[0].range(), 0x10010..0x10021);
+    }
+}
@@ -0,0 +1,148 @@
+//!
+//! Address space with translation, for the targets whose addresses are not flat virtual addresses,
+//! such as physical memory of kernel target, emulators, dump files consisted of memory runs.
+//!
+//! [`AddressSpace`] implements [`ReadMemory`] and [`WriteMemory`], so all the read/write/scan utilities work on it.
+//! The minidump target reads the memory of process through an [`AddressSpace`] over the dump file,
+//! translated by the [`MemoryRuns`] of its memory list.
+//!
+
+use crate::{memory::*, range::RangeValue};
+use core::ops::{Deref, Range};
+
+/// Translate the address of a space to the address of its backing memory
+pub trait AddressTranslate {
+    /// Return the backing address, and the length of continuous bytes which are valid from it
+    fn translate(&self, address: usize) -> Option<(usize, usize)>;
+}
+
+impl<F: Fn(usize) -> Option<(usize, usize)>> AddressTranslate for F {
+    #[inline]
+    fn translate(&self, address: usize) -> Option<(usize, usize)> {
+        self(address)
+    }
+}
+
+/// A continuous range of address space, mapped to the offset of backing memory
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MemoryRun {
+    pub address: usize,
+    pub size: usize,
+    pub offset: usize,
+}
+
+impl RangeValue for MemoryRun {
+    #[inline]
+    fn as_range(&self) -> Range<usize> {
+        self.address..self.address + self.size
+    }
+}
+
+/// Translation by memory runs, such as the dump file or the physical memory layout
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRuns(Vec<MemoryRun>);
+
+impl MemoryRuns {
+    pub fn new(mut runs: Vec<MemoryRun>) -> Self {
+        runs.sort_by_key(|r| r.address);
+        Self(runs)
+    }
+
+    pub fn add(&mut self, run: MemoryRun) {
+        let i = self.0.partition_point(|r| r.address < run.address);
+        self.0.insert(i, run);
+    }
+
+    #[inline]
+    pub fn runs(&self) -> &[MemoryRun] {
+        &self.0
+    }
+}
+
+impl AddressTranslate for MemoryRuns {
+    fn translate(&self, address: usize) -> Option<(usize, usize)> {
+        let run = RangeValue::binary_search(&self.0, address)?;
+        let offset = address - run.address;
+        Some((run.offset + offset, run.size - offset))
+    }
+}
+
+/// Memory view which translates the address before accessing its backing memory
+pub struct AddressSpace<B, T> {
+    pub backing: B,
+    pub translator: T,
+}
+
+impl<B, T> AddressSpace<B, T> {
+    pub fn new(backing: B, translator: T) -> Self {
+        Self {
+            backing,
+            translator,
+        }
+    }
+}
+
+impl<B: Deref, T: AddressTranslate> ReadMemory for AddressSpace<B, T>
+where
+    B::Target: ReadMemory,
+{
+    fn read_memory<'a>(&self, addr: usize, data: &'a mut [u8]) -> Option<&'a mut [u8]> {
+        let mut done = 0;
+        while done < data.len() {
+            let (real, len) = match self.translator.translate(addr + done) {
+                Some(r) if r.1 > 0 => r,
+                _ => break,
+            };
+            let n = len.min(data.len() - done);
+            match self.backing.read_memory(real, &mut data[done..done + n]) {
+                Some(r) if r.len() == n => done += n,
+                Some(r) => {
+                    done += r.len();
+                    break;
+                }
+                None => break,
+            }
+        }
+        if done > 0 {
+            Some(&mut data[..done])
+        } else {
+            None
+        }
+    }
+}
+
+impl<B: Deref, T: AddressTranslate> WriteMemory for AddressSpace<B, T>
+where
+    B::Target: WriteMemory,
+{
+    fn write_memory(&self, address: usize, data: &[u8]) -> Option<usize> {
+        let mut done = 0;
+        while done < data.len() {
+            let (real, len) = match self.translator.translate(address + done) {
+                Some(r) if r.1 > 0 => r,
+                _ => break,
+            };
+            let n = len.min(data.len() - done);
+            match self.backing.write_memory(real, &data[done..done + n]) {
+                Some(w) if w == n => done += n,
+                Some(w) => {
+                    done += w;
+                    break;
+                }
+                None => break,
+            }
+        }
+        if done > 0 {
+            Some(done)
+        } else {
+            None
+        }
+    }
+
+    fn flush_cache(&self, address: usize, len: usize) -> std::io::Result<()> {
+        match self.translator.translate(address) {
+            Some((real, _)) => self.backing.flush_cache(real, len),
+            None => Ok(()),
+        }
+    }
+}
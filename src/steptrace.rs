@@ -0,0 +1,172 @@
+//!
+//! Single-step tracing of a thread with a callback for each instruction executed, the building
+//! block of the coverage and taint tools.
+//!
+//! [`StepTracer::start`] registers the callback of a thread. If it's the event thread, the tracing
+//! starts at its current instruction after the user callback returns, otherwise at the next event
+//! of the thread. The steps are handled by the engine and never reach the user callback; the other
+//! events of the traced thread, such as the breakpoints, still do, but the reply is replaced to
+//! keep stepping. The tracing ends when the callback returns [`StepAction::Stop`] or the thread
+//! exits.
+//!
+//! [`crate::itrace::TraceRecorder::trace_thread`] records an instruction trace by it.
+//!
+
+use crate::{disasm::read_code, prelude::*, register::regid};
+
+use spin::Mutex;
+use std::sync::Arc;
+
+pub type StepCallback = Box<dyn FnMut(&mut StepInsn<'_>) -> StepAction>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepAction {
+    Continue,
+    Stop,
+}
+
+/// An instruction about to execute, passed to the callback
+pub struct StepInsn<'a> {
+    pub tid: tid_t,
+    /// Index of the instruction in the trace, from 0
+    pub index: usize,
+    pub address: usize,
+    /// [`MAX_INSN_SIZE`] bytes of code from the address, the original ones under the software
+    /// breakpoints, fewer at the end of readable memory
+    pub bytes: Vec<u8>,
+    /// Architecture of the thread context, one of the `ARCH_*`
+    pub arch: u32,
+    /// Registers of the thread, modified ones are written back when continued
+    pub regs: &'a mut dyn UDbgRegs,
+    pub target: &'a dyn UDbgTarget,
+}
+
+struct Trace {
+    tid: tid_t,
+    callback: Arc<Mutex<StepCallback>>,
+    started: bool,
+    steps: usize,
+}
+
+/// The traced threads of a target
+#[derive(Default)]
+pub struct StepTracer {
+    traces: Mutex<Vec<Trace>>,
+}
+
+impl StepTracer {
+    /// Trace a thread, fails if it's being traced
+    pub fn start(&self, tid: tid_t, callback: StepCallback) -> UDbgResult<()> {
+        let mut traces = self.traces.lock();
+        if traces.iter().any(|t| t.tid == tid) {
+            return Err(format!("thread {tid} is being traced").into());
+        }
+        traces.push(Trace {
+            tid,
+            callback: Arc::new(Mutex::new(callback)),
+            started: false,
+            steps: 0,
+        });
+        Ok(())
+    }
+
+    /// Stop tracing a thread, it runs freely after its next step
+    pub fn stop(&self, tid: tid_t) -> bool {
+        let mut traces = self.traces.lock();
+        let len = traces.len();
+        traces.retain(|t| t.tid != tid);
+        traces.len() != len
+    }
+
+    pub fn is_tracing(&self, tid: tid_t) -> bool {
+        self.traces.lock().iter().any(|t| t.tid == tid)
+    }
+
+    /// Instructions delivered of a traced thread
+    pub fn steps(&self, tid: tid_t) -> Option<usize> {
+        self.traces
+            .lock()
+            .iter()
+            .find(|t| t.tid == tid)
+            .map(|t| t.steps)
+    }
+
+    /// Call back the instruction at the pc of event thread, returns the reply to continue
+    fn deliver(&self, ctx: &mut dyn TraceContext, tid: tid_t) -> Option<UserReply> {
+        let (callback, index) = {
+            let mut traces = self.traces.lock();
+            let trace = traces.iter_mut().find(|t| t.tid == tid)?;
+            trace.started = true;
+            trace.steps += 1;
+            (trace.callback.clone(), trace.steps - 1)
+        };
+        let target = ctx.target();
+        let arch = ctx.arch();
+        let action = match ctx.register() {
+            Some(regs) => {
+                let address = regs.get_reg(regid::COMM_REG_PC).map_or(0, |r| r.as_int());
+                let mut insn = StepInsn {
+                    tid,
+                    index,
+                    address,
+                    bytes: read_code(target.as_ref(), address, MAX_INSN_SIZE),
+                    arch,
+                    regs,
+                    target: target.as_ref(),
+                };
+                // the lock of traces is released, the callback could start or stop a trace
+                (callback.lock())(&mut insn)
+            }
+            None => StepAction::Stop,
+        };
+        if action == StepAction::Stop {
+            self.stop(tid);
+            return None;
+        }
+        Some(UserReply::StepIn)
+    }
+
+    /// Called by the engine before the hooks of user, handles the steps of traced threads
+    pub(crate) fn on_event(
+        &self,
+        ctx: &mut dyn TraceContext,
+        event: &UEvent,
+    ) -> Option<(UserReply, Option<UEvent>)> {
+        match *event {
+            UEvent::Step => {}
+            UEvent::ThreadExit(tid) => {
+                self.stop(tid as tid_t);
+                return None;
+            }
+            UEvent::ProcessExit(_) => {
+                self.traces.lock().clear();
+                return None;
+            }
+            _ => return None,
+        }
+        let tid = ctx.target().base().event_tid.get();
+        let started = self.traces.lock().iter().any(|t| t.tid == tid && t.started);
+        if !started {
+            return None;
+        }
+        Some((
+            self.deliver(ctx, tid).unwrap_or(UserReply::Run(false)),
+            None,
+        ))
+    }
+
+    /// Called by the engine with the reply of user, starts the pending trace of event thread, and
+    /// keeps the traced thread stepping
+    pub(crate) fn on_reply(&self, ctx: &mut dyn TraceContext, reply: UserReply) -> UserReply {
+        let tid = ctx.target().base().event_tid.get();
+        let started = match self.traces.lock().iter().find(|t| t.tid == tid) {
+            Some(t) => t.started,
+            None => return reply,
+        };
+        if started {
+            // the instruction is delivered at the step onto it
+            return UserReply::StepIn;
+        }
+        self.deliver(ctx, tid).unwrap_or(reply)
+    }
+}
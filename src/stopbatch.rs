@@ -0,0 +1,204 @@
+//!
+//! Coalescing of the simultaneous breakpoint stops of many threads into one stop, an opt-in mode
+//! for the heavily parallel targets.
+//!
+//! The threads hitting breakpoints at nearly the same time are reported to the debugger one by one,
+//! each costs a stop and continue cycle of the user. When [`TargetBase::stop_batch`] is enabled, at
+//! the stop of a breakpoint the other threads trapped by a software breakpoint and waiting to
+//! report it are found by their pc, and listed with the event thread in [`StopBatch::current`].
+//! The threads already at the trap pc of a breakpoint when it's enabled are not counted as trapped
+//! by it, until they report an event.
+//! The user handles them in one stop and replies once, the queued hits of these threads are
+//! continued by the engine without reaching the user callback.
+//!
+
+use crate::prelude::*;
+
+use spin::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Distance from the address of a software breakpoint to the pc of the thread trapped by it
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const TRAP_PC_OFFSET: usize = 1;
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+const TRAP_PC_OFFSET: usize = 0;
+
+/// A thread stopped by a breakpoint
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct BatchHit {
+    pub tid: tid_t,
+    pub id: BpID,
+    pub address: usize,
+}
+
+/// Stop batching state of a target, disabled by default
+#[derive(Default)]
+pub struct StopBatch {
+    enabled: AtomicBool,
+    /// The hits of current stop, the event thread first
+    current: Mutex<Vec<BatchHit>>,
+    /// The hits reported in a batch, whose events are not received yet
+    pending: Mutex<Vec<BatchHit>>,
+    /// The threads at the trap pc of a breakpoint when it's enabled, and the breakpoint address
+    parked: Mutex<Vec<(tid_t, usize)>>,
+    coalesced: AtomicUsize,
+}
+
+impl StopBatch {
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enable or disable the batching, the hits reported already are still continued
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The hits of current stop, the event thread first. Empty if it's not stopped by a
+    /// breakpoint or the batching is disabled
+    pub fn current(&self) -> Vec<BatchHit> {
+        self.current.lock().clone()
+    }
+
+    /// Count of the hits continued by the engine, which were reported in a batch
+    #[inline]
+    pub fn coalesced(&self) -> usize {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    /// Called by the engine before a software breakpoint at `address` is written, records the
+    /// threads at its trap pc, which can't be trapped by it yet
+    pub(crate) fn on_enable<T: UDbgTarget + ?Sized>(&self, target: &T, address: usize) {
+        if !self.is_enabled() {
+            return;
+        }
+        let threads = match target.enum_thread(false) {
+            Ok(threads) => threads,
+            Err(_) => return,
+        };
+        let pc = address + TRAP_PC_OFFSET;
+        let mut parked = self.parked.lock();
+        parked.retain(|&(_, a)| a != address);
+        parked.extend(
+            threads
+                .filter(|t| t.reg_value("_pc").ok() == Some(pc))
+                .map(|t| (t.tid, address)),
+        );
+    }
+
+    /// The threads other than `tid` trapped by a software breakpoint
+    fn trapped<T: UDbgTarget + ?Sized>(&self, target: &T, tid: tid_t) -> Vec<BatchHit> {
+        let threads = match target.enum_thread(false) {
+            Ok(threads) => threads,
+            Err(_) => return vec![],
+        };
+        let parked = self.parked.lock().clone();
+        threads
+            .filter(|t| t.tid != tid)
+            .filter_map(|t| {
+                let address = t.reg_value("_pc").ok()?.checked_sub(TRAP_PC_OFFSET)?;
+                if parked.contains(&(t.tid, address)) {
+                    return None;
+                }
+                let bp = target.get_bp_by_address(address)?;
+                let matched = bp.enabled()
+                    && matches!(bp.get_type(), BpType::Soft)
+                    && (bp.hit_tid() == 0 || bp.hit_tid() == t.tid);
+                matched.then(|| BatchHit {
+                    tid: t.tid,
+                    id: bp.get_id(),
+                    address,
+                })
+            })
+            .collect()
+    }
+
+    /// Forget the hit of `tid` reported in a batch, true if it's the one at `address`, which is
+    /// coalesced
+    fn take_pending(&self, tid: tid_t, address: usize) -> bool {
+        let mut pending = self.pending.lock();
+        let coalesced = match pending.iter().position(|h| h.tid == tid) {
+            Some(i) => pending.remove(i).address == address,
+            None => false,
+        };
+        if coalesced {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+        coalesced
+    }
+
+    /// Called by the engine before the user callback, returns the reply if it's a hit reported in
+    /// a batch before
+    pub(crate) fn on_event(&self, ctx: &mut dyn TraceContext, event: &UEvent) -> Option<UserReply> {
+        let target = ctx.target();
+        let tid = target.base().event_tid.get();
+        // it has run since, its pc is no longer the one before the breakpoint
+        self.parked.lock().retain(|&(t, _)| t != tid);
+        let bp = match event {
+            UEvent::Breakpoint(bp) => bp,
+            UEvent::ProcessExit(_) => {
+                self.current.lock().clear();
+                self.pending.lock().clear();
+                self.parked.lock().clear();
+                return None;
+            }
+            _ => {
+                // a thread reports its trap first, it wasn't trapped as supposed
+                self.current.lock().clear();
+                self.pending.lock().retain(|h| h.tid != tid);
+                return None;
+            }
+        };
+
+        let address = bp.address();
+        if self.take_pending(tid, address) {
+            return Some(UserReply::Run(false));
+        }
+
+        let mut current = self.current.lock();
+        current.clear();
+        if !self.is_enabled() {
+            return None;
+        }
+        current.push(BatchHit {
+            tid,
+            id: bp.get_id(),
+            address,
+        });
+        let others = self.trapped(target.as_ref(), tid);
+        self.pending.lock().extend(others.iter().copied());
+        current.extend(others);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending() {
+        let batch = StopBatch::default();
+        batch.pending.lock().extend([
+            BatchHit {
+                tid: 2,
+                id: 1,
+                address: 0x100,
+            },
+            BatchHit {
+                tid: 3,
+                id: 2,
+                address: 0x200,
+            },
+        ]);
+        assert!(!batch.take_pending(1, 0x100));
+        assert!(batch.take_pending(2, 0x100));
+        assert!(!batch.take_pending(2, 0x100));
+        // stopped elsewhere, the hit reported is forgotten
+        assert!(!batch.take_pending(3, 0x300));
+        assert!(!batch.take_pending(3, 0x200));
+        assert_eq!(batch.coalesced(), 1);
+        assert!(batch.pending.lock().is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+//!
+//! Supervisor mode: relaunch the debuggee when it exits or crashes, for long fuzzing or
+//! monitoring runs.
+//!
+//! [`Supervisor::run`] creates the target, drives the event loop with the user callback, and when
+//! the loop ends decides by the [`RestartPolicy`] whether to launch it again after a backoff.
+//! The breakpoints and patches persisted in a [`Project`] are re-applied to each new instance.
+//!
+
+use crate::{prelude::*, project::Project};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+/// How the debuggee is launched
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LaunchOptions {
+    pub path: String,
+    pub cwd: Option<String>,
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestartPolicy {
+    /// Max restarts, None for unlimited
+    pub max_restarts: Option<usize>,
+    /// Restart after a normal exit too, not only crashes
+    pub restart_on_exit: bool,
+    /// Wait before the first restart, doubled after each quick failure
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// A run lasted longer than this resets the backoff
+    pub reset_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: Some(10),
+            restart_on_exit: false,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ExitReason {
+    /// Exited with the code
+    Exited(u32),
+    /// A second-chance exception with the code
+    Crashed(u32),
+    /// The event loop ended without exit event, such as detached
+    Ended,
+}
+
+/// A run of the debuggee
+#[derive(Debug, Clone, Serialize)]
+pub struct RunRecord {
+    pub pid: pid_t,
+    pub start: SystemTime,
+    pub duration: Duration,
+    pub reason: ExitReason,
+}
+
+pub struct Supervisor {
+    pub launch: LaunchOptions,
+    pub policy: RestartPolicy,
+    /// Re-applied to each instance, when the initial modules are loaded and then each module load
+    pub project: Option<Project>,
+    runs: Vec<RunRecord>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Supervisor {
+    pub fn new(launch: LaunchOptions, policy: RestartPolicy) -> Self {
+        Self {
+            launch,
+            policy,
+            project: None,
+            runs: vec![],
+            stopped: Default::default(),
+        }
+    }
+
+    /// The flag to stop restarting, the current run is not interrupted
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stopped.clone()
+    }
+
+    pub fn runs(&self) -> &[RunRecord] {
+        &self.runs
+    }
+
+    /// Launch and debug the target until the policy stops restarting it
+    pub fn run<E: UDbgEngine + ?Sized>(
+        &mut self,
+        engine: &mut E,
+        callback: &mut UDbgCallback<'_>,
+    ) -> UDbgResult<()> {
+        let mut backoff = self.policy.backoff;
+        let mut restarts = 0;
+        loop {
+            let record = self.run_once(engine, callback)?;
+            info!(
+                "run {} of pid {} ended after {:?}: {:?}",
+                self.runs.len() + 1,
+                record.pid,
+                record.duration,
+                record.reason
+            );
+            let restart = match record.reason {
+                ExitReason::Crashed(_) => true,
+                ExitReason::Exited(_) | ExitReason::Ended => self.policy.restart_on_exit,
+            };
+            if record.duration >= self.policy.reset_after {
+                backoff = self.policy.backoff;
+            }
+            self.runs.push(record);
+
+            if !restart || self.stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            if self
+                .policy
+                .max_restarts
+                .map_or(false, |max| restarts >= max)
+            {
+                warn!("max restarts {restarts} reached");
+                break;
+            }
+            restarts += 1;
+            info!("restart #{restarts} in {backoff:?}");
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(self.policy.max_backoff);
+        }
+        Ok(())
+    }
+
+    fn run_once<E: UDbgEngine + ?Sized>(
+        &mut self,
+        engine: &mut E,
+        callback: &mut UDbgCallback<'_>,
+    ) -> UDbgResult<RunRecord> {
+        let args = self
+            .launch
+            .args
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        let target = engine.create(&self.launch.path, self.launch.cwd.as_deref(), &args)?;
+        let pid = target.base().pid.get();
+        let start = SystemTime::now();
+        let started = Instant::now();
+
+        let project = self.project.as_ref();
+        let mut reason = ExitReason::Ended;
+        let mut initialized = false;
+        engine.event_loop(&mut |ctx, event| {
+            match &event {
+                UEvent::InitBp => {
+                    if let Some(p) = project {
+                        p.apply_all(ctx.target().as_ref())
+                            .log_error("apply project");
+                    }
+                    initialized = true;
+                }
+                // the initial modules are applied at the initial breakpoint
+                UEvent::ModuleLoad(_) if initialized => {
+                    if let Some(p) = project {
+                        p.on_event(ctx.target().as_ref(), &event);
+                    }
+                }
+                &UEvent::Exception { first: false, code } => reason = ExitReason::Crashed(code),
+                &UEvent::ProcessExit(code) if reason == ExitReason::Ended => {
+                    reason = ExitReason::Exited(code)
+                }
+                _ => {}
+            }
+            callback(ctx, event)
+        })?;
+
+        Ok(RunRecord {
+            pid,
+            start,
+            duration: started.elapsed(),
+            reason,
+        })
+    }
+}
@@ -102,6 +102,21 @@ pub trait SymbolFile {
     fn path(&self) -> &str;
     fn global(&self) -> anyhow::Result<Arc<SymbolMap>>;
 
+    /// Source line which starts at the offset exactly
+    fn find_line(&self, offset: u32) -> Option<SourceLine> {
+        None
+    }
+
+    /// Source line which the code at offset belongs to
+    fn line_at(&self, offset: u32) -> Option<SourceLine> {
+        None
+    }
+
+    /// The functions inlined at offset, from the outermost
+    fn inline_frames(&self, offset: u32) -> Vec<InlineFrame> {
+        vec![]
+    }
+
     fn find_type(&self, name: &str) -> Vec<TypeInfo> {
         vec![]
     }
@@ -132,6 +147,21 @@ pub trait SymbolFile {
     }
 }
 
+/// Source file and line number of code
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceLine {
+    pub file: Arc<str>,
+    pub line: u32,
+}
+
+/// A function inlined into the code
+#[derive(Debug, Clone, Serialize)]
+pub struct InlineFrame {
+    pub name: Arc<str>,
+    /// Where it's inlined in the caller
+    pub call_site: Option<SourceLine>,
+}
+
 /// symbol information
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Symbol {
@@ -249,55 +279,91 @@ impl SymbolsData {
     }
 
     pub fn find_symbol(&self, offset: usize, max_offset: usize) -> Option<Symbol> {
-        self.user_syms
-            .read()
-            .find_symbol(offset, max_offset)
-            .or_else(|| {
-                self.pdb
-                    .read()
-                    .as_ref()
-                    .and_then(|p| p.global().ok())
-                    .and_then(|s| s.find_symbol(offset, max_offset))
-            })
-            .or_else(|| self.exports.find_symbol(offset, max_offset))
+        crate::metrics::time_symbols(|| {
+            self.user_syms
+                .read()
+                .find_symbol(offset, max_offset)
+                .or_else(|| {
+                    self.pdb
+                        .read()
+                        .as_ref()
+                        .and_then(|p| p.global().ok())
+                        .and_then(|s| s.find_symbol(offset, max_offset))
+                })
+                .or_else(|| self.exports.find_symbol(offset, max_offset))
+        })
     }
 
     pub fn get_symbol(&self, name: &str) -> Option<Symbol> {
-        self.user_syms
-            .read()
-            .get_symbol(name)
-            .or_else(|| {
-                self.pdb
-                    .read()
-                    .as_ref()
-                    .and_then(|p| p.global().ok())
-                    .and_then(|s| s.get_symbol(name))
-            })
-            .or_else(|| self.exports.get_symbol(name))
+        crate::metrics::time_symbols(|| {
+            self.user_syms
+                .read()
+                .get_symbol(name)
+                .or_else(|| {
+                    self.pdb
+                        .read()
+                        .as_ref()
+                        .and_then(|p| p.global().ok())
+                        .and_then(|s| s.get_symbol(name))
+                })
+                .or_else(|| self.exports.get_symbol(name))
+        })
     }
 
-    pub fn enum_symbol<'a>(&'a self, pat: Option<&str>) -> UDbgResult<Vec<Symbol>> {
+    /// Enumerate symbols lazily, the symbols of PDB and exports are not collected,
+    /// only the user symbols are snapshotted
+    pub fn enum_symbol<'a>(
+        &'a self,
+        pat: Option<&str>,
+    ) -> UDbgResult<impl Iterator<Item = Symbol> + 'a> {
         let global = self.pdb.read().as_ref().and_then(|p| p.global().ok());
-        let user_syms = self.user_syms.read();
-        let iter = user_syms
-            .iter()
-            .chain(global.iter().map(|s| s.iter()).flatten())
-            .chain(self.exports.iter());
+        let user_syms = self
+            .user_syms
+            .read()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
         let pattern =
             glob::Pattern::new(pat.unwrap_or("*")).map_err(|e| format!("pattern: {:?}", e))?;
         let options = glob::MatchOptions {
             case_sensitive: false,
             ..Default::default()
         };
-        Ok(iter
-            .filter_map(|(_, s)| {
-                if pattern.matches_with(s.name.as_ref(), options) {
-                    Some(s.clone())
-                } else {
-                    None
-                }
-            })
-            .collect())
+        Ok(user_syms
+            .into_iter()
+            .chain(global.into_iter().flat_map(SharedSymbolIter::new))
+            .chain(self.exports.values().cloned())
+            .filter(move |s| pattern.matches_with(s.name.as_ref(), options)))
+    }
+}
+
+/// Iterate a shared [`SymbolMap`] without collecting it
+pub struct SharedSymbolIter {
+    map: Arc<SymbolMap>,
+    next: core::ops::Bound<usize>,
+}
+
+impl SharedSymbolIter {
+    pub fn new(map: Arc<SymbolMap>) -> Self {
+        Self {
+            map,
+            next: core::ops::Bound::Unbounded,
+        }
+    }
+}
+
+impl Iterator for SharedSymbolIter {
+    type Item = Symbol;
+
+    fn next(&mut self) -> Option<Symbol> {
+        use core::ops::Bound::*;
+
+        let (&k, s) = match self.next {
+            Unbounded => self.map.iter().next(),
+            bound => self.map.range((bound, Unbounded)).next(),
+        }?;
+        self.next = Excluded(k);
+        Some(s.clone())
     }
 }
 
@@ -385,7 +451,7 @@ pub trait UDbgModule: GetProp {
                 #[cfg(windows)]
                 Some(path) => Arc::new(crate::pdbfile::PDBData::load(path, None)?),
                 #[cfg(not(windows))]
-                Some(_) => return Err(UDbgError::NotSupport),
+                Some(path) => Arc::new(crate::dwarf::DwarfData::load(path)?),
                 None => return Err(UDbgError::NotFound),
             });
             Ok(())
@@ -397,7 +463,7 @@ pub trait UDbgModule: GetProp {
     /// enumerate symbols by optional wildcard
     fn enum_symbol(&self, pat: Option<&str>) -> UDbgResult<Box<dyn Iterator<Item = Symbol> + '_>> {
         if let Some(syms) = self.symbols_data() {
-            Ok(Box::new(syms.enum_symbol(pat)?.into_iter()))
+            Ok(Box::new(syms.enum_symbol(pat)?))
         } else {
             Err(UDbgError::NotSupport)
         }
@@ -405,13 +471,12 @@ pub trait UDbgModule: GetProp {
 
     /// get all exported symbols
     fn get_exports(&self) -> Option<Vec<Symbol>> {
-        Some(
-            self.symbols_data()?
-                .exports
-                .iter()
-                .map(|i| i.1.clone())
-                .collect(),
-        )
+        Some(self.enum_exports()?.collect())
+    }
+
+    /// enumerate exported symbols lazily
+    fn enum_exports(&self) -> Option<Box<dyn Iterator<Item = Symbol> + '_>> {
+        Some(Box::new(self.symbols_data()?.exports.values().cloned()))
     }
 }
 
@@ -616,16 +681,11 @@ impl<T: UDbgModule> SymbolManager<T> {
         self.base.try_read()?.get_module(name)
     }
 
+    /// Enumerate the modules in the order of base, by a snapshot of the list taken under one read
+    /// lock, so no lock is held by the iterator while a module is loading
     pub fn enum_module<'a>(&'a self) -> Box<dyn Iterator<Item = Arc<dyn UDbgModule + 'a>> + 'a> {
-        match self.base.try_read() {
-            Some(sm) => Box::new(
-                sm.list
-                    .clone()
-                    .into_iter()
-                    .map(|m| m as Arc<dyn UDbgModule + 'a>),
-            ),
-            None => Box::new(vec![].into_iter()),
-        }
+        let list = self.base.read_recursive().list.clone();
+        Box::new(list.into_iter().map(|m| m as Arc<dyn UDbgModule + 'a>))
     }
 }
 
@@ -0,0 +1,180 @@
+//!
+//! Batch symbolization of raw addresses, e.g. the stack frames of crash reports or ETW traces.
+//!
+//! A [`Symbolizer`] keeps the modules loaded as [`ImageModule`] across requests, each request gives
+//! the module list with the bases of the original process, see [`Symbolizer::session`], and
+//! the addresses are resolved to `module!symbol+offset` with the source line if the PDB has line info.
+//!
+
+use crate::{offline::ImageModule, prelude::*};
+
+use core::ops::Range;
+use spin::Mutex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A module of the original process
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModuleEntry {
+    /// Path or file name of the module, the file name is searched in [`Symbolizer::search_paths`]
+    pub path: String,
+    pub base: usize,
+    /// Size of the module, the size of image file is used if None
+    #[serde(default)]
+    pub size: Option<usize>,
+}
+
+/// A symbolized address
+#[derive(Debug, Clone, Serialize)]
+pub struct Frame {
+    pub address: usize,
+    pub module: Option<Arc<str>>,
+    pub symbol: Option<Arc<str>>,
+    /// Offset to the symbol, or to the module if no symbol
+    pub offset: usize,
+    pub line: Option<SourceLine>,
+}
+
+impl core::fmt::Display for Frame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match (&self.module, &self.symbol) {
+            (Some(m), Some(s)) if self.offset == 0 => write!(f, "{m}!{s}")?,
+            (Some(m), Some(s)) => write!(f, "{m}!{s}+{:x}", self.offset)?,
+            (Some(m), None) => write!(f, "{m}+{:x}", self.offset)?,
+            _ => write!(f, "{:x}", self.address)?,
+        }
+        if let Some(line) = self.line.as_ref() {
+            write!(f, " [{}:{}]", line.file, line.line)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Symbolizer {
+    /// Directories to find the modules given by file name
+    pub search_paths: Vec<PathBuf>,
+    /// Max offset from a symbol without length
+    pub max_offset: usize,
+    /// Loaded modules by path, None if failed to load
+    cache: Mutex<HashMap<PathBuf, Option<Arc<ImageModule>>>>,
+}
+
+impl Symbolizer {
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
+        Self {
+            search_paths,
+            max_offset: 0x10000,
+            cache: Default::default(),
+        }
+    }
+
+    fn find_file(&self, path: &str) -> Option<PathBuf> {
+        let path = Path::new(path);
+        if path.is_file() {
+            return Some(path.into());
+        }
+        let name = path.file_name()?;
+        self.search_paths
+            .iter()
+            .map(|dir| dir.join(name))
+            .find(|p| p.is_file())
+    }
+
+    /// Load the module of path, or get it from cache
+    pub fn module(&self, path: &str) -> Option<Arc<ImageModule>> {
+        let path = self.find_file(path)?;
+        if let Some(m) = self.cache.lock().get(&path) {
+            return m.clone();
+        }
+        let module = ImageModule::load(&path, None)
+            .log_error_with(|err| format!("load {}: {err:?}", path.display()))
+            .map(Arc::new);
+        self.cache.lock().insert(path, module.clone());
+        module
+    }
+
+    /// Drop the cached modules, e.g. after the files or symbol files are updated
+    pub fn clear_cache(&self) {
+        self.cache.lock().clear();
+    }
+
+    /// Begin a request with the module list of the original process
+    pub fn session(&self, modules: &[ModuleEntry]) -> SymbolizeSession<'_> {
+        let mut list = modules
+            .iter()
+            .map(|entry| {
+                let module = self.module(&entry.path);
+                let size = entry
+                    .size
+                    .or_else(|| module.as_ref().map(|m| m.data.size))
+                    .unwrap_or_default();
+                let name = module.as_ref().map_or_else(
+                    || {
+                        Path::new(&entry.path)
+                            .file_name()
+                            .map_or(entry.path.as_str().into(), |n| n.to_string_lossy().into())
+                    },
+                    |m| m.data.name.clone(),
+                );
+                (entry.base..entry.base + size, name, module)
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|(r, _, _)| r.start);
+        SymbolizeSession {
+            symbolizer: self,
+            modules: list,
+        }
+    }
+}
+
+/// Symbolize the addresses of a request
+pub struct SymbolizeSession<'a> {
+    symbolizer: &'a Symbolizer,
+    modules: Vec<(Range<usize>, Arc<str>, Option<Arc<ImageModule>>)>,
+}
+
+impl SymbolizeSession<'_> {
+    pub fn symbolize(&self, address: usize) -> Frame {
+        let mut result = Frame {
+            address,
+            module: None,
+            symbol: None,
+            offset: 0,
+            line: None,
+        };
+        let i = self.modules.partition_point(|(r, _, _)| r.start <= address);
+        let (range, name, module) = match i.checked_sub(1).map(|i| &self.modules[i]) {
+            Some(m) if m.0.contains(&address) => m,
+            _ => return result,
+        };
+        let rva = address - range.start;
+        result.module = Some(name.clone());
+        result.offset = rva;
+
+        let module = match module {
+            Some(m) => m,
+            None => return result,
+        };
+        if let Some(s) = module.find_symbol(rva, self.symbolizer.max_offset) {
+            let offset = rva - s.offset as usize;
+            let max = match s.len {
+                SYM_NOLEN => self.symbolizer.max_offset,
+                len => len as usize,
+            };
+            if offset <= max {
+                result.symbol = Some(s.name);
+                result.offset = offset;
+            }
+        }
+        result.line = module.symbol_file().and_then(|pdb| pdb.line_at(rva as u32));
+        result
+    }
+
+    pub fn symbolize_all<'s>(
+        &'s self,
+        addresses: impl IntoIterator<Item = usize> + 's,
+    ) -> impl Iterator<Item = Frame> + 's {
+        addresses.into_iter().map(|a| self.symbolize(a))
+    }
+}
@@ -0,0 +1,653 @@
+//!
+//! Exchange the symbols and comments with the files of other tools, to reuse the analysis done there.
+//!
+//! Supported formats for import:
+//! - linker map files of MSVC and GNU ld (`.map`)
+//! - scripts exported by IDA, with `MakeName`/`set_name` and `MakeComm`/`set_cmt` calls (`.idc`, `.py`)
+//! - symbol table exported by Ghidra, with the `Name` and `Location` columns,
+//!   or the input of `ImportSymbolsScript.py` (`.csv`, `.txt`)
+//! - the JSON exported by [`SymbolDocument`] (`.json`)
+//!
+//! The module is matched by the link timestamp or [`BinaryId`] if known, otherwise by name and size,
+//! the addresses are rebased from the preferred image base to the loaded module.
+//!
+//! [`SymbolDocument`] exports the symbols of a loaded module to the same formats except the map file,
+//! the addresses are at the preferred image base, as the static analysis tools load the binary.
+//!
+
+use crate::{comment::*, prelude::*, project::BinaryId};
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SymbolFormat {
+    Map,
+    Idc,
+    Python,
+    GhidraCsv,
+    Json,
+}
+
+impl SymbolFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        Some(match ext.as_str() {
+            "map" => Self::Map,
+            "idc" => Self::Idc,
+            "py" => Self::Python,
+            "csv" | "txt" => Self::GhidraCsv,
+            "json" => Self::Json,
+            _ => return None,
+        })
+    }
+}
+
+/// Which module the external symbols belong to, the unknown fields are not checked
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModuleMatcher {
+    pub id: Option<BinaryId>,
+    /// `TimeDateStamp` in the PE file header
+    pub timestamp: Option<u32>,
+    /// Module name, the extension is optional
+    pub name: Option<String>,
+    pub size: Option<usize>,
+}
+
+impl ModuleMatcher {
+    pub fn matches<T: UDbgTarget + ?Sized>(&self, target: &T, module: &dyn UDbgModule) -> bool {
+        let data = module.data();
+        if let Some(id) = self.id.as_ref() {
+            return BinaryId::of_module(target, module).as_ref() == Some(id);
+        }
+        if let Some(ts) = self.timestamp {
+            if let Some(t) = pe_timestamp(target, data.base) {
+                return t == ts;
+            }
+        }
+        let name_matches = self.name.as_ref().map_or(true, |n| {
+            data.name.eq_ignore_ascii_case(n)
+                || data
+                    .name
+                    .rsplit_once('.')
+                    .map_or(false, |(stem, _)| stem.eq_ignore_ascii_case(n))
+        });
+        let size_matches = self.size.map_or(true, |s| s == data.size);
+        (self.name.is_some() || self.size.is_some()) && name_matches && size_matches
+    }
+}
+
+/// Symbols and comments read from an external file, the addresses are virtual addresses
+/// at the preferred image base
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExternalSymbols {
+    pub module: ModuleMatcher,
+    /// Preferred image base, the one in the module header is used if None
+    pub image_base: Option<usize>,
+    pub symbols: Vec<(usize, String)>,
+    pub comments: Vec<(usize, String)>,
+}
+
+/// Counts of the items imported by [`ExternalSymbols::apply`]
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ImportResult {
+    pub symbols: usize,
+    pub comments: usize,
+    /// Items out of the module range
+    pub skipped: usize,
+}
+
+impl ExternalSymbols {
+    /// Load a file, the format is detected from the extension
+    pub fn load<P: AsRef<Path>>(path: P) -> UDbgResult<Self> {
+        let path = path.as_ref();
+        let format = SymbolFormat::from_path(path).ok_or(UDbgError::NotSupport)?;
+        let mut result = Self::parse(format, &std::fs::read_to_string(path)?);
+        if result.module.name.is_none() {
+            // the file is usually named after the binary
+            result.module.name = path.file_stem().map(|s| s.to_string_lossy().into());
+        }
+        Ok(result)
+    }
+
+    pub fn parse(format: SymbolFormat, text: &str) -> Self {
+        match format {
+            SymbolFormat::Map => parse_map(text),
+            SymbolFormat::Idc | SymbolFormat::Python => parse_ida_script(text),
+            SymbolFormat::GhidraCsv => parse_ghidra_csv(text),
+            SymbolFormat::Json => serde_json::from_str::<SymbolDocument>(text)
+                .log_error_with(|err| format!("parse symbols: {err:?}"))
+                .map(ExternalSymbols::from)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Find the loaded module which the symbols belong to
+    pub fn find_module<'a, T: UDbgTarget + ?Sized>(
+        &self,
+        target: &'a T,
+    ) -> UDbgResult<Option<Arc<dyn UDbgModule + 'a>>> {
+        Ok(target
+            .enum_module()?
+            .find(|m| self.module.matches(target, m.as_ref())))
+    }
+
+    /// Add the symbols to module, and the comments to target as [`CommentSource::MapFile`]
+    pub fn apply<T: UDbgTarget + ?Sized>(
+        &self,
+        target: &T,
+        module: &dyn UDbgModule,
+    ) -> UDbgResult<ImportResult> {
+        let data = module.data();
+        let image_base = self
+            .image_base
+            .or_else(|| pe_image_base(target, data.base))
+            .unwrap_or_default();
+        let rva = |va: usize| va.checked_sub(image_base).filter(|&r| r < data.size);
+
+        let mut result = ImportResult::default();
+        for (va, name) in self.symbols.iter() {
+            match rva(*va) {
+                Some(offset) => {
+                    module.add_symbol(offset, name)?;
+                    result.symbols += 1;
+                }
+                None => result.skipped += 1,
+            }
+        }
+        let comments = &target.base().comments;
+        for (va, text) in self.comments.iter() {
+            match rva(*va) {
+                Some(offset) => {
+                    comments.add(data.base + offset, text, CommentSource::MapFile);
+                    result.comments += 1;
+                }
+                None => result.skipped += 1,
+            }
+        }
+        Ok(result)
+    }
+
+    /// Apply to the matched module, fails if no module matches
+    pub fn apply_matched<T: UDbgTarget + ?Sized>(&self, target: &T) -> UDbgResult<ImportResult> {
+        let module = self.find_module(target)?.ok_or(UDbgError::NotFound)?;
+        self.apply(target, module.as_ref())
+    }
+}
+
+fn optional_header<T: ReadMemory + ?Sized>(target: &T, base: usize) -> Option<usize> {
+    if target.read_value::<u16>(base)? != 0x5A4D {
+        return None;
+    }
+    let nt = base + target.read_value::<u32>(base + 0x3C)? as usize;
+    (target.read_value::<u32>(nt)? == 0x4550).then(|| nt + 24)
+}
+
+fn pe_timestamp<T: ReadMemory + ?Sized>(target: &T, base: usize) -> Option<u32> {
+    target.read_value::<u32>(optional_header(target, base)? - 24 + 8)
+}
+
+fn pe_image_base<T: ReadMemory + ?Sized>(target: &T, base: usize) -> Option<usize> {
+    let optional = optional_header(target, base)?;
+    match target.read_value::<u16>(optional)? {
+        // PE32+
+        0x20B => target.read_value::<u64>(optional + 24).map(|b| b as usize),
+        _ => target.read_value::<u32>(optional + 28).map(|b| b as usize),
+    }
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let s = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// Map file of MSVC link or GNU ld
+fn parse_map(text: &str) -> ExternalSymbols {
+    let mut result = ExternalSymbols::default();
+    // the first line of MSVC map is the module name
+    result.module.name = text
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .filter(|l| !l.contains(char::is_whitespace))
+        .map(Into::into);
+
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(ts) = line.strip_prefix("Timestamp is ") {
+            result.module.timestamp = ts
+                .split_whitespace()
+                .next()
+                .and_then(|t| u32::from_str_radix(t, 16).ok());
+            continue;
+        }
+        if let Some(base) = line.strip_prefix("Preferred load address is ") {
+            result.image_base = parse_hex(base);
+            continue;
+        }
+
+        let items = line.split_whitespace().collect::<Vec<_>>();
+        match items.as_slice() {
+            // MSVC: 0001:00000000       _main       00401000 f   main.obj
+            [seg, name, va, ..] if seg.len() > 5 && seg.as_bytes()[4] == b':' => {
+                if seg.starts_with("0000") {
+                    // absolute symbols
+                    continue;
+                }
+                if let Some(va) = parse_hex(va).filter(|&va| va > 0) {
+                    result.symbols.push((va, name.to_string()));
+                }
+            }
+            // GNU ld:                 0x0000000000401126                main
+            [va, name] if va.starts_with("0x") && !name.contains(['=', '(', ')', '*']) => {
+                if let Some(va) = parse_hex(va).filter(|&va| va > 0) {
+                    result.symbols.push((va, name.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Parse a string literal of IDC/Python at the beginning of `s`
+fn parse_string(s: &str) -> Option<String> {
+    let s = s.trim();
+    let quote = s.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let mut result = String::new();
+    let mut chars = s[1..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => result.push('\n'),
+                't' => result.push('\t'),
+                c => result.push(c),
+            },
+            c if c == quote => return Some(result),
+            c => result.push(c),
+        }
+    }
+    None
+}
+
+/// Split the arguments of a call, respecting the string literals
+fn split_args(s: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut quote = None;
+    let mut escape = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match quote {
+            _ if escape => escape = false,
+            Some(_) if c == '\\' => escape = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ',' => {
+                result.push(s[start..i].trim());
+                start = i + 1;
+            }
+            None if c == ')' => {
+                result.push(s[start..i].trim());
+                return result;
+            }
+            None => {}
+        }
+    }
+    result
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    let s = s.trim().trim_end_matches(['L', 'l']);
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(h) => usize::from_str_radix(h, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Scripts exported by IDA, in IDC or IDAPython
+fn parse_ida_script(text: &str) -> ExternalSymbols {
+    let mut result = ExternalSymbols::default();
+    for line in text.lines() {
+        let mut rest = line;
+        while let Some(i) = rest.find('(') {
+            let func = rest[..i]
+                .rsplit(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .next()
+                .unwrap_or_default();
+            let args = split_args(&rest[i + 1..]);
+            rest = &rest[i + 1..];
+
+            let (address, value) = match args.as_slice() {
+                [a, v, ..] => match (parse_number(a), parse_string(v)) {
+                    (Some(a), Some(v)) if !v.is_empty() => (a, v),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            match func {
+                "MakeName" | "MakeNameEx" | "set_name" => result.symbols.push((address, value)),
+                "MakeComm" | "MakeRptCmt" | "set_cmt" => result.comments.push((address, value)),
+                _ => {}
+            }
+        }
+    }
+    result
+}
+
+/// Split a line of CSV, the quoted fields may contain commas and `""` as the escaped quote
+fn split_csv(line: &str) -> Vec<String> {
+    let mut result = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => result.push(core::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    result.push(field);
+    result
+}
+
+/// Symbol table exported by Ghidra
+fn parse_ghidra_csv(text: &str) -> ExternalSymbols {
+    let mut result = ExternalSymbols::default();
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let header = match lines.next() {
+        Some(h) => split_csv(h),
+        None => return result,
+    };
+    let column = |names: &[&str]| {
+        header
+            .iter()
+            .position(|h| names.iter().any(|n| h.trim().eq_ignore_ascii_case(n)))
+    };
+    let (name, location) = match (column(&["Name"]), column(&["Location", "Address"])) {
+        (Some(n), Some(l)) => (n, l),
+        _ => {
+            // the input of ImportSymbolsScript.py: `name address [f|l]`
+            for line in text.lines().filter(|l| !l.trim_start().starts_with('#')) {
+                let items = line.split_whitespace().collect::<Vec<_>>();
+                if let [n, a, ..] = items.as_slice() {
+                    if let Some(va) = parse_hex(a) {
+                        result.symbols.push((va, n.to_string()));
+                    }
+                }
+            }
+            return result;
+        }
+    };
+    let kind = column(&["Type"]);
+
+    for line in lines {
+        let fields = split_csv(line);
+        if kind
+            .and_then(|k| fields.get(k))
+            .map_or(false, |k| k.eq_ignore_ascii_case("External"))
+        {
+            continue;
+        }
+        let (n, l) = match (fields.get(name), fields.get(location)) {
+            (Some(n), Some(l)) if !n.is_empty() => (n, l),
+            _ => continue,
+        };
+        // "ram:00401000" or "00401000"
+        let l = l.rsplit(':').next().unwrap_or_default();
+        if let Some(va) = parse_hex(l) {
+            result.symbols.push((va, n.clone()));
+        }
+    }
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolKind {
+    Function,
+    Label,
+}
+
+/// Where an exported symbol comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymbolOrigin {
+    /// Added by user, or synthesized by analysis such as signature matching
+    User,
+    Pdb,
+    Export,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedSymbol {
+    pub rva: usize,
+    pub name: String,
+    pub kind: SymbolKind,
+    pub origin: SymbolOrigin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedComment {
+    pub rva: usize,
+    pub text: String,
+}
+
+/// Symbols and comments of a module, the schema of the JSON format
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SymbolDocument {
+    pub module: String,
+    #[serde(default)]
+    pub id: Option<BinaryId>,
+    pub size: usize,
+    /// Preferred image base, the addresses in the scripts are based on it
+    pub image_base: usize,
+    pub symbols: Vec<ExportedSymbol>,
+    #[serde(default)]
+    pub comments: Vec<ExportedComment>,
+}
+
+impl From<SymbolDocument> for ExternalSymbols {
+    fn from(doc: SymbolDocument) -> Self {
+        let base = doc.image_base;
+        Self {
+            module: ModuleMatcher {
+                id: doc.id,
+                timestamp: None,
+                name: Some(doc.module),
+                size: Some(doc.size),
+            },
+            image_base: Some(base),
+            symbols: doc
+                .symbols
+                .into_iter()
+                .map(|s| (base + s.rva, s.name))
+                .collect(),
+            comments: doc
+                .comments
+                .into_iter()
+                .map(|c| (base + c.rva, c.text))
+                .collect(),
+        }
+    }
+}
+
+/// Escape a string literal for IDC/Python
+fn quote(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                result.push('\\');
+                result.push(c);
+            }
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+impl SymbolDocument {
+    /// Collect the user, PDB and exported symbols of a loaded module, one symbol per address
+    /// in that priority, and the comments except the generated ones
+    pub fn collect<T: UDbgTarget + ?Sized>(target: &T, module: &dyn UDbgModule) -> Self {
+        let data = module.data();
+        let range = data.base..data.base + data.size;
+        let code = target
+            .enum_memory()
+            .map(|pages| {
+                pages
+                    .filter(|p| p.is_commit() && p.is_executable() && range.contains(&p.base))
+                    .map(|p| p.base - data.base..p.base + p.size - data.base)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let kind = |s: &Symbol| {
+            if (s.len != SYM_NOLEN && s.len > 0)
+                || code.iter().any(|r| r.contains(&(s.offset as usize)))
+            {
+                SymbolKind::Function
+            } else {
+                SymbolKind::Label
+            }
+        };
+
+        let mut symbols = BTreeMap::<usize, ExportedSymbol>::new();
+        let mut add = |s: &Symbol, origin| {
+            symbols
+                .entry(s.offset as usize)
+                .or_insert_with(|| ExportedSymbol {
+                    rva: s.offset as usize,
+                    name: s.name.to_string(),
+                    kind: kind(s),
+                    origin,
+                });
+        };
+        if let Some(syms) = module.symbols_data() {
+            syms.user_syms
+                .read()
+                .values()
+                .for_each(|s| add(s, SymbolOrigin::User));
+            let pdb = syms.pdb.read().as_ref().and_then(|p| p.global().ok());
+            if let Some(global) = pdb {
+                global.values().for_each(|s| add(s, SymbolOrigin::Pdb));
+            }
+            syms.exports
+                .values()
+                .for_each(|s| add(s, SymbolOrigin::Export));
+        }
+
+        let comments = target
+            .base()
+            .comments
+            .in_range(range)
+            .into_iter()
+            .filter(|(_, c)| c.source != CommentSource::SourceLine)
+            .map(|(a, c)| ExportedComment {
+                rva: a - data.base,
+                text: c.text,
+            })
+            .collect();
+
+        Self {
+            module: data.name.to_string(),
+            id: BinaryId::of_module(target, module),
+            size: data.size,
+            image_base: pe_image_base(target, data.base).unwrap_or_default(),
+            symbols: symbols.into_values().collect(),
+            comments,
+        }
+    }
+
+    /// IDC script, run by File > Script file in IDA
+    pub fn to_idc(&self) -> String {
+        let mut result = format!(
+            "// symbols of {}\n#include <idc.idc>\n\nstatic main() {{\n",
+            self.module
+        );
+        for s in self.symbols.iter() {
+            let va = self.image_base + s.rva;
+            if s.kind == SymbolKind::Function {
+                result += &format!("    add_func(0x{va:x}, BADADDR);\n");
+            }
+            result += &format!("    set_name(0x{va:x}, {}, SN_NOWARN);\n", quote(&s.name));
+        }
+        for c in self.comments.iter() {
+            let va = self.image_base + c.rva;
+            result += &format!("    set_cmt(0x{va:x}, {}, 0);\n", quote(&c.text));
+        }
+        result += "}\n";
+        result
+    }
+
+    /// IDAPython script
+    pub fn to_python(&self) -> String {
+        let mut result = format!(
+            "# symbols of {}\nimport idc\nimport ida_funcs\n\n",
+            self.module
+        );
+        for s in self.symbols.iter() {
+            let va = self.image_base + s.rva;
+            if s.kind == SymbolKind::Function {
+                result += &format!("ida_funcs.add_func(0x{va:x})\n");
+            }
+            result += &format!(
+                "idc.set_name(0x{va:x}, {}, idc.SN_NOWARN)\n",
+                quote(&s.name)
+            );
+        }
+        for c in self.comments.iter() {
+            let va = self.image_base + c.rva;
+            result += &format!("idc.set_cmt(0x{va:x}, {}, 0)\n", quote(&c.text));
+        }
+        result
+    }
+
+    /// Input of the `ImportSymbolsScript.py` of Ghidra, the whitespaces in names are replaced
+    pub fn to_ghidra(&self) -> String {
+        let mut result = String::new();
+        for s in self.symbols.iter() {
+            let name = s.name.replace(char::is_whitespace, "_");
+            let kind = match s.kind {
+                SymbolKind::Function => 'f',
+                SymbolKind::Label => 'l',
+            };
+            result += &format!("{name} {:x} {kind}\n", self.image_base + s.rva);
+        }
+        result
+    }
+
+    pub fn export(&self, format: SymbolFormat) -> UDbgResult<String> {
+        Ok(match format {
+            SymbolFormat::Idc => self.to_idc(),
+            SymbolFormat::Python => self.to_python(),
+            SymbolFormat::GhidraCsv => self.to_ghidra(),
+            SymbolFormat::Json => {
+                serde_json::to_string_pretty(self).map_err(std::io::Error::from)?
+            }
+            SymbolFormat::Map => return Err(UDbgError::NotSupport),
+        })
+    }
+
+    /// Write to a file, the format is detected from the extension
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> UDbgResult<()> {
+        let path = path.as_ref();
+        let format = SymbolFormat::from_path(path).ok_or(UDbgError::NotSupport)?;
+        std::fs::write(path, self.export(format)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+//!
+//! Caching symbol proxy: serve a symbol cache over HTTP to the other instances of a team or a
+//! fleet of analysis VMs, and download the missing files from the upstream servers once for all.
+//!
+//! The files are stored by their request path, which is the symbol store layout
+//! `<name>/<signature>/<name>` used by [`crate::shell::ShellData::symcache`], so the cache of a
+//! proxy works as a local cache too. The debuginfod requests `buildid/<id>/debuginfo` are cached
+//! in the same way. Point the instances at a proxy by `_NT_SYMBOL_PATH=srv*<cache>*http://<proxy>`.
+//!
+//! The connections served at the same time are bounded, each read and write of them times out,
+//! and the request headers are capped, so the stalled or hostile clients can't exhaust the proxy.
+//!
+
+use crate::prelude::*;
+
+use parking_lot::{Condvar, Mutex};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Max connections served at the same time, the others are refused with 503
+const MAX_CONNECTIONS: usize = 64;
+/// Timeout of each read and write of the connections
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Max length of the request line and each header line
+const MAX_LINE: usize = 0x2000;
+/// Max count of the header lines
+const MAX_HEADERS: usize = 100;
+
+/// Relative path in cache of a request, None if it would escape the cache
+pub fn cache_path(request: &str) -> Option<PathBuf> {
+    let mut result = PathBuf::new();
+    for part in request.split('/').filter(|p| !p.is_empty()) {
+        if part == "." || part == ".." || part.contains(['\\', ':']) {
+            return None;
+        }
+        result.push(part);
+    }
+    (!result.as_os_str().is_empty()).then_some(result)
+}
+
+fn join_url(server: &str, request: &str) -> String {
+    format!(
+        "{}/{}",
+        server.trim_end_matches('/'),
+        request.trim_start_matches('/')
+    )
+}
+
+/// Download `url` to the file at `dest` through a temporary file, Ok(false) if it's not found
+pub fn download(url: &str, dest: &Path) -> UDbgResult<bool> {
+    let response = match ureq::get(url).call() {
+        Ok(r) => r,
+        Err(ureq::Error::Status(404, _)) => return Ok(false),
+        Err(err) => return Err(format!("{url}: {err}").into()),
+    };
+    if let Some(dir) = dest.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temp = dest.with_extension("part");
+    let mut file = File::create(&temp)?;
+    std::io::copy(&mut response.into_reader(), &mut file)?;
+    drop(file);
+    std::fs::rename(&temp, dest)?;
+    Ok(true)
+}
+
+/// Fetch a file from the symbol `server` into `cache`, returns its path if it's found
+pub fn fetch(server: &str, request: &str, cache: &Path) -> UDbgResult<Option<PathBuf>> {
+    let path = match cache_path(request) {
+        Some(r) => cache.join(r),
+        None => return Ok(None),
+    };
+    if path.is_file() || download(&join_url(server, request), &path)? {
+        Ok(Some(path))
+    } else {
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ProxyStats {
+    /// Requests served from the cache
+    pub hits: usize,
+    pub downloads: usize,
+    /// Requests not found in any upstream
+    pub misses: usize,
+    pub errors: usize,
+}
+
+pub struct SymbolProxy {
+    cache: PathBuf,
+    upstreams: Vec<String>,
+    /// The downloads in progress, the requests of the same file wait for them
+    pending: Mutex<HashMap<PathBuf, Arc<Condvar>>>,
+    hits: AtomicUsize,
+    downloads: AtomicUsize,
+    misses: AtomicUsize,
+    errors: AtomicUsize,
+    /// The connections being served
+    active: AtomicUsize,
+}
+
+impl SymbolProxy {
+    /// A proxy of the `upstreams` tried in order, caching in the directory `cache`
+    pub fn new(cache: impl Into<PathBuf>, upstreams: Vec<String>) -> Self {
+        Self {
+            cache: cache.into(),
+            upstreams,
+            pending: Default::default(),
+            hits: Default::default(),
+            downloads: Default::default(),
+            misses: Default::default(),
+            errors: Default::default(),
+            active: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn cache(&self) -> &Path {
+        &self.cache
+    }
+
+    pub fn stats(&self) -> ProxyStats {
+        ProxyStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            downloads: self.downloads.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Path of the requested file in cache, downloaded from the upstreams if it's missing.
+    /// The concurrent requests of a file share one download
+    pub fn get(&self, request: &str) -> UDbgResult<Option<PathBuf>> {
+        let relative = match cache_path(request) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let path = self.cache.join(&relative);
+
+        let mut pending = self.pending.lock();
+        while let Some(cv) = pending.get(&relative).cloned() {
+            cv.wait(&mut pending);
+        }
+        if path.is_file() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(path));
+        }
+        let cv = Arc::new(Condvar::new());
+        pending.insert(relative.clone(), cv.clone());
+        drop(pending);
+
+        let result = self.fetch_upstream(request, &path);
+        self.pending.lock().remove(&relative);
+        cv.notify_all();
+
+        let counter = match result {
+            Ok(true) => &self.downloads,
+            Ok(false) => &self.misses,
+            Err(_) => &self.errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        result.map(|found| found.then_some(path))
+    }
+
+    fn fetch_upstream(&self, request: &str, path: &Path) -> UDbgResult<bool> {
+        let mut err = None;
+        for upstream in self.upstreams.iter() {
+            match download(&join_url(upstream, request), path) {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(e) => err = Some(e),
+            }
+        }
+        err.map_or(Ok(false), Err)
+    }
+
+    /// Serve HTTP on `address`, a thread for each connection up to [`MAX_CONNECTIONS`], blocks
+    /// until the listener fails
+    pub fn serve(self: Arc<Self>, address: impl ToSocketAddrs) -> UDbgResult<()> {
+        let listener = TcpListener::bind(address)?;
+        info!("symbol proxy listening on {:?}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            let mut stream = stream?;
+            stream.set_read_timeout(Some(IO_TIMEOUT))?;
+            stream.set_write_timeout(Some(IO_TIMEOUT))?;
+            if self.active.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                self.active.fetch_sub(1, Ordering::Relaxed);
+                respond(&mut stream, "503 Service Unavailable").log_error("symbol proxy");
+                continue;
+            }
+            let this = self.clone();
+            std::thread::spawn(move || {
+                this.handle(stream).log_error("symbol proxy");
+                this.active.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        Ok(())
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        read_line(&mut reader, &mut line)?;
+        let mut header = String::new();
+        for i in 0.. {
+            if i == MAX_HEADERS {
+                return respond(&mut stream, "431 Request Header Fields Too Large");
+            }
+            header.clear();
+            if read_line(&mut reader, &mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+        }
+
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let target = parts.next().unwrap_or_default();
+        let request = target.split('?').next().unwrap_or_default();
+        if method != "GET" && method != "HEAD" {
+            return respond(&mut stream, "405 Method Not Allowed");
+        }
+        match self.get(request) {
+            Ok(Some(path)) => {
+                let mut file = File::open(path)?;
+                let len = file.metadata()?.len();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\
+                     Content-Length: {len}\r\nConnection: close\r\n\r\n"
+                )?;
+                if method == "GET" {
+                    std::io::copy(&mut file, &mut stream)?;
+                }
+                Ok(())
+            }
+            Ok(None) => respond(&mut stream, "404 Not Found"),
+            Err(err) => {
+                warn!("symbol proxy {request}: {err:?}");
+                respond(&mut stream, "502 Bad Gateway")
+            }
+        }
+    }
+}
+
+/// Read a line of at most [`MAX_LINE`] bytes, the longer one is an error
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> std::io::Result<usize> {
+    let len = reader.by_ref().take(MAX_LINE as u64).read_line(line)?;
+    if len == MAX_LINE && !line.ends_with('\n') {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(len)
+}
+
+fn respond(stream: &mut TcpStream, status: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache() {
+        let path = cache_path("/ntdll.pdb/1234ABCD1/ntdll.pdb").unwrap();
+        assert_eq!(
+            path,
+            ["ntdll.pdb", "1234ABCD1", "ntdll.pdb"]
+                .iter()
+                .collect::<PathBuf>()
+        );
+        assert_eq!(cache_path("a//b/"), Some(["a", "b"].iter().collect()));
+        assert_eq!(cache_path("/"), None);
+        assert_eq!(cache_path("/a/../../etc/passwd"), None);
+        assert_eq!(cache_path("/a/./b"), None);
+        assert_eq!(cache_path("/a\\..\\b"), None);
+        assert_eq!(cache_path("/C:/b"), None);
+    }
+
+    #[test]
+    fn url() {
+        assert_eq!(join_url("http://s/", "/a/b"), "http://s/a/b");
+        assert_eq!(join_url("http://s", "a/b"), "http://s/a/b");
+    }
+
+    #[test]
+    fn line() {
+        let mut line = String::new();
+        let mut reader = std::io::Cursor::new(b"GET / HTTP/1.1\r\nHost: s\r\n".to_vec());
+        assert_eq!(read_line(&mut reader, &mut line).unwrap(), 16);
+        assert_eq!(line, "GET / HTTP/1.1\r\n");
+
+        let mut reader = std::io::Cursor::new(vec![b'a'; MAX_LINE + 1]);
+        assert!(read_line(&mut reader, &mut String::new()).is_err());
+    }
+}
@@ -0,0 +1,434 @@
+//!
+//! Syscall tracing, like strace: report [`UEvent::SyscallEnter`] and [`UEvent::SyscallExit`] with
+//! the number, name and arguments of each syscall.
+//!
+//! On Linux the threads are resumed by `PTRACE_SYSCALL` when it's enabled, and the engine passes
+//! the syscall stops to [`SyscallTrace`]. On Windows the `Nt*` stubs of ntdll are instrumented by
+//! breakpoints, the number is read from the stub and a breakpoint at the return address reports
+//! the exit, so the syscalls made without the stubs are not traced there.
+//!
+
+use crate::{
+    breakpoint::{return_address, ReturnWaiters},
+    prelude::*,
+    register::regid,
+};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// The arguments read of each syscall
+pub const MAX_ARGS: usize = 6;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SyscallInfo {
+    pub tid: tid_t,
+    pub nr: usize,
+    pub name: Option<Arc<str>>,
+    pub args: Vec<usize>,
+    /// The return value, on exit only
+    pub ret: Option<usize>,
+}
+
+struct Pending {
+    /// The return address of the stub on Windows
+    ret_address: usize,
+    info: Arc<SyscallInfo>,
+}
+
+/// Syscall tracing state of a target, disabled by default
+#[derive(Default)]
+pub struct SyscallTrace {
+    enabled: AtomicBool,
+    /// Names of the syscalls to report, None for all
+    filter: Mutex<Option<HashSet<String>>>,
+    /// The syscalls entered and not exited of each thread
+    pending: Mutex<HashMap<tid_t, Vec<Pending>>>,
+    /// The instrumented stubs, with the number and name
+    stubs: Mutex<HashMap<usize, (usize, Arc<str>)>>,
+    /// Breakpoints at the return addresses of stubs, with the threads of syscalls returning to them
+    returns: Mutex<ReturnWaiters<()>>,
+}
+
+impl SyscallTrace {
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn enable<T: UDbgTarget + ?Sized>(&self, target: &T, enable: bool) -> UDbgResult<()> {
+        if enable == self.is_enabled() {
+            return Ok(());
+        }
+        if enable {
+            sys::enable(self, target)?;
+        } else {
+            let stubs = core::mem::take(&mut *self.stubs.lock());
+            for address in stubs.into_keys() {
+                if let Some(bp) = target.get_breakpoint(address as BpID) {
+                    bp.remove().log_error("remove syscall bp");
+                }
+            }
+            self.returns.lock().clear();
+            self.pending.lock().clear();
+        }
+        self.enabled.store(enable, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Report the syscalls of the names only, None to report all of them
+    pub fn set_filter(&self, names: Option<&[&str]>) {
+        *self.filter.lock() = names.map(|n| n.iter().map(|&n| n.into()).collect());
+    }
+
+    fn is_reported(&self, info: &SyscallInfo) -> bool {
+        match &*self.filter.lock() {
+            Some(filter) => info
+                .name
+                .as_ref()
+                .map_or(false, |n| filter.contains(n.as_ref())),
+            None => true,
+        }
+    }
+
+    fn enter(&self, ret_address: usize, info: SyscallInfo) -> Option<UEvent> {
+        let info = Arc::new(info);
+        self.pending
+            .lock()
+            .entry(info.tid)
+            .or_default()
+            .push(Pending {
+                ret_address,
+                info: info.clone(),
+            });
+        self.is_reported(&info).then(|| UEvent::SyscallEnter(info))
+    }
+
+    /// Pop the last syscall of the thread which returns to the address, 0 for any
+    fn pop(&self, tid: tid_t, ret_address: usize) -> Option<Arc<SyscallInfo>> {
+        let mut pending = self.pending.lock();
+        let stack = pending.get_mut(&tid)?;
+        let i = stack
+            .iter()
+            .rposition(|p| ret_address == 0 || p.ret_address == ret_address)?;
+        // the syscalls above it will never return, such as the interrupted callbacks
+        let result = stack.drain(i..).next().map(|p| p.info);
+        if stack.is_empty() {
+            pending.remove(&tid);
+        }
+        result
+    }
+
+    fn exit(&self, info: &SyscallInfo, ret: usize) -> Option<UEvent> {
+        let mut info = info.clone();
+        info.ret = Some(ret);
+        self.is_reported(&info)
+            .then(|| UEvent::SyscallExit(Arc::new(info)))
+    }
+
+    /// Called by the Linux engine at the syscall stops of `PTRACE_SYSCALL`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn on_syscall_stop(
+        &self,
+        tid: tid_t,
+        regs: &libc::user_regs_struct,
+    ) -> Option<UEvent> {
+        let (nr, args, ret) = sys::syscall_regs(regs)?;
+        // without the kernel support, the stops of a thread alternate between entry and exit
+        let entry = sys::is_syscall_entry(tid, regs)
+            .unwrap_or_else(|| !self.pending.lock().contains_key(&tid));
+        if !entry {
+            // no pending one if it's entered before the tracing is enabled
+            let info = self.pop(tid, 0)?;
+            return self.exit(&info, ret);
+        }
+        self.enter(
+            0,
+            SyscallInfo {
+                tid,
+                nr,
+                name: sys::syscall_name(nr).map(Into::into),
+                args,
+                ret: None,
+            },
+        )
+    }
+
+    /// Called by the engine before the user callback, the breakpoints of stubs are replied by it
+    /// with the syscall events to pass to the user callback
+    pub(crate) fn on_event(
+        &self,
+        ctx: &mut dyn TraceContext,
+        event: &UEvent,
+    ) -> Option<(UserReply, Option<UEvent>)> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let address = match event {
+            UEvent::Breakpoint(bp) => bp.address(),
+            _ => return None,
+        };
+        let stub = self.stubs.lock().get(&address).cloned();
+        if let Some((nr, name)) = stub {
+            return Some((UserReply::Run(false), self.on_stub(ctx, nr, name)));
+        }
+        if !self.returns.lock().contains(address) {
+            return None;
+        }
+
+        let target = ctx.target();
+        let tid = target.base().event_tid.get();
+        let info = match self.pop(tid, address) {
+            Some(info) => info,
+            // another thread passes the return address
+            None => return Some((UserReply::Run(false), None)),
+        };
+        self.returns.lock().take(address, tid);
+
+        let ret = match ctx.arch() {
+            ARCH_X64 => regid::X86_REG_RAX,
+            ARCH_X86 => regid::X86_REG_EAX,
+            ARCH_ARM64 => regid::ARM64_REG_X0,
+            _ => regid::ARM_REG_R0,
+        };
+        let ret = ctx
+            .register()
+            .and_then(|r| r.get_reg(ret))
+            .map_or(0, |r| r.as_int());
+        Some((UserReply::Run(false), self.exit(&info, ret)))
+    }
+
+    fn on_stub(&self, ctx: &mut dyn TraceContext, nr: usize, name: Arc<str>) -> Option<UEvent> {
+        let target = ctx.target();
+        let tid = target.base().event_tid.get();
+        let arch = ctx.arch();
+        let regs = &*ctx.register()?;
+        let args = (1..=MAX_ARGS)
+            .map(|i| target.read_argument(regs, i, None).unwrap_or_default())
+            .collect();
+        let ret_address = return_address(target.as_ref(), regs, arch)?;
+
+        // the exit is not traced without the own breakpoint
+        if let Err(err) = self
+            .returns
+            .lock()
+            .wait(target.as_ref(), ret_address, tid, ())
+        {
+            warn!("syscall return {ret_address:x}: {err:?}");
+            return None;
+        }
+
+        self.enter(
+            ret_address,
+            SyscallInfo {
+                tid,
+                nr,
+                name: Some(name),
+                args,
+                ret: None,
+            },
+        )
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::*;
+
+    /// The syscall number of stub: `mov r10, rcx; mov eax, nr` on x64, `mov eax, nr` on x86
+    fn stub_number(code: &[u8]) -> Option<usize> {
+        let imm = match code {
+            [0x4C, 0x8B, 0xD1, 0xB8, imm @ ..] => imm,
+            [0xB8, imm @ ..] => imm,
+            _ => return None,
+        };
+        Some(u32::from_le_bytes(imm.get(..4)?.try_into().ok()?) as usize)
+    }
+
+    pub fn enable<T: UDbgTarget + ?Sized>(trace: &SyscallTrace, target: &T) -> UDbgResult<()> {
+        if !cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+            return Err(UDbgError::NotSupport);
+        }
+        let ntdll = target.get_module("ntdll").ok_or(UDbgError::NotFound)?;
+        let base = ntdll.data().base;
+        let mut stubs = trace.stubs.lock();
+        for s in ntdll.enum_symbol(Some("Nt*"))? {
+            let address = base + s.offset as usize;
+            let nr = match stub_number(&target.read_bytes(address, 8)) {
+                Some(nr) => nr,
+                None => continue,
+            };
+            if stubs.contains_key(&address) {
+                continue;
+            }
+            match target.add_breakpoint(address.into()) {
+                Ok(_) => {
+                    stubs.insert(address, (nr, s.name.clone()));
+                }
+                Err(UDbgError::BpExists) => {}
+                Err(err) => warn!("instrument {}: {err:?}", s.name),
+            }
+        }
+        info!("{} syscall stubs instrumented", stubs.len());
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use super::*;
+
+    pub fn enable<T: UDbgTarget + ?Sized>(_trace: &SyscallTrace, _target: &T) -> UDbgResult<()> {
+        if cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+            Ok(())
+        } else {
+            Err(UDbgError::NotSupport)
+        }
+    }
+
+    /// The number, arguments and return value in the registers at a syscall stop
+    #[cfg(target_arch = "x86_64")]
+    pub fn syscall_regs(r: &libc::user_regs_struct) -> Option<(usize, Vec<usize>, usize)> {
+        let args = [r.rdi, r.rsi, r.rdx, r.r10, r.r8, r.r9];
+        Some((
+            r.orig_rax as usize,
+            args.iter().map(|&a| a as usize).collect(),
+            r.rax as usize,
+        ))
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn syscall_regs(r: &libc::user_regs_struct) -> Option<(usize, Vec<usize>, usize)> {
+        Some((
+            r.regs[8] as usize,
+            r.regs[..MAX_ARGS].iter().map(|&a| a as usize).collect(),
+            r.regs[0] as usize,
+        ))
+    }
+
+    /// If the syscall stop is the entry, by `PTRACE_GET_SYSCALL_INFO` of Linux 5.3 or later, or by
+    /// the `-ENOSYS` in rax at the entry on x86_64. None if it can't be told
+    pub fn is_syscall_entry(tid: tid_t, r: &libc::user_regs_struct) -> Option<bool> {
+        const PTRACE_GET_SYSCALL_INFO: u32 = 0x420e;
+        const PTRACE_SYSCALL_INFO_ENTRY: u8 = 1;
+        const PTRACE_SYSCALL_INFO_EXIT: u8 = 2;
+
+        // `struct ptrace_syscall_info`, starts with the op
+        let mut info = [0u8; 88];
+        let size = unsafe {
+            libc::ptrace(
+                PTRACE_GET_SYSCALL_INFO as _,
+                tid,
+                info.len(),
+                info.as_mut_ptr(),
+            )
+        };
+        if size > 0 {
+            match info[0] {
+                PTRACE_SYSCALL_INFO_ENTRY => return Some(true),
+                PTRACE_SYSCALL_INFO_EXIT => return Some(false),
+                _ => {}
+            }
+        }
+        #[cfg(target_arch = "x86_64")]
+        return Some(r.rax as i64 == -(libc::ENOSYS as i64));
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            let _ = r;
+            None
+        }
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn syscall_regs(_r: &libc::user_regs_struct) -> Option<(usize, Vec<usize>, usize)> {
+        None
+    }
+
+    macro_rules! syscall_table {
+        ($($sys:ident)*) => { &[$((libc::$sys as usize, stringify!($sys)),)*] };
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    static COMMON: &[(usize, &str)] = syscall_table!(
+        SYS_read SYS_write SYS_close SYS_fstat SYS_lseek SYS_mmap SYS_mprotect SYS_munmap SYS_brk
+        SYS_rt_sigaction SYS_rt_sigprocmask SYS_rt_sigreturn SYS_ioctl SYS_pread64 SYS_pwrite64
+        SYS_readv SYS_writev SYS_sched_yield SYS_mremap SYS_msync SYS_mincore SYS_madvise
+        SYS_shmget SYS_shmat SYS_shmctl SYS_shmdt SYS_dup SYS_dup3 SYS_nanosleep SYS_getitimer
+        SYS_setitimer SYS_getpid SYS_sendfile SYS_socket SYS_connect SYS_accept SYS_accept4
+        SYS_sendto SYS_recvfrom SYS_sendmsg SYS_recvmsg SYS_sendmmsg SYS_recvmmsg SYS_shutdown
+        SYS_bind SYS_listen SYS_getsockname SYS_getpeername SYS_socketpair SYS_setsockopt
+        SYS_getsockopt SYS_clone SYS_execve SYS_execveat SYS_exit SYS_exit_group SYS_wait4
+        SYS_waitid SYS_kill SYS_tkill SYS_tgkill SYS_uname SYS_semget SYS_semop SYS_semctl
+        SYS_semtimedop SYS_msgget SYS_msgsnd SYS_msgrcv SYS_msgctl SYS_fcntl SYS_flock SYS_fsync
+        SYS_fdatasync SYS_truncate SYS_ftruncate SYS_getcwd SYS_chdir SYS_fchdir SYS_fchmod
+        SYS_fchown SYS_umask SYS_gettimeofday SYS_settimeofday SYS_getrlimit SYS_setrlimit
+        SYS_prlimit64 SYS_getrusage SYS_sysinfo SYS_times SYS_ptrace SYS_syslog SYS_getuid
+        SYS_getgid SYS_setuid SYS_setgid SYS_geteuid SYS_getegid SYS_setpgid SYS_getppid
+        SYS_setsid SYS_getsid SYS_getpgid SYS_setreuid SYS_setregid SYS_getgroups SYS_setgroups
+        SYS_setresuid SYS_getresuid SYS_setresgid SYS_getresgid SYS_setfsuid SYS_setfsgid
+        SYS_capget SYS_capset SYS_rt_sigpending SYS_rt_sigtimedwait SYS_rt_sigqueueinfo
+        SYS_rt_tgsigqueueinfo SYS_rt_sigsuspend SYS_sigaltstack SYS_personality SYS_statfs
+        SYS_fstatfs SYS_getpriority SYS_setpriority SYS_sched_setparam SYS_sched_getparam
+        SYS_sched_setscheduler SYS_sched_getscheduler SYS_sched_get_priority_max
+        SYS_sched_get_priority_min SYS_sched_rr_get_interval SYS_sched_setaffinity
+        SYS_sched_getaffinity SYS_sched_setattr SYS_sched_getattr SYS_mlock SYS_mlock2
+        SYS_munlock SYS_mlockall SYS_munlockall SYS_vhangup SYS_pivot_root SYS_prctl
+        SYS_adjtimex SYS_chroot SYS_sync SYS_syncfs SYS_acct SYS_mount SYS_umount2 SYS_swapon
+        SYS_swapoff SYS_reboot SYS_sethostname SYS_setdomainname SYS_init_module
+        SYS_finit_module SYS_delete_module SYS_quotactl SYS_gettid SYS_readahead SYS_setxattr
+        SYS_lsetxattr SYS_fsetxattr SYS_getxattr SYS_lgetxattr SYS_fgetxattr SYS_listxattr
+        SYS_llistxattr SYS_flistxattr SYS_removexattr SYS_lremovexattr SYS_fremovexattr
+        SYS_futex SYS_set_robust_list SYS_get_robust_list SYS_io_setup SYS_io_destroy
+        SYS_io_getevents SYS_io_submit SYS_io_cancel SYS_getdents64 SYS_set_tid_address
+        SYS_restart_syscall SYS_fadvise64 SYS_timer_create SYS_timer_settime SYS_timer_gettime
+        SYS_timer_getoverrun SYS_timer_delete SYS_clock_settime SYS_clock_gettime
+        SYS_clock_getres SYS_clock_nanosleep SYS_clock_adjtime SYS_epoll_create1 SYS_epoll_ctl
+        SYS_epoll_pwait SYS_ioprio_set SYS_ioprio_get SYS_inotify_init1 SYS_inotify_add_watch
+        SYS_inotify_rm_watch SYS_openat SYS_mkdirat SYS_mknodat SYS_fchownat SYS_newfstatat
+        SYS_unlinkat SYS_renameat SYS_renameat2 SYS_linkat SYS_symlinkat SYS_readlinkat
+        SYS_fchmodat SYS_faccessat SYS_pselect6 SYS_ppoll SYS_unshare SYS_setns SYS_splice
+        SYS_tee SYS_vmsplice SYS_sync_file_range SYS_move_pages SYS_utimensat SYS_signalfd4
+        SYS_timerfd_create SYS_timerfd_settime SYS_timerfd_gettime SYS_eventfd2 SYS_fallocate
+        SYS_pipe2 SYS_preadv SYS_pwritev SYS_preadv2 SYS_pwritev2 SYS_perf_event_open
+        SYS_name_to_handle_at SYS_open_by_handle_at SYS_getcpu SYS_process_vm_readv
+        SYS_process_vm_writev SYS_kcmp SYS_seccomp SYS_getrandom SYS_memfd_create SYS_bpf
+        SYS_userfaultfd SYS_membarrier SYS_copy_file_range SYS_statx
+    );
+
+    #[cfg(target_arch = "x86_64")]
+    static ARCH: &[(usize, &str)] = syscall_table!(
+        SYS_open SYS_stat SYS_lstat SYS_poll SYS_access SYS_pipe SYS_select SYS_dup2 SYS_pause
+        SYS_alarm SYS_fork SYS_vfork SYS_creat SYS_rename SYS_mkdir SYS_rmdir SYS_link SYS_unlink
+        SYS_symlink SYS_readlink SYS_chmod SYS_chown SYS_lchown SYS_mknod SYS_utime SYS_utimes
+        SYS_futimesat SYS_arch_prctl SYS_getdents SYS_epoll_create SYS_epoll_wait SYS_eventfd
+        SYS_signalfd SYS_inotify_init SYS_time SYS_getpgrp SYS_iopl SYS_ioperm SYS_modify_ldt
+    );
+
+    #[cfg(target_arch = "aarch64")]
+    static ARCH: &[(usize, &str)] = &[];
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn syscall_name(nr: usize) -> Option<&'static str> {
+        COMMON
+            .iter()
+            .chain(ARCH)
+            .find(|s| s.0 == nr)
+            .map(|s| s.1.trim_start_matches("SYS_"))
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn syscall_name(_nr: usize) -> Option<&'static str> {
+        None
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android")))]
+mod sys {
+    use super::*;
+
+    pub fn enable<T: UDbgTarget + ?Sized>(_trace: &SyscallTrace, _target: &T) -> UDbgResult<()> {
+        Err(UDbgError::NotSupport)
+    }
+}
@@ -0,0 +1,555 @@
+//!
+//! Backward value tracking over an instruction trace of [`crate::itrace`]: where did the value of
+//! a register or memory at a position come from.
+//!
+//! The trace records what each instruction wrote but not what it read, so the sources are matched
+//! by value: the instruction writing the value is found first, then the register or the memory
+//! written in the trace which held the same value before that instruction, the most recently
+//! written one, is taken as the source of a move, and so on backward. It's approximate: the small
+//! values are not followed as they match too much, and a value computed by arithmetic or loaded
+//! from the memory never written in the trace ends the chain.
+//!
+//! [`forward_taint`] replays the trace from a position with the designated inputs tainted, and
+//! reports the conditional branches and the memory writes depending on them, to triage which
+//! parts of a parser the input reaches. The instructions are decoded from the code of target by
+//! iced, so it works on the x86 traces only, and the taint is propagated at the granularity of
+//! full registers, the flags and bytes of memory: any tainted source taints all the destinations.
+//!
+//! The crate has no emulator backend, such as unicorn, to run the code from the inputs, so the
+//! replay of a recorded trace stands in for it, with the limits of the trace:
+//! - only the path recorded is covered, the side not taken of a tainted branch is not explored
+//! - the operand addresses are computed by the last recorded values of the registers, the ones
+//!   based on FS and GS are unknown, and the accesses by them are neither tainted nor reported
+//! - the code is read from the target as it is at the replay, the code modified since the
+//!   recording is decoded wrongly, so a dump taken at the recording is preferred
+//!
+
+use crate::{
+    itrace::{MemWrite, TraceReader, TraceStep},
+    prelude::*,
+    register::general_regs,
+};
+
+use core::ops::Range;
+use iced_x86::{
+    Decoder, DecoderOptions, FlowControl, Instruction, InstructionInfoFactory, Mnemonic, OpAccess,
+    OpKind, Register, UsedMemory,
+};
+use std::collections::{HashMap, HashSet};
+
+/// The values below are not followed, they are likely counters, flags or small constants
+const MIN_LINK_VALUE: u64 = 0x100;
+/// Max records searched backward for a source in memory
+const MAX_SCAN: usize = 0x100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Location {
+    /// A register by id
+    Reg(u32),
+    /// Memory of 1 to 8 bytes, little endian
+    Memory { address: usize, size: usize },
+}
+
+impl Location {
+    /// A general register by name
+    pub fn reg(arch: u32, name: &str) -> Option<Self> {
+        general_regs(arch)
+            .iter()
+            .find(|r| r.0 == name)
+            .map(|r| Self::Reg(r.1))
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Self::Reg(_) => 8,
+            Self::Memory { size, .. } => *size,
+        }
+    }
+}
+
+/// How a value is linked to the next evidence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Link {
+    /// Moved from the location of the next evidence
+    Moved,
+    /// No source held the same value, computed by the instruction or loaded from unknown memory
+    Computed,
+    /// The value is there since the trace started
+    Initial,
+}
+
+/// An instruction in the chain producing the value
+#[derive(Debug, Clone, Serialize)]
+pub struct Evidence {
+    /// Position of the instruction in the trace, 0 for [`Link::Initial`]
+    pub position: usize,
+    pub pc: usize,
+    /// Where the value is written to
+    pub location: Location,
+    pub value: u64,
+    pub link: Link,
+}
+
+fn mask(size: usize) -> u64 {
+    if size >= 8 {
+        u64::MAX
+    } else {
+        (1u64 << (size * 8)) - 1
+    }
+}
+
+/// Value of register at `pos`, and the index of the record setting it
+fn reg_at(steps: &[TraceStep], pos: usize, id: u32) -> Option<(u64, usize)> {
+    steps[..=pos]
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, s)| s.regs.iter().find(|r| r.0 == id).map(|r| (r.1, i)))
+}
+
+/// Value of memory at `pos`, and the index of the last record writing it, None if it's never
+/// written before `pos`
+fn mem_at(
+    steps: &[TraceStep],
+    pos: usize,
+    address: usize,
+    size: usize,
+) -> Option<(u64, Option<usize>)> {
+    let mut bytes = vec![None; size];
+    let mut last = None;
+    let mut fill = |w: &MemWrite, old: bool| {
+        let data = if old { &w.old } else { &w.new };
+        let mut filled = false;
+        for (i, b) in bytes.iter_mut().enumerate() {
+            let a = address + i;
+            if b.is_none() && a >= w.address && a < w.address + data.len() {
+                *b = Some(data[a - w.address]);
+                filled = true;
+            }
+        }
+        filled
+    };
+    for (i, s) in steps[..=pos].iter().enumerate().rev() {
+        for w in s.writes.iter() {
+            if fill(w, false) {
+                last.get_or_insert(i);
+            }
+        }
+    }
+    // the bytes unwritten before have the old value of the first write after
+    for s in steps[pos + 1..].iter() {
+        for w in s.writes.iter() {
+            fill(w, true);
+        }
+    }
+    let mut value = [0u8; 8];
+    for (i, b) in bytes.into_iter().enumerate() {
+        value[i] = b?;
+    }
+    Some((u64::from_le_bytes(value), last))
+}
+
+fn value_at(steps: &[TraceStep], pos: usize, location: Location) -> Option<(u64, Option<usize>)> {
+    match location {
+        Location::Reg(id) => reg_at(steps, pos, id).map(|(v, i)| (v, Some(i))),
+        Location::Memory { address, size } => mem_at(steps, pos, address, size),
+    }
+}
+
+/// The location holding `value` before the instruction at `pos`, the most recently written one
+fn find_source(steps: &[TraceStep], pos: usize, dest: Location, value: u64) -> Option<Location> {
+    let size = dest.size();
+    let m = mask(size);
+    let mut best: Option<(Location, usize)> = None;
+    let mut update = |location, record: usize| {
+        if best.map_or(true, |(_, r)| record > r) {
+            best = Some((location, record));
+        }
+    };
+
+    // the first record has all the registers
+    for &(id, _) in steps[0].regs.iter() {
+        if Location::Reg(id) == dest {
+            continue;
+        }
+        if let Some((v, record)) = reg_at(steps, pos, id).filter(|(v, _)| v & m == value) {
+            if size == 8 && v != value {
+                continue;
+            }
+            update(Location::Reg(id), record);
+        }
+    }
+
+    let bytes = value.to_le_bytes();
+    let start = pos.saturating_sub(MAX_SCAN);
+    let written = steps[start..=pos]
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, s)| {
+            s.writes
+                .iter()
+                .find(|w| w.new.len() >= size && w.new[..size] == bytes[..size])
+                .map(|w| (w.address, start + i))
+        });
+    if let Some((address, record)) = written {
+        let location = Location::Memory { address, size };
+        // still there before the instruction
+        let holds = mem_at(steps, pos, address, size).map_or(false, |(v, _)| v == value);
+        if location != dest && holds {
+            update(location, record);
+        }
+    }
+    best.map(|(location, _)| location)
+}
+
+/// Walk the trace backward from the current position of `reader`, to find the chain of
+/// instructions producing the value at `location`, the latest first
+pub fn trace_value(
+    reader: &TraceReader,
+    location: Location,
+    max_depth: usize,
+) -> UDbgResult<Vec<Evidence>> {
+    if !(1..=8).contains(&location.size()) {
+        return Err("the size of memory must be 1 to 8".into());
+    }
+    let steps = reader.steps();
+    let (mut location, mut pos) = (location, reader.position());
+    let mut result = vec![];
+    for _ in 0..max_depth {
+        let (value, record) = value_at(steps, pos, location).ok_or(UDbgError::NotFound)?;
+        let producer = match record {
+            Some(record) if record > 0 => record - 1,
+            _ => {
+                result.push(Evidence {
+                    position: 0,
+                    pc: steps[0].pc,
+                    location,
+                    value,
+                    link: Link::Initial,
+                });
+                break;
+            }
+        };
+        let source = (value >= MIN_LINK_VALUE)
+            .then(|| find_source(steps, producer, location, value))
+            .flatten();
+        result.push(Evidence {
+            position: producer,
+            pc: steps[producer].pc,
+            location,
+            value,
+            link: if source.is_some() {
+                Link::Moved
+            } else {
+                Link::Computed
+            },
+        });
+        match source {
+            Some(source) => (location, pos) = (source, producer),
+            None => break,
+        }
+    }
+    Ok(result)
+}
+
+/// An input of [`forward_taint`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TaintInput {
+    /// A general register by name
+    Reg(String),
+    Memory(Range<usize>),
+}
+
+/// A conditional branch depending on the inputs
+#[derive(Debug, Clone, Serialize)]
+pub struct TaintedBranch {
+    pub position: usize,
+    pub pc: usize,
+    pub taken: bool,
+}
+
+/// A memory write of the data depending on the inputs
+#[derive(Debug, Clone, Serialize)]
+pub struct TaintedWrite {
+    pub position: usize,
+    pub pc: usize,
+    pub address: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaintReport {
+    pub branches: Vec<TaintedBranch>,
+    pub writes: Vec<TaintedWrite>,
+    /// Count of the instructions reading the tainted data
+    pub instructions: usize,
+    /// Count of the instructions failed to decode, their destinations are left as they were
+    pub undecoded: usize,
+}
+
+impl TaintReport {
+    /// The distinct addresses of the tainted branches
+    pub fn branch_sites(&self) -> Vec<usize> {
+        let mut sites = self.branches.iter().map(|b| b.pc).collect::<Vec<_>>();
+        sites.sort_unstable();
+        sites.dedup();
+        sites
+    }
+}
+
+/// The tainted locations
+#[derive(Default)]
+struct TaintState {
+    /// Full registers by lowercase name
+    regs: HashSet<String>,
+    flags: bool,
+    memory: HashSet<usize>,
+}
+
+fn is_read(access: OpAccess) -> bool {
+    matches!(
+        access,
+        OpAccess::Read | OpAccess::CondRead | OpAccess::ReadWrite | OpAccess::ReadCondWrite
+    )
+}
+
+fn is_write(access: OpAccess) -> bool {
+    matches!(
+        access,
+        OpAccess::Write | OpAccess::CondWrite | OpAccess::ReadWrite | OpAccess::ReadCondWrite
+    )
+}
+
+/// The registers not tracked, or every push of a tainted value would taint the stack pointer
+fn is_pointer_reg(reg: Register) -> bool {
+    matches!(
+        reg.full_register(),
+        Register::RSP | Register::RIP | Register::ESP | Register::EIP
+    )
+}
+
+/// `xor reg, reg` and the like, whose result doesn't depend on the source
+fn is_zeroing(insn: &Instruction) -> bool {
+    matches!(
+        insn.mnemonic(),
+        Mnemonic::Xor | Mnemonic::Sub | Mnemonic::Pxor | Mnemonic::Xorps | Mnemonic::Xorpd
+    ) && insn.op_count() == 2
+        && insn.op0_kind() == OpKind::Register
+        && insn.op1_kind() == OpKind::Register
+        && insn.op0_register() == insn.op1_register()
+}
+
+/// Replay the trace of `reader` from its current position with the `inputs` tainted, the code is
+/// read from `code`, which should be the target recorded or a dump of it. It's not an emulation,
+/// see the module document for the limits
+pub fn forward_taint<T: ReadMemory + ?Sized>(
+    reader: &TraceReader,
+    code: &T,
+    inputs: &[TaintInput],
+) -> UDbgResult<TaintReport> {
+    const MAX_X86_INSN: usize = 15;
+
+    let arch = reader.arch();
+    let bitness = match arch {
+        ARCH_X86 => 32,
+        ARCH_X64 => 64,
+        _ => return Err(UDbgError::NotSupport),
+    };
+    let full_name = |r: Register| {
+        let full = if bitness == 32 {
+            r.full_register32()
+        } else {
+            r.full_register()
+        };
+        format!("{full:?}").to_lowercase()
+    };
+    let reg_ids = general_regs(arch)
+        .iter()
+        .copied()
+        .collect::<HashMap<_, _>>();
+
+    let mut taint = TaintState::default();
+    for input in inputs {
+        match input {
+            TaintInput::Reg(name) => {
+                let name = name.to_lowercase();
+                if !reg_ids.contains_key(name.as_str()) {
+                    return Err(format!("unknown register: {name}").into());
+                }
+                taint.regs.insert(name);
+            }
+            TaintInput::Memory(range) => taint.memory.extend(range.clone()),
+        }
+    }
+
+    let steps = reader.steps();
+    let start = reader.position();
+    let mut regs = HashMap::new();
+    for s in steps[..=start].iter() {
+        regs.extend(s.regs.iter().copied());
+    }
+
+    let mut report = TaintReport::default();
+    let mut factory = InstructionInfoFactory::new();
+    for position in start..steps.len() {
+        if position > start {
+            regs.extend(steps[position].regs.iter().copied());
+        }
+        let pc = steps[position].pc;
+        let bytes = code.read_bytes(pc, MAX_X86_INSN);
+        let insn = Decoder::with_ip(bitness, &bytes, pc as u64, DecoderOptions::NONE).decode();
+        if insn.is_invalid() {
+            report.undecoded += 1;
+            continue;
+        }
+
+        let value = |reg: Register, _: usize, _: usize| -> Option<u64> {
+            match reg {
+                Register::FS | Register::GS => None,
+                r if r.is_segment_register() => Some(0),
+                r => {
+                    let value = *regs.get(reg_ids.get(full_name(r).as_str())?)?;
+                    Some(match r.size() {
+                        4 => value & 0xFFFF_FFFF,
+                        2 => value & 0xFFFF,
+                        _ => value,
+                    })
+                }
+            }
+        };
+        let bytes_of = |m: &UsedMemory| {
+            let address = m.virtual_address(0, value)? as usize;
+            Some(address..address + m.memory_size().size())
+        };
+
+        let info = factory.info(&insn);
+        let tainted_regs = info
+            .used_registers()
+            .iter()
+            .filter(|r| !is_pointer_reg(r.register()))
+            .any(|r| is_read(r.access()) && taint.regs.contains(&full_name(r.register())));
+        let tainted_memory = info.used_memory().iter().any(|m| {
+            is_read(m.access())
+                && bytes_of(m).map_or(false, |mut r| r.any(|a| taint.memory.contains(&a)))
+        });
+        let reads_flags = insn.rflags_read() != 0;
+        let tainted =
+            (tainted_regs || tainted_memory || (reads_flags && taint.flags)) && !is_zeroing(&insn);
+        if tainted {
+            report.instructions += 1;
+        }
+
+        if insn.flow_control() == FlowControl::ConditionalBranch && reads_flags && taint.flags {
+            let next = steps.get(position + 1).map(|s| s.pc as u64);
+            report.branches.push(TaintedBranch {
+                position,
+                pc,
+                taken: next.map_or(false, |n| n != insn.next_ip()),
+            });
+        }
+
+        for r in info
+            .used_registers()
+            .iter()
+            .filter(|r| is_write(r.access()) && !is_pointer_reg(r.register()))
+        {
+            let name = full_name(r.register());
+            if tainted {
+                taint.regs.insert(name);
+            } else if r.access() == OpAccess::Write || r.access() == OpAccess::ReadWrite {
+                taint.regs.remove(&name);
+            }
+        }
+        for m in info.used_memory().iter().filter(|m| is_write(m.access())) {
+            let range = match bytes_of(m) {
+                Some(range) => range,
+                None => continue,
+            };
+            if tainted {
+                report.writes.push(TaintedWrite {
+                    position,
+                    pc,
+                    address: range.start,
+                    size: range.len(),
+                });
+                taint.memory.extend(range);
+            } else {
+                for a in range {
+                    taint.memory.remove(&a);
+                }
+            }
+        }
+        if insn.rflags_modified() != 0 {
+            taint.flags = tainted;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// mov r1, r2; mov [0x8000], r1; mov r3, [0x8000]
+    fn steps() -> Vec<TraceStep> {
+        vec![
+            TraceStep {
+                pc: 0x1000,
+                regs: vec![(1, 0), (2, 0x4141), (3, 0)],
+                writes: vec![],
+            },
+            TraceStep {
+                pc: 0x1003,
+                regs: vec![(1, 0x4141)],
+                writes: vec![],
+            },
+            TraceStep {
+                pc: 0x1006,
+                regs: vec![],
+                writes: vec![MemWrite {
+                    address: 0x8000,
+                    old: vec![0; 8],
+                    new: 0x4141u64.to_le_bytes().to_vec(),
+                }],
+            },
+            TraceStep {
+                pc: 0x1009,
+                regs: vec![(3, 0x4141)],
+                writes: vec![],
+            },
+        ]
+    }
+
+    #[test]
+    fn memory_value() {
+        let steps = steps();
+        assert_eq!(mem_at(&steps, 3, 0x8000, 8), Some((0x4141, Some(2))));
+        // the old value of the first write after
+        assert_eq!(mem_at(&steps, 1, 0x8000, 2), Some((0, None)));
+        // partly never written
+        assert_eq!(mem_at(&steps, 3, 0x8004, 8), None);
+    }
+
+    #[test]
+    fn source() {
+        let steps = steps();
+        let memory = Location::Memory {
+            address: 0x8000,
+            size: 8,
+        };
+        assert_eq!(
+            find_source(&steps, 2, Location::Reg(3), 0x4141),
+            Some(memory)
+        );
+        assert_eq!(
+            find_source(&steps, 1, memory, 0x4141),
+            Some(Location::Reg(1))
+        );
+        assert_eq!(
+            find_source(&steps, 0, Location::Reg(1), 0x4141),
+            Some(Location::Reg(2))
+        );
+        assert_eq!(find_source(&steps, 0, Location::Reg(1), 0x4242), None);
+    }
+}
@@ -3,7 +3,10 @@
 //!
 
 use crate::os::{priority_t, Module, Process};
-use crate::{pe::*, prelude::*, register::*};
+use crate::{
+    audit::AuditLog, bookmark::Bookmarks, comment::*, pe::*, prelude::*, readonly::ReadOnlyTarget,
+    register::*,
+};
 
 use core::ops::Deref;
 use parking_lot::RwLock;
@@ -60,10 +63,57 @@ pub struct TargetBase {
     pub arch: &'static str,
     /// Context architecture when target interruptted
     pub context_arch: Cell<u32>,
+    /// Byte order of target data, set by the engine when the target is attached or created
+    pub endian: Cell<Endian>,
     #[serde(skip)]
     pub flags: Cell<UDbgFlags>,
     #[serde(skip)]
     pub status: Cell<UDbgStatus>,
+    /// Audit log of the mutating operations, disabled by default
+    #[serde(skip)]
+    pub audit: Arc<AuditLog>,
+    /// Bookmarks and navigation history
+    #[serde(skip)]
+    pub bookmarks: Arc<Bookmarks>,
+    /// Comments attached to addresses
+    #[serde(skip)]
+    pub comments: Arc<Comments>,
+    /// Coalescing of the simultaneous breakpoint stops, disabled by default
+    #[serde(skip)]
+    pub stop_batch: Arc<crate::stopbatch::StopBatch>,
+    /// Capture of crash artifacts at second-chance exceptions, disabled by default
+    #[serde(skip)]
+    pub crash: Arc<crate::crash::CrashCapture>,
+    /// Software watchpoints by page protection
+    #[serde(skip)]
+    pub mem_watch: Arc<crate::memwatch::MemWatches>,
+    /// Event-rate guard of breakpoints, disabled by default
+    #[serde(skip)]
+    pub bp_rate: Arc<crate::throttle::RateGuard>,
+    /// Syscall tracing, disabled by default
+    #[serde(skip)]
+    pub syscall: Arc<crate::syscall::SyscallTrace>,
+    /// Suspend counts of the threads frozen by the crate
+    #[serde(skip)]
+    pub freeze: Arc<crate::freeze::ThreadFreezer>,
+    /// Value history of the watched registers and memory at each stop
+    #[serde(skip)]
+    pub timeline: Arc<crate::timeline::ValueTimeline>,
+    /// Module loads and unloads observed
+    #[serde(skip)]
+    pub module_loads: Arc<crate::modgraph::LoadLog>,
+    /// OEP heuristic of the packed modules, armed if [`UDbgFlags::AUTO_UNPACK`] is set
+    #[cfg(windows)]
+    #[serde(skip)]
+    pub oep: Arc<parking_lot::Mutex<crate::unpack::OepTracker>>,
+    /// Inline hooks handled by the callbacks in debugger
+    #[cfg(not(feature = "passive"))]
+    #[serde(skip)]
+    pub hooks: Arc<crate::hook::InlineHooks>,
+    /// Threads traced by single-stepping
+    #[cfg(not(feature = "passive"))]
+    #[serde(skip)]
+    pub step_trace: Arc<crate::steptrace::StepTracer>,
 }
 
 impl Default for TargetBase {
@@ -75,7 +125,25 @@ impl Default for TargetBase {
             flags: Default::default(),
             arch: std::env::consts::ARCH,
             context_arch: Cell::new(UDBG_ARCH),
+            endian: Cell::new(Endian::NATIVE),
             status: Cell::new(UDbgStatus::Opened),
+            audit: Default::default(),
+            bookmarks: Default::default(),
+            comments: Default::default(),
+            stop_batch: Default::default(),
+            crash: Default::default(),
+            mem_watch: Default::default(),
+            bp_rate: Default::default(),
+            syscall: Default::default(),
+            freeze: Default::default(),
+            timeline: Default::default(),
+            module_loads: Default::default(),
+            #[cfg(windows)]
+            oep: Default::default(),
+            #[cfg(not(feature = "passive"))]
+            hooks: Default::default(),
+            #[cfg(not(feature = "passive"))]
+            step_trace: Default::default(),
         }
     }
 }
@@ -100,6 +168,15 @@ impl TargetBase {
         }
     }
 
+    /// Endianness and pointer width of target data
+    #[inline]
+    pub fn data_layout(&self) -> DataLayout {
+        DataLayout {
+            endian: self.endian.get(),
+            pointer_size: self.pointer_size(),
+        }
+    }
+
     pub fn check_attached(&self) -> UDbgResult<()> {
         if self.status.get() < UDbgStatus::Attached {
             Err(UDbgError::NotAttached)
@@ -125,6 +202,8 @@ impl CommonBase {
     pub fn new(ps: Process) -> Self {
         let base = TargetBase::default();
         base.pid.set(ps.pid());
+        // the live targets run on this host
+        base.endian.set(Endian::NATIVE);
         Self {
             base,
             process: ps,
@@ -256,6 +335,38 @@ pub trait UDbgThread: Deref<Target = ThreadData> + GetProp {
     fn last_error(&self) -> Option<u32> {
         None
     }
+
+    /// Get integer register value by name, through the "@reg" property
+    fn reg_value(&self, name: &str) -> UDbgResult<usize> {
+        use serde_value::Value;
+
+        match self.get_prop(&format!("@{name}"))? {
+            Value::U64(v) => Ok(v as usize),
+            Value::U32(v) => Ok(v as usize),
+            _ => Err(UDbgError::InvalidRegister),
+        }
+    }
+
+    /// Walk the raw stack memory of thread in `target` from its stack pointer, classify each slot
+    /// (return address, heap pointer, string, etc.), useful when unwinding fails
+    fn stack_scan(
+        &self,
+        target: &dyn UDbgTarget,
+        max_slots: usize,
+    ) -> UDbgResult<Vec<crate::annotate::StackSlot>> {
+        let sp = self.reg_value("_sp")?;
+        Ok(crate::annotate::Annotator::new(target).stack_scan(sp, max_slots))
+    }
+
+    /// Unwind the call stack of this thread in `target` from the innermost frame, the thread must
+    /// be stopped, see [`crate::unwind`]
+    fn stack_trace(
+        &self,
+        target: &dyn UDbgTarget,
+        max_depth: usize,
+    ) -> UDbgResult<Vec<crate::unwind::CallFrame>> {
+        crate::unwind::stack_trace(target, self, max_depth)
+    }
 }
 
 impl core::fmt::Debug for dyn UDbgThread {
@@ -272,6 +383,55 @@ impl core::fmt::Debug for dyn UDbgThread {
     }
 }
 
+thread_local! {
+    static IN_EVENT_LOOP: Cell<bool> = Cell::new(false);
+}
+
+/// Mark current thread as running a debug event loop, to detect the reentrancy from event handlers
+pub struct EventLoopGuard(());
+
+impl EventLoopGuard {
+    /// Enter the event loop, fails if current thread is already in one
+    pub fn enter() -> UDbgResult<Self> {
+        if IN_EVENT_LOOP.with(|x| x.replace(true)) {
+            return Err(UDbgError::Reentrant);
+        }
+        Ok(Self(()))
+    }
+
+    /// If current thread is in a debug event loop, i.e. called from event handler
+    #[inline]
+    pub fn is_active() -> bool {
+        IN_EVENT_LOOP.with(Cell::get)
+    }
+
+    /// Fails if called from event handler, for the operations which would deadlock there
+    #[inline]
+    pub fn check_outside() -> UDbgResult<()> {
+        if Self::is_active() {
+            Err(UDbgError::Reentrant)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for EventLoopGuard {
+    fn drop(&mut self) {
+        IN_EVENT_LOOP.with(|x| x.set(false));
+    }
+}
+
+/// Refuse to debug the current process by external engines
+#[inline]
+pub fn check_self_debug(pid: pid_t) -> UDbgResult<()> {
+    if pid as u32 == std::process::id() {
+        Err(UDbgError::SelfDebug)
+    } else {
+        Ok(())
+    }
+}
+
 /// Debugger Engine, interfaces of debugging
 pub trait UDbgEngine {
     fn enum_process(&self) -> UDbgResult<Box<dyn Iterator<Item = ProcessInfo>>> {
@@ -287,6 +447,11 @@ pub trait UDbgEngine {
         self.open(std::process::id() as _)
     }
 
+    /// Open a process non-invasively, the returned target could only be observed
+    fn open_readonly(&mut self, pid: pid_t) -> UDbgResult<ReadOnlyTarget> {
+        self.open(pid).map(ReadOnlyTarget::new)
+    }
+
     /// Attach to a active process
     fn attach(&mut self, pid: pid_t) -> UDbgResult<Arc<dyn UDbgTarget>>;
 
@@ -469,20 +634,79 @@ pub trait TargetUtil: UDbgTarget {
         self.add_breakpoint(opt.into())
     }
 
-    fn read_ptr(&self, a: usize) -> Option<usize> {
-        if self.base().is_ptr32() {
-            self.read_value::<u32>(a).map(|r| r as usize)
-        } else {
-            self.read_value::<u64>(a).map(|r| r as usize)
+    /// Add a hardware breakpoint for all threads, watching `size` bytes at `address`,
+    /// the debug register is allocated automatically
+    fn add_hw_bp(&self, address: usize, size: usize, kind: HwbpType) -> UDbgResult<HwBreakpoint> {
+        self.add_hw_bp_for(None, address, size, kind)
+    }
+
+    /// Add a hardware breakpoint for only one thread if `tid` specified
+    fn add_hw_bp_for(
+        &self,
+        tid: Option<tid_t>,
+        address: usize,
+        size: usize,
+        kind: HwbpType,
+    ) -> UDbgResult<HwBreakpoint> {
+        let len = HwbpLen::from_size(size)
+            .filter(|_| size <= self.base().pointer_size())
+            .ok_or_else(|| format!("invalid hardware breakpoint size: {size}"))?;
+        if address % size != 0 {
+            return Err(format!("{address:x} is not aligned to {size}").into());
+        }
+        if IS_X86 && matches!(kind, HwbpType::Execute) && size != 1 {
+            return Err("execute breakpoint must be 1 byte".into());
         }
+
+        let mut opt = BpOpt::hwbp(address, kind, Some(len));
+        opt.tid = tid;
+        let bp = self.add_breakpoint(opt)?;
+        // the hardware breakpoints are also indexed by -(slot + 1)
+        let slot = (0..4)
+            .find(|i| {
+                self.get_breakpoint(-(*i as BpID + 1))
+                    .map_or(false, |b| b.get_id() == bp.get_id())
+            })
+            .unwrap_or_default();
+        Ok(HwBreakpoint {
+            bp,
+            kind,
+            size,
+            slot,
+            tid,
+        })
+    }
+
+    /// Count of the unused debug registers
+    fn free_hw_slots(&self) -> usize {
+        (0..4)
+            .filter(|i| self.get_breakpoint(-(*i as BpID + 1)).is_none())
+            .count()
+    }
+
+    /// Scan the readable memory lazily, see [`crate::scan`] for the matchers
+    fn scan_memory<M: crate::scan::Matcher>(
+        &self,
+        matcher: M,
+    ) -> UDbgResult<crate::scan::ScanIter<'_, Self, M>> {
+        let regions = crate::scan::readable_regions(self)?;
+        Ok(crate::scan::ScanIter::new(self, regions, matcher, 0x100000))
+    }
+
+    /// Scan the readable memory by an IDA-style pattern, such as `48 8B ?? ?? E8`
+    fn scan_pattern(
+        &self,
+        pattern: &str,
+    ) -> UDbgResult<crate::scan::ScanIter<'_, Self, crate::scan::BytePattern>> {
+        self.scan_memory(pattern.parse::<crate::scan::BytePattern>()?)
+    }
+
+    fn read_ptr(&self, a: usize) -> Option<usize> {
+        self.read_pointer(a, self.base().data_layout())
     }
 
     fn write_ptr(&self, a: usize, p: usize) -> Option<usize> {
-        if self.base().is_ptr32() {
-            self.write_value(a, &(p as u32))
-        } else {
-            self.write_value(a, &(p as u64))
-        }
+        self.write_pointer(a, p, self.base().data_layout())
     }
 
     fn read_argument(
@@ -507,6 +731,48 @@ pub trait TargetUtil: UDbgTarget {
         self.get_symbol_(addr, None).map(|s| s.to_string(addr))
     }
 
+    /// The functions inlined at address, from the outermost, by the symbol file of module
+    fn inline_frames(&self, address: usize) -> Vec<crate::symbol::InlineFrame> {
+        self.find_module(address)
+            .and_then(|m| {
+                let offset = address - m.data().base;
+                Some(m.symbol_file()?.inline_frames(offset as u32))
+            })
+            .unwrap_or_default()
+    }
+
+    /// Start of the function which contains `address`, by the unwind table on Windows, or the nearest symbol
+    fn function_start(&self, address: usize) -> Option<usize> {
+        #[cfg(windows)]
+        if let Some(m) = self.find_module(address) {
+            let base = m.data().base;
+            if let Some(f) = m.find_function(address - base) {
+                return Some(base + f.BeginAddress as usize);
+            }
+        }
+        self.get_symbol_(address, Some(0x10000))
+            .filter(|s| !s.symbol.is_empty())
+            .map(|s| address - s.offset)
+    }
+
+    /// Range of the function which contains `address`, by the unwind table on Windows, or the symbol with length
+    fn function_range(&self, address: usize) -> Option<core::ops::Range<usize>> {
+        let m = self.find_module(address)?;
+        let base = m.data().base;
+        #[cfg(windows)]
+        if let Some(f) = m.find_function(address - base) {
+            #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+            let end = f.EndAddress;
+            #[cfg(any(target_arch = "aarch64"))]
+            let end = f.BeginAddress + f.FunctionLength();
+            return Some(base + f.BeginAddress as usize..base + end as usize);
+        }
+        m.find_symbol(address - base, 0x10000)
+            .filter(|s| s.len != SYM_NOLEN)
+            .map(|s| base + s.offset as usize..base + (s.offset + s.len) as usize)
+            .filter(|r| r.contains(&address))
+    }
+
     fn get_symbol_module_info(&self, addr: usize) -> Option<String> {
         self.find_module(addr).map(|m| {
             let data = m.data();
@@ -591,12 +857,489 @@ pub trait TargetUtil: UDbgTarget {
     fn pid(&self) -> pid_t {
         self.base().pid.get()
     }
+
+    /// Scan the stack of a thread in this target, the same as [`UDbgThread::stack_scan`]
+    fn stack_scan(
+        &self,
+        thread: &dyn UDbgThread,
+        max_slots: usize,
+    ) -> UDbgResult<Vec<crate::annotate::StackSlot>> {
+        let sp = thread.reg_value("_sp")?;
+        Ok(crate::annotate::Annotator::new(self).stack_scan(sp, max_slots))
+    }
+
+    /// Watch the access to a range by page protection, for the ranges too large for the hardware
+    /// breakpoints, see [`crate::memwatch`]
+    #[cfg(not(feature = "passive"))]
+    fn add_mem_watch(
+        &self,
+        range: core::ops::Range<usize>,
+        access: crate::memwatch::WatchAccess,
+    ) -> UDbgResult<usize> {
+        self.base().mem_watch.add(self, range, access)
+    }
+
+    #[cfg(not(feature = "passive"))]
+    fn remove_mem_watch(&self, id: usize) -> UDbgResult<()> {
+        self.base().mem_watch.remove(self, id)
+    }
+
+    /// Capture the writable memory, thread contexts and handles of stopped target, see
+    /// [`crate::snapshot`]
+    #[cfg(not(feature = "passive"))]
+    fn snapshot(&self) -> UDbgResult<crate::snapshot::ProcessSnapshot> {
+        crate::snapshot::ProcessSnapshot::capture(self)
+    }
+
+    /// Roll the target back to the snapshot
+    #[cfg(not(feature = "passive"))]
+    fn restore(
+        &self,
+        snapshot: &crate::snapshot::ProcessSnapshot,
+    ) -> UDbgResult<crate::snapshot::RestoreReport> {
+        snapshot.restore(self)
+    }
+
+    /// Find the pointers to an object in `range`, aligned with `align`, see [`crate::refs`]
+    fn find_references(
+        &self,
+        range: core::ops::Range<usize>,
+        align: usize,
+    ) -> UDbgResult<Vec<crate::refs::Reference>> {
+        crate::refs::find_references(self, range, align, None)
+    }
+
+    /// Find the instances of a C++ class by its vtable, see [`crate::vtable`]
+    fn find_instances(&self, vtable: usize) -> UDbgResult<Vec<crate::vtable::Instance>> {
+        crate::vtable::find_instances(self, vtable, None)
+    }
+
+    /// Disassemble `count` instructions with the symbolized operands, see [`crate::disasm`]. It's
+    /// not named `disasm`, which is kept by the deprecated [`TargetArchUtil::disasm`]
+    fn disassemble(&self, address: usize, count: usize) -> UDbgResult<Vec<crate::disasm::Insn>> {
+        crate::disasm::disasm(self, address, count)
+    }
+
+    /// Address next to the call, syscall or `rep` instruction at `address`, where stepping over it
+    /// should break
+    fn check_call(&self, address: usize) -> Option<usize> {
+        let insn = self.disassemble(address, 1).ok()?.pop()?;
+        insn.is_step_over().then(|| insn.next())
+    }
+
+    /// Dependency graph and load order of the modules, see [`crate::modgraph`]
+    fn module_graph(&self) -> UDbgResult<crate::modgraph::ModuleGraph> {
+        crate::modgraph::ModuleGraph::build(self)
+    }
+
+    /// Diagnose why `library` failed to load, see [`crate::loadfail`]
+    #[cfg(windows)]
+    fn diagnose_load(
+        &self,
+        library: &str,
+        error: Option<u32>,
+    ) -> UDbgResult<crate::loadfail::LoadDiagnosis> {
+        crate::loadfail::diagnose(self, library, error)
+    }
+
+    /// Check the modules for the patched code, IAT and EAT hooks, see [`crate::integrity`]
+    fn detect_hooks(&self) -> Vec<crate::integrity::DetectedHook> {
+        crate::integrity::detect_hooks(self)
+    }
+
+    /// The export and import tables of the image loaded at `base`, see [`crate::image`]
+    fn image(&self, base: usize) -> UDbgResult<crate::image::RemoteImage<'_, Self>> {
+        crate::image::RemoteImage::new(self, base)
+    }
+
+    /// Enumerate the busy heap blocks, to diff with another later, see [`crate::heap`]
+    fn heap_snapshot(&self) -> UDbgResult<crate::heap::HeapSnapshot> {
+        crate::heap::HeapSnapshot::capture(self)
+    }
+
+    /// Typed accessor of the PEB, see [`crate::peb`]
+    #[cfg(windows)]
+    fn peb(&self) -> UDbgResult<crate::peb::Peb<'_, Self>> {
+        crate::peb::Peb::of(self)
+    }
+
+    /// Typed accessor of the TEB of a thread, see [`crate::peb`]
+    #[cfg(windows)]
+    fn thread_teb(&self, thread: &dyn UDbgThread) -> UDbgResult<crate::peb::Teb<'_, Self>> {
+        crate::peb::Teb::of(self, thread)
+    }
+
+    /// Enable or disable the syscall tracing, see [`crate::syscall`]
+    #[cfg(not(feature = "passive"))]
+    fn trace_syscalls(&self, enable: bool) -> UDbgResult<()> {
+        self.base().syscall.enable(self, enable)
+    }
+
+    /// Unwind the call stack of a stopped thread, the same as [`UDbgThread::stack_trace`]
+    fn stack_trace(
+        &self,
+        thread: &dyn UDbgThread,
+        max_depth: usize,
+    ) -> UDbgResult<Vec<crate::unwind::CallFrame>> {
+        crate::unwind::stack_trace(self, thread, max_depth)
+    }
+
+    /// Suspend all threads of target a level by [`crate::freeze`] while running `f`, so it nests
+    /// in a [`TargetUtil::freeze`]. If a thread fails to suspend, the ones suspended are resumed
+    /// and `f` is not run
+    #[cfg(not(feature = "passive"))]
+    fn with_suspended<R>(
+        &self,
+        f: impl FnOnce(&[Box<dyn UDbgThread>]) -> UDbgResult<R>,
+    ) -> UDbgResult<R> {
+        let freeze = &self.base().freeze;
+        let threads = self.enum_thread(false)?.collect::<Vec<_>>();
+        let mut suspended = vec![];
+        let mut failed = None;
+        for t in threads.iter() {
+            match freeze.suspend_thread(t.as_ref()) {
+                Ok(_) => suspended.push(t),
+                Err(err) => {
+                    failed = Some(format!("suspend ~{}: {err:?}", t.tid));
+                    break;
+                }
+            }
+        }
+
+        let result = match failed {
+            Some(err) => Err(err.into()),
+            None => f(&threads),
+        };
+
+        for t in suspended {
+            freeze
+                .resume_thread(t.as_ref())
+                .log_error_with(|err| format!("resume ~{}: {err:?}", t.tid));
+        }
+        result
+    }
+
+    /// Suspend all threads a level with the counts tracked by the crate, returns the threads
+    /// suspended, see [`crate::freeze`]
+    #[cfg(not(feature = "passive"))]
+    fn suspend_all(&self) -> UDbgResult<Vec<tid_t>> {
+        self.base().freeze.suspend_all(self)
+    }
+
+    /// Resume every thread suspended by the crate a level, returns the count of them
+    #[cfg(not(feature = "passive"))]
+    fn resume_all(&self) -> usize {
+        self.base().freeze.resume_all(self)
+    }
+
+    /// Suspend a thread a level with the count tracked by the crate, returns its count
+    #[cfg(not(feature = "passive"))]
+    fn suspend_thread(&self, tid: tid_t) -> UDbgResult<usize> {
+        self.base().freeze.suspend(self, tid)
+    }
+
+    /// Resume a thread suspended by the crate a level, returns its count
+    #[cfg(not(feature = "passive"))]
+    fn resume_thread(&self, tid: tid_t) -> UDbgResult<usize> {
+        self.base().freeze.resume(self, tid)
+    }
+
+    /// Create a thread at `address` in target, and wait for its exit if `wait`, see
+    /// [`crate::inject`]
+    #[cfg(not(feature = "passive"))]
+    fn create_remote_thread(
+        &self,
+        address: usize,
+        param: usize,
+        wait: Option<core::time::Duration>,
+    ) -> UDbgResult<crate::inject::RemoteThread> {
+        crate::inject::create_remote_thread(self, address, param, wait)
+    }
+
+    /// Run `code` by a new thread from `entry_offset`
+    #[cfg(not(feature = "passive"))]
+    fn inject_code(
+        &self,
+        code: &[u8],
+        entry_offset: usize,
+        param: usize,
+        wait: Option<core::time::Duration>,
+    ) -> UDbgResult<crate::inject::RemoteThread> {
+        crate::inject::inject_code(self, code, entry_offset, param, wait)
+    }
+
+    /// Load a library into target by the loader of system, see [`crate::inject::inject_library`]
+    #[cfg(not(feature = "passive"))]
+    fn inject_library(
+        &self,
+        path: &str,
+        wait: Option<core::time::Duration>,
+    ) -> UDbgResult<crate::inject::InjectedLibrary> {
+        crate::inject::inject_library(self, path, wait)
+    }
+
+    /// Hook the function at `address` by a callback in debugger, see [`crate::hook`]
+    #[cfg(not(feature = "passive"))]
+    fn hook_function(
+        &self,
+        address: usize,
+        callback: impl FnMut(&mut crate::hook::HookCall<'_>) -> crate::hook::HookAction + 'static,
+    ) -> UDbgResult<crate::hook::HookId> {
+        self.base().hooks.install(self, address, callback)
+    }
+
+    #[cfg(not(feature = "passive"))]
+    fn unhook_function(&self, id: crate::hook::HookId) -> UDbgResult<()> {
+        self.base().hooks.remove(self, id)
+    }
+
+    /// Single-step the thread `tid`, calling back each instruction until it returns
+    /// [`crate::steptrace::StepAction::Stop`], see [`crate::steptrace`]
+    #[cfg(not(feature = "passive"))]
+    fn trace(
+        &self,
+        tid: tid_t,
+        callback: impl FnMut(&mut crate::steptrace::StepInsn<'_>) -> crate::steptrace::StepAction
+            + 'static,
+    ) -> UDbgResult<()> {
+        self.base().step_trace.start(tid, Box::new(callback))
+    }
+
+    /// Suspend all threads until the guard is dropped
+    #[cfg(not(feature = "passive"))]
+    fn freeze(&self) -> UDbgResult<crate::freeze::FreezeGuard<'_, Self>> {
+        crate::freeze::FreezeGuard::new(self)
+    }
+
+    /// Patch code safely: suspend all threads, ensure no thread is executing in the patch range
+    /// (and no return address on its stack points into it, if `check_stack`),
+    /// then write the bytes, flush the instruction cache and resume the threads. Nothing is written
+    /// if a thread can't be suspended or its pc can't be read
+    #[cfg(not(feature = "passive"))]
+    fn safe_patch(&self, address: usize, data: &[u8], check_stack: bool) -> UDbgResult<()> {
+        const STACK_SLOTS: usize = 0x400;
+
+        let range = address..address + data.len();
+        self.with_suspended(|threads| {
+            for t in threads.iter() {
+                match t.reg_value("_pc") {
+                    Ok(pc) if range.contains(&pc) => {
+                        return Err(format!("~{} is executing at {pc:x}", t.tid).into())
+                    }
+                    Ok(_) => {}
+                    Err(err) => return Err(format!("get pc of ~{}: {err:?}", t.tid).into()),
+                }
+                if check_stack {
+                    if let Some(slot) = self
+                        .stack_scan(t.as_ref(), STACK_SLOTS)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .find(|s| s.is_return_address() && range.contains(&s.value))
+                    {
+                        return Err(format!(
+                            "return address {:x} of ~{} is in patch range",
+                            slot.value, t.tid
+                        )
+                        .into());
+                    }
+                }
+            }
+
+            let written = self
+                .write_code(address, data)
+                .ok_or(UDbgError::MemoryError)?;
+            if written < data.len() {
+                return Err(UDbgError::MemoryError);
+            }
+            Ok(())
+        })
+    }
+
+    /// Ensure the whole range is in the committed and writable regions
+    fn check_writable(&self, address: usize, size: usize) -> UDbgResult<()> {
+        let end = address.checked_add(size).ok_or(UDbgError::InvalidAddress)?;
+        let mut cur = address;
+        while cur < end {
+            let page = self.virtual_query(cur).ok_or(UDbgError::InvalidAddress)?;
+            if !page.is_commit() || !page.is_writable() {
+                return Err(UDbgError::AccessDenied(format!(
+                    "{:x} is {}",
+                    page.base,
+                    page.protect()
+                )));
+            }
+            if page.base + page.size <= cur {
+                return Err(UDbgError::InvalidAddress);
+            }
+            cur = page.base + page.size;
+        }
+        Ok(())
+    }
+
+    /// Write a value after checking the protection and size of the destination,
+    /// and read it back to verify if `verify`; the write is recorded in [`TargetBase::audit`] like the others
+    #[cfg(not(feature = "passive"))]
+    fn write_value_checked<V: Copy>(
+        &self,
+        address: usize,
+        val: &V,
+        verify: bool,
+    ) -> UDbgResult<()> {
+        let data = val.as_byte_array();
+        self.check_writable(address, data.len())?;
+        let written = self
+            .write_memory(address, data)
+            .ok_or(UDbgError::MemoryError)?;
+        if written < data.len() {
+            return Err(UDbgError::MemoryError);
+        }
+        if verify && self.read_bytes(address, data.len()) != data {
+            return Err(format!("{address:x} differs from the written value").into());
+        }
+        Ok(())
+    }
+
+    /// Write `new` only if the current value is `expected`, returns false if not. The threads are suspended
+    /// between the compare and the write, so the live code of target can't change the value in the meantime.
+    /// Fails without comparing if a thread can't be suspended, see [`TargetUtil::with_suspended`]
+    #[cfg(not(feature = "passive"))]
+    fn compare_and_swap<V: Copy>(&self, address: usize, expected: &V, new: &V) -> UDbgResult<bool> {
+        self.with_suspended(|_| {
+            let current = self.read_bytes(address, core::mem::size_of::<V>());
+            if current != expected.as_byte_array() {
+                return Ok(false);
+            }
+            self.write_value_checked(address, new, true)?;
+            Ok(true)
+        })
+    }
+
+    /// Write many chunks in batches, e.g. a patch set or a snapshot to restore. The chunks touching the same
+    /// or adjacent pages are grouped, on Windows the read-only regions of a group are made writable once
+    /// and restored after all its chunks are written. Returns the result of each chunk in the order of `chunks`
+    #[cfg(not(feature = "passive"))]
+    fn write_memory_chunks(&self, chunks: &[(usize, &[u8])]) -> Vec<UDbgResult<usize>> {
+        const PAGE_MASK: usize = 0xFFF;
+
+        let mut results = chunks
+            .iter()
+            .map(|_| Ok(0))
+            .collect::<Vec<UDbgResult<usize>>>();
+        let mut order = (0..chunks.len())
+            .filter(|&i| !chunks[i].1.is_empty())
+            .collect::<Vec<_>>();
+        order.sort_by_key(|&i| chunks[i].0);
+
+        let mut rest = order.as_slice();
+        while let Some(&first) = rest.first() {
+            let start = chunks[first].0 & !PAGE_MASK;
+            let mut end = start;
+            let count = rest
+                .iter()
+                .take_while(|&&i| {
+                    let (address, data) = chunks[i];
+                    if address & !PAGE_MASK > end {
+                        return false;
+                    }
+                    end = end.max(address.saturating_add(data.len() + PAGE_MASK) & !PAGE_MASK);
+                    true
+                })
+                .count();
+            let (batch, next) = rest.split_at(count);
+            rest = next;
+
+            let mut pages = vec![];
+            let mut cur = start;
+            while cur < end {
+                match self.virtual_query(cur) {
+                    Some(p) if p.base + p.size > cur => {
+                        cur = p.base + p.size;
+                        pages.push(p);
+                    }
+                    _ => break,
+                }
+            }
+
+            #[cfg(windows)]
+            let restore = self.process().map_or(vec![], |process| {
+                pages
+                    .iter()
+                    .filter(|p| p.is_commit() && !p.is_writable())
+                    .filter_map(|p| {
+                        let base = p.base.max(start);
+                        let size = (p.base + p.size).min(end) - base;
+                        let protect = if p.is_executable() {
+                            PAGE_EXECUTE_READWRITE
+                        } else {
+                            PAGE_READWRITE
+                        };
+                        let old = process.protect_memory(base, size, protect);
+                        if old.is_none() {
+                            warn!("protect {base:x} {size:x} failed");
+                        }
+                        Some((base, size, old?))
+                    })
+                    .collect::<Vec<_>>()
+            });
+
+            for &i in batch {
+                let (address, data) = chunks[i];
+                let code = pages
+                    .iter()
+                    .find(|p| p.base <= address && address < p.base + p.size)
+                    .map_or(false, MemoryPage::is_executable);
+                let written = if code {
+                    self.write_code(address, data)
+                } else {
+                    self.write_memory(address, data)
+                };
+                results[i] = match written {
+                    Some(n) if n == data.len() => Ok(n),
+                    Some(n) => {
+                        Err(format!("{address:x}: {n:x} of {:x} written", data.len()).into())
+                    }
+                    None => Err(UDbgError::MemoryError),
+                };
+            }
+
+            #[cfg(windows)]
+            if let Some(process) = self.process() {
+                for (base, size, old) in restore {
+                    process.protect_memory(base, size, old);
+                }
+            }
+        }
+        results
+    }
+
+    /// Regions which are views of shared memory, see [`SharedRegion::shared_with`] to follow the processes sharing them
+    fn shared_regions(&self) -> UDbgResult<Vec<SharedRegion<'_>>> {
+        let process = self.process().ok_or(UDbgError::NotSupport)?;
+        Ok(self
+            .enum_memory()?
+            .filter(MemoryPage::is_shared)
+            .map(|page| SharedRegion { page, process })
+            .collect())
+    }
 }
 impl<'a, T: UDbgTarget + ?Sized + 'a> TargetUtil for T {}
 
+/// Reader of the arguments at the entry of a call, by 1-based index as [`TargetUtil::read_argument`],
+/// the APIs are stdcall on x86
+pub(crate) fn call_args<'a>(
+    target: &'a dyn UDbgTarget,
+    regs: &'a dyn UDbgRegs,
+    arch: u32,
+) -> impl Fn(usize) -> Option<usize> + 'a {
+    let cc = (arch == ARCH_X86).then(|| CallingConv::StdCall);
+    move |i| target.read_argument(regs, i, cc)
+}
+
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub trait TargetArchUtil: UDbgTarget {
-    fn disasm(&self, address: usize) -> Option<iced_x86::Instruction> {
+    /// Decode the instruction at `address` by iced, see [`TargetUtil::disassemble`] for the
+    /// structured and symbolized one
+    fn decode_insn(&self, address: usize) -> Option<iced_x86::Instruction> {
         use iced_x86::{Decoder, DecoderOptions, Instruction};
 
         let buffer = self.read_bytes(address, MAX_INSN_SIZE);
@@ -614,25 +1357,69 @@ pub trait TargetArchUtil: UDbgTarget {
         }
     }
 
-    #[inline(always)]
-    fn check_call(&self, address: usize) -> Option<usize> {
-        use iced_x86::Mnemonic::*;
+    #[deprecated(note = "renamed to `decode_insn`")]
+    fn disasm(&self, address: usize) -> Option<iced_x86::Instruction> {
+        self.decode_insn(address)
+    }
 
-        self.disasm(address).and_then(|insn| {
-            if matches!(insn.mnemonic(), Call | Syscall | Sysenter) || insn.has_rep_prefix() {
-                Some(address + insn.len())
-            } else {
-                None
-            }
+    /// Disassemble `count` instructions, with the symbols and comments inline
+    fn disasm_lines(&self, address: usize, count: usize) -> Vec<DisasmLine> {
+        use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter};
+
+        // show the original bytes under the software breakpoints
+        let buffer = crate::disasm::read_code(self, address, count * MAX_INSN_SIZE);
+
+        let bitness = if self.base().is_ptr32() { 32 } else { 64 };
+        let mut decoder = Decoder::with_ip(bitness, &buffer, address as u64, DecoderOptions::NONE);
+        let mut formatter = IntelFormatter::new();
+        let mut insn = Instruction::default();
+        let mut result = Vec::with_capacity(count);
+        while result.len() < count && decoder.can_decode() {
+            let offset = decoder.position();
+            decoder.decode_out(&mut insn);
+            let mut text = String::new();
+            formatter.format(&insn, &mut text);
+            let bytes = buffer[offset..offset + insn.len()].to_vec();
+            result.push(DisasmLine::new(self, insn.ip() as usize, bytes, text));
+        }
+        result
+    }
+
+    /// Check if the address follows a call instruction
+    fn is_return_address(&self, address: usize) -> bool {
+        use iced_x86::{Decoder, DecoderOptions, Mnemonic};
+        const MAX_CALL_SIZE: usize = 8;
+
+        let mut buf = [0u8; MAX_CALL_SIZE];
+        if self
+            .read_memory(address.wrapping_sub(MAX_CALL_SIZE), &mut buf)
+            .map(|r| r.len())
+            != Some(MAX_CALL_SIZE)
+        {
+            return false;
+        }
+        let bitness = if self.base().is_ptr32() { 32 } else { 64 };
+        (2..=MAX_CALL_SIZE).any(|len| {
+            let insn =
+                Decoder::new(bitness, &buf[MAX_CALL_SIZE - len..], DecoderOptions::NONE).decode();
+            !insn.is_invalid() && insn.len() == len && insn.mnemonic() == Mnemonic::Call
         })
     }
 }
 
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 pub trait TargetArchUtil: UDbgTarget {
-    #[inline(always)]
-    fn check_call(&self, address: usize) -> Option<usize> {
-        todo!();
+    /// Check if the address follows a BL/BLR(BLX) instruction
+    fn is_return_address(&self, address: usize) -> bool {
+        let insn = match self.read_value::<u32>(address.wrapping_sub(4)) {
+            Some(insn) => insn,
+            None => return false,
+        };
+        if self.base().is_ptr32() {
+            insn & 0x0F000000 == 0x0B000000 || insn & 0x0FFFFFF0 == 0x012FFF30
+        } else {
+            insn & 0xFC000000 == 0x94000000 || insn & 0xFFFFFC1F == 0xD63F0000
+        }
     }
 }
 
@@ -660,6 +1447,61 @@ pub trait TraceContext {
             _ => core::mem::size_of::<usize>(),
         }
     }
+
+    /// Annotate the general registers by the symbol or memory region they point to, see
+    /// [`crate::annotate`]
+    fn annotated(&mut self) -> Vec<crate::annotate::RegAnnotation> {
+        let arch = self.arch();
+        let target = self.target();
+        let annotator = crate::annotate::Annotator::new(target.as_ref());
+        self.register()
+            .map(|regs| annotator.annotate_regs(regs, arch))
+            .unwrap_or_default()
+    }
+}
+
+/// View of the same shared memory in another process
+#[derive(Debug, Clone, Serialize)]
+pub struct SharedView {
+    pub pid: pid_t,
+    /// Handle of the section in that process, on Windows
+    pub handle: Option<usize>,
+    /// Base address of the view in that process, if known
+    pub address: Option<usize>,
+}
+
+/// Memory region of target which is a view of shared memory
+pub struct SharedRegion<'a> {
+    pub page: MemoryPage,
+    process: &'a Process,
+}
+
+impl Deref for SharedRegion<'_> {
+    type Target = MemoryPage;
+
+    #[inline]
+    fn deref(&self) -> &MemoryPage {
+        &self.page
+    }
+}
+
+impl SharedRegion<'_> {
+    /// Other processes which map the same shared memory
+    pub fn shared_with(&self) -> UDbgResult<Vec<SharedView>> {
+        cfg_if! {
+            if #[cfg(any(windows, target_os = "linux", target_os = "android"))] {
+                self.process.shared_with(self.page.base)
+            } else {
+                Err(UDbgError::NotSupport)
+            }
+        }
+    }
+
+    /// The section which this view is mapped from
+    #[cfg(windows)]
+    pub fn section(&self) -> Option<crate::os::object::KernelObject> {
+        self.process.query_address_object(self.page.base)
+    }
 }
 
 impl MemoryPage {
@@ -743,9 +1585,14 @@ impl MemoryPage {
         }
     }
 
+    /// If it's a view of shared memory, a mapped section on Windows, or `MAP_SHARED` mapping on unix
     #[inline]
     pub fn is_shared(&self) -> bool {
-        self.as_linux_protect()[3] == b's'
+        if self.is_windows() {
+            self.type_ == MEM_MAPPED
+        } else {
+            self.as_linux_protect()[3] == b's'
+        }
     }
 
     pub fn is_executable(&self) -> bool {
@@ -764,6 +1611,17 @@ impl MemoryPage {
         }
     }
 
+    pub fn is_readable(&self) -> bool {
+        if self.is_windows() {
+            self.is_commit()
+                && self.protect & PAGE_GUARD == 0
+                && self.protect & (PAGE_NOACCESS | PAGE_EXECUTE) == 0
+                && self.protect > 0
+        } else {
+            self.as_linux_protect()[0] == b'r'
+        }
+    }
+
     pub fn is_readonly(&self) -> bool {
         if self.is_windows() {
             self.protect == PAGE_READONLY
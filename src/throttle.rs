@@ -0,0 +1,221 @@
+//!
+//! Event-rate guard of breakpoints, the safety valve for breakpoints on hot paths.
+//!
+//! When a [`RateLimit`] is set to [`TargetBase::bp_rate`], a breakpoint hit more than
+//! [`RateLimit::max_hits`] times in [`RateLimit::window`] is demoted: its hits are only counted
+//! and not passed to the user callback, or it's disabled. The user callback is notified by a
+//! [`BpDemoted`] event instead of the breakpoint event which exceeds the limit.
+//!
+
+use crate::prelude::*;
+
+use core::time::Duration;
+use spin::Mutex;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Kind of the custom event which carries [`BpDemoted`]
+pub const BP_DEMOTED_EVENT: &str = "bp_demoted";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DemoteAction {
+    /// Keep the breakpoint, count the hits without stopping
+    CountOnly,
+    Disable,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub max_hits: usize,
+    pub window: Duration,
+    pub action: DemoteAction,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            max_hits: 1000,
+            window: Duration::from_secs(1),
+            action: DemoteAction::CountOnly,
+        }
+    }
+}
+
+/// A breakpoint was demoted for exceeding the rate limit
+#[derive(Debug, Clone, Serialize)]
+pub struct BpDemoted {
+    pub id: BpID,
+    pub address: usize,
+    /// Hits in the window when it's demoted
+    pub hits: usize,
+    pub window: Duration,
+    pub action: DemoteAction,
+}
+
+impl BpDemoted {
+    #[inline]
+    pub fn into_event(self) -> UEvent {
+        UEvent::custom(BP_DEMOTED_EVENT, self)
+    }
+}
+
+/// Rate guard state of a target, disabled until a limit is set
+#[derive(Default)]
+pub struct RateGuard {
+    limit: Mutex<Option<RateLimit>>,
+    /// The start and hits of current window of each breakpoint
+    windows: Mutex<HashMap<BpID, (Instant, usize)>>,
+    /// The action and the hits counted since demoted
+    demoted: Mutex<HashMap<BpID, (DemoteAction, usize)>>,
+}
+
+impl RateGuard {
+    pub fn limit(&self) -> Option<RateLimit> {
+        self.limit.lock().clone()
+    }
+
+    /// Set the limit, None to disable the guard, the demoted breakpoints stay demoted
+    pub fn set_limit(&self, limit: Option<RateLimit>) {
+        self.windows.lock().clear();
+        *self.limit.lock() = limit;
+    }
+
+    /// The demoted breakpoints, with the hits counted without stopping since demoted
+    pub fn demoted(&self) -> Vec<(BpID, DemoteAction, usize)> {
+        self.demoted
+            .lock()
+            .iter()
+            .map(|(&id, &(action, hits))| (id, action, hits))
+            .collect()
+    }
+
+    /// Restore a demoted breakpoint to stop again, returns false if it's not demoted.
+    /// The disabled ones should be enabled by the caller.
+    pub fn restore(&self, id: BpID) -> bool {
+        self.windows.lock().remove(&id);
+        self.demoted.lock().remove(&id).is_some()
+    }
+
+    /// Called by the engine before the user callback, returns the reply if the breakpoint event
+    /// is held back, with the notification to pass to the user callback
+    pub(crate) fn on_event(&self, event: &UEvent) -> Option<(UserReply, Option<UEvent>)> {
+        let bp = match event {
+            UEvent::Breakpoint(bp) => bp,
+            _ => return None,
+        };
+        let id = bp.get_id();
+        if let Some((_, hits)) = self.demoted.lock().get_mut(&id) {
+            // count the hit before it's continued without stopping
+            *hits += 1;
+            return Some((UserReply::Run(false), None));
+        }
+        let limit = self.limit()?;
+
+        let now = Instant::now();
+        let hits = {
+            let mut windows = self.windows.lock();
+            let (start, hits) = windows.entry(id).or_insert((now, 0));
+            if now.duration_since(*start) > limit.window {
+                *start = now;
+                *hits = 0;
+            }
+            *hits += 1;
+            *hits
+        };
+        if hits <= limit.max_hits {
+            return None;
+        }
+
+        self.windows.lock().remove(&id);
+        self.demoted.lock().insert(id, (limit.action, 0));
+        if limit.action == DemoteAction::Disable {
+            bp.enable(false).log_error("disable bp");
+        }
+        warn!(
+            "breakpoint {id} at {:x} hit {hits} times in {:?}, demoted to {:?}",
+            bp.address(),
+            limit.window,
+            limit.action
+        );
+        let notice = BpDemoted {
+            id,
+            address: bp.address(),
+            hits,
+            window: limit.window,
+            action: limit.action,
+        };
+        Some((UserReply::Run(false), Some(notice.into_event())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct TestBp(Cell<bool>);
+
+    impl UDbgBreakpoint for TestBp {
+        fn get_id(&self) -> BpID {
+            1
+        }
+        fn address(&self) -> usize {
+            0x1000
+        }
+        fn enabled(&self) -> bool {
+            self.0.get()
+        }
+        fn get_type(&self) -> BpType {
+            BpType::Soft
+        }
+        fn hit_count(&self) -> usize {
+            0
+        }
+        fn set_count(&self, _count: usize) {}
+        fn set_hit_thread(&self, _tid: tid_t) {}
+        fn hit_tid(&self) -> tid_t {
+            0
+        }
+        fn origin_bytes(&self) -> Option<&[u8]> {
+            None
+        }
+        fn enable(&self, enable: bool) -> UDbgResult<()> {
+            self.0.set(enable);
+            Ok(())
+        }
+        fn remove(&self) -> UDbgResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn demote() {
+        let bp = Arc::new(TestBp(Cell::new(true)));
+        let event = UEvent::Breakpoint(bp.clone());
+        let guard = RateGuard::default();
+        assert!(guard.on_event(&event).is_none());
+
+        guard.set_limit(Some(RateLimit {
+            max_hits: 3,
+            window: Duration::from_secs(60),
+            action: DemoteAction::Disable,
+        }));
+        for _ in 0..3 {
+            assert!(guard.on_event(&event).is_none());
+        }
+        let (reply, notice) = guard.on_event(&event).unwrap();
+        assert!(matches!(reply, UserReply::Run(false)));
+        assert!(notice.is_some());
+        assert!(!bp.enabled());
+
+        // counted without stopping
+        assert!(matches!(guard.on_event(&event), Some((_, None))));
+        assert_eq!(guard.demoted(), [(1, DemoteAction::Disable, 1)]);
+
+        assert!(guard.restore(1));
+        assert!(!guard.restore(1));
+        assert!(guard.on_event(&event).is_none());
+    }
+}
@@ -0,0 +1,209 @@
+//!
+//! History of the values of registers and memory at each stop of a session.
+//!
+//! The locations added to [`TargetBase::timeline`] are recorded by the engine at each event before
+//! the user callback receives it, a register from the context of the event thread and memory from
+//! the target. Nothing is recorded until a location is watched. Each location keeps the latest
+//! [`ValueTimeline::max_entries`] values, and with [`ValueTimeline::set_changes_only`] a value
+//! equal to the previous one is not recorded again, the stops between are implied.
+//!
+
+use crate::{exception::stop_reason, prelude::*};
+
+use spin::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+const DEFAULT_MAX_ENTRIES: usize = 0x1000;
+
+/// A location to record
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Location {
+    /// A register by name, of the event thread
+    Reg(String),
+    Memory {
+        address: usize,
+        size: usize,
+    },
+}
+
+/// The value of a location at a stop
+#[derive(Debug, Clone, Serialize)]
+pub struct ValueEntry {
+    /// Index of the stop in session, from 0
+    pub stop: u64,
+    pub time: SystemTime,
+    pub tid: tid_t,
+    /// Stop reason, such as `breakpoint 401000`
+    pub reason: String,
+    /// Little endian bytes, of pointer size for the registers
+    pub value: Vec<u8>,
+}
+
+impl ValueEntry {
+    /// The value as an integer, from the first 8 bytes
+    pub fn as_u64(&self) -> u64 {
+        let mut buf = [0u8; 8];
+        let len = self.value.len().min(8);
+        buf[..len].copy_from_slice(&self.value[..len]);
+        u64::from_le_bytes(buf)
+    }
+}
+
+/// Value history of the watched locations, see the module document
+pub struct ValueTimeline {
+    entries: Mutex<BTreeMap<Location, VecDeque<ValueEntry>>>,
+    stops: AtomicU64,
+    max_entries: AtomicUsize,
+    changes_only: AtomicBool,
+}
+
+impl Default for ValueTimeline {
+    fn default() -> Self {
+        Self {
+            entries: Default::default(),
+            stops: Default::default(),
+            max_entries: AtomicUsize::new(DEFAULT_MAX_ENTRIES),
+            changes_only: Default::default(),
+        }
+    }
+}
+
+impl ValueTimeline {
+    /// Start recording a location, the history of a watched one is kept
+    pub fn watch(&self, location: Location) {
+        self.entries.lock().entry(location).or_default();
+    }
+
+    /// Stop recording a location and drop its history
+    pub fn unwatch(&self, location: &Location) -> bool {
+        self.entries.lock().remove(location).is_some()
+    }
+
+    pub fn locations(&self) -> Vec<Location> {
+        self.entries.lock().keys().cloned().collect()
+    }
+
+    /// Clear the histories, the locations keep being watched
+    pub fn clear(&self) {
+        for history in self.entries.lock().values_mut() {
+            history.clear();
+        }
+    }
+
+    /// Count of the stops since the first location was watched
+    #[inline]
+    pub fn stops(&self) -> u64 {
+        self.stops.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn max_entries(&self) -> usize {
+        self.max_entries.load(Ordering::Relaxed)
+    }
+
+    /// Set the entries kept for each location, the older ones are dropped
+    pub fn set_max_entries(&self, max: usize) {
+        self.max_entries.store(max.max(1), Ordering::Relaxed);
+        for history in self.entries.lock().values_mut() {
+            while history.len() > max.max(1) {
+                history.pop_front();
+            }
+        }
+    }
+
+    /// Record a value only if it differs from the previous one
+    pub fn set_changes_only(&self, changes_only: bool) {
+        self.changes_only.store(changes_only, Ordering::Relaxed);
+    }
+
+    /// The history of a location, from the oldest
+    pub fn history(&self, location: &Location) -> Vec<ValueEntry> {
+        self.entries
+            .lock()
+            .get(location)
+            .map(|h| h.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The entries of a location recorded in the range of stops
+    pub fn history_between(
+        &self,
+        location: &Location,
+        stops: core::ops::Range<u64>,
+    ) -> Vec<ValueEntry> {
+        self.entries
+            .lock()
+            .get(location)
+            .map(|h| {
+                h.iter()
+                    .filter(|e| stops.contains(&e.stop))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The value of a location at a stop: the last one recorded at or before it
+    pub fn value_at(&self, location: &Location, stop: u64) -> Option<ValueEntry> {
+        self.entries
+            .lock()
+            .get(location)?
+            .iter()
+            .rev()
+            .find(|e| e.stop <= stop)
+            .cloned()
+    }
+
+    #[inline]
+    pub fn last(&self, location: &Location) -> Option<ValueEntry> {
+        self.entries.lock().get(location)?.back().cloned()
+    }
+
+    /// Called by the engine before the user callback
+    pub(crate) fn on_event(&self, ctx: &mut dyn TraceContext, event: &UEvent) {
+        let mut entries = self.entries.lock();
+        if entries.is_empty() {
+            return;
+        }
+        let stop = self.stops.fetch_add(1, Ordering::Relaxed);
+        let target = ctx.target();
+        let (time, tid) = (SystemTime::now(), target.base().event_tid.get());
+        let reason = stop_reason(event);
+        let pointer_size = ctx.pointer_size();
+        let (max, changes_only) = (
+            self.max_entries(),
+            self.changes_only.load(Ordering::Relaxed),
+        );
+
+        for (location, history) in entries.iter_mut() {
+            let value = match location {
+                Location::Reg(name) => ctx.register().and_then(|r| r.get(name)).map(|r| {
+                    let bytes = r.as_int().to_le_bytes();
+                    bytes[..pointer_size.min(bytes.len())].to_vec()
+                }),
+                &Location::Memory { address, size } => {
+                    Some(target.read_bytes(address, size)).filter(|v| v.len() == size)
+                }
+            };
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+            if changes_only && history.back().map_or(false, |e| e.value == value) {
+                continue;
+            }
+            while history.len() >= max {
+                history.pop_front();
+            }
+            history.push_back(ValueEntry {
+                stop,
+                time,
+                tid,
+                reason: reason.clone(),
+                value,
+            });
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//!
+//! TLS plaintext tap: hook the read/write functions of SChannel and OpenSSL/BoringSSL in target,
+//! surface the plaintext buffers as events, for protocol debugging without a MITM proxy.
+//!
+//! It's best-effort, the functions are resolved by symbols, or by signatures for the statically linked libraries.
+//! Enabled by the feature `tls-tap`.
+//!
+
+use crate::{
+    ipc::{return_reg, ApiHit, ApiHooks, IpcDirection},
+    prelude::*,
+    scan::{scan_memory, CancelToken, Matcher, ScanEvent, ScanOptions},
+    target::call_args,
+};
+
+use std::sync::Arc;
+
+/// Kind of the custom event which carries [`TlsData`]
+pub const TLS_EVENT: &str = "tls";
+
+const SECBUFFER_DATA: u32 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum TlsLibrary {
+    SChannel,
+    /// OpenSSL and the compatible libraries, such as BoringSSL, LibreSSL
+    OpenSsl,
+}
+
+/// Plaintext written to or read from a TLS connection
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsData {
+    pub library: TlsLibrary,
+    pub direction: IpcDirection,
+    pub tid: tid_t,
+    /// Address of `SSL` or `CtxtHandle`, identifies the connection
+    pub session: usize,
+    pub data: Vec<u8>,
+    /// The plaintext is larger than [`TlsTap::max_payload`]
+    pub truncated: bool,
+}
+
+impl TlsData {
+    #[inline]
+    pub fn into_event(self) -> UEvent {
+        UEvent::custom(TLS_EVENT, self)
+    }
+}
+
+/// Function to hook, the signatures are the same with OpenSSL/SSPI
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TlsApi {
+    SslWrite,
+    SslRead,
+    SslWriteEx,
+    SslReadEx,
+    EncryptMessage,
+    DecryptMessage,
+}
+
+impl TlsApi {
+    pub const ALL: &'static [Self] = &[
+        Self::SslWrite,
+        Self::SslRead,
+        Self::SslWriteEx,
+        Self::SslReadEx,
+        Self::EncryptMessage,
+        Self::DecryptMessage,
+    ];
+
+    fn symbols(self) -> &'static [&'static str] {
+        match self {
+            Self::SslWrite => &["SSL_write"],
+            Self::SslRead => &["SSL_read"],
+            Self::SslWriteEx => &["SSL_write_ex"],
+            Self::SslReadEx => &["SSL_read_ex"],
+            Self::EncryptMessage => &["sspicli!EncryptMessage", "secur32!EncryptMessage"],
+            Self::DecryptMessage => &["sspicli!DecryptMessage", "secur32!DecryptMessage"],
+        }
+    }
+
+    pub fn library(self) -> TlsLibrary {
+        match self {
+            Self::EncryptMessage | Self::DecryptMessage => TlsLibrary::SChannel,
+            _ => TlsLibrary::OpenSsl,
+        }
+    }
+
+    pub fn direction(self) -> IpcDirection {
+        match self {
+            Self::SslWrite | Self::SslWriteEx | Self::EncryptMessage => IpcDirection::Send,
+            _ => IpcDirection::Recv,
+        }
+    }
+}
+
+/// Call waiting for return to read the plaintext
+struct Pending {
+    api: TlsApi,
+    session: usize,
+    /// Buffer, or `SecBufferDesc`
+    buffer: usize,
+    /// Size of buffer, or pointer to the processed size
+    size: usize,
+}
+
+/// Interceptor of the TLS plaintext in a target
+pub struct TlsTap {
+    hooks: ApiHooks<TlsApi, Pending>,
+    /// Max bytes of plaintext to read for each call
+    pub max_payload: usize,
+}
+
+impl TlsTap {
+    pub fn new(target: Arc<dyn UDbgTarget>) -> Self {
+        Self {
+            hooks: ApiHooks::new(target),
+            max_payload: crate::ipc::MAX_PAYLOAD,
+        }
+    }
+
+    #[inline]
+    fn target(&self) -> &dyn UDbgTarget {
+        self.hooks.target().as_ref()
+    }
+
+    /// Hook the functions resolved by symbols, returns the count of hooked functions,
+    /// could be called again after the TLS libraries loaded
+    pub fn install(&mut self) -> usize {
+        for &api in TlsApi::ALL {
+            for sym in api.symbols() {
+                if let Some(address) = self.target().get_address_by_symbol(sym) {
+                    if self.hooks.hook(address, api, sym) {
+                        break;
+                    }
+                }
+            }
+        }
+        self.hooks.len()
+    }
+
+    /// Hook a function located by other means, such as in statically linked BoringSSL without symbols
+    #[inline]
+    pub fn hook(&mut self, address: usize, api: TlsApi) -> bool {
+        self.hooks.hook(address, api, "tls")
+    }
+
+    /// Scan the executable regions of a module for the signature of `api`, and hook all the matched addresses
+    pub fn hook_signature<M: Matcher + ?Sized>(
+        &mut self,
+        module: &str,
+        matcher: &M,
+        api: TlsApi,
+    ) -> UDbgResult<usize> {
+        let module = self
+            .target()
+            .get_module(module)
+            .ok_or(UDbgError::NotFound)?;
+        let range = module.data().base..module.data().base + module.data().size;
+        let regions = self
+            .target()
+            .enum_memory()?
+            .filter(|p| p.is_commit() && p.is_executable())
+            .filter(|p| range.contains(&p.base))
+            .map(|p| p.base..p.base + p.size)
+            .collect::<Vec<_>>();
+
+        let mut found = vec![];
+        let target = self.hooks.target().clone();
+        scan_memory(
+            target.as_ref(),
+            &regions,
+            matcher,
+            &ScanOptions::default(),
+            &CancelToken::default(),
+            |e| {
+                if let ScanEvent::Found(a) = e {
+                    found.push(a);
+                }
+                true
+            },
+        );
+        Ok(found.into_iter().filter(|&a| self.hook(a, api)).count())
+    }
+
+    /// Remove all the breakpoints set by this tap
+    #[inline]
+    pub fn uninstall(&mut self) {
+        self.hooks.unhook_all();
+    }
+
+    /// If the breakpoint at `address` is set by this tap, the event should be continued silently then
+    #[inline]
+    pub fn owns(&self, address: usize) -> bool {
+        self.hooks.owns(address)
+    }
+
+    /// Handle a breakpoint event, returns the plaintext if intercepted
+    pub fn on_breakpoint(
+        &mut self,
+        bp: &dyn UDbgBreakpoint,
+        ctx: &mut dyn TraceContext,
+    ) -> Option<TlsData> {
+        let tid = self.target().base().event_tid.get();
+        match self.hooks.hit(bp.address(), tid)? {
+            ApiHit::Entry(api) => self.on_entry(api, tid, ctx),
+            ApiHit::Return(pending) => self.on_return(pending, tid, ctx),
+        }
+    }
+
+    /// Handle an event, returns the [`TLS_EVENT`] if plaintext intercepted
+    pub fn on_event(&mut self, event: &UEvent, ctx: &mut dyn TraceContext) -> Option<UEvent> {
+        match event {
+            UEvent::Breakpoint(bp) => self
+                .on_breakpoint(bp.as_ref(), ctx)
+                .map(TlsData::into_event),
+            _ => None,
+        }
+    }
+
+    fn on_entry(&mut self, api: TlsApi, tid: tid_t, ctx: &mut dyn TraceContext) -> Option<TlsData> {
+        let arch = ctx.arch();
+        let regs: &dyn UDbgRegs = ctx.register()?;
+        let target = self.hooks.target().clone();
+        let arg = call_args(target.as_ref(), regs, arch);
+
+        let session = arg(1)?;
+        let pending = match api {
+            // the message is encrypted in place, read it before calling
+            TlsApi::EncryptMessage => {
+                let (data, truncated) = self.read_sec_buffers(arg(3)?);
+                return Some(TlsData {
+                    library: api.library(),
+                    direction: api.direction(),
+                    tid,
+                    session,
+                    data,
+                    truncated,
+                });
+            }
+            TlsApi::DecryptMessage => Pending {
+                api,
+                session,
+                buffer: arg(2)?,
+                size: 0,
+            },
+            TlsApi::SslWriteEx | TlsApi::SslReadEx => Pending {
+                api,
+                session,
+                buffer: arg(2)?,
+                size: arg(4).filter(|&p| p != 0)?,
+            },
+            TlsApi::SslWrite | TlsApi::SslRead => Pending {
+                api,
+                session,
+                buffer: arg(2)?,
+                size: arg(3)?,
+            },
+        };
+        self.hooks.wait_return(tid, regs, arch, pending);
+        None
+    }
+
+    fn on_return(
+        &mut self,
+        pending: Pending,
+        tid: tid_t,
+        ctx: &mut dyn TraceContext,
+    ) -> Option<TlsData> {
+        let arch = ctx.arch();
+        let regs: &dyn UDbgRegs = ctx.register()?;
+        // int or SECURITY_STATUS
+        let result = regs.get_reg(return_reg(arch))?.as_int() as u32 as i32;
+        let (data, truncated) = match pending.api {
+            TlsApi::SslWrite | TlsApi::SslRead => {
+                if result <= 0 {
+                    return None;
+                }
+                self.read_payload(pending.buffer, (result as usize).min(pending.size))
+            }
+            TlsApi::SslWriteEx | TlsApi::SslReadEx => {
+                if result != 1 {
+                    return None;
+                }
+                let size = match self.target().base().pointer_size() {
+                    4 => self.target().read_value::<u32>(pending.size)? as usize,
+                    _ => self.target().read_value::<u64>(pending.size)? as usize,
+                };
+                self.read_payload(pending.buffer, size)
+            }
+            TlsApi::DecryptMessage => {
+                // SEC_E_OK
+                if result != 0 {
+                    return None;
+                }
+                self.read_sec_buffers(pending.buffer)
+            }
+            TlsApi::EncryptMessage => return None,
+        };
+        Some(TlsData {
+            library: pending.api.library(),
+            direction: pending.api.direction(),
+            tid,
+            session: pending.session,
+            data,
+            truncated,
+        })
+    }
+
+    fn read_payload(&self, address: usize, size: usize) -> (Vec<u8>, bool) {
+        let len = size.min(self.max_payload);
+        (self.target().read_bytes(address, len), size > len)
+    }
+
+    /// Concat the data buffers of a `SecBufferDesc`
+    fn read_sec_buffers(&self, desc: usize) -> (Vec<u8>, bool) {
+        let target = self.target();
+        let psize = target.base().pointer_size();
+        let count = target.read_value::<u32>(desc + 4).unwrap_or(0) as usize;
+        let buffers = target.read_ptr(desc + 8).unwrap_or(0);
+
+        let mut result = vec![];
+        let mut truncated = false;
+        // cbBuffer, BufferType, pvBuffer
+        for i in 0..count.min(16) {
+            let buf = buffers + i * (8 + psize);
+            if target.read_value::<u32>(buf + 4) != Some(SECBUFFER_DATA) {
+                continue;
+            }
+            let size = target.read_value::<u32>(buf).unwrap_or(0) as usize;
+            let rest = self.max_payload.saturating_sub(result.len());
+            let (data, t) = match target.read_ptr(buf + 8) {
+                Some(p) => self.read_payload(p, size.min(rest)),
+                None => continue,
+            };
+            result.extend_from_slice(&data);
+            truncated |= t || size > rest;
+        }
+        (result, truncated)
+    }
+}
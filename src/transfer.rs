@@ -0,0 +1,258 @@
+//!
+//! Encoding of the memory transferred by the remote servers, to keep them responsive over slow
+//! links.
+//!
+//! The memory is split at the page boundaries, each chunk is sent as is, as unchanged, or as the
+//! xor against the last content of the same chunk sent in this session, which is mostly zeros.
+//! The whole payload is then compressed by deflate. Both are negotiated per session by the
+//! names in [`Encoding`], and the client decodes the payloads with a [`MemoryDecoder`].
+//!
+//! The payload is a sequence of chunks, each starts with a tag byte: [`CHUNK_RAW`] followed by
+//! the bytes, [`CHUNK_SAME`], or [`CHUNK_XOR`] followed by the xor bytes.
+//!
+
+use crate::prelude::*;
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+pub const CHUNK_RAW: u8 = 0;
+pub const CHUNK_SAME: u8 = 1;
+pub const CHUNK_XOR: u8 = 2;
+
+const PAGE_SIZE: usize = 0x1000;
+/// The chunks kept in the cache of a session, it's cleared when full
+const MAX_CACHED_CHUNKS: usize = 0x4000;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Encoding {
+    pub delta: bool,
+    pub deflate: bool,
+}
+
+impl Encoding {
+    pub const NAMES: &'static [&'static str] = &["delta", "deflate"];
+
+    /// The encodings supported in the names requested by the client
+    pub fn negotiate<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut result = Self::default();
+        for name in names {
+            match name {
+                "delta" => result.delta = true,
+                "deflate" => result.deflate = true,
+                _ => {}
+            }
+        }
+        result
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut result = vec![];
+        if self.delta {
+            result.push("delta");
+        }
+        if self.deflate {
+            result.push("deflate");
+        }
+        result
+    }
+
+    #[inline]
+    pub fn is_plain(&self) -> bool {
+        !self.delta && !self.deflate
+    }
+}
+
+fn chunks(address: usize, len: usize) -> impl Iterator<Item = (usize, usize)> {
+    let end = address + len;
+    let mut start = address;
+    core::iter::from_fn(move || {
+        if start >= end {
+            return None;
+        }
+        let next = ((start & !(PAGE_SIZE - 1)) + PAGE_SIZE).min(end);
+        let chunk = (start, next - start);
+        start = next;
+        Some(chunk)
+    })
+}
+
+/// The content of the chunks transferred, by address, the same on both sides
+#[derive(Default)]
+struct ChunkCache(HashMap<usize, Vec<u8>>);
+
+impl ChunkCache {
+    fn get(&self, address: usize, len: usize) -> Option<&[u8]> {
+        self.0
+            .get(&address)
+            .map(Vec::as_slice)
+            .filter(|c| c.len() == len)
+    }
+
+    fn insert(&mut self, address: usize, data: &[u8]) {
+        if self.0.len() >= MAX_CACHED_CHUNKS && !self.0.contains_key(&address) {
+            self.0.clear();
+        }
+        self.0.insert(address, data.to_vec());
+    }
+}
+
+/// Encoder of a session on the server side
+#[derive(Default)]
+pub struct MemoryEncoder {
+    pub encoding: Encoding,
+    cache: ChunkCache,
+}
+
+impl MemoryEncoder {
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            cache: Default::default(),
+        }
+    }
+
+    /// Encode the memory read at `address`
+    pub fn encode(&mut self, address: usize, data: &[u8]) -> UDbgResult<Vec<u8>> {
+        if self.encoding.is_plain() {
+            return Ok(data.to_vec());
+        }
+        let mut payload = Vec::with_capacity(data.len() + data.len() / PAGE_SIZE + 1);
+        let mut offset = 0;
+        for (start, len) in chunks(address, data.len()) {
+            let chunk = &data[offset..offset + len];
+            offset += len;
+            if !self.encoding.delta {
+                payload.push(CHUNK_RAW);
+                payload.extend_from_slice(chunk);
+                continue;
+            }
+            match self.cache.get(start, len) {
+                Some(last) if last == chunk => payload.push(CHUNK_SAME),
+                Some(last) => {
+                    payload.push(CHUNK_XOR);
+                    payload.extend(chunk.iter().zip(last).map(|(a, b)| a ^ b));
+                }
+                None => {
+                    payload.push(CHUNK_RAW);
+                    payload.extend_from_slice(chunk);
+                }
+            }
+            self.cache.insert(start, chunk);
+        }
+
+        if !self.encoding.deflate {
+            return Ok(payload);
+        }
+        let mut encoder = DeflateEncoder::new(vec![], Compression::fast());
+        encoder.write_all(&payload)?;
+        Ok(encoder.finish()?)
+    }
+}
+
+/// Decoder of a session on the client side
+#[derive(Default)]
+pub struct MemoryDecoder {
+    pub encoding: Encoding,
+    cache: ChunkCache,
+}
+
+impl MemoryDecoder {
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            cache: Default::default(),
+        }
+    }
+
+    /// Decode the payload of `len` bytes memory at `address`
+    pub fn decode(&mut self, address: usize, len: usize, payload: &[u8]) -> UDbgResult<Vec<u8>> {
+        if self.encoding.is_plain() {
+            return Ok(payload.to_vec());
+        }
+        let inflated;
+        let mut payload = if self.encoding.deflate {
+            let mut buf = vec![];
+            DeflateDecoder::new(payload).read_to_end(&mut buf)?;
+            inflated = buf;
+            inflated.as_slice()
+        } else {
+            payload
+        };
+
+        let mut result = Vec::with_capacity(len);
+        for (start, len) in chunks(address, len) {
+            let (&tag, rest) = payload.split_first().ok_or("truncated payload")?;
+            let chunk = match tag {
+                CHUNK_SAME => self.cache.get(start, len).ok_or("unknown chunk")?.to_vec(),
+                CHUNK_RAW | CHUNK_XOR => {
+                    let bytes = rest.get(..len).ok_or("truncated payload")?;
+                    payload = &rest[len..];
+                    if tag == CHUNK_RAW {
+                        bytes.to_vec()
+                    } else {
+                        let last = self.cache.get(start, len).ok_or("unknown chunk")?;
+                        bytes.iter().zip(last).map(|(a, b)| a ^ b).collect()
+                    }
+                }
+                _ => return Err(format!("invalid chunk tag {tag}").into()),
+            };
+            if tag == CHUNK_SAME {
+                payload = rest;
+            }
+            self.cache.insert(start, &chunk);
+            result.extend_from_slice(&chunk);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for (delta, deflate) in [(true, false), (false, true), (true, true)] {
+            let encoding = Encoding { delta, deflate };
+            let mut encoder = MemoryEncoder::new(encoding);
+            let mut decoder = MemoryDecoder::new(encoding);
+            let address = 0x10ff0;
+            let mut data = (0..0x2000).map(|i| i as u8).collect::<Vec<_>>();
+            for round in 0..3 {
+                let payload = encoder.encode(address, &data).unwrap();
+                assert_eq!(decoder.decode(address, data.len(), &payload).unwrap(), data);
+                data[0x100 + round] ^= 0xff;
+            }
+        }
+    }
+
+    #[test]
+    fn delta_chunks() {
+        let mut encoder = MemoryEncoder::new(Encoding::negotiate(["delta"]));
+        // split at the page boundary, 0x800 bytes in the first page and 0x1000 in the second
+        let data = vec![0x5a; 0x1800];
+        let raw = encoder.encode(0x800, &data).unwrap();
+        assert_eq!(raw.len(), 2 + data.len());
+        assert_eq!(raw[0], CHUNK_RAW);
+        assert_eq!(raw[0x801], CHUNK_RAW);
+
+        let same = encoder.encode(0x800, &data).unwrap();
+        assert_eq!(same, [CHUNK_SAME, CHUNK_SAME]);
+
+        let mut changed = data.clone();
+        changed[0x900] = 0;
+        let xor = encoder.encode(0x800, &changed).unwrap();
+        assert_eq!(xor.len(), 2 + 0x1000);
+        assert_eq!(&xor[..2], [CHUNK_SAME, CHUNK_XOR]);
+        assert!(xor[2..]
+            .iter()
+            .enumerate()
+            .all(|(i, &b)| b == if i == 0x100 { 0x5a } else { 0 }));
+
+        // a delta is not decodable without the chunk it's based on
+        let mut decoder = MemoryDecoder::new(encoder.encoding);
+        assert!(decoder.decode(0x800, data.len(), &same).is_err());
+    }
+}
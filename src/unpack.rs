@@ -0,0 +1,357 @@
+//!
+//! Packer detection and the written-then-executed heuristic to locate the OEP (original entry point) of packed modules.
+//!
+//! The packers are recognized by their section names, or by the generic traits of a packed image:
+//! entry point in a writable section, sections without raw data, and high entropy.
+//!
+//! [`OepTracker`] removes the execute permission of the sections except the one containing the unpacking stub,
+//! and snapshots their content; the first execution in a section whose content has changed since then
+//! is reported as [`UEvent::PossibleOEP`]. The execution is trapped by DEP, so it only works on Windows with DEP enabled,
+//! and it's defeated if the stub restores the execute permission by itself.
+//! The Windows engine arms it automatically when [`UDbgFlags::AUTO_UNPACK`] is set.
+//!
+
+use crate::{entropy::*, prelude::*};
+
+use core::ops::Range;
+use std::sync::Arc;
+
+#[cfg(windows)]
+const PAGE_SIZE: usize = 0x1000;
+const MAX_SECTIONS: usize = 96;
+/// Max bytes of a section to compute the entropy
+const ENTROPY_SAMPLE: usize = 0x10000;
+
+const IMAGE_SCN_CNT_CODE: u32 = 0x00000020;
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x80000000;
+
+/// Section names left by the well-known packers and protectors
+#[rustfmt::skip]
+const SIGNATURES: &[(&str, &[&str])] = &[
+    ("UPX", &["UPX0", "UPX1", "UPX2", "UPX!"]),
+    ("ASPack", &[".aspack", ".adata"]),
+    ("MPRESS", &[".MPRESS1", ".MPRESS2"]),
+    ("PECompact", &["PEC2", "PEC2TO", "PECompact2"]),
+    ("Petite", &[".petite"]),
+    ("FSG", &["FSG!"]),
+    ("NsPack", &["nsp0", "nsp1", "nsp2", ".nsp0", ".nsp1"]),
+    ("MEW", &["MEW"]),
+    ("kkrunchy", &["kkrunchy"]),
+    ("Themida", &[".themida", ".winlice"]),
+    ("VMProtect", &[".vmp0", ".vmp1", ".vmp2"]),
+    ("Enigma", &[".enigma1", ".enigma2"]),
+    ("Obsidium", &[".obsidium"]),
+    ("ExeCryptor", &[".ecrypt"]),
+];
+
+/// Section header of a loaded image
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageSection {
+    pub name: String,
+    pub address: usize,
+    pub virtual_size: usize,
+    pub raw_size: usize,
+    pub characteristics: u32,
+}
+
+impl ImageSection {
+    #[inline]
+    pub fn range(&self) -> Range<usize> {
+        self.address..self.address + self.virtual_size.max(self.raw_size)
+    }
+
+    #[inline]
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & (IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_CNT_CODE) > 0
+    }
+
+    #[inline]
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_WRITE > 0
+    }
+}
+
+/// Read the section headers of the PE image loaded at `base`
+pub fn read_sections<T: ReadMemory + ?Sized>(target: &T, base: usize) -> Option<Vec<ImageSection>> {
+    if target.read_value::<u16>(base)? != 0x5A4D {
+        return None;
+    }
+    let nt = base + target.read_value::<u32>(base + 0x3C)? as usize;
+    if target.read_value::<u32>(nt)? != 0x4550 {
+        return None;
+    }
+    let count = target.read_value::<u16>(nt + 6)? as usize;
+    let optional_size = target.read_value::<u16>(nt + 20)? as usize;
+    let table = nt + 24 + optional_size;
+    let data = target.read_bytes(table, count.min(MAX_SECTIONS) * 40);
+    Some(
+        data.chunks_exact(40)
+            .map(|s| {
+                let u32_at = |i: usize| u32::from_le_bytes(s[i..i + 4].try_into().unwrap());
+                let name = &s[..8];
+                let len = name.iter().position(|&b| b == 0).unwrap_or(8);
+                ImageSection {
+                    name: String::from_utf8_lossy(&name[..len]).into(),
+                    address: base + u32_at(12) as usize,
+                    virtual_size: u32_at(8) as usize,
+                    raw_size: u32_at(16) as usize,
+                    characteristics: u32_at(36),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Result of packer detection
+#[derive(Debug, Clone, Serialize)]
+pub struct PackerInfo {
+    /// Name of the packer, "unknown" if it's recognized by the generic traits
+    pub name: &'static str,
+    pub module: Arc<str>,
+    pub base: usize,
+    pub entry: usize,
+    /// Why the module is considered as packed
+    pub evidence: String,
+}
+
+/// Check if the module is packed, by section names first, then by the generic traits
+pub fn detect_packer<T: UDbgTarget + ?Sized>(
+    target: &T,
+    module: &dyn UDbgModule,
+) -> Option<PackerInfo> {
+    let data = module.data();
+    let sections = read_sections(target, data.base)?;
+    let entry = data.entry_point();
+    let info = |name, evidence| PackerInfo {
+        name,
+        module: data.name.clone(),
+        base: data.base,
+        entry,
+        evidence,
+    };
+
+    for (packer, names) in SIGNATURES {
+        if let Some(s) = sections.iter().find(|s| names.contains(&s.name.as_str())) {
+            return Some(info(packer, format!("section {}", s.name)));
+        }
+    }
+
+    let entry_section = sections.iter().find(|s| s.range().contains(&entry))?;
+    if !entry_section.is_writable() {
+        return None;
+    }
+    let mut evidence = vec![format!(
+        "entry in writable section {:?}",
+        entry_section.name
+    )];
+    if let Some(s) = sections
+        .iter()
+        .find(|s| s.raw_size == 0 && s.virtual_size > 0)
+    {
+        evidence.push(format!("section {:?} has no raw data", s.name));
+    }
+    if let Some((s, e)) = sections
+        .iter()
+        .filter(|s| s.raw_size > 0)
+        .map(|s| {
+            let data = target.read_bytes(s.address, s.raw_size.min(ENTROPY_SAMPLE));
+            (s, shannon_entropy(&data))
+        })
+        .find(|(_, e)| *e >= HIGH_ENTROPY)
+    {
+        evidence.push(format!("section {:?} has entropy {e:.2}", s.name));
+    }
+    (evidence.len() > 1).then(|| info("unknown", evidence.join(", ")))
+}
+
+#[cfg(windows)]
+fn page_hash(data: &[u8]) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Protection without execute permission, the write permission is kept for unpacking
+#[cfg(windows)]
+fn strip_execute(protect: u32) -> Option<u32> {
+    use crate::pe::*;
+
+    let extra = protect & !0xFF;
+    Some(
+        extra
+            | match protect & 0xFF {
+                PAGE_EXECUTE | PAGE_EXECUTE_READ => PAGE_READONLY,
+                PAGE_EXECUTE_READWRITE => PAGE_READWRITE,
+                PAGE_EXECUTE_WRITECOPY => PAGE_WRITECOPY,
+                _ => return None,
+            },
+    )
+}
+
+#[cfg(windows)]
+struct ArmedPage {
+    address: usize,
+    protect: u32,
+    hash: u64,
+}
+
+/// A module armed for the OEP heuristic
+#[cfg(windows)]
+struct ArmedModule {
+    packer: PackerInfo,
+    pages: Vec<ArmedPage>,
+}
+
+/// Result of an execute fault handled by [`OepTracker::on_execute`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OepFault {
+    /// The code was written since armed, the module is disarmed then
+    PossibleOep,
+    /// The page is unchanged, its protection is restored and the execution should be continued
+    Unchanged,
+}
+
+/// Trap the first execution of the unpacked code in armed modules
+#[cfg(windows)]
+#[derive(Default)]
+pub struct OepTracker {
+    armed: Vec<ArmedModule>,
+}
+
+#[cfg(windows)]
+impl OepTracker {
+    /// Modules being armed
+    pub fn armed(&self) -> impl Iterator<Item = &PackerInfo> + '_ {
+        self.armed.iter().map(|m| &m.packer)
+    }
+
+    /// Remove the execute permission of the sections except the one containing the current entry point,
+    /// returns the count of the armed pages
+    pub fn arm<T: UDbgTarget + ?Sized>(
+        &mut self,
+        target: &T,
+        packer: PackerInfo,
+    ) -> UDbgResult<usize> {
+        let process = target.process().ok_or(UDbgError::NotSupport)?;
+        let sections = read_sections(target, packer.base).ok_or(UDbgError::InvalidAddress)?;
+        let stub = sections
+            .iter()
+            .find(|s| s.range().contains(&packer.entry))
+            .map(ImageSection::range)
+            .unwrap_or_default();
+        // restore the pages armed before, or their stripped protection would be saved as original
+        self.disarm(target, packer.base);
+
+        let mut pages = vec![];
+        let mut buf = vec![0u8; PAGE_SIZE];
+        for s in sections
+            .iter()
+            .filter(|s| !s.range().contains(&packer.entry))
+        {
+            let range = s.range();
+            let mut address = range.start & !(PAGE_SIZE - 1);
+            while address < range.end {
+                // the page is shared with the stub section
+                if stub.contains(&address) || stub.contains(&(address + PAGE_SIZE - 1)) {
+                    address += PAGE_SIZE;
+                    continue;
+                }
+                let protect = match target.virtual_query(address) {
+                    Some(p) if p.is_commit() => p.protect,
+                    _ => {
+                        address += PAGE_SIZE;
+                        continue;
+                    }
+                };
+                if let Some(new) = strip_execute(protect) {
+                    let data = target.read_memory(address, &mut buf).unwrap_or_default();
+                    let hash = page_hash(data);
+                    if process.protect_memory(address, PAGE_SIZE, new).is_some() {
+                        pages.push(ArmedPage {
+                            address,
+                            protect,
+                            hash,
+                        });
+                    }
+                }
+                address += PAGE_SIZE;
+            }
+        }
+        let count = pages.len();
+        self.armed.push(ArmedModule { packer, pages });
+        Ok(count)
+    }
+
+    /// Detect the packer of module, and arm it if packed
+    pub fn check_module<T: UDbgTarget + ?Sized>(
+        &mut self,
+        target: &T,
+        module: &dyn UDbgModule,
+    ) -> Option<&PackerInfo> {
+        let packer = detect_packer(target, module)?;
+        info!(
+            "{} is packed by {}: {}",
+            packer.module, packer.name, packer.evidence
+        );
+        self.arm(target, packer)
+            .log_error("arm oep")
+            .filter(|&n| n > 0)?;
+        self.armed.last().map(|m| &m.packer)
+    }
+
+    /// Restore the protection of the armed pages of module
+    pub fn disarm<T: UDbgTarget + ?Sized>(&mut self, target: &T, base: usize) {
+        let module = match self.armed.iter().position(|m| m.packer.base == base) {
+            Some(i) => self.armed.remove(i),
+            None => return,
+        };
+        if let Some(process) = target.process() {
+            for p in module.pages {
+                process.protect_memory(p.address, PAGE_SIZE, p.protect);
+            }
+        }
+    }
+
+    /// Restore the protection of the armed pages of all modules, called by the engine when
+    /// detaching, or the target would fault on the first execution of them
+    pub fn disarm_all<T: UDbgTarget + ?Sized>(&mut self, target: &T) {
+        let bases = self.armed.iter().map(|m| m.packer.base).collect::<Vec<_>>();
+        for base in bases {
+            self.disarm(target, base);
+        }
+    }
+
+    /// Handle an execute access violation at `address`, returns None if it's not in the armed pages
+    pub fn on_execute<T: UDbgTarget + ?Sized>(
+        &mut self,
+        target: &T,
+        address: usize,
+    ) -> Option<OepFault> {
+        let page = address & !(PAGE_SIZE - 1);
+        let (base, hash) = self.armed.iter().find_map(|m| {
+            m.pages
+                .iter()
+                .find(|p| p.address == page)
+                .map(|p| (m.packer.base, p.hash))
+        })?;
+
+        let mut buf = vec![0u8; PAGE_SIZE];
+        let data = target.read_memory(page, &mut buf).unwrap_or_default();
+        if page_hash(data) != hash {
+            self.disarm(target, base);
+            return Some(OepFault::PossibleOep);
+        }
+
+        let module = self.armed.iter_mut().find(|m| m.packer.base == base)?;
+        let i = module.pages.iter().position(|p| p.address == page)?;
+        let p = module.pages.swap_remove(i);
+        target
+            .process()?
+            .protect_memory(p.address, PAGE_SIZE, p.protect);
+        Some(OepFault::Unchanged)
+    }
+}
@@ -0,0 +1,534 @@
+//!
+//! Call stack unwinding of the stopped threads.
+//!
+//! On Windows the frames are walked by `StackWalk64`, which uses the unwind table of modules
+//! on x64, the memory and function tables are provided by target rather than dbghelp.
+//! On Linux the frames are unwound by the `.eh_frame` of modules on x86_64 and aarch64, read from
+//! the module files. The frame pointer chain is followed where no CFI is found, and on the other
+//! platforms, each return address is validated by the preceding call instruction.
+//! If no caller is found, the stack is scanned for return addresses.
+//!
+
+use crate::{annotate::Annotator, prelude::*};
+
+use std::sync::Arc;
+
+/// Stack slots to scan when the frames can't be unwound
+const SCAN_SLOTS: usize = 0x400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum UnwindMethod {
+    /// The innermost frame, from the thread context
+    Context,
+    /// By the unwind information of module
+    UnwindInfo,
+    FramePointer,
+    /// Guessed by scanning the stack, may be stale
+    Scan,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallFrame {
+    pub pc: usize,
+    pub sp: usize,
+    /// The pc of the caller frame
+    pub return_address: Option<usize>,
+    pub module: Option<Arc<str>>,
+    /// Symbol of the pc, such as `ntdll!RtlUserThreadStart+21`
+    pub symbol: Option<String>,
+    pub method: UnwindMethod,
+}
+
+impl CallFrame {
+    fn new<T: UDbgTarget + ?Sized>(target: &T, pc: usize, sp: usize, method: UnwindMethod) -> Self {
+        Self {
+            pc,
+            sp,
+            return_address: None,
+            module: target.find_module(pc).map(|m| m.data().name.clone()),
+            symbol: target.get_symbol_string(pc),
+            method,
+        }
+    }
+}
+
+impl core::fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:x} {:x}", self.sp, self.pc)?;
+        match (self.symbol.as_ref(), self.module.as_ref()) {
+            (Some(s), _) => write!(f, " {s}")?,
+            (None, Some(m)) => write!(f, " {m}")?,
+            _ => {}
+        }
+        if self.method == UnwindMethod::Scan {
+            f.write_str(" (scanned)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Unwind the call stack of a stopped thread, from the innermost frame
+pub fn stack_trace<T: UDbgTarget + ?Sized, Th: UDbgThread + ?Sized>(
+    target: &T,
+    thread: &Th,
+    max_depth: usize,
+) -> UDbgResult<Vec<CallFrame>> {
+    let mut frames = sys::unwind(target, thread, max_depth.max(1))?;
+    if frames.len() == 1 && max_depth > 1 {
+        let ps = target.base().pointer_size();
+        let sp = frames[0].sp;
+        frames.extend(
+            Annotator::new(target)
+                .stack_scan(sp, SCAN_SLOTS)
+                .into_iter()
+                .filter(|s| s.is_return_address())
+                .take(max_depth - 1)
+                .map(|s| CallFrame::new(target, s.value, s.address + ps, UnwindMethod::Scan)),
+        );
+    }
+    for i in 1..frames.len() {
+        frames[i - 1].return_address = Some(frames[i].pc);
+    }
+    Ok(frames)
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::*;
+    use crate::os::windows::Align16;
+
+    use core::cell::Cell;
+    use core::ffi::c_void;
+    use core::mem::zeroed;
+    use core::ptr::{null, null_mut};
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, LPDWORD, TRUE};
+    use winapi::um::dbghelp::{AddrModeFlat, StackWalk64, ADDRESS64, STACKFRAME64};
+    use winapi::um::winnt::HANDLE;
+
+    thread_local! {
+        // the `&T` being walked, the callbacks of StackWalk64 only get the process handle
+        static WALKING: Cell<*const c_void> = Cell::new(null());
+    }
+
+    unsafe fn target<'a, T: UDbgTarget + ?Sized>(_process: HANDLE) -> &'a T {
+        *(WALKING.with(Cell::get) as *const &T)
+    }
+
+    unsafe extern "system" fn read_memory<T: UDbgTarget + ?Sized>(
+        process: HANDLE,
+        address: u64,
+        buffer: *mut c_void,
+        size: DWORD,
+        read: LPDWORD,
+    ) -> BOOL {
+        let buf = core::slice::from_raw_parts_mut(buffer as *mut u8, size as usize);
+        match target::<T>(process).read_memory(address as usize, buf) {
+            Some(r) => {
+                if !read.is_null() {
+                    *read = r.len() as u32;
+                }
+                TRUE
+            }
+            None => FALSE,
+        }
+    }
+
+    unsafe extern "system" fn function_table<T: UDbgTarget + ?Sized>(
+        process: HANDLE,
+        address: u64,
+    ) -> *mut c_void {
+        let address = address as usize;
+        target::<T>(process)
+            .find_module(address)
+            .and_then(|m| {
+                let f = m.find_function(address - m.data().base)?;
+                Some(f as *const RUNTIME_FUNCTION as *mut c_void)
+            })
+            .unwrap_or(null_mut())
+    }
+
+    unsafe extern "system" fn module_base<T: UDbgTarget + ?Sized>(
+        process: HANDLE,
+        address: u64,
+    ) -> u64 {
+        target::<T>(process)
+            .find_module(address as usize)
+            .map_or(0, |m| m.data().base as u64)
+    }
+
+    fn flat(offset: usize) -> ADDRESS64 {
+        ADDRESS64 {
+            Offset: offset as u64,
+            Segment: 0,
+            Mode: AddrModeFlat,
+        }
+    }
+
+    pub fn unwind<T: UDbgTarget + ?Sized, Th: UDbgThread + ?Sized>(
+        target: &T,
+        thread: &Th,
+        max_depth: usize,
+    ) -> UDbgResult<Vec<CallFrame>> {
+        use winapi::um::winnt::*;
+
+        let mut cx = Align16::<ThreadContext>::new();
+        let mut cx32 = Align16::<ThreadContext32>::new();
+        let (machine, context, pc, sp, fp) =
+            if cfg!(target_pointer_width = "64") && target.base().is_ptr32() {
+                let c = cx32.as_mut();
+                thread.get_context32(c)?;
+                let regs = (c.Eip as usize, c.Esp as usize, c.Ebp as usize);
+                (
+                    IMAGE_FILE_MACHINE_I386,
+                    c as *mut _ as *mut c_void,
+                    regs.0,
+                    regs.1,
+                    regs.2,
+                )
+            } else {
+                let c = cx.as_mut();
+                thread.get_context(c)?;
+                #[cfg(target_arch = "x86_64")]
+                let regs = (IMAGE_FILE_MACHINE_AMD64, c.Rip, c.Rsp, c.Rbp);
+                #[cfg(target_arch = "aarch64")]
+                let regs = (IMAGE_FILE_MACHINE_ARM64, c.Pc, c.Sp, c.Fp);
+                #[cfg(target_arch = "x86")]
+                let regs = (IMAGE_FILE_MACHINE_I386, c.Eip, c.Esp, c.Ebp);
+                let (machine, pc, sp, fp) = regs;
+                let context = c as *mut _ as *mut c_void;
+                (machine, context, pc as usize, sp as usize, fp as usize)
+            };
+
+        let mut frames = vec![];
+        unsafe {
+            let mut frame: STACKFRAME64 = zeroed();
+            frame.AddrPC = flat(pc);
+            frame.AddrStack = flat(sp);
+            frame.AddrFrame = flat(fp);
+            // the offline targets have no process, all the reads go through the callbacks
+            let process = target.process().map_or(null_mut(), |p| *p.handle);
+            let prev = WALKING.with(|w| w.replace(&target as *const &T as *const c_void));
+            while frames.len() < max_depth
+                && StackWalk64(
+                    machine as DWORD,
+                    process,
+                    null_mut(),
+                    &mut frame,
+                    context,
+                    Some(read_memory::<T>),
+                    Some(function_table::<T>),
+                    Some(module_base::<T>),
+                    None,
+                ) != 0
+            {
+                let pc = frame.AddrPC.Offset as usize;
+                if pc == 0 {
+                    break;
+                }
+                let method = match frames.len() {
+                    0 => UnwindMethod::Context,
+                    _ => UnwindMethod::UnwindInfo,
+                };
+                frames.push(CallFrame::new(
+                    target,
+                    pc,
+                    frame.AddrStack.Offset as usize,
+                    method,
+                ));
+            }
+            WALKING.with(|w| w.set(prev));
+        }
+        if frames.is_empty() {
+            frames.push(CallFrame::new(target, pc, sp, UnwindMethod::Context));
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(not(windows))]
+mod sys {
+    use super::*;
+
+    cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            const FP_REG: &str = "rbp";
+            const LR_REG: Option<&str> = None;
+        } else if #[cfg(target_arch = "aarch64")] {
+            const FP_REG: &str = "x29";
+            const LR_REG: Option<&str> = Some("lr");
+        } else if #[cfg(target_arch = "arm")] {
+            const FP_REG: &str = "r11";
+            const LR_REG: Option<&str> = Some("lr");
+        } else {
+            const FP_REG: &str = "ebp";
+            const LR_REG: Option<&str> = None;
+        }
+    }
+
+    /// The registers of a frame needed to unwind its caller
+    #[derive(Debug, Clone, Copy)]
+    pub struct FrameRegs {
+        pub pc: usize,
+        pub sp: usize,
+        pub fp: usize,
+        /// The link register, 0 on x86
+        pub lr: usize,
+    }
+
+    pub fn unwind<T: UDbgTarget + ?Sized, Th: UDbgThread + ?Sized>(
+        target: &T,
+        thread: &Th,
+        max_depth: usize,
+    ) -> UDbgResult<Vec<CallFrame>> {
+        let mut regs = FrameRegs {
+            pc: thread.reg_value("_pc")?,
+            sp: thread.reg_value("_sp")?,
+            fp: thread.reg_value(FP_REG).unwrap_or(0),
+            lr: LR_REG.and_then(|r| thread.reg_value(r).ok()).unwrap_or(0),
+        };
+        let mut cfi = cfi::Unwinder::new(target);
+
+        let mut frames = vec![CallFrame::new(
+            target,
+            regs.pc,
+            regs.sp,
+            UnwindMethod::Context,
+        )];
+        while frames.len() < max_depth {
+            if let Some(caller) = cfi.step(&regs, frames.len() == 1) {
+                frames.push(CallFrame::new(
+                    target,
+                    caller.pc,
+                    caller.sp,
+                    UnwindMethod::UnwindInfo,
+                ));
+                regs = caller;
+                continue;
+            }
+            let caller = match frame_pointer(target, &regs) {
+                Some(caller) => caller,
+                None => break,
+            };
+            frames.push(CallFrame::new(
+                target,
+                caller.pc,
+                caller.sp,
+                UnwindMethod::FramePointer,
+            ));
+            // the frames grow down, a caller's frame is always above
+            if caller.fp <= regs.fp {
+                break;
+            }
+            regs = caller;
+        }
+        Ok(frames)
+    }
+
+    /// Unwind a frame by its frame record: the caller's frame pointer, then the return address
+    fn frame_pointer<T: UDbgTarget + ?Sized>(target: &T, regs: &FrameRegs) -> Option<FrameRegs> {
+        let ps = target.base().pointer_size();
+        let fp = regs.fp;
+        if fp < regs.sp || fp % ps != 0 {
+            return None;
+        }
+        let next = target.read_ptr(fp)?;
+        let ret = target.read_ptr(fp + ps)?;
+        if ret == 0 || !target.is_return_address(ret) {
+            return None;
+        }
+        Some(FrameRegs {
+            pc: ret,
+            sp: fp + 2 * ps,
+            fp: next,
+            lr: regs.lr,
+        })
+    }
+
+    #[cfg(all(
+        any(target_os = "linux", target_os = "android"),
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    mod cfi {
+        use super::*;
+
+        use anyhow::Context;
+        use core::ops::Range;
+        use gimli::{
+            BaseAddresses, CfaRule, EhFrame, Register, RegisterRule, RunTimeEndian, UnwindContext,
+            UnwindSection,
+        };
+        use goblin::elf::{program_header::PT_LOAD, section_header::SHT_NOBITS, Elf};
+        use memmap2::Mmap;
+        use std::collections::HashMap;
+
+        cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                const SP: Register = gimli::X86_64::RSP;
+                const FP: Register = gimli::X86_64::RBP;
+                const RA: Register = gimli::X86_64::RA;
+                /// The return address column is not a register
+                const HAS_LR: bool = false;
+            } else {
+                const SP: Register = gimli::AArch64::SP;
+                const FP: Register = gimli::AArch64::X29;
+                const RA: Register = gimli::AArch64::X30;
+                const HAS_LR: bool = true;
+            }
+        }
+
+        /// The `.eh_frame` of a module file
+        struct EhFrameData {
+            map: Mmap,
+            range: Range<usize>,
+            bases: BaseAddresses,
+            endian: RunTimeEndian,
+            /// The loaded address minus the virtual address in file
+            bias: usize,
+        }
+
+        impl EhFrameData {
+            fn load(path: &str, base: usize) -> anyhow::Result<Self> {
+                let map = Utils::mapfile(path).context("map")?;
+                let (range, bases, endian, low) = {
+                    let elf = Elf::parse(&map).context("parse")?;
+                    let section = |name: &str| {
+                        elf.section_headers.iter().find(|s| {
+                            s.sh_type != SHT_NOBITS
+                                && elf.shdr_strtab.get_at(s.sh_name) == Some(name)
+                        })
+                    };
+                    let eh_frame = section(".eh_frame").context("no .eh_frame")?;
+                    let mut bases = BaseAddresses::default().set_eh_frame(eh_frame.sh_addr);
+                    if let Some(text) = section(".text") {
+                        bases = bases.set_text(text.sh_addr);
+                    }
+                    if let Some(got) = section(".got") {
+                        bases = bases.set_got(got.sh_addr);
+                    }
+                    let start = eh_frame.sh_offset as usize;
+                    let endian = if elf.little_endian {
+                        RunTimeEndian::Little
+                    } else {
+                        RunTimeEndian::Big
+                    };
+                    // the module base is the page of the lowest loadable segment, as in dwarf
+                    let low = elf
+                        .program_headers
+                        .iter()
+                        .filter(|p| p.p_type == PT_LOAD)
+                        .map(|p| p.p_vaddr & !0xFFF)
+                        .min()
+                        .unwrap_or(0);
+                    (start..start + eh_frame.sh_size as usize, bases, endian, low)
+                };
+                map.get(range.clone()).context("truncated .eh_frame")?;
+                Ok(Self {
+                    map,
+                    range,
+                    bases,
+                    endian,
+                    bias: base.wrapping_sub(low as usize),
+                })
+            }
+        }
+
+        /// Unwind the frames by the CFI of modules, the parsed modules are cached in a walk
+        pub struct Unwinder<'a, T: UDbgTarget + ?Sized> {
+            target: &'a T,
+            modules: HashMap<usize, Option<EhFrameData>>,
+        }
+
+        impl<'a, T: UDbgTarget + ?Sized> Unwinder<'a, T> {
+            pub fn new(target: &'a T) -> Self {
+                Self {
+                    target,
+                    modules: HashMap::new(),
+                }
+            }
+
+            /// Recover the registers of the caller, None if the module of pc has no CFI for it
+            pub fn step(&mut self, regs: &FrameRegs, innermost: bool) -> Option<FrameRegs> {
+                let target = self.target;
+                let module = target.find_module(regs.pc)?;
+                let data = module.data();
+                let eh = self
+                    .modules
+                    .entry(data.base)
+                    .or_insert_with(|| EhFrameData::load(&data.path, data.base).ok())
+                    .as_ref()?;
+
+                let eh_frame = EhFrame::new(&eh.map[eh.range.clone()], eh.endian);
+                // a return address may be past the end of function, look up the call instead
+                let pc = if innermost { regs.pc } else { regs.pc - 1 };
+                let mut ctx = UnwindContext::new();
+                let row = eh_frame
+                    .unwind_info_for_address(
+                        &eh.bases,
+                        &mut ctx,
+                        pc.wrapping_sub(eh.bias) as u64,
+                        EhFrame::cie_from_offset,
+                    )
+                    .ok()?;
+
+                let get = |reg: Register| match reg {
+                    r if r == SP => Some(regs.sp),
+                    r if r == FP => Some(regs.fp),
+                    r if r == RA && HAS_LR => Some(regs.lr),
+                    _ => None,
+                };
+                let cfa = match row.cfa() {
+                    CfaRule::RegisterAndOffset { register, offset } => {
+                        get(*register)?.wrapping_add(*offset as usize)
+                    }
+                    _ => return None,
+                };
+                let recover = |reg: Register| {
+                    Some(match row.register(reg) {
+                        RegisterRule::Undefined | RegisterRule::SameValue => get(reg)?,
+                        RegisterRule::Offset(n) => target.read_ptr(cfa.wrapping_add(n as usize))?,
+                        RegisterRule::ValOffset(n) => cfa.wrapping_add(n as usize),
+                        RegisterRule::Register(r) => get(r)?,
+                        _ => return None,
+                    })
+                };
+                // the return address of the outermost frame, such as `_start`, is undefined
+                if let RegisterRule::Undefined = row.register(RA) {
+                    return None;
+                }
+                let pc = recover(RA)?;
+                // the stack grows down, stop if the walk doesn't progress
+                if pc == 0 || cfa < regs.sp || (cfa == regs.sp && pc == regs.pc) {
+                    return None;
+                }
+                Some(FrameRegs {
+                    pc,
+                    sp: cfa,
+                    fp: recover(FP)?,
+                    // the link register holds the return address after returning
+                    lr: if HAS_LR { pc } else { 0 },
+                })
+            }
+        }
+    }
+
+    /// Only the frame pointers are followed on the other platforms
+    #[cfg(not(all(
+        any(target_os = "linux", target_os = "android"),
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    )))]
+    mod cfi {
+        use super::*;
+
+        pub struct Unwinder<'a, T: UDbgTarget + ?Sized>(&'a T);
+
+        impl<'a, T: UDbgTarget + ?Sized> Unwinder<'a, T> {
+            pub fn new(target: &'a T) -> Self {
+                Self(target)
+            }
+
+            pub fn step(&mut self, _regs: &FrameRegs, _innermost: bool) -> Option<FrameRegs> {
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//!
+//! Value search and narrowing in the memory of target, like the scans of Cheat Engine.
+//!
+//! [`ValueSearch::first_scan`] finds the addresses holding a typed value, an integer, a float or a
+//! string, which become the candidates. Each [`ValueSearch::refine`] pass reads the candidates
+//! again and keeps the ones matching a [`Refine`] condition against their previous values, such as
+//! changed or increased by N, until few remain. The candidate set could be saved and loaded to
+//! continue later in the same process.
+//!
+
+use crate::{
+    prelude::*,
+    scan::{scan_target, ScanOptions},
+};
+
+use std::path::Path;
+
+/// Max span of the candidates read at once
+const READ_WINDOW: usize = 0x10000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+    Utf8,
+    Utf16,
+}
+
+impl ValueType {
+    /// Size of the numbers, None for the strings whose size is of the value searched
+    pub fn size(self) -> Option<usize> {
+        Some(match self {
+            Self::I8 => 1,
+            Self::I16 => 2,
+            Self::I32 | Self::F32 => 4,
+            Self::I64 | Self::F64 => 8,
+            Self::Utf8 | Self::Utf16 => return None,
+        })
+    }
+
+    #[inline]
+    pub fn is_float(self) -> bool {
+        matches!(self, Self::F32 | Self::F64)
+    }
+
+    /// Little endian bytes of the value
+    pub fn encode(self, value: &Value) -> UDbgResult<Vec<u8>> {
+        Ok(match (self, value) {
+            (Self::I8, &Value::Int(v)) => (v as i8).to_le_bytes().to_vec(),
+            (Self::I16, &Value::Int(v)) => (v as i16).to_le_bytes().to_vec(),
+            (Self::I32, &Value::Int(v)) => (v as i32).to_le_bytes().to_vec(),
+            (Self::I64, &Value::Int(v)) => v.to_le_bytes().to_vec(),
+            (Self::F32, &Value::Float(v)) => (v as f32).to_le_bytes().to_vec(),
+            (Self::F64, &Value::Float(v)) => v.to_le_bytes().to_vec(),
+            (Self::F32 | Self::F64, &Value::Int(v)) => return self.encode(&Value::Float(v as f64)),
+            (Self::Utf8, Value::Str(s)) => s.as_bytes().to_vec(),
+            (Self::Utf16, Value::Str(s)) => s.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            _ => return Err(format!("{value:?} is not a value of {self:?}").into()),
+        })
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Option<Value> {
+        let int = |n: usize| {
+            let mut buf = [0u8; 8];
+            buf[..n].copy_from_slice(bytes.get(..n)?);
+            // sign extended
+            let shift = 64 - n * 8;
+            Some(Value::Int(i64::from_le_bytes(buf) << shift >> shift))
+        };
+        match self {
+            Self::I8 => int(1),
+            Self::I16 => int(2),
+            Self::I32 => int(4),
+            Self::I64 => int(8),
+            Self::F32 => Some(Value::Float(
+                f32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as f64,
+            )),
+            Self::F64 => Some(Value::Float(f64::from_le_bytes(
+                bytes.get(..8)?.try_into().ok()?,
+            ))),
+            Self::Utf8 => Some(Value::Str(String::from_utf8_lossy(bytes).into())),
+            Self::Utf16 => {
+                let wide = bytes
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .collect::<Vec<_>>();
+                Some(Value::Str(String::from_utf16_lossy(&wide)))
+            }
+        }
+    }
+
+    /// Tolerance of comparing two floats around `magnitude`
+    fn tolerance(self, magnitude: f64) -> f64 {
+        let epsilon = match self {
+            Self::F32 => f32::EPSILON as f64,
+            _ => f64::EPSILON,
+        };
+        4.0 * epsilon * magnitude.abs().max(1.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl Value {
+    fn number(&self) -> Option<f64> {
+        match *self {
+            Self::Int(v) => Some(v as f64),
+            Self::Float(v) => Some(v),
+            Self::Str(_) => None,
+        }
+    }
+}
+
+/// Condition of a refine pass, against the value of previous pass
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Refine {
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    IncreasedBy(f64),
+    DecreasedBy(f64),
+    /// Equal to the value now
+    Exact(Value),
+}
+
+/// A candidate address with its value of last pass
+#[derive(Debug, Clone, Serialize)]
+pub struct Candidate {
+    pub address: usize,
+    pub value: Value,
+}
+
+/// The candidates of a search, see the module document
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValueSearch {
+    ty: ValueType,
+    /// Only the addresses aligned with it are found
+    pub align: usize,
+    /// Size of the values, 0 before the first scan for the strings
+    size: usize,
+    addresses: Vec<usize>,
+    /// The values of last pass, `size` bytes for each address
+    values: Vec<u8>,
+    passes: usize,
+}
+
+impl ValueSearch {
+    /// A search of the values of type, the numbers are aligned by their size up to 4
+    pub fn new(ty: ValueType) -> Self {
+        Self {
+            ty,
+            align: ty.size().map_or(1, |s| s.min(4)),
+            size: ty.size().unwrap_or_default(),
+            addresses: vec![],
+            values: vec![],
+            passes: 0,
+        }
+    }
+
+    #[inline]
+    pub fn value_type(&self) -> ValueType {
+        self.ty
+    }
+
+    /// Count of the candidates
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Count of the scans done, the first one included
+    #[inline]
+    pub fn passes(&self) -> usize {
+        self.passes
+    }
+
+    #[inline]
+    pub fn addresses(&self) -> &[usize] {
+        &self.addresses
+    }
+
+    /// The candidates with their values of last pass
+    pub fn candidates(&self) -> impl Iterator<Item = Candidate> + '_ {
+        self.addresses
+            .iter()
+            .zip(self.values.chunks_exact(self.size.max(1)))
+            .filter_map(|(&address, bytes)| {
+                Some(Candidate {
+                    address,
+                    value: self.ty.decode(bytes)?,
+                })
+            })
+    }
+
+    /// Scan all the readable memory for the value, the candidates of previous passes are dropped.
+    /// The floats are matched exactly, as the bytes of the value converted to the type
+    pub fn first_scan<T: UDbgTarget + ?Sized>(
+        &mut self,
+        target: &T,
+        value: &Value,
+    ) -> UDbgResult<usize> {
+        let bytes = self.ty.encode(value)?;
+        if bytes.is_empty() {
+            return Err("empty value".into());
+        }
+        let opts = ScanOptions {
+            align: self.align.max(1),
+            ..Default::default()
+        };
+        self.addresses = scan_target(target, &bytes, &opts)?;
+        self.size = bytes.len();
+        self.values = bytes.repeat(self.addresses.len());
+        self.passes = 1;
+        Ok(self.len())
+    }
+
+    /// Current values of the candidates, None if it can't be read
+    fn read_values<T: ReadMemory + ?Sized>(&self, target: &T) -> Vec<Option<Vec<u8>>> {
+        let size = self.size;
+        let mut result = Vec::with_capacity(self.addresses.len());
+        let mut i = 0;
+        while i < self.addresses.len() {
+            let start = self.addresses[i];
+            let count = self.addresses[i..]
+                .iter()
+                .take_while(|&&a| a + size - start <= READ_WINDOW)
+                .count()
+                .max(1);
+            let end = self.addresses[i + count - 1] + size;
+            let data = target.read_bytes(start, end - start);
+            for &a in &self.addresses[i..i + count] {
+                let offset = a - start;
+                result.push(data.get(offset..offset + size).map(<[u8]>::to_vec));
+            }
+            i += count;
+        }
+        result
+    }
+
+    /// The value `new` meets the condition against `old`
+    fn matches(&self, refine: &Refine, old: &[u8], new: &[u8]) -> UDbgResult<bool> {
+        let ty = self.ty;
+        let number = |bytes: &[u8]| {
+            ty.decode(bytes)
+                .and_then(|v| v.number())
+                .ok_or_else(|| UDbgError::from(format!("{ty:?} is not a number")))
+        };
+        // the exact difference of integers, or the difference of floats in tolerance
+        let differs_by = |delta: f64| -> UDbgResult<bool> {
+            let (old, new) = (number(old)?, number(new)?);
+            Ok(if ty.is_float() {
+                let magnitude = old.abs().max(new.abs());
+                (new - old - delta).abs() <= ty.tolerance(magnitude)
+            } else {
+                new - old == delta
+            })
+        };
+        Ok(match refine {
+            Refine::Changed => old != new,
+            Refine::Unchanged => old == new,
+            Refine::Increased => number(new)? > number(old)?,
+            Refine::Decreased => number(new)? < number(old)?,
+            &Refine::IncreasedBy(n) => differs_by(n)?,
+            &Refine::DecreasedBy(n) => differs_by(-n)?,
+            Refine::Exact(value) => ty.encode(value)? == new,
+        })
+    }
+
+    /// Keep the candidates meeting the condition, and update their values. The candidates can't be
+    /// read are dropped. Returns the count of candidates kept
+    pub fn refine<T: ReadMemory + ?Sized>(
+        &mut self,
+        target: &T,
+        refine: &Refine,
+    ) -> UDbgResult<usize> {
+        if self.passes == 0 {
+            return Err("no first scan".into());
+        }
+        let current = self.read_values(target);
+        let mut addresses = vec![];
+        let mut values = vec![];
+        for ((&address, old), new) in self
+            .addresses
+            .iter()
+            .zip(self.values.chunks_exact(self.size))
+            .zip(current)
+        {
+            let new = match new {
+                Some(new) => new,
+                None => continue,
+            };
+            if self.matches(refine, old, &new)? {
+                addresses.push(address);
+                values.extend(new);
+            }
+        }
+        self.addresses = addresses;
+        self.values = values;
+        self.passes += 1;
+        Ok(self.len())
+    }
+
+    /// Keep only the candidates whose address is in the range
+    pub fn retain_range(&mut self, range: core::ops::Range<usize>) {
+        let size = self.size.max(1);
+        let mut values = vec![];
+        for (address, bytes) in self.addresses.iter().zip(self.values.chunks_exact(size)) {
+            if range.contains(address) {
+                values.extend_from_slice(bytes);
+            }
+        }
+        self.addresses.retain(|a| range.contains(a));
+        self.values = values;
+    }
+
+    /// Save the candidates as JSON, to be loaded and refined later
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> UDbgResult<()> {
+        let data = serde_json::to_vec(self).map_err(std::io::Error::from)?;
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> UDbgResult<Self> {
+        let data = std::fs::read(path)?;
+        let result = serde_json::from_slice::<Self>(&data).map_err(std::io::Error::from)?;
+        let consistent = result.values.len() == result.addresses.len() * result.size
+            && (result.size > 0 || result.addresses.is_empty());
+        if !consistent {
+            return Err("inconsistent candidates".into());
+        }
+        Ok(result)
+    }
+}
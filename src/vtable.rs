@@ -0,0 +1,76 @@
+//!
+//! Instances of a C++ class found by its vtable: the objects whose first pointer is the vtable.
+//!
+//! The vtable comes from the symbols, such as ``module!Class::`vftable'`` of MSVC or the
+//! `_ZTV` symbols of the Itanium ABI, see [`vtable_by_symbol`]. The candidates are the references
+//! in the writable memory which is neither a module nor a stack, the owners are classified by the
+//! heap blocks if a [`HeapSnapshot`] is given.
+//!
+
+use crate::{
+    heap::HeapSnapshot,
+    prelude::*,
+    refs::{find_references, RefOwner},
+};
+
+/// A candidate instance
+#[derive(Debug, Clone, Serialize)]
+pub struct Instance {
+    pub address: usize,
+    /// The memory containing the object
+    pub owner: RefOwner,
+}
+
+impl Instance {
+    /// The object is at the start of a heap block, the most likely candidate
+    pub fn is_block_start(&self) -> bool {
+        matches!(self.owner, RefOwner::HeapBlock { address, .. } if address == self.address)
+    }
+}
+
+/// Address of the vtable by a symbol, the address point of the Itanium `_ZTV` symbols follows
+/// the offset to top and the typeinfo
+pub fn vtable_by_symbol<T: UDbgTarget + ?Sized>(target: &T, symbol: &str) -> Option<usize> {
+    let address = target.get_address_by_symbol(symbol)?;
+    let name = symbol.rsplit('!').next().unwrap_or(symbol);
+    Some(if name.starts_with("_ZTV") {
+        address + 2 * target.base().pointer_size()
+    } else {
+        address
+    })
+}
+
+/// Scan the memory for the instances of class by its `vtable`
+pub fn find_instances<T: UDbgTarget + ?Sized>(
+    target: &T,
+    vtable: usize,
+    heap: Option<&HeapSnapshot>,
+) -> UDbgResult<Vec<Instance>> {
+    let layout = target.base().data_layout();
+    // the first slot of a vtable points to code, unless it's a pure virtual one
+    let first = target
+        .read_pointer(vtable, layout)
+        .ok_or(UDbgError::InvalidAddress)?;
+    if !target
+        .virtual_query(first)
+        .map_or(false, |p| p.is_executable())
+    {
+        return Err(format!("{vtable:x} is not a vtable").into());
+    }
+
+    let refs = find_references(target, vtable..vtable + 1, layout.pointer_size, heap)?;
+    Ok(refs
+        .into_iter()
+        .filter(|r| match r.owner {
+            RefOwner::HeapBlock { .. } | RefOwner::Heap { .. } => true,
+            RefOwner::Memory { .. } => target
+                .virtual_query(r.address)
+                .map_or(false, |p| p.is_writable()),
+            _ => false,
+        })
+        .map(|r| Instance {
+            address: r.address,
+            owner: r.owner,
+        })
+        .collect())
+}
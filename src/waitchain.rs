@@ -0,0 +1,372 @@
+//!
+//! Wait chains of threads: what a thread waits for, who owns it, what the owner waits for, and so on.
+//!
+//! On Windows it wraps the Wait Chain Traversal API. On Linux it's approximated from `/proc`:
+//! the syscall a thread blocks in, the owner of a futex (the PI futex word, or the `__owner`
+//! of glibc's `pthread_mutex_t`), and the file of a blocking fd.
+//! A chain which returns to one of its threads is a deadlock, see [`find_deadlocks`].
+//!
+
+use crate::prelude::*;
+
+/// A node of wait chain
+#[derive(Debug, Clone, Serialize)]
+pub struct WaitNode {
+    /// Object type, such as "Thread", "CriticalSection", "Mutex", "Futex", "Socket"
+    pub kind: &'static str,
+    /// Status of the object, such as "Owned", "Blocked", "Running"
+    pub status: &'static str,
+    /// Name of the lock object, the file of an fd, or the kernel function a thread sleeps in
+    pub name: String,
+    /// Thread and process of a thread node
+    pub tid: Option<tid_t>,
+    pub pid: Option<pid_t>,
+    /// Address of the lock object in target, e.g. the futex word
+    pub address: Option<usize>,
+}
+
+impl WaitNode {
+    fn thread(pid: pid_t, tid: tid_t, status: &'static str) -> Self {
+        Self {
+            kind: "Thread",
+            status,
+            name: String::new(),
+            tid: Some(tid),
+            pid: Some(pid),
+            address: None,
+        }
+    }
+
+    fn object(kind: &'static str, status: &'static str, name: String) -> Self {
+        Self {
+            kind,
+            status,
+            name,
+            tid: None,
+            pid: None,
+            address: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_thread(&self) -> bool {
+        self.kind == "Thread"
+    }
+}
+
+/// The wait chain starting from a thread
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WaitChain {
+    pub nodes: Vec<WaitNode>,
+    /// The chain returns to one of its threads
+    pub cycle: bool,
+}
+
+impl WaitChain {
+    /// The last thread in the chain other than the first one, i.e. the owner of the resource
+    pub fn owner(&self) -> Option<&WaitNode> {
+        self.nodes.iter().skip(1).rev().find(|n| n.is_thread())
+    }
+
+    /// The last resource in the chain
+    pub fn resource(&self) -> Option<&WaitNode> {
+        self.nodes.iter().rev().find(|n| !n.is_thread())
+    }
+
+    /// The thread waits for a resource owned by other thread, or for IO uninterruptibly
+    pub fn is_blocked(&self) -> bool {
+        self.owner().is_some() || self.nodes.iter().any(|n| n.kind == "DiskSleep")
+    }
+
+    /// Threads in the chain
+    pub fn threads(&self) -> impl Iterator<Item = tid_t> + '_ {
+        self.nodes.iter().filter_map(|n| n.tid)
+    }
+}
+
+/// Get the wait chain of a thread of target
+pub fn wait_chain<T: UDbgTarget + ?Sized>(target: &T, tid: tid_t) -> UDbgResult<WaitChain> {
+    sys::wait_chain(target, tid)
+}
+
+/// The deadlocks among the threads of target, each is the threads in the cycle
+pub fn find_deadlocks<T: UDbgTarget + ?Sized>(target: &T) -> UDbgResult<Vec<Vec<tid_t>>> {
+    let mut result: Vec<Vec<tid_t>> = vec![];
+    for t in target.enum_thread(false)? {
+        let chain = match wait_chain(target, t.tid) {
+            Ok(c) if c.cycle => c,
+            _ => continue,
+        };
+        // the chain ends with the thread closing the cycle,
+        // the first thread may wait for the cycle without being in it
+        let threads = chain.threads().collect::<Vec<_>>();
+        let last = match threads.last() {
+            Some(&t) => t,
+            None => continue,
+        };
+        let start = threads.iter().position(|&t| t == last).unwrap_or(0);
+        let mut tids = match &threads[start..threads.len() - 1] {
+            [] => threads.clone(),
+            cycle => cycle.to_vec(),
+        };
+        tids.sort();
+        tids.dedup();
+        if !tids.is_empty() && !result.contains(&tids) {
+            result.push(tids);
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::*;
+
+    use core::mem::zeroed;
+    use winapi::um::wct::*;
+
+    /// Session of Wait Chain Traversal, closed when dropped
+    pub struct WctSession(HWCT);
+
+    impl WctSession {
+        pub fn open() -> UDbgResult<Self> {
+            let session = unsafe { OpenThreadWaitChainSession(0, None) };
+            if session.is_null() {
+                return Err(UDbgError::system());
+            }
+            Ok(Self(session))
+        }
+
+        /// Wait chain of a thread, including the nodes in other processes
+        pub fn chain(&self, tid: tid_t) -> UDbgResult<WaitChain> {
+            unsafe {
+                let mut nodes: [WAITCHAIN_NODE_INFO; WCT_MAX_NODE_COUNT as usize] = zeroed();
+                let mut count = nodes.len() as u32;
+                let mut cycle = 0;
+                let ok = GetThreadWaitChain(
+                    self.0,
+                    0,
+                    WCT_OUT_OF_PROC_FLAG | WCT_OUT_OF_PROC_COM_FLAG | WCT_OUT_OF_PROC_CS_FLAG,
+                    tid,
+                    &mut count,
+                    nodes.as_mut_ptr(),
+                    &mut cycle,
+                );
+                if ok == 0 {
+                    return Err(UDbgError::system());
+                }
+                let count = (count as usize).min(nodes.len());
+                Ok(WaitChain {
+                    nodes: nodes[..count].iter().map(|n| node(n)).collect(),
+                    cycle: cycle != 0,
+                })
+            }
+        }
+    }
+
+    impl Drop for WctSession {
+        fn drop(&mut self) {
+            unsafe { CloseThreadWaitChainSession(self.0) }
+        }
+    }
+
+    fn object_type(t: WCT_OBJECT_TYPE) -> &'static str {
+        match t {
+            WctCriticalSectionType => "CriticalSection",
+            WctSendMessageType => "SendMessage",
+            WctMutexType => "Mutex",
+            WctAlpcType => "Alpc",
+            WctComType => "Com",
+            WctThreadWaitType => "ThreadWait",
+            WctProcessWaitType => "ProcessWait",
+            WctThreadType => "Thread",
+            WctComActivationType => "ComActivation",
+            WctSocketIoType => "SocketIo",
+            WctSmbIoType => "SmbIo",
+            _ => "Unknown",
+        }
+    }
+
+    fn object_status(s: WCT_OBJECT_STATUS) -> &'static str {
+        match s {
+            WctStatusNoAccess => "NoAccess",
+            WctStatusRunning => "Running",
+            WctStatusBlocked => "Blocked",
+            WctStatusPidOnly => "PidOnly",
+            WctStatusPidOnlyRpcss => "PidOnlyRpcss",
+            WctStatusOwned => "Owned",
+            WctStatusNotOwned => "NotOwned",
+            WctStatusAbandoned => "Abandoned",
+            WctStatusError => "Error",
+            _ => "Unknown",
+        }
+    }
+
+    unsafe fn node(n: &WAITCHAIN_NODE_INFO) -> WaitNode {
+        let status = object_status(n.ObjectStatus);
+        if n.ObjectType == WctThreadType {
+            let t = n.u.ThreadObject();
+            WaitNode::thread(t.ProcessId, t.ThreadId, status)
+        } else {
+            let name = String::from_wide_ptr(n.u.LockObject().ObjectName.as_ptr());
+            WaitNode::object(object_type(n.ObjectType), status, name)
+        }
+    }
+
+    pub fn wait_chain<T: UDbgTarget + ?Sized>(_target: &T, tid: tid_t) -> UDbgResult<WaitChain> {
+        WctSession::open()?.chain(tid)
+    }
+}
+
+#[cfg(windows)]
+pub use sys::WctSession;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    const MAX_NODES: usize = 16;
+    const FUTEX_CMD_MASK: usize = 0x7F;
+    const FUTEX_WAIT: usize = 0;
+    const FUTEX_LOCK_PI: usize = 6;
+    const FUTEX_WAIT_BITSET: usize = 9;
+    const FUTEX_LOCK_PI2: usize = 13;
+    const FUTEX_TID_MASK: u32 = 0x3FFF_FFFF;
+    /// Offset of `__owner` in glibc's `pthread_mutex_t`, after `__lock` and `__count`
+    const MUTEX_OWNER_OFFSET: usize = 8;
+
+    /// State of thread, the 3rd field of `/proc/<pid>/task/<tid>/stat`
+    fn task_state(pid: pid_t, tid: tid_t) -> Option<char> {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/task/{tid}/stat")).ok()?;
+        stat.rsplit_once(')')?.1.trim_start().chars().next()
+    }
+
+    /// The syscall number and arguments a thread blocks in
+    fn task_syscall(pid: pid_t, tid: tid_t) -> Option<(libc::c_long, Vec<usize>)> {
+        let text = std::fs::read_to_string(format!("/proc/{pid}/task/{tid}/syscall")).ok()?;
+        let mut fields = text.split_whitespace();
+        let nr = fields.next()?.parse::<libc::c_long>().ok()?;
+        let args = fields
+            .take(6)
+            .filter_map(|a| usize::from_str_radix(a.trim_start_matches("0x"), 16).ok())
+            .collect();
+        Some((nr, args))
+    }
+
+    fn wchan(pid: pid_t, tid: tid_t) -> String {
+        let wchan = std::fs::read_to_string(format!("/proc/{pid}/task/{tid}/wchan"))
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        if wchan == "0" {
+            String::new()
+        } else {
+            wchan
+        }
+    }
+
+    fn is_fd_wait(nr: libc::c_long) -> bool {
+        use libc::*;
+
+        match nr {
+            SYS_read | SYS_readv | SYS_write | SYS_recvfrom | SYS_recvmsg | SYS_sendto
+            | SYS_sendmsg | SYS_accept | SYS_accept4 | SYS_connect => true,
+            _ => false,
+        }
+    }
+
+    /// Kind of the file an fd refers to, by the link target in `/proc/<pid>/fd`
+    fn fd_node(pid: pid_t, fd: usize) -> WaitNode {
+        let path = std::fs::read_link(format!("/proc/{pid}/fd/{fd}"))
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| format!("fd {fd}"));
+        let kind = if path.starts_with("socket:") {
+            "Socket"
+        } else if path.starts_with("pipe:") {
+            "Pipe"
+        } else {
+            "File"
+        };
+        WaitNode::object(kind, "Blocked", path)
+    }
+
+    /// The owner thread of a futex, if it's a PI futex or a glibc mutex
+    fn futex_owner<T: UDbgTarget + ?Sized>(
+        target: &T,
+        pid: pid_t,
+        address: usize,
+        op: usize,
+    ) -> Option<tid_t> {
+        let owner = match op & FUTEX_CMD_MASK {
+            FUTEX_LOCK_PI | FUTEX_LOCK_PI2 => target.read_value::<u32>(address)? & FUTEX_TID_MASK,
+            _ => target.read_value::<u32>(address + MUTEX_OWNER_OFFSET)?,
+        } as tid_t;
+        // the word may be not a mutex at all, accept only a thread of target
+        (owner > 0 && std::path::Path::new(&format!("/proc/{pid}/task/{owner}")).exists())
+            .then(|| owner)
+    }
+
+    pub fn wait_chain<T: UDbgTarget + ?Sized>(target: &T, tid: tid_t) -> UDbgResult<WaitChain> {
+        let pid = target.pid();
+        let mut chain = WaitChain::default();
+        let mut visited = HashSet::new();
+        let mut tid = tid;
+        while chain.nodes.len() < MAX_NODES {
+            if !visited.insert(tid) {
+                chain.nodes.push(WaitNode::thread(pid, tid, "Blocked"));
+                chain.cycle = true;
+                break;
+            }
+            let state = task_state(pid, tid).ok_or(UDbgError::NotFound)?;
+            if !matches!(state, 'S' | 'D') {
+                chain.nodes.push(WaitNode::thread(pid, tid, "Running"));
+                break;
+            }
+            chain.nodes.push(WaitNode::thread(pid, tid, "Blocked"));
+
+            let (nr, args) = task_syscall(pid, tid).unwrap_or((-1, vec![]));
+            let arg = |i: usize| args.get(i).copied().unwrap_or_default();
+            if nr == libc::SYS_futex
+                && matches!(
+                    arg(1) & FUTEX_CMD_MASK,
+                    FUTEX_WAIT | FUTEX_WAIT_BITSET | FUTEX_LOCK_PI | FUTEX_LOCK_PI2
+                )
+            {
+                let address = arg(0);
+                let owner = futex_owner(target, pid, address, arg(1)).filter(|&o| o != tid);
+                let mut node = WaitNode::object(
+                    "Futex",
+                    if owner.is_some() { "Owned" } else { "Unknown" },
+                    target.get_symbol_string(address).unwrap_or_default(),
+                );
+                node.address = Some(address);
+                chain.nodes.push(node);
+                match owner {
+                    Some(owner) => tid = owner,
+                    None => break,
+                }
+            } else if nr >= 0 && is_fd_wait(nr) {
+                chain.nodes.push(fd_node(pid, arg(0)));
+                break;
+            } else {
+                let kind = if state == 'D' { "DiskSleep" } else { "Sleep" };
+                chain
+                    .nodes
+                    .push(WaitNode::object(kind, "Blocked", wchan(pid, tid)));
+                break;
+            }
+        }
+        Ok(chain)
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android")))]
+mod sys {
+    use super::*;
+
+    pub fn wait_chain<T: UDbgTarget + ?Sized>(_target: &T, _tid: tid_t) -> UDbgResult<WaitChain> {
+        Err(UDbgError::NotSupport)
+    }
+}